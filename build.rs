@@ -0,0 +1,13 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    // The gRPC service is generated from proto/koodb.proto. protox parses it,
+    // so building doesn't need protoc installed.
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/koodb.proto");
+        let descriptors = protox::compile(["koodb.proto"], ["proto"]).expect("proto/koodb.proto should compile");
+        tonic_build::configure()
+            .compile_fds(descriptors)
+            .expect("gRPC code generation failed");
+    }
+}
@@ -0,0 +1,62 @@
+use koo_db::alter::DropBehavior;
+use koo_db::error::Result;
+use koo_db::flexible_database::{FieldType, FlexibleDatabase, Schema};
+use koo_db::migrations::Migration;
+use rusqlite::types::Value;
+use std::collections::HashMap;
+
+struct CreateUsers;
+
+impl Migration for CreateUsers {
+    fn version(&self) -> i64 {
+        1
+    }
+
+    fn name(&self) -> &str {
+        "create_users"
+    }
+
+    fn up(&self, db: &mut FlexibleDatabase) -> Result<()> {
+        let fields = [
+            ("name".to_string(), FieldType::Text),
+            ("age".to_string(), FieldType::Integer),
+            ("active".to_string(), FieldType::Boolean),
+        ];
+        db.define_schema(Schema::new("users", fields))
+    }
+
+    fn down(&self, db: &mut FlexibleDatabase) -> Result<()> {
+        db.drop_schema("users", DropBehavior::DropTable)
+    }
+}
+
+fn main() -> Result<()> {
+    let mut db = FlexibleDatabase::new(":memory:")?;
+
+    let applied = db.migrate(&[&CreateUsers])?;
+    println!("Applied {} migration(s), now at version {:?}", applied, db.schema_version()?);
+
+    let mut data = HashMap::new();
+    data.insert("name".to_string(), Value::Text("Alice".to_string()));
+    data.insert("age".to_string(), Value::Integer(30));
+    data.insert("active".to_string(), Value::Integer(1));
+    let id = db.create_model("users", data)?;
+    println!("Created user {}", id);
+
+    let mut changes = HashMap::new();
+    changes.insert("age".to_string(), Value::Integer(31));
+    db.update_model("users", &id, changes)?;
+
+    for model in db.get_all_models("users")? {
+        println!("{:?}", model);
+    }
+
+    db.export_json(std::io::stdout())?;
+    println!();
+
+    db.delete_model("users", id)?;
+    db.rollback(&[&CreateUsers], 1)?;
+    println!("Rolled back to version {:?}", db.schema_version()?);
+
+    Ok(())
+}
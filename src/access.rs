@@ -0,0 +1,255 @@
+use rusqlite::types::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FlexibleDatabase, Model, ModelId};
+
+// Who is calling, as passed to an `AccessPolicy`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CallerContext {
+    pub user: Option<String>,
+    pub roles: Vec<String>,
+    // Anything else a policy needs, e.g. the caller's team
+    pub attributes: HashMap<String, String>,
+}
+
+impl CallerContext {
+    // Caller without a user, which is the default
+    pub fn anonymous() -> CallerContext {
+        CallerContext::default()
+    }
+
+    pub fn user(name: &str) -> CallerContext {
+        CallerContext {
+            user: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_role(mut self, role: &str) -> CallerContext {
+        self.roles.push(role.to_string());
+        self
+    }
+
+    pub fn with_attribute(mut self, name: &str, value: &str) -> CallerContext {
+        self.attributes.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+// Decides which models a caller may see and change
+pub trait AccessPolicy: Send + Sync {
+    // Whether `model` of `schema` is visible to the caller
+    fn can_read(&self, schema: &str, model: &Model, caller: &CallerContext) -> bool;
+    // Whether the caller may create `model`, or update or delete it. Updates
+    // are checked against the model both before and after the change.
+    fn can_write(&self, schema: &str, model: &Model, caller: &CallerContext) -> bool;
+}
+
+// With an access policy set, models the caller can't read are left out of
+// everything that reads models: gets, queries, searches and iteration. A
+// query's limit applies before the policy, so a page may come back short.
+// Updates and deletes of unreadable models behave as if the model didn't
+// exist, and writes the policy refuses fail with `AccessDenied`. Counts,
+// exports, imports and raw SQL are not checked.
+impl FlexibleDatabase {
+    pub fn set_access_policy(&mut self, policy: impl AccessPolicy + 'static) {
+        self.access_policy = Some(Arc::new(policy));
+    }
+
+    pub fn clear_access_policy(&mut self) {
+        self.access_policy = None;
+    }
+
+    // Caller that the access policy is consulted for from now on
+    pub fn set_caller(&mut self, caller: CallerContext) {
+        self.caller = caller;
+    }
+
+    pub fn caller(&self) -> &CallerContext {
+        &self.caller
+    }
+
+    // Whether the caller may see a model read from `schema_name`
+    pub(crate) fn readable(&self, schema_name: &str, model: &Model) -> bool {
+        match &self.access_policy {
+            Some(policy) => policy.can_read(schema_name, model, &self.caller),
+            None => true,
+        }
+    }
+
+    // Check a model about to be created
+    pub(crate) fn check_create(
+        &self,
+        schema_name: &str,
+        id: Option<ModelId>,
+        data: &HashMap<String, Value>,
+    ) -> Result<()> {
+        let Some(policy) = &self.access_policy else {
+            return Ok(());
+        };
+        let model = Model::new(id, data.clone());
+        if policy.can_write(schema_name, &model, &self.caller) {
+            Ok(())
+        } else {
+            Err(KooError::AccessDenied {
+                schema: schema_name.to_string(),
+                id: None,
+            })
+        }
+    }
+
+    // Check an update of `id` with `changes`, or its deletion without.
+    // Returns false when the model doesn't exist or the caller can't see it.
+    pub(crate) fn check_change(
+        &self,
+        schema_name: &str,
+        id: &ModelId,
+        changes: Option<&HashMap<String, Value>>,
+    ) -> Result<bool> {
+        let Some(policy) = &self.access_policy else {
+            return Ok(true);
+        };
+        let schema = self.schema_or_err(schema_name)?;
+        let Some(current) = self.stored_model(schema, id)? else {
+            return Ok(false);
+        };
+        if !policy.can_read(schema_name, &current, &self.caller) {
+            return Ok(false);
+        }
+
+        let mut allowed = policy.can_write(schema_name, &current, &self.caller);
+        if let (true, Some(changes)) = (allowed, changes) {
+            let mut changed = current.clone();
+            changed.data.extend(changes.iter().map(|(k, v)| (k.clone(), v.clone())));
+            allowed = policy.can_write(schema_name, &changed, &self.caller);
+        }
+        if allowed {
+            Ok(true)
+        } else {
+            Err(KooError::AccessDenied {
+                schema: schema_name.to_string(),
+                id: Some(id.clone()),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::flexible_database::{FieldType, Schema};
+    use crate::model_builder::ModelBuilder;
+    use crate::query::Query;
+
+    // Owners see and change their own notes; an auditor sees everyone's
+    // but changes nothing that isn't theirs
+    struct Owners;
+
+    impl AccessPolicy for Owners {
+        fn can_read(&self, schema: &str, model: &Model, caller: &CallerContext) -> bool {
+            caller.has_role("auditor") || self.can_write(schema, model, caller)
+        }
+
+        fn can_write(&self, _schema: &str, model: &Model, caller: &CallerContext) -> bool {
+            caller
+                .user
+                .as_ref()
+                .is_some_and(|user| model.get_str("owner").ok() == Some(user.as_str()))
+        }
+    }
+
+    fn note(owner: &str) -> HashMap<String, Value> {
+        ModelBuilder::new().text("owner", owner).build()
+    }
+
+    fn notes() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("notes", [("owner".to_string(), FieldType::Text)]))
+            .unwrap();
+        for owner in ["ann", "bob", "ann"] {
+            db.create_model("notes", note(owner)).unwrap();
+        }
+        db.set_access_policy(Owners);
+        db
+    }
+
+    fn visible(db: &FlexibleDatabase) -> Vec<ModelId> {
+        let models = db.find(&Query::new("notes")).unwrap();
+        models.into_iter().filter_map(|model| model.id).collect()
+    }
+
+    #[test]
+    fn callers_are_built_up() {
+        let caller = CallerContext::user("ann")
+            .with_role("auditor")
+            .with_attribute("team", "east");
+        assert_eq!(caller.user.as_deref(), Some("ann"));
+        assert!(caller.has_role("auditor"));
+        assert!(!caller.has_role("admin"));
+        assert_eq!(caller.attributes["team"], "east");
+        assert_eq!(CallerContext::anonymous(), CallerContext::default());
+    }
+
+    #[test]
+    fn reads_leave_out_what_the_caller_cant_see() {
+        let mut db = notes();
+        assert!(visible(&db).is_empty());
+        assert!(db.get_model("notes", 1).unwrap().is_none());
+
+        db.set_caller(CallerContext::user("ann"));
+        assert_eq!(db.caller().user.as_deref(), Some("ann"));
+        assert_eq!(visible(&db), [ModelId::from(1), ModelId::from(3)]);
+        assert!(db.get_model("notes", 2).unwrap().is_none());
+
+        db.set_caller(CallerContext::user("bob").with_role("auditor"));
+        assert_eq!(visible(&db).len(), 3);
+        db.clear_access_policy();
+        db.set_caller(CallerContext::anonymous());
+        assert_eq!(visible(&db).len(), 3);
+    }
+
+    #[test]
+    fn refused_creates_are_denied() {
+        let mut db = notes();
+        db.set_caller(CallerContext::user("ann"));
+        assert!(db.create_model("notes", note("ann")).is_ok());
+        assert!(matches!(
+            db.create_model("notes", note("bob")),
+            Err(KooError::AccessDenied { id: None, .. })
+        ));
+    }
+
+    #[test]
+    fn changes_are_checked_before_and_after() {
+        let mut db = notes();
+        db.set_caller(CallerContext::user("ann"));
+        assert!(db.update_model("notes", 1, note("ann")).unwrap());
+        // Giving a note away would leave it unwritable
+        assert!(matches!(
+            db.update_model("notes", 1, note("bob")),
+            Err(KooError::AccessDenied { id: Some(_), .. })
+        ));
+        // Models the caller can't see are as good as missing
+        assert!(!db.update_model("notes", 2, note("ann")).unwrap());
+        assert!(!db.delete_model("notes", 2).unwrap());
+
+        db.set_caller(CallerContext::user("bob").with_role("auditor"));
+        assert!(matches!(
+            db.delete_model("notes", 1),
+            Err(KooError::AccessDenied { id: Some(_), .. })
+        ));
+        assert!(db.delete_model("notes", 2).unwrap());
+
+        db.clear_access_policy();
+        let owners = db.find(&Query::new("notes")).unwrap();
+        assert_eq!(owners.len(), 2);
+        assert_eq!(owners[0].get_str("owner").unwrap(), "ann");
+    }
+}
@@ -0,0 +1,353 @@
+use indexmap::IndexMap;
+use rusqlite::types::Value;
+use std::collections::HashMap;
+
+use crate::error::Result;
+use crate::flexible_database::{FieldType, FlexibleDatabase, PrimaryKey, Schema};
+
+// A column of a table being adopted, from pragma_table_xinfo
+struct AdoptedColumn {
+    name: String,
+    declared_type: String,
+    not_null: bool,
+    default: Option<String>,
+    // Position in the primary key, from 1, or 0
+    key_position: usize,
+}
+
+// Schemas for tables kooDB didn't create, so its API can be used over an
+// existing SQLite database. Adopted schemas only live in the handle: the
+// tables are left as they are and nothing is recorded in `_koo_schemas`.
+impl FlexibleDatabase {
+    // Open the SQLite file at `path`, load the schemas its catalog records,
+    // if it has one, and adopt the rest of its tables
+    pub fn adopt(path: &str) -> Result<FlexibleDatabase> {
+        let mut db = FlexibleDatabase::new(path)?;
+        let has_catalog: bool = db.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_koo_schemas')",
+            [],
+            |row| row.get(0),
+        )?;
+        if has_catalog {
+            db.load_schemas()?;
+        }
+        db.adopt_tables()?;
+        Ok(db)
+    }
+
+    // Define a schema for every table of the main database that has none,
+    // returning their names. Fields follow the declared column types by
+    // SQLite's affinity rules: BOOL columns are Boolean, INT ones Integer,
+    // CHAR, CLOB, TEXT and date ones Text, and REAL, FLOA, DOUB, NUMERIC
+    // and DECIMAL ones Real. A single-column foreign key to an adopted
+    // table's integer id is a Reference. Columns of other types and
+    // generated columns are left out.
+    //
+    // An `id` primary key becomes the model id, and any other primary key
+    // a composite key. Tables kooDB can't address, without a primary key,
+    // WITHOUT ROWID or keyed on a column it leaves out, aren't adopted, nor
+    // are tables whose schema wouldn't be valid.
+    pub fn adopt_tables(&mut self) -> Result<Vec<String>> {
+        let tables: Vec<String> = self
+            .conn
+            .prepare(
+                "SELECT name FROM pragma_table_list
+                 WHERE schema = 'main' AND type = 'table' AND NOT wr
+                   AND name NOT LIKE 'sqlite_%' AND name NOT LIKE '\\_koo\\_%' ESCAPE '\\'
+                 ORDER BY name",
+            )?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let mut adopted = IndexMap::new();
+        for table in tables.into_iter().filter(|table| !self.schemas.contains_key(table)) {
+            let columns = self.adopted_columns(&table)?;
+            if let Some(schema) = adopted_schema(&table, &columns) {
+                adopted.insert(table, schema);
+            }
+        }
+
+        // References need the keys of every adopted table
+        let integer_keyed: Vec<String> = self
+            .schemas
+            .values()
+            .chain(adopted.values())
+            .filter(|schema| schema.key == PrimaryKey::Integer)
+            .map(|schema| schema.name.clone())
+            .collect();
+        for (table, schema) in adopted.iter_mut() {
+            let foreign_keys: Vec<(i64, String, String, Option<String>)> = self
+                .conn
+                .prepare("SELECT id, \"table\", \"from\", \"to\" FROM pragma_foreign_key_list(?)")?
+                .query_map([table], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
+                .collect::<rusqlite::Result<_>>()?;
+            for (id, target, from, to) in &foreign_keys {
+                let single = foreign_keys.iter().filter(|(other, ..)| other == id).count() == 1;
+                let to_id = to.as_deref().is_none_or(|to| to == "id");
+                if single
+                    && to_id
+                    && integer_keyed.contains(target)
+                    && schema.fields.get(from) == Some(&FieldType::Integer)
+                {
+                    schema.fields.insert(from.clone(), FieldType::Reference(target.clone()));
+                }
+            }
+        }
+
+        let mut names = Vec::with_capacity(adopted.len());
+        for (table, schema) in adopted {
+            // Such as one with a default that doesn't fit its column's type
+            let Ok(schema) = schema.materialize() else {
+                continue;
+            };
+            self.schemas.insert(table.clone(), schema);
+            names.push(table);
+        }
+        Ok(names)
+    }
+
+    fn adopted_columns(&self, table: &str) -> Result<Vec<AdoptedColumn>> {
+        let columns = self
+            .conn
+            .prepare("SELECT name, type, \"notnull\", dflt_value, pk FROM pragma_table_xinfo(?) WHERE hidden = 0")?
+            .query_map([table], |row| {
+                Ok(AdoptedColumn {
+                    name: row.get(0)?,
+                    declared_type: row.get(1)?,
+                    not_null: row.get(2)?,
+                    default: row.get(3)?,
+                    key_position: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(columns)
+    }
+}
+
+// The schema of a table, if kooDB can address its rows
+fn adopted_schema(table: &str, columns: &[AdoptedColumn]) -> Option<Schema> {
+    let mut key_columns: Vec<&AdoptedColumn> = columns.iter().filter(|column| column.key_position > 0).collect();
+    key_columns.sort_by_key(|column| column.key_position);
+    let key = match key_columns.as_slice() {
+        [] => return None,
+        // Only a column declared exactly INTEGER is the rowid
+        [column] if column.name == "id" && column.declared_type.eq_ignore_ascii_case("INTEGER") => PrimaryKey::Integer,
+        [column] if column.name == "id" && adopted_type(&column.declared_type) == Some(FieldType::Text) => {
+            PrimaryKey::Text
+        }
+        _ if key_columns.iter().any(|column| column.name == "id") => return None,
+        _ => PrimaryKey::Composite(key_columns.iter().map(|column| column.name.clone()).collect()),
+    };
+
+    let mut fields = Vec::new();
+    let mut defaults = HashMap::new();
+    let mut nullable = Vec::new();
+    for column in columns {
+        if column.name == "id" && key.has_id_column() {
+            continue;
+        }
+        let Some(field_type) = adopted_type(&column.declared_type) else {
+            if column.key_position > 0 {
+                return None;
+            }
+            continue;
+        };
+        // Nullable columns are optional, unless they are part of the key or
+        // have a default that would be lost by inserting NULL
+        let default = column.default.as_deref().map(default_value);
+        if !column.not_null && column.key_position == 0 && !matches!(default, Some(None)) {
+            nullable.push(column.name.clone());
+        }
+        if let Some(Some(default)) = default {
+            defaults.insert(column.name.clone(), default);
+        }
+        fields.push((column.name.clone(), field_type));
+    }
+
+    let mut schema = Schema::new(table, fields);
+    schema.key = key;
+    schema.defaults = defaults;
+    schema.nullable_fields = nullable;
+    Some(schema)
+}
+
+// Field type for a declared column type, by SQLite's affinity rules
+fn adopted_type(declared_type: &str) -> Option<FieldType> {
+    let declared = declared_type.to_ascii_uppercase();
+    let field_type = if declared.contains("BOOL") {
+        FieldType::Boolean
+    } else if declared.contains("INT") {
+        FieldType::Integer
+    } else if ["CHAR", "CLOB", "TEXT", "DATE", "TIME"]
+        .iter()
+        .any(|t| declared.contains(t))
+    {
+        FieldType::Text
+    } else if ["REAL", "FLOA", "DOUB", "NUMERIC", "DECIMAL"]
+        .iter()
+        .any(|t| declared.contains(t))
+    {
+        FieldType::Real
+    } else {
+        return None;
+    };
+    Some(field_type)
+}
+
+// A column default written as a plain literal; expressions such as
+// CURRENT_TIMESTAMP can't be used, as kooDB inserts every field
+fn default_value(default: &str) -> Option<Value> {
+    let default = default.trim();
+    if default.eq_ignore_ascii_case("NULL") {
+        return Some(Value::Null);
+    }
+    if let Some(text) = default.strip_prefix('\'').and_then(|rest| rest.strip_suffix('\'')) {
+        return Some(Value::Text(text.replace("''", "'")));
+    }
+    if let Ok(i) = default.parse::<i64>() {
+        return Some(Value::Integer(i));
+    }
+    default.parse::<f64>().ok().map(Value::Real)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::flexible_database::{FieldDef, ModelId};
+    use crate::temp_file::TempFile;
+
+    fn database(sql: &str) -> FlexibleDatabase {
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        db.conn.execute_batch(sql).unwrap();
+        db
+    }
+
+    #[test]
+    fn columns_become_fields_by_their_affinity() {
+        let mut db = database(
+            "CREATE TABLE users (
+                id INTEGER PRIMARY KEY,
+                name VARCHAR(40) NOT NULL,
+                admin BOOLEAN NOT NULL DEFAULT 0,
+                balance DOUBLE,
+                joined DATETIME,
+                avatar BLOB,
+                status TEXT DEFAULT 'new',
+                created TEXT DEFAULT CURRENT_TIMESTAMP
+            );
+            INSERT INTO users (name, balance) VALUES ('ann', NULL);",
+        );
+        assert_eq!(db.adopt_tables().unwrap(), vec!["users"]);
+        let schema = &db.schemas["users"];
+        assert_eq!(schema.key, PrimaryKey::Integer);
+        let fields: Vec<(&str, &FieldType)> = schema.fields.iter().map(|(name, t)| (name.as_str(), t)).collect();
+        assert_eq!(
+            fields,
+            vec![
+                ("name", &FieldType::Text),
+                ("admin", &FieldType::Boolean),
+                ("balance", &FieldType::Real),
+                ("joined", &FieldType::Text),
+                ("status", &FieldType::Text),
+                ("created", &FieldType::Text),
+            ]
+        );
+        assert!(schema.is_nullable("balance") && schema.is_nullable("status") && !schema.is_nullable("name"));
+        // Inserting NULL would lose the default, which kooDB can't fill in
+        assert!(!schema.is_nullable("created") && !schema.defaults.contains_key("created"));
+
+        let ann = db.get_model("users", 1).unwrap().unwrap();
+        assert_eq!(ann.data["balance"], Value::Null);
+        assert_eq!(ann.data["status"], Value::Text("new".to_string()));
+        let data = HashMap::from([
+            ("name".to_string(), Value::Text("bob".to_string())),
+            ("created".to_string(), Value::Text("2026-01-01".to_string())),
+        ]);
+        let id = db.create_model("users", data).unwrap();
+        let bob = db.get_model("users", id).unwrap().unwrap();
+        assert_eq!(bob.data["admin"], Value::Integer(0));
+        // Adopted schemas aren't recorded
+        assert!(db.stored_schemas().unwrap().is_empty());
+    }
+
+    #[test]
+    fn foreign_keys_to_integer_ids_become_references() {
+        let mut db = database(
+            "CREATE TABLE authors (id INTEGER PRIMARY KEY, name TEXT NOT NULL);
+             CREATE TABLE books (
+                id INTEGER PRIMARY KEY,
+                author_id INTEGER NOT NULL REFERENCES authors (id),
+                editor TEXT REFERENCES authors (name)
+             );",
+        );
+        db.adopt_tables().unwrap();
+        let books = &db.schemas["books"];
+        assert_eq!(books.fields["author_id"], FieldType::Reference("authors".to_string()));
+        assert_eq!(books.fields["editor"], FieldType::Text);
+    }
+
+    #[test]
+    fn other_keys_are_text_or_composite() {
+        let mut db = database(
+            "CREATE TABLE countries (id TEXT PRIMARY KEY, name TEXT NOT NULL);
+             CREATE TABLE members (team INTEGER, person INTEGER, since TEXT, PRIMARY KEY (person, team));
+             INSERT INTO countries VALUES ('nz', 'New Zealand');",
+        );
+        db.adopt_tables().unwrap();
+        assert_eq!(db.schemas["countries"].key, PrimaryKey::Text);
+        let nz = db
+            .get_model("countries", ModelId::Text("nz".to_string()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(nz.data["name"], Value::Text("New Zealand".to_string()));
+        assert_eq!(
+            db.schemas["members"].key,
+            PrimaryKey::Composite(vec!["person".to_string(), "team".to_string()])
+        );
+    }
+
+    #[test]
+    fn unaddressable_tables_are_left_alone() {
+        let mut db = database(
+            "CREATE TABLE log (line TEXT);
+             CREATE TABLE pairs (a INTEGER, b INTEGER, PRIMARY KEY (a, b)) WITHOUT ROWID;
+             CREATE TABLE files (digest BLOB PRIMARY KEY, size INTEGER);
+             CREATE TABLE wide (id INT PRIMARY KEY, x TEXT);
+             CREATE TABLE odd (id INTEGER PRIMARY KEY, n INTEGER DEFAULT 'many');",
+        );
+        assert_eq!(db.adopt_tables().unwrap(), Vec::<String>::new());
+        assert!(db.schemas.is_empty());
+    }
+
+    #[test]
+    fn adopting_a_file_loads_its_catalog_first() {
+        let file = TempFile::new("db");
+        let mut db = FlexibleDatabase::new(file.path()).unwrap();
+        let notes = Schema::new("notes", []).field("body", FieldDef::new(FieldType::Text).encrypted());
+        db.define_schema(notes).unwrap();
+        db.conn
+            .execute_batch("CREATE TABLE legacy (id INTEGER PRIMARY KEY, value REAL NOT NULL)")
+            .unwrap();
+        drop(db);
+
+        let db = FlexibleDatabase::adopt(file.path()).unwrap();
+        let mut names: Vec<&String> = db.schemas.keys().collect();
+        names.sort();
+        assert_eq!(names, ["legacy", "notes"]);
+        // The recorded definition wins over what the table would suggest
+        assert_eq!(db.schemas["notes"].encrypted_fields, vec!["body".to_string()]);
+    }
+
+    #[test]
+    fn defaults_are_read_as_literals() {
+        assert_eq!(default_value("NULL"), Some(Value::Null));
+        assert_eq!(default_value(" 'it''s' "), Some(Value::Text("it's".to_string())));
+        assert_eq!(default_value("42"), Some(Value::Integer(42)));
+        assert_eq!(default_value("-1.5"), Some(Value::Real(-1.5)));
+        assert_eq!(default_value("CURRENT_TIMESTAMP"), None);
+        assert_eq!(adopted_type("UNSIGNED BIG INT"), Some(FieldType::Integer));
+        assert_eq!(adopted_type("NVARCHAR(10)"), Some(FieldType::Text));
+        assert_eq!(adopted_type(""), None);
+    }
+}
@@ -0,0 +1,594 @@
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FieldType, FlexibleDatabase, PrimaryKey, Schema, VERSION_COLUMN, table_definition};
+use crate::tree::PARENT_FIELD;
+
+// First SQLite versions with ALTER TABLE ... RENAME COLUMN and DROP COLUMN
+const RENAME_COLUMN_VERSION: i32 = 3_025_000;
+const DROP_COLUMN_VERSION: i32 = 3_035_000;
+
+// What `drop_schema` does with the table behind the schema
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropBehavior {
+    // Forget the schema but leave the table and its rows in place, so it
+    // can be defined again later
+    KeepData,
+    // Drop the table, its rows and its full-text index
+    DropTable,
+}
+
+impl FlexibleDatabase {
+    // Remove a schema from the registry. Schemas whose reference fields
+    // point at it must be dropped or changed first when the table goes too.
+    pub fn drop_schema(&mut self, schema_name: &str, behavior: DropBehavior) -> Result<()> {
+        self.schema_or_err(schema_name)?;
+
+        if behavior == DropBehavior::DropTable
+            && let Some(referrer) = self.referring_schema(schema_name)
+        {
+            return Err(KooError::InvalidSchema(format!(
+                "'{}' is referenced by '{}'",
+                schema_name, referrer
+            )));
+        }
+
+        self.in_transaction(|db| {
+            if behavior == DropBehavior::DropTable {
+                db.drop_fts_index(schema_name)?;
+                db.drop_history_triggers(schema_name)?;
+                db.drop_changelog_triggers(schema_name)?;
+                db.drop_change_triggers(schema_name)?;
+                db.drop_append_only_triggers(schema_name)?;
+                for join_table in db.schemas[schema_name].many_to_many.values() {
+                    db.conn.execute(&format!("DROP TABLE IF EXISTS {}", join_table), [])?;
+                }
+                db.conn.execute(&format!("DROP TABLE {}", schema_name), [])?;
+                db.conn
+                    .execute(&format!("DROP TABLE IF EXISTS {}_history", schema_name), [])?;
+            }
+            db.schemas.remove(schema_name);
+            db.forget_schema(schema_name)
+        })?;
+        self.flush_statement_cache();
+        Ok(())
+    }
+
+    // Rename a schema and its table. Reference fields of other schemas are
+    // pointed at the new name, as are the polymorphic references they
+    // hold, and the indexes, history and full-text index follow it, so
+    // the old name is free to define again.
+    pub fn rename_schema(&mut self, old_name: &str, new_name: &str) -> Result<()> {
+        self.schema_or_err(old_name)?;
+        if self.schemas.contains_key(new_name) {
+            return Err(KooError::InvalidSchema(format!("schema '{}' already exists", new_name)));
+        }
+
+        self.in_transaction(|db| {
+            let mut schema = db.schemas.remove(old_name).expect("schema is registered");
+            // Field indexes are named after the schema
+            db.drop_field_indexes(&schema)?;
+            // The FTS table names its content table, so it can't follow a rename
+            if !schema.fts_fields.is_empty() {
+                db.drop_fts_index(old_name)?;
+            }
+            db.drop_history_triggers(old_name)?;
+            db.drop_changelog_triggers(old_name)?;
+            db.drop_change_triggers(old_name)?;
+            db.drop_append_only_triggers(old_name)?;
+            // They follow the table but keep their names, which defining a
+            // schema under the old name would need
+            db.conn.execute_batch(&format!(
+                "DROP INDEX IF EXISTS {old_name}_tenant;
+                DROP INDEX IF EXISTS {old_name}_parent;"
+            ))?;
+            db.conn
+                .execute(&format!("ALTER TABLE {} RENAME TO {}", old_name, new_name), [])?;
+
+            schema.name = new_name.to_string();
+            db.rename_history(old_name, &schema)?;
+            if schema.tenant_scoped {
+                db.create_tenant_index(&schema)?;
+            }
+            if schema.hierarchical {
+                db.create_parent_index(&schema)?;
+            }
+            db.create_field_indexes(&schema)?;
+            if !schema.fts_fields.is_empty() {
+                db.create_fts_index(&schema)?;
+            }
+            if schema.history {
+                db.create_history(&schema)?;
+            }
+            db.refresh_changelog(&schema)?;
+            db.refresh_change_triggers(&schema)?;
+            db.refresh_append_only_triggers(&schema)?;
+            db.schemas.insert(new_name.to_string(), schema);
+
+            // SQLite rewrites the REFERENCES clauses itself
+            for schema in db.schemas.values_mut() {
+                for field_type in schema.fields.values_mut() {
+                    retarget(field_type, old_name, new_name);
+                }
+                schema.many_to_many = std::mem::take(&mut schema.many_to_many)
+                    .into_iter()
+                    .map(|(target, join_table)| match target == old_name {
+                        true => (new_name.to_string(), join_table),
+                        false => (target, join_table),
+                    })
+                    .collect();
+            }
+            // Polymorphic references name the schema in every value
+            for schema in db.schemas.values() {
+                for (field_name, field_type) in &schema.fields {
+                    let FieldType::Polymorphic(targets) = field_type else {
+                        continue;
+                    };
+                    if !targets.iter().any(|target| target == new_name) || schema.encrypted_fields.contains(field_name)
+                    {
+                        continue;
+                    }
+                    db.conn.execute(
+                        &format!(
+                            "UPDATE {table} SET {field} = json_array(?2, json_extract({field}, '$[1]'))
+                             WHERE CASE WHEN json_valid({field}) THEN json_extract({field}, '$[0]') END = ?1",
+                            table = schema.name,
+                            field = field_name
+                        ),
+                        [old_name, new_name],
+                    )?;
+                }
+            }
+            db.forget_schema(old_name)?;
+            for schema in db.schemas.values() {
+                db.record_schema(schema)?;
+            }
+            Ok(())
+        })?;
+        self.flush_statement_cache();
+        Ok(())
+    }
+
+    // Rename a field, keeping its data. Older SQLite versions without
+    // RENAME COLUMN get the table rebuilt instead.
+    pub fn rename_field(&mut self, schema_name: &str, old_name: &str, new_name: &str) -> Result<()> {
+        let schema = self.alterable_field(schema_name, old_name)?;
+        if schema.fields.contains_key(new_name) || new_name == "id" || (schema.versioned && new_name == VERSION_COLUMN)
+        {
+            return Err(KooError::InvalidSchema(format!(
+                "'{}' already has a column named '{}'",
+                schema_name, new_name
+            )));
+        }
+
+        let mut renamed = schema.clone();
+        // Keep the field's place in the column order
+        let (index, _, field_type) = renamed.fields.shift_remove_full(old_name).expect("field was checked");
+        renamed.fields.shift_insert(index, new_name.to_string(), field_type);
+        if let Some(default) = renamed.defaults.remove(old_name) {
+            renamed.defaults.insert(new_name.to_string(), default);
+        }
+        if let Some(validators) = renamed.validators.remove(old_name) {
+            renamed.validators.insert(new_name.to_string(), validators);
+        }
+        if let Some(expression) = renamed.computed_fields.remove(old_name) {
+            renamed.computed_fields.insert(new_name.to_string(), expression);
+        }
+        if let Some(expiry) = &mut renamed.expiry {
+            for field_name in std::iter::once(&mut expiry.field).chain(expiry.flag.as_mut()) {
+                if field_name == old_name {
+                    *field_name = new_name.to_string();
+                }
+            }
+        }
+        for field_name in renamed
+            .fts_fields
+            .iter_mut()
+            .chain(renamed.encrypted_fields.iter_mut())
+            .chain(renamed.nullable_fields.iter_mut())
+            .chain(renamed.indexed_fields.iter_mut())
+            .chain(renamed.unique_fields.iter_mut())
+        {
+            if field_name == old_name {
+                *field_name = new_name.to_string();
+            }
+        }
+
+        let mut columns = data_columns(schema);
+        for column in columns.iter_mut() {
+            if column.0 == old_name {
+                column.1 = new_name.to_string();
+            }
+        }
+
+        let history = schema.history;
+        self.replace_schema(
+            renamed,
+            |db| {
+                if rusqlite::version_number() >= RENAME_COLUMN_VERSION {
+                    db.conn.execute(
+                        &format!("ALTER TABLE {} RENAME COLUMN {} TO {}", schema_name, old_name, new_name),
+                        [],
+                    )?;
+                    // Past states keep their values under the new name
+                    if history {
+                        db.conn.execute(
+                            &format!(
+                                "ALTER TABLE {}_history RENAME COLUMN {} TO {}",
+                                schema_name, old_name, new_name
+                            ),
+                            [],
+                        )?;
+                    }
+                    Ok(false)
+                } else {
+                    Ok(true)
+                }
+            },
+            &columns,
+        )
+    }
+
+    // Remove a field and its data. Reference fields, and every field on
+    // SQLite versions without DROP COLUMN, are removed by rebuilding the
+    // table; indexes created outside kooDB don't survive a rebuild.
+    pub fn drop_field(&mut self, schema_name: &str, field_name: &str) -> Result<()> {
+        let schema = self.alterable_field(schema_name, field_name)?;
+        if let PrimaryKey::Composite(key_fields) = &schema.key
+            && key_fields.iter().any(|f| f == field_name)
+        {
+            return Err(KooError::InvalidSchema(format!(
+                "'{}.{}' is part of the primary key",
+                schema_name, field_name
+            )));
+        }
+        if schema.expiry.as_ref().is_some_and(|expiry| expiry.uses(field_name)) {
+            return Err(KooError::InvalidSchema(format!(
+                "'{}.{}' is used to expire models",
+                schema_name, field_name
+            )));
+        }
+
+        let mut dropped = schema.clone();
+        let field_type = dropped.fields.shift_remove(field_name).expect("field was checked");
+        dropped.defaults.remove(field_name);
+        dropped.validators.remove(field_name);
+        dropped.computed_fields.remove(field_name);
+        dropped.fts_fields.retain(|f| f != field_name);
+        dropped.encrypted_fields.retain(|f| f != field_name);
+        dropped.nullable_fields.retain(|f| f != field_name);
+        dropped.indexed_fields.retain(|f| f != field_name);
+        dropped.unique_fields.retain(|f| f != field_name);
+
+        let columns: Vec<(String, String)> = data_columns(schema)
+            .into_iter()
+            .filter(|(column, _)| column != field_name)
+            .collect();
+        let is_reference = matches!(field_type, FieldType::Reference(_));
+
+        self.replace_schema(
+            dropped,
+            |db| {
+                if rusqlite::version_number() >= DROP_COLUMN_VERSION && !is_reference {
+                    db.conn
+                        .execute(&format!("ALTER TABLE {} DROP COLUMN {}", schema_name, field_name), [])?;
+                    Ok(false)
+                } else {
+                    Ok(true)
+                }
+            },
+            &columns,
+        )
+    }
+
+    // Schema owning a field that can be renamed or dropped. Fields mixed in
+    // from templates have to change in the template instead.
+    fn alterable_field(&self, schema_name: &str, field_name: &str) -> Result<&Schema> {
+        let schema = self.schema_or_err(schema_name)?;
+        if !schema.fields.contains_key(field_name) {
+            return Err(KooError::UnknownField {
+                schema: schema_name.to_string(),
+                field: field_name.to_string(),
+            });
+        }
+        if schema.hierarchical && field_name == PARENT_FIELD {
+            return Err(KooError::InvalidSchema(format!(
+                "'{}.{}' holds the parent of each model",
+                schema_name, field_name
+            )));
+        }
+        // The expression would be left naming a column that is gone
+        if let Some((computed, _)) = schema
+            .computed_fields
+            .iter()
+            .find(|(computed, expression)| *computed != field_name && mentions(expression, field_name))
+        {
+            return Err(KooError::InvalidSchema(format!(
+                "'{}.{}' is used to compute '{}'",
+                schema_name, field_name, computed
+            )));
+        }
+        for template in &schema.templates {
+            if template.materialize()?.fields.contains_key(field_name) {
+                return Err(KooError::InvalidSchema(format!(
+                    "'{}.{}' comes from template '{}'",
+                    schema_name, field_name, template.name
+                )));
+            }
+        }
+        Ok(schema)
+    }
+
+    // Swap in the altered definition of a schema. `alter` changes the table
+    // in place and returns whether it still needs rebuilding, in which case
+    // the rows are copied into a new table, mapping each (old, new) column
+    // in `columns`. The full-text index is recreated over the new layout.
+    pub(crate) fn replace_schema(
+        &mut self,
+        schema: Schema,
+        alter: impl FnOnce(&mut FlexibleDatabase) -> Result<bool>,
+        columns: &[(String, String)],
+    ) -> Result<()> {
+        let table = schema.name.clone();
+        self.in_transaction(|db| {
+            // Indexed columns can't be dropped, and the indexes go with
+            // the table when it is rebuilt
+            let current = db.schemas[&table].clone();
+            db.drop_field_indexes(&current)?;
+            db.drop_fts_index(&table)?;
+            db.drop_history_triggers(&table)?;
+            db.drop_changelog_triggers(&table)?;
+            db.drop_change_triggers(&table)?;
+            db.drop_append_only_triggers(&table)?;
+
+            if alter(db)? {
+                let rebuilt = format!("{}_koo_rebuild", table);
+                let old_columns: Vec<&str> = columns.iter().map(|c| c.0.as_str()).collect();
+                let new_columns: Vec<&str> = columns.iter().map(|c| c.1.as_str()).collect();
+                // References into the table are dangling between the DROP
+                // and the RENAME, so they are checked at commit instead
+                db.conn.execute_batch(&format!(
+                    "PRAGMA defer_foreign_keys = ON;
+                    CREATE TABLE {};
+                    INSERT INTO {rebuilt} ({}) SELECT {} FROM {table};
+                    DROP TABLE {table};
+                    ALTER TABLE {rebuilt} RENAME TO {table};",
+                    table_definition(&schema, &rebuilt),
+                    new_columns.join(", "),
+                    old_columns.join(", "),
+                ))?;
+            }
+
+            if !schema.fts_fields.is_empty() {
+                db.create_fts_index(&schema)?;
+            }
+            if schema.tenant_scoped {
+                db.create_tenant_index(&schema)?;
+            }
+            if schema.hierarchical {
+                db.create_parent_index(&schema)?;
+            }
+            db.create_field_indexes(&schema)?;
+            if schema.history {
+                db.create_history(&schema)?;
+            }
+            db.refresh_changelog(&schema)?;
+            db.refresh_change_triggers(&schema)?;
+            db.refresh_append_only_triggers(&schema)?;
+            db.record_schema(&schema)?;
+            db.schemas.insert(table.clone(), schema);
+            Ok(())
+        })?;
+        self.flush_statement_cache();
+        Ok(())
+    }
+
+    // Another schema with a reference field pointing at `schema_name`
+    fn referring_schema(&self, schema_name: &str) -> Option<&str> {
+        self.schemas
+            .values()
+            .filter(|schema| schema.name != schema_name)
+            .find(|schema| {
+                schema.many_to_many.contains_key(schema_name)
+                    || schema.fields.values().any(|field_type| match field_type {
+                        FieldType::Reference(target) => target == schema_name,
+                        FieldType::Polymorphic(targets) => targets.iter().any(|target| target == schema_name),
+                        _ => false,
+                    })
+            })
+            .map(|schema| schema.name.as_str())
+    }
+}
+
+fn retarget(field_type: &mut FieldType, old_name: &str, new_name: &str) {
+    match field_type {
+        FieldType::Reference(target) if target == old_name => *target = new_name.to_string(),
+        FieldType::Polymorphic(targets) => {
+            for target in targets.iter_mut().filter(|target| *target == old_name) {
+                *target = new_name.to_string();
+            }
+        }
+        _ => {}
+    }
+}
+
+// Whether an SQL expression names the column, going by its words
+pub(crate) fn mentions(expression: &str, column: &str) -> bool {
+    expression
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .any(|word| word.eq_ignore_ascii_case(column))
+}
+
+// Every stored column of a schema, paired with itself. Computed columns
+// aren't stored, and are filled in by SQLite again.
+fn data_columns(schema: &Schema) -> Vec<(String, String)> {
+    let mut columns = Vec::new();
+    if schema.key.has_id_column() {
+        columns.push("id".to_string());
+    }
+    columns.extend(schema.fields.keys().filter(|f| !schema.is_computed(f)).cloned());
+    if schema.versioned {
+        columns.push(VERSION_COLUMN.to_string());
+    }
+    columns.into_iter().map(|c| (c.clone(), c)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::Value;
+
+    use crate::flexible_database::FieldDef;
+    use crate::model_builder::ModelBuilder;
+
+    fn people() -> Schema {
+        Schema::new("people", [("name".to_string(), FieldType::Text)])
+            .with_tenancy()
+            .with_hierarchy()
+            .with_history()
+            .with_fts(&["name"])
+            .with_index("name")
+    }
+
+    fn objects_named_after(db: &FlexibleDatabase, prefix: &str) -> Vec<String> {
+        let mut stmt = db
+            .conn
+            .prepare(
+                "SELECT name FROM sqlite_master WHERE name LIKE ? || '%' AND name NOT LIKE 'sqlite_%' ORDER BY name",
+            )
+            .unwrap();
+        stmt.query_map([prefix], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn renamed_schemas_take_their_indexes_and_history_along() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(people()).unwrap();
+        let data = ModelBuilder::new()
+            .text("name", "Ada")
+            .text("tenant_id", "acme")
+            .build();
+        let id = db.create_model("people", data).unwrap();
+
+        db.rename_schema("people", "folks").unwrap();
+        assert_eq!(objects_named_after(&db, "people"), Vec::<String>::new());
+        assert!(db.get_model("folks", &id).unwrap().is_some());
+        assert_eq!(db.search("folks", "Ada").unwrap().len(), 1);
+        let as_of = db.get_model_as_of("folks", &id, "9999-01-01 00:00:00").unwrap();
+        assert_eq!(as_of.unwrap().get_str("name").unwrap(), "Ada");
+
+        db.define_schema(people()).unwrap();
+        assert!(db.get_model("people", &id).unwrap().is_none());
+    }
+
+    #[test]
+    fn renaming_onto_a_defined_schema_is_refused() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(people()).unwrap();
+        db.define_schema(Schema::new("folks", [("name".to_string(), FieldType::Text)]))
+            .unwrap();
+        assert!(matches!(
+            db.rename_schema("people", "folks"),
+            Err(KooError::InvalidSchema(_))
+        ));
+        assert!(db.schemas.contains_key("people"));
+    }
+
+    fn books() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("books", [])
+            .field("title", FieldDef::new(FieldType::Text).indexed())
+            .field("pages", FieldDef::new(FieldType::Integer).with_default(100))
+            .field("blurb", FieldDef::new(FieldType::Text).nullable())
+            .with_fts(&["title"]);
+        db.define_schema(schema).unwrap();
+        let data = ModelBuilder::new().text("title", "Dune").set("pages", 412).build();
+        db.create_model("books", data).unwrap();
+        db
+    }
+
+    #[test]
+    fn renamed_fields_keep_their_data_place_and_settings() {
+        let mut db = books();
+        db.rename_field("books", "title", "name").unwrap();
+
+        let schema = &db.schemas["books"];
+        let fields: Vec<&str> = schema.fields.keys().map(String::as_str).collect();
+        assert_eq!(fields, ["name", "pages", "blurb"]);
+        assert_eq!(schema.fts_fields, ["name"]);
+        assert_eq!(schema.indexed_fields, ["name"]);
+        let model = db.get_model("books", 1).unwrap().unwrap();
+        assert_eq!(model.get_str("name").unwrap(), "Dune");
+        assert!(!model.data.contains_key("title"));
+        assert_eq!(db.search("books", "Dune").unwrap().len(), 1);
+
+        // The definition stored in the database follows along
+        db.schemas.clear();
+        db.load_schemas().unwrap();
+        assert!(db.schemas["books"].fields.contains_key("name"));
+    }
+
+    #[test]
+    fn renaming_onto_an_existing_column_is_refused() {
+        let mut db = books();
+        for taken in ["pages", "id"] {
+            assert!(matches!(
+                db.rename_field("books", "title", taken),
+                Err(KooError::InvalidSchema(_))
+            ));
+        }
+        assert!(matches!(
+            db.rename_field("books", "missing", "other"),
+            Err(KooError::UnknownField { .. })
+        ));
+    }
+
+    #[test]
+    fn dropped_fields_lose_their_data_and_settings() {
+        let mut db = books();
+        db.drop_field("books", "pages").unwrap();
+        assert!(!db.schemas["books"].fields.contains_key("pages"));
+        assert!(!db.schemas["books"].defaults.contains_key("pages"));
+        let model = db.get_model("books", 1).unwrap().unwrap();
+        assert!(!model.data.contains_key("pages"));
+        assert_eq!(model.get_str("title").unwrap(), "Dune");
+
+        // Indexed fields are dropped too, with their index
+        db.drop_field("books", "title").unwrap();
+        assert!(db.schemas["books"].indexed_fields.is_empty());
+        assert!(db.schemas["books"].fts_fields.is_empty());
+    }
+
+    #[test]
+    fn dropping_reference_fields_rebuilds_the_table() {
+        let mut db = books();
+        db.define_schema(
+            Schema::new("reviews", [("stars".to_string(), FieldType::Integer)])
+                .field("book", FieldDef::new(FieldType::Reference("books".to_string()))),
+        )
+        .unwrap();
+        let data = ModelBuilder::new().set("stars", 5).set("book", 1).build();
+        let id = db.create_model("reviews", data).unwrap();
+        db.drop_field("reviews", "book").unwrap();
+        let model = db.get_model("reviews", id).unwrap().unwrap();
+        assert_eq!(model.data.get("stars"), Some(&Value::Integer(5)));
+        assert!(!model.data.contains_key("book"));
+        db.delete_model("books", 1).unwrap();
+    }
+
+    #[test]
+    fn key_fields_cannot_be_dropped() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new(
+            "pairs",
+            [
+                ("a".to_string(), FieldType::Integer),
+                ("b".to_string(), FieldType::Integer),
+            ],
+        )
+        .with_key(PrimaryKey::Composite(vec!["a".to_string(), "b".to_string()]));
+        db.define_schema(schema).unwrap();
+        assert!(matches!(db.drop_field("pairs", "a"), Err(KooError::InvalidSchema(_))));
+    }
+}
@@ -0,0 +1,137 @@
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FlexibleDatabase, Schema};
+
+// Refuse to change the models of an append-only schema
+pub(crate) fn check_mutable(schema: &Schema) -> Result<()> {
+    if schema.append_only {
+        return Err(KooError::InvalidSchema(format!(
+            "schema '{}' is append-only",
+            schema.name
+        )));
+    }
+    Ok(())
+}
+
+// Triggers holding append-only schemas to it in SQL too, for those that
+// also ask for SQL checks. The API refuses the same writes before they
+// reach SQLite, so only statements that bypass kooDB ever set them off.
+impl FlexibleDatabase {
+    pub(crate) fn refresh_append_only_triggers(&self, schema: &Schema) -> Result<()> {
+        // Triggers of attached tables would have to live in their file
+        let table = &schema.name;
+        if table.contains('.') {
+            return Ok(());
+        }
+        self.drop_append_only_triggers(table)?;
+        if !schema.append_only || !schema.sql_checks {
+            return Ok(());
+        }
+        self.conn.execute_batch(&format!(
+            "CREATE TRIGGER {table}_append_only_bu BEFORE UPDATE ON {table} BEGIN
+                SELECT RAISE(ABORT, 'schema ''{table}'' is append-only');
+            END;
+            CREATE TRIGGER {table}_append_only_bd BEFORE DELETE ON {table} BEGIN
+                SELECT RAISE(ABORT, 'schema ''{table}'' is append-only');
+            END;"
+        ))?;
+        Ok(())
+    }
+
+    pub(crate) fn drop_append_only_triggers(&self, table: &str) -> Result<()> {
+        self.conn.execute_batch(&format!(
+            "DROP TRIGGER IF EXISTS {table}_append_only_bu;
+            DROP TRIGGER IF EXISTS {table}_append_only_bd;"
+        ))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::Value;
+    use std::collections::HashMap;
+
+    use crate::alter::DropBehavior;
+    use crate::flexible_database::{FieldType, ModelId};
+
+    fn events(schema: Schema) -> (FlexibleDatabase, ModelId) {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(schema).unwrap();
+        let id = db.create_model("events", event("created")).unwrap();
+        (db, id)
+    }
+
+    fn event(kind: &str) -> HashMap<String, Value> {
+        HashMap::from([
+            ("kind".to_string(), Value::Text(kind.to_string())),
+            ("at".to_string(), Value::Integer(0)),
+        ])
+    }
+
+    fn log() -> Schema {
+        Schema::new(
+            "events",
+            [
+                ("kind".to_string(), FieldType::Text),
+                ("at".to_string(), FieldType::Integer),
+            ],
+        )
+        .with_append_only()
+    }
+
+    #[test]
+    fn models_can_be_added_but_not_changed() {
+        let (db, id) = events(log());
+        db.create_model("events", event("renamed")).unwrap();
+        assert!(matches!(
+            db.update_model("events", id.clone(), event("edited")),
+            Err(KooError::InvalidSchema(_))
+        ));
+        assert!(matches!(
+            db.delete_model("events", id.clone()),
+            Err(KooError::InvalidSchema(_))
+        ));
+        assert!(matches!(
+            db.increment("events", id.clone(), "at", 1),
+            Err(KooError::InvalidSchema(_))
+        ));
+
+        let stored = db.get_model("events", id).unwrap().unwrap();
+        assert_eq!(stored.data["kind"], Value::Text("created".to_string()));
+        assert_eq!(db.count("events").unwrap(), 2);
+    }
+
+    #[test]
+    fn sql_checks_add_triggers() {
+        let (db, _) = events(log());
+        db.execute_raw("UPDATE events SET kind = 'raw'", &[]).unwrap();
+
+        let (mut db, _) = events(log().with_sql_checks());
+        assert!(db.execute_raw("UPDATE events SET kind = 'raw'", &[]).is_err());
+        assert!(db.execute_raw("DELETE FROM events", &[]).is_err());
+        db.execute_raw("INSERT INTO events (kind, at) VALUES ('raw', 1)", &[])
+            .unwrap();
+
+        // The triggers follow the table
+        db.rename_schema("events", "history").unwrap();
+        assert!(db.execute_raw("DELETE FROM history", &[]).is_err());
+        db.drop_schema("history", DropBehavior::DropTable).unwrap();
+        let triggers: i64 = db
+            .conn
+            .query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'trigger'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(triggers, 0);
+    }
+
+    #[test]
+    fn append_only_models_never_expire() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        assert!(matches!(
+            db.define_schema(log().with_expiry("at")),
+            Err(KooError::InvalidSchema(_))
+        ));
+    }
+}
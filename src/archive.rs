@@ -0,0 +1,336 @@
+use rusqlite::types::Value;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::append_only::check_mutable;
+use crate::audit::AuditAction;
+use crate::copy::mapped_columns;
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FieldType, FlexibleDatabase, Schema, VERSION_COLUMN, row_key};
+
+// Rows moved per transaction when the policy doesn't say
+const DEFAULT_BATCH_SIZE: usize = 1000;
+
+// Which models `archive` moves: those whose `field` holds a time further
+// back than `older_than`. The field is text in any format SQLite's date
+// functions read, such as "2024-05-01 12:00:00", or an integer count of
+// seconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchivePolicy {
+    field: String,
+    older_than: Duration,
+    batch_size: usize,
+}
+
+impl ArchivePolicy {
+    pub fn older_than(field_name: &str, age: Duration) -> ArchivePolicy {
+        ArchivePolicy {
+            field: field_name.to_string(),
+            older_than: age,
+            batch_size: DEFAULT_BATCH_SIZE,
+        }
+    }
+
+    // Move at most this many models per transaction, so other writers get
+    // a turn between batches
+    pub fn batch_size(mut self, batch_size: usize) -> ArchivePolicy {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+}
+
+// Reported after each batch `archive` commits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveProgress {
+    // Models moved by this batch
+    pub batch: usize,
+    // Models moved so far
+    pub archived: usize,
+}
+
+impl FlexibleDatabase {
+    // Move the models of `schema_name` that `policy` matches into `target`,
+    // a schema with the same fields and key, e.g. "orders_archive" or
+    // "archive.orders" in an attached file. Each batch is copied and
+    // deleted in one transaction, so a model is never in both schemas or
+    // neither; a failure stops the run, keeping the batches already
+    // committed. Like `copy_rows`, rows move as they are, without the
+    // access policy; with auditing enabled each move is recorded as a create
+    // in `target` and a delete from `schema_name`. Returns the number of
+    // models moved.
+    pub fn archive(
+        &mut self,
+        schema_name: &str,
+        policy: &ArchivePolicy,
+        target: &str,
+        mut progress: impl FnMut(ArchiveProgress),
+    ) -> Result<usize> {
+        let source = self.schema_or_err(schema_name)?;
+        check_mutable(source)?;
+        let destination = self.schema_or_err(target)?;
+        if source.key != destination.key || source.versioned != destination.versioned {
+            return Err(KooError::InvalidSchema(format!(
+                "'{}' and '{}' are keyed differently",
+                schema_name, target
+            )));
+        }
+
+        let mut mapping = Vec::new();
+        if source.key.has_id_column() {
+            mapping.push(("id", "id"));
+        }
+        for field_name in source.fields.keys().filter(|f| !source.is_computed(f)) {
+            mapping.push((field_name.as_str(), field_name.as_str()));
+        }
+        let (mut source_columns, mut target_columns) = mapped_columns(source, destination, &mapping)?;
+        if source.versioned {
+            source_columns.push(VERSION_COLUMN.to_string());
+            target_columns.push(VERSION_COLUMN.to_string());
+        }
+
+        // Worked out once, so every batch, and both statements of each,
+        // agree on which models are old enough
+        let (condition, cutoff) = self.archive_condition(source, policy)?;
+        let rowid = row_key(source);
+        let batch = format!(
+            "{rowid} IN (SELECT {rowid} FROM {table} WHERE {condition} ORDER BY {rowid} LIMIT {limit})",
+            table = source.name,
+            limit = policy.batch_size
+        );
+        let copy = format!(
+            "INSERT INTO {} ({}) SELECT {} FROM {} WHERE {}",
+            destination.name,
+            target_columns.join(", "),
+            source_columns.join(", "),
+            source.name,
+            batch
+        );
+        let delete = format!("DELETE FROM {} WHERE {}", source.name, batch);
+
+        let (source, destination) = (source.clone(), destination.clone());
+        let mut archived = 0;
+        loop {
+            let moved = self.in_transaction(|db| {
+                let params = [cutoff.clone()];
+                let copied = db.traced(&copy, 1, || {
+                    let copied = db.audit_inserts(&destination, &copy, &params)?;
+                    Ok((copied, copied))
+                })?;
+                let deleted = db.traced(&delete, 1, || {
+                    let deleted = db.audit_rows(&source, &batch, &params, AuditAction::Delete, || {
+                        Ok(db.conn.execute(&delete, [&cutoff])?)
+                    })?;
+                    Ok((deleted, deleted))
+                })?;
+                if copied != deleted {
+                    return Err(KooError::InvalidData(format!(
+                        "archiving '{}' copied {} models but deleted {}",
+                        schema_name, copied, deleted
+                    )));
+                }
+                Ok(deleted)
+            })?;
+            if moved == 0 {
+                break;
+            }
+            archived += moved;
+            progress(ArchiveProgress { batch: moved, archived });
+        }
+        Ok(archived)
+    }
+
+    // Condition on the policy's field, with the cutoff time it compares to
+    fn archive_condition(&self, schema: &Schema, policy: &ArchivePolicy) -> Result<(String, Value)> {
+        let field_type = schema.fields.get(&policy.field).ok_or_else(|| KooError::UnknownField {
+            schema: schema.name.clone(),
+            field: policy.field.clone(),
+        })?;
+        let age = policy.older_than.as_secs_f64();
+        match field_type {
+            FieldType::Text => {
+                let cutoff: f64 =
+                    self.conn
+                        .query_row("SELECT julianday('now', ?)", [format!("-{} seconds", age)], |row| {
+                            row.get(0)
+                        })?;
+                Ok((format!("julianday({}) < ?", policy.field), Value::Real(cutoff)))
+            }
+            FieldType::Integer => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                let cutoff = now.saturating_sub(policy.older_than).as_secs() as i64;
+                Ok((format!("{} < ?", policy.field), Value::Integer(cutoff)))
+            }
+            _ => Err(KooError::InvalidSchema(format!(
+                "'{}.{}' is {}, not a time to archive by",
+                schema.name,
+                policy.field,
+                field_type.name()
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::flexible_database::{FieldDef, PrimaryKey};
+
+    fn now() -> i64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+    }
+
+    fn day() -> Duration {
+        Duration::from_secs(86_400)
+    }
+
+    fn schema(name: &str, placed: FieldType) -> Schema {
+        Schema::new(name, [])
+            .field("label", FieldDef::new(FieldType::Text))
+            .field("placed", FieldDef::new(placed))
+    }
+
+    // Orders placed at the given times, and an empty archive for them
+    fn orders(placed: &[Value]) -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let placed_type = match placed.first() {
+            Some(Value::Text(_)) => FieldType::Text,
+            _ => FieldType::Integer,
+        };
+        db.define_schema(schema("orders", placed_type.clone())).unwrap();
+        db.define_schema(schema("orders_archive", placed_type)).unwrap();
+        for (i, time) in placed.iter().enumerate() {
+            let data = HashMap::from([
+                ("label".to_string(), Value::Text(format!("order {}", i + 1))),
+                ("placed".to_string(), time.clone()),
+            ]);
+            db.create_model("orders", data).unwrap();
+        }
+        db
+    }
+
+    fn ids(db: &FlexibleDatabase, schema_name: &str) -> Vec<i64> {
+        let mut statement = db
+            .conn
+            .prepare(&format!("SELECT id FROM {} ORDER BY id", schema_name))
+            .unwrap();
+        statement
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(|id| id.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn old_models_move_in_batches() {
+        let old = Value::Integer(now() - 10 * 86_400);
+        let recent = Value::Integer(now() - 60);
+        let mut db = orders(&[old.clone(), recent, old.clone(), old.clone(), old.clone(), old]);
+        let policy = ArchivePolicy::older_than("placed", day()).batch_size(2);
+
+        let mut reports = Vec::new();
+        let moved = db
+            .archive("orders", &policy, "orders_archive", |p| reports.push(p))
+            .unwrap();
+        assert_eq!(moved, 5);
+        let batches: Vec<(usize, usize)> = reports.iter().map(|p| (p.batch, p.archived)).collect();
+        assert_eq!(batches, [(2, 2), (2, 4), (1, 5)]);
+
+        assert_eq!(ids(&db, "orders"), [2]);
+        assert_eq!(ids(&db, "orders_archive"), [1, 3, 4, 5, 6]);
+        let moved = db.get_model("orders_archive", 3).unwrap().unwrap();
+        assert_eq!(moved.data["label"], Value::Text("order 3".to_string()));
+
+        // Nothing is left to move
+        assert_eq!(db.archive("orders", &policy, "orders_archive", |_| {}).unwrap(), 0);
+    }
+
+    #[test]
+    fn text_times_are_compared_as_dates() {
+        let times = ["2001-02-03 04:05:06", "2999-01-01 00:00:00", "2001-02-03"];
+        let mut db = orders(&times.map(|t| Value::Text(t.to_string())));
+        let policy = ArchivePolicy::older_than("placed", day());
+        assert_eq!(db.archive("orders", &policy, "orders_archive", |_| {}).unwrap(), 2);
+        assert_eq!(ids(&db, "orders"), [2]);
+    }
+
+    #[test]
+    fn a_failing_batch_keeps_the_ones_before_it() {
+        let old = Value::Integer(now() - 10 * 86_400);
+        let mut db = orders(&[old.clone(), old.clone(), old]);
+        db.execute_raw(
+            "INSERT INTO orders_archive (id, label, placed) VALUES (2, 'taken', 0)",
+            &[],
+        )
+        .unwrap();
+        let policy = ArchivePolicy::older_than("placed", day()).batch_size(1);
+
+        let mut reports = Vec::new();
+        assert!(
+            db.archive("orders", &policy, "orders_archive", |p| reports.push(p))
+                .is_err()
+        );
+        assert_eq!(reports.len(), 1);
+        // The clashing model stays put rather than being lost
+        assert_eq!(ids(&db, "orders"), [2, 3]);
+        assert_eq!(ids(&db, "orders_archive"), [1, 2]);
+    }
+
+    #[test]
+    fn targets_must_be_keyed_alike() {
+        let mut db = orders(&[Value::Integer(0)]);
+        db.define_schema(schema("by_code", FieldType::Integer).with_key(PrimaryKey::Text))
+            .unwrap();
+        let policy = ArchivePolicy::older_than("placed", day());
+        assert!(matches!(
+            db.archive("orders", &policy, "by_code", |_| {}),
+            Err(KooError::InvalidSchema(_))
+        ));
+        assert_eq!(ids(&db, "orders"), [1]);
+    }
+
+    #[test]
+    fn policies_need_a_time_field() {
+        let mut db = orders(&[Value::Integer(0)]);
+        let archive = |db: &mut FlexibleDatabase, field: &str| {
+            db.archive(
+                "orders",
+                &ArchivePolicy::older_than(field, day()),
+                "orders_archive",
+                |_| {},
+            )
+        };
+        assert!(matches!(
+            archive(&mut db, "shipped"),
+            Err(KooError::UnknownField { .. })
+        ));
+        db.define_schema(schema("flags", FieldType::Boolean)).unwrap();
+        db.define_schema(schema("flags_archive", FieldType::Boolean)).unwrap();
+        let policy = ArchivePolicy::older_than("placed", day());
+        assert!(matches!(
+            db.archive("flags", &policy, "flags_archive", |_| {}),
+            Err(KooError::InvalidSchema(_))
+        ));
+        assert_eq!(ArchivePolicy::older_than("placed", day()).batch_size(0).batch_size, 1);
+    }
+
+    #[test]
+    fn moves_are_audited_on_both_sides() {
+        let old = Value::Integer(now() - 10 * 86_400);
+        let mut db = orders(&[old.clone(), Value::Integer(now()), old]);
+        db.enable_audit().unwrap();
+        let policy = ArchivePolicy::older_than("placed", day()).batch_size(1);
+        assert_eq!(db.archive("orders", &policy, "orders_archive", |_| {}).unwrap(), 2);
+
+        for id in [1, 3] {
+            let deleted = db.audit_history("orders", id).unwrap();
+            assert_eq!(deleted.len(), 1);
+            assert_eq!(deleted[0].action, AuditAction::Delete);
+            let created = db.audit_history("orders_archive", id).unwrap();
+            assert_eq!(created.len(), 1);
+            assert_eq!(created[0].action, AuditAction::Create);
+            assert_eq!(created[0].new_values, deleted[0].old_values);
+        }
+        assert!(db.audit_history("orders", 2).unwrap().is_empty());
+    }
+}
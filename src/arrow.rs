@@ -0,0 +1,414 @@
+#[cfg(feature = "parquet")]
+use arrow_array::Array;
+use arrow_array::builder::{
+    BooleanBuilder, FixedSizeListBuilder, Float32Builder, Float64Builder, Int64Builder, StringBuilder,
+};
+#[cfg(feature = "parquet")]
+use arrow_array::cast::AsArray;
+#[cfg(feature = "parquet")]
+use arrow_array::types::{
+    Float32Type, Float64Type, Int8Type, Int16Type, Int32Type, Int64Type, UInt8Type, UInt16Type, UInt32Type, UInt64Type,
+};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema as ArrowSchema, SchemaRef};
+use rusqlite::types::Value;
+use std::sync::Arc;
+
+use crate::error::Result;
+use crate::flexible_database::{FieldType, FlexibleDatabase, Model, ModelId, PrimaryKey, Schema, VERSION_COLUMN};
+use crate::query::Query;
+use crate::vector::decode;
+#[cfg(feature = "parquet")]
+use crate::vector::{encode, is_vector};
+
+// Rows per record batch
+pub(crate) const BATCH_ROWS: usize = 8192;
+
+// Fields map to Arrow types by how they are stored: Text, Enum, GeoPoint
+// and Polymorphic to Utf8, Integer and Reference to Int64, Real to
+// Float64, Boolean to Boolean, and Vector(n) to a FixedSizeList of n
+// Float32.
+pub fn data_type(field_type: &FieldType) -> DataType {
+    match field_type {
+        FieldType::Text | FieldType::Enum(_) | FieldType::GeoPoint | FieldType::Polymorphic(_) => DataType::Utf8,
+        FieldType::Integer | FieldType::Reference(_) => DataType::Int64,
+        FieldType::Real => DataType::Float64,
+        FieldType::Boolean => DataType::Boolean,
+        FieldType::Vector(dimensions) => DataType::FixedSizeList(
+            Arc::new(Field::new("item", DataType::Float32, true)),
+            *dimensions as i32,
+        ),
+    }
+}
+
+// The Arrow schema of `schema`'s rows: the id, unless the key is
+// composite, then the fields in column order, then the version if the
+// schema is versioned
+pub fn arrow_schema(schema: &Schema) -> ArrowSchema {
+    let mut fields = Vec::new();
+    match schema.key {
+        PrimaryKey::Integer => fields.push(Field::new("id", DataType::Int64, false)),
+        PrimaryKey::Text => fields.push(Field::new("id", DataType::Utf8, false)),
+        PrimaryKey::Composite(_) => {}
+    }
+    for (field_name, field_type) in &schema.fields {
+        // Computed fields are whatever their expression gives, and
+        // references can be left empty, by hierarchy roots
+        let nullable = schema.is_computed(field_name)
+            || schema.is_nullable(field_name)
+            || matches!(field_type, FieldType::Reference(_));
+        fields.push(Field::new(field_name, data_type(field_type), nullable));
+    }
+    if schema.versioned {
+        fields.push(Field::new(VERSION_COLUMN, DataType::Int64, false));
+    }
+    ArrowSchema::new(fields)
+}
+
+impl Query {
+    // Run the query on `db`, as `FlexibleDatabase::find_arrow`
+    pub fn to_arrow(&self, db: &FlexibleDatabase) -> Result<Vec<RecordBatch>> {
+        db.find_arrow(self)
+    }
+}
+
+// Query results as Arrow record batches, for handing kooDB data to polars,
+// DataFusion and other Arrow-based tools
+impl FlexibleDatabase {
+    // Run a query like `find`, returning the models as record batches of
+    // at most 8192 rows, laid out by `arrow_schema` or, for a query that
+    // selects fields, the id and those fields. Included references are
+    // left out. There is always at least one batch, empty if no model
+    // matched, so the layout is known either way.
+    pub fn find_arrow(&self, query: &Query) -> Result<Vec<RecordBatch>> {
+        let schema = self.schema_or_err(&query.schema)?;
+        let projected = match &query.fields {
+            Some(field_names) => Some(schema.projected(field_names)?),
+            None => None,
+        };
+        let columns = projected.as_ref().unwrap_or(schema);
+        let arrow: SchemaRef = Arc::new(arrow_schema(columns));
+
+        let models = self.find(query)?;
+        if models.is_empty() {
+            return Ok(vec![RecordBatch::new_empty(arrow)]);
+        }
+        models
+            .chunks(BATCH_ROWS)
+            .map(|chunk| models_to_batch(columns, &arrow, chunk))
+            .collect()
+    }
+}
+
+// `models` of `schema` as one batch laid out by `arrow`, from
+// `arrow_schema`. Values that don't fit a column's type are left null.
+pub(crate) fn models_to_batch(schema: &Schema, arrow: &SchemaRef, models: &[Model]) -> Result<RecordBatch> {
+    let mut columns = Vec::with_capacity(arrow.fields().len());
+    for field in arrow.fields() {
+        let name = field.name().as_str();
+        let column = match name {
+            "id" if schema.key.has_id_column() => {
+                let ids: Vec<Value> = models
+                    .iter()
+                    .map(|model| match &model.id {
+                        Some(ModelId::Integer(id)) => Value::Integer(*id),
+                        Some(ModelId::Text(id)) => Value::Text(id.clone()),
+                        _ => Value::Null,
+                    })
+                    .collect();
+                column_array(field.data_type(), ids.iter())
+            }
+            _ => column_array(
+                field.data_type(),
+                models.iter().map(|model| model.data.get(name).unwrap_or(&Value::Null)),
+            ),
+        };
+        columns.push(column);
+    }
+    Ok(RecordBatch::try_new(arrow.clone(), columns)?)
+}
+
+fn column_array<'a>(data_type: &DataType, values: impl Iterator<Item = &'a Value>) -> ArrayRef {
+    match data_type {
+        DataType::Int64 => {
+            let mut builder = Int64Builder::new();
+            for value in values {
+                builder.append_option(match value {
+                    Value::Integer(i) => Some(*i),
+                    _ => None,
+                });
+            }
+            Arc::new(builder.finish())
+        }
+        DataType::Float64 => {
+            let mut builder = Float64Builder::new();
+            for value in values {
+                builder.append_option(match value {
+                    Value::Real(f) => Some(*f),
+                    Value::Integer(i) => Some(*i as f64),
+                    _ => None,
+                });
+            }
+            Arc::new(builder.finish())
+        }
+        DataType::Boolean => {
+            let mut builder = BooleanBuilder::new();
+            for value in values {
+                builder.append_option(match value {
+                    Value::Integer(i) => Some(*i != 0),
+                    _ => None,
+                });
+            }
+            Arc::new(builder.finish())
+        }
+        DataType::FixedSizeList(_, dimensions) => {
+            let dimensions = *dimensions as usize;
+            let mut builder = FixedSizeListBuilder::new(Float32Builder::new(), dimensions as i32);
+            for value in values {
+                match decode(value).filter(|vector| vector.len() == dimensions) {
+                    Some(vector) => {
+                        builder.values().append_slice(&vector);
+                        builder.append(true);
+                    }
+                    None => {
+                        builder.values().append_nulls(dimensions);
+                        builder.append(false);
+                    }
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        _ => {
+            let mut builder = StringBuilder::new();
+            for value in values {
+                match value {
+                    Value::Text(s) => builder.append_value(s),
+                    Value::Integer(i) => builder.append_value(i.to_string()),
+                    Value::Real(f) => builder.append_value(f.to_string()),
+                    _ => builder.append_null(),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+    }
+}
+
+// The value at `row` of `array`, or None if its type has no SQLite
+// counterpart. Lists of numbers become vectors.
+#[cfg(feature = "parquet")]
+pub(crate) fn array_value(array: &dyn Array, row: usize) -> Option<Value> {
+    if array.is_null(row) {
+        return Some(Value::Null);
+    }
+    let value = match array.data_type() {
+        DataType::Boolean => Value::Integer(array.as_boolean().value(row) as i64),
+        DataType::Int8 => Value::Integer(array.as_primitive::<Int8Type>().value(row) as i64),
+        DataType::Int16 => Value::Integer(array.as_primitive::<Int16Type>().value(row) as i64),
+        DataType::Int32 => Value::Integer(array.as_primitive::<Int32Type>().value(row) as i64),
+        DataType::Int64 => Value::Integer(array.as_primitive::<Int64Type>().value(row)),
+        DataType::UInt8 => Value::Integer(array.as_primitive::<UInt8Type>().value(row) as i64),
+        DataType::UInt16 => Value::Integer(array.as_primitive::<UInt16Type>().value(row) as i64),
+        DataType::UInt32 => Value::Integer(array.as_primitive::<UInt32Type>().value(row) as i64),
+        DataType::UInt64 => Value::Integer(i64::try_from(array.as_primitive::<UInt64Type>().value(row)).ok()?),
+        DataType::Float32 => Value::Real(array.as_primitive::<Float32Type>().value(row) as f64),
+        DataType::Float64 => Value::Real(array.as_primitive::<Float64Type>().value(row)),
+        DataType::Utf8 => Value::Text(array.as_string::<i32>().value(row).to_string()),
+        DataType::LargeUtf8 => Value::Text(array.as_string::<i64>().value(row).to_string()),
+        DataType::Utf8View => Value::Text(array.as_string_view().value(row).to_string()),
+        DataType::FixedSizeList(_, _) => list_vector(array.as_fixed_size_list().value(row).as_ref())?,
+        DataType::List(_) => list_vector(array.as_list::<i32>().value(row).as_ref())?,
+        _ => return None,
+    };
+    Some(value)
+}
+
+#[cfg(feature = "parquet")]
+fn list_vector(items: &dyn Array) -> Option<Value> {
+    let mut vector = Vec::with_capacity(items.len());
+    for i in 0..items.len() {
+        match array_value(items, i)? {
+            Value::Real(f) => vector.push(f as f32),
+            Value::Integer(n) => vector.push(n as f32),
+            _ => return None,
+        }
+    }
+    Some(encode(&vector))
+}
+
+// `value`, read from an Arrow array, as stored for a field of
+// `field_type`, or None if it doesn't fit
+#[cfg(feature = "parquet")]
+pub(crate) fn field_value(value: Value, field_type: &FieldType) -> Option<Value> {
+    match (value, field_type) {
+        (Value::Null, _) => Some(Value::Null),
+        (Value::Text(s), FieldType::Text | FieldType::Enum(_) | FieldType::GeoPoint | FieldType::Polymorphic(_)) => {
+            Some(Value::Text(s))
+        }
+        (Value::Integer(i), FieldType::Integer | FieldType::Reference(_)) => Some(Value::Integer(i)),
+        (Value::Integer(i), FieldType::Real) => Some(Value::Real(i as f64)),
+        (Value::Real(f), FieldType::Real) => Some(Value::Real(f)),
+        (Value::Integer(i @ (0 | 1)), FieldType::Boolean) => Some(Value::Integer(i)),
+        (value, FieldType::Vector(dimensions)) if is_vector(&value, *dimensions) => Some(value),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::Array;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Float32Type, Float64Type, Int64Type};
+    use std::collections::HashMap;
+
+    use crate::flexible_database::FieldDef;
+    use crate::query::{Direction, Op};
+    use crate::vector::encode;
+
+    #[test]
+    fn fields_map_to_the_types_they_are_stored_as() {
+        let vector = DataType::FixedSizeList(Arc::new(Field::new("item", DataType::Float32, true)), 3);
+        let cases = [
+            (FieldType::Text, DataType::Utf8),
+            (FieldType::Enum(vec!["a".to_string()]), DataType::Utf8),
+            (FieldType::GeoPoint, DataType::Utf8),
+            (FieldType::Polymorphic(vec!["a".to_string()]), DataType::Utf8),
+            (FieldType::Integer, DataType::Int64),
+            (FieldType::Reference("a".to_string()), DataType::Int64),
+            (FieldType::Real, DataType::Float64),
+            (FieldType::Boolean, DataType::Boolean),
+            (FieldType::Vector(3), vector),
+        ];
+        for (field_type, expected) in cases {
+            assert_eq!(data_type(&field_type), expected, "{:?}", field_type);
+        }
+    }
+
+    #[test]
+    fn schemas_lay_out_the_id_fields_and_version() {
+        let schema = Schema::new("tasks", [])
+            .field("title", FieldDef::new(FieldType::Text))
+            .field("due", FieldDef::new(FieldType::Integer).nullable())
+            .field("parent", FieldDef::new(FieldType::Reference("tasks".to_string())))
+            .field("late", FieldDef::new(FieldType::Boolean).computed("due < 0"))
+            .with_versioning()
+            .materialize()
+            .unwrap();
+        let layout: Vec<(String, bool)> = arrow_schema(&schema)
+            .fields()
+            .iter()
+            .map(|field| (field.name().clone(), field.is_nullable()))
+            .collect();
+        let expected = [
+            ("id", false),
+            ("title", false),
+            ("due", true),
+            ("parent", true),
+            ("late", true),
+            (VERSION_COLUMN, false),
+        ];
+        assert_eq!(layout, expected.map(|(name, nullable)| (name.to_string(), nullable)));
+
+        let composite = Schema::new("pairs", [])
+            .field("a", FieldDef::new(FieldType::Integer))
+            .field("b", FieldDef::new(FieldType::Integer))
+            .with_key(PrimaryKey::Composite(vec!["a".to_string(), "b".to_string()]));
+        assert_eq!(arrow_schema(&composite).fields().len(), 2);
+        let text = Schema::new("tags", []).with_key(PrimaryKey::Text);
+        assert_eq!(arrow_schema(&text).field(0).data_type(), &DataType::Utf8);
+    }
+
+    fn readings(rows: i64) -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("readings", [])
+            .field("sensor", FieldDef::new(FieldType::Text))
+            .field("value", FieldDef::new(FieldType::Real))
+            .field("ok", FieldDef::new(FieldType::Boolean))
+            .field("shape", FieldDef::new(FieldType::Vector(2)).nullable());
+        db.define_schema(schema).unwrap();
+        db.transaction(|db| {
+            for i in 0..rows {
+                let data = HashMap::from([
+                    ("sensor".to_string(), Value::Text(format!("s{}", i % 3))),
+                    ("value".to_string(), Value::Real(i as f64 / 2.0)),
+                    ("ok".to_string(), Value::Integer(i % 2)),
+                    ("shape".to_string(), encode(&[i as f32, 1.0])),
+                ]);
+                db.create_model("readings", data)?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        db
+    }
+
+    #[test]
+    fn query_results_become_record_batches() {
+        let db = readings(4);
+        let query = Query::new("readings").order_by("value", Direction::Desc).limit(3);
+        let batches = query.to_arrow(&db).unwrap();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.schema().as_ref(), &arrow_schema(&db.schemas["readings"]));
+        assert_eq!(batch.num_rows(), 3);
+
+        let ids = batch.column(0).as_primitive::<Int64Type>();
+        assert_eq!(ids.values().to_vec(), vec![4, 3, 2]);
+        let sensors = batch.column_by_name("sensor").unwrap().as_string::<i32>();
+        assert_eq!(sensors.value(0), "s0");
+        let values = batch.column_by_name("value").unwrap().as_primitive::<Float64Type>();
+        assert_eq!(values.value(0), 1.5);
+        let ok = batch.column_by_name("ok").unwrap().as_boolean();
+        assert!(ok.value(0) && !ok.value(1));
+        let shapes = batch.column_by_name("shape").unwrap().as_fixed_size_list();
+        let shape = shapes.value(0);
+        assert_eq!(shape.as_primitive::<Float32Type>().values().to_vec(), vec![3.0, 1.0]);
+    }
+
+    #[test]
+    fn selected_fields_narrow_the_layout() {
+        let db = readings(2);
+        let batches = db.find_arrow(&Query::new("readings").select(&["value"])).unwrap();
+        let names: Vec<&str> = batches[0]
+            .schema_ref()
+            .fields()
+            .iter()
+            .map(|field| field.name().as_str())
+            .collect();
+        assert_eq!(names, vec!["id", "value"]);
+    }
+
+    #[test]
+    fn results_are_split_into_batches() {
+        let db = readings(BATCH_ROWS as i64 + 5);
+        let batches = db.find_arrow(&Query::new("readings")).unwrap();
+        let rows: Vec<usize> = batches.iter().map(RecordBatch::num_rows).collect();
+        assert_eq!(rows, vec![BATCH_ROWS, 5]);
+    }
+
+    #[test]
+    fn empty_results_still_give_the_layout() {
+        let db = readings(2);
+        let query = Query::new("readings").filter("value", Op::Gt, 100);
+        let batches = db.find_arrow(&query).unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].num_rows(), 0);
+        assert_eq!(batches[0].num_columns(), 5);
+    }
+
+    #[test]
+    fn values_that_dont_fit_are_left_null() {
+        let db = readings(1);
+        let model = db.get_model("readings", 1).unwrap().unwrap();
+        let schema = &db.schemas["readings"];
+        let arrow: SchemaRef = Arc::new(arrow_schema(schema));
+        let mut odd = model.clone();
+        odd.data.insert("shape".to_string(), encode(&[1.0, 2.0, 3.0]));
+        let batch = models_to_batch(schema, &arrow, &[model, odd.clone()]).unwrap();
+        let shapes = batch.column_by_name("shape").unwrap();
+        assert!(shapes.is_valid(0) && shapes.is_null(1));
+        // Only where the column may hold nulls
+        odd.data.insert("value".to_string(), Value::Text("high".to_string()));
+        assert!(models_to_batch(schema, &arrow, &[odd]).is_err());
+    }
+}
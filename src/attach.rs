@@ -0,0 +1,285 @@
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FieldType, FlexibleDatabase, Schema, VERSION_COLUMN};
+use crate::query::{Condition, check_field};
+
+// Other database files attached to the connection. The schemas recorded in
+// an attached file are registered as "<alias>.<name>", and SQLite resolves
+// such qualified names itself, so they work with every operation, e.g.
+// `db.find(&Query::new("archive.orders"))`. Schemas defined with a
+// qualified name are created in, and recorded by, the attached file.
+impl FlexibleDatabase {
+    // Attach the database at `path` as `alias`, creating it if it doesn't
+    // exist. Returns the number of schemas registered from its catalog.
+    pub fn attach(&mut self, path: &str, alias: &str) -> Result<usize> {
+        check_alias(alias)?;
+        self.conn.execute(&format!("ATTACH DATABASE ? AS {}", alias), [path])?;
+
+        let has_catalog: bool = self.conn.query_row(
+            &format!(
+                "SELECT EXISTS (SELECT 1 FROM {}.sqlite_master WHERE type = 'table' AND name = '_koo_schemas')",
+                alias
+            ),
+            [],
+            |row| row.get(0),
+        )?;
+        if !has_catalog {
+            return Ok(0);
+        }
+
+        let mut stmt = self
+            .conn
+            .prepare(&format!("SELECT definition FROM {}._koo_schemas", alias))?;
+        let definitions = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        drop(stmt);
+
+        let schemas = definitions
+            .iter()
+            .map(|definition| Schema::from_json(definition))
+            .collect::<Result<Vec<_>>>()?;
+        let names: Vec<String> = schemas.iter().map(|schema| schema.name.clone()).collect();
+        for mut schema in schemas {
+            schema.name = format!("{}.{}", alias, schema.name);
+            // References between schemas of the attached file stay inside it
+            for field_type in schema.fields.values_mut() {
+                if let FieldType::Reference(target) = field_type
+                    && names.contains(target)
+                {
+                    *target = format!("{}.{}", alias, target);
+                }
+            }
+            let schema = schema.materialize()?;
+            self.schemas.insert(schema.name.clone(), schema);
+        }
+        Ok(names.len())
+    }
+
+    // Detach `alias` and forget the schemas registered under it
+    pub fn detach(&mut self, alias: &str) -> Result<()> {
+        check_alias(alias)?;
+        self.conn.execute(&format!("DETACH DATABASE {}", alias), [])?;
+        let prefix = format!("{}.", alias);
+        self.schemas.retain(|name, _| !name.starts_with(&prefix));
+        Ok(())
+    }
+
+    // Copy the models of `<from_alias>.<schema_name>` that match every
+    // condition in `filter` into `schema_name`, keeping their ids. An empty
+    // filter copies everything. Both schemas must be registered and have
+    // the same layout. A model whose id is already taken fails the whole
+    // copy. Returns the number of models copied.
+    pub fn copy_models(&self, from_alias: &str, schema_name: &str, filter: &[Condition]) -> Result<usize> {
+        check_alias(from_alias)?;
+        let target = self.schema_or_err(schema_name)?;
+        let source = self.schema_or_err(&format!("{}.{}", from_alias, schema_name))?;
+        if source.key != target.key || source.versioned != target.versioned {
+            return Err(KooError::InvalidSchema(format!(
+                "'{}' and '{}' are keyed differently",
+                source.name, target.name
+            )));
+        }
+
+        let mut columns = Vec::new();
+        if target.key.has_id_column() {
+            columns.push("id".to_string());
+        }
+        for (field_name, field_type) in &target.fields {
+            // Computed from the copied columns instead
+            if target.is_computed(field_name) {
+                continue;
+            }
+            match source.fields.get(field_name) {
+                Some(source_type) if source_type == field_type || references_alike(source_type, field_type) => {}
+                _ => {
+                    return Err(KooError::InvalidSchema(format!(
+                        "'{}' has no field '{}' of type {}",
+                        source.name,
+                        field_name,
+                        field_type.name()
+                    )));
+                }
+            }
+            columns.push(field_name.clone());
+        }
+        if target.versioned {
+            columns.push(VERSION_COLUMN.to_string());
+        }
+        let columns = columns.join(", ");
+
+        let mut sql = format!(
+            "INSERT INTO {} ({}) SELECT {} FROM {}",
+            target.name, columns, columns, source.name
+        );
+        let mut params = Vec::new();
+        if !filter.is_empty() {
+            let mut clauses = Vec::new();
+            for condition in filter {
+                check_field(source, &condition.field)?;
+                let (clause, param) = condition.to_sql()?;
+                clauses.push(clause);
+                params.push(param);
+            }
+            sql.push_str(&format!(" WHERE {}", clauses.join(" AND ")));
+        }
+
+        // One statement, so a failure leaves nothing half copied
+        self.traced(&sql, params.len(), || {
+            let copied = self.audit_inserts(target, &sql, &params)?;
+            Ok((copied, copied))
+        })
+    }
+}
+
+// Whether `alias` names a database attached to the connection
+pub(crate) fn is_attached(conn: &rusqlite::Connection, alias: &str) -> Result<bool> {
+    let mut stmt = conn.prepare("SELECT name FROM pragma_database_list")?;
+    let names = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(alias != "main" && names.iter().any(|name| name == alias))
+}
+
+// Aliases are spliced into SQL, so only plain identifiers are accepted
+fn check_alias(alias: &str) -> Result<()> {
+    let valid = alias.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && alias.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !alias.eq_ignore_ascii_case("main")
+        && !alias.eq_ignore_ascii_case("temp");
+    if valid {
+        Ok(())
+    } else {
+        Err(KooError::InvalidData(format!(
+            "'{}' can't be used as a database alias",
+            alias
+        )))
+    }
+}
+
+// A reference to `orders` in the attached file matches one to `orders` here
+pub(crate) fn references_alike(source: &FieldType, target: &FieldType) -> bool {
+    match (source, target) {
+        (FieldType::Reference(source), FieldType::Reference(target)) => {
+            source == target || source.split_once('.').is_some_and(|(_, name)| name == target)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::Value;
+    use std::collections::HashMap;
+
+    use crate::flexible_database::ModelId;
+    use crate::query::{Op, Query};
+    use crate::temp_file::TempFile;
+
+    fn orders(name: &str) -> Schema {
+        Schema::new(
+            name,
+            [
+                ("item".to_string(), FieldType::Text),
+                ("year".to_string(), FieldType::Integer),
+            ],
+        )
+    }
+
+    fn order(item: &str, year: i64) -> HashMap<String, Value> {
+        HashMap::from([
+            ("item".to_string(), Value::Text(item.to_string())),
+            ("year".to_string(), Value::Integer(year)),
+        ])
+    }
+
+    // An archive file holding two orders, made by a handle of its own
+    fn archive() -> TempFile {
+        let file = TempFile::new("db");
+        let mut archive = FlexibleDatabase::new(file.path()).unwrap();
+        archive.define_schema(orders("orders")).unwrap();
+        archive.create_model("orders", order("lamp", 2019)).unwrap();
+        archive.create_model("orders", order("desk", 2023)).unwrap();
+        file
+    }
+
+    #[test]
+    fn attached_schemas_are_registered_under_the_alias() {
+        let file = archive();
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        assert_eq!(db.attach(file.path(), "archive").unwrap(), 1);
+
+        let found = db
+            .find(&Query::new("archive.orders").filter("year", Op::Lt, 2020))
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].data["item"], Value::Text("lamp".to_string()));
+
+        db.detach("archive").unwrap();
+        assert!(matches!(db.count("archive.orders"), Err(KooError::SchemaNotFound(_))));
+    }
+
+    #[test]
+    fn qualified_schemas_are_recorded_in_the_attached_file() {
+        let file = TempFile::new("db");
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        assert_eq!(db.attach(file.path(), "archive").unwrap(), 0);
+        db.define_schema(orders("archive.orders")).unwrap();
+        db.create_model("archive.orders", order("lamp", 2019)).unwrap();
+        assert!(db.stored_schemas().unwrap().is_empty());
+        db.detach("archive").unwrap();
+
+        let mut reopened = FlexibleDatabase::new(file.path()).unwrap();
+        reopened.load_schemas().unwrap();
+        assert_eq!(reopened.count("orders").unwrap(), 1);
+    }
+
+    #[test]
+    fn copies_keep_ids_and_honour_the_filter() {
+        let file = archive();
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(orders("orders")).unwrap();
+        db.attach(file.path(), "archive").unwrap();
+
+        let filter = [Condition {
+            field: "year".to_string(),
+            op: Op::Ge,
+            value: Value::Integer(2020),
+        }];
+        assert_eq!(db.copy_models("archive", "orders", &filter).unwrap(), 1);
+        let copied = db.get_model("orders", 2).unwrap().unwrap();
+        assert_eq!(copied.data["item"], Value::Text("desk".to_string()));
+
+        // The desk is taken now, so copying everything fails as a whole
+        assert!(db.copy_models("archive", "orders", &[]).is_err());
+        assert_eq!(db.count("orders").unwrap(), 1);
+        assert!(db.get_model("orders", ModelId::Integer(1)).unwrap().is_none());
+    }
+
+    #[test]
+    fn copies_need_matching_layouts() {
+        let file = archive();
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(orders("orders").field("price", FieldType::Real))
+            .unwrap();
+        db.attach(file.path(), "archive").unwrap();
+        assert!(matches!(
+            db.copy_models("archive", "orders", &[]),
+            Err(KooError::InvalidSchema(_))
+        ));
+    }
+
+    #[test]
+    fn aliases_must_be_plain_identifiers() {
+        let file = TempFile::new("db");
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        for alias in ["main", "TEMP", "1st", "a-b", "x; DROP TABLE y", ""] {
+            assert!(
+                matches!(db.attach(file.path(), alias), Err(KooError::InvalidData(_))),
+                "{}",
+                alias
+            );
+        }
+        assert!(db.attach(file.path(), "_old2").is_ok());
+    }
+}
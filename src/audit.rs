@@ -0,0 +1,474 @@
+use rusqlite::types::Value;
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FlexibleDatabase, Model, ModelId, Schema, read_model, select_sql};
+use crate::wire::model_to_json;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    Create,
+    Update,
+    Delete,
+}
+
+impl AuditAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Create => "create",
+            AuditAction::Update => "update",
+            AuditAction::Delete => "delete",
+        }
+    }
+
+    fn from_str(action: &str) -> Option<AuditAction> {
+        match action {
+            "create" => Some(AuditAction::Create),
+            "update" => Some(AuditAction::Update),
+            "delete" => Some(AuditAction::Delete),
+            _ => None,
+        }
+    }
+}
+
+// One change recorded in `_koo_audit`. The values are the model in the
+// export row layout before and after the change, without encrypted fields.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub action: AuditAction,
+    pub old_values: Option<serde_json::Value>,
+    pub new_values: Option<serde_json::Value>,
+    // UTC, as "YYYY-MM-DD HH:MM:SS"
+    pub changed_at: String,
+    // User of the caller context at the time, see `set_caller`
+    pub actor: Option<String>,
+}
+
+// With auditing enabled, every model created, updated or deleted through
+// the API, by `create_model`, `update_model` and `delete_model` as well as
+// by imports and copies, is recorded in the `_koo_audit` table, in the same
+// transaction as the change. Only raw SQL is not recorded.
+impl FlexibleDatabase {
+    pub fn enable_audit(&mut self) -> Result<()> {
+        self.ensure_audit_table()?;
+        self.audit = true;
+        Ok(())
+    }
+
+    pub fn disable_audit(&mut self) {
+        self.audit = false;
+    }
+
+    // Recorded changes of one model, oldest first
+    pub fn audit_history(&self, schema_name: &str, id: impl Into<ModelId>) -> Result<Vec<AuditEntry>> {
+        self.schema_or_err(schema_name)?;
+        self.ensure_audit_table()?;
+        let mut stmt = self.conn.prepare(
+            "SELECT action, old_values, new_values, changed_at, actor FROM _koo_audit
+             WHERE schema_name = ? AND model_id = ? ORDER BY seq",
+        )?;
+        let rows = stmt
+            .query_map((schema_name, id.into().to_string()), |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut entries = Vec::new();
+        for (action, old_values, new_values, changed_at, actor) in rows {
+            let action = AuditAction::from_str(&action)
+                .ok_or_else(|| KooError::InvalidData(format!("unknown audit action '{}'", action)))?;
+            entries.push(AuditEntry {
+                action,
+                old_values: old_values.map(|json| serde_json::from_str(&json)).transpose()?,
+                new_values: new_values.map(|json| serde_json::from_str(&json)).transpose()?,
+                changed_at,
+                actor,
+            });
+        }
+        Ok(entries)
+    }
+
+    // Run `insert`, which creates a model and returns its id, and record it
+    pub(crate) fn audit_create(&self, schema: &Schema, insert: impl FnOnce() -> Result<ModelId>) -> Result<ModelId> {
+        if !self.audit {
+            return insert();
+        }
+        self.in_audit_savepoint(|| {
+            let id = insert()?;
+            let new = self.stored_model(schema, &id)?;
+            self.record_audit(schema, &id, AuditAction::Create, None, new.as_ref())?;
+            Ok(id)
+        })
+    }
+
+    // Run `change`, which updates or deletes the model `id` and returns the
+    // number of rows it changed, and record it
+    pub(crate) fn audit_change(
+        &self,
+        schema: &Schema,
+        id: &ModelId,
+        action: AuditAction,
+        change: impl FnOnce() -> Result<usize>,
+    ) -> Result<usize> {
+        if !self.audit {
+            return change();
+        }
+        self.in_audit_savepoint(|| {
+            let old = self.stored_model(schema, id)?;
+            let changed = change()?;
+            if changed > 0 {
+                let new = match action {
+                    AuditAction::Delete => None,
+                    _ => self.stored_model(schema, id)?,
+                };
+                self.record_audit(schema, id, action, old.as_ref(), new.as_ref())?;
+            }
+            Ok(changed)
+        })
+    }
+
+    // Run `insert`, which inserts at most one row, possibly replacing the
+    // model `id`, and returns the number of rows it inserted. The row is
+    // recorded as created, or as an update of the model it replaced. Without
+    // an id, the row is the one SQLite last numbered.
+    pub(crate) fn audit_insert(
+        &self,
+        schema: &Schema,
+        id: Option<ModelId>,
+        insert: impl FnOnce() -> Result<usize>,
+    ) -> Result<usize> {
+        if !self.audit {
+            return insert();
+        }
+        self.in_audit_savepoint(|| {
+            let old = match &id {
+                Some(id) => self.stored_model(schema, id)?,
+                None => None,
+            };
+            let inserted = insert()?;
+            if inserted > 0 {
+                let id = id.unwrap_or_else(|| ModelId::Integer(self.conn.last_insert_rowid()));
+                let new = self.stored_model(schema, &id)?;
+                let action = match old {
+                    Some(_) => AuditAction::Update,
+                    None => AuditAction::Create,
+                };
+                self.record_audit(schema, &id, action, old.as_ref(), new.as_ref())?;
+            }
+            Ok(inserted)
+        })
+    }
+
+    // Run `sql`, an INSERT of any number of rows into `schema`, recording
+    // each row it inserts as created. Returns the number of rows inserted.
+    pub(crate) fn audit_inserts(&self, schema: &Schema, sql: &str, params: &[Value]) -> Result<usize> {
+        if !self.audit {
+            return Ok(self.conn.execute(sql, rusqlite::params_from_iter(params))?);
+        }
+        self.in_audit_savepoint(|| {
+            let rowids = self
+                .conn
+                .prepare(&format!("{} RETURNING rowid", sql))?
+                .query_map(rusqlite::params_from_iter(params), |row| row.get::<_, i64>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let sql = format!("{} WHERE {}.rowid = ?", select_sql(schema), schema.name);
+            for rowid in &rowids {
+                let mut stmt = self.prepare_cached(&sql)?;
+                let mut rows = stmt.query([rowid])?;
+                if let Some(row) = rows.next()? {
+                    let new = read_model(row, schema)?;
+                    let id = new.id.clone().expect("stored models have ids");
+                    self.record_audit(schema, &id, AuditAction::Create, None, Some(&new))?;
+                }
+            }
+            Ok(rowids.len())
+        })
+    }
+
+    // Run `change`, which updates or deletes the models of `schema` that
+    // `condition` matches and returns the number of rows it changed, and
+    // record each of them
+    pub(crate) fn audit_rows(
+        &self,
+        schema: &Schema,
+        condition: &str,
+        params: &[Value],
+        action: AuditAction,
+        change: impl FnOnce() -> Result<usize>,
+    ) -> Result<usize> {
+        if !self.audit {
+            return change();
+        }
+        self.in_audit_savepoint(|| {
+            let sql = format!("{} WHERE {}", select_sql(schema), condition);
+            let mut olds = Vec::new();
+            let mut stmt = self.prepare_cached(&sql)?;
+            let mut rows = stmt.query(rusqlite::params_from_iter(params))?;
+            while let Some(row) = rows.next()? {
+                olds.push(read_model(row, schema)?);
+            }
+            drop(rows);
+            drop(stmt);
+
+            let changed = change()?;
+            for old in &olds {
+                let id = old.id.clone().expect("stored models have ids");
+                let new = match action {
+                    AuditAction::Delete => None,
+                    _ => self.stored_model(schema, &id)?,
+                };
+                self.record_audit(schema, &id, action, Some(old), new.as_ref())?;
+            }
+            Ok(changed)
+        })
+    }
+
+    fn ensure_audit_table(&self) -> Result<()> {
+        self.conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS _koo_audit (
+                seq INTEGER PRIMARY KEY,
+                schema_name TEXT NOT NULL,
+                model_id TEXT NOT NULL,
+                action TEXT NOT NULL,
+                old_values TEXT,
+                new_values TEXT,
+                changed_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                actor TEXT
+            );
+            CREATE INDEX IF NOT EXISTS _koo_audit_model ON _koo_audit (schema_name, model_id);",
+        )?;
+        Ok(())
+    }
+
+    // Keep a change and its audit entry together; a savepoint rather than a
+    // transaction as the caller may have one open
+    fn in_audit_savepoint<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        self.conn.execute_batch("SAVEPOINT koo_audit")?;
+        match f() {
+            Ok(value) => {
+                self.conn.execute_batch("RELEASE koo_audit")?;
+                Ok(value)
+            }
+            Err(err) => {
+                let _ = self.conn.execute_batch("ROLLBACK TO koo_audit; RELEASE koo_audit");
+                Err(err)
+            }
+        }
+    }
+
+    fn record_audit(
+        &self,
+        schema: &Schema,
+        id: &ModelId,
+        action: AuditAction,
+        old: Option<&Model>,
+        new: Option<&Model>,
+    ) -> Result<()> {
+        let values = |model: &Model| {
+            let mut json = model_to_json(schema, model);
+            if let Some(object) = json.as_object_mut() {
+                object.retain(|field_name, _| !schema.encrypted_fields.contains(field_name));
+            }
+            json.to_string()
+        };
+        self.prepare_cached(
+            "INSERT INTO _koo_audit (schema_name, model_id, action, old_values, new_values, actor)
+             VALUES (?, ?, ?, ?, ?, ?)",
+        )?
+        .execute((
+            &schema.name,
+            id.to_string(),
+            action.as_str(),
+            old.map(values),
+            new.map(values),
+            &self.caller.user,
+        ))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    use crate::access::CallerContext;
+    use crate::field_encryption::StaticKey;
+    use crate::flexible_database::{FieldDef, FieldType};
+    use crate::import::{ConflictStrategy, ImportOptions};
+    use crate::temp_file::TempFile;
+    use crate::validation::Validator;
+
+    fn accounts() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("accounts", [("owner".to_string(), FieldType::Text)]).field(
+            "balance",
+            FieldDef::new(FieldType::Integer).validate(Validator::Min(0.0)),
+        ))
+        .unwrap();
+        db.enable_audit().unwrap();
+        db
+    }
+
+    fn account(owner: &str, balance: i64) -> HashMap<String, Value> {
+        HashMap::from([
+            ("owner".to_string(), Value::Text(owner.to_string())),
+            ("balance".to_string(), Value::Integer(balance)),
+        ])
+    }
+
+    fn actions(entries: &[AuditEntry]) -> Vec<AuditAction> {
+        entries.iter().map(|entry| entry.action).collect()
+    }
+
+    #[test]
+    fn each_change_is_recorded_with_its_values() {
+        let mut db = accounts();
+        db.set_caller(CallerContext::user("ada"));
+        let id = db.create_model("accounts", account("Ada", 10)).unwrap();
+        db.set_caller(CallerContext::anonymous());
+        db.update_model("accounts", id.clone(), account("Ada", 25)).unwrap();
+        db.delete_model("accounts", id.clone()).unwrap();
+
+        let history = db.audit_history("accounts", id).unwrap();
+        assert_eq!(
+            actions(&history),
+            [AuditAction::Create, AuditAction::Update, AuditAction::Delete]
+        );
+        let created = json!({"id": 1, "owner": "Ada", "balance": 10});
+        let updated = json!({"id": 1, "owner": "Ada", "balance": 25});
+        assert_eq!(
+            (&history[0].old_values, &history[0].new_values),
+            (&None, &Some(created.clone()))
+        );
+        assert_eq!(
+            (&history[1].old_values, &history[1].new_values),
+            (&Some(created), &Some(updated.clone()))
+        );
+        assert_eq!(
+            (&history[2].old_values, &history[2].new_values),
+            (&Some(updated), &None)
+        );
+        assert_eq!(history[0].actor.as_deref(), Some("ada"));
+        assert_eq!(history[1].actor, None);
+        assert_eq!(history[0].changed_at.len(), "YYYY-MM-DD HH:MM:SS".len());
+    }
+
+    #[test]
+    fn only_changes_that_happen_are_recorded() {
+        let db = accounts();
+        let id = db.create_model("accounts", account("Ada", 10)).unwrap();
+        assert!(db.update_model("accounts", id.clone(), account("Ada", -5)).is_err());
+        assert!(!db.update_model("accounts", 9, account("Bob", 1)).unwrap());
+        assert!(!db.delete_model("accounts", 9).unwrap());
+        db.conn.execute("UPDATE accounts SET balance = 0", []).unwrap();
+
+        assert_eq!(
+            actions(&db.audit_history("accounts", id).unwrap()),
+            [AuditAction::Create]
+        );
+        assert!(db.audit_history("accounts", 9).unwrap().is_empty());
+    }
+
+    #[test]
+    fn nothing_is_recorded_while_disabled() {
+        let mut db = accounts();
+        db.disable_audit();
+        let id = db.create_model("accounts", account("Ada", 10)).unwrap();
+        assert!(db.audit_history("accounts", id.clone()).unwrap().is_empty());
+
+        db.enable_audit().unwrap();
+        db.delete_model("accounts", id.clone()).unwrap();
+        assert_eq!(
+            actions(&db.audit_history("accounts", id).unwrap()),
+            [AuditAction::Delete]
+        );
+        assert!(matches!(
+            db.audit_history("missing", 1),
+            Err(KooError::SchemaNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn encrypted_fields_are_left_out() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.set_key_provider(StaticKey([7; 32])).unwrap();
+        db.define_schema(
+            Schema::new("accounts", [("owner".to_string(), FieldType::Text)])
+                .field("pin", FieldDef::new(FieldType::Text).encrypted()),
+        )
+        .unwrap();
+        db.enable_audit().unwrap();
+        let data = HashMap::from([
+            ("owner".to_string(), Value::Text("Ada".to_string())),
+            ("pin".to_string(), Value::Text("1234".to_string())),
+        ]);
+        let id = db.create_model("accounts", data).unwrap();
+
+        let history = db.audit_history("accounts", id).unwrap();
+        assert_eq!(history[0].new_values, Some(json!({"id": 1, "owner": "Ada"})));
+    }
+
+    #[test]
+    fn imported_rows_are_recorded() {
+        let source = accounts();
+        source.create_model("accounts", account("Ada", 10)).unwrap();
+        let mut exported = Vec::new();
+        source.export_json(&mut exported).unwrap();
+
+        let mut db = accounts();
+        db.create_model("accounts", account("Old", 1)).unwrap();
+        let overwrite = ImportOptions {
+            on_conflict: ConflictStrategy::Overwrite,
+            preserve_ids: true,
+        };
+        db.import_json(exported.as_slice(), overwrite).unwrap();
+        let history = db.audit_history("accounts", 1).unwrap();
+        assert_eq!(actions(&history), [AuditAction::Create, AuditAction::Update]);
+        assert_eq!(
+            history[1].old_values,
+            Some(json!({"id": 1, "owner": "Old", "balance": 1}))
+        );
+        assert_eq!(
+            history[1].new_values,
+            Some(json!({"id": 1, "owner": "Ada", "balance": 10}))
+        );
+
+        let file = TempFile::with_contents("csv", "owner,balance\nBob,5\n");
+        assert_eq!(db.import_csv("accounts", file.path(), true).unwrap(), 1);
+        let history = db.audit_history("accounts", 2).unwrap();
+        assert_eq!(
+            history[0].new_values,
+            Some(json!({"id": 2, "owner": "Bob", "balance": 5}))
+        );
+    }
+
+    #[test]
+    fn rows_copied_from_an_attached_database_are_recorded() {
+        let mut db = accounts();
+        db.conn.execute_batch("ATTACH ':memory:' AS other").unwrap();
+        db.conn
+            .execute_batch(
+                "CREATE TABLE other.accounts (id INTEGER PRIMARY KEY, owner TEXT NOT NULL, balance INTEGER NOT NULL);
+                 INSERT INTO other.accounts VALUES (7, 'Cy', 3), (8, 'Di', 4);",
+            )
+            .unwrap();
+        let mut other = db.schemas["accounts"].clone();
+        other.name = "other.accounts".to_string();
+        db.schemas.insert(other.name.clone(), other);
+
+        assert_eq!(db.copy_models("other", "accounts", &[]).unwrap(), 2);
+        let history = db.audit_history("accounts", 8).unwrap();
+        assert_eq!(actions(&history), [AuditAction::Create]);
+        assert_eq!(
+            history[0].new_values,
+            Some(json!({"id": 8, "owner": "Di", "balance": 4}))
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{Connection, DatabaseName};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use crate::error::Result;
+use crate::flexible_database::FlexibleDatabase;
+
+// Pages copied per backup step. Between steps other connections may write
+// to the source database; SQLite restarts the copy to pick their changes up.
+const PAGES_PER_STEP: i32 = 100;
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackupProgress {
+    pub remaining_pages: i32,
+    pub total_pages: i32,
+}
+
+impl FlexibleDatabase {
+    // Copy the live database to `path` using SQLite's online backup API,
+    // reporting progress after every step
+    pub fn backup_to<P, F>(&self, path: P, mut progress: F) -> Result<()>
+    where
+        P: AsRef<Path>,
+        F: FnMut(BackupProgress),
+    {
+        let mut target = Connection::open(path)?;
+        copy_database(&self.conn, &mut target, &mut progress)
+    }
+
+    // Copy a backup into `target_path` and open it, with the schemas
+    // recorded in the backup's catalog registered
+    pub fn restore_from<P: AsRef<Path>>(backup_path: P, target_path: &str) -> Result<FlexibleDatabase> {
+        let source = Connection::open(backup_path)?;
+        let mut db = FlexibleDatabase::new(target_path)?;
+        copy_database(&source, &mut db.conn, &mut |_| {})?;
+        db.load_schemas()?;
+        Ok(db)
+    }
+}
+
+fn copy_database(
+    source: &Connection,
+    target: &mut Connection,
+    progress: &mut dyn FnMut(BackupProgress),
+) -> Result<()> {
+    let backup = Backup::new_with_names(source, DatabaseName::Main, target, DatabaseName::Main)?;
+    loop {
+        let step = backup.step(PAGES_PER_STEP)?;
+        let p = backup.progress();
+        progress(BackupProgress {
+            remaining_pages: p.remaining,
+            total_pages: p.pagecount,
+        });
+        match step {
+            StepResult::Done => return Ok(()),
+            StepResult::More => {}
+            // Busy or Locked: another connection holds the database
+            _ => thread::sleep(RETRY_DELAY),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flexible_database::{FieldType, Schema};
+    use crate::model_builder::ModelBuilder;
+    use crate::temp_file::TempFile;
+
+    #[test]
+    fn backups_restore_with_their_schemas() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("notes", [("body".to_string(), FieldType::Text)]))
+            .unwrap();
+        db.create_model("notes", ModelBuilder::new().text("body", "kept").build())
+            .unwrap();
+
+        let backup = TempFile::new("db");
+        let mut steps = Vec::new();
+        db.backup_to(backup.path(), |progress| steps.push(progress)).unwrap();
+        assert_eq!(steps.last().map(|p| p.remaining_pages), Some(0));
+
+        let restored = FlexibleDatabase::restore_from(backup.path(), ":memory:").unwrap();
+        let model = restored.get_model("notes", 1).unwrap().unwrap();
+        assert_eq!(model.get_str("body").unwrap(), "kept");
+    }
+
+    #[test]
+    fn large_backups_report_every_step() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("notes", [("body".to_string(), FieldType::Text)]))
+            .unwrap();
+        db.transaction(|db| {
+            for _ in 0..2000 {
+                db.create_model("notes", ModelBuilder::new().text("body", "x".repeat(500)).build())?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        let backup = TempFile::new("db");
+        let mut steps = Vec::new();
+        db.backup_to(backup.path(), |progress| steps.push(progress)).unwrap();
+        assert!(steps.len() > 1);
+        assert!(steps.windows(2).all(|w| w[1].remaining_pages < w[0].remaining_pages));
+        assert!(steps.iter().all(|p| p.total_pages == steps[0].total_pages));
+        assert_eq!(steps.last().unwrap().remaining_pages, 0);
+
+        let restored = FlexibleDatabase::restore_from(backup.path(), ":memory:").unwrap();
+        let last = restored.get_model("notes", 2000).unwrap().unwrap();
+        assert_eq!(last.get_str("body").unwrap().len(), 500);
+    }
+
+    #[test]
+    fn restores_replace_what_the_target_held() {
+        let (source, backup, target) = (TempFile::new("db"), TempFile::new("db"), TempFile::new("db"));
+        let mut db = FlexibleDatabase::new(source.path()).unwrap();
+        db.define_schema(Schema::new("notes", [("body".to_string(), FieldType::Text)]))
+            .unwrap();
+        db.create_model("notes", ModelBuilder::new().text("body", "kept").build())
+            .unwrap();
+        db.backup_to(backup.path(), |_| {}).unwrap();
+
+        let mut old = FlexibleDatabase::new(target.path()).unwrap();
+        old.define_schema(Schema::new("tags", [("label".to_string(), FieldType::Text)]))
+            .unwrap();
+        drop(old);
+
+        let restored = FlexibleDatabase::restore_from(backup.path(), target.path()).unwrap();
+        assert!(restored.schemas.contains_key("notes"));
+        assert!(!restored.schemas.contains_key("tags"));
+        drop(restored);
+        let mut reopened = FlexibleDatabase::new(target.path()).unwrap();
+        reopened.load_schemas().unwrap();
+        let model = reopened.get_model("notes", 1).unwrap().unwrap();
+        assert_eq!(model.get_str("body").unwrap(), "kept");
+    }
+}
@@ -0,0 +1,445 @@
+use clap::{Subcommand, ValueEnum};
+use rusqlite::types::Value;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use koo_db::alter::DropBehavior;
+use koo_db::error::{KooError, Result};
+use koo_db::flexible_database::{FieldType, FlexibleDatabase, PrimaryKey, Schema};
+use koo_db::import::{ConflictStrategy, ImportOptions};
+use koo_db::migrations::Migration;
+use koo_db::query::{Direction, Op, Query};
+use koo_db::wire::{data_from_json, model_id_from_str, model_to_json, value_from_str};
+
+use crate::migrations;
+use crate::table::print_table;
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[command(subcommand, about = "List, show, define and drop schemas")]
+    Schema(SchemaCommand),
+    #[command(about = "Print one model as JSON")]
+    Get { schema: String, id: String },
+    #[command(about = "Insert a model given as a JSON object and print its id")]
+    Insert {
+        schema: String,
+        #[arg(help = "e.g. '{\"name\": \"Alice\", \"age\": 30}'")]
+        data: String,
+    },
+    #[command(about = "Print matching models, one JSON object per line")]
+    Query {
+        schema: String,
+        #[arg(long = "where", value_name = "CONDITION", help = "e.g. 'age>=30'; may be repeated")]
+        conditions: Vec<String>,
+        #[arg(long, value_name = "FIELD[:desc]", help = "Sort by a field; may be repeated")]
+        order: Vec<String>,
+        #[arg(long)]
+        limit: Option<usize>,
+        #[arg(long, default_value_t = 0)]
+        offset: usize,
+    },
+    #[command(about = "Export every schema as JSON, or one schema as JSON or CSV")]
+    Export {
+        file: PathBuf,
+        #[arg(long)]
+        schema: Option<String>,
+    },
+    #[command(about = "Import a JSON export, or a CSV file into one schema")]
+    Import {
+        file: PathBuf,
+        #[arg(long, help = "Schema to load a CSV file into")]
+        schema: Option<String>,
+        #[arg(long, value_enum, default_value_t = OnConflict::Error)]
+        on_conflict: OnConflict,
+        #[arg(long, help = "Give imported rows new integer ids")]
+        fresh_ids: bool,
+    },
+    #[command(subcommand, about = "Apply or revert SQL migrations")]
+    Migrate(MigrateCommand),
+    #[command(about = "Start an interactive shell; also the default without a command")]
+    Shell,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SchemaCommand {
+    #[command(about = "List schemas with their fields and row counts")]
+    List,
+    #[command(about = "Print a schema definition as JSON")]
+    Show { name: String },
+    #[command(about = "Define the schemas in a JSON or YAML file")]
+    Define { file: PathBuf },
+    #[command(about = "Forget a schema, keeping its table unless --drop-table is given")]
+    Drop {
+        name: String,
+        #[arg(long)]
+        drop_table: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MigrateCommand {
+    #[command(about = "List applied migrations")]
+    Status,
+    #[command(about = "Apply the pending migrations in a directory of <version>_<name>.sql files")]
+    Up { dir: PathBuf },
+    #[command(about = "Revert applied migrations using their .down.sql files")]
+    Down {
+        dir: PathBuf,
+        #[arg(long, default_value_t = 1)]
+        steps: usize,
+    },
+}
+
+// How models are printed: JSON for scripts, tables in the shell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Table,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum OnConflict {
+    Skip,
+    Overwrite,
+    Error,
+}
+
+pub fn run(db: &mut FlexibleDatabase, command: Command, format: Format) -> Result<()> {
+    match command {
+        Command::Schema(command) => run_schema(db, command),
+        Command::Get { schema, id } => {
+            let schema = find_schema(db, &schema)?;
+            let id = model_id_from_str(schema, &id)?;
+            match db.get_model(&schema.name, id.clone())? {
+                Some(model) if format == Format::Table => {
+                    print_table(schema, &[model]);
+                    Ok(())
+                }
+                Some(model) => {
+                    println!("{}", serde_json::to_string_pretty(&model_to_json(schema, &model))?);
+                    Ok(())
+                }
+                None => Err(KooError::InvalidData(format!("{} {} not found", schema.name, id))),
+            }
+        }
+        Command::Insert { schema, data } => {
+            let schema = find_schema(db, &schema)?;
+            let data = data_from_json(schema, &serde_json::from_str(&data)?)?;
+            let id = db.create_model(&schema.name, data)?;
+            println!("{}", id);
+            Ok(())
+        }
+        Command::Query {
+            schema,
+            conditions,
+            order,
+            limit,
+            offset,
+        } => {
+            let schema = find_schema(db, &schema)?;
+            let mut query = Query::new(&schema.name).offset(offset);
+            for condition in &conditions {
+                let (field_name, op, value) = parse_condition(schema, condition)?;
+                query = query.filter(&field_name, op, value);
+            }
+            for order in &order {
+                let (field_name, direction) = match order.rsplit_once(':') {
+                    None => (order.as_str(), Direction::Asc),
+                    Some((field_name, "asc")) => (field_name, Direction::Asc),
+                    Some((field_name, "desc")) => (field_name, Direction::Desc),
+                    Some(_) => {
+                        return Err(KooError::InvalidData(format!(
+                            "'{}' should be field[:asc|:desc]",
+                            order
+                        )));
+                    }
+                };
+                query = query.order_by(field_name, direction);
+            }
+            if let Some(limit) = limit {
+                query = query.limit(limit);
+            }
+
+            let models = db.find(&query)?;
+            match format {
+                Format::Table => print_table(schema, &models),
+                Format::Json => {
+                    for model in &models {
+                        println!("{}", model_to_json(schema, model));
+                    }
+                }
+            }
+            Ok(())
+        }
+        Command::Export { file, schema } => {
+            match schema {
+                Some(schema) if is_csv(&file) => {
+                    let written = db.export_csv(&schema, &file)?;
+                    eprintln!("exported {} rows", written);
+                }
+                Some(schema) => db.export_schema_json(&schema, BufWriter::new(File::create(&file)?))?,
+                None if is_csv(&file) => {
+                    return Err(KooError::InvalidData("CSV exports need --schema".to_string()));
+                }
+                None => db.export_json(BufWriter::new(File::create(&file)?))?,
+            }
+            Ok(())
+        }
+        Command::Import {
+            file,
+            schema,
+            on_conflict,
+            fresh_ids,
+        } => {
+            if is_csv(&file) {
+                let schema = schema.ok_or_else(|| KooError::InvalidData("CSV imports need --schema".to_string()))?;
+                let inserted = db.import_csv(&schema, &file, true)?;
+                eprintln!("imported {} rows", inserted);
+                return Ok(());
+            }
+
+            let options = ImportOptions {
+                on_conflict: match on_conflict {
+                    OnConflict::Skip => ConflictStrategy::Skip,
+                    OnConflict::Overwrite => ConflictStrategy::Overwrite,
+                    OnConflict::Error => ConflictStrategy::Error,
+                },
+                preserve_ids: !fresh_ids,
+            };
+            let report = db.import_json(BufReader::new(File::open(&file)?), options)?;
+            eprintln!(
+                "imported {} schemas, {} rows inserted, {} skipped",
+                report.schemas, report.inserted, report.skipped
+            );
+            Ok(())
+        }
+        Command::Migrate(command) => run_migrate(db, command),
+        Command::Shell => Err(KooError::InvalidData("the shell is already running".to_string())),
+    }
+}
+
+fn run_schema(db: &mut FlexibleDatabase, command: SchemaCommand) -> Result<()> {
+    match command {
+        SchemaCommand::List => {
+            for schema in db.schemas() {
+                let fields: Vec<String> = schema
+                    .fields
+                    .iter()
+                    .map(|(name, field_type)| format!("{}: {}", name, field_type.name()))
+                    .collect();
+                println!(
+                    "{} ({} rows) {{{}}}",
+                    schema.name,
+                    db.count(&schema.name)?,
+                    fields.join(", ")
+                );
+            }
+            Ok(())
+        }
+        SchemaCommand::Show { name } => {
+            let schema = find_schema(db, &name)?;
+            let json: serde_json::Value = serde_json::from_str(&schema.to_json())?;
+            println!("{}", serde_json::to_string_pretty(&json)?);
+            Ok(())
+        }
+        SchemaCommand::Define { file } => {
+            let defined = db.define_schemas_from_file(&file)?;
+            eprintln!("defined {} schemas", defined);
+            Ok(())
+        }
+        SchemaCommand::Drop { name, drop_table } => {
+            let behavior = if drop_table {
+                DropBehavior::DropTable
+            } else {
+                DropBehavior::KeepData
+            };
+            db.drop_schema(&name, behavior)
+        }
+    }
+}
+
+fn run_migrate(db: &mut FlexibleDatabase, command: MigrateCommand) -> Result<()> {
+    match command {
+        MigrateCommand::Status => {
+            for migration in db.applied_migrations()? {
+                println!("{}\t{}\t{}", migration.version, migration.name, migration.applied_at);
+            }
+            Ok(())
+        }
+        MigrateCommand::Up { dir } => {
+            let migrations = migrations::read_dir(&dir)?;
+            let migrations: Vec<&dyn Migration> = migrations.iter().map(|m| m as &dyn Migration).collect();
+            let applied = db.migrate(&migrations)?;
+            eprintln!("applied {} migrations", applied);
+            Ok(())
+        }
+        MigrateCommand::Down { dir, steps } => {
+            let migrations = migrations::read_dir(&dir)?;
+            let migrations: Vec<&dyn Migration> = migrations.iter().map(|m| m as &dyn Migration).collect();
+            let reverted = db.rollback(&migrations, steps)?;
+            eprintln!("reverted {} migrations", reverted);
+            Ok(())
+        }
+    }
+}
+
+fn find_schema<'a>(db: &'a FlexibleDatabase, name: &str) -> Result<&'a Schema> {
+    db.schema(name)
+        .ok_or_else(|| KooError::SchemaNotFound(name.to_string()))
+}
+
+fn is_csv(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e.eq_ignore_ascii_case("csv"))
+}
+
+// "age>=30" into the field, comparison and typed value
+fn parse_condition(schema: &Schema, condition: &str) -> Result<(String, Op, Value)> {
+    let bad_condition = || KooError::InvalidData(format!("'{}' should look like field=value", condition));
+
+    let start = condition.find(['=', '!', '<', '>']).ok_or_else(bad_condition)?;
+    let (field_name, rest) = condition.split_at(start);
+    let (op, raw) = [
+        (">=", Op::Ge),
+        ("<=", Op::Le),
+        ("!=", Op::Ne),
+        ("=", Op::Eq),
+        ("<", Op::Lt),
+        (">", Op::Gt),
+    ]
+    .into_iter()
+    .find_map(|(symbol, op)| rest.strip_prefix(symbol).map(|raw| (op, raw)))
+    .ok_or_else(bad_condition)?;
+
+    let field_name = field_name.trim();
+    let field_type = match (field_name, &schema.key) {
+        ("id", PrimaryKey::Text) => &FieldType::Text,
+        ("id", PrimaryKey::Integer) => &FieldType::Integer,
+        _ => schema.fields.get(field_name).ok_or_else(|| KooError::UnknownField {
+            schema: schema.name.clone(),
+            field: field_name.to_string(),
+        })?,
+    };
+
+    let value = value_from_str(raw, field_type, &schema.name, field_name)?;
+    Ok((field_name.to_string(), op, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn people() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new(
+            "people",
+            [
+                ("name".to_string(), FieldType::Text),
+                ("age".to_string(), FieldType::Integer),
+                ("member".to_string(), FieldType::Boolean),
+            ],
+        );
+        db.define_schema(schema).unwrap();
+        db
+    }
+
+    fn insert(db: &mut FlexibleDatabase, data: &str) {
+        let command = Command::Insert {
+            schema: "people".to_string(),
+            data: data.to_string(),
+        };
+        run(db, command, Format::Json).unwrap();
+    }
+
+    #[test]
+    fn conditions_are_parsed_with_typed_values() {
+        let db = people();
+        let schema = db.schema("people").unwrap();
+        let parsed = |condition: &str| parse_condition(schema, condition).unwrap();
+        assert_eq!(parsed("age>=30"), ("age".to_string(), Op::Ge, Value::Integer(30)));
+        assert_eq!(parsed("age != 4"), ("age".to_string(), Op::Ne, Value::Integer(4)));
+        assert_eq!(
+            parsed("name=a=b"),
+            ("name".to_string(), Op::Eq, Value::Text("a=b".to_string()))
+        );
+        assert_eq!(parsed("member=true"), ("member".to_string(), Op::Eq, Value::Integer(1)));
+        assert_eq!(parsed("id<3"), ("id".to_string(), Op::Lt, Value::Integer(3)));
+
+        assert!(parse_condition(schema, "age").is_err());
+        assert!(parse_condition(schema, "age=old").is_err());
+        assert!(matches!(
+            parse_condition(schema, "height>1"),
+            Err(KooError::UnknownField { .. })
+        ));
+    }
+
+    #[test]
+    fn inserted_models_can_be_got_and_queried() {
+        let mut db = people();
+        insert(&mut db, r#"{"name": "Ada", "age": 36, "member": true}"#);
+        insert(&mut db, r#"{"name": "Bob", "age": 20, "member": false}"#);
+        assert_eq!(db.count("people").unwrap(), 2);
+
+        let get = |id: &str| Command::Get {
+            schema: "people".to_string(),
+            id: id.to_string(),
+        };
+        run(&mut db, get("1"), Format::Json).unwrap();
+        assert!(run(&mut db, get("3"), Format::Json).is_err());
+        assert!(run(&mut db, get("first"), Format::Json).is_err());
+
+        let query = |order: &str| Command::Query {
+            schema: "people".to_string(),
+            conditions: vec!["age>18".to_string()],
+            order: vec![order.to_string()],
+            limit: Some(1),
+            offset: 0,
+        };
+        run(&mut db, query("age:desc"), Format::Table).unwrap();
+        assert!(run(&mut db, query("age:sideways"), Format::Json).is_err());
+    }
+
+    #[test]
+    fn unknown_schemas_are_reported() {
+        let mut db = people();
+        let command = Command::Get {
+            schema: "pets".to_string(),
+            id: "1".to_string(),
+        };
+        assert!(matches!(
+            run(&mut db, command, Format::Json),
+            Err(KooError::SchemaNotFound(name)) if name == "pets"
+        ));
+    }
+
+    #[test]
+    fn csv_files_need_a_schema() {
+        let mut db = people();
+        let export = Command::Export {
+            file: PathBuf::from("people.csv"),
+            schema: None,
+        };
+        assert!(run(&mut db, export, Format::Json).is_err());
+        let import = Command::Import {
+            file: PathBuf::from("people.csv"),
+            schema: None,
+            on_conflict: OnConflict::Error,
+            fresh_ids: false,
+        };
+        assert!(run(&mut db, import, Format::Json).is_err());
+    }
+
+    #[test]
+    fn schemas_can_be_dropped_keeping_their_table() {
+        let mut db = people();
+        insert(&mut db, r#"{"name": "Ada", "age": 36, "member": true}"#);
+        let drop = SchemaCommand::Drop {
+            name: "people".to_string(),
+            drop_table: false,
+        };
+        run(&mut db, Command::Schema(drop), Format::Json).unwrap();
+        assert!(db.schema("people").is_none());
+        let rows = db.query_raw("SELECT name FROM people", &[]).unwrap();
+        assert_eq!(rows.len(), 1);
+    }
+}
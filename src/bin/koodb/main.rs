@@ -0,0 +1,49 @@
+// Command line tool for inspecting and seeding kooDB databases. Schemas are
+// read from the database's catalog, so anything defined through the
+// library is available here.
+
+mod commands;
+mod migrations;
+mod shell;
+mod table;
+
+use clap::Parser;
+use koo_db::flexible_database::FlexibleDatabase;
+use std::ffi::OsString;
+use std::process::ExitCode;
+
+use commands::{Command, Format};
+
+#[derive(Debug, Parser)]
+#[command(name = "koodb", version, about = "Inspect and manage kooDB databases")]
+struct Cli {
+    #[arg(help = "Database file, created if it doesn't exist")]
+    database: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+fn main() -> ExitCode {
+    // `koodb shell <database>` is accepted too, as it reads more naturally
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    if args.len() == 3 && args[1] == "shell" {
+        args.swap(1, 2);
+    }
+    let cli = Cli::parse_from(args);
+
+    let result = FlexibleDatabase::new(&cli.database).and_then(|mut db| {
+        db.load_schemas()?;
+        match cli.command {
+            None | Some(Command::Shell) => shell::run(&mut db, &cli.database),
+            Some(command) => commands::run(&mut db, command, Format::Json),
+        }
+    });
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::path::Path;
+
+use koo_db::error::{KooError, Result};
+use koo_db::flexible_database::FlexibleDatabase;
+use koo_db::migrations::Migration;
+
+// A migration read from a directory of SQL files: `<version>_<name>.sql`
+// holds the change and the optional `<version>_<name>.down.sql` reverts it
+#[derive(Debug)]
+pub struct SqlMigration {
+    version: i64,
+    name: String,
+    up: String,
+    down: Option<String>,
+}
+
+impl Migration for SqlMigration {
+    fn version(&self) -> i64 {
+        self.version
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn up(&self, db: &mut FlexibleDatabase) -> Result<()> {
+        db.conn.execute_batch(&self.up)?;
+        Ok(())
+    }
+
+    fn down(&self, db: &mut FlexibleDatabase) -> Result<()> {
+        let down = self.down.as_ref().ok_or_else(|| {
+            KooError::InvalidData(format!(
+                "migration {}_{} has no .down.sql file",
+                self.version, self.name
+            ))
+        })?;
+        db.conn.execute_batch(down)?;
+        Ok(())
+    }
+}
+
+// Migrations in `dir`, ordered by version
+pub fn read_dir(dir: &Path) -> Result<Vec<SqlMigration>> {
+    let mut migrations = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(file_name) = path.file_name().and_then(|f| f.to_str()) else {
+            continue;
+        };
+        let Some(stem) = file_name.strip_suffix(".sql") else {
+            continue;
+        };
+        if stem.ends_with(".down") {
+            continue;
+        }
+
+        let (version, name) = stem
+            .split_once('_')
+            .and_then(|(version, name)| Some((version.parse::<i64>().ok()?, name)))
+            .ok_or_else(|| KooError::InvalidData(format!("'{}' should be named <version>_<name>.sql", file_name)))?;
+
+        let down_path = path.with_file_name(format!("{}.down.sql", stem));
+        let down = if down_path.exists() {
+            Some(std::fs::read_to_string(&down_path)?)
+        } else {
+            None
+        };
+        migrations.push(SqlMigration {
+            version,
+            name: name.to_string(),
+            up: std::fs::read_to_string(&path)?,
+            down,
+        });
+    }
+
+    migrations.sort_by_key(|m| m.version);
+    if let Some(pair) = migrations.windows(2).find(|pair| pair[0].version == pair[1].version) {
+        return Err(KooError::InvalidData(format!(
+            "migrations '{}' and '{}' share version {}",
+            pair[0].name, pair[1].name, pair[0].version
+        )));
+    }
+    Ok(migrations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A directory holding the given files, removed when the test is done
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn with_files(files: &[(&str, &str)]) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("koo-migrations-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir(&dir).unwrap();
+            for (name, contents) in files {
+                std::fs::write(dir.join(name), contents).unwrap();
+            }
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn migrations_are_read_in_version_order_with_their_down_files() {
+        let dir = TempDir::with_files(&[
+            ("10_add_index.sql", "CREATE INDEX t_n ON t (n);"),
+            ("2_create.sql", "CREATE TABLE t (n INTEGER);"),
+            ("2_create.down.sql", "DROP TABLE t;"),
+            ("notes.txt", "not a migration"),
+        ]);
+        let migrations = read_dir(&dir.0).unwrap();
+        let names: Vec<(i64, &str)> = migrations.iter().map(|m| (m.version, m.name.as_str())).collect();
+        assert_eq!(names, [(2, "create"), (10, "add_index")]);
+        assert!(migrations[0].down.is_some());
+        assert!(migrations[1].down.is_none());
+
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let migrations: Vec<&dyn Migration> = migrations.iter().map(|m| m as &dyn Migration).collect();
+        assert_eq!(db.migrate(&migrations).unwrap(), 2);
+        // The index has no down file, so it can't be reverted
+        assert!(db.rollback(&migrations, 1).is_err());
+    }
+
+    #[test]
+    fn misnamed_or_clashing_files_are_refused() {
+        let dir = TempDir::with_files(&[("create.sql", "")]);
+        assert!(matches!(read_dir(&dir.0), Err(KooError::InvalidData(_))));
+
+        let dir = TempDir::with_files(&[("1_a.sql", ""), ("1_b.sql", "")]);
+        assert!(matches!(read_dir(&dir.0), Err(KooError::InvalidData(message)) if message.contains("share version 1")));
+    }
+}
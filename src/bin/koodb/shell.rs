@@ -0,0 +1,261 @@
+use clap::Parser;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Context, Editor, Helper, Highlighter, Hinter, Validator};
+use std::path::PathBuf;
+
+use koo_db::error::{KooError, Result};
+use koo_db::flexible_database::FlexibleDatabase;
+
+use crate::commands::{self, Command, Format};
+
+const COMMANDS: &[&str] = &[
+    "schema", "get", "insert", "query", "export", "import", "migrate", "help", "exit",
+];
+const SCHEMA_COMMANDS: &[&str] = &["list", "show", "define", "drop"];
+const MIGRATE_COMMANDS: &[&str] = &["status", "up", "down"];
+
+// One line typed into the shell: the same commands as the CLI, without the
+// database argument
+#[derive(Debug, Parser)]
+#[command(name = "", no_binary_name = true, disable_version_flag = true)]
+struct ShellLine {
+    #[command(subcommand)]
+    command: Command,
+}
+
+// Read commands until `exit` or end of input. Query results are printed as
+// tables, and lines are kept in ~/.koodb_history between sessions.
+pub fn run(db: &mut FlexibleDatabase, database: &str) -> Result<()> {
+    let mut editor = Editor::<ShellHelper, DefaultHistory>::new().map_err(readline_error)?;
+    editor.set_helper(Some(ShellHelper::default()));
+    if let Some(helper) = editor.helper_mut() {
+        helper.refresh(db);
+    }
+    let history = history_path();
+    if let Some(path) = &history {
+        // There is no history file on first use
+        let _ = editor.load_history(path);
+    }
+
+    println!("kooDB shell on {}. Type help for commands and exit to leave.", database);
+    loop {
+        let line = match editor.readline("koodb> ") {
+            Ok(line) => line,
+            // Ctrl-C drops the line being typed, as in other shells
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(readline_error(err)),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line);
+        if matches!(line, "exit" | "quit") {
+            break;
+        }
+
+        let words = match split_words(line) {
+            Ok(words) => words,
+            Err(err) => {
+                eprintln!("error: {}", err);
+                continue;
+            }
+        };
+        match ShellLine::try_parse_from(words) {
+            Ok(parsed) => {
+                if let Err(err) = commands::run(db, parsed.command, Format::Table) {
+                    eprintln!("error: {}", err);
+                }
+            }
+            // Also how `help` and `--help` are shown
+            Err(err) => {
+                let _ = err.print();
+            }
+        }
+
+        // Commands can define or drop schemas
+        if let Some(helper) = editor.helper_mut() {
+            helper.refresh(db);
+        }
+    }
+
+    if let Some(path) = &history {
+        editor.save_history(path).map_err(readline_error)?;
+    }
+    Ok(())
+}
+
+// Completes command names, then schema and field names
+#[derive(Default, Helper, Hinter, Highlighter, Validator)]
+struct ShellHelper {
+    // Schema names with their field names
+    schemas: Vec<(String, Vec<String>)>,
+}
+
+impl ShellHelper {
+    fn refresh(&mut self, db: &FlexibleDatabase) {
+        self.schemas = db
+            .schemas()
+            .into_iter()
+            .map(|schema| (schema.name.clone(), schema.fields.keys().cloned().collect()))
+            .collect();
+    }
+
+    fn candidates(&self, previous: &[&str]) -> Vec<String> {
+        match previous {
+            [] => return COMMANDS.iter().map(|c| c.to_string()).collect(),
+            ["schema"] => return SCHEMA_COMMANDS.iter().map(|c| c.to_string()).collect(),
+            ["migrate"] => return MIGRATE_COMMANDS.iter().map(|c| c.to_string()).collect(),
+            _ => {}
+        }
+
+        // Once a schema has been named its fields are what's left to type;
+        // `--where` and `--order` values start with one
+        let named = self.schemas.iter().find(|(name, _)| previous.contains(&name.as_str()));
+        match named {
+            Some((_, fields)) => fields.clone(),
+            None => self.schemas.iter().map(|(name, _)| name.clone()).collect(),
+        }
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let before = &line[..pos];
+        let start = before
+            .rfind(|c: char| c.is_whitespace() || c == '\'' || c == '"')
+            .map_or(0, |i| i + 1);
+        let prefix = &before[start..];
+        if prefix.starts_with('-') {
+            return Ok((start, Vec::new()));
+        }
+
+        let previous: Vec<&str> = before[..start].split_whitespace().collect();
+        let matches = self
+            .candidates(&previous)
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(prefix))
+            .map(|candidate| Pair {
+                display: candidate.clone(),
+                replacement: candidate,
+            })
+            .collect();
+        Ok((start, matches))
+    }
+}
+
+// Split a line into words, honouring single and double quotes so JSON can
+// be typed as one argument. Backslash escapes the next character inside
+// double quotes.
+fn split_words(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if let Some(word) = word.take() {
+                    words.push(word);
+                }
+            }
+            '\'' | '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some(end) if end == c => break,
+                        Some('\\') if c == '"' => word.extend(chars.next()),
+                        Some(other) => word.push(other),
+                        None => return Err(KooError::InvalidData(format!("unclosed {} quote", c))),
+                    }
+                }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".koodb_history"))
+}
+
+fn readline_error(err: ReadlineError) -> KooError {
+    match err {
+        ReadlineError::Io(err) => KooError::Io(err),
+        err => KooError::Io(std::io::Error::other(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use koo_db::flexible_database::{FieldType, Schema};
+
+    fn helper() -> ShellHelper {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let fields = [
+            ("name".to_string(), FieldType::Text),
+            ("nickname".to_string(), FieldType::Text),
+        ];
+        db.define_schema(Schema::new("people", fields)).unwrap();
+        db.define_schema(Schema::new("pets", [("name".to_string(), FieldType::Text)]))
+            .unwrap();
+        let mut helper = ShellHelper::default();
+        helper.refresh(&db);
+        helper
+    }
+
+    fn complete(helper: &ShellHelper, line: &str) -> (usize, Vec<String>) {
+        let history = DefaultHistory::new();
+        let (start, pairs) = helper.complete(line, line.len(), &Context::new(&history)).unwrap();
+        (start, pairs.into_iter().map(|pair| pair.replacement).collect())
+    }
+
+    #[test]
+    fn words_are_split_on_whitespace_outside_quotes() {
+        let words = split_words(r#"insert people '{"name": "Ada"}'  "a \"b\"" c"#).unwrap();
+        assert_eq!(words, ["insert", "people", r#"{"name": "Ada"}"#, r#"a "b""#, "c"]);
+        assert_eq!(
+            split_words("query people --where 'name=Bo b'x").unwrap()[3],
+            "name=Bo bx"
+        );
+        assert!(split_words("insert people '{").is_err());
+        assert!(split_words("   ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn commands_then_subcommands_are_completed() {
+        let helper = helper();
+        assert_eq!(
+            complete(&helper, "ex"),
+            (0, vec!["export".to_string(), "exit".to_string()])
+        );
+        assert_eq!(
+            complete(&helper, "schema d"),
+            (7, vec!["define".to_string(), "drop".to_string()])
+        );
+        assert_eq!(complete(&helper, "migrate u").1, ["up"]);
+    }
+
+    #[test]
+    fn schemas_then_their_fields_are_completed() {
+        let helper = helper();
+        assert_eq!(complete(&helper, "get pe").1, ["people", "pets"]);
+        assert_eq!(complete(&helper, "query people --where n").1, ["name", "nickname"]);
+        assert_eq!(complete(&helper, "query people --where 'nick").1, ["nickname"]);
+        assert!(complete(&helper, "query people --wh").1.is_empty());
+    }
+
+    #[test]
+    fn shell_lines_parse_as_cli_commands() {
+        let parsed = ShellLine::try_parse_from(["get", "people", "1"]).unwrap();
+        assert!(matches!(parsed.command, Command::Get { schema, id } if schema == "people" && id == "1"));
+        assert!(ShellLine::try_parse_from(["fetch", "people"]).is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use koo_db::flexible_database::{Model, Schema};
+use koo_db::wire::model_to_json;
+
+// Longest cell printed before it is cut short
+const MAX_CELL_WIDTH: usize = 40;
+
+// Print models as an aligned table with a header row, one column per entry
+// of the export row layout
+pub fn print_table(schema: &Schema, models: &[Model]) {
+    let rows: Vec<serde_json::Map<String, serde_json::Value>> = models
+        .iter()
+        .filter_map(|model| match model_to_json(schema, model) {
+            serde_json::Value::Object(object) => Some(object),
+            _ => None,
+        })
+        .collect();
+
+    let mut columns: Vec<String> = Vec::new();
+    if schema.key.has_id_column() {
+        columns.push("id".to_string());
+    }
+    columns.extend(schema.fields.keys().cloned());
+    if schema.versioned {
+        columns.push("version".to_string());
+    }
+
+    let cells: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| columns.iter().map(|column| cell(row.get(column))).collect())
+        .collect();
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            let widest = cells.iter().map(|row| row[i].chars().count()).max().unwrap_or(0);
+            widest.max(column.chars().count())
+        })
+        .collect();
+
+    print_row(&columns, &widths);
+    let rule: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    println!("{}", rule.join("-+-"));
+    for row in &cells {
+        print_row(row, &widths);
+    }
+    match rows.len() {
+        1 => println!("(1 row)"),
+        count => println!("({} rows)", count),
+    }
+}
+
+fn print_row(cells: &[String], widths: &[usize]) {
+    let padded: Vec<String> = cells
+        .iter()
+        .zip(widths)
+        .map(|(cell, width)| format!("{:width$}", cell, width = width))
+        .collect();
+    println!("{}", padded.join(" | ").trim_end());
+}
+
+fn cell(value: Option<&serde_json::Value>) -> String {
+    let text = match value {
+        None | Some(serde_json::Value::Null) => "NULL".to_string(),
+        Some(serde_json::Value::String(s)) => s.clone(),
+        Some(other) => other.to_string(),
+    };
+    // Keep every row on one line
+    let text = text.replace(['\n', '\r', '\t'], " ");
+    if text.chars().count() > MAX_CELL_WIDTH {
+        let cut: String = text.chars().take(MAX_CELL_WIDTH - 1).collect();
+        format!("{}…", cut)
+    } else {
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cells_show_nulls_and_stay_on_one_line() {
+        assert_eq!(cell(None), "NULL");
+        assert_eq!(cell(Some(&serde_json::Value::Null)), "NULL");
+        assert_eq!(cell(Some(&"two\nlines".into())), "two lines");
+        assert_eq!(cell(Some(&serde_json::json!(true))), "true");
+        assert_eq!(cell(Some(&serde_json::json!([1, 2]))), "[1,2]");
+    }
+
+    #[test]
+    fn long_cells_are_cut_short() {
+        let long = "é".repeat(MAX_CELL_WIDTH + 5);
+        let shown = cell(Some(&long.into()));
+        assert_eq!(shown.chars().count(), MAX_CELL_WIDTH);
+        assert!(shown.ends_with('…'));
+        let fits = "x".repeat(MAX_CELL_WIDTH);
+        assert_eq!(cell(Some(&fits.clone().into())), fits);
+    }
+}
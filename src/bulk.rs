@@ -0,0 +1,215 @@
+use rusqlite::types::Value;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::diff::index_definitions;
+use crate::error::Result;
+use crate::flexible_database::FlexibleDatabase;
+
+// Models created per transaction when the options don't say
+const DEFAULT_CHUNK_SIZE: usize = 10_000;
+
+// How `bulk_load` goes about a load
+pub struct BulkOptions<'a> {
+    chunk_size: usize,
+    drop_indexes: bool,
+    progress: Option<Box<dyn FnMut(BulkProgress) + 'a>>,
+}
+
+impl Default for BulkOptions<'_> {
+    fn default() -> Self {
+        BulkOptions {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            drop_indexes: true,
+            progress: None,
+        }
+    }
+}
+
+impl<'a> BulkOptions<'a> {
+    pub fn new() -> BulkOptions<'a> {
+        BulkOptions::default()
+    }
+
+    // Create at most this many models per transaction
+    pub fn chunk_size(mut self, chunk_size: usize) -> BulkOptions<'a> {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    // Whether to drop the table's indexes while loading and create them
+    // again at the end, which is quicker than updating them row by row.
+    // Unique indexes are always kept, as they check the data. On by default.
+    pub fn drop_indexes(mut self, enabled: bool) -> BulkOptions<'a> {
+        self.drop_indexes = enabled;
+        self
+    }
+
+    // Called after each chunk is committed
+    pub fn on_progress(mut self, progress: impl FnMut(BulkProgress) + 'a) -> BulkOptions<'a> {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+}
+
+// Reported after each chunk `bulk_load` commits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BulkProgress {
+    // Models created so far
+    pub loaded: usize,
+    // Since the load started
+    pub elapsed: Duration,
+    // Models per second so far
+    pub rate: f64,
+}
+
+impl FlexibleDatabase {
+    // Create a model in `schema_name` for each of `rows`, a chunk per
+    // transaction, returning how many were created. Each goes through
+    // `create_model`, so defaults, validation and encryption apply. A
+    // failure stops the load, keeping the chunks already committed; the
+    // dropped indexes are created again either way.
+    pub fn bulk_load(
+        &mut self,
+        schema_name: &str,
+        rows: impl IntoIterator<Item = HashMap<String, Value>>,
+        mut options: BulkOptions,
+    ) -> Result<usize> {
+        self.schema_or_err(schema_name)?;
+        // Indexes of attached tables live in the attached file's catalog
+        let dropped = match options.drop_indexes && !schema_name.contains('.') {
+            true => index_definitions(&self.conn, schema_name)?
+                .into_iter()
+                .filter(|index| !index.sql.trim_start().to_uppercase().starts_with("CREATE UNIQUE"))
+                .collect(),
+            false => Vec::new(),
+        };
+        for index in &dropped {
+            self.conn.execute_batch(&format!("DROP INDEX {}", index.name))?;
+        }
+
+        let started = Instant::now();
+        let mut rows = rows.into_iter().peekable();
+        let mut loaded = 0;
+        let mut result = Ok(());
+        while rows.peek().is_some() {
+            let chunk = self.in_transaction(|db| {
+                let mut created = 0;
+                for data in rows.by_ref().take(options.chunk_size) {
+                    db.create_model(schema_name, data)?;
+                    created += 1;
+                }
+                Ok(created)
+            });
+            match chunk {
+                Ok(created) => loaded += created,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+            if let Some(progress) = options.progress.as_mut() {
+                let elapsed = started.elapsed();
+                progress(BulkProgress {
+                    loaded,
+                    elapsed,
+                    rate: loaded as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+                });
+            }
+        }
+
+        for index in &dropped {
+            self.conn.execute_batch(&index.sql)?;
+        }
+        result.map(|()| loaded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::error::KooError;
+    use crate::flexible_database::{FieldDef, FieldType, Schema};
+    use crate::validation::Validator;
+
+    fn database() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("readings", [])
+            .field("sensor", FieldDef::new(FieldType::Text).indexed())
+            .field("serial", FieldDef::new(FieldType::Integer).unique())
+            .field("value", FieldDef::new(FieldType::Real).validate(Validator::Min(0.0)));
+        db.define_schema(schema).unwrap();
+        db
+    }
+
+    fn rows(values: &[f64]) -> Vec<HashMap<String, Value>> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(serial, value)| {
+                HashMap::from([
+                    ("sensor".to_string(), Value::Text("s1".to_string())),
+                    ("serial".to_string(), Value::Integer(serial as i64)),
+                    ("value".to_string(), Value::Real(*value)),
+                ])
+            })
+            .collect()
+    }
+
+    fn index_names(db: &FlexibleDatabase) -> Vec<String> {
+        let indexes = index_definitions(&db.conn, "readings").unwrap();
+        indexes.into_iter().map(|index| index.name).collect()
+    }
+
+    #[test]
+    fn chunks_are_loaded_and_reported() {
+        let mut db = database();
+        let mut reports = Vec::new();
+        let options = BulkOptions::new()
+            .chunk_size(3)
+            .on_progress(|progress| reports.push(progress));
+        let loaded = db.bulk_load("readings", rows(&[1.0; 7]), options).unwrap();
+        assert_eq!(loaded, 7);
+        assert_eq!(db.count("readings").unwrap(), 7);
+        let counts: Vec<usize> = reports.iter().map(|progress| progress.loaded).collect();
+        assert_eq!(counts, vec![3, 6, 7]);
+        assert!(reports.iter().all(|progress| progress.rate > 0.0));
+    }
+
+    #[test]
+    fn a_failure_keeps_the_chunks_already_committed() {
+        let mut db = database();
+        let before = index_names(&db);
+        let options = BulkOptions::new().chunk_size(2);
+        let err = db
+            .bulk_load("readings", rows(&[1.0, 2.0, 3.0, -1.0, 5.0]), options)
+            .unwrap_err();
+        assert!(matches!(err, KooError::Validation { .. }), "{:?}", err);
+        assert_eq!(db.count("readings").unwrap(), 2);
+        // The dropped index is back
+        assert_eq!(index_names(&db), before);
+    }
+
+    #[test]
+    fn unique_indexes_still_check_the_rows() {
+        let mut db = database();
+        let mut data = rows(&[1.0, 2.0]);
+        data[1].insert("serial".to_string(), Value::Integer(0));
+        assert!(db.bulk_load("readings", data, BulkOptions::new()).is_err());
+        assert_eq!(db.count("readings").unwrap(), 0);
+        assert_eq!(index_names(&db), vec!["readings_sensor_idx", "readings_serial_key"]);
+    }
+
+    #[test]
+    fn empty_loads_and_tiny_chunks_are_fine() {
+        let mut db = database();
+        assert_eq!(db.bulk_load("readings", Vec::new(), BulkOptions::new()).unwrap(), 0);
+        let options = BulkOptions::new().chunk_size(0).drop_indexes(false);
+        assert_eq!(db.bulk_load("readings", rows(&[1.0, 2.0]), options).unwrap(), 2);
+        assert!(matches!(
+            db.bulk_load("missing", Vec::new(), BulkOptions::new()),
+            Err(KooError::SchemaNotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,157 @@
+use crate::attach::is_attached;
+use crate::error::Result;
+use crate::flexible_database::{FieldType, FlexibleDatabase, Schema};
+
+// Every defined schema is also recorded in the `_koo_schemas` table, in the
+// `export_schema_json` format, so tools opening the database later can find
+// out what it holds. Templates are recorded already merged in. Schemas
+// named after an attached database, "<alias>.<name>", are recorded in that
+// database's catalog under their plain name.
+impl FlexibleDatabase {
+    fn ensure_catalog_table(&self, table: &str) -> Result<()> {
+        self.conn.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    name TEXT PRIMARY KEY,
+                    definition TEXT NOT NULL
+                )",
+                table
+            ),
+            [],
+        )?;
+        Ok(())
+    }
+
+    // Catalog table holding the schema, and the alias of the attached
+    // database it belongs to
+    fn catalog_for<'a>(&self, schema_name: &'a str) -> Result<(String, Option<&'a str>)> {
+        if let Some((alias, _)) = schema_name.split_once('.')
+            && is_attached(&self.conn, alias)?
+        {
+            return Ok((format!("{}._koo_schemas", alias), Some(alias)));
+        }
+        Ok(("_koo_schemas".to_string(), None))
+    }
+
+    // Schemas recorded in the database, ordered by name. They may include
+    // schemas this handle hasn't defined.
+    pub fn stored_schemas(&self) -> Result<Vec<Schema>> {
+        self.ensure_catalog_table("_koo_schemas")?;
+
+        let mut stmt = self.conn.prepare("SELECT definition FROM _koo_schemas ORDER BY name")?;
+        let definitions = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        definitions
+            .iter()
+            .map(|definition| Schema::from_json(definition))
+            .collect()
+    }
+
+    // Register the recorded schemas this handle doesn't know yet, without
+    // touching their tables. Returns the number of schemas added.
+    pub fn load_schemas(&mut self) -> Result<usize> {
+        let mut loaded = 0;
+        for schema in self.stored_schemas()? {
+            if !self.schemas.contains_key(&schema.name) {
+                self.schemas.insert(schema.name.clone(), schema.materialize()?);
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    pub(crate) fn record_schema(&self, schema: &Schema) -> Result<()> {
+        let (table, alias) = self.catalog_for(&schema.name)?;
+        self.ensure_catalog_table(&table)?;
+
+        let definition = match alias {
+            None => schema.to_json(),
+            Some(alias) => unqualified(schema, alias).to_json(),
+        };
+        self.conn.execute(
+            &format!("INSERT OR REPLACE INTO {} (name, definition) VALUES (?, ?)", table),
+            (stored_name(&schema.name, alias), definition),
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn forget_schema(&self, schema_name: &str) -> Result<()> {
+        let (table, alias) = self.catalog_for(schema_name)?;
+        self.ensure_catalog_table(&table)?;
+        self.conn.execute(
+            &format!("DELETE FROM {} WHERE name = ?", table),
+            [stored_name(schema_name, alias)],
+        )?;
+        Ok(())
+    }
+}
+
+fn stored_name<'a>(schema_name: &'a str, alias: Option<&str>) -> &'a str {
+    match alias {
+        Some(alias) => schema_name.strip_prefix(alias).map_or(schema_name, |name| &name[1..]),
+        None => schema_name,
+    }
+}
+
+// The schema as the attached database sees it: without the alias in its
+// name or in references to its other schemas
+fn unqualified(schema: &Schema, alias: &str) -> Schema {
+    let mut schema = schema.clone();
+    schema.name = stored_name(&schema.name, Some(alias)).to_string();
+    for field_type in schema.fields.values_mut() {
+        if let FieldType::Reference(target) = field_type
+            && let Some(name) = target.strip_prefix(alias).and_then(|rest| rest.strip_prefix('.'))
+        {
+            *target = name.to_string();
+        }
+    }
+    schema
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alter::DropBehavior;
+    use crate::flexible_database::{FieldDef, FieldType};
+    use crate::temp_file::TempFile;
+
+    fn users() -> Schema {
+        Schema::new("users", [("name".to_string(), FieldType::Text)])
+            .field("age", FieldDef::new(FieldType::Integer).with_default(0))
+            .with_versioning()
+    }
+
+    #[test]
+    fn defined_schemas_are_found_by_later_handles() {
+        let file = TempFile::new("db");
+        let mut db = FlexibleDatabase::new(file.path()).unwrap();
+        db.define_schema(users()).unwrap();
+        db.define_schema(Schema::new("teams", [("name".to_string(), FieldType::Text)]))
+            .unwrap();
+        drop(db);
+
+        let mut db = FlexibleDatabase::new(file.path()).unwrap();
+        let names: Vec<String> = db.stored_schemas().unwrap().into_iter().map(|s| s.name).collect();
+        assert_eq!(names, ["teams", "users"]);
+        assert_eq!(db.load_schemas().unwrap(), 2);
+        let users = db.schema("users").unwrap();
+        assert!(users.versioned);
+        assert_eq!(users.defaults["age"], rusqlite::types::Value::Integer(0));
+    }
+
+    #[test]
+    fn loading_keeps_the_schemas_a_handle_already_has() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(users()).unwrap();
+        assert_eq!(db.load_schemas().unwrap(), 0);
+    }
+
+    #[test]
+    fn dropped_schemas_leave_the_catalog() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(users()).unwrap();
+        db.drop_schema("users", DropBehavior::KeepData).unwrap();
+        assert!(db.stored_schemas().unwrap().is_empty());
+    }
+}
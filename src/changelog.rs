@@ -0,0 +1,431 @@
+use rusqlite::types::Value;
+use std::collections::HashMap;
+
+use crate::changes::ChangeOp;
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FlexibleDatabase, ModelId, PrimaryKey, Schema, VERSION_COLUMN};
+
+// Current time as change timestamps store it, with milliseconds
+const NOW: &str = "strftime('%Y-%m-%d %H:%M:%f', 'now')";
+
+// One committed write recorded in `_koo_changelog`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeRecord {
+    // Increases with every change; pass the last one seen to `changes_since`
+    pub seq: i64,
+    pub schema: String,
+    pub op: ChangeOp,
+    pub id: ModelId,
+    // Every column of the row after an insert or update, including "id" and
+    // "version" where the schema has them; None for deletes. Encrypted
+    // fields hold their ciphertext.
+    pub data: Option<HashMap<String, Value>>,
+    // UTC, with milliseconds
+    pub changed_at: String,
+    // Database the change was replicated from, None for local writes
+    pub origin: Option<String>,
+}
+
+// The changelog records every insert, update and delete of the schemas in
+// the main database, including writes made outside kooDB, through
+// triggers that write to `_koo_changelog` in the same transaction. Once
+// enabled it survives reopening the file, and schemas defined later are
+// logged too. A process can replicate the database by polling
+// `changes_since` with the last sequence number it applied.
+impl FlexibleDatabase {
+    pub fn enable_changelog(&mut self) -> Result<()> {
+        self.conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS _koo_changelog (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                schema_name TEXT NOT NULL,
+                op TEXT NOT NULL,
+                model_id TEXT NOT NULL,
+                data TEXT,
+                changed_at TEXT NOT NULL,
+                origin TEXT
+            )",
+        )?;
+        let schemas: Vec<Schema> = self.schemas.values().cloned().collect();
+        for schema in &schemas {
+            self.create_changelog_triggers(schema)?;
+        }
+        Ok(())
+    }
+
+    pub fn changelog_enabled(&self) -> Result<bool> {
+        Ok(self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_koo_changelog')",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    // Changes with a sequence number above `seq`, oldest first. Start from 0.
+    pub fn changes_since(&self, seq: i64) -> Result<Vec<ChangeRecord>> {
+        if !self.changelog_enabled()? {
+            return Ok(Vec::new());
+        }
+        let mut stmt = self.conn.prepare(
+            "SELECT seq, schema_name, op, model_id, data, changed_at, origin FROM _koo_changelog
+             WHERE seq > ? ORDER BY seq",
+        )?;
+        let rows = stmt
+            .query_map([seq], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                    row.get::<_, String>(5)?,
+                    row.get::<_, Option<String>>(6)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut records = Vec::new();
+        for (seq, schema, op, model_id, data, changed_at, origin) in rows {
+            let op = match op.as_str() {
+                "insert" => ChangeOp::Insert,
+                "update" => ChangeOp::Update,
+                "delete" => ChangeOp::Delete,
+                _ => return Err(KooError::InvalidData(format!("change {} has unknown op '{}'", seq, op))),
+            };
+            let id = match serde_json::from_str(&model_id)? {
+                serde_json::Value::Array(values) => {
+                    ModelId::Composite(values.iter().map(column_from_json).collect::<Result<_>>()?)
+                }
+                json => match column_from_json(&json)? {
+                    Value::Integer(id) => ModelId::Integer(id),
+                    Value::Text(id) => ModelId::Text(id),
+                    _ => return Err(KooError::InvalidData(format!("change {} has a malformed id", seq))),
+                },
+            };
+            let data = match data {
+                None => None,
+                Some(data) => match serde_json::from_str(&data)? {
+                    serde_json::Value::Object(object) => Some(
+                        object
+                            .iter()
+                            .map(|(column, json)| Ok((column.clone(), column_from_json(json)?)))
+                            .collect::<Result<_>>()?,
+                    ),
+                    _ => return Err(KooError::InvalidData(format!("change {} has malformed data", seq))),
+                },
+            };
+            records.push(ChangeRecord {
+                seq,
+                schema,
+                op,
+                id,
+                data,
+                changed_at,
+                origin,
+            });
+        }
+        Ok(records)
+    }
+
+    // Sequence number of the latest change, 0 when none are recorded
+    pub fn latest_change_seq(&self) -> Result<i64> {
+        if !self.changelog_enabled()? {
+            return Ok(0);
+        }
+        Ok(self
+            .conn
+            .query_row("SELECT COALESCE(MAX(seq), 0) FROM _koo_changelog", [], |row| row.get(0))?)
+    }
+
+    // Forget the changes up to and including `seq`, e.g. once every replica
+    // has applied them
+    pub fn prune_changelog(&self, seq: i64) -> Result<usize> {
+        if !self.changelog_enabled()? {
+            return Ok(0);
+        }
+        Ok(self.conn.execute("DELETE FROM _koo_changelog WHERE seq <= ?", [seq])?)
+    }
+
+    // Log writes to `schema` if the changelog is enabled. Attached schemas
+    // aren't logged; their triggers can't reach the main database.
+    pub(crate) fn refresh_changelog(&self, schema: &Schema) -> Result<()> {
+        if schema.name.contains('.') || !self.changelog_enabled()? {
+            return Ok(());
+        }
+        self.create_changelog_triggers(schema)
+    }
+
+    pub(crate) fn drop_changelog_triggers(&self, table: &str) -> Result<()> {
+        self.conn.execute_batch(&format!(
+            "DROP TRIGGER IF EXISTS {table}_changelog_ai;
+            DROP TRIGGER IF EXISTS {table}_changelog_au;
+            DROP TRIGGER IF EXISTS {table}_changelog_ad;"
+        ))?;
+        Ok(())
+    }
+
+    fn create_changelog_triggers(&self, schema: &Schema) -> Result<()> {
+        let table = &schema.name;
+        self.drop_changelog_triggers(table)?;
+
+        let columns = logged_columns(schema);
+        let data = |row: &str| {
+            let entries: Vec<String> = columns
+                .iter()
+                .map(|c| format!("'{}', {}", c, column_json(&format!("{}.{}", row, c))))
+                .collect();
+            format!("json_object({})", entries.join(", "))
+        };
+        let key = |row: &str| match &schema.key {
+            PrimaryKey::Composite(key_fields) => {
+                let values: Vec<String> = key_fields.iter().map(|f| format!("{}.{}", row, f)).collect();
+                format!("json_array({})", values.join(", "))
+            }
+            _ => format!("json_quote({}.id)", row),
+        };
+        let key_changed = match &schema.key {
+            PrimaryKey::Composite(key_fields) => key_fields
+                .iter()
+                .map(|f| format!("old.{f} IS NOT new.{f}"))
+                .collect::<Vec<_>>()
+                .join(" OR "),
+            _ => "old.id IS NOT new.id".to_string(),
+        };
+        let log = |op: &str, row: &str, data: &str| {
+            format!(
+                "INSERT INTO _koo_changelog (schema_name, op, model_id, data, changed_at) \
+                 VALUES ('{table}', {op}, {}, {data}, {NOW});",
+                key(row)
+            )
+        };
+
+        self.conn.execute_batch(&format!(
+            "CREATE TRIGGER {table}_changelog_ai AFTER INSERT ON {table} BEGIN
+                {}
+            END;
+            CREATE TRIGGER {table}_changelog_au AFTER UPDATE ON {table} BEGIN
+                -- A new key is the old model going away and a new one appearing
+                INSERT INTO _koo_changelog (schema_name, op, model_id, data, changed_at)
+                    SELECT '{table}', 'delete', {}, NULL, {NOW} WHERE {key_changed};
+                {}
+            END;
+            CREATE TRIGGER {table}_changelog_ad AFTER DELETE ON {table} BEGIN
+                {}
+            END;",
+            log("'insert'", "new", &data("new")),
+            key("old"),
+            log(
+                &format!("CASE WHEN {key_changed} THEN 'insert' ELSE 'update' END"),
+                "new",
+                &data("new")
+            ),
+            log("'delete'", "old", "NULL"),
+        ))?;
+        Ok(())
+    }
+}
+
+// Columns a change's data holds
+pub(crate) fn logged_columns(schema: &Schema) -> Vec<String> {
+    let mut columns = Vec::new();
+    if schema.key.has_id_column() {
+        columns.push("id".to_string());
+    }
+    columns.extend(schema.fields.keys().cloned());
+    if schema.versioned {
+        columns.push(VERSION_COLUMN.to_string());
+    }
+    columns
+}
+
+// SQL for a column as JSON; blobs, which JSON can't hold, become
+// {"blob": "<hex>"}
+fn column_json(column: &str) -> String {
+    format!(
+        "CASE WHEN typeof({0}) = 'blob' THEN json_object('blob', hex({0})) ELSE {0} END",
+        column
+    )
+}
+
+// Inverse of `column_json`
+fn column_from_json(json: &serde_json::Value) -> Result<Value> {
+    let malformed = || KooError::InvalidData(format!("malformed changelog value {}", json));
+    Ok(match json {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Integer(*b as i64),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(n.as_f64().ok_or_else(malformed)?),
+        },
+        serde_json::Value::String(s) => Value::Text(s.clone()),
+        serde_json::Value::Object(object) => {
+            let hex = object.get("blob").and_then(|hex| hex.as_str()).ok_or_else(malformed)?;
+            let bytes = (0..hex.len())
+                .step_by(2)
+                .map(|i| hex.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(malformed)?;
+            Value::Blob(bytes)
+        }
+        serde_json::Value::Array(_) => return Err(malformed()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::flexible_database::FieldType;
+
+    fn files() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new(
+            "files",
+            [
+                ("name".to_string(), FieldType::Text),
+                ("size".to_string(), FieldType::Integer),
+            ],
+        ))
+        .unwrap();
+        db.enable_changelog().unwrap();
+        db
+    }
+
+    fn file(name: &str, size: i64) -> HashMap<String, Value> {
+        HashMap::from([
+            ("name".to_string(), Value::Text(name.to_string())),
+            ("size".to_string(), Value::Integer(size)),
+        ])
+    }
+
+    fn ops(records: &[ChangeRecord]) -> Vec<ChangeOp> {
+        records.iter().map(|record| record.op).collect()
+    }
+
+    #[test]
+    fn every_write_is_logged_in_order() {
+        let db = files();
+        let id = db.create_model("files", file("a.bin", 2)).unwrap();
+        db.update_model("files", id.clone(), file("b.bin", 1)).unwrap();
+        db.delete_model("files", id.clone()).unwrap();
+
+        let records = db.changes_since(0).unwrap();
+        assert_eq!(ops(&records), [ChangeOp::Insert, ChangeOp::Update, ChangeOp::Delete]);
+        assert_eq!(records.iter().map(|record| record.seq).collect::<Vec<_>>(), [1, 2, 3]);
+        assert!(records.iter().all(|record| record.id == id && record.schema == "files"));
+        assert!(records.iter().all(|record| record.origin.is_none()));
+
+        let inserted = records[0].data.as_ref().unwrap();
+        assert_eq!(inserted["id"], Value::Integer(1));
+        assert_eq!(inserted["size"], Value::Integer(2));
+        assert_eq!(
+            records[1].data.as_ref().unwrap()["name"],
+            Value::Text("b.bin".to_string())
+        );
+        assert_eq!(records[2].data, None);
+
+        assert_eq!(ops(&db.changes_since(2).unwrap()), [ChangeOp::Delete]);
+        assert_eq!(db.latest_change_seq().unwrap(), 3);
+    }
+
+    #[test]
+    fn raw_writes_and_later_schemas_are_logged() {
+        let mut db = files();
+        // SQLite lets a blob into a text column, and JSON can't hold one
+        db.conn
+            .execute("INSERT INTO files (name, size) VALUES (x'00ff', 2)", [])
+            .unwrap();
+        db.define_schema(Schema::new("tags", [("label".to_string(), FieldType::Text)]))
+            .unwrap();
+        db.create_model(
+            "tags",
+            HashMap::from([("label".to_string(), Value::Text("x".to_string()))]),
+        )
+        .unwrap();
+
+        let records = db.changes_since(0).unwrap();
+        let schemas: Vec<&str> = records.iter().map(|record| record.schema.as_str()).collect();
+        assert_eq!(schemas, ["files", "tags"]);
+        assert_eq!(records[0].data.as_ref().unwrap()["name"], Value::Blob(vec![0, 255]));
+    }
+
+    #[test]
+    fn changed_keys_are_a_delete_and_an_insert() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(
+            Schema::new(
+                "seats",
+                [
+                    ("row".to_string(), FieldType::Integer),
+                    ("seat".to_string(), FieldType::Integer),
+                ],
+            )
+            .with_key(PrimaryKey::Composite(vec!["row".to_string(), "seat".to_string()])),
+        )
+        .unwrap();
+        db.enable_changelog().unwrap();
+        db.conn.execute("INSERT INTO seats VALUES (1, 2)", []).unwrap();
+        db.conn.execute("UPDATE seats SET seat = 3", []).unwrap();
+
+        let records = db.changes_since(1).unwrap();
+        assert_eq!(ops(&records), [ChangeOp::Delete, ChangeOp::Insert]);
+        assert_eq!(
+            records[0].id,
+            ModelId::Composite(vec![Value::Integer(1), Value::Integer(2)])
+        );
+        assert_eq!(
+            records[1].id,
+            ModelId::Composite(vec![Value::Integer(1), Value::Integer(3)])
+        );
+    }
+
+    #[test]
+    fn pruned_changes_are_gone() {
+        let db = files();
+        for name in ["a", "b", "c"] {
+            db.create_model("files", file(name, 0)).unwrap();
+        }
+        assert_eq!(db.prune_changelog(2).unwrap(), 2);
+        assert_eq!(db.changes_since(0).unwrap().len(), 1);
+        // Sequence numbers aren't reused
+        db.create_model("files", file("d", 0)).unwrap();
+        assert_eq!(db.latest_change_seq().unwrap(), 4);
+    }
+
+    #[test]
+    fn nothing_is_logged_until_enabled() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("files", [("name".to_string(), FieldType::Text)]))
+            .unwrap();
+        db.create_model(
+            "files",
+            HashMap::from([("name".to_string(), Value::Text("a".to_string()))]),
+        )
+        .unwrap();
+        assert!(!db.changelog_enabled().unwrap());
+        assert!(db.changes_since(0).unwrap().is_empty());
+        assert_eq!(db.latest_change_seq().unwrap(), 0);
+        assert_eq!(db.prune_changelog(10).unwrap(), 0);
+    }
+
+    #[test]
+    fn column_values_round_trip_through_json() {
+        for value in [
+            Value::Null,
+            Value::Integer(-1),
+            Value::Real(0.25),
+            Value::Text("x".to_string()),
+            Value::Blob(vec![0xab, 0x01]),
+        ] {
+            let json: serde_json::Value = match &value {
+                Value::Null => serde_json::Value::Null,
+                Value::Integer(i) => (*i).into(),
+                Value::Real(f) => (*f).into(),
+                Value::Text(s) => s.clone().into(),
+                Value::Blob(_) => serde_json::json!({"blob": "AB01"}),
+            };
+            assert_eq!(column_from_json(&json).unwrap(), value);
+        }
+        assert!(column_from_json(&serde_json::json!({"blob": "ABC"})).is_err());
+        assert!(column_from_json(&serde_json::json!([1])).is_err());
+    }
+}
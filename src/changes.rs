@@ -0,0 +1,422 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::Value;
+
+use crate::error::Result;
+use crate::flexible_database::{FlexibleDatabase, ModelId, PrimaryKey, Schema};
+
+// SQL function the change triggers report each written row through
+const CHANGE_FUNCTION: &str = "koo_change";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeOp {
+    Insert,
+    Update,
+    Delete,
+}
+
+// A row of a schema that was written by a committed transaction
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+    pub op: ChangeOp,
+    pub schema: String,
+    pub id: ModelId,
+}
+
+// Changes made by the open transaction, and the channels waiting for them
+// once it commits. Shared with the connection's hooks.
+#[derive(Debug, Default)]
+pub(crate) struct ChangeFeed {
+    pending: Mutex<Vec<ChangeEvent>>,
+    subscribers: Mutex<Vec<(String, Sender<ChangeEvent>)>>,
+}
+
+impl ChangeFeed {
+    fn record(&self, event: ChangeEvent) {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).push(event);
+    }
+
+    fn is_subscribed(&self, schema_name: &str) -> bool {
+        let subscribers = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        subscribers.iter().any(|(name, _)| name == schema_name)
+    }
+
+    // Deliver the pending changes, dropping subscribers whose receiver is gone
+    fn publish(&self) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap_or_else(|e| e.into_inner()));
+        if pending.is_empty() {
+            return;
+        }
+
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        subscribers.retain(|(schema_name, sender)| {
+            pending
+                .iter()
+                .filter(|event| &event.schema == schema_name)
+                .all(|event| sender.send(event.clone()).is_ok())
+        });
+    }
+
+    fn discard(&self) {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+}
+
+impl FlexibleDatabase {
+    // Receive an event for every model of `schema_name` inserted, updated or
+    // deleted through this handle. Events are sent when the writing
+    // transaction commits; rolled back changes are never reported. Dropping
+    // the receiver ends the subscription.
+    pub fn subscribe(&mut self, schema_name: &str) -> Result<Receiver<ChangeEvent>> {
+        self.schema_or_err(schema_name)?;
+
+        let feed = match &self.changes {
+            Some(feed) => Arc::clone(feed),
+            None => {
+                let feed = Arc::new(ChangeFeed::default());
+                register_change(&self.conn, Arc::clone(&feed))?;
+                self.changes = Some(Arc::clone(&feed));
+                self.install_hooks();
+                feed
+            }
+        };
+        if !feed.is_subscribed(schema_name) {
+            self.create_change_triggers(&self.schemas[schema_name])?;
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let mut subscribers = feed.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        subscribers.push((schema_name.to_string(), sender));
+        Ok(receiver)
+    }
+
+    // Report writes to `schema` again after its table was recreated, if
+    // anyone subscribed to it
+    pub(crate) fn refresh_change_triggers(&self, schema: &Schema) -> Result<()> {
+        match &self.changes {
+            Some(feed) if feed.is_subscribed(&schema.name) => self.create_change_triggers(schema),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn drop_change_triggers(&self, table: &str) -> Result<()> {
+        let trigger = trigger_name(table);
+        self.conn.execute_batch(&format!(
+            "DROP TRIGGER IF EXISTS temp.{trigger}_ai;
+            DROP TRIGGER IF EXISTS temp.{trigger}_au;
+            DROP TRIGGER IF EXISTS temp.{trigger}_ad;"
+        ))?;
+        Ok(())
+    }
+
+    // Temporary triggers, which can watch the tables of attached databases
+    // too and which fire for every row a DELETE without a WHERE removes.
+    // They pass the row's key to the change function, since the update
+    // hook only has its rowid and can't read the row.
+    fn create_change_triggers(&self, schema: &Schema) -> Result<()> {
+        let table = &schema.name;
+        self.drop_change_triggers(table)?;
+
+        let (kind, key_fields) = match &schema.key {
+            PrimaryKey::Composite(key_fields) => ("composite", key_fields.clone()),
+            _ => ("id", vec!["id".to_string()]),
+        };
+        let report = |op: &str, row: &str| {
+            let key: Vec<String> = key_fields.iter().map(|f| format!("{}.{}", row, f)).collect();
+            format!("{CHANGE_FUNCTION}('{table}', {op}, '{kind}', {})", key.join(", "))
+        };
+        let key_changed = key_fields
+            .iter()
+            .map(|f| format!("old.{f} IS NOT new.{f}"))
+            .collect::<Vec<_>>()
+            .join(" OR ");
+
+        let trigger = trigger_name(table);
+        self.conn.execute_batch(&format!(
+            "CREATE TEMP TRIGGER {trigger}_ai AFTER INSERT ON {table} BEGIN
+                SELECT {};
+            END;
+            CREATE TEMP TRIGGER {trigger}_au AFTER UPDATE ON {table} BEGIN
+                -- A new key is the old model going away and a new one appearing
+                SELECT {} WHERE {key_changed};
+                SELECT {};
+            END;
+            CREATE TEMP TRIGGER {trigger}_ad AFTER DELETE ON {table} BEGIN
+                SELECT {};
+            END;",
+            report("'insert'", "new"),
+            report("'delete'", "old"),
+            report(
+                &format!("CASE WHEN {key_changed} THEN 'insert' ELSE 'update' END"),
+                "new"
+            ),
+            report("'delete'", "old"),
+        ))?;
+        Ok(())
+    }
+
+    // Point the connection's hooks at the change feed and the read cache,
+    // which share them; called whenever either is set up or removed
+    pub(crate) fn install_hooks(&self) {
+        let cache = self.read_cache.clone();
+        self.conn.update_hook(Some(move |_, db: &str, table: &str, _| {
+            if let Some(cache) = &cache {
+                cache.invalidate(db, table);
+            }
+        }));
+
+        // Returning false lets the commit go ahead
+        let feed = self.changes.clone();
+        self.conn.commit_hook(Some(move || {
+            if let Some(feed) = &feed {
+                feed.publish();
+            }
+            false
+        }));
+
+        let (feed, cache) = (self.changes.clone(), self.read_cache.clone());
+        self.conn.rollback_hook(Some(move || {
+            if let Some(feed) = &feed {
+                feed.discard();
+            }
+            if let Some(cache) = &cache {
+                cache.clear();
+            }
+        }));
+    }
+}
+
+// Name of the change triggers of `table`, which may be qualified
+fn trigger_name(table: &str) -> String {
+    format!("koo_feed_{}", table.replace('.', "_"))
+}
+
+// Define `CHANGE_FUNCTION` on `conn`, recording the write it is given in
+// `feed`: the schema, the op and the kind of key, then the key's values
+fn register_change(conn: &rusqlite::Connection, feed: Arc<ChangeFeed>) -> Result<()> {
+    conn.create_scalar_function(CHANGE_FUNCTION, -1, FunctionFlags::SQLITE_UTF8, move |ctx| {
+        let op = match ctx.get::<String>(1)?.as_str() {
+            "insert" => ChangeOp::Insert,
+            "update" => ChangeOp::Update,
+            _ => ChangeOp::Delete,
+        };
+        let key = (3..ctx.len())
+            .map(|i| ctx.get::<Value>(i))
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let id = match (ctx.get::<String>(2)?.as_str(), key.as_slice()) {
+            ("id", [Value::Integer(id)]) => ModelId::Integer(*id),
+            ("id", [Value::Text(id)]) => ModelId::Text(id.clone()),
+            _ => ModelId::Composite(key),
+        };
+        feed.record(ChangeEvent {
+            op,
+            schema: ctx.get(0)?,
+            id,
+        });
+        Ok(Value::Null)
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::KooError;
+    use crate::flexible_database::FieldType;
+    use crate::model_builder::ModelBuilder;
+
+    fn event(op: ChangeOp, schema: &str, id: impl Into<ModelId>) -> ChangeEvent {
+        ChangeEvent {
+            op,
+            schema: schema.to_string(),
+            id: id.into(),
+        }
+    }
+
+    fn received(changes: &Receiver<ChangeEvent>) -> Vec<ChangeEvent> {
+        changes.try_iter().collect()
+    }
+
+    fn notes() -> Schema {
+        Schema::new("notes", [("body".to_string(), FieldType::Text)])
+    }
+
+    fn note(body: &str) -> std::collections::HashMap<String, Value> {
+        ModelBuilder::new().text("body", body).build()
+    }
+
+    #[test]
+    fn committed_writes_are_reported_with_their_ids() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(notes()).unwrap();
+        let changes = db.subscribe("notes").unwrap();
+
+        let id = db.create_model("notes", note("one")).unwrap();
+        db.update_model("notes", &id, note("two")).unwrap();
+        db.delete_model("notes", &id).unwrap();
+        assert_eq!(
+            received(&changes),
+            [
+                event(ChangeOp::Insert, "notes", 1),
+                event(ChangeOp::Update, "notes", 1),
+                event(ChangeOp::Delete, "notes", 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn rolled_back_writes_are_not_reported() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(notes()).unwrap();
+        let changes = db.subscribe("notes").unwrap();
+
+        let result: Result<()> = db.transaction(|db| {
+            db.create_model("notes", note("gone"))?;
+            Err(KooError::InvalidData("abandoned".to_string()))
+        });
+        assert!(result.is_err());
+        db.create_model("notes", note("kept")).unwrap();
+        assert_eq!(received(&changes), [event(ChangeOp::Insert, "notes", 1)]);
+    }
+
+    #[test]
+    fn text_and_composite_keys_are_reported() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(notes().with_key(PrimaryKey::Text)).unwrap();
+        let seats = Schema::new(
+            "seats",
+            [
+                ("row".to_string(), FieldType::Text),
+                ("number".to_string(), FieldType::Integer),
+            ],
+        )
+        .with_key(PrimaryKey::Composite(vec!["row".to_string(), "number".to_string()]));
+        db.define_schema(seats).unwrap();
+        let note_changes = db.subscribe("notes").unwrap();
+        let seat_changes = db.subscribe("seats").unwrap();
+
+        db.create_model("notes", ModelBuilder::new().text("id", "a").text("body", "one").build())
+            .unwrap();
+        db.create_model("seats", ModelBuilder::new().text("row", "F").set("number", 7).build())
+            .unwrap();
+        assert_eq!(received(&note_changes), [event(ChangeOp::Insert, "notes", "a")]);
+        assert_eq!(
+            received(&seat_changes),
+            [event(
+                ChangeOp::Insert,
+                "seats",
+                vec![Value::Text("F".to_string()), Value::Integer(7)]
+            )]
+        );
+    }
+
+    #[test]
+    fn a_new_key_is_a_delete_and_an_insert() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(notes()).unwrap();
+        db.create_model("notes", note("one")).unwrap();
+        let changes = db.subscribe("notes").unwrap();
+
+        db.execute_raw("UPDATE notes SET id = 5", &[]).unwrap();
+        assert_eq!(
+            received(&changes),
+            [event(ChangeOp::Delete, "notes", 1), event(ChangeOp::Insert, "notes", 5)]
+        );
+    }
+
+    #[test]
+    fn every_row_of_a_truncation_is_reported() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(notes()).unwrap();
+        db.create_model("notes", note("one")).unwrap();
+        db.create_model("notes", note("two")).unwrap();
+        let changes = db.subscribe("notes").unwrap();
+
+        db.execute_raw("DELETE FROM notes", &[]).unwrap();
+        assert_eq!(
+            received(&changes),
+            [event(ChangeOp::Delete, "notes", 1), event(ChangeOp::Delete, "notes", 2)]
+        );
+    }
+
+    #[test]
+    fn attached_writes_are_reported_under_their_qualified_name() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(notes()).unwrap();
+        db.attach(":memory:", "archive").unwrap();
+        db.define_schema(Schema::new("archive.notes", [("body".to_string(), FieldType::Text)]))
+            .unwrap();
+        let main_changes = db.subscribe("notes").unwrap();
+        let archive_changes = db.subscribe("archive.notes").unwrap();
+
+        db.create_model("archive.notes", note("old")).unwrap();
+        assert_eq!(received(&main_changes), []);
+        assert_eq!(
+            received(&archive_changes),
+            [event(ChangeOp::Insert, "archive.notes", 1)]
+        );
+    }
+
+    #[test]
+    fn subscriptions_outlive_a_rebuilt_table() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(notes().field("draft", crate::flexible_database::FieldDef::new(FieldType::Text)))
+            .unwrap();
+        let changes = db.subscribe("notes").unwrap();
+
+        db.drop_field("notes", "draft").unwrap();
+        db.create_model("notes", note("one")).unwrap();
+        assert_eq!(received(&changes), [event(ChangeOp::Insert, "notes", 1)]);
+    }
+
+    #[test]
+    fn subscribers_only_hear_about_their_schema() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(notes()).unwrap();
+        db.define_schema(Schema::new("tags", [("label".to_string(), FieldType::Text)]))
+            .unwrap();
+        let (first, second) = (db.subscribe("notes").unwrap(), db.subscribe("notes").unwrap());
+        let tags = db.subscribe("tags").unwrap();
+
+        db.create_model("notes", note("one")).unwrap();
+        db.create_model("tags", ModelBuilder::new().text("label", "red").build())
+            .unwrap();
+        assert_eq!(received(&first), [event(ChangeOp::Insert, "notes", 1)]);
+        assert_eq!(received(&second), [event(ChangeOp::Insert, "notes", 1)]);
+        assert_eq!(received(&tags), [event(ChangeOp::Insert, "tags", 1)]);
+        assert!(matches!(db.subscribe("missing"), Err(KooError::SchemaNotFound(_))));
+    }
+
+    #[test]
+    fn events_wait_for_the_commit() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(notes()).unwrap();
+        let changes = db.subscribe("notes").unwrap();
+        db.transaction(|db| {
+            db.create_model("notes", note("one"))?;
+            db.create_model("notes", note("two"))?;
+            assert!(received(&changes).is_empty());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            received(&changes),
+            [event(ChangeOp::Insert, "notes", 1), event(ChangeOp::Insert, "notes", 2)]
+        );
+    }
+
+    #[test]
+    fn dropped_receivers_end_the_subscription() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(notes()).unwrap();
+        let kept = db.subscribe("notes").unwrap();
+        drop(db.subscribe("notes").unwrap());
+
+        db.create_model("notes", note("one")).unwrap();
+        let feed = db.changes.clone().unwrap();
+        assert_eq!(feed.subscribers.lock().unwrap().len(), 1);
+        assert_eq!(received(&kept).len(), 1);
+    }
+}
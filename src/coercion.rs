@@ -0,0 +1,489 @@
+use rusqlite::types::Value;
+use uuid::Uuid;
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::Model;
+
+// How far a conversion may go to make a value fit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Coercion {
+    // Only values stored the way the type is and representable in it
+    // exactly, such as an Integer of 0 or 1 for a bool or a Real a f32
+    // holds without rounding
+    #[default]
+    Strict,
+    // Also values of other storage classes, such as numbers written as
+    // text, with reals truncated or rounded and numbers out of range
+    // clamped
+    Lossy,
+}
+
+// A Rust type model values can be read as and written from
+pub trait Coerce: Sized {
+    // Said of the type in errors, as in "holds text, not an unsigned integer"
+    const EXPECTED: &'static str;
+
+    // `value` as this type, or None if it doesn't convert. NULL never does;
+    // read an `Option` for fields that may hold one.
+    fn from_value(value: &Value, coercion: Coercion) -> Option<Self>;
+
+    // This as a value for a model's data, or None if it doesn't convert
+    fn to_value(&self, coercion: Coercion) -> Option<Value>;
+}
+
+impl Model {
+    // Read a field as `T`, strictly. Fails with `InvalidData` if the model
+    // has no such field and `TypeMismatch` if its value doesn't convert.
+    pub fn get_as<T: Coerce>(&self, field_name: &str) -> Result<T> {
+        self.get_as_with(field_name, Coercion::Strict)
+    }
+
+    pub fn get_as_with<T: Coerce>(&self, field_name: &str, coercion: Coercion) -> Result<T> {
+        let value = self.field(field_name)?;
+        T::from_value(value, coercion).ok_or_else(|| mismatch(field_name, T::EXPECTED, value))
+    }
+
+    // The typed accessors read strictly, as `get_as` does
+    pub fn get_str(&self, field_name: &str) -> Result<&str> {
+        match self.field(field_name)? {
+            Value::Text(s) => Ok(s),
+            value => Err(mismatch(field_name, String::EXPECTED, value)),
+        }
+    }
+
+    pub fn get_i64(&self, field_name: &str) -> Result<i64> {
+        self.get_as(field_name)
+    }
+
+    pub fn get_f64(&self, field_name: &str) -> Result<f64> {
+        self.get_as(field_name)
+    }
+
+    pub fn get_bool(&self, field_name: &str) -> Result<bool> {
+        self.get_as(field_name)
+    }
+
+    // Deserialize a text field holding JSON, as `ModelBuilder::json` writes
+    #[cfg(feature = "serde")]
+    pub fn get_json<T: serde::de::DeserializeOwned>(&self, field_name: &str) -> Result<T> {
+        Ok(serde_json::from_str(self.get_str(field_name)?)?)
+    }
+
+    fn field(&self, field_name: &str) -> Result<&Value> {
+        self.get(field_name)
+            .ok_or_else(|| KooError::InvalidData(format!("model has no field '{}'", field_name)))
+    }
+}
+
+fn mismatch(field_name: &str, expected: &'static str, value: &Value) -> KooError {
+    KooError::TypeMismatch {
+        field: field_name.to_string(),
+        expected,
+        found: held(value),
+    }
+}
+
+fn held(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Integer(_) => "an integer",
+        Value::Real(_) => "a real",
+        Value::Text(_) => "text",
+        Value::Blob(_) => "a blob",
+    }
+}
+
+// Numbers written as text, for lossy conversions
+fn parsed_number(text: &str) -> Option<Value> {
+    let text = text.trim();
+    match text.parse::<i64>() {
+        Ok(i) => Some(Value::Integer(i)),
+        Err(_) => text.parse::<f64>().ok().filter(|f| !f.is_nan()).map(Value::Real),
+    }
+}
+
+impl<T: Coerce> Coerce for Option<T> {
+    const EXPECTED: &'static str = T::EXPECTED;
+
+    fn from_value(value: &Value, coercion: Coercion) -> Option<Self> {
+        match value {
+            Value::Null => Some(None),
+            value => T::from_value(value, coercion).map(Some),
+        }
+    }
+
+    fn to_value(&self, coercion: Coercion) -> Option<Value> {
+        match self {
+            None => Some(Value::Null),
+            Some(value) => value.to_value(coercion),
+        }
+    }
+}
+
+impl Coerce for bool {
+    const EXPECTED: &'static str = "a boolean";
+
+    fn from_value(value: &Value, coercion: Coercion) -> Option<Self> {
+        match (value, coercion) {
+            (Value::Integer(0), _) => Some(false),
+            (Value::Integer(1), _) => Some(true),
+            (Value::Integer(i), Coercion::Lossy) => Some(*i != 0),
+            (Value::Real(f), Coercion::Lossy) => Some(*f != 0.0),
+            (Value::Text(s), Coercion::Lossy) => match s.trim().to_ascii_lowercase().as_str() {
+                "true" | "t" | "yes" | "y" | "on" => Some(true),
+                "false" | "f" | "no" | "n" | "off" => Some(false),
+                other => parsed_number(other).and_then(|n| bool::from_value(&n, coercion)),
+            },
+            _ => None,
+        }
+    }
+
+    fn to_value(&self, _: Coercion) -> Option<Value> {
+        Some(Value::Integer(*self as i64))
+    }
+}
+
+impl Coerce for i64 {
+    const EXPECTED: &'static str = "an integer";
+
+    fn from_value(value: &Value, coercion: Coercion) -> Option<Self> {
+        match (value, coercion) {
+            (Value::Integer(i), _) => Some(*i),
+            // Saturating, with NaN as 0
+            (Value::Real(f), Coercion::Lossy) => Some(*f as i64),
+            (Value::Text(s), Coercion::Lossy) => parsed_number(s).and_then(|n| i64::from_value(&n, coercion)),
+            _ => None,
+        }
+    }
+
+    fn to_value(&self, _: Coercion) -> Option<Value> {
+        Some(Value::Integer(*self))
+    }
+}
+
+impl Coerce for u64 {
+    const EXPECTED: &'static str = "an unsigned integer";
+
+    fn from_value(value: &Value, coercion: Coercion) -> Option<Self> {
+        match (value, coercion) {
+            (Value::Integer(i), Coercion::Strict) => u64::try_from(*i).ok(),
+            (Value::Integer(i), Coercion::Lossy) => Some((*i).max(0) as u64),
+            (Value::Real(f), Coercion::Lossy) => Some(*f as u64),
+            (Value::Text(s), Coercion::Lossy) => match s.trim().parse::<u64>() {
+                Ok(u) => Some(u),
+                Err(_) => parsed_number(s).and_then(|n| u64::from_value(&n, coercion)),
+            },
+            _ => None,
+        }
+    }
+
+    // SQLite integers are signed, so larger ones are clamped
+    fn to_value(&self, coercion: Coercion) -> Option<Value> {
+        match (i64::try_from(*self), coercion) {
+            (Ok(i), _) => Some(Value::Integer(i)),
+            (Err(_), Coercion::Strict) => None,
+            (Err(_), Coercion::Lossy) => Some(Value::Integer(i64::MAX)),
+        }
+    }
+}
+
+impl Coerce for f64 {
+    const EXPECTED: &'static str = "a real";
+
+    fn from_value(value: &Value, coercion: Coercion) -> Option<Self> {
+        match (value, coercion) {
+            (Value::Real(f), _) => Some(*f),
+            // Not every integer beyond 2^53 has an exact f64
+            (Value::Integer(i), Coercion::Strict) if i.unsigned_abs() <= 1 << 53 => Some(*i as f64),
+            (Value::Integer(i), Coercion::Lossy) => Some(*i as f64),
+            (Value::Text(s), Coercion::Lossy) => parsed_number(s).and_then(|n| f64::from_value(&n, coercion)),
+            _ => None,
+        }
+    }
+
+    fn to_value(&self, _: Coercion) -> Option<Value> {
+        Some(Value::Real(*self))
+    }
+}
+
+impl Coerce for f32 {
+    const EXPECTED: &'static str = "a 32-bit real";
+
+    fn from_value(value: &Value, coercion: Coercion) -> Option<Self> {
+        let f = f64::from_value(value, coercion)?;
+        match coercion {
+            Coercion::Strict => Some(f as f32).filter(|narrowed| *narrowed as f64 == f || f.is_nan()),
+            Coercion::Lossy => Some(f as f32),
+        }
+    }
+
+    fn to_value(&self, _: Coercion) -> Option<Value> {
+        Some(Value::Real(*self as f64))
+    }
+}
+
+impl Coerce for String {
+    const EXPECTED: &'static str = "text";
+
+    fn from_value(value: &Value, coercion: Coercion) -> Option<Self> {
+        match (value, coercion) {
+            (Value::Text(s), _) => Some(s.clone()),
+            (Value::Integer(i), Coercion::Lossy) => Some(i.to_string()),
+            (Value::Real(f), Coercion::Lossy) => Some(f.to_string()),
+            (Value::Blob(bytes), Coercion::Lossy) => Some(String::from_utf8_lossy(bytes).into_owned()),
+            _ => None,
+        }
+    }
+
+    fn to_value(&self, _: Coercion) -> Option<Value> {
+        Some(Value::Text(self.clone()))
+    }
+}
+
+impl Coerce for Vec<u8> {
+    const EXPECTED: &'static str = "a blob";
+
+    fn from_value(value: &Value, coercion: Coercion) -> Option<Self> {
+        match (value, coercion) {
+            (Value::Blob(bytes), _) => Some(bytes.clone()),
+            (Value::Text(s), Coercion::Lossy) => Some(s.as_bytes().to_vec()),
+            _ => None,
+        }
+    }
+
+    fn to_value(&self, _: Coercion) -> Option<Value> {
+        Some(Value::Blob(self.clone()))
+    }
+}
+
+// Stored as hyphenated text, as generated UUID ids are. Lossy conversions
+// also take the 16 bytes of a blob.
+impl Coerce for Uuid {
+    const EXPECTED: &'static str = "a UUID";
+
+    fn from_value(value: &Value, coercion: Coercion) -> Option<Self> {
+        match (value, coercion) {
+            (Value::Text(s), _) => Uuid::parse_str(s.trim()).ok(),
+            (Value::Blob(bytes), Coercion::Lossy) => Uuid::from_slice(bytes).ok(),
+            _ => None,
+        }
+    }
+
+    fn to_value(&self, _: Coercion) -> Option<Value> {
+        Some(Value::Text(self.hyphenated().to_string()))
+    }
+}
+
+// Dates and times are stored as text in the formats SQLite's date and time
+// functions use, in UTC. Lossy conversions also take numbers of seconds
+// since the Unix epoch.
+#[cfg(feature = "chrono")]
+mod chrono_types {
+    use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+    use rusqlite::types::Value;
+
+    use super::{Coerce, Coercion};
+
+    const DATE_TIME_FORMATS: [&str; 2] = ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"];
+
+    fn naive_date_time(value: &Value, coercion: Coercion) -> Option<NaiveDateTime> {
+        match (value, coercion) {
+            (Value::Text(s), _) => {
+                let s = s.trim();
+                DATE_TIME_FORMATS
+                    .iter()
+                    .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+                    .or_else(|| DateTime::parse_from_rfc3339(s).ok().map(|t| t.naive_utc()))
+            }
+            (Value::Integer(secs), Coercion::Lossy) => DateTime::from_timestamp(*secs, 0).map(|t| t.naive_utc()),
+            (Value::Real(secs), Coercion::Lossy) => {
+                DateTime::from_timestamp_micros((secs * 1e6) as i64).map(|t| t.naive_utc())
+            }
+            _ => None,
+        }
+    }
+
+    impl Coerce for NaiveDateTime {
+        const EXPECTED: &'static str = "a date and time";
+
+        fn from_value(value: &Value, coercion: Coercion) -> Option<Self> {
+            naive_date_time(value, coercion)
+        }
+
+        fn to_value(&self, _: Coercion) -> Option<Value> {
+            Some(Value::Text(self.format("%Y-%m-%d %H:%M:%S%.f").to_string()))
+        }
+    }
+
+    impl Coerce for DateTime<Utc> {
+        const EXPECTED: &'static str = "a date and time";
+
+        fn from_value(value: &Value, coercion: Coercion) -> Option<Self> {
+            naive_date_time(value, coercion).map(|t| t.and_utc())
+        }
+
+        fn to_value(&self, coercion: Coercion) -> Option<Value> {
+            self.naive_utc().to_value(coercion)
+        }
+    }
+
+    impl Coerce for NaiveDate {
+        const EXPECTED: &'static str = "a date";
+
+        fn from_value(value: &Value, coercion: Coercion) -> Option<Self> {
+            let date = match value {
+                Value::Text(s) => NaiveDate::parse_from_str(s.trim(), "%Y-%m-%d").ok(),
+                _ => None,
+            };
+            match coercion {
+                Coercion::Strict => date,
+                Coercion::Lossy => date.or_else(|| naive_date_time(value, coercion).map(|t| t.date())),
+            }
+        }
+
+        fn to_value(&self, _: Coercion) -> Option<Value> {
+            Some(Value::Text(self.format("%Y-%m-%d").to_string()))
+        }
+    }
+
+    impl Coerce for NaiveTime {
+        const EXPECTED: &'static str = "a time";
+
+        fn from_value(value: &Value, coercion: Coercion) -> Option<Self> {
+            let time = match value {
+                Value::Text(s) => NaiveTime::parse_from_str(s.trim(), "%H:%M:%S%.f").ok(),
+                _ => None,
+            };
+            match coercion {
+                Coercion::Strict => time,
+                Coercion::Lossy => time.or_else(|| naive_date_time(value, coercion).map(|t| t.time())),
+            }
+        }
+
+        fn to_value(&self, _: Coercion) -> Option<Value> {
+            Some(Value::Text(self.format("%H:%M:%S%.f").to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use Coercion::{Lossy, Strict};
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.to_string())
+    }
+
+    #[test]
+    fn strict_reads_take_only_exact_values() {
+        assert_eq!(bool::from_value(&Value::Integer(1), Strict), Some(true));
+        assert_eq!(bool::from_value(&Value::Integer(2), Strict), None);
+        assert_eq!(i64::from_value(&Value::Integer(-7), Strict), Some(-7));
+        assert_eq!(i64::from_value(&Value::Real(1.0), Strict), None);
+        assert_eq!(i64::from_value(&text("3"), Strict), None);
+        assert_eq!(u64::from_value(&Value::Integer(-1), Strict), None);
+        assert_eq!(f64::from_value(&Value::Integer(3), Strict), Some(3.0));
+        assert_eq!(f64::from_value(&Value::Integer(i64::MAX), Strict), None);
+        assert_eq!(f32::from_value(&Value::Real(0.5), Strict), Some(0.5));
+        assert_eq!(f32::from_value(&Value::Real(0.1), Strict), None);
+        assert_eq!(String::from_value(&Value::Integer(1), Strict), None);
+        assert_eq!(Vec::<u8>::from_value(&text("a"), Strict), None);
+        assert_eq!(String::from_value(&Value::Null, Strict), None);
+        assert_eq!(Option::<String>::from_value(&Value::Null, Strict), Some(None));
+        assert_eq!(Option::<i64>::from_value(&text("x"), Strict), None);
+    }
+
+    #[test]
+    fn lossy_reads_convert_clamp_and_parse() {
+        assert_eq!(bool::from_value(&Value::Integer(5), Lossy), Some(true));
+        assert_eq!(bool::from_value(&text(" Yes "), Lossy), Some(true));
+        assert_eq!(bool::from_value(&text("off"), Lossy), Some(false));
+        assert_eq!(bool::from_value(&text("0.0"), Lossy), Some(false));
+        assert_eq!(bool::from_value(&text("maybe"), Lossy), None);
+        assert_eq!(i64::from_value(&Value::Real(2.9), Lossy), Some(2));
+        assert_eq!(i64::from_value(&Value::Real(1e300), Lossy), Some(i64::MAX));
+        assert_eq!(i64::from_value(&text(" 42 "), Lossy), Some(42));
+        assert_eq!(i64::from_value(&text("4.5"), Lossy), Some(4));
+        assert_eq!(u64::from_value(&Value::Integer(-3), Lossy), Some(0));
+        assert_eq!(u64::from_value(&text("18446744073709551615"), Lossy), Some(u64::MAX));
+        assert_eq!(f64::from_value(&text("2.5"), Lossy), Some(2.5));
+        assert_eq!(f64::from_value(&text("NaN"), Lossy), None);
+        assert_eq!(f32::from_value(&Value::Real(0.1), Lossy), Some(0.1));
+        assert_eq!(String::from_value(&Value::Real(1.5), Lossy), Some("1.5".to_string()));
+        assert_eq!(
+            String::from_value(&Value::Blob(b"hi".to_vec()), Lossy),
+            Some("hi".to_string())
+        );
+        assert_eq!(Vec::<u8>::from_value(&text("hi"), Lossy), Some(b"hi".to_vec()));
+    }
+
+    #[test]
+    fn writes_store_values_the_way_reads_expect() {
+        assert_eq!(true.to_value(Strict), Some(Value::Integer(1)));
+        assert_eq!(7i64.to_value(Strict), Some(Value::Integer(7)));
+        assert_eq!(u64::MAX.to_value(Strict), None);
+        assert_eq!(u64::MAX.to_value(Lossy), Some(Value::Integer(i64::MAX)));
+        assert_eq!(0.5f32.to_value(Strict), Some(Value::Real(0.5)));
+        assert_eq!(None::<i64>.to_value(Strict), Some(Value::Null));
+        assert_eq!(Some("a".to_string()).to_value(Strict), Some(text("a")));
+
+        let id = Uuid::new_v4();
+        let stored = id.to_value(Strict).unwrap();
+        assert_eq!(stored, Value::Text(id.hyphenated().to_string()));
+        assert_eq!(Uuid::from_value(&stored, Strict), Some(id));
+        let bytes = Value::Blob(id.as_bytes().to_vec());
+        assert_eq!(Uuid::from_value(&bytes, Strict), None);
+        assert_eq!(Uuid::from_value(&bytes, Lossy), Some(id));
+    }
+
+    #[test]
+    fn models_read_fields_as_rust_types() {
+        let data = HashMap::from([
+            ("age".to_string(), Value::Integer(41)),
+            ("height".to_string(), text("1.8")),
+            ("nickname".to_string(), Value::Null),
+        ]);
+        let model = Model::new(None, data);
+        assert_eq!(model.get_as::<i64>("age").unwrap(), 41);
+        assert_eq!(model.get_as::<Option<String>>("nickname").unwrap(), None);
+        assert_eq!(model.get_as_with::<f64>("height", Lossy).unwrap(), 1.8);
+
+        let err = model.get_as::<f64>("height").unwrap_err();
+        assert_eq!(err.to_string(), "field 'height' holds text, not a real");
+        assert!(matches!(model.get_as::<i64>("weight"), Err(KooError::InvalidData(_))));
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn dates_and_times_use_sqlite_formats() {
+        use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+
+        let at = NaiveDate::from_ymd_opt(2024, 2, 29)
+            .unwrap()
+            .and_hms_opt(13, 5, 0)
+            .unwrap();
+        assert_eq!(at.to_value(Strict), Some(text("2024-02-29 13:05:00")));
+        for stored in [
+            "2024-02-29 13:05:00",
+            "2024-02-29T13:05:00",
+            "2024-02-29T14:05:00+01:00",
+        ] {
+            assert_eq!(NaiveDateTime::from_value(&text(stored), Strict), Some(at), "{}", stored);
+        }
+        assert_eq!(NaiveDateTime::from_value(&Value::Integer(0), Strict), None);
+        let epoch = DateTime::<Utc>::from_value(&Value::Integer(0), Lossy).unwrap();
+        assert_eq!(epoch.timestamp(), 0);
+
+        assert_eq!(NaiveDate::from_value(&text("2024-02-29"), Strict), Some(at.date()));
+        assert_eq!(NaiveDate::from_value(&text("2024-02-29 13:05:00"), Strict), None);
+        assert_eq!(
+            NaiveDate::from_value(&text("2024-02-29 13:05:00"), Lossy),
+            Some(at.date())
+        );
+        assert_eq!(NaiveTime::from_value(&text("13:05:00"), Strict), Some(at.time()));
+        assert_eq!(at.time().to_value(Strict), Some(text("13:05:00")));
+    }
+}
@@ -0,0 +1,292 @@
+use crate::attach::references_alike;
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FieldType, FlexibleDatabase, PrimaryKey, Schema, computed_write};
+use crate::query::Query;
+
+impl FlexibleDatabase {
+    // Copy the models of `from_schema` that `filter` matches into
+    // `to_schema` with a single INSERT ... SELECT, so the rows never leave
+    // SQLite; useful for archiving or denormalizing large tables. Each
+    // (source, target) pair in `mapping` copies a source field, or "id",
+    // into a target field of the same type. Target fields left out take
+    // their default, and integer ids are numbered afresh unless "id" is
+    // mapped. Rows are copied as they are, without validation or the access
+    // policy, and a failing row fails the whole copy. With auditing enabled
+    // each copied model is recorded as created. Only the conditions of
+    // `filter` are used, not its order or limit. Returns the number of
+    // models copied.
+    pub fn copy_rows(
+        &self,
+        from_schema: &str,
+        to_schema: &str,
+        mapping: &[(&str, &str)],
+        filter: Option<&Query>,
+    ) -> Result<usize> {
+        let source = self.schema_or_err(from_schema)?;
+        let target = self.schema_or_err(to_schema)?;
+        if mapping.is_empty() {
+            return Err(KooError::InvalidData(format!(
+                "no fields are mapped from '{}' to '{}'",
+                from_schema, to_schema
+            )));
+        }
+
+        let (source_columns, target_columns) = mapped_columns(source, target, mapping)?;
+
+        let (where_sql, params) = match filter {
+            Some(query) if query.schema != from_schema => {
+                return Err(KooError::InvalidData(format!(
+                    "filter is a query on '{}', not '{}'",
+                    query.schema, from_schema
+                )));
+            }
+            Some(query) => query.where_sql(source)?,
+            None => (String::new(), Vec::new()),
+        };
+        let sql = format!(
+            "INSERT INTO {} ({}) SELECT {} FROM {}{}",
+            target.name,
+            target_columns.join(", "),
+            source_columns.join(", "),
+            source.name,
+            where_sql
+        );
+
+        self.traced(&sql, params.len(), || {
+            let copied = self.retrying(|| self.audit_inserts(target, &sql, &params))?;
+            Ok((copied, copied))
+        })
+    }
+}
+
+// Source and target columns of a copy, checking each (source, target) pair
+// of `mapping`
+pub(crate) fn mapped_columns(
+    source: &Schema,
+    target: &Schema,
+    mapping: &[(&str, &str)],
+) -> Result<(Vec<String>, Vec<String>)> {
+    let mut source_columns = Vec::new();
+    let mut target_columns = Vec::new();
+    for (source_field, target_field) in mapping {
+        let source_type = copied_type(source, source_field)?;
+        let target_type = copied_type(target, target_field)?;
+        if target.is_computed(target_field) {
+            return Err(computed_write(target, target_field));
+        }
+        if target_columns.iter().any(|column| column == target_field) {
+            return Err(KooError::InvalidData(format!(
+                "'{}.{}' is mapped to more than once",
+                target.name, target_field
+            )));
+        }
+        // Ids and references are both integers, and foreign keys check
+        // what ends up in a reference
+        let compatible = source_type == target_type
+            || references_alike(&source_type, &target_type)
+            || matches!(
+                (&source_type, &target_type),
+                (FieldType::Enum(_), FieldType::Text)
+                    | (FieldType::Integer, FieldType::Reference(_))
+                    | (FieldType::Reference(_), FieldType::Integer)
+            );
+        if !compatible {
+            return Err(KooError::InvalidSchema(format!(
+                "'{}.{}' is {} but '{}.{}' is {}",
+                source.name,
+                source_field,
+                source_type.name(),
+                target.name,
+                target_field,
+                target_type.name()
+            )));
+        }
+        source_columns.push(source_field.to_string());
+        target_columns.push(target_field.to_string());
+    }
+    Ok((source_columns, target_columns))
+}
+
+// Type of a field or id that can be copied as stored. Each schema encrypts
+// with its own keys, so ciphertext can't move between them.
+fn copied_type(schema: &Schema, field_name: &str) -> Result<FieldType> {
+    if field_name == "id" && schema.key.has_id_column() {
+        return Ok(match schema.key {
+            PrimaryKey::Text => FieldType::Text,
+            _ => FieldType::Integer,
+        });
+    }
+    let field_type = schema.fields.get(field_name).ok_or_else(|| KooError::UnknownField {
+        schema: schema.name.clone(),
+        field: field_name.to_string(),
+    })?;
+    if schema.encrypted_fields.iter().any(|f| f == field_name) {
+        return Err(KooError::InvalidData(format!(
+            "'{}.{}' is encrypted, so it can't be copied",
+            schema.name, field_name
+        )));
+    }
+    Ok(field_type.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::Value;
+    use std::collections::HashMap;
+
+    use crate::audit::AuditAction;
+    use crate::flexible_database::{FieldDef, ModelId};
+    use crate::query::{Direction, Op};
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.to_string())
+    }
+
+    // Orders to copy, and an archive to copy them into
+    fn shop() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let status = FieldType::Enum(vec!["open".to_string(), "closed".to_string()]);
+        db.define_schema(
+            Schema::new("orders", [])
+                .field("customer", FieldDef::new(FieldType::Text))
+                .field("total", FieldDef::new(FieldType::Integer))
+                .field("status", FieldDef::new(status)),
+        )
+        .unwrap();
+        db.define_schema(
+            Schema::new("archive", [])
+                .field("customer", FieldDef::new(FieldType::Text).unique())
+                .field("total", FieldDef::new(FieldType::Integer))
+                .field("status", FieldDef::new(FieldType::Text))
+                .field("note", FieldDef::new(FieldType::Text).with_default(text("archived")))
+                .field("doubled", FieldDef::new(FieldType::Integer).computed("total * 2")),
+        )
+        .unwrap();
+        for (customer, total, status) in [("ann", 5, "open"), ("bob", 30, "closed"), ("cat", 40, "closed")] {
+            let data = HashMap::from([
+                ("customer".to_string(), text(customer)),
+                ("total".to_string(), Value::Integer(total)),
+                ("status".to_string(), text(status)),
+            ]);
+            db.create_model("orders", data).unwrap();
+        }
+        db
+    }
+
+    fn archived(db: &FlexibleDatabase) -> Vec<(Option<ModelId>, Value, Value, Value)> {
+        let query = Query::new("archive").order_by("customer", Direction::Asc);
+        db.find(&query)
+            .unwrap()
+            .into_iter()
+            .map(|model| {
+                let id = model.id.clone();
+                (
+                    id,
+                    model.data["customer"].clone(),
+                    model.data["status"].clone(),
+                    model.data["note"].clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn copies_the_matching_models() {
+        let db = shop();
+        let filter = Query::new("orders").filter("total", Op::Ge, Value::Integer(30));
+        let mapping = [("customer", "customer"), ("total", "total"), ("status", "status")];
+        assert_eq!(db.copy_rows("orders", "archive", &mapping, Some(&filter)).unwrap(), 2);
+
+        let rows = archived(&db);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            (&rows[0].1, &rows[0].2, &rows[0].3),
+            (&text("bob"), &text("closed"), &text("archived"))
+        );
+        assert_eq!(rows[1].1, text("cat"));
+        // Fresh ids, and computed fields follow the copied row
+        assert_eq!(rows[0].0, Some(ModelId::Integer(1)));
+        let cat = db.get_model("archive", 2).unwrap().unwrap();
+        assert_eq!(cat.data["doubled"], Value::Integer(80));
+        assert_eq!(db.count("orders").unwrap(), 3);
+    }
+
+    #[test]
+    fn mapped_ids_are_kept() {
+        let db = shop();
+        let mapping = [
+            ("id", "id"),
+            ("customer", "customer"),
+            ("total", "total"),
+            ("status", "status"),
+        ];
+        let filter = Query::new("orders").filter("customer", Op::Eq, text("cat"));
+        db.copy_rows("orders", "archive", &mapping, Some(&filter)).unwrap();
+        assert_eq!(archived(&db)[0].0, Some(ModelId::Integer(3)));
+    }
+
+    #[test]
+    fn a_failing_row_fails_the_whole_copy() {
+        let db = shop();
+        let cat = HashMap::from([
+            ("customer".to_string(), text("cat")),
+            ("total".to_string(), Value::Integer(1)),
+            ("status".to_string(), text("open")),
+        ]);
+        db.create_model("archive", cat).unwrap();
+        let mapping = [("customer", "customer"), ("total", "total"), ("status", "status")];
+        assert!(db.copy_rows("orders", "archive", &mapping, None).is_err());
+        assert_eq!(db.count("archive").unwrap(), 1);
+    }
+
+    #[test]
+    fn mappings_are_checked() {
+        let db = shop();
+        let copy = |mapping: &[(&str, &str)]| db.copy_rows("orders", "archive", mapping, None);
+
+        assert!(matches!(copy(&[]), Err(KooError::InvalidData(_))));
+        assert!(matches!(copy(&[("size", "total")]), Err(KooError::UnknownField { .. })));
+        assert!(matches!(
+            copy(&[("customer", "total")]),
+            Err(KooError::InvalidSchema(_))
+        ));
+        // Enums copy into text, but text doesn't copy into an enum
+        let reverse = db.copy_rows("archive", "orders", &[("status", "status")], None);
+        assert!(matches!(reverse, Err(KooError::InvalidSchema(_))));
+        let twice = [("customer", "customer"), ("status", "customer")];
+        assert!(matches!(copy(&twice), Err(KooError::InvalidData(_))));
+        assert!(copy(&[("total", "doubled")]).is_err());
+        assert_eq!(db.count("archive").unwrap(), 0);
+    }
+
+    #[test]
+    fn filters_must_query_the_source() {
+        let db = shop();
+        let filter = Query::new("archive");
+        let mapping = [("customer", "customer")];
+        assert!(matches!(
+            db.copy_rows("orders", "archive", &mapping, Some(&filter)),
+            Err(KooError::InvalidData(_))
+        ));
+    }
+
+    #[test]
+    fn copied_models_are_audited() {
+        let mut db = shop();
+        db.enable_audit().unwrap();
+        let closed = Query::new("orders").filter("status", Op::Eq, text("closed"));
+        let mapping = [("customer", "customer"), ("total", "total"), ("status", "status")];
+        assert_eq!(db.copy_rows("orders", "archive", &mapping, Some(&closed)).unwrap(), 2);
+
+        for (id, customer) in [(1, "bob"), (2, "cat")] {
+            let history = db.audit_history("archive", id).unwrap();
+            assert_eq!(history.len(), 1);
+            assert_eq!(history[0].action, AuditAction::Create);
+            let values = history[0].new_values.as_ref().unwrap();
+            assert_eq!(values["customer"], customer);
+            assert_eq!(values["note"], "archived");
+        }
+        assert!(db.audit_history("orders", 2).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,283 @@
+use rusqlite::OptionalExtension;
+use rusqlite::types::Value;
+
+use crate::append_only::check_mutable;
+use crate::audit::AuditAction;
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FieldType, FlexibleDatabase, ModelId, PrimaryKey, VERSION_COLUMN, key_filter};
+use crate::validation::validate;
+
+// Counters, such as view counts or stock levels, change by a delta in one
+// UPDATE rather than being read, changed and written back, so writers that
+// increment the same model at once never lose each other's changes.
+impl FlexibleDatabase {
+    // Add `delta` to the number in `field_name` of the model `id`, returning
+    // the new value, or None if there is no such model. An Integer field
+    // takes an integer delta, a Real field either kind; pass a negative one
+    // to decrement. A change that would break the field's min or max is
+    // refused with `KooError::Validation`, leaving the model as it was.
+    pub fn increment(
+        &self,
+        schema_name: &str,
+        id: impl Into<ModelId>,
+        field_name: &str,
+        delta: impl Into<Value>,
+    ) -> Result<Option<Value>> {
+        let schema = self.schema_or_err(schema_name)?;
+        check_mutable(schema)?;
+        let id = id.into();
+        let delta = delta.into();
+
+        let field_type = schema.fields.get(field_name).ok_or_else(|| KooError::UnknownField {
+            schema: schema_name.to_string(),
+            field: field_name.to_string(),
+        })?;
+        let reason = if !matches!(field_type, FieldType::Integer | FieldType::Real) {
+            Some("not a number")
+        } else if schema.is_computed(field_name) {
+            Some("computed")
+        } else if schema.encrypted_fields.iter().any(|f| f == field_name) {
+            Some("encrypted")
+        } else if matches!(&schema.key, PrimaryKey::Composite(key_fields) if key_fields.iter().any(|f| f == field_name))
+        {
+            Some("part of the key")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            return Err(KooError::InvalidData(format!(
+                "'{}.{}' can't be incremented, as it is {}",
+                schema_name, field_name, reason
+            )));
+        }
+        match (field_type, &delta) {
+            (FieldType::Integer, Value::Integer(_)) | (FieldType::Real, Value::Integer(_) | Value::Real(_)) => {}
+            _ => {
+                return Err(KooError::InvalidData(format!(
+                    "'{}.{}' can't be incremented by {:?}",
+                    schema_name, field_name, delta
+                )));
+            }
+        }
+        // The policy sees the model as it is, as the new value is only
+        // known once it has been written
+        if !self.check_change(schema_name, &id, None)? {
+            return Ok(None);
+        }
+
+        // The field's checks, applied to the new value so a change that
+        // breaks them matches no row
+        let (key_sql, key_values) = key_filter(schema, &id)?;
+        let incremented = format!("({} + ?1)", field_name);
+        let mut conditions = vec![key_sql];
+        for validator in schema.validators.get(field_name).into_iter().flatten() {
+            conditions.extend(validator.sql(&incremented));
+        }
+        let mut sets = vec![format!("{0} = {0} + ?1", field_name)];
+        if schema.versioned {
+            sets.push(format!("{0} = {0} + 1", VERSION_COLUMN));
+        }
+        let sql = format!(
+            "UPDATE {} SET {} WHERE {} RETURNING {}",
+            schema_name,
+            sets.join(", "),
+            conditions.join(" AND "),
+            field_name
+        );
+        let mut params = vec![delta.clone()];
+        params.extend(key_values.iter().cloned());
+        if self.planned(&sql, &params)? {
+            return Ok(None);
+        }
+
+        let mut value = None;
+        self.retrying(|| {
+            self.audit_change(schema, &id, AuditAction::Update, || {
+                self.traced(&sql, params.len(), || {
+                    value = self
+                        .prepare_cached(&sql)?
+                        .query_row(rusqlite::params_from_iter(&params), |row| row.get::<_, Value>(0))
+                        .optional()?;
+                    let changed = value.is_some() as usize;
+                    Ok((changed, changed))
+                })
+            })
+        })?;
+        // RETURNING gives whole reals back as integers, as SQLite stores them
+        if let (FieldType::Real, Some(Value::Integer(i))) = (field_type, &value) {
+            value = Some(Value::Real(*i as f64));
+        }
+        if value.is_some() || !schema.validators.contains_key(field_name) {
+            return Ok(value);
+        }
+
+        // Nothing matched: either the model is gone or the checks failed
+        let sql = format!("SELECT {} FROM {} WHERE {}", incremented, schema_name, &conditions[0]);
+        let refused: Option<Value> = self
+            .conn
+            .query_row(&sql, rusqlite::params_from_iter(&params), |row| row.get(0))
+            .optional()?;
+        if let Some(refused) = refused {
+            validate(schema, [(&field_name.to_string(), &refused)])?;
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::thread;
+
+    use crate::flexible_database::{FieldDef, Schema};
+    use crate::temp_file::TempFile;
+    use crate::validation::Validator;
+
+    fn products(schema: Schema) -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(schema).unwrap();
+        db
+    }
+
+    fn stock() -> Schema {
+        Schema::new("products", [])
+            .field("name", FieldDef::new(FieldType::Text))
+            .field("views", FieldDef::new(FieldType::Integer).with_default(0))
+            .field("rating", FieldDef::new(FieldType::Real).with_default(0.0))
+            .field("stock", FieldDef::new(FieldType::Integer).validate(Validator::Min(0.0)))
+    }
+
+    fn product(db: &FlexibleDatabase, stock: i64) -> ModelId {
+        let data = HashMap::from([
+            ("name".to_string(), Value::Text("pen".to_string())),
+            ("stock".to_string(), Value::Integer(stock)),
+        ]);
+        db.create_model("products", data).unwrap()
+    }
+
+    #[test]
+    fn increments_return_the_new_value() {
+        let db = products(stock());
+        let id = product(&db, 5);
+        assert_eq!(
+            db.increment("products", id.clone(), "views", 1).unwrap(),
+            Some(Value::Integer(1))
+        );
+        assert_eq!(
+            db.increment("products", id.clone(), "views", 9).unwrap(),
+            Some(Value::Integer(10))
+        );
+        assert_eq!(
+            db.increment("products", id.clone(), "stock", -2).unwrap(),
+            Some(Value::Integer(3))
+        );
+        assert_eq!(
+            db.increment("products", id.clone(), "rating", 1).unwrap(),
+            Some(Value::Real(1.0))
+        );
+        assert_eq!(
+            db.increment("products", id.clone(), "rating", 0.5).unwrap(),
+            Some(Value::Real(1.5))
+        );
+
+        let stored = db.get_model("products", id).unwrap().unwrap();
+        assert_eq!(stored.data["views"], Value::Integer(10));
+        assert_eq!(db.increment("products", 99, "views", 1).unwrap(), None);
+    }
+
+    #[test]
+    fn increments_that_break_the_checks_are_refused() {
+        let db = products(stock());
+        let id = product(&db, 1);
+        assert_eq!(
+            db.increment("products", id.clone(), "stock", -1).unwrap(),
+            Some(Value::Integer(0))
+        );
+        assert!(matches!(
+            db.increment("products", id.clone(), "stock", -1),
+            Err(KooError::Validation { .. })
+        ));
+        let stored = db.get_model("products", id).unwrap().unwrap();
+        assert_eq!(stored.data["stock"], Value::Integer(0));
+        // A missing model is still just missing
+        assert_eq!(db.increment("products", 99, "stock", -1).unwrap(), None);
+    }
+
+    #[test]
+    fn only_stored_numbers_can_be_incremented() {
+        let schema = stock()
+            .field("total", FieldDef::new(FieldType::Integer).computed("stock * 2"))
+            .field("secret", FieldDef::new(FieldType::Integer).encrypted().nullable());
+        let db = products(schema);
+        let id = product(&db, 1);
+        for field in ["name", "total", "secret"] {
+            assert!(
+                matches!(
+                    db.increment("products", id.clone(), field, 1),
+                    Err(KooError::InvalidData(_))
+                ),
+                "{}",
+                field
+            );
+        }
+        assert!(matches!(
+            db.increment("products", id.clone(), "views", 0.5),
+            Err(KooError::InvalidData(_))
+        ));
+        assert!(matches!(
+            db.increment("products", id, "likes", 1),
+            Err(KooError::UnknownField { .. })
+        ));
+    }
+
+    #[test]
+    fn increments_bump_the_version() {
+        let db = products(stock().with_versioning());
+        let id = product(&db, 1);
+        db.increment("products", id.clone(), "views", 1).unwrap();
+        let stored = db.get_model("products", id).unwrap().unwrap();
+        assert_eq!(stored.data[VERSION_COLUMN], Value::Integer(2));
+    }
+
+    #[test]
+    fn concurrent_increments_are_all_kept() {
+        let file = TempFile::new("db");
+        let mut db = FlexibleDatabase::new(file.path()).unwrap();
+        db.define_schema(stock()).unwrap();
+        let id = product(&db, 0);
+
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let (path, id) = (file.path().to_string(), id.clone());
+                thread::spawn(move || {
+                    let mut db = FlexibleDatabase::new(&path).unwrap();
+                    db.load_schemas().unwrap();
+                    for _ in 0..25 {
+                        db.increment("products", id.clone(), "views", 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let stored = db.get_model("products", id).unwrap().unwrap();
+        assert_eq!(stored.data["views"], Value::Integer(100));
+    }
+
+    #[test]
+    fn increments_are_audited() {
+        let mut db = products(stock());
+        db.enable_audit().unwrap();
+        let id = product(&db, 5);
+        db.increment("products", id.clone(), "stock", -2).unwrap();
+        assert!(db.increment("products", id.clone(), "stock", -9).is_err());
+
+        let history = db.audit_history("products", id).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].action, AuditAction::Update);
+        assert_eq!(history[1].old_values.as_ref().unwrap()["stock"], 5);
+        assert_eq!(history[1].new_values.as_ref().unwrap()["stock"], 3);
+    }
+}
@@ -0,0 +1,707 @@
+use rusqlite::types::Value;
+use rusqlite::{Connection, OpenFlags};
+use std::path::Path;
+
+use crate::error::{KooError, Result};
+use crate::export::schema_to_json;
+use crate::flexible_database::{
+    FieldType, FlexibleDatabase, PrimaryKey, Schema, VERSION_COLUMN, column_definition, computed_column_definition,
+    sql_literal, table_definition,
+};
+use crate::tree::PARENT_FIELD;
+
+// A field that differs between two versions of a schema
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldChange {
+    Added {
+        field: String,
+        field_type: FieldType,
+    },
+    Removed {
+        field: String,
+        field_type: FieldType,
+    },
+    Retyped {
+        field: String,
+        from: FieldType,
+        to: FieldType,
+    },
+}
+
+// An index on a schema's table, by the statement that created it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexDefinition {
+    pub name: String,
+    pub sql: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexChange {
+    Added(IndexDefinition),
+    Removed(IndexDefinition),
+    Changed { from: IndexDefinition, to: IndexDefinition },
+}
+
+// What it takes to turn one version of a schema into another, from
+// `Schema::diff` or `diff_against_file`
+#[derive(Debug, Clone)]
+pub struct SchemaDiff {
+    pub schema: String,
+    pub fields: Vec<FieldChange>,
+    // Settings that differ, as schema files name them, e.g. "versioned"
+    pub settings: Vec<String>,
+    // The schemas' indexed fields, or when comparing databases every index
+    // on the tables
+    pub indexes: Vec<IndexChange>,
+    from: Schema,
+    to: Schema,
+    // Every index the table ends up with, to recreate after a rebuild
+    pub(crate) to_indexes: Vec<IndexDefinition>,
+}
+
+// How one database's schemas differ from another's
+#[derive(Debug, Clone, Default)]
+pub struct DatabaseDiff {
+    // Schemas only the other database has
+    pub added: Vec<Schema>,
+    // Schemas only this one has
+    pub removed: Vec<String>,
+    // Schemas both have, where they differ
+    pub changed: Vec<SchemaDiff>,
+    added_indexes: Vec<Vec<IndexDefinition>>,
+}
+
+impl Schema {
+    // How `other` differs from this schema: the fields it adds, removes or
+    // gives another type, and the settings it changes. A renamed field
+    // shows as one removed and one added.
+    pub fn diff(&self, other: &Schema) -> SchemaDiff {
+        let mut fields = Vec::new();
+        for (field_name, field_type) in &self.fields {
+            match other.fields.get(field_name) {
+                None => fields.push(FieldChange::Removed {
+                    field: field_name.clone(),
+                    field_type: field_type.clone(),
+                }),
+                Some(to) if to != field_type => fields.push(FieldChange::Retyped {
+                    field: field_name.clone(),
+                    from: field_type.clone(),
+                    to: to.clone(),
+                }),
+                Some(_) => {}
+            }
+        }
+        for (field_name, field_type) in &other.fields {
+            if !self.fields.contains_key(field_name) {
+                fields.push(FieldChange::Added {
+                    field: field_name.clone(),
+                    field_type: field_type.clone(),
+                });
+            }
+        }
+
+        let (from_indexes, to_indexes) = (field_indexes(self), field_indexes(other));
+        let mut indexes = Vec::new();
+        for index in &from_indexes {
+            if !to_indexes.contains(index) {
+                indexes.push(IndexChange::Removed(index.clone()));
+            }
+        }
+        for index in &to_indexes {
+            if !from_indexes.contains(index) {
+                indexes.push(IndexChange::Added(index.clone()));
+            }
+        }
+
+        let (from, to) = (schema_to_json(self), schema_to_json(other));
+        let mut settings = Vec::new();
+        for setting in to.keys().chain(from.keys().filter(|key| !to.contains_key(*key))) {
+            if !matches!(setting.as_str(), "name" | "fields" | "indexes" | "unique") && from.get(setting) != to.get(setting) {
+                settings.push(setting.clone());
+            }
+        }
+
+        SchemaDiff {
+            schema: other.name.clone(),
+            fields,
+            settings,
+            indexes,
+            from: self.clone(),
+            to: other.clone(),
+            to_indexes,
+        }
+    }
+}
+
+impl SchemaDiff {
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty() && self.settings.is_empty() && self.indexes.is_empty()
+    }
+
+    // SQL that brings the table and the `_koo_schemas` catalog from the old
+    // version to the new, in order. Columns are added and dropped in place
+    // where SQLite allows it; other changes, such as a new type or key,
+    // rebuild the table, copying the columns both versions share. Added
+    // fields need a default to fill existing rows with. The triggers and
+    // full-text indexes kooDB keeps for history, change logs and search
+    // aren't covered; use the alter methods on schemas that have them.
+    pub fn alter_statements(&self) -> Result<Vec<String>> {
+        if self.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.check_additions()?;
+        let (from, to) = (&self.from, &self.to);
+        let table = &to.name;
+
+        let mut statements = Vec::new();
+        if self.needs_rebuild() {
+            let rebuilt = format!("{}_koo_rebuild", table);
+            let mut columns: Vec<&str> = match (&from.key, &to.key) {
+                (PrimaryKey::Integer, PrimaryKey::Integer) | (PrimaryKey::Text, PrimaryKey::Text) => vec!["id"],
+                _ => vec![],
+            };
+            columns.extend(
+                from.fields
+                    .keys()
+                    .filter(|field| {
+                        to.fields.contains_key(*field) && !from.is_computed(field) && !to.is_computed(field)
+                    })
+                    .map(String::as_str),
+            );
+            if from.versioned && to.versioned {
+                columns.push(VERSION_COLUMN);
+            }
+            statements.extend([
+                "PRAGMA defer_foreign_keys = ON".to_string(),
+                format!("CREATE TABLE {}", table_definition(to, &rebuilt)),
+                format!(
+                    "INSERT INTO {rebuilt} ({columns}) SELECT {columns} FROM {table}",
+                    columns = columns.join(", ")
+                ),
+                format!("DROP TABLE {}", table),
+                format!("ALTER TABLE {} RENAME TO {}", rebuilt, table),
+            ]);
+            statements.extend(self.to_indexes.iter().map(|index| index.sql.clone()));
+        } else {
+            statements.extend(self.in_place_statements());
+        }
+        statements.push(format!(
+            "INSERT OR REPLACE INTO _koo_schemas (name, definition) VALUES ({}, {})",
+            sql_literal(&Value::Text(table.clone())),
+            sql_literal(&Value::Text(to.to_json()))
+        ));
+        Ok(statements)
+    }
+
+    // Added fields need a value for the rows already in the table
+    pub(crate) fn check_additions(&self) -> Result<()> {
+        let to = &self.to;
+        for change in &self.fields {
+            if let FieldChange::Added { field, .. } = change
+                && !to.defaults.contains_key(field)
+                && !to.is_computed(field)
+                && !(to.hierarchical && field == PARENT_FIELD)
+            {
+                return Err(KooError::InvalidSchema(format!(
+                    "a default is needed to add '{}' to existing rows of '{}'",
+                    field, to.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // ADD and DROP COLUMN statements, and the index changes around them,
+    // for a diff that doesn't need the table rebuilt
+    pub(crate) fn in_place_statements(&self) -> Vec<String> {
+        let (from, to) = (&self.from, &self.to);
+        let table = &to.name;
+        let mut statements = Vec::new();
+        // Indexed columns can't be dropped, so indexes go first
+        for change in &self.indexes {
+            if let IndexChange::Removed(index) | IndexChange::Changed { from: index, .. } = change {
+                statements.push(format!("DROP INDEX {}", index.name));
+            }
+        }
+        for change in &self.fields {
+            match change {
+                FieldChange::Added { field, field_type } => {
+                    let column = match to.computed_fields.get(field) {
+                        Some(expression) => computed_column_definition(field, field_type, expression),
+                        None if to.is_nullable(field) => {
+                            column_definition(field, field_type, to.defaults.get(field)).replacen(" NOT NULL", "", 1)
+                        }
+                        None => column_definition(field, field_type, to.defaults.get(field)),
+                    };
+                    statements.push(format!("ALTER TABLE {} ADD COLUMN {}", table, column));
+                }
+                FieldChange::Removed { field, .. } => {
+                    statements.push(format!("ALTER TABLE {} DROP COLUMN {}", table, field));
+                }
+                FieldChange::Retyped { .. } => unreachable!("retyped fields rebuild the table"),
+            }
+        }
+        match (from.versioned, to.versioned) {
+            (false, true) => statements.push(format!(
+                "ALTER TABLE {} ADD COLUMN {} INTEGER NOT NULL DEFAULT 1",
+                table, VERSION_COLUMN
+            )),
+            (true, false) => statements.push(format!("ALTER TABLE {} DROP COLUMN {}", table, VERSION_COLUMN)),
+            _ => {}
+        }
+        for change in &self.indexes {
+            if let IndexChange::Added(index) | IndexChange::Changed { to: index, .. } = change {
+                statements.push(index.sql.clone());
+            }
+        }
+        statements
+    }
+
+    // Whether the table's DDL changes in a way ADD and DROP COLUMN can't make
+    pub(crate) fn needs_rebuild(&self) -> bool {
+        let (from, to) = (&self.from, &self.to);
+        let fields_changed = self.fields.iter().any(|change| match change {
+            FieldChange::Retyped { .. } => true,
+            FieldChange::Added { field_type, .. } => matches!(field_type, FieldType::Reference(_)),
+            FieldChange::Removed { field, field_type } => {
+                matches!(field_type, FieldType::Reference(_)) || from.is_computed(field)
+            }
+        });
+        // Columns both have must be declared the same way
+        let columns_changed = from
+            .fields
+            .keys()
+            .filter(|field| to.fields.contains_key(*field))
+            .any(|field| {
+                from.defaults.get(field) != to.defaults.get(field)
+                    || from.computed_fields.get(field) != to.computed_fields.get(field)
+                    || from.encrypted_fields.contains(field) != to.encrypted_fields.contains(field)
+                    || from.is_nullable(field) != to.is_nullable(field)
+                    || (to.sql_checks && from.validators.get(field) != to.validators.get(field))
+            });
+        fields_changed
+            || columns_changed
+            || from.key != to.key
+            || from.hierarchical != to.hierarchical
+            || from.sql_checks != to.sql_checks
+            || (to.sql_checks && !self.fields.is_empty())
+    }
+}
+
+impl DatabaseDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    // SQL that brings this database's tables and catalog in line with the
+    // other's: new tables created, old ones dropped, and the rest altered
+    // as `SchemaDiff::alter_statements` does
+    pub fn alter_statements(&self) -> Result<Vec<String>> {
+        let mut statements = Vec::new();
+        for (schema, indexes) in self.added.iter().zip(&self.added_indexes) {
+            statements.push(format!("CREATE TABLE {}", table_definition(schema, &schema.name)));
+            statements.extend(indexes.iter().map(|index| index.sql.clone()));
+            statements.push(format!(
+                "INSERT OR REPLACE INTO _koo_schemas (name, definition) VALUES ({}, {})",
+                sql_literal(&Value::Text(schema.name.clone())),
+                sql_literal(&Value::Text(schema.to_json()))
+            ));
+        }
+        for diff in &self.changed {
+            statements.extend(diff.alter_statements()?);
+        }
+        for name in &self.removed {
+            statements.push(format!("DROP TABLE {}", name));
+            statements.push(format!(
+                "DELETE FROM _koo_schemas WHERE name = {}",
+                sql_literal(&Value::Text(name.clone()))
+            ));
+        }
+        Ok(statements)
+    }
+}
+
+impl FlexibleDatabase {
+    pub(crate) fn create_field_indexes(&self, schema: &Schema) -> Result<()> {
+        for index in field_indexes(schema) {
+            self.conn.execute(&index.sql, [])?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn drop_field_indexes(&self, schema: &Schema) -> Result<()> {
+        for index in field_indexes(schema) {
+            self.conn.execute(&format!("DROP INDEX IF EXISTS {}", index.name), [])?;
+        }
+        Ok(())
+    }
+
+    // How the schemas recorded in the database at `other_db_path` differ
+    // from the ones defined here, including the indexes on their tables;
+    // `alter_statements` gives the SQL to make this database match. The
+    // other file is only read. Schemas of attached databases are left out.
+    pub fn diff_against_file<P: AsRef<Path>>(&self, other_db_path: P) -> Result<DatabaseDiff> {
+        let other = Connection::open_with_flags(
+            other_db_path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        let has_catalog: bool = other.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_koo_schemas')",
+            [],
+            |row| row.get(0),
+        )?;
+        let mut other_schemas = Vec::new();
+        if has_catalog {
+            let definitions = other
+                .prepare("SELECT definition FROM _koo_schemas ORDER BY name")?
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            for definition in definitions {
+                other_schemas.push(Schema::from_json(&definition)?.materialize()?);
+            }
+        }
+
+        let mut diff = DatabaseDiff::default();
+        for schema in &other_schemas {
+            match self.schemas.get(&schema.name) {
+                None => {
+                    diff.added_indexes.push(index_definitions(&other, &schema.name)?);
+                    diff.added.push(schema.clone());
+                }
+                Some(current) => {
+                    let mut schema_diff = current.diff(schema);
+                    let (from, to) = (
+                        index_definitions(&self.conn, &schema.name)?,
+                        index_definitions(&other, &schema.name)?,
+                    );
+                    for index in &from {
+                        match to.iter().find(|other| other.name == index.name) {
+                            None => schema_diff.indexes.push(IndexChange::Removed(index.clone())),
+                            Some(other) if normalized(&other.sql) != normalized(&index.sql) => {
+                                schema_diff.indexes.push(IndexChange::Changed {
+                                    from: index.clone(),
+                                    to: other.clone(),
+                                });
+                            }
+                            Some(_) => {}
+                        }
+                    }
+                    for index in &to {
+                        if !from.iter().any(|current| current.name == index.name) {
+                            schema_diff.indexes.push(IndexChange::Added(index.clone()));
+                        }
+                    }
+                    schema_diff.to_indexes = to;
+                    if !schema_diff.is_empty() {
+                        diff.changed.push(schema_diff);
+                    }
+                }
+            }
+        }
+        let mut removed: Vec<String> = self
+            .schemas
+            .keys()
+            .filter(|name| !name.contains('.') && !other_schemas.iter().any(|schema| schema.name == **name))
+            .cloned()
+            .collect();
+        removed.sort();
+        diff.removed = removed;
+        Ok(diff)
+    }
+}
+
+// Indexes of a schema's indexed and unique fields, as `define_schema`
+// creates them. An attached database's index is named in it, but its
+// table isn't.
+pub(crate) fn field_indexes(schema: &Schema) -> Vec<IndexDefinition> {
+    let table = schema.name.rsplit('.').next().unwrap_or(&schema.name);
+    let indexed = schema.indexed_fields.iter().map(|field_name| ("INDEX", "idx", field_name));
+    let unique = schema.unique_fields.iter().map(|field_name| ("UNIQUE INDEX", "key", field_name));
+    indexed
+        .chain(unique)
+        .map(|(kind, suffix, field_name)| {
+            let name = format!("{}_{}_{}", schema.name, field_name, suffix);
+            IndexDefinition {
+                sql: format!("CREATE {} IF NOT EXISTS {} ON {} ({})", kind, name, table, field_name),
+                name,
+            }
+        })
+        .collect()
+}
+
+// Indexes created by a statement, so not those SQLite makes for keys
+pub(crate) fn index_definitions(conn: &Connection, table: &str) -> Result<Vec<IndexDefinition>> {
+    let indexes = conn
+        .prepare(
+            "SELECT name, sql FROM sqlite_master
+             WHERE type = 'index' AND tbl_name = ? AND sql IS NOT NULL ORDER BY name",
+        )?
+        .query_map([table], |row| {
+            Ok(IndexDefinition {
+                name: row.get(0)?,
+                sql: row.get(1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(indexes)
+}
+
+// SQL with IF NOT EXISTS and runs of whitespace taken out, so indexes
+// created the same way compare equal
+fn normalized(sql: &str) -> String {
+    sql.replace("IF NOT EXISTS ", "")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::flexible_database::FieldDef;
+    use crate::temp_file::TempFile;
+
+    fn users() -> Schema {
+        Schema::new("users", [])
+            .field("name", FieldDef::new(FieldType::Text))
+            .field("age", FieldDef::new(FieldType::Integer))
+            .field("nickname", FieldDef::new(FieldType::Text).nullable())
+    }
+
+    fn database(path: &str, schemas: impl IntoIterator<Item = Schema>) -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(path).unwrap();
+        for schema in schemas {
+            db.define_schema(schema).unwrap();
+        }
+        db
+    }
+
+    fn user(db: &FlexibleDatabase, name: &str, age: i64) {
+        let data = HashMap::from([
+            ("name".to_string(), Value::Text(name.to_string())),
+            ("age".to_string(), Value::Integer(age)),
+        ]);
+        db.create_model("users", data).unwrap();
+    }
+
+    // Run the statements, then read the schema back as a new handle would
+    fn apply(db: &FlexibleDatabase, statements: &[String]) -> Schema {
+        db.conn.execute_batch(&statements.join(";\n")).unwrap();
+        let definition: String = db
+            .conn
+            .query_row("SELECT definition FROM _koo_schemas WHERE name = 'users'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        Schema::from_json(&definition).unwrap().materialize().unwrap()
+    }
+
+    #[test]
+    fn fields_settings_and_indexes_are_compared() {
+        let from = users().materialize().unwrap();
+        let to = Schema::new("users", [])
+            .field("name", FieldDef::new(FieldType::Text).indexed())
+            .field("age", FieldDef::new(FieldType::Real))
+            .field("email", FieldDef::new(FieldType::Text).with_default("".to_string()))
+            .with_versioning()
+            .materialize()
+            .unwrap();
+        let diff = from.diff(&to);
+        assert!(!diff.is_empty());
+        assert_eq!(
+            diff.fields,
+            vec![
+                FieldChange::Retyped {
+                    field: "age".to_string(),
+                    from: FieldType::Integer,
+                    to: FieldType::Real,
+                },
+                FieldChange::Removed {
+                    field: "nickname".to_string(),
+                    field_type: FieldType::Text,
+                },
+                FieldChange::Added {
+                    field: "email".to_string(),
+                    field_type: FieldType::Text,
+                },
+            ]
+        );
+        assert!(diff.settings.contains(&"versioned".to_string()), "{:?}", diff.settings);
+        assert_eq!(diff.indexes, vec![IndexChange::Added(field_indexes(&to)[0].clone())]);
+
+        let same = users().materialize().unwrap();
+        let diff = from.diff(&same);
+        assert!(diff.is_empty());
+        assert_eq!(diff.alter_statements().unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn columns_are_added_and_dropped_in_place() {
+        let db = database(":memory:", [users()]);
+        user(&db, "ann", 30);
+        let to = Schema::new("users", [])
+            .field("name", FieldDef::new(FieldType::Text))
+            .field("age", FieldDef::new(FieldType::Integer))
+            .field("email", FieldDef::new(FieldType::Text).with_default("none".to_string()))
+            .field(
+                "bio",
+                FieldDef::new(FieldType::Text).nullable().with_default("-".to_string()),
+            )
+            .with_versioning()
+            .materialize()
+            .unwrap();
+        let diff = db.schemas["users"].diff(&to);
+        let statements = diff.alter_statements().unwrap();
+        assert!(
+            statements.iter().all(|sql| !sql.starts_with("CREATE TABLE")),
+            "{:?}",
+            statements
+        );
+        assert!(statements.iter().any(|sql| sql.contains("DROP COLUMN nickname")));
+
+        let stored = apply(&db, &statements);
+        assert!(stored.diff(&to).is_empty());
+        let row: (String, String, i64) = db
+            .conn
+            .query_row(
+                &format!("SELECT name, email, {} FROM users", VERSION_COLUMN),
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(row, ("ann".to_string(), "none".to_string(), 1));
+    }
+
+    #[test]
+    fn retyped_fields_rebuild_the_table_and_keep_the_rows() {
+        let db = database(
+            ":memory:",
+            [users().field(
+                "email",
+                FieldDef::new(FieldType::Text).unique().with_default("".to_string()),
+            )],
+        );
+        user(&db, "ann", 30);
+        let to = Schema::new("users", [])
+            .field("name", FieldDef::new(FieldType::Text))
+            .field("age", FieldDef::new(FieldType::Real))
+            .field("nickname", FieldDef::new(FieldType::Text).nullable())
+            .field(
+                "email",
+                FieldDef::new(FieldType::Text).unique().with_default("".to_string()),
+            )
+            .materialize()
+            .unwrap();
+        let diff = db.schemas["users"].diff(&to);
+        let statements = diff.alter_statements().unwrap();
+        assert!(
+            statements
+                .iter()
+                .any(|sql| sql.starts_with("CREATE TABLE users_koo_rebuild"))
+        );
+
+        let stored = apply(&db, &statements);
+        assert!(stored.diff(&to).is_empty());
+        let row: (String, f64) = db
+            .conn
+            .query_row("SELECT name, age FROM users", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        assert_eq!(row, ("ann".to_string(), 30.0));
+        // The unique index came back with the table
+        let indexes = index_definitions(&db.conn, "users").unwrap();
+        assert_eq!(
+            indexes.iter().map(|index| index.name.as_str()).collect::<Vec<_>>(),
+            vec!["users_email_key"]
+        );
+    }
+
+    #[test]
+    fn added_fields_need_a_default() {
+        let from = users().materialize().unwrap();
+        let to = users()
+            .field("email", FieldDef::new(FieldType::Text))
+            .materialize()
+            .unwrap();
+        assert!(matches!(
+            from.diff(&to).alter_statements(),
+            Err(KooError::InvalidSchema(message)) if message.contains("'email'")
+        ));
+    }
+
+    #[test]
+    fn databases_converge_on_the_other_file() {
+        let (this, other) = (TempFile::new("db"), TempFile::new("db"));
+        let products = Schema::new("products", []).field("title", FieldDef::new(FieldType::Text).indexed());
+        let orders = Schema::new("orders", []).field("total", FieldDef::new(FieldType::Real));
+        let db = database(this.path(), [users(), orders]);
+        user(&db, "ann", 30);
+        let target_users = users().field("email", FieldDef::new(FieldType::Text).with_default("".to_string()));
+        let target = database(other.path(), [target_users, products]);
+        target
+            .conn
+            .execute("CREATE INDEX users_by_age ON users (age)", [])
+            .unwrap();
+        drop(target);
+
+        let diff = db.diff_against_file(other.path()).unwrap();
+        assert_eq!(
+            diff.added.iter().map(|schema| schema.name.as_str()).collect::<Vec<_>>(),
+            vec!["products"]
+        );
+        assert_eq!(diff.removed, vec!["orders".to_string()]);
+        assert_eq!(diff.changed.len(), 1);
+        let changed = &diff.changed[0];
+        assert_eq!(changed.schema, "users");
+        assert!(
+            changed
+                .indexes
+                .iter()
+                .any(|change| matches!(change, IndexChange::Added(index) if index.name == "users_by_age"))
+        );
+
+        db.conn
+            .execute_batch(&diff.alter_statements().unwrap().join(";\n"))
+            .unwrap();
+        drop(db);
+        let mut db = FlexibleDatabase::new(this.path()).unwrap();
+        db.load_schemas().unwrap();
+        assert!(db.diff_against_file(other.path()).unwrap().is_empty());
+        assert_eq!(db.count("users").unwrap(), 1);
+        assert_eq!(index_definitions(&db.conn, "products").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn index_changes_are_found_by_their_sql() {
+        let (this, other) = (TempFile::new("db"), TempFile::new("db"));
+        let db = database(this.path(), [users()]);
+        db.conn
+            .execute("CREATE INDEX users_lookup ON users (name)", [])
+            .unwrap();
+        let target = database(other.path(), [users()]);
+        target
+            .conn
+            .execute("CREATE INDEX users_lookup ON users (name, age)", [])
+            .unwrap();
+        drop(target);
+
+        let diff = db.diff_against_file(other.path()).unwrap();
+        assert!(matches!(
+            diff.changed[0].indexes.as_slice(),
+            [IndexChange::Changed { from, to }] if from.sql.ends_with("(name)") && to.sql.ends_with("(name, age)")
+        ));
+        db.conn
+            .execute_batch(&diff.alter_statements().unwrap().join(";\n"))
+            .unwrap();
+        let target = FlexibleDatabase::new(other.path()).unwrap();
+        target.conn.execute("DROP INDEX users_lookup", []).unwrap();
+        target
+            .conn
+            .execute("CREATE INDEX   users_lookup ON users (name,   age)", [])
+            .unwrap();
+        drop(target);
+        // Only the whitespace differs now
+        assert!(db.diff_against_file(other.path()).unwrap().is_empty());
+    }
+}
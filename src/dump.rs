@@ -0,0 +1,344 @@
+use rusqlite::types::Value;
+use std::io::Write;
+
+use crate::error::Result;
+use crate::flexible_database::{FlexibleDatabase, sql_literal};
+
+// What `dump_sql` writes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DumpOptions {
+    schemas: Option<Vec<String>>,
+    schema_only: bool,
+}
+
+impl DumpOptions {
+    pub fn new() -> DumpOptions {
+        DumpOptions::default()
+    }
+
+    // Only these schemas, with their indexes, triggers and full-text
+    // indexes and their entries in the `_koo_schemas` catalog, instead of
+    // the whole database
+    pub fn schemas(mut self, schema_names: &[&str]) -> DumpOptions {
+        self.schemas = Some(schema_names.iter().map(|name| name.to_string()).collect());
+        self
+    }
+
+    // Leave the rows out, writing only the statements that create tables
+    pub fn schema_only(mut self, enabled: bool) -> DumpOptions {
+        self.schema_only = enabled;
+        self
+    }
+}
+
+// An object of sqlite_master, in creation order
+struct DumpedObject {
+    kind: String,
+    name: String,
+    table: String,
+    sql: String,
+}
+
+// A SQL script of the database in the form of the sqlite3 shell's `.dump`,
+// suited to keeping in git or restoring with the stock `sqlite3` tool
+impl FlexibleDatabase {
+    // Write the tables and their rows as one transaction of CREATE and
+    // INSERT statements, then the indexes, triggers and views, returning
+    // the number of rows written. Full-text indexes are created empty and
+    // rebuilt from their tables rather than dumped. Attached databases are
+    // left out.
+    pub fn dump_sql<W: Write>(&self, mut writer: W, options: DumpOptions) -> Result<usize> {
+        let objects = self.dump_objects(&options)?;
+        writer.write_all(b"PRAGMA foreign_keys=OFF;\nBEGIN TRANSACTION;\n")?;
+
+        let mut written = 0;
+        let mut rebuilds = Vec::new();
+        for object in objects.iter().filter(|object| object.kind == "table") {
+            if object.sql.starts_with("CREATE VIRTUAL TABLE") {
+                writeln!(writer, "{};", object.sql)?;
+                if object.sql.contains("fts5") {
+                    rebuilds.push(object.name.as_str());
+                }
+                continue;
+            }
+            if object.name == "_koo_schemas" && options.schemas.is_some() {
+                // Other schemas may already be in the catalog of the
+                // database the script is run against
+                writeln!(
+                    writer,
+                    "{};",
+                    object.sql.replacen("CREATE TABLE", "CREATE TABLE IF NOT EXISTS", 1)
+                )?;
+            } else if object.name != "sqlite_sequence" {
+                writeln!(writer, "{};", object.sql)?;
+            }
+            if !options.schema_only {
+                written += self.dump_rows(&mut writer, object, &options)?;
+            }
+        }
+        for table in rebuilds {
+            let table = quoted(table);
+            writeln!(writer, "INSERT INTO {0}({0}) VALUES('rebuild');", table)?;
+        }
+        for object in objects.iter().filter(|object| object.kind != "table") {
+            writeln!(writer, "{};", object.sql)?;
+        }
+
+        writer.write_all(b"COMMIT;\n")?;
+        writer.flush()?;
+        Ok(written)
+    }
+
+    fn dump_objects(&self, options: &DumpOptions) -> Result<Vec<DumpedObject>> {
+        // Shadow tables hold the contents of virtual tables, which are
+        // rebuilt instead
+        let shadow: Vec<String> = self
+            .conn
+            .prepare("SELECT name FROM pragma_table_list WHERE schema = 'main' AND type = 'shadow'")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        let objects = self
+            .conn
+            .prepare(
+                "SELECT type, name, tbl_name, sql FROM sqlite_master
+                 WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite_autoindex_%'
+                 ORDER BY tbl_name = 'sqlite_sequence', rowid",
+            )?
+            .query_map([], |row| {
+                Ok(DumpedObject {
+                    kind: row.get(0)?,
+                    name: row.get(1)?,
+                    table: row.get(2)?,
+                    sql: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(objects
+            .into_iter()
+            .filter(|object| !shadow.contains(&object.name))
+            .filter(|object| match &options.schemas {
+                None => true,
+                Some(schema_names) => {
+                    object.name == "_koo_schemas"
+                        || schema_names.contains(&object.table)
+                        || schema_names
+                            .iter()
+                            .any(|name| object.sql.contains(&format!("content='{}'", name)))
+                }
+            })
+            .collect())
+    }
+
+    fn dump_rows<W: Write>(&self, writer: &mut W, object: &DumpedObject, options: &DumpOptions) -> Result<usize> {
+        // Generated columns can't be inserted into, so columns are named
+        // when the table has any
+        let columns: Vec<(String, bool)> = self
+            .conn
+            .prepare("SELECT name, hidden IN (2, 3) FROM pragma_table_xinfo(?)")?
+            .query_map([&object.name], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        let stored: Vec<String> = columns
+            .iter()
+            .filter(|(_, generated)| !generated)
+            .map(|(name, _)| quoted(name))
+            .collect();
+        let table = quoted(&object.name);
+        let target = match stored.len() == columns.len() {
+            true => table.clone(),
+            false => format!("{}({})", table, stored.join(",")),
+        };
+
+        let mut sql = format!("SELECT {} FROM {}", stored.join(", "), table);
+        if let Some(schema_names) = options.schemas.as_ref().filter(|_| object.name == "_koo_schemas") {
+            let names: Vec<String> = schema_names
+                .iter()
+                .map(|name| sql_literal(&Value::Text(name.clone())))
+                .collect();
+            sql.push_str(&format!(" WHERE name IN ({})", names.join(", ")));
+        }
+        let verb = match object.name.as_str() {
+            "_koo_schemas" if options.schemas.is_some() => "INSERT OR REPLACE INTO",
+            _ => "INSERT INTO",
+        };
+        if object.name == "sqlite_sequence" {
+            writer.write_all(b"DELETE FROM sqlite_sequence;\n")?;
+        }
+
+        let mut stmt = self.conn.prepare(&sql)?;
+        let mut rows = stmt.query([])?;
+        let mut written = 0;
+        while let Some(row) = rows.next()? {
+            let mut values = Vec::with_capacity(stored.len());
+            for i in 0..stored.len() {
+                values.push(dumped_literal(&row.get::<_, Value>(i)?));
+            }
+            writeln!(writer, "{} {} VALUES({});", verb, target, values.join(","))?;
+            written += 1;
+        }
+        Ok(written)
+    }
+}
+
+// `value` as a literal, with the infinities written as the sqlite3 shell
+// does, since Rust's "inf" isn't SQL
+fn dumped_literal(value: &Value) -> String {
+    match value {
+        Value::Real(f) if f.is_nan() => "NULL".to_string(),
+        Value::Real(f) if f.is_infinite() && *f > 0.0 => "1e999".to_string(),
+        Value::Real(f) if f.is_infinite() => "-1e999".to_string(),
+        _ => sql_literal(value),
+    }
+}
+
+// `name` as an identifier, quoted only when it needs to be
+fn quoted(name: &str) -> String {
+    let plain = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    match plain {
+        true => name.to_string(),
+        false => format!("\"{}\"", name.replace('"', "\"\"")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+    use std::collections::HashMap;
+
+    use crate::flexible_database::{FieldDef, FieldType, Schema};
+    use crate::options::DatabaseOptions;
+
+    fn database() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let articles = Schema::new("articles", [])
+            .field("title", FieldDef::new(FieldType::Text).indexed())
+            .field("words", FieldDef::new(FieldType::Integer))
+            .field("long", FieldDef::new(FieldType::Boolean).computed("words > 100"))
+            .with_fts(&["title"]);
+        db.define_schema(articles).unwrap();
+        let tags = Schema::new("tags", []).field("label", FieldDef::new(FieldType::Text));
+        db.define_schema(tags).unwrap();
+        for (title, words) in [("Rust's borrow checker", 250), ("Quiet mornings", 40)] {
+            let data = HashMap::from([
+                ("title".to_string(), Value::Text(title.to_string())),
+                ("words".to_string(), Value::Integer(words)),
+            ]);
+            db.create_model("articles", data).unwrap();
+        }
+        let data = HashMap::from([("label".to_string(), Value::Text("misc".to_string()))]);
+        db.create_model("tags", data).unwrap();
+        db
+    }
+
+    fn dump(db: &FlexibleDatabase, options: DumpOptions) -> (String, usize) {
+        let mut script = Vec::new();
+        let written = db.dump_sql(&mut script, options).unwrap();
+        (String::from_utf8(script).unwrap(), written)
+    }
+
+    fn restore(script: &str) -> FlexibleDatabase {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(script).unwrap();
+        let mut db = FlexibleDatabase::from_connection(conn, DatabaseOptions::default()).unwrap();
+        db.load_schemas().unwrap();
+        db
+    }
+
+    #[test]
+    fn dumps_restore_the_whole_database() {
+        let db = database();
+        let (script, written) = dump(&db, DumpOptions::new());
+        assert!(script.starts_with("PRAGMA foreign_keys=OFF;\nBEGIN TRANSACTION;\n"));
+        assert!(script.ends_with("COMMIT;\n"));
+        // Two articles, a tag and their catalog entries
+        assert_eq!(written, 5);
+        assert!(script.contains("INSERT INTO articles(id,title,words) VALUES(1,'Rust''s borrow checker',250);"));
+
+        let restored = restore(&script);
+        assert_eq!(restored.count("articles").unwrap(), 2);
+        assert_eq!(restored.count("tags").unwrap(), 1);
+        let article = restored.get_model("articles", 1).unwrap().unwrap();
+        assert_eq!(article.data["long"], Value::Integer(1));
+        // The full-text index was rebuilt from the table
+        assert_eq!(restored.search("articles", "borrow").unwrap().len(), 1);
+        let index: i64 = restored
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND name = 'articles_title_idx'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(index, 1);
+    }
+
+    #[test]
+    fn chosen_schemas_merge_into_another_database() {
+        let db = database();
+        let (script, written) = dump(&db, DumpOptions::new().schemas(&["tags"]));
+        assert_eq!(written, 2);
+        assert!(!script.contains("articles"));
+        assert!(script.contains("CREATE TABLE IF NOT EXISTS _koo_schemas"));
+
+        let mut other = FlexibleDatabase::new(":memory:").unwrap();
+        other
+            .define_schema(Schema::new("notes", []).field("body", FieldDef::new(FieldType::Text)))
+            .unwrap();
+        other.conn.execute_batch(&script).unwrap();
+        other.load_schemas().unwrap();
+        assert_eq!(other.count("tags").unwrap(), 1);
+        let stored: Vec<String> = other
+            .stored_schemas()
+            .unwrap()
+            .into_iter()
+            .map(|schema| schema.name)
+            .collect();
+        assert_eq!(stored.len(), 2, "{:?}", stored);
+    }
+
+    #[test]
+    fn schema_only_dumps_leave_the_rows_out() {
+        let db = database();
+        let (script, written) = dump(&db, DumpOptions::new().schema_only(true));
+        assert_eq!(written, 0);
+        assert!(!script.contains("INSERT INTO articles("));
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&script).unwrap();
+        let rows: i64 = conn
+            .query_row("SELECT COUNT(*) FROM articles", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 0);
+    }
+
+    #[test]
+    fn literals_and_names_are_written_as_sql() {
+        assert_eq!(dumped_literal(&Value::Real(f64::INFINITY)), "1e999");
+        assert_eq!(dumped_literal(&Value::Real(f64::NEG_INFINITY)), "-1e999");
+        assert_eq!(dumped_literal(&Value::Real(f64::NAN)), "NULL");
+        assert_eq!(dumped_literal(&Value::Text("it's".to_string())), "'it''s'");
+        assert_eq!(quoted("plain_name1"), "plain_name1");
+        assert_eq!(quoted("1st"), "\"1st\"");
+        assert_eq!(quoted("odd \"name\""), "\"odd \"\"name\"\"\"");
+
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        db.conn
+            .execute_batch(
+                "CREATE TABLE \"odd table\" (x REAL, b BLOB); INSERT INTO \"odd table\" VALUES (1e999, x'00ff');",
+            )
+            .unwrap();
+        let (script, _) = dump(&db, DumpOptions::new());
+        assert!(
+            script.contains("INSERT INTO \"odd table\" VALUES(1e999,X'00ff');"),
+            "{}",
+            script
+        );
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&script).unwrap();
+        let x: f64 = conn
+            .query_row("SELECT x FROM \"odd table\"", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(x, f64::INFINITY);
+    }
+}
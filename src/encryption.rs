@@ -0,0 +1,248 @@
+use rusqlite::Connection;
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::FlexibleDatabase;
+use crate::options::DatabaseOptions;
+
+// Hash used by SQLCipher's key derivation and page HMACs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherHash {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+// How the key given to `open_encrypted_with` becomes the encryption key.
+// Anything left unset keeps SQLCipher's default: PBKDF2-HMAC-SHA512 with
+// 256,000 iterations and 4 KiB pages. A database must be opened with the
+// same settings it was created with.
+#[derive(Debug, Clone, Default)]
+pub struct KeyOptions {
+    pub(crate) raw_key: bool,
+    pub(crate) kdf_iterations: Option<u32>,
+    pub(crate) kdf_algorithm: Option<CipherHash>,
+    pub(crate) hmac_algorithm: Option<CipherHash>,
+    pub(crate) page_size: Option<u32>,
+    pub(crate) compatibility: Option<u32>,
+}
+
+impl KeyOptions {
+    pub fn new() -> KeyOptions {
+        KeyOptions::default()
+    }
+
+    // Use the key, 64 hex digits, as the encryption key itself and skip key
+    // derivation, e.g. for keys kept in a key management service
+    pub fn raw_key(mut self) -> KeyOptions {
+        self.raw_key = true;
+        self
+    }
+
+    pub fn kdf_iterations(mut self, iterations: u32) -> KeyOptions {
+        self.kdf_iterations = Some(iterations);
+        self
+    }
+
+    pub fn kdf_algorithm(mut self, hash: CipherHash) -> KeyOptions {
+        self.kdf_algorithm = Some(hash);
+        self
+    }
+
+    pub fn hmac_algorithm(mut self, hash: CipherHash) -> KeyOptions {
+        self.hmac_algorithm = Some(hash);
+        self
+    }
+
+    pub fn page_size(mut self, bytes: u32) -> KeyOptions {
+        self.page_size = Some(bytes);
+        self
+    }
+
+    // Use all the defaults of an older SQLCipher major version (1 to 4), to
+    // open databases it created
+    pub fn compatibility(mut self, major_version: u32) -> KeyOptions {
+        self.compatibility = Some(major_version);
+        self
+    }
+
+    // The key, then the cipher settings, which must come before the
+    // database is first read
+    fn apply(&self, conn: &Connection, key: &str) -> Result<()> {
+        if self.raw_key {
+            if key.len() != 64 || !key.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(KooError::InvalidData("a raw key must be 64 hex digits".to_string()));
+            }
+            conn.execute_batch(&format!("PRAGMA key = \"x'{}'\"", key))?;
+        } else {
+            conn.pragma_update(None, "key", key)?;
+        }
+
+        if let Some(version) = self.compatibility {
+            conn.pragma_update(None, "cipher_compatibility", version)?;
+        }
+        if let Some(iterations) = self.kdf_iterations {
+            conn.pragma_update(None, "kdf_iter", iterations)?;
+        }
+        if let Some(hash) = self.kdf_algorithm {
+            conn.execute_batch(&format!(
+                "PRAGMA cipher_kdf_algorithm = PBKDF2_HMAC_{}",
+                hash_name(hash)
+            ))?;
+        }
+        if let Some(hash) = self.hmac_algorithm {
+            conn.execute_batch(&format!("PRAGMA cipher_hmac_algorithm = HMAC_{}", hash_name(hash)))?;
+        }
+        if let Some(bytes) = self.page_size {
+            conn.pragma_update(None, "cipher_page_size", bytes)?;
+        }
+        Ok(())
+    }
+}
+
+fn hash_name(hash: CipherHash) -> &'static str {
+    match hash {
+        CipherHash::Sha1 => "SHA1",
+        CipherHash::Sha256 => "SHA256",
+        CipherHash::Sha512 => "SHA512",
+    }
+}
+
+// Encryption at rest with SQLCipher, behind the `sqlcipher` feature. The
+// whole file is encrypted, including the schema catalog, and so are
+// journals and WAL files.
+impl FlexibleDatabase {
+    // Open or create the database at `db_path`, encrypted with a key
+    // derived from `key`
+    pub fn open_encrypted(db_path: &str, key: &str) -> Result<FlexibleDatabase> {
+        FlexibleDatabase::open_encrypted_with(db_path, key, KeyOptions::default(), DatabaseOptions::default())
+    }
+
+    pub fn open_encrypted_with(
+        db_path: &str,
+        key: &str,
+        key_options: KeyOptions,
+        options: DatabaseOptions,
+    ) -> Result<FlexibleDatabase> {
+        let conn = Connection::open(db_path)?;
+        key_options.apply(&conn, key)?;
+
+        // SQLCipher only checks the key once the file is read
+        if let Err(err) = conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(())) {
+            return Err(match err.sqlite_error_code() {
+                Some(rusqlite::ErrorCode::NotADatabase) => KooError::InvalidData(format!(
+                    "'{}' can't be decrypted: the key or key options are wrong, or it isn't encrypted",
+                    db_path
+                )),
+                _ => err.into(),
+            });
+        }
+        FlexibleDatabase::from_connection(conn, options)
+    }
+
+    // Re-encrypt the database with a key derived from `new_key`, keeping
+    // the current key options. Handles opened with the old key stop working.
+    pub fn rekey(&self, new_key: &str) -> Result<()> {
+        self.conn.pragma_update(None, "rekey", new_key)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::Value;
+    use std::collections::HashMap;
+
+    use crate::flexible_database::{FieldType, Schema};
+    use crate::temp_file::TempFile;
+
+    // Define the notes schema and write one secret note
+    fn write_note(db: &mut FlexibleDatabase) {
+        db.define_schema(Schema::new("notes", [("body".to_string(), FieldType::Text)]))
+            .unwrap();
+        let data = HashMap::from([("body".to_string(), Value::Text("the secret plan".to_string()))]);
+        db.create_model("notes", data).unwrap();
+    }
+
+    fn read_note(db: &mut FlexibleDatabase) -> Value {
+        db.load_schemas().unwrap();
+        db.get_model("notes", 1).unwrap().unwrap().data["body"].clone()
+    }
+
+    #[test]
+    fn encrypted_files_open_only_with_their_key() {
+        let file = TempFile::new("db");
+        write_note(&mut FlexibleDatabase::open_encrypted(file.path(), "hunter2").unwrap());
+
+        let bytes = std::fs::read(file.path()).unwrap();
+        assert!(!bytes.windows(15).any(|window| window == b"the secret plan"));
+        assert!(!bytes.starts_with(b"SQLite format 3"));
+
+        let mut db = FlexibleDatabase::open_encrypted(file.path(), "hunter2").unwrap();
+        assert_eq!(read_note(&mut db), Value::Text("the secret plan".to_string()));
+        assert!(matches!(
+            FlexibleDatabase::open_encrypted(file.path(), "wrong"),
+            Err(KooError::InvalidData(_))
+        ));
+        assert!(FlexibleDatabase::new(file.path()).unwrap().stored_schemas().is_err());
+    }
+
+    #[test]
+    fn rekeyed_files_need_the_new_key() {
+        let file = TempFile::new("db");
+        let mut db = FlexibleDatabase::open_encrypted(file.path(), "old").unwrap();
+        write_note(&mut db);
+        db.rekey("new").unwrap();
+        drop(db);
+
+        assert!(FlexibleDatabase::open_encrypted(file.path(), "old").is_err());
+        let mut db = FlexibleDatabase::open_encrypted(file.path(), "new").unwrap();
+        assert_eq!(read_note(&mut db), Value::Text("the secret plan".to_string()));
+    }
+
+    #[test]
+    fn key_options_must_match_on_reopening() {
+        let file = TempFile::new("db");
+        let key_options = || {
+            KeyOptions::new()
+                .kdf_iterations(1000)
+                .kdf_algorithm(CipherHash::Sha256)
+                .hmac_algorithm(CipherHash::Sha256)
+                .page_size(8192)
+        };
+        let open = |key_options| {
+            FlexibleDatabase::open_encrypted_with(file.path(), "hunter2", key_options, DatabaseOptions::default())
+        };
+        write_note(&mut open(key_options()).unwrap());
+
+        assert!(open(KeyOptions::new()).is_err());
+        assert_eq!(
+            read_note(&mut open(key_options()).unwrap()),
+            Value::Text("the secret plan".to_string())
+        );
+    }
+
+    #[test]
+    fn raw_keys_are_64_hex_digits() {
+        let file = TempFile::new("db");
+        let open = |key: &str| {
+            FlexibleDatabase::open_encrypted_with(
+                file.path(),
+                key,
+                KeyOptions::new().raw_key(),
+                DatabaseOptions::default(),
+            )
+        };
+        assert!(matches!(open("abc"), Err(KooError::InvalidData(_))));
+        assert!(matches!(open(&"g".repeat(64)), Err(KooError::InvalidData(_))));
+
+        let key = "0123456789abcdef".repeat(4);
+        write_note(&mut open(&key).unwrap());
+        assert_eq!(
+            read_note(&mut open(&key).unwrap()),
+            Value::Text("the secret plan".to_string())
+        );
+        // The raw key isn't a passphrase for the derived one
+        assert!(FlexibleDatabase::open_encrypted(file.path(), &key).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use std::fmt;
+
+use crate::flexible_database::ModelId;
+use crate::validation::FieldViolation;
+
+// Errors returned by kooDB operations
+#[derive(Debug)]
+pub enum KooError {
+    // Error reported by SQLite
+    Sqlite(rusqlite::Error),
+    // Error reading or writing an external file or stream
+    Io(std::io::Error),
+    // Error encoding or decoding JSON
+    Json(serde_json::Error),
+    // Error reading or writing CSV
+    Csv(csv::Error),
+    // Error building or reading Arrow data
+    #[cfg(feature = "arrow")]
+    Arrow(arrow_schema::ArrowError),
+    // Error reading or writing a Parquet file
+    #[cfg(feature = "parquet")]
+    Parquet(parquet::errors::ParquetError),
+    // Error reported by a PostgreSQL server being imported from
+    #[cfg(feature = "postgres")]
+    Postgres(postgres::Error),
+    // Error reported by a MySQL server being imported from
+    #[cfg(feature = "mysql")]
+    Mysql(mysql::Error),
+    // No schema has been defined with this name
+    SchemaNotFound(String),
+    // The field is not part of the schema
+    UnknownField { schema: String, field: String },
+    // A required field without a default was left out of an insert
+    MissingField { schema: String, field: String },
+    // Field values broke the schema's constraints
+    Validation { schema: String, violations: Vec<FieldViolation> },
+    // The schema definition can't be used for the requested operation
+    InvalidSchema(String),
+    // A recorded migration version has no matching `Migration`
+    MigrationNotFound(i64),
+    // Imported data doesn't match the expected layout or field types
+    InvalidData(String),
+    // A model field read as a Rust type holds a value that doesn't convert
+    TypeMismatch { field: String, expected: &'static str, found: &'static str },
+    // Encoded data was written by a newer, unknown wire format version
+    UnsupportedVersion(u64),
+    // The model was updated by someone else after it was read
+    StaleVersion { schema: String, id: ModelId, expected: i64 },
+    // A reference field points at a model that doesn't exist
+    ForeignKeyViolation { schema: String, id: i64, references: String },
+    // An encrypted field couldn't be encrypted or decrypted
+    Encryption(String),
+    // The access policy refused the caller a write; `id` is None for creates
+    AccessDenied { schema: String, id: Option<ModelId> },
+    // Two databases couldn't be synced
+    Sync(String),
+    // Another connection kept the database locked through every attempt
+    // the retry policy allows
+    Busy { attempts: u32 },
+}
+
+pub type Result<T> = std::result::Result<T, KooError>;
+
+impl fmt::Display for KooError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KooError::Sqlite(err) => write!(f, "sqlite error: {}", err),
+            KooError::Io(err) => write!(f, "io error: {}", err),
+            KooError::Json(err) => write!(f, "json error: {}", err),
+            KooError::Csv(err) => write!(f, "csv error: {}", err),
+            #[cfg(feature = "arrow")]
+            KooError::Arrow(err) => write!(f, "arrow error: {}", err),
+            #[cfg(feature = "parquet")]
+            KooError::Parquet(err) => write!(f, "parquet error: {}", err),
+            #[cfg(feature = "postgres")]
+            KooError::Postgres(err) => write!(f, "postgres error: {}", err),
+            #[cfg(feature = "mysql")]
+            KooError::Mysql(err) => write!(f, "mysql error: {}", err),
+            KooError::SchemaNotFound(name) => write!(f, "schema '{}' is not defined", name),
+            KooError::UnknownField { schema, field } => {
+                write!(f, "schema '{}' has no field '{}'", schema, field)
+            }
+            KooError::MissingField { schema, field } => {
+                write!(f, "field '{}.{}' is required", schema, field)
+            }
+            KooError::Validation { schema, violations } => {
+                let violations: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+                write!(f, "invalid '{}' model: {}", schema, violations.join("; "))
+            }
+            KooError::InvalidSchema(message) => write!(f, "invalid schema: {}", message),
+            KooError::MigrationNotFound(version) => {
+                write!(f, "no migration provided for applied version {}", version)
+            }
+            KooError::InvalidData(message) => write!(f, "invalid data: {}", message),
+            KooError::TypeMismatch { field, expected, found } => {
+                write!(f, "field '{}' holds {}, not {}", field, found, expected)
+            }
+            KooError::UnsupportedVersion(version) => {
+                write!(f, "unsupported wire format version {}", version)
+            }
+            KooError::StaleVersion { schema, id, expected } => write!(
+                f,
+                "{} {} is no longer at version {}",
+                schema, id, expected
+            ),
+            KooError::ForeignKeyViolation { schema, id, references } => write!(
+                f,
+                "{} {} references a missing row in '{}'",
+                schema, id, references
+            ),
+            KooError::Encryption(message) => write!(f, "encryption error: {}", message),
+            KooError::AccessDenied { schema, id: Some(id) } => write!(f, "access to {} {} denied", schema, id),
+            KooError::AccessDenied { schema, id: None } => {
+                write!(f, "creating '{}' models is not allowed", schema)
+            }
+            KooError::Sync(message) => write!(f, "sync error: {}", message),
+            KooError::Busy { attempts } => write!(f, "database still busy after {} attempts", attempts),
+        }
+    }
+}
+
+impl std::error::Error for KooError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KooError::Sqlite(err) => Some(err),
+            KooError::Io(err) => Some(err),
+            KooError::Json(err) => Some(err),
+            KooError::Csv(err) => Some(err),
+            #[cfg(feature = "arrow")]
+            KooError::Arrow(err) => Some(err),
+            #[cfg(feature = "parquet")]
+            KooError::Parquet(err) => Some(err),
+            #[cfg(feature = "postgres")]
+            KooError::Postgres(err) => Some(err),
+            #[cfg(feature = "mysql")]
+            KooError::Mysql(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for KooError {
+    fn from(err: rusqlite::Error) -> Self {
+        KooError::Sqlite(err)
+    }
+}
+
+impl From<std::io::Error> for KooError {
+    fn from(err: std::io::Error) -> Self {
+        KooError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for KooError {
+    fn from(err: serde_json::Error) -> Self {
+        KooError::Json(err)
+    }
+}
+
+impl From<csv::Error> for KooError {
+    fn from(err: csv::Error) -> Self {
+        KooError::Csv(err)
+    }
+}
+
+#[cfg(feature = "arrow")]
+impl From<arrow_schema::ArrowError> for KooError {
+    fn from(err: arrow_schema::ArrowError) -> Self {
+        KooError::Arrow(err)
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl From<parquet::errors::ParquetError> for KooError {
+    fn from(err: parquet::errors::ParquetError) -> Self {
+        KooError::Parquet(err)
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl From<postgres::Error> for KooError {
+    fn from(err: postgres::Error) -> Self {
+        KooError::Postgres(err)
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl From<mysql::Error> for KooError {
+    fn from(err: mysql::Error) -> Self {
+        KooError::Mysql(err)
+    }
+}
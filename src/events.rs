@@ -0,0 +1,342 @@
+use rusqlite::OptionalExtension;
+use std::sync::Arc;
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FlexibleDatabase, ModelId};
+
+const EVENTS_TABLE: &str = "_koo_events";
+
+// Events handed to a projection at a time while it catches up
+const CATCH_UP_BATCH: usize = 500;
+
+// Something that happened to an aggregate, such as an order or an
+// account, as recorded by `append_event`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    // Position in the whole log, counting up from 1
+    pub seq: i64,
+    pub aggregate_id: String,
+    // Position among the events of the aggregate, counting up from 1
+    pub version: i64,
+    // What happened, e.g. "OrderPlaced"
+    pub kind: String,
+    pub payload: serde_json::Value,
+    // When the event was appended, in UTC
+    pub recorded_at: String,
+}
+
+// Applies an event to the schemas a projection keeps, e.g. by creating or
+// updating a model of an "order_summaries" schema
+pub type Projection = dyn Fn(&FlexibleDatabase, &Event) -> Result<()> + Send + Sync;
+
+// Event sourcing: the events are the record of what happened, kept in
+// `_koo_events`, and regular schemas are projections of them, rebuilt
+// from the log whenever needed. Each projection has a checkpoint in
+// `_koo_projections`, the last event it has applied, so it picks up where
+// it left off after a restart. Appending an event applies it to every
+// registered projection in the same transaction, so either the event and
+// all the projected changes are stored or none of them.
+impl FlexibleDatabase {
+    // Record an event of `aggregate_id`, returning it as stored. With
+    // `expected_version`, the number of events the aggregate should have
+    // so far, the append fails with `StaleVersion` if someone else got
+    // there first. A projection that fails undoes the append.
+    pub fn append_event(
+        &mut self,
+        aggregate_id: &str,
+        kind: &str,
+        payload: serde_json::Value,
+        expected_version: Option<i64>,
+    ) -> Result<Event> {
+        self.create_event_tables()?;
+        self.in_transaction(|db| {
+            let version: i64 = db.conn.query_row(
+                "SELECT COALESCE(MAX(version), 0) FROM _koo_events WHERE aggregate_id = ?",
+                [aggregate_id],
+                |row| row.get(0),
+            )?;
+            if let Some(expected) = expected_version
+                && expected != version
+            {
+                return Err(KooError::StaleVersion {
+                    schema: EVENTS_TABLE.to_string(),
+                    id: ModelId::Text(aggregate_id.to_string()),
+                    expected,
+                });
+            }
+
+            let sql = "INSERT INTO _koo_events (aggregate_id, version, kind, payload) VALUES (?, ?, ?, ?)
+                 RETURNING seq, aggregate_id, version, kind, payload, recorded_at";
+            let event = db.traced(sql, 4, || {
+                let event = db.prepare_cached(sql)?.query_row(
+                    rusqlite::params![aggregate_id, version + 1, kind, payload.to_string()],
+                    read_event,
+                )?;
+                Ok((event, 1))
+            })?;
+
+            for (name, projection) in db.projections.clone() {
+                db.catch_up(&name, &projection)?;
+            }
+            Ok(event)
+        })
+    }
+
+    // The events of `aggregate_id`, oldest first, to replay its state from
+    pub fn events(&self, aggregate_id: &str) -> Result<Vec<Event>> {
+        self.create_event_tables()?;
+        let sql = "SELECT seq, aggregate_id, version, kind, payload, recorded_at FROM _koo_events
+             WHERE aggregate_id = ? ORDER BY version";
+        self.query_events(sql, rusqlite::params![aggregate_id])
+    }
+
+    // Up to `limit` events of every aggregate appended after `seq`, in the
+    // order they were appended
+    pub fn events_since(&self, seq: i64, limit: usize) -> Result<Vec<Event>> {
+        self.create_event_tables()?;
+        let sql = "SELECT seq, aggregate_id, version, kind, payload, recorded_at FROM _koo_events
+             WHERE seq > ? ORDER BY seq LIMIT ?";
+        self.query_events(sql, rusqlite::params![seq, limit as i64])
+    }
+
+    // Keep the schemas `projection` writes up to date with the log under
+    // `name`, replacing a projection already registered under it. Events
+    // appended since its checkpoint, or all of them the first time, are
+    // applied first; returns how many.
+    pub fn register_projection(
+        &mut self,
+        name: &str,
+        projection: impl Fn(&FlexibleDatabase, &Event) -> Result<()> + Send + Sync + 'static,
+    ) -> Result<usize> {
+        self.create_event_tables()?;
+        let projection: Arc<Projection> = Arc::new(projection);
+        let applied = self.in_transaction(|db| db.catch_up(name, &projection))?;
+        self.projections.insert(name.to_string(), projection);
+        Ok(applied)
+    }
+
+    // Stop applying events to the projection, keeping its checkpoint for
+    // when it is registered again
+    pub fn unregister_projection(&mut self, name: &str) -> bool {
+        self.projections.shift_remove(name).is_some()
+    }
+
+    // Apply every event to the projection again from the start, returning
+    // how many there were. Clear the schemas it writes first, in `reset`,
+    // which runs in the same transaction.
+    pub fn rebuild_projection(
+        &mut self,
+        name: &str,
+        reset: impl FnOnce(&mut FlexibleDatabase) -> Result<()>,
+    ) -> Result<usize> {
+        let projection = self
+            .projections
+            .get(name)
+            .cloned()
+            .ok_or_else(|| KooError::InvalidData(format!("projection '{}' is not registered", name)))?;
+        self.create_event_tables()?;
+        self.in_transaction(|db| {
+            reset(db)?;
+            db.conn.execute("DELETE FROM _koo_projections WHERE name = ?", [name])?;
+            db.catch_up(name, &projection)
+        })
+    }
+
+    // Seq of the last event applied to the projection, if it has applied any
+    pub fn projection_checkpoint(&self, name: &str) -> Result<Option<i64>> {
+        self.create_event_tables()?;
+        Ok(self
+            .conn
+            .query_row("SELECT seq FROM _koo_projections WHERE name = ?", [name], |row| {
+                row.get(0)
+            })
+            .optional()?)
+    }
+
+    fn create_event_tables(&self) -> Result<()> {
+        self.conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS _koo_events (
+                seq INTEGER PRIMARY KEY,
+                aggregate_id TEXT NOT NULL,
+                version INTEGER NOT NULL,
+                kind TEXT NOT NULL,
+                payload TEXT NOT NULL,
+                recorded_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+                UNIQUE (aggregate_id, version)
+            );
+            CREATE TABLE IF NOT EXISTS _koo_projections (
+                name TEXT PRIMARY KEY,
+                seq INTEGER NOT NULL
+            ) WITHOUT ROWID;",
+        )?;
+        Ok(())
+    }
+
+    // Apply the events after the projection's checkpoint and move the
+    // checkpoint past them
+    fn catch_up(&self, name: &str, projection: &Arc<Projection>) -> Result<usize> {
+        let mut checkpoint = self.projection_checkpoint(name)?.unwrap_or(0);
+        let mut applied = 0;
+        loop {
+            let events = self.events_since(checkpoint, CATCH_UP_BATCH)?;
+            let Some(last) = events.last() else {
+                break;
+            };
+            checkpoint = last.seq;
+            for event in &events {
+                projection(self, event)?;
+            }
+            applied += events.len();
+        }
+        if applied > 0 {
+            self.conn.execute(
+                "INSERT INTO _koo_projections (name, seq) VALUES (?, ?)
+                 ON CONFLICT (name) DO UPDATE SET seq = excluded.seq",
+                rusqlite::params![name, checkpoint],
+            )?;
+        }
+        Ok(applied)
+    }
+
+    fn query_events(&self, sql: &str, params: impl rusqlite::Params) -> Result<Vec<Event>> {
+        self.traced(sql, 1, || {
+            let events = self
+                .prepare_cached(sql)?
+                .query_map(params, read_event)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let count = events.len();
+            Ok((events, count))
+        })
+    }
+}
+
+fn read_event(row: &rusqlite::Row) -> rusqlite::Result<Event> {
+    let payload: String = row.get(4)?;
+    let payload = serde_json::from_str(&payload)
+        .map_err(|err| rusqlite::Error::FromSqlConversionFailure(4, rusqlite::types::Type::Text, Box::new(err)))?;
+    Ok(Event {
+        seq: row.get(0)?,
+        aggregate_id: row.get(1)?,
+        version: row.get(2)?,
+        kind: row.get(3)?,
+        payload,
+        recorded_at: row.get(5)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn deposit(db: &mut FlexibleDatabase, account: &str, amount: i64) -> Event {
+        db.append_event(account, "Deposited", json!({ "amount": amount }), None)
+            .unwrap()
+    }
+
+    // Keeps the total deposited in the key-value store
+    fn total(db: &FlexibleDatabase, event: &Event) -> Result<()> {
+        let kv = db.kv()?;
+        let total = kv.get_i64("total")?.unwrap_or(0);
+        kv.set_i64("total", total + event.payload["amount"].as_i64().unwrap_or(0))
+    }
+
+    fn projected(db: &FlexibleDatabase) -> Option<i64> {
+        db.kv().unwrap().get_i64("total").unwrap()
+    }
+
+    #[test]
+    fn events_replay_per_aggregate() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        assert!(db.events("a").unwrap().is_empty());
+        let first = deposit(&mut db, "a", 5);
+        deposit(&mut db, "b", 7);
+        let third = deposit(&mut db, "a", 1);
+        assert_eq!((first.seq, first.version), (1, 1));
+        assert_eq!((third.seq, third.version), (3, 2));
+        assert_eq!(third.payload, json!({ "amount": 1 }));
+        assert!(!third.recorded_at.is_empty());
+
+        assert_eq!(db.events("a").unwrap(), [first, third]);
+        let since: Vec<i64> = db.events_since(1, 10).unwrap().iter().map(|e| e.seq).collect();
+        assert_eq!(since, [2, 3]);
+        assert_eq!(db.events_since(0, 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn expected_versions_catch_concurrent_appends() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.append_event("a", "Opened", json!({}), Some(0)).unwrap();
+        db.append_event("a", "Deposited", json!({}), Some(1)).unwrap();
+        assert!(matches!(
+            db.append_event("a", "Deposited", json!({}), Some(1)),
+            Err(KooError::StaleVersion { expected: 1, .. })
+        ));
+        assert_eq!(db.events("a").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn projections_catch_up_and_follow_the_log() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        deposit(&mut db, "a", 5);
+        deposit(&mut db, "b", 7);
+        assert_eq!(db.projection_checkpoint("totals").unwrap(), None);
+        assert_eq!(db.register_projection("totals", total).unwrap(), 2);
+        assert_eq!(projected(&db), Some(12));
+
+        let event = deposit(&mut db, "a", 3);
+        assert_eq!(projected(&db), Some(15));
+        assert_eq!(db.projection_checkpoint("totals").unwrap(), Some(event.seq));
+
+        // Picks up where it left off, without applying anything twice
+        assert!(db.unregister_projection("totals"));
+        assert!(!db.unregister_projection("totals"));
+        deposit(&mut db, "b", 10);
+        assert_eq!(projected(&db), Some(15));
+        assert_eq!(db.register_projection("totals", total).unwrap(), 1);
+        assert_eq!(projected(&db), Some(25));
+    }
+
+    #[test]
+    fn a_failing_projection_undoes_the_append() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.register_projection("totals", total).unwrap();
+        db.register_projection("picky", |_, event| match event.payload["amount"].as_i64() {
+            Some(amount) if amount < 0 => Err(KooError::InvalidData("negative deposit".to_string())),
+            _ => Ok(()),
+        })
+        .unwrap();
+
+        deposit(&mut db, "a", 5);
+        assert!(
+            db.append_event("a", "Deposited", json!({ "amount": -1 }), None)
+                .is_err()
+        );
+        assert_eq!(db.events("a").unwrap().len(), 1);
+        assert_eq!(projected(&db), Some(5));
+        assert_eq!(db.projection_checkpoint("totals").unwrap(), Some(1));
+    }
+
+    #[test]
+    fn rebuilt_projections_apply_every_event_again() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.register_projection("totals", total).unwrap();
+        for amount in [1, 2, 3] {
+            deposit(&mut db, "a", amount);
+        }
+        db.kv().unwrap().set_i64("total", 1000).unwrap();
+
+        let applied = db
+            .rebuild_projection("totals", |db| {
+                db.kv()?.delete("total")?;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(applied, 3);
+        assert_eq!(projected(&db), Some(6));
+        assert!(matches!(
+            db.rebuild_projection("missing", |_| Ok(())),
+            Err(KooError::InvalidData(_))
+        ));
+    }
+}
@@ -0,0 +1,344 @@
+use rusqlite::types::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::audit::AuditAction;
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FieldType, FlexibleDatabase, Schema};
+
+// When the models of a schema expire, from `Schema::with_expiry` or
+// `with_soft_expiry`. `field` holds the time each model expires at: text in
+// any format SQLite's date functions read, or an integer count of seconds
+// since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Expiry {
+    pub field: String,
+    // Boolean field set on expired models instead of deleting them
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub flag: Option<String>,
+}
+
+impl Expiry {
+    // Check the fields against the schema they belong to
+    pub(crate) fn check(&self, schema: &Schema) -> Result<()> {
+        match schema.fields.get(&self.field) {
+            Some(FieldType::Text | FieldType::Integer) => {}
+            Some(field_type) => {
+                return Err(KooError::InvalidSchema(format!(
+                    "'{}.{}' is {}, not a time models expire at",
+                    schema.name,
+                    self.field,
+                    field_type.name()
+                )));
+            }
+            None => {
+                return Err(KooError::UnknownField {
+                    schema: schema.name.clone(),
+                    field: self.field.clone(),
+                });
+            }
+        }
+        if let Some(flag) = &self.flag {
+            match schema.fields.get(flag) {
+                Some(FieldType::Boolean) if !schema.is_computed(flag) => {}
+                Some(_) => {
+                    return Err(KooError::InvalidSchema(format!(
+                        "'{}.{}' must be a stored boolean to flag expired models",
+                        schema.name, flag
+                    )));
+                }
+                None => {
+                    return Err(KooError::UnknownField {
+                        schema: schema.name.clone(),
+                        field: flag.clone(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Whether renaming or dropping the field would lose track of expiry
+    pub(crate) fn uses(&self, field_name: &str) -> bool {
+        self.field == field_name || self.flag.as_deref() == Some(field_name)
+    }
+}
+
+// Background expiry started by `spawn_expiry`, stopped when dropped
+pub struct ExpiryTask {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ExpiryTask {
+    // Stop expiring, waiting for a run in progress to finish
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for ExpiryTask {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// Run `expire_now` on a shared handle every `interval` from a background
+// thread, e.g. the one a server works on, until the returned task is
+// stopped. Failed runs are tried again on the next interval.
+pub fn spawn_expiry(db: Arc<Mutex<FlexibleDatabase>>, interval: Duration) -> ExpiryTask {
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let handle = thread::spawn(move || {
+        while !stopped.load(Ordering::Relaxed) {
+            thread::park_timeout(interval);
+            if stopped.load(Ordering::Relaxed) {
+                break;
+            }
+            let db = db.lock().unwrap_or_else(|e| e.into_inner());
+            let _ = db.expire_now();
+        }
+    });
+    ExpiryTask {
+        stop,
+        handle: Some(handle),
+    }
+}
+
+// Schemas with an expiry hold models, such as sessions or cached results,
+// that stop being wanted at a set time. Expired models are still read like
+// any other until `expire_now` deletes or flags them, so call it on a
+// timer, or keep `spawn_expiry` running. Like other bulk operations it
+// works in SQL, without the access policy, though with auditing enabled
+// each expired model is recorded.
+impl FlexibleDatabase {
+    // Delete, or flag, the expired models of every schema with an expiry.
+    // Returns how many models expired.
+    pub fn expire_now(&self) -> Result<usize> {
+        let mut schemas: Vec<&Schema> = self.schemas.values().filter(|s| s.expiry.is_some()).collect();
+        schemas.sort_by(|a, b| a.name.cmp(&b.name));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+
+        let mut expired = 0;
+        for schema in schemas {
+            let Some(expiry) = &schema.expiry else { continue };
+            let condition = match schema.fields.get(&expiry.field) {
+                Some(FieldType::Integer) => format!("{} <= ?", expiry.field),
+                _ => format!("julianday({}) <= julianday(?, 'unixepoch')", expiry.field),
+            };
+            let (sql, condition, action) = match &expiry.flag {
+                Some(flag) => {
+                    let condition = format!("{} AND {flag} = 0", condition);
+                    let sql = format!("UPDATE {} SET {flag} = 1 WHERE {}", schema.name, condition);
+                    (sql, condition, AuditAction::Update)
+                }
+                None => {
+                    let sql = format!("DELETE FROM {} WHERE {}", schema.name, condition);
+                    (sql, condition, AuditAction::Delete)
+                }
+            };
+            let params = [Value::Integer(now)];
+            expired += self.traced(&sql, 1, || {
+                let changed = self.retrying(|| {
+                    self.audit_rows(schema, &condition, &params, action, || {
+                        Ok(self.prepare_cached(&sql)?.execute([now])?)
+                    })
+                })?;
+                Ok((changed, changed))
+            })?;
+        }
+        Ok(expired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::flexible_database::FieldDef;
+    use crate::query::{Op, Query};
+
+    fn now() -> i64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+    }
+
+    fn sessions(expires: FieldType, soft: bool) -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("sessions", [])
+            .field("user", FieldDef::new(FieldType::Text))
+            .field("expires", FieldDef::new(expires))
+            .field("expired", FieldDef::new(FieldType::Boolean).with_default(false));
+        let schema = match soft {
+            true => schema.with_soft_expiry("expires", "expired"),
+            false => schema.with_expiry("expires"),
+        };
+        db.define_schema(schema).unwrap();
+        db
+    }
+
+    fn session(db: &FlexibleDatabase, user: &str, expires: Value) {
+        let data = HashMap::from([
+            ("user".to_string(), Value::Text(user.to_string())),
+            ("expires".to_string(), expires),
+        ]);
+        db.create_model("sessions", data).unwrap();
+    }
+
+    fn users(db: &FlexibleDatabase, query: Query) -> Vec<String> {
+        let mut users: Vec<String> = db
+            .find(&query)
+            .unwrap()
+            .into_iter()
+            .map(|model| match &model.data["user"] {
+                Value::Text(user) => user.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+        users.sort();
+        users
+    }
+
+    #[test]
+    fn expired_models_are_deleted() {
+        let db = sessions(FieldType::Integer, false);
+        session(&db, "ann", Value::Integer(now() - 60));
+        session(&db, "bob", Value::Integer(now() + 3600));
+        session(&db, "cat", Value::Integer(0));
+
+        // Still read until they're expired
+        assert_eq!(db.count("sessions").unwrap(), 3);
+        assert_eq!(db.expire_now().unwrap(), 2);
+        assert_eq!(users(&db, Query::new("sessions")), ["bob"]);
+        assert_eq!(db.expire_now().unwrap(), 0);
+    }
+
+    #[test]
+    fn text_times_expire_as_dates() {
+        let db = sessions(FieldType::Text, false);
+        let text = |s: &str| Value::Text(s.to_string());
+        session(&db, "ann", text("2001-02-03 04:05:06"));
+        session(&db, "bob", text("2999-01-01"));
+        session(&db, "cat", text("2001-02-03T04:05:06Z"));
+        assert_eq!(db.expire_now().unwrap(), 2);
+        assert_eq!(users(&db, Query::new("sessions")), ["bob"]);
+    }
+
+    #[test]
+    fn soft_expiry_flags_models_once() {
+        let db = sessions(FieldType::Integer, true);
+        session(&db, "ann", Value::Integer(now() - 60));
+        session(&db, "bob", Value::Integer(now() + 3600));
+
+        assert_eq!(db.expire_now().unwrap(), 1);
+        assert_eq!(db.expire_now().unwrap(), 0);
+        let flagged = Query::new("sessions").filter("expired", Op::Eq, true);
+        assert_eq!(users(&db, flagged), ["ann"]);
+        assert_eq!(db.count("sessions").unwrap(), 2);
+    }
+
+    #[test]
+    fn expiry_fields_are_checked() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = || {
+            Schema::new("sessions", [])
+                .field("expires", FieldDef::new(FieldType::Real))
+                .field("at", FieldDef::new(FieldType::Integer))
+                .field("gone", FieldDef::new(FieldType::Boolean))
+        };
+        let define = |db: &mut FlexibleDatabase, schema: Schema| db.define_schema(schema);
+
+        assert!(matches!(
+            define(&mut db, schema().with_expiry("expires")),
+            Err(KooError::InvalidSchema(_))
+        ));
+        assert!(matches!(
+            define(&mut db, schema().with_expiry("ends")),
+            Err(KooError::UnknownField { .. })
+        ));
+        assert!(matches!(
+            define(&mut db, schema().with_soft_expiry("at", "expires")),
+            Err(KooError::InvalidSchema(_))
+        ));
+        assert!(matches!(
+            define(&mut db, schema().with_soft_expiry("at", "flag")),
+            Err(KooError::UnknownField { .. })
+        ));
+        define(&mut db, schema().with_soft_expiry("at", "gone")).unwrap();
+    }
+
+    #[test]
+    fn renames_follow_the_expiry_fields() {
+        let mut db = sessions(FieldType::Integer, true);
+        db.rename_field("sessions", "expires", "ends").unwrap();
+        db.rename_field("sessions", "expired", "ended").unwrap();
+        let expiry = db.schemas["sessions"].expiry.clone().unwrap();
+        assert_eq!((expiry.field.as_str(), expiry.flag.as_deref()), ("ends", Some("ended")));
+
+        assert!(matches!(
+            db.drop_field("sessions", "ends"),
+            Err(KooError::InvalidSchema(_))
+        ));
+        assert!(matches!(
+            db.drop_field("sessions", "ended"),
+            Err(KooError::InvalidSchema(_))
+        ));
+    }
+
+    #[test]
+    fn the_background_task_expires_models() {
+        let db = sessions(FieldType::Integer, false);
+        session(&db, "ann", Value::Integer(0));
+        let db = Arc::new(Mutex::new(db));
+
+        let task = spawn_expiry(db.clone(), Duration::from_millis(5));
+        let started = std::time::Instant::now();
+        while db.lock().unwrap().count("sessions").unwrap() > 0 {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(5));
+        }
+        task.stop();
+
+        // Nothing runs once stopped
+        session(&db.lock().unwrap(), "bob", Value::Integer(0));
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(db.lock().unwrap().count("sessions").unwrap(), 1);
+    }
+
+    #[test]
+    fn expiries_are_audited() {
+        for soft in [false, true] {
+            let mut db = sessions(FieldType::Integer, soft);
+            db.enable_audit().unwrap();
+            session(&db, "ann", Value::Integer(now() - 60));
+            session(&db, "bob", Value::Integer(now() + 3600));
+            assert_eq!(db.expire_now().unwrap(), 1);
+
+            let history = db.audit_history("sessions", 1).unwrap();
+            let expected = match soft {
+                true => AuditAction::Update,
+                false => AuditAction::Delete,
+            };
+            assert_eq!(history.len(), 2);
+            assert_eq!(history[1].action, expected);
+            if soft {
+                assert_eq!(history[1].new_values.as_ref().unwrap()["expired"], true);
+            }
+            assert_eq!(db.audit_history("sessions", 2).unwrap().len(), 1);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use rusqlite::types::Value;
+use std::collections::HashMap;
+
+use crate::error::Result;
+use crate::flexible_database::FlexibleDatabase;
+use crate::query::Query;
+
+// A statement as kooDB would run it, for debugging and query tuning
+#[derive(Debug, Clone, PartialEq)]
+pub struct SqlPlan {
+    pub sql: String,
+    // Bound to the statement's placeholders in order. Encrypted fields
+    // are already sealed.
+    pub params: Vec<Value>,
+    // SQLite's EXPLAIN QUERY PLAN, one step per line, nested steps
+    // indented under their parent, e.g. "SEARCH users USING INDEX
+    // users_email (email=?)"
+    pub query_plan: Vec<String>,
+}
+
+impl FlexibleDatabase {
+    // The SELECT that `find` would run for `query`, without running it
+    pub fn explain_plan(&self, query: &Query) -> Result<SqlPlan> {
+        let schema = self.schema_or_err(&query.schema)?;
+        let projected = match query.kept_fields() {
+            Some(kept) => Some(schema.projected(&kept)?),
+            None => None,
+        };
+        let columns = match &projected {
+            Some(projected) if self.access_policy.is_none() => projected,
+            _ => schema,
+        };
+        let (sql, params) = query.to_sql(schema, columns)?;
+        self.sql_plan(sql, params)
+    }
+
+    // Run `f` with writes turned into plans: `create_model`,
+    // `update_model`, `delete_model` and `increment` check their input and
+    // the access policy as usual, then record the statement they would
+    // run instead of running it. Returns the recorded statements in order.
+    // Within `f` those calls report success without knowing what the
+    // write would have done: creates of integer-keyed models return id 0,
+    // updates and deletes true, and increments None.
+    pub fn dry_run<T>(&self, f: impl FnOnce(&FlexibleDatabase) -> Result<T>) -> Result<Vec<SqlPlan>> {
+        let outer = self.dry_run.replace(Some(Vec::new()));
+        let result = f(self);
+        let plans = self.dry_run.replace(outer).unwrap_or_default();
+        result.map(|_| plans)
+    }
+
+    // During `dry_run`, record the write `sql` instead of running it,
+    // returning whether it was recorded
+    pub(crate) fn planned(&self, sql: &str, params: &[Value]) -> Result<bool> {
+        if self.dry_run.borrow().is_none() {
+            return Ok(false);
+        }
+        let plan = self.sql_plan(sql.to_string(), params.to_vec())?;
+        if let Some(plans) = self.dry_run.borrow_mut().as_mut() {
+            plans.push(plan);
+        }
+        Ok(true)
+    }
+
+    fn sql_plan(&self, sql: String, params: Vec<Value>) -> Result<SqlPlan> {
+        let query_plan = self.query_plan(&sql, Some(&params))?;
+        Ok(SqlPlan {
+            sql,
+            params,
+            query_plan,
+        })
+    }
+
+    // EXPLAIN QUERY PLAN for `sql`, which SQLite works out without running
+    // the statement. Without `params` every placeholder is taken as NULL.
+    pub(crate) fn query_plan(&self, sql: &str, params: Option<&[Value]>) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(&format!("EXPLAIN QUERY PLAN {}", sql))?;
+        let mut rows = match params {
+            Some(params) => stmt.query(rusqlite::params_from_iter(params))?,
+            None => stmt.raw_query(),
+        };
+        let mut steps = Vec::new();
+        while let Some(row) = rows.next()? {
+            steps.push((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, String>(3)?));
+        }
+
+        // Parents come before their children
+        let mut depths = HashMap::new();
+        let mut lines = Vec::with_capacity(steps.len());
+        for (id, parent, detail) in steps {
+            let depth = depths.get(&parent).map_or(0, |depth| depth + 1);
+            depths.insert(id, depth);
+            lines.push(format!("{}{}", "  ".repeat(depth), detail));
+        }
+        Ok(lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::error::KooError;
+    use crate::flexible_database::{FieldDef, FieldType, ModelId, Schema};
+    use crate::query::Op;
+    use crate::validation::Validator;
+
+    fn users() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("users", [])
+            .field("email", FieldDef::new(FieldType::Text).indexed())
+            .field("name", FieldDef::new(FieldType::Text))
+            .field(
+                "logins",
+                FieldDef::new(FieldType::Integer).validate(Validator::Min(0.0)),
+            );
+        db.define_schema(schema).unwrap();
+        db.create_model("users", user("ann@example.com")).unwrap();
+        db
+    }
+
+    fn user(email: &str) -> HashMap<String, Value> {
+        HashMap::from([
+            ("email".to_string(), Value::Text(email.to_string())),
+            ("name".to_string(), Value::Text("Ann".to_string())),
+            ("logins".to_string(), Value::Integer(0)),
+        ])
+    }
+
+    #[test]
+    fn plans_show_the_sql_and_the_indexes_used() {
+        let db = users();
+        let by_email = Query::new("users").filter("email", Op::Eq, Value::Text("ann@example.com".to_string()));
+        let plan = db.explain_plan(&by_email).unwrap();
+        assert!(
+            plan.sql.starts_with("SELECT ") && plan.sql.contains("FROM users"),
+            "{}",
+            plan.sql
+        );
+        assert_eq!(plan.params, [Value::Text("ann@example.com".to_string())]);
+        assert!(
+            plan.query_plan.iter().any(|step| step.contains("USING INDEX")),
+            "{:?}",
+            plan.query_plan
+        );
+
+        let by_name = Query::new("users").filter("name", Op::Eq, Value::Text("Ann".to_string()));
+        let plan = db.explain_plan(&by_name).unwrap();
+        assert!(
+            plan.query_plan.iter().any(|step| step.starts_with("SCAN users")),
+            "{:?}",
+            plan.query_plan
+        );
+        assert!(db.explain_plan(&Query::new("groups")).is_err());
+    }
+
+    #[test]
+    fn nested_steps_are_indented() {
+        let db = users();
+        let plan = db
+            .sql_plan(
+                "SELECT * FROM users WHERE id IN (SELECT id FROM users WHERE name = ?)".to_string(),
+                vec![Value::Text("Ann".to_string())],
+            )
+            .unwrap();
+        assert!(
+            plan.query_plan.iter().any(|step| step.starts_with("  ")),
+            "{:?}",
+            plan.query_plan
+        );
+    }
+
+    #[test]
+    fn dry_runs_record_writes_without_making_them() {
+        let db = users();
+        let plans = db
+            .dry_run(|db| {
+                assert_eq!(db.create_model("users", user("bob@example.com"))?, ModelId::Integer(0));
+                assert!(db.update_model("users", 1, user("ann@example.org"))?);
+                assert_eq!(db.increment("users", 1, "logins", 1)?, None);
+                assert!(db.delete_model("users", 1)?);
+                Ok(())
+            })
+            .unwrap();
+
+        let statements: Vec<&str> = plans.iter().map(|plan| plan.sql.split(' ').next().unwrap()).collect();
+        assert_eq!(statements, ["INSERT", "UPDATE", "UPDATE", "DELETE"]);
+        assert!(plans[0].params.contains(&Value::Text("bob@example.com".to_string())));
+        assert!(
+            plans[3]
+                .query_plan
+                .iter()
+                .any(|step| step.contains("USING INTEGER PRIMARY KEY"))
+        );
+
+        let ann = db.get_model("users", 1).unwrap().unwrap();
+        assert_eq!(ann.data["email"], Value::Text("ann@example.com".to_string()));
+        assert_eq!(db.count("users").unwrap(), 1);
+    }
+
+    #[test]
+    fn dry_runs_still_check_their_input() {
+        let db = users();
+        let mut invalid = user("bob@example.com");
+        invalid.insert("logins".to_string(), Value::Integer(-1));
+        let result = db.dry_run(|db| db.create_model("users", invalid));
+        assert!(matches!(result, Err(KooError::Validation { .. })));
+
+        // Writes are made again once the dry run is over
+        db.create_model("users", user("bob@example.com")).unwrap();
+        assert_eq!(db.count("users").unwrap(), 2);
+    }
+}
@@ -0,0 +1,284 @@
+use rusqlite::types::Value;
+use std::io::Write;
+use std::path::Path;
+
+use crate::error::Result;
+use crate::flexible_database::{FieldType, FlexibleDatabase, PrimaryKey, Schema, UuidVersion, read_model, row_key, select_sql};
+use crate::validation::Validator;
+use crate::wire::{WIRE_VERSION, model_to_json, value_to_json};
+
+impl FlexibleDatabase {
+    // Write every defined schema and its rows as a single JSON document:
+    // {"version": 1, "schemas": [{"name": ..., "fields": {...}, "rows": [...]}, ...]}
+    // Rows are streamed to the writer one at a time rather than collected.
+    pub fn export_json<W: Write>(&self, mut writer: W) -> Result<()> {
+        let mut names: Vec<&String> = self.schemas.keys().collect();
+        names.sort();
+
+        write!(writer, "{{\"version\":{},\"schemas\":[", WIRE_VERSION)?;
+        for (i, name) in names.into_iter().enumerate() {
+            if i > 0 {
+                writer.write_all(b",")?;
+            }
+            self.write_schema_json(&self.schemas[name], false, &mut writer)?;
+        }
+        writer.write_all(b"]}")?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Write one schema and its rows as a JSON object
+    pub fn export_schema_json<W: Write>(&self, schema_name: &str, mut writer: W) -> Result<()> {
+        let schema = self.schema_or_err(schema_name)?;
+        self.write_schema_json(schema, true, &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Write the rows of one schema to a CSV file with a header row.
+    // Columns are the id, unless the schema has a composite key, followed
+    // by the schema fields in column order.
+    pub fn export_csv<P: AsRef<Path>>(&self, schema_name: &str, path: P) -> Result<usize> {
+        let schema = self.schema_or_err(schema_name)?;
+        let columns = csv_columns(schema);
+
+        let mut writer = csv::Writer::from_path(path)?;
+        let with_id = schema.key.has_id_column();
+        let mut header = if with_id { vec!["id"] } else { vec![] };
+        header.extend(columns.iter().map(|c| c.as_str()));
+        writer.write_record(&header)?;
+
+        let mut stmt = self.conn.prepare(&format!("{} ORDER BY {}", select_sql(schema), row_key(schema)))?;
+        let mut rows = stmt.query([])?;
+        let mut written = 0;
+        while let Some(row) = rows.next()? {
+            let model = read_model(row, schema)?;
+
+            let mut record = vec![];
+            if with_id {
+                record.push(model.id.map(|id| id.to_string()).unwrap_or_default());
+            }
+            for column in &columns {
+                record.push(value_to_csv(&model.data[*column], &schema.fields[*column]));
+            }
+            writer.write_record(&record)?;
+            written += 1;
+        }
+
+        writer.flush()?;
+        Ok(written)
+    }
+
+    fn write_schema_json<W: Write>(&self, schema: &Schema, versioned: bool, writer: &mut W) -> Result<()> {
+        let mut object = serde_json::Map::new();
+        if versioned {
+            object.insert("version".to_string(), WIRE_VERSION.into());
+        }
+        object.extend(schema_to_json(schema));
+        // Rows are streamed after the definition, so the object is written
+        // without its closing brace
+        let definition = serde_json::to_string(&object)?;
+        writer.write_all(&definition.as_bytes()[..definition.len() - 1])?;
+        writer.write_all(b",\"rows\":[")?;
+
+        let mut stmt = self.conn.prepare(&format!("{} ORDER BY {}", select_sql(schema), row_key(schema)))?;
+        let mut rows = stmt.query([])?;
+        let mut first = true;
+        while let Some(row) = rows.next()? {
+            let model = read_model(row, schema)?;
+            if !first {
+                writer.write_all(b",")?;
+            }
+            first = false;
+            serde_json::to_writer(&mut *writer, &model_to_json(schema, &model))?;
+        }
+
+        writer.write_all(b"]}")?;
+        Ok(())
+    }
+}
+
+// Definition of a schema as it appears in exports, without the rows
+pub(crate) fn schema_to_json(schema: &Schema) -> serde_json::Map<String, serde_json::Value> {
+    let fields: serde_json::Map<String, serde_json::Value> = schema
+        .fields
+        .iter()
+        .map(|(name, field_type)| (name.clone(), field_type.name().into()))
+        .collect();
+
+    let mut object = serde_json::Map::new();
+    object.insert("name".to_string(), schema.name.clone().into());
+    object.insert("fields".to_string(), fields.into());
+    match &schema.key {
+        PrimaryKey::Integer => {}
+        PrimaryKey::Text => {
+            object.insert("key".to_string(), "Text".into());
+        }
+        PrimaryKey::Composite(key_fields) => {
+            object.insert("key".to_string(), key_fields.clone().into());
+        }
+    }
+    if schema.versioned {
+        object.insert("versioned".to_string(), true.into());
+    }
+    if schema.sql_checks {
+        object.insert("sql_checks".to_string(), true.into());
+    }
+    if schema.tenant_scoped {
+        object.insert("tenant_scoped".to_string(), true.into());
+    }
+    if schema.history {
+        object.insert("history".to_string(), true.into());
+    }
+    if schema.hierarchical {
+        object.insert("hierarchical".to_string(), true.into());
+    }
+    if schema.append_only {
+        object.insert("append_only".to_string(), true.into());
+    }
+    match schema.uuid_ids {
+        None => {}
+        Some(UuidVersion::V4) => {
+            object.insert("uuid".to_string(), "v4".into());
+        }
+        Some(UuidVersion::V7) => {
+            object.insert("uuid".to_string(), "v7".into());
+        }
+    }
+    if !schema.defaults.is_empty() {
+        let defaults: serde_json::Map<String, serde_json::Value> = schema
+            .fields
+            .iter()
+            .filter_map(|(name, field_type)| {
+                let default = schema.defaults.get(name)?;
+                Some((name.clone(), value_to_json(default, field_type)))
+            })
+            .collect();
+        object.insert("defaults".to_string(), defaults.into());
+    }
+    if !schema.computed_fields.is_empty() {
+        let computed: serde_json::Map<String, serde_json::Value> = schema
+            .fields
+            .keys()
+            .filter_map(|name| Some((name.clone(), schema.computed_fields.get(name)?.clone().into())))
+            .collect();
+        object.insert("computed".to_string(), computed.into());
+    }
+    if let Some(expiry) = &schema.expiry {
+        let mut entry = serde_json::Map::new();
+        entry.insert("field".to_string(), expiry.field.clone().into());
+        if let Some(flag) = &expiry.flag {
+            entry.insert("flag".to_string(), flag.clone().into());
+        }
+        object.insert("expiry".to_string(), entry.into());
+    }
+    if !schema.validators.is_empty() {
+        let validators: serde_json::Map<String, serde_json::Value> = schema
+            .fields
+            .keys()
+            .filter_map(|name| {
+                let validators = schema.validators.get(name)?;
+                Some((name.clone(), validators.iter().map(Validator::to_json).collect()))
+            })
+            .collect();
+        object.insert("validators".to_string(), validators.into());
+    }
+    if !schema.fts_fields.is_empty() {
+        object.insert("fts".to_string(), schema.fts_fields.clone().into());
+    }
+    if !schema.encrypted_fields.is_empty() {
+        object.insert("encrypted".to_string(), schema.encrypted_fields.clone().into());
+    }
+    if !schema.nullable_fields.is_empty() {
+        object.insert("nullable".to_string(), schema.nullable_fields.clone().into());
+    }
+    if !schema.indexed_fields.is_empty() {
+        object.insert("indexes".to_string(), schema.indexed_fields.clone().into());
+    }
+    if !schema.unique_fields.is_empty() {
+        object.insert("unique".to_string(), schema.unique_fields.clone().into());
+    }
+    if !schema.many_to_many.is_empty() {
+        let relations: serde_json::Map<String, serde_json::Value> = schema
+            .many_to_many
+            .iter()
+            .map(|(target, join_table)| (target.clone(), join_table.clone().into()))
+            .collect();
+        object.insert("many_to_many".to_string(), relations.into());
+    }
+    object
+}
+
+// Field columns of a schema as laid out in CSV files
+pub(crate) fn csv_columns(schema: &Schema) -> Vec<&String> {
+    schema.fields.keys().collect()
+}
+
+fn value_to_csv(value: &Value, field_type: &FieldType) -> String {
+    match (value, field_type) {
+        (Value::Null, _) => String::new(),
+        (Value::Integer(i), FieldType::Boolean) => (*i != 0).to_string(),
+        (Value::Blob(_), FieldType::Vector(_)) => value_to_json(value, field_type).to_string(),
+        (Value::Integer(i), _) => i.to_string(),
+        (Value::Real(f), _) => f.to_string(),
+        (Value::Text(s), _) => s.clone(),
+        (Value::Blob(b), _) => String::from_utf8_lossy(b).into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model_builder::ModelBuilder;
+
+    fn exported(db: &FlexibleDatabase) -> serde_json::Value {
+        let mut out = Vec::new();
+        db.export_json(&mut out).unwrap();
+        serde_json::from_slice(&out).unwrap()
+    }
+
+    #[test]
+    fn every_schema_is_exported_with_its_rows() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("tasks", [("title".to_string(), FieldType::Text), ("done".to_string(), FieldType::Boolean)])).unwrap();
+        db.define_schema(Schema::new("notes", [("body".to_string(), FieldType::Text)])).unwrap();
+        db.create_model("tasks", ModelBuilder::new().text("title", "write").set("done", true).build()).unwrap();
+        db.create_model("tasks", ModelBuilder::new().text("title", "test").set("done", false).build()).unwrap();
+
+        let json = exported(&db);
+        assert_eq!(json["version"], WIRE_VERSION);
+        let schemas = json["schemas"].as_array().unwrap();
+        let names: Vec<&str> = schemas.iter().map(|s| s["name"].as_str().unwrap()).collect();
+        assert_eq!(names, ["notes", "tasks"]);
+        assert_eq!(schemas[0]["rows"], serde_json::json!([]));
+        assert_eq!(schemas[1]["fields"], serde_json::json!({"title": "Text", "done": "Boolean"}));
+        assert_eq!(
+            schemas[1]["rows"],
+            serde_json::json!([
+                {"id": 1, "title": "write", "done": true},
+                {"id": 2, "title": "test", "done": false},
+            ])
+        );
+    }
+
+    #[test]
+    fn an_empty_database_exports_no_schemas() {
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        assert_eq!(exported(&db), serde_json::json!({"version": WIRE_VERSION, "schemas": []}));
+    }
+
+    #[test]
+    fn one_schema_exports_with_the_format_version() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("notes", [("body".to_string(), FieldType::Text)])).unwrap();
+        db.create_model("notes", ModelBuilder::new().text("body", "\"quoted\"").build()).unwrap();
+
+        let mut out = Vec::new();
+        db.export_schema_json("notes", &mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["version"], WIRE_VERSION);
+        assert_eq!(json["rows"], serde_json::json!([{"id": 1, "body": "\"quoted\""}]));
+
+        assert!(matches!(db.export_schema_json("missing", Vec::new()), Err(crate::error::KooError::SchemaNotFound(_))));
+    }
+}
@@ -0,0 +1,684 @@
+use rusqlite::types::Value;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FieldType, FlexibleDatabase, ModelId, PrimaryKey, Schema};
+use crate::geo::GeoPoint;
+use crate::relations::ModelRef;
+use crate::tree::PARENT_FIELD;
+use crate::validation::Validator;
+use crate::vector;
+
+// Attempts at a model whose unique fields are all new before giving up
+const UNIQUE_ATTEMPTS: usize = 100;
+
+const WORDS: &[&str] = &[
+    "alpha", "amber", "apple", "atlas", "birch", "blue", "brook", "cedar", "cloud", "coral", "delta", "ember",
+    "falcon", "fern", "frost", "garnet", "harbor", "iris", "jade", "lake", "lunar", "maple", "meadow", "nova", "oak",
+    "opal", "pine", "quartz", "river", "sage", "shore", "stone", "summit", "tide", "vale", "willow",
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct GeneratorOptions {
+    // Seed for the same models on every run; None seeds from the clock
+    pub seed: Option<u64>,
+    // Fields whose values must differ between models, besides those in
+    // the key and unique indexes, which always must
+    pub unique_fields: Vec<String>,
+    // Values to pick from for a field, instead of generating them, e.g.
+    // for text that must match a pattern or should look realistic
+    pub choices: HashMap<String, Vec<Value>>,
+}
+
+// Small, fast generator, seeded so runs can be repeated (SplitMix64)
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in 0..n, for n > 0
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    // Uniform in 0.0..1.0
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+
+    fn word(&mut self) -> &'static str {
+        WORDS[self.below(WORDS.len())]
+    }
+}
+
+// Random models for load tests and demos. Values follow the field types
+// and validators: numbers within Min and Max, text within MaxLength, enum
+// values from the allowed ones, references to models that exist. Text
+// under a Pattern is tried in a few common shapes; give `choices` for
+// patterns none of them match.
+impl FlexibleDatabase {
+    // Create `n` random models of `schema_name` in one transaction,
+    // returning their ids. Referenced schemas need models to point at,
+    // except for the parents of hierarchical ones.
+    pub fn generate_fake(&mut self, schema_name: &str, n: usize, options: GeneratorOptions) -> Result<Vec<ModelId>> {
+        let seed = options.seed.unwrap_or_else(|| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            now.as_nanos() as u64
+        });
+        let mut rng = Rng(seed);
+
+        self.in_transaction(|db| {
+            let schema = db.schema_or_err(schema_name)?.clone();
+            for field_name in options.unique_fields.iter().chain(options.choices.keys()) {
+                if field_name != "id" && !schema.fields.contains_key(field_name) {
+                    return Err(KooError::UnknownField {
+                        schema: schema_name.to_string(),
+                        field: field_name.clone(),
+                    });
+                }
+            }
+
+            let mut targets = HashMap::new();
+            for field_type in schema.fields.values() {
+                let names = match field_type {
+                    FieldType::Reference(target) => vec![target.clone()],
+                    FieldType::Polymorphic(names) => names.clone(),
+                    _ => continue,
+                };
+                for name in names {
+                    if let Entry::Vacant(entry) = targets.entry(name) {
+                        let ids = db.existing_ids(entry.key())?;
+                        entry.insert(ids);
+                    }
+                }
+            }
+
+            let unique = unique_columns(db, &schema, &options)?;
+            let mut seen = db.existing_tuples(&schema, &unique)?;
+
+            let mut ids = Vec::with_capacity(n);
+            for _ in 0..n {
+                let mut attempts = 0;
+                let data = loop {
+                    let data = fake_data(db, &schema, &options, &targets, &unique, &mut rng)?;
+                    let tuples: Vec<Vec<String>> = unique.iter().map(|columns| tuple(&data, columns)).collect();
+                    if tuples.iter().zip(&seen).all(|(tuple, seen)| !seen.contains(tuple)) {
+                        for (tuple, seen) in tuples.into_iter().zip(&mut seen) {
+                            seen.insert(tuple);
+                        }
+                        break data;
+                    }
+                    attempts += 1;
+                    if attempts == UNIQUE_ATTEMPTS {
+                        return Err(KooError::InvalidData(format!(
+                            "couldn't generate a '{}' model with unique values after {} attempts",
+                            schema_name, UNIQUE_ATTEMPTS
+                        )));
+                    }
+                };
+                let id = db.create_model(schema_name, data)?;
+                // Later models may be children of earlier ones
+                if schema.hierarchical
+                    && let Some(parents) = targets.get_mut(&schema.name)
+                {
+                    parents.push(id.clone());
+                }
+                ids.push(id);
+            }
+            Ok(ids)
+        })
+    }
+
+    fn existing_ids(&self, schema_name: &str) -> Result<Vec<ModelId>> {
+        let schema = self.schema_or_err(schema_name)?;
+        let sql = match schema.key {
+            PrimaryKey::Composite(_) => return Ok(vec![]),
+            _ => format!("SELECT id FROM {}", schema.name),
+        };
+        let ids = self
+            .conn
+            .prepare(&sql)?
+            .query_map([], |row| row.get::<_, Value>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(ids
+            .into_iter()
+            .filter_map(|id| match id {
+                Value::Integer(id) => Some(ModelId::Integer(id)),
+                Value::Text(id) => Some(ModelId::Text(id)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    // Values already stored in each set of unique columns
+    fn existing_tuples(&self, schema: &Schema, unique: &[Vec<String>]) -> Result<Vec<HashSet<Vec<String>>>> {
+        let mut seen = Vec::new();
+        for columns in unique {
+            let sql = format!("SELECT {} FROM {}", columns.join(", "), schema.name);
+            let mut stmt = self.conn.prepare(&sql)?;
+            let mut rows = stmt.query([])?;
+            let mut tuples = HashSet::new();
+            while let Some(row) = rows.next()? {
+                let values = (0..columns.len())
+                    .map(|i| row.get::<_, Value>(i).map(|value| format!("{:?}", value)))
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                tuples.insert(values);
+            }
+            seen.push(tuples);
+        }
+        Ok(seen)
+    }
+}
+
+// Sets of columns whose values must be new: those of unique indexes, such
+// as the key, and each of `unique_fields`. Integer ids are left to SQLite.
+fn unique_columns(db: &FlexibleDatabase, schema: &Schema, options: &GeneratorOptions) -> Result<Vec<Vec<String>>> {
+    let mut unique = Vec::new();
+    let indexes: Vec<(String, bool)> = db
+        .conn
+        .prepare(&format!("PRAGMA index_list({})", schema.name))?
+        .query_map([], |row| Ok((row.get::<_, String>(1)?, row.get::<_, bool>(2)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    for (index, is_unique) in indexes {
+        if !is_unique {
+            continue;
+        }
+        let columns: Vec<String> = db
+            .conn
+            .prepare(&format!("PRAGMA index_info({})", index))?
+            .query_map([], |row| row.get::<_, Option<String>>(2))?
+            .collect::<rusqlite::Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+        if !columns.is_empty() && !unique.contains(&columns) {
+            unique.push(columns);
+        }
+    }
+    for field_name in &options.unique_fields {
+        let columns = vec![field_name.clone()];
+        if !unique.contains(&columns) {
+            unique.push(columns);
+        }
+    }
+    Ok(unique)
+}
+
+fn tuple(data: &HashMap<String, Value>, columns: &[String]) -> Vec<String> {
+    columns
+        .iter()
+        .map(|column| format!("{:?}", data.get(column).unwrap_or(&Value::Null)))
+        .collect()
+}
+
+fn fake_data(
+    db: &FlexibleDatabase,
+    schema: &Schema,
+    options: &GeneratorOptions,
+    targets: &HashMap<String, Vec<ModelId>>,
+    unique: &[Vec<String>],
+    rng: &mut Rng,
+) -> Result<HashMap<String, Value>> {
+    let is_unique = |field_name: &str| unique.iter().any(|columns| columns.iter().any(|c| c == field_name));
+    let mut data = HashMap::new();
+    if schema.key == PrimaryKey::Text && schema.uuid_ids.is_none() {
+        let id = match options.choices.get("id") {
+            Some(choices) if !choices.is_empty() => rng.pick(choices).clone(),
+            _ => Value::Text(format!("{:016x}", rng.next())),
+        };
+        data.insert("id".to_string(), id);
+    }
+
+    for (field_name, field_type) in &schema.fields {
+        if schema.is_computed(field_name) {
+            continue;
+        }
+        if let Some(choices) = options.choices.get(field_name)
+            && !choices.is_empty()
+        {
+            data.insert(field_name.clone(), rng.pick(choices).clone());
+            continue;
+        }
+        let validators = schema.validators.get(field_name).map(Vec::as_slice).unwrap_or_default();
+        let expiry = schema.expiry.as_ref();
+        let value = if expiry.is_some_and(|expiry| expiry.field == *field_name) {
+            expiry_value(db, field_type, rng)?
+        } else if expiry.is_some_and(|expiry| expiry.flag.as_deref() == Some(field_name)) {
+            Value::Integer(0)
+        } else if schema.hierarchical && field_name == PARENT_FIELD {
+            let parents = &targets[&schema.name];
+            // About one model in four is a root
+            match parents.is_empty() || rng.below(4) == 0 {
+                true => Value::Null,
+                false => id_value(rng.pick(parents)),
+            }
+        } else {
+            fake_value(
+                schema,
+                field_name,
+                field_type,
+                validators,
+                targets,
+                is_unique(field_name),
+                rng,
+            )?
+        };
+        data.insert(field_name.clone(), value);
+    }
+    Ok(data)
+}
+
+fn fake_value(
+    schema: &Schema,
+    field_name: &str,
+    field_type: &FieldType,
+    validators: &[Validator],
+    targets: &HashMap<String, Vec<ModelId>>,
+    unique: bool,
+    rng: &mut Rng,
+) -> Result<Value> {
+    let min = validators.iter().find_map(|v| match v {
+        Validator::Min(min) => Some(*min),
+        _ => None,
+    });
+    let max = validators.iter().find_map(|v| match v {
+        Validator::Max(max) => Some(*max),
+        _ => None,
+    });
+    // Unique numbers get room to differ
+    let span = if unique { 1e9 } else { 1000.0 };
+    let (low, high) = match (min, max) {
+        (Some(min), Some(max)) => (min, max),
+        (Some(min), None) => (min, min + span),
+        (None, Some(max)) => (max - span, max),
+        (None, None) => (0.0, span),
+    };
+
+    let value = match field_type {
+        FieldType::Integer => {
+            let (low, high) = (low.ceil() as i64, high.floor() as i64);
+            if low > high {
+                return Err(no_value(schema, field_name));
+            }
+            let width = (high - low) as u64 + 1;
+            Value::Integer(low + (rng.next() % width.max(1)) as i64)
+        }
+        FieldType::Real => Value::Real(low + rng.unit() * (high - low)),
+        FieldType::Boolean => Value::Integer(rng.below(2) as i64),
+        FieldType::Enum(allowed) if allowed.is_empty() => return Err(no_value(schema, field_name)),
+        FieldType::Enum(allowed) => Value::Text(rng.pick(allowed).clone()),
+        FieldType::Text => fake_text(schema, field_name, validators, unique, rng)?,
+        FieldType::GeoPoint => GeoPoint::new(rng.unit() * 180.0 - 90.0, rng.unit() * 360.0 - 180.0).into(),
+        FieldType::Vector(dimensions) => {
+            let numbers: Vec<f32> = (0..*dimensions).map(|_| (rng.unit() * 2.0 - 1.0) as f32).collect();
+            vector::encode(&numbers)
+        }
+        FieldType::Reference(target) => match targets.get(target) {
+            Some(ids) if !ids.is_empty() => id_value(rng.pick(ids)),
+            _ => return Err(no_targets(schema, field_name, target)),
+        },
+        FieldType::Polymorphic(names) => {
+            let filled: Vec<&String> = names
+                .iter()
+                .filter(|name| targets.get(*name).is_some_and(|ids| !ids.is_empty()))
+                .collect();
+            if filled.is_empty() {
+                return Err(no_targets(schema, field_name, &names.join("' or '")));
+            }
+            let name = *rng.pick(&filled);
+            ModelRef::new(name, rng.pick(&targets[name]).clone()).into()
+        }
+    };
+    Ok(value)
+}
+
+// Text shaped by the field's name, then in other shapes until one passes
+// the field's Pattern, shortened to its MaxLength
+fn fake_text(
+    schema: &Schema,
+    field_name: &str,
+    validators: &[Validator],
+    unique: bool,
+    rng: &mut Rng,
+) -> Result<Value> {
+    let max_length = validators.iter().find_map(|v| match v {
+        Validator::MaxLength(max) => Some(*max),
+        _ => None,
+    });
+    let patterns: Vec<&regex::Regex> = validators
+        .iter()
+        .filter_map(|v| match v {
+            Validator::Pattern(pattern) => Some(pattern),
+            _ => None,
+        })
+        .collect();
+
+    let name = field_name.to_ascii_lowercase();
+    // Unique text gets numbers wide enough to tell values apart
+    let number = rng.below(if unique { 1_000_000_000 } else { 10_000 });
+    let word = rng.word();
+    let words = format!("{} {} {}", word, rng.word(), rng.word());
+    let mut candidates = Vec::new();
+    if name.contains("email") {
+        candidates.push(format!("{}{}@example.com", word, number));
+    }
+    if name.contains("url") || name.contains("website") {
+        candidates.push(format!("https://example.com/{}/{}", word, number));
+    }
+    if name.contains("name") {
+        let mut chars = word.chars();
+        let first = chars.next().map(|c| c.to_ascii_uppercase()).into_iter();
+        candidates.push(first.chain(chars).collect());
+    }
+    if name.contains("phone") {
+        candidates.push(format!("555-{:04}", number));
+    }
+    candidates.extend([
+        words,
+        word.to_string(),
+        format!("{}-{}", word, number),
+        number.to_string(),
+        format!("{}{}@example.com", word, number),
+        format!("{:016x}", rng.next()),
+        word.to_ascii_uppercase(),
+    ]);
+    if unique {
+        // Shapes without the number get one at the end
+        for candidate in candidates.iter_mut() {
+            if !candidate.contains(&number.to_string()) {
+                candidate.push_str(&format!("-{}", number));
+            }
+        }
+    }
+
+    for candidate in candidates {
+        let candidate = match max_length {
+            Some(max) => candidate.chars().take(max).collect(),
+            None => candidate,
+        };
+        if patterns.iter().all(|pattern| pattern.is_match(&candidate)) {
+            return Ok(Value::Text(candidate));
+        }
+    }
+    Err(KooError::InvalidData(format!(
+        "can't generate text for '{}.{}' matching its pattern; give choices for it",
+        schema.name, field_name
+    )))
+}
+
+// A time up to 30 days from now, in the field's type
+fn expiry_value(db: &FlexibleDatabase, field_type: &FieldType, rng: &mut Rng) -> Result<Value> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let at = now + 60 + rng.below(30 * 24 * 60 * 60) as i64;
+    match field_type {
+        FieldType::Integer => Ok(Value::Integer(at)),
+        _ => Ok(db
+            .conn
+            .query_row("SELECT datetime(?, 'unixepoch')", [at], |row| row.get(0))?),
+    }
+}
+
+fn id_value(id: &ModelId) -> Value {
+    match id {
+        ModelId::Integer(id) => Value::Integer(*id),
+        id => Value::Text(id.to_string()),
+    }
+}
+
+fn no_value(schema: &Schema, field_name: &str) -> KooError {
+    KooError::InvalidData(format!("no value satisfies '{}.{}'", schema.name, field_name))
+}
+
+fn no_targets(schema: &Schema, field_name: &str, target: &str) -> KooError {
+    KooError::InvalidData(format!(
+        "'{}.{}' refers to '{}', which has no models to pick from",
+        schema.name, field_name, target
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::flexible_database::FieldDef;
+
+    fn options(seed: u64) -> GeneratorOptions {
+        GeneratorOptions {
+            seed: Some(seed),
+            ..GeneratorOptions::default()
+        }
+    }
+
+    fn people() -> Schema {
+        Schema::new("people", [])
+            .field("name", FieldDef::new(FieldType::Text))
+            .field("email", FieldDef::new(FieldType::Text).unique())
+            .field(
+                "age",
+                FieldDef::new(FieldType::Integer)
+                    .validate(Validator::Min(18.0))
+                    .validate(Validator::Max(65.0)),
+            )
+            .field("score", FieldDef::new(FieldType::Real).validate(Validator::Min(-1.0)))
+            .field(
+                "code",
+                FieldDef::new(FieldType::Text)
+                    .validate(Validator::MaxLength(5))
+                    .validate(Validator::pattern("^[a-z]+$").unwrap()),
+            )
+            .field(
+                "tier",
+                FieldDef::new(FieldType::Enum(vec!["free".to_string(), "pro".to_string()])),
+            )
+            .field("active", FieldDef::new(FieldType::Boolean))
+            .field("home", FieldDef::new(FieldType::GeoPoint))
+            .field("embedding", FieldDef::new(FieldType::Vector(3)))
+    }
+
+    fn database(schemas: impl IntoIterator<Item = Schema>) -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        for schema in schemas {
+            db.define_schema(schema).unwrap();
+        }
+        db
+    }
+
+    fn rows(db: &FlexibleDatabase, sql: &str) -> Vec<Vec<Value>> {
+        let mut statement = db.conn.prepare(sql).unwrap();
+        let columns = statement.column_count();
+        statement
+            .query_map([], |row| (0..columns).map(|i| row.get(i)).collect())
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn values_follow_the_types_and_validators() {
+        let mut db = database([people()]);
+        let ids = db.generate_fake("people", 50, options(7)).unwrap();
+        assert_eq!(ids.len(), 50);
+        assert_eq!(db.count("people").unwrap(), 50);
+
+        for row in rows(&db, "SELECT age, score, code, tier, active, email FROM people") {
+            let Value::Integer(age) = row[0] else {
+                panic!("{:?}", row)
+            };
+            assert!((18..=65).contains(&age));
+            let Value::Real(score) = row[1] else {
+                panic!("{:?}", row)
+            };
+            assert!(score >= -1.0);
+            let Value::Text(code) = &row[2] else {
+                panic!("{:?}", row)
+            };
+            assert!(
+                code.len() <= 5 && code.chars().all(|c| c.is_ascii_lowercase()),
+                "{}",
+                code
+            );
+            assert!(matches!(&row[3], Value::Text(tier) if tier == "free" || tier == "pro"));
+            assert!(matches!(row[4], Value::Integer(0 | 1)));
+            assert!(matches!(&row[5], Value::Text(email) if email.ends_with("@example.com")));
+        }
+        let emails = rows(&db, "SELECT COUNT(DISTINCT email) FROM people");
+        assert_eq!(emails[0][0], Value::Integer(50));
+    }
+
+    #[test]
+    fn a_seed_repeats_the_same_models() {
+        let generated = |seed| {
+            let mut db = database([people()]);
+            db.generate_fake("people", 10, options(seed)).unwrap();
+            rows(&db, "SELECT * FROM people ORDER BY id")
+        };
+        assert_eq!(generated(42), generated(42));
+        assert_ne!(generated(42), generated(43));
+    }
+
+    #[test]
+    fn choices_are_picked_from() {
+        let mut db = database([people()]);
+        let tiers = vec![Value::Text("pro".to_string())];
+        let chosen = GeneratorOptions {
+            choices: HashMap::from([("tier".to_string(), tiers)]),
+            ..options(1)
+        };
+        db.generate_fake("people", 20, chosen).unwrap();
+        assert_eq!(
+            rows(&db, "SELECT DISTINCT tier FROM people"),
+            vec![vec![Value::Text("pro".to_string())]]
+        );
+    }
+
+    #[test]
+    fn unique_fields_give_up_once_values_run_out() {
+        let mut db = database([people()]);
+        db.generate_fake("people", 5, options(1)).unwrap();
+        let ages: Vec<Value> = [20, 21, 22].into_iter().map(Value::Integer).collect();
+        let unique = GeneratorOptions {
+            unique_fields: vec!["age".to_string()],
+            choices: HashMap::from([("age".to_string(), ages)]),
+            ..options(2)
+        };
+        // Earlier models may already hold some of the ages
+        let err = db.generate_fake("people", 4, unique).unwrap_err();
+        assert!(
+            matches!(err, KooError::InvalidData(ref message) if message.contains("unique")),
+            "{:?}",
+            err
+        );
+        // The transaction takes back the models made before the failure
+        assert_eq!(db.count("people").unwrap(), 5);
+    }
+
+    #[test]
+    fn references_point_at_existing_models() {
+        let teams = Schema::new("teams", []).field("name", FieldDef::new(FieldType::Text));
+        let players =
+            Schema::new("players", []).field("team", FieldDef::new(FieldType::Reference("teams".to_string())));
+        let mut db = database([teams, players]);
+        assert!(matches!(
+            db.generate_fake("players", 1, options(1)),
+            Err(KooError::InvalidData(message)) if message.contains("no models to pick from")
+        ));
+
+        let teams = db.generate_fake("teams", 3, options(1)).unwrap();
+        db.generate_fake("players", 20, options(1)).unwrap();
+        for row in rows(&db, "SELECT team FROM players") {
+            let Value::Integer(team) = row[0] else {
+                panic!("{:?}", row)
+            };
+            assert!(teams.contains(&ModelId::Integer(team)));
+        }
+    }
+
+    #[test]
+    fn hierarchical_parents_come_from_earlier_models() {
+        let folders = Schema::new("folders", [])
+            .field("name", FieldDef::new(FieldType::Text))
+            .with_hierarchy();
+        let mut db = database([folders]);
+        db.generate_fake("folders", 30, options(3)).unwrap();
+        let sql = format!("SELECT id, {} FROM folders", PARENT_FIELD);
+        let mut roots = 0;
+        for row in rows(&db, &sql) {
+            match (&row[0], &row[1]) {
+                (_, Value::Null) => roots += 1,
+                (Value::Integer(id), Value::Integer(parent)) => assert!(parent < id),
+                _ => panic!("{:?}", row),
+            }
+        }
+        assert!(roots >= 1);
+    }
+
+    #[test]
+    fn expiry_times_are_in_the_future() {
+        let sessions = Schema::new("sessions", [])
+            .field("expires_at", FieldDef::new(FieldType::Integer))
+            .with_expiry("expires_at");
+        let mut db = database([sessions]);
+        db.generate_fake("sessions", 10, options(1)).unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        for row in rows(&db, "SELECT expires_at FROM sessions") {
+            assert!(matches!(row[0], Value::Integer(at) if at > now));
+        }
+    }
+
+    #[test]
+    fn text_ids_are_generated() {
+        let tags = Schema::new("tags", [])
+            .field("label", FieldDef::new(FieldType::Text))
+            .with_key(PrimaryKey::Text);
+        let mut db = database([tags]);
+        let ids = db.generate_fake("tags", 5, options(1)).unwrap();
+        assert!(ids.iter().all(|id| matches!(id, ModelId::Text(_))));
+        let distinct: HashSet<_> = ids.iter().map(ModelId::to_string).collect();
+        assert_eq!(distinct.len(), 5);
+    }
+
+    #[test]
+    fn unknown_fields_and_unmatched_patterns_are_refused() {
+        let mut db = database([people()]);
+        let unknown = GeneratorOptions {
+            unique_fields: vec!["nickname".to_string()],
+            ..options(1)
+        };
+        assert!(matches!(
+            db.generate_fake("people", 1, unknown),
+            Err(KooError::UnknownField { field, .. }) if field == "nickname"
+        ));
+
+        let codes = Schema::new("codes", []).field(
+            "code",
+            FieldDef::new(FieldType::Text).validate(Validator::pattern("^[A-Z]{2}[0-9]{3}$").unwrap()),
+        );
+        db.define_schema(codes).unwrap();
+        assert!(matches!(
+            db.generate_fake("codes", 1, options(1)),
+            Err(KooError::InvalidData(message)) if message.contains("give choices")
+        ));
+        let choices = vec![Value::Text("AB123".to_string())];
+        let chosen = GeneratorOptions {
+            choices: HashMap::from([("code".to_string(), choices)]),
+            ..options(1)
+        };
+        db.generate_fake("codes", 2, chosen).unwrap();
+        assert_eq!(db.count("codes").unwrap(), 2);
+    }
+}
@@ -0,0 +1,333 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use rusqlite::Connection;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::{Value, ValueRef};
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FlexibleDatabase, Schema};
+
+// SQL function that `select_columns` wraps encrypted columns in
+pub(crate) const DECRYPT_FUNCTION: &str = "koo_decrypt";
+
+// First byte of every encrypted value, so the layout can change later
+const FORMAT_VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = 1 + 4 + NONCE_LEN;
+
+// Supplies the AES-256 keys for encrypted fields. Each key has an id that
+// is stored with the values it encrypted, so keys can be rotated: new
+// writes use the current key while older values are still read with the
+// key they were written with.
+pub trait KeyProvider: Send + Sync {
+    // Id and key to encrypt a new value of `schema.field` with
+    fn encryption_key(&self, schema: &str, field: &str) -> Result<(u32, [u8; 32])>;
+    // Key with the given id, to decrypt a stored value
+    fn decryption_key(&self, key_id: u32) -> Result<[u8; 32]>;
+}
+
+// One key, with id 0, for every encrypted field
+pub struct StaticKey(pub [u8; 32]);
+
+impl KeyProvider for StaticKey {
+    fn encryption_key(&self, _schema: &str, _field: &str) -> Result<(u32, [u8; 32])> {
+        Ok((0, self.0))
+    }
+
+    fn decryption_key(&self, key_id: u32) -> Result<[u8; 32]> {
+        match key_id {
+            0 => Ok(self.0),
+            _ => Err(KooError::Encryption(format!("no key with id {}", key_id))),
+        }
+    }
+}
+
+// Fields marked `FieldDef::encrypted` are stored as AES-256-GCM ciphertext
+// with a random nonce, encrypted on write and decrypted as models are read.
+// As SQLite only sees ciphertext, encrypted fields can't be filtered,
+// sorted, indexed for search or used in keys and references. Values
+// written before a field was encrypted are read back as they are.
+impl FlexibleDatabase {
+    // Use `provider` for the keys of encrypted fields. Set it before reading
+    // or writing any schema with encrypted fields.
+    pub fn set_key_provider(&mut self, provider: impl KeyProvider + 'static) -> Result<()> {
+        let provider: Arc<dyn KeyProvider> = Arc::new(provider);
+        register_decrypt(&self.conn, Some(provider.clone()))?;
+        self.key_provider = Some(provider);
+        Ok(())
+    }
+
+    // `value` as it should be stored in `schema.field_name`
+    pub(crate) fn seal_field(&self, schema: &Schema, field_name: &str, value: Value) -> Result<Value> {
+        if value == Value::Null || !schema.encrypted_fields.iter().any(|f| f == field_name) {
+            return Ok(value);
+        }
+        let provider = self.key_provider.as_ref().ok_or_else(|| {
+            KooError::Encryption(format!(
+                "'{}.{}' is encrypted but no key provider is set",
+                schema.name, field_name
+            ))
+        })?;
+        let (key_id, key) = provider.encryption_key(&schema.name, field_name)?;
+
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+            .encrypt(&nonce, encode_plaintext(&value).as_slice())
+            .map_err(|_| KooError::Encryption(format!("couldn't encrypt '{}.{}'", schema.name, field_name)))?;
+
+        let mut stored = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        stored.push(FORMAT_VERSION);
+        stored.extend_from_slice(&key_id.to_le_bytes());
+        stored.extend_from_slice(&nonce);
+        stored.extend_from_slice(&ciphertext);
+        Ok(Value::Blob(stored))
+    }
+}
+
+// Define `DECRYPT_FUNCTION` on `conn`. Without a provider it fails on
+// every encrypted value, rather than SQLite reporting an unknown function.
+pub(crate) fn register_decrypt(conn: &Connection, provider: Option<Arc<dyn KeyProvider>>) -> Result<()> {
+    // SQLite catches no panics, and the provider is only read
+    let provider = AssertUnwindSafe(provider);
+    conn.create_scalar_function(
+        DECRYPT_FUNCTION,
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        move |ctx| match ctx.get_raw(0) {
+            ValueRef::Blob(stored) => {
+                let opened = match provider.as_deref() {
+                    Some(provider) => open_value(provider, stored),
+                    None => Err(KooError::Encryption(
+                        "encrypted fields can't be read without a key provider".to_string(),
+                    )),
+                };
+                opened.map_err(|err| rusqlite::Error::UserFunctionError(Box::new(err)))
+            }
+            other => Ok(Value::from(other)),
+        },
+    )?;
+    Ok(())
+}
+
+// Decrypt a value written by `seal_field`
+fn open_value(provider: &dyn KeyProvider, stored: &[u8]) -> Result<Value> {
+    if stored.len() < HEADER_LEN || stored[0] != FORMAT_VERSION {
+        return Err(KooError::Encryption(
+            "stored value is not in a known format".to_string(),
+        ));
+    }
+    let key_id = u32::from_le_bytes(stored[1..5].try_into().expect("four bytes"));
+    let key = provider.decryption_key(key_id)?;
+    let plaintext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+        .decrypt(Nonce::from_slice(&stored[5..HEADER_LEN]), &stored[HEADER_LEN..])
+        .map_err(|_| KooError::Encryption(format!("stored value can't be decrypted with key {}", key_id)))?;
+    decode_plaintext(&plaintext)
+}
+
+// A type tag followed by the value, so it decrypts to the same SQLite type
+fn encode_plaintext(value: &Value) -> Vec<u8> {
+    let (tag, mut payload) = match value {
+        Value::Null => (0, Vec::new()),
+        Value::Integer(i) => (1, i.to_le_bytes().to_vec()),
+        Value::Real(f) => (2, f.to_le_bytes().to_vec()),
+        Value::Text(s) => (3, s.as_bytes().to_vec()),
+        Value::Blob(b) => (4, b.clone()),
+    };
+    payload.insert(0, tag);
+    payload
+}
+
+fn decode_plaintext(plaintext: &[u8]) -> Result<Value> {
+    let bad = || KooError::Encryption("decrypted value is malformed".to_string());
+    let (tag, payload) = plaintext.split_first().ok_or_else(bad)?;
+    match tag {
+        0 => Ok(Value::Null),
+        1 => Ok(Value::Integer(i64::from_le_bytes(
+            payload.try_into().map_err(|_| bad())?,
+        ))),
+        2 => Ok(Value::Real(f64::from_le_bytes(payload.try_into().map_err(|_| bad())?))),
+        3 => Ok(Value::Text(String::from_utf8(payload.to_vec()).map_err(|_| bad())?)),
+        4 => Ok(Value::Blob(payload.to_vec())),
+        _ => Err(bad()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::flexible_database::{FieldDef, FieldType, PrimaryKey};
+    use crate::query::{Direction, Op, Query};
+    use crate::temp_file::TempFile;
+
+    // Encrypts with key 1 and still reads the values `StaticKey` wrote
+    struct Rotated;
+
+    impl KeyProvider for Rotated {
+        fn encryption_key(&self, _schema: &str, _field: &str) -> Result<(u32, [u8; 32])> {
+            Ok((1, [2; 32]))
+        }
+
+        fn decryption_key(&self, key_id: u32) -> Result<[u8; 32]> {
+            match key_id {
+                0 => Ok([1; 32]),
+                1 => Ok([2; 32]),
+                _ => Err(KooError::Encryption(format!("no key with id {}", key_id))),
+            }
+        }
+    }
+
+    fn patients() -> Schema {
+        Schema::new("patients", [("name".to_string(), FieldType::Text)])
+            .field("ssn", FieldDef::new(FieldType::Text).encrypted())
+            .field("weight", FieldDef::new(FieldType::Integer).nullable().encrypted())
+    }
+
+    fn patient(ssn: &str, weight: Value) -> HashMap<String, Value> {
+        HashMap::from([
+            ("name".to_string(), Value::Text("Ada".to_string())),
+            ("ssn".to_string(), Value::Text(ssn.to_string())),
+            ("weight".to_string(), weight),
+        ])
+    }
+
+    fn database() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.set_key_provider(StaticKey([1; 32])).unwrap();
+        db.define_schema(patients()).unwrap();
+        db
+    }
+
+    fn stored(db: &FlexibleDatabase, column: &str) -> Value {
+        db.conn
+            .query_row(&format!("SELECT {} FROM patients", column), [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn encrypted_fields_are_stored_as_ciphertext_and_read_back() {
+        let db = database();
+        let id = db
+            .create_model("patients", patient("123-45", Value::Integer(70)))
+            .unwrap();
+
+        let Value::Blob(ssn) = stored(&db, "ssn") else {
+            panic!("ssn isn't stored as a blob");
+        };
+        assert_eq!(ssn[0], FORMAT_VERSION);
+        assert!(!ssn.windows(6).any(|window| window == b"123-45"));
+        assert_eq!(stored(&db, "name"), Value::Text("Ada".to_string()));
+
+        let model = db.get_model("patients", id).unwrap().unwrap();
+        assert_eq!(model.data["ssn"], Value::Text("123-45".to_string()));
+        assert_eq!(model.data["weight"], Value::Integer(70));
+    }
+
+    #[test]
+    fn nulls_and_equal_values_are_stored_unalike() {
+        let db = database();
+        db.create_model("patients", patient("123-45", Value::Null)).unwrap();
+        db.create_model("patients", patient("123-45", Value::Null)).unwrap();
+        assert_eq!(stored(&db, "weight"), Value::Null);
+
+        let ssns: Vec<Value> = db
+            .conn
+            .prepare("SELECT ssn FROM patients")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_ne!(ssns[0], ssns[1]);
+    }
+
+    #[test]
+    fn keys_can_be_rotated() {
+        let mut db = database();
+        let old = db.create_model("patients", patient("old", Value::Integer(1))).unwrap();
+        db.set_key_provider(Rotated).unwrap();
+        let new = db.create_model("patients", patient("new", Value::Integer(2))).unwrap();
+
+        for (id, ssn) in [(old, "old"), (new.clone(), "new")] {
+            let model = db.get_model("patients", id).unwrap().unwrap();
+            assert_eq!(model.data["ssn"], Value::Text(ssn.to_string()));
+        }
+
+        // The static key only knows key 0
+        db.set_key_provider(StaticKey([1; 32])).unwrap();
+        assert!(db.get_model("patients", new).is_err());
+    }
+
+    #[test]
+    fn encrypted_fields_need_a_key_provider() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(patients()).unwrap();
+        assert!(matches!(
+            db.create_model("patients", patient("123-45", Value::Null)),
+            Err(KooError::Encryption(_))
+        ));
+
+        let file = TempFile::new("db");
+        let mut db = FlexibleDatabase::new(file.path()).unwrap();
+        db.set_key_provider(StaticKey([1; 32])).unwrap();
+        db.define_schema(patients()).unwrap();
+        let id = db.create_model("patients", patient("123-45", Value::Null)).unwrap();
+
+        let mut reopened = FlexibleDatabase::new(file.path()).unwrap();
+        reopened.load_schemas().unwrap();
+        assert!(reopened.get_model("patients", id).is_err());
+    }
+
+    #[test]
+    fn tampered_values_are_refused() {
+        let db = database();
+        let id = db.create_model("patients", patient("123-45", Value::Null)).unwrap();
+        db.conn.execute("UPDATE patients SET ssn = ssn || x'00'", []).unwrap();
+        assert!(db.get_model("patients", id).is_err());
+
+        assert!(open_value(&StaticKey([1; 32]), &[FORMAT_VERSION, 0, 0]).is_err());
+        assert!(open_value(&StaticKey([1; 32]), &[9; HEADER_LEN + 16]).is_err());
+    }
+
+    #[test]
+    fn encrypted_fields_cant_be_filtered_or_sorted_on() {
+        let db = database();
+        let filtered = db.find(&Query::new("patients").filter("ssn", Op::Eq, "123-45".to_string()));
+        assert!(matches!(filtered, Err(KooError::InvalidData(_))));
+        let sorted = db.find(&Query::new("patients").order_by("weight", Direction::Asc));
+        assert!(matches!(sorted, Err(KooError::InvalidData(_))));
+    }
+
+    #[test]
+    fn keys_and_references_cant_be_encrypted() {
+        let mut db = database();
+        let reference = Schema::new("visits", []).field(
+            "patient",
+            FieldDef::new(FieldType::Reference("patients".to_string())).encrypted(),
+        );
+        assert!(matches!(db.define_schema(reference), Err(KooError::InvalidSchema(_))));
+
+        let keyed = Schema::new("codes", [("code".to_string(), FieldType::Text)])
+            .field("region", FieldDef::new(FieldType::Text).encrypted())
+            .with_key(PrimaryKey::Composite(vec!["code".to_string(), "region".to_string()]));
+        assert!(matches!(db.define_schema(keyed), Err(KooError::InvalidSchema(_))));
+    }
+
+    #[test]
+    fn plaintext_keeps_its_type() {
+        for value in [
+            Value::Null,
+            Value::Integer(-7),
+            Value::Real(0.5),
+            Value::Text("ü".to_string()),
+            Value::Blob(vec![3, 4]),
+        ] {
+            assert_eq!(decode_plaintext(&encode_plaintext(&value)).unwrap(), value);
+        }
+        assert!(decode_plaintext(&[1, 0]).is_err());
+        assert!(decode_plaintext(&[]).is_err());
+    }
+}
@@ -0,0 +1,466 @@
+use indexmap::IndexMap;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FieldType, FlexibleDatabase, ModelId, PrimaryKey, Schema};
+use crate::schema_file::parse_document;
+use crate::wire::{data_from_json, model_id_from_str, value_to_json};
+
+// Ids of the models `load_fixtures` created, by schema and label
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Fixtures {
+    ids: IndexMap<(String, String), ModelId>,
+}
+
+impl Fixtures {
+    pub fn id(&self, schema_name: &str, label: &str) -> Option<&ModelId> {
+        self.ids.get(&(schema_name.to_string(), label.to_string()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+// A model to create, as written in a fixture file
+struct Record {
+    schema: String,
+    label: String,
+    data: serde_json::Value,
+}
+
+impl Record {
+    fn key(&self) -> (String, String) {
+        (self.schema.clone(), self.label.clone())
+    }
+}
+
+// Known state for tests and demos, kept in JSON or YAML files that name
+// each model so others can refer to it before it has an id:
+//
+//   users:
+//     ann: {name: Ann}
+//   posts:
+//     hello: {title: Hello, author: ann}
+//
+// A Reference field given a string takes the id of the model of that label
+// in the referenced schema, and a Polymorphic one given [schema, label]
+// that model's reference. Models are created in file order, except that
+// one waits for the models it refers to. What was loaded is recorded in
+// `_koo_fixtures`, so `reset_fixtures` can remove it again.
+impl FlexibleDatabase {
+    // Create the models in a fixture file, or in every .json, .yaml and
+    // .yml file of a directory, by name; files may refer to each other's
+    // labels and to those of earlier loads. Nothing is created unless
+    // every model is.
+    pub fn load_fixtures<P: AsRef<Path>>(&mut self, path: P) -> Result<Fixtures> {
+        let mut records = Vec::new();
+        for file in fixture_files(path.as_ref())? {
+            records.extend(self.read_fixture_file(&file)?);
+        }
+
+        self.in_transaction(|db| {
+            db.create_fixture_table()?;
+            let mut ids = db.loaded_fixtures()?;
+            let mut declared = HashSet::new();
+            for record in &records {
+                if ids.contains_key(&record.key()) || !declared.insert(record.key()) {
+                    return Err(KooError::InvalidData(format!(
+                        "fixture '{}.{}' is defined twice",
+                        record.schema, record.label
+                    )));
+                }
+            }
+
+            let mut loaded = Fixtures::default();
+            let mut pending = records;
+            while !pending.is_empty() {
+                let mut waiting = Vec::new();
+                let mut created = false;
+                for record in std::mem::take(&mut pending) {
+                    let schema = db.schema_or_err(&record.schema)?;
+                    let Some(resolved) = resolve_labels(schema, &record, &declared, &ids)? else {
+                        waiting.push(record);
+                        continue;
+                    };
+                    let data = data_from_json(schema, &resolved).map_err(|err| match err {
+                        KooError::InvalidData(message) => {
+                            KooError::InvalidData(format!("fixture '{}.{}': {}", record.schema, record.label, message))
+                        }
+                        err => err,
+                    })?;
+                    let id = db.create_model(&record.schema, data)?;
+                    db.conn.execute(
+                        "INSERT INTO _koo_fixtures (schema_name, label, id) VALUES (?, ?, ?)",
+                        rusqlite::params![record.schema, record.label, id_text(schema, &id)],
+                    )?;
+                    ids.insert(record.key(), id.clone());
+                    loaded.ids.insert(record.key(), id);
+                    created = true;
+                }
+                // A round that created nothing left models that wait on
+                // each other
+                if !waiting.is_empty() && !created {
+                    let labels: Vec<String> = waiting
+                        .iter()
+                        .map(|record| format!("'{}.{}'", record.schema, record.label))
+                        .collect();
+                    return Err(KooError::InvalidData(format!(
+                        "fixtures {} refer to each other in a cycle",
+                        labels.join(", ")
+                    )));
+                }
+                pending = waiting;
+            }
+            Ok(loaded)
+        })
+    }
+
+    // Delete every model loaded by `load_fixtures`, latest first so those
+    // referring to others go before them, returning how many were still
+    // there. Models of schemas since dropped are skipped.
+    pub fn reset_fixtures(&mut self) -> Result<usize> {
+        self.in_transaction(|db| {
+            db.create_fixture_table()?;
+            let loaded: Vec<(String, String)> = db
+                .conn
+                .prepare("SELECT schema_name, id FROM _koo_fixtures ORDER BY seq DESC")?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<_>>()?;
+            let mut deleted = 0;
+            for (schema_name, id) in loaded {
+                let Some(schema) = db.schemas.get(&schema_name) else {
+                    continue;
+                };
+                let id = model_id_from_str(schema, &id)?;
+                deleted += db.delete_model(&schema_name, id)? as usize;
+            }
+            db.conn.execute("DELETE FROM _koo_fixtures", [])?;
+            Ok(deleted)
+        })
+    }
+
+    // The models of a fixture file, checked against their schemas
+    fn read_fixture_file(&self, file: &Path) -> Result<Vec<Record>> {
+        let located = |message: String| KooError::InvalidData(format!("{}: {}", file.display(), message));
+        let source = std::fs::read_to_string(file)?;
+        let document = parse_document(file, &source).map_err(located)?;
+        let schemas = document
+            .as_object()
+            .ok_or_else(|| located("expected an object of schemas".to_string()))?;
+
+        let mut records = Vec::new();
+        for (schema_name, entries) in schemas {
+            if !self.schemas.contains_key(schema_name) {
+                return Err(located(format!("unknown schema '{}'", schema_name)));
+            }
+            let entries = entries
+                .as_object()
+                .ok_or_else(|| located(format!("'{}' must map labels to models", schema_name)))?;
+            for (label, data) in entries {
+                if !data.is_object() {
+                    return Err(located(format!(
+                        "fixture '{}.{}' must be an object",
+                        schema_name, label
+                    )));
+                }
+                records.push(Record {
+                    schema: schema_name.clone(),
+                    label: label.clone(),
+                    data: data.clone(),
+                });
+            }
+        }
+        Ok(records)
+    }
+
+    fn create_fixture_table(&self) -> Result<()> {
+        self.conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS _koo_fixtures (
+                seq INTEGER PRIMARY KEY,
+                schema_name TEXT NOT NULL,
+                label TEXT NOT NULL,
+                id TEXT NOT NULL,
+                UNIQUE (schema_name, label)
+            )",
+        )?;
+        Ok(())
+    }
+
+    // Ids of the fixtures already loaded, whose labels new ones may use
+    fn loaded_fixtures(&self) -> Result<HashMap<(String, String), ModelId>> {
+        let rows: Vec<(String, String, String)> = self
+            .conn
+            .prepare("SELECT schema_name, label, id FROM _koo_fixtures")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        let mut ids = HashMap::new();
+        for (schema_name, label, id) in rows {
+            if let Some(schema) = self.schemas.get(&schema_name) {
+                ids.insert((schema_name, label), model_id_from_str(schema, &id)?);
+            }
+        }
+        Ok(ids)
+    }
+}
+
+fn fixture_files(path: &Path) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let file = entry?.path();
+        if matches!(file.extension().and_then(|e| e.to_str()), Some("json" | "yaml" | "yml")) {
+            files.push(file);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// The record's data with its labels replaced by ids, or None while one
+// names a model not created yet
+fn resolve_labels(
+    schema: &Schema,
+    record: &Record,
+    declared: &HashSet<(String, String)>,
+    ids: &HashMap<(String, String), ModelId>,
+) -> Result<Option<serde_json::Value>> {
+    let mut data = record.data.clone();
+    let Some(object) = data.as_object_mut() else {
+        return Ok(Some(data));
+    };
+    for (field_name, json) in object.iter_mut() {
+        let (target, label, polymorphic) = match (schema.fields.get(field_name), &*json) {
+            (Some(FieldType::Reference(target)), serde_json::Value::String(label)) => {
+                (target.clone(), label.clone(), false)
+            }
+            // Only a label of that schema; anything else is taken as an id
+            (Some(FieldType::Polymorphic(_)), serde_json::Value::Array(pair)) => match pair.as_slice() {
+                [serde_json::Value::String(target), serde_json::Value::String(label)]
+                    if declared.contains(&(target.clone(), label.clone()))
+                        || ids.contains_key(&(target.clone(), label.clone())) =>
+                {
+                    (target.clone(), label.clone(), true)
+                }
+                _ => continue,
+            },
+            _ => continue,
+        };
+        let key = (target, label);
+        let Some(id) = ids.get(&key) else {
+            if declared.contains(&key) {
+                return Ok(None);
+            }
+            return Err(KooError::InvalidData(format!(
+                "fixture '{}.{}' refers to '{}.{}', which isn't defined",
+                record.schema, record.label, key.0, key.1
+            )));
+        };
+        let id = match id {
+            ModelId::Integer(id) => serde_json::Value::from(*id),
+            ModelId::Text(id) => serde_json::Value::from(id.clone()),
+            ModelId::Composite(_) => serde_json::Value::Null,
+        };
+        *json = match polymorphic {
+            true => serde_json::json!([key.0, id]),
+            false => id,
+        };
+    }
+    Ok(Some(data))
+}
+
+// `id` as `model_id_from_str` reads it back
+fn id_text(schema: &Schema, id: &ModelId) -> String {
+    match (id, &schema.key) {
+        (ModelId::Composite(values), PrimaryKey::Composite(key_fields)) => {
+            let values: Vec<serde_json::Value> = key_fields
+                .iter()
+                .zip(values)
+                .map(|(field_name, value)| value_to_json(value, &schema.fields[field_name]))
+                .collect();
+            serde_json::Value::Array(values).to_string()
+        }
+        (id, _) => id.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::Value;
+
+    use crate::flexible_database::FieldDef;
+    use crate::relations::ModelRef;
+
+    // A directory of fixture files, removed when the test ends
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(files: &[(&str, &str)]) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("koo-fixtures-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir(&dir).unwrap();
+            for (name, contents) in files {
+                std::fs::write(dir.join(name), contents).unwrap();
+            }
+            TempDir(dir)
+        }
+
+        fn file(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn blog() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("users", [("name".to_string(), FieldType::Text)]).field(
+            "favourite",
+            FieldDef::new(FieldType::Reference("posts".to_string())).nullable(),
+        ))
+        .unwrap();
+        db.define_schema(
+            Schema::new("posts", [("title".to_string(), FieldType::Text)])
+                .field("author", FieldDef::new(FieldType::Reference("users".to_string()))),
+        )
+        .unwrap();
+        let subject = FieldType::Polymorphic(vec!["posts".to_string(), "users".to_string()]);
+        db.define_schema(Schema::new("notes", [("subject".to_string(), subject)]))
+            .unwrap();
+        db
+    }
+
+    fn field(db: &FlexibleDatabase, schema_name: &str, id: &ModelId, field_name: &str) -> Value {
+        db.get_model(schema_name, id).unwrap().unwrap().data[field_name].clone()
+    }
+
+    #[test]
+    fn labels_resolve_to_the_ids_created() {
+        let dir = TempDir::new(&[(
+            "blog.json",
+            r#"{
+                "posts": {"hello": {"title": "Hello", "author": "ann"}},
+                "users": {"ann": {"name": "Ann"}, "bob": {"name": "Bob"}},
+                "notes": {"on_post": {"subject": ["posts", "hello"]}, "on_id": {"subject": ["users", 2]}}
+            }"#,
+        )]);
+        let mut db = blog();
+        let fixtures = db.load_fixtures(dir.file("blog.json")).unwrap();
+        assert_eq!(fixtures.len(), 5);
+
+        let ann = fixtures.id("users", "ann").unwrap();
+        let hello = fixtures.id("posts", "hello").unwrap();
+        assert_eq!(
+            field(&db, "posts", hello, "author"),
+            Value::Integer(ann.as_i64().unwrap())
+        );
+        let on_post = field(&db, "notes", fixtures.id("notes", "on_post").unwrap(), "subject");
+        assert_eq!(
+            ModelRef::from_value(&on_post),
+            Some(ModelRef::new("posts", hello.clone()))
+        );
+        // Pairs that name no label are ids
+        let on_id = field(&db, "notes", fixtures.id("notes", "on_id").unwrap(), "subject");
+        assert_eq!(ModelRef::from_value(&on_id), Some(ModelRef::new("users", 2)));
+        assert_eq!(fixtures.id("users", "cat"), None);
+    }
+
+    #[test]
+    fn directories_load_every_fixture_file() {
+        let dir = TempDir::new(&[
+            ("1-users.json", r#"{"users": {"ann": {"name": "Ann"}}}"#),
+            (
+                "2-posts.json",
+                r#"{"posts": {"hello": {"title": "Hello", "author": "ann"}}}"#,
+            ),
+            ("notes.txt", "not a fixture"),
+        ]);
+        let mut db = blog();
+        assert_eq!(db.load_fixtures(&dir.0).unwrap().len(), 2);
+
+        // Later loads may use the labels of earlier ones, but not define them again
+        let more = TempDir::new(&[
+            (
+                "posts.json",
+                r#"{"posts": {"again": {"title": "Again", "author": "ann"}}}"#,
+            ),
+            ("users.json", r#"{"users": {"ann": {"name": "Another Ann"}}}"#),
+        ]);
+        assert_eq!(db.load_fixtures(more.file("posts.json")).unwrap().len(), 1);
+        assert!(matches!(
+            db.load_fixtures(more.file("users.json")),
+            Err(KooError::InvalidData(_))
+        ));
+        assert_eq!(db.count("users").unwrap(), 1);
+    }
+
+    #[test]
+    fn failed_loads_create_nothing() {
+        let dir = TempDir::new(&[
+            (
+                "missing.json",
+                r#"{"users": {"ann": {"name": "Ann"}}, "posts": {"hello": {"title": "Hello", "author": "cat"}}}"#,
+            ),
+            (
+                "cycle.json",
+                r#"{"users": {"ann": {"name": "Ann", "favourite": "hello"}},
+                    "posts": {"hello": {"title": "Hello", "author": "ann"}}}"#,
+            ),
+            ("unknown.json", r#"{"groups": {"admins": {}}}"#),
+            ("invalid.json", r#"{"users": {"ann": {"name": 12}}}"#),
+        ]);
+        let mut db = blog();
+        for name in ["missing.json", "cycle.json", "unknown.json", "invalid.json"] {
+            assert!(db.load_fixtures(dir.file(name)).is_err(), "{}", name);
+        }
+        assert_eq!(db.count("users").unwrap(), 0);
+        assert!(db.load_fixtures(dir.file("absent.json")).is_err());
+    }
+
+    #[test]
+    fn resets_remove_what_was_loaded() {
+        let dir = TempDir::new(&[
+            ("users.json", r#"{"users": {"ann": {"name": "Ann"}}}"#),
+            (
+                "posts.json",
+                r#"{"posts": {"hello": {"title": "Hello", "author": "ann"}}}"#,
+            ),
+        ]);
+        let mut db = blog();
+        db.load_fixtures(dir.file("users.json")).unwrap();
+        db.load_fixtures(dir.file("posts.json")).unwrap();
+        let kept = HashMap::from([("name".to_string(), Value::Text("Kept".to_string()))]);
+        db.create_model("users", kept).unwrap();
+
+        // Posts first, as they refer to users
+        assert_eq!(db.reset_fixtures().unwrap(), 2);
+        assert_eq!(db.count("users").unwrap(), 1);
+        assert_eq!(db.count("posts").unwrap(), 0);
+        assert_eq!(db.reset_fixtures().unwrap(), 0);
+        assert_eq!(db.load_fixtures(dir.file("users.json")).unwrap().len(), 1);
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn yaml_fixtures_load_like_json() {
+        let dir = TempDir::new(&[(
+            "blog.yaml",
+            "users:\n  ann: {name: Ann}\nposts:\n  hello: {title: Hello, author: ann}\n",
+        )]);
+        let mut db = blog();
+        let fixtures = db.load_fixtures(dir.file("blog.yaml")).unwrap();
+        let hello = fixtures.id("posts", "hello").unwrap();
+        assert_eq!(field(&db, "posts", hello, "title"), Value::Text("Hello".to_string()));
+    }
+}
@@ -1,85 +1,1061 @@
-use rusqlite::{Connection, Result, types::Value};
+use indexmap::IndexMap;
+use rusqlite::{Connection, OptionalExtension, Row, types::Value};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::append_only::check_mutable;
+use crate::access::{AccessPolicy, CallerContext};
+use crate::audit::AuditAction;
+use crate::changes::ChangeFeed;
+use crate::error::{KooError, Result};
+use crate::events::Projection;
+use crate::expiry::Expiry;
+use crate::explain::SqlPlan;
+use crate::field_encryption::{DECRYPT_FUNCTION, KeyProvider, register_decrypt};
+use crate::geo::{GeoPoint, register_geo};
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+use crate::options::DatabaseOptions;
+use crate::read_cache::ReadCache;
+use crate::relations::ModelRef;
+use crate::retry::RetryPolicy;
+use crate::statement_cache::{DEFAULT_STATEMENT_CACHE_CAPACITY, StatementCache};
+use crate::sync::ConflictStrategy;
+use crate::telemetry::{FieldTelemetry, FieldTracker};
+use crate::tenancy::TENANT_FIELD;
+use crate::tracer::Tracer;
+use crate::tree::PARENT_FIELD;
+use crate::validation::{Validator, check_constraints, validate};
+use crate::vector::{is_vector, register_vector};
+use crate::write_behind::WriteBuffer;
 
 // Generic model representation
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Model {
-    pub id: Option<i32>,
+    pub id: Option<ModelId>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::value_map"))]
     pub data: HashMap<String, Value>,
+    // Models its reference fields point at, by field name, when the query
+    // asked for them with `Query::include`
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "HashMap::is_empty"))]
+    pub related: HashMap<String, Model>,
+    // Set when the model was read with field telemetry enabled
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) tracker: Option<FieldTracker>,
+}
+
+impl Model {
+    pub fn new(id: Option<ModelId>, data: HashMap<String, Value>) -> Model {
+        Model { id, data, related: HashMap::new(), tracker: None }
+    }
+    
+    // Read a field, counting the access when field telemetry is enabled
+    pub fn get(&self, field_name: &str) -> Option<&Value> {
+        let value = self.data.get(field_name);
+        if let (Some(_), Some(tracker)) = (value, &self.tracker) {
+            tracker.record(field_name);
+        }
+        value
+    }
+}
+
+// Identifies a model within its schema, matching the schema's primary key
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModelId {
+    Integer(i64),
+    Text(String),
+    // Values of the key fields, in key order
+    Composite(Vec<Value>),
+}
+
+impl ModelId {
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            ModelId::Integer(id) => Some(*id),
+            _ => None,
+        }
+    }
+    
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            ModelId::Text(id) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ModelId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelId::Integer(id) => write!(f, "{}", id),
+            ModelId::Text(id) => write!(f, "{}", id),
+            ModelId::Composite(values) => {
+                let values: Vec<String> = values.iter().map(sql_literal).collect();
+                write!(f, "({})", values.join(", "))
+            }
+        }
+    }
+}
+
+impl From<i32> for ModelId {
+    fn from(id: i32) -> ModelId {
+        ModelId::Integer(id as i64)
+    }
+}
+
+impl From<i64> for ModelId {
+    fn from(id: i64) -> ModelId {
+        ModelId::Integer(id)
+    }
+}
+
+impl From<&str> for ModelId {
+    fn from(id: &str) -> ModelId {
+        ModelId::Text(id.to_string())
+    }
+}
+
+impl From<String> for ModelId {
+    fn from(id: String) -> ModelId {
+        ModelId::Text(id)
+    }
+}
+
+impl From<Vec<Value>> for ModelId {
+    fn from(values: Vec<Value>) -> ModelId {
+        ModelId::Composite(values)
+    }
+}
+
+impl From<&ModelId> for ModelId {
+    fn from(id: &ModelId) -> ModelId {
+        id.clone()
+    }
+}
+
+// How the models of a schema are keyed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PrimaryKey {
+    // `id INTEGER PRIMARY KEY`, assigned by SQLite on insert
+    #[default]
+    Integer,
+    // `id TEXT PRIMARY KEY`, given as the "id" entry of the inserted data
+    Text,
+    // The named schema fields together, in order
+    Composite(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum UuidVersion {
+    // Random
+    V4,
+    // Timestamp prefixed, so ids sort roughly by creation time and index better
+    V7,
+}
+
+impl UuidVersion {
+    pub(crate) fn generate(&self) -> String {
+        match self {
+            UuidVersion::V4 => uuid::Uuid::new_v4().to_string(),
+            UuidVersion::V7 => uuid::Uuid::now_v7().to_string(),
+        }
+    }
+}
+
+impl PrimaryKey {
+    // Whether the table has an `id` column; composite keys don't
+    pub fn has_id_column(&self) -> bool {
+        !matches!(self, PrimaryKey::Composite(_))
+    }
 }
 
 // Schema definition for a model type
-#[derive(Debug, Clone)]
+// Only `name` and `fields` are required when deserializing
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
 pub struct Schema {
     pub name: String,
-    pub fields: HashMap<String, FieldType>,
+    // Fields in column order, which is the order of the table's columns and
+    // of every listing of fields, such as exports
+    pub fields: IndexMap<String, FieldType>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "crate::serialization::is_default"))]
+    pub key: PrimaryKey,
+    // Generate text ids with this UUID version when none is given
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub uuid_ids: Option<UuidVersion>,
+    // Keep a `version` column for optimistic concurrency control
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "crate::serialization::is_default"))]
+    pub versioned: bool,
+    // Also enforce field constraints with CHECK clauses in the table DDL
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "crate::serialization::is_default"))]
+    pub sql_checks: bool,
+    // Add a `tenant_id` field that `FlexibleDatabase::tenant` handles fill
+    // in and filter by
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "crate::serialization::is_default"))]
+    pub tenant_scoped: bool,
+    // Keep every past state of the models in a `<schema>_history` table
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "crate::serialization::is_default"))]
+    pub history: bool,
+    // Add a `parent_id` field referencing this schema, for tree helpers
+    // such as `get_children`
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "crate::serialization::is_default"))]
+    pub hierarchical: bool,
+    // Refuse updates and deletes, keeping every model as it was created
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "crate::serialization::is_default"))]
+    pub append_only: bool,
+    // Values used for fields left out of `create_model`
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "crate::serialization::value_map", skip_serializing_if = "HashMap::is_empty")
+    )]
+    pub defaults: HashMap<String, Value>,
+    // Fields computed by SQLite from an SQL expression over other columns,
+    // mapped to the expression
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "HashMap::is_empty"))]
+    pub computed_fields: HashMap<String, String>,
+    // Field holding when each model expires, for `expire_now`
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub expiry: Option<Expiry>,
+    // Constraints checked before every insert and update
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "crate::serialization::validator_map", skip_serializing_if = "HashMap::is_empty")
+    )]
+    pub validators: HashMap<String, Vec<Validator>>,
+    // Text fields indexed for full-text search
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Vec::is_empty"))]
+    pub fts_fields: Vec<String>,
+    // Fields stored encrypted with keys from the database's key provider
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Vec::is_empty"))]
+    pub encrypted_fields: Vec<String>,
+    // Fields whose column may hold NULL, which is also their default unless
+    // they are given another
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Vec::is_empty"))]
+    pub nullable_fields: Vec<String>,
+    // Fields indexed when the schema is defined, by an index named
+    // `<schema>_<field>_idx`
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Vec::is_empty"))]
+    pub indexed_fields: Vec<String>,
+    // Fields no two models may share a value of, enforced by a unique index
+    // named `<schema>_<field>_key`
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Vec::is_empty"))]
+    pub unique_fields: Vec<String>,
+    // Schemas this one has a many-to-many relation with, mapped to the join
+    // table linking them
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "IndexMap::is_empty"))]
+    pub many_to_many: IndexMap<String, String>,
+    // Templates whose fields are mixed into this schema when it is defined
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Vec::is_empty"))]
+    pub templates: Vec<Schema>,
 }
 
-#[derive(Debug, Clone)]
+impl Schema {
+    // Schema with the given fields in iteration order. Pass a Vec or array
+    // rather than a HashMap, or use `field`, to get the same column order
+    // on every run.
+    pub fn new(name: &str, fields: impl IntoIterator<Item = (String, FieldType)>) -> Schema {
+        Schema {
+            name: name.to_string(),
+            fields: fields.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+    
+    // Add a field after the existing ones, along with its default if it has
+    // one
+    pub fn field(mut self, field_name: &str, field: impl Into<FieldDef>) -> Schema {
+        let field = field.into();
+        if let Some(default) = field.default {
+            self.defaults.insert(field_name.to_string(), default);
+        }
+        if !field.validators.is_empty() {
+            self.validators.insert(field_name.to_string(), field.validators);
+        }
+        if let Some(expression) = field.computed {
+            self.computed_fields.insert(field_name.to_string(), expression);
+        }
+        if field.encrypted {
+            self.encrypted_fields.push(field_name.to_string());
+        }
+        if field.nullable && !self.nullable_fields.iter().any(|f| f == field_name) {
+            self.nullable_fields.push(field_name.to_string());
+        }
+        if field.indexed {
+            self = self.with_index(field_name);
+        }
+        if field.unique {
+            self = self.with_unique_index(field_name);
+        }
+        self.fields.insert(field_name.to_string(), field.field_type);
+        self
+    }
+    
+    // Index a field, to speed up filtering and sorting on it
+    pub fn with_index(mut self, field_name: &str) -> Schema {
+        if !self.indexed_fields.iter().any(|f| f == field_name) {
+            self.indexed_fields.push(field_name.to_string());
+        }
+        self
+    }
+    
+    // Index a field uniquely, so no two models can share a value of it.
+    // NULLs of a nullable field don't count.
+    pub fn with_unique_index(mut self, field_name: &str) -> Schema {
+        if !self.unique_fields.iter().any(|f| f == field_name) {
+            self.unique_fields.push(field_name.to_string());
+        }
+        self
+    }
+    
+    // Key the table by something other than an integer id
+    pub fn with_key(mut self, key: PrimaryKey) -> Schema {
+        self.key = key;
+        self
+    }
+    
+    // Key the table by UUID strings that `create_model` generates, so ids
+    // don't reveal insertion order or volume. An explicit "id" in the data
+    // is still accepted, e.g. when importing.
+    pub fn with_uuid_ids(mut self, version: UuidVersion) -> Schema {
+        self.key = PrimaryKey::Text;
+        self.uuid_ids = Some(version);
+        self
+    }
+    
+    // Add a `version` column that starts at 1 and is bumped by every update.
+    // Models read from the schema carry it as the "version" entry, and
+    // `update_model` requires it back, failing with `StaleVersion` when
+    // another writer has updated the model since it was read.
+    pub fn with_versioning(mut self) -> Schema {
+        self.versioned = true;
+        self
+    }
+    
+    // Emit field constraints, such as the allowed values of an enum, as SQL
+    // CHECK clauses too, so writes that bypass kooDB are held to them. Only
+    // applies when the table is created.
+    pub fn with_sql_checks(mut self) -> Schema {
+        self.sql_checks = true;
+        self
+    }
+    
+    // Keep the models of every tenant in the same table, told apart by a
+    // `tenant_id` text field. Use `FlexibleDatabase::tenant` to work with one
+    // tenant's models; the database itself still sees them all.
+    pub fn with_tenancy(mut self) -> Schema {
+        self.tenant_scoped = true;
+        self
+    }
+    
+    // Record every state a model goes through, so it can be read back with
+    // `get_model_as_of` and compared with `diff_model`
+    pub fn with_history(mut self) -> Schema {
+        self.history = true;
+        self
+    }
+    
+    // Relate models of this schema to any number of `target` models and
+    // back, through the join table `join_table`, which is created with the
+    // schema. Both sides need integer ids. Links are made and read with
+    // `attach_related`, `detach_related` and `get_related`.
+    pub fn relates_many(mut self, target: &str, join_table: &str) -> Schema {
+        self.many_to_many.insert(target.to_string(), join_table.to_string());
+        self
+    }
+    
+    // Arrange the models in trees: each has a `parent_id` pointing at
+    // another model of the schema, or NULL for roots, which is also what
+    // leaving it out of the data gives. Needs integer ids.
+    pub fn with_hierarchy(mut self) -> Schema {
+        self.hierarchical = true;
+        self
+    }
+    
+    // Keep every model as it was created, for event logs and other records
+    // that must not change: `update_model`, `delete_model` and everything
+    // built on them fail with `InvalidSchema`. Together with
+    // `with_sql_checks`, triggers also refuse UPDATE and DELETE statements
+    // that bypass kooDB.
+    pub fn with_append_only(mut self) -> Schema {
+        self.append_only = true;
+        self
+    }
+    
+    // Let models expire at the time in `field_name`, after which
+    // `FlexibleDatabase::expire_now` deletes them
+    pub fn with_expiry(mut self, field_name: &str) -> Schema {
+        self.expiry = Some(Expiry {
+            field: field_name.to_string(),
+            flag: None,
+        });
+        self
+    }
+    
+    // Like `with_expiry`, but expired models are kept, with the boolean
+    // `flag_name` set to true instead
+    pub fn with_soft_expiry(mut self, field_name: &str, flag_name: &str) -> Schema {
+        self.expiry = Some(Expiry {
+            field: field_name.to_string(),
+            flag: Some(flag_name.to_string()),
+        });
+        self
+    }
+    
+    // Mix in the fields of a template schema, with their defaults, computed
+    // expressions, validators and full-text and encryption settings. Its
+    // tenancy, versioning, history, hierarchy, SQL checks and append-only
+    // settings are turned on here too, and its expiry applies unless this
+    // schema has its own. The template doesn't need to be defined itself;
+    // it is merged in by `define_schema`.
+    pub fn extends(mut self, base: &Schema) -> Schema {
+        self.templates.push(base.clone());
+        self
+    }
+    
+    // Copy of this schema with template fields merged into its own. A field
+    // declared both here and in a template must have the same type.
+    pub fn materialize(&self) -> Result<Schema> {
+        let mut schema = self.clone();
+        for template in &self.templates {
+            let template = template.materialize()?;
+            schema.tenant_scoped |= template.tenant_scoped;
+            schema.versioned |= template.versioned;
+            schema.history |= template.history;
+            schema.sql_checks |= template.sql_checks;
+            schema.append_only |= template.append_only;
+            if schema.expiry.is_none() {
+                schema.expiry = template.expiry.clone();
+            }
+            // The template's parent field references the template, where
+            // this schema's must reference this schema
+            schema.hierarchical |= template.hierarchical;
+            for (field_name, field_type) in template.fields {
+                if template.hierarchical && field_name == PARENT_FIELD {
+                    continue;
+                }
+                match schema.fields.get(&field_name) {
+                    Some(existing) if *existing != field_type => {
+                        return Err(KooError::InvalidSchema(format!(
+                            "field '{}.{}' is {} but template '{}' declares it as {}",
+                            self.name,
+                            field_name,
+                            existing.name(),
+                            template.name,
+                            field_type.name()
+                        )));
+                    }
+                    Some(_) => {}
+                    None => {
+                        schema.fields.insert(field_name, field_type);
+                    }
+                }
+            }
+            for (field_name, default) in template.defaults {
+                schema.defaults.entry(field_name).or_insert(default);
+            }
+            for (field_name, expression) in template.computed_fields {
+                schema.computed_fields.entry(field_name).or_insert(expression);
+            }
+            for (field_name, validators) in template.validators {
+                schema.validators.entry(field_name).or_default().extend(validators);
+            }
+            for field_name in template.fts_fields {
+                if !schema.fts_fields.contains(&field_name) {
+                    schema.fts_fields.push(field_name);
+                }
+            }
+            for field_name in template.encrypted_fields {
+                if !schema.encrypted_fields.contains(&field_name) {
+                    schema.encrypted_fields.push(field_name);
+                }
+            }
+            for field_name in template.nullable_fields {
+                if !schema.nullable_fields.contains(&field_name) {
+                    schema.nullable_fields.push(field_name);
+                }
+            }
+            for field_name in template.indexed_fields {
+                if !schema.indexed_fields.contains(&field_name) {
+                    schema.indexed_fields.push(field_name);
+                }
+            }
+            for field_name in template.unique_fields {
+                if !schema.unique_fields.contains(&field_name) {
+                    schema.unique_fields.push(field_name);
+                }
+            }
+        }
+        
+        if schema.tenant_scoped {
+            match schema.fields.get(TENANT_FIELD) {
+                None => {
+                    schema.fields.insert(TENANT_FIELD.to_string(), FieldType::Text);
+                }
+                Some(FieldType::Text) => {}
+                Some(_) => {
+                    return Err(KooError::InvalidSchema(format!(
+                        "'{}.{}' must be text to hold the tenant",
+                        schema.name, TENANT_FIELD
+                    )));
+                }
+            }
+        }
+        if schema.hierarchical {
+            if schema.key != PrimaryKey::Integer {
+                return Err(KooError::InvalidSchema(format!(
+                    "hierarchical schema '{}' needs an integer id",
+                    schema.name
+                )));
+            }
+            let parent = FieldType::Reference(schema.name.clone());
+            match schema.fields.get(PARENT_FIELD) {
+                None => {
+                    schema.fields.insert(PARENT_FIELD.to_string(), parent);
+                }
+                Some(field_type) if *field_type == parent => {}
+                Some(_) => {
+                    return Err(KooError::InvalidSchema(format!(
+                        "'{}.{}' must reference '{}' to hold the parent",
+                        schema.name, PARENT_FIELD, schema.name
+                    )));
+                }
+            }
+        }
+        if schema.append_only && schema.expiry.is_some() {
+            return Err(KooError::InvalidSchema(format!(
+                "append-only schema '{}' can't expire models",
+                schema.name
+            )));
+        }
+        if schema.versioned && schema.fields.contains_key(VERSION_COLUMN) {
+            return Err(KooError::InvalidSchema(format!(
+                "'{}.{}' clashes with the version column",
+                schema.name, VERSION_COLUMN
+            )));
+        }
+        if !schema.many_to_many.is_empty() && schema.key != PrimaryKey::Integer {
+            return Err(KooError::InvalidSchema(format!(
+                "many-to-many relations of '{}' need an integer id",
+                schema.name
+            )));
+        }
+        if schema.uuid_ids.is_some() && schema.key != PrimaryKey::Text {
+            return Err(KooError::InvalidSchema(format!(
+                "generated UUID ids of '{}' need a text key",
+                schema.name
+            )));
+        }
+        if let PrimaryKey::Composite(key_fields) = &schema.key {
+            if key_fields.is_empty() {
+                return Err(KooError::InvalidSchema(format!(
+                    "composite key of '{}' has no fields",
+                    schema.name
+                )));
+            }
+            if let Some(field_name) = key_fields.iter().find(|f| !schema.fields.contains_key(*f)) {
+                return Err(KooError::UnknownField {
+                    schema: schema.name.clone(),
+                    field: field_name.clone(),
+                });
+            }
+        }
+        
+        for (field_name, validators) in &schema.validators {
+            let field_type = schema.fields.get(field_name).ok_or_else(|| KooError::UnknownField {
+                schema: schema.name.clone(),
+                field: field_name.clone(),
+            })?;
+            if let Some(validator) = validators.iter().find(|v| !v.applies_to(field_type)) {
+                return Err(KooError::InvalidSchema(format!(
+                    "{} can't be used on '{}.{}', which is {}",
+                    validator,
+                    schema.name,
+                    field_name,
+                    field_type.name()
+                )));
+            }
+        }
+        
+        // Ciphertext can't be compared, indexed or referenced from SQL
+        for field_name in &schema.encrypted_fields {
+            let field_type = schema.fields.get(field_name).ok_or_else(|| KooError::UnknownField {
+                schema: schema.name.clone(),
+                field: field_name.clone(),
+            })?;
+            let reason = if matches!(field_type, FieldType::Reference(_)) {
+                Some("a reference")
+            } else if matches!(&schema.key, PrimaryKey::Composite(key_fields) if key_fields.contains(field_name)) {
+                Some("part of the key")
+            } else if schema.fts_fields.contains(field_name) {
+                Some("indexed for search")
+            } else if schema.tenant_scoped && field_name == TENANT_FIELD {
+                Some("the tenant")
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                return Err(KooError::InvalidSchema(format!(
+                    "'{}.{}' can't be encrypted, as it is {}",
+                    schema.name, field_name, reason
+                )));
+            }
+        }
+        
+        // Only SQLite writes a computed column
+        for field_name in schema.computed_fields.keys() {
+            let field_type = schema.fields.get(field_name).ok_or_else(|| KooError::UnknownField {
+                schema: schema.name.clone(),
+                field: field_name.clone(),
+            })?;
+            let reason = if matches!(field_type, FieldType::Reference(_)) {
+                Some("a reference")
+            } else if matches!(&schema.key, PrimaryKey::Composite(key_fields) if key_fields.contains(field_name)) {
+                Some("part of the key")
+            } else if schema.defaults.contains_key(field_name) {
+                Some("given a default")
+            } else if schema.validators.contains_key(field_name) {
+                Some("validated")
+            } else if schema.fts_fields.contains(field_name) {
+                Some("indexed for search")
+            } else if schema.encrypted_fields.contains(field_name) {
+                Some("encrypted")
+            } else if schema.tenant_scoped && field_name == TENANT_FIELD {
+                Some("the tenant")
+            } else if schema.hierarchical && field_name == PARENT_FIELD {
+                Some("the parent")
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                return Err(KooError::InvalidSchema(format!(
+                    "'{}.{}' can't be computed, as it is {}",
+                    schema.name, field_name, reason
+                )));
+            }
+        }
+        
+        if let Some(expiry) = &schema.expiry {
+            expiry.check(&schema)?;
+        }
+        
+        for field_name in schema
+            .nullable_fields
+            .iter()
+            .chain(&schema.indexed_fields)
+            .chain(&schema.unique_fields)
+        {
+            if !schema.fields.contains_key(field_name) {
+                return Err(KooError::UnknownField {
+                    schema: schema.name.clone(),
+                    field: field_name.clone(),
+                });
+            }
+        }
+        for field_name in &schema.nullable_fields {
+            if matches!(&schema.key, PrimaryKey::Composite(key_fields) if key_fields.contains(field_name)) {
+                return Err(KooError::InvalidSchema(format!(
+                    "'{}.{}' can't be nullable, as it is part of the key",
+                    schema.name, field_name
+                )));
+            }
+            schema.defaults.entry(field_name.clone()).or_insert(Value::Null);
+        }
+        
+        for (field_name, default) in &schema.defaults {
+            let field_type = schema.fields.get(field_name).ok_or_else(|| KooError::UnknownField {
+                schema: schema.name.clone(),
+                field: field_name.clone(),
+            })?;
+            let null = *default == Value::Null && schema.is_nullable(field_name);
+            if !null && !default_matches(default, field_type) {
+                return Err(KooError::InvalidSchema(format!(
+                    "default for '{}.{}' is not a valid {}",
+                    schema.name,
+                    field_name,
+                    field_type.name()
+                )));
+            }
+        }
+        Ok(schema)
+    }
+    
+    // Whether this schema mixes in the named template, directly or through
+    // another template
+    pub fn uses_template(&self, template_name: &str) -> bool {
+        self.templates
+            .iter()
+            .any(|t| t.name == template_name || t.uses_template(template_name))
+    }
+    
+    // Index the given text fields with FTS5 so they can be used with `search`
+    pub fn with_fts(mut self, fields: &[&str]) -> Schema {
+        self.fts_fields = fields.iter().map(|f| f.to_string()).collect();
+        self
+    }
+    
+    // This schema narrowed to the named fields, plus those of a composite
+    // key, so that `select_sql` and `read_model` only touch their columns.
+    // "id" may be named, and always comes along anyway.
+    pub(crate) fn projected(&self, field_names: &[String]) -> Result<Schema> {
+        for field_name in field_names {
+            if field_name != "id" && !self.fields.contains_key(field_name) {
+                return Err(KooError::UnknownField {
+                    schema: self.name.clone(),
+                    field: field_name.clone(),
+                });
+            }
+        }
+        let mut projected = self.clone();
+        projected.fields.retain(|field_name, _| {
+            field_names.contains(field_name)
+                || matches!(&self.key, PrimaryKey::Composite(key_fields) if key_fields.contains(field_name))
+        });
+        Ok(projected)
+    }
+    
+    // Whether SQLite computes the field rather than storing what is written
+    pub(crate) fn is_computed(&self, field_name: &str) -> bool {
+        self.computed_fields.contains_key(field_name)
+    }
+    
+    pub(crate) fn is_nullable(&self, field_name: &str) -> bool {
+        self.nullable_fields.iter().any(|f| f == field_name)
+    }
+}
+
+// A field declaration for `Schema::field`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldDef {
+    pub field_type: FieldType,
+    pub default: Option<Value>,
+    pub validators: Vec<Validator>,
+    pub encrypted: bool,
+    pub computed: Option<String>,
+    pub nullable: bool,
+    pub indexed: bool,
+    pub unique: bool,
+}
+
+impl FieldDef {
+    pub fn new(field_type: FieldType) -> FieldDef {
+        FieldDef {
+            field_type,
+            default: None,
+            validators: Vec::new(),
+            encrypted: false,
+            computed: None,
+            nullable: false,
+            indexed: false,
+            unique: false,
+        }
+    }
+    
+    // Constrain the values the field accepts
+    pub fn validate(mut self, validator: Validator) -> FieldDef {
+        self.validators.push(validator);
+        self
+    }
+    
+    // Value stored when the field is omitted on insert. It is also part of
+    // the column DDL, so it applies to inserts made outside kooDB.
+    pub fn with_default(mut self, value: impl Into<Value>) -> FieldDef {
+        self.default = Some(value.into());
+        self
+    }
+    
+    // Store the field encrypted; see `FlexibleDatabase::set_key_provider`.
+    // Encrypted fields can't be filtered or sorted on.
+    pub fn encrypted(mut self) -> FieldDef {
+        self.encrypted = true;
+        self
+    }
+    
+    // Compute the field from an SQL expression over the other columns of
+    // the row, e.g. "price * quantity", as a generated column. It reads and
+    // filters like any other field but can't be written, and is NULL where
+    // the expression is.
+    pub fn computed(mut self, expression: &str) -> FieldDef {
+        self.computed = Some(expression.to_string());
+        self
+    }
+    
+    // Allow NULL, which becomes the default unless one is given
+    pub fn nullable(mut self) -> FieldDef {
+        self.nullable = true;
+        self
+    }
+    
+    // Index the field, as `Schema::with_index`
+    pub fn indexed(mut self) -> FieldDef {
+        self.indexed = true;
+        self
+    }
+    
+    // Refuse a value another model already has
+    pub fn unique(mut self) -> FieldDef {
+        self.unique = true;
+        self
+    }
+}
+
+impl From<FieldType> for FieldDef {
+    fn from(field_type: FieldType) -> FieldDef {
+        FieldDef::new(field_type)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum FieldType {
     Text,
     Integer,
     Real,
     Boolean,
+    // Id of a model in the named schema, enforced as a foreign key
+    Reference(String),
+    // A model of any of the listed schemas, see `ModelRef`. Unlike
+    // `Reference`, it isn't checked to exist.
+    Polymorphic(Vec<String>),
+    // Text limited to one of the listed values
+    Enum(Vec<String>),
+    // Latitude and longitude, see `GeoPoint`
+    GeoPoint,
+    // Embedding of the given number of dimensions, see `vector::encode`
+    Vector(usize),
+}
+
+impl FieldType {
+    // Name used when a schema is written out, e.g. in exports
+    pub fn name(&self) -> String {
+        match self {
+            FieldType::Text => "Text".to_string(),
+            FieldType::Integer => "Integer".to_string(),
+            FieldType::Real => "Real".to_string(),
+            FieldType::Boolean => "Boolean".to_string(),
+            FieldType::Reference(target) => format!("Reference({})", target),
+            FieldType::Polymorphic(targets) => format!("Polymorphic({})", targets.join("|")),
+            FieldType::Enum(allowed) => format!("Enum({})", allowed.join("|")),
+            FieldType::GeoPoint => "GeoPoint".to_string(),
+            FieldType::Vector(dimensions) => format!("Vector({})", dimensions),
+        }
+    }
+    
+    // Inverse of `name`
+    pub fn from_name(name: &str) -> Option<FieldType> {
+        match name {
+            "Text" => Some(FieldType::Text),
+            "Integer" => Some(FieldType::Integer),
+            "Real" => Some(FieldType::Real),
+            "Boolean" => Some(FieldType::Boolean),
+            "GeoPoint" => Some(FieldType::GeoPoint),
+            _ => {
+                if let Some(allowed) = name.strip_prefix("Enum(").and_then(|rest| rest.strip_suffix(')')) {
+                    return Some(FieldType::Enum(allowed.split('|').map(String::from).collect()));
+                }
+                if let Some(targets) = name.strip_prefix("Polymorphic(").and_then(|rest| rest.strip_suffix(')')) {
+                    return Some(FieldType::Polymorphic(targets.split('|').map(String::from).collect()));
+                }
+                if let Some(dimensions) = name.strip_prefix("Vector(").and_then(|rest| rest.strip_suffix(')')) {
+                    return dimensions.parse().ok().map(FieldType::Vector);
+                }
+                name.strip_prefix("Reference(")
+                    .and_then(|rest| rest.strip_suffix(')'))
+                    .map(|target| FieldType::Reference(target.to_string()))
+            }
+        }
+    }
 }
 
 pub struct FlexibleDatabase {
     pub conn: Connection,
     pub schemas: HashMap<String, Schema>,
+    pub(crate) telemetry: Option<Arc<FieldTelemetry>>,
+    pub(crate) statement_cache: StatementCache,
+    pub(crate) tracer: Option<Tracer>,
+    pub(crate) slow_query_threshold: Option<Duration>,
+    #[cfg(feature = "metrics")]
+    pub(crate) metrics: Metrics,
+    pub(crate) changes: Option<Arc<ChangeFeed>>,
+    pub(crate) key_provider: Option<Arc<dyn KeyProvider>>,
+    pub(crate) access_policy: Option<Arc<dyn AccessPolicy>>,
+    pub(crate) caller: CallerContext,
+    pub(crate) audit: bool,
+    pub(crate) conflict_strategy: ConflictStrategy,
+    pub(crate) read_cache: Option<Arc<ReadCache>>,
+    pub(crate) retry_policy: RetryPolicy,
+    pub(crate) projections: IndexMap<String, Arc<Projection>>,
+    // Statements recorded instead of run, during `dry_run`
+    pub(crate) dry_run: RefCell<Option<Vec<SqlPlan>>>,
+    pub(crate) write_behind: RefCell<Option<WriteBuffer>>,
+}
+
+// Buffered writes are flushed when the handle goes, as far as they can be
+impl Drop for FlexibleDatabase {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
 }
 
 impl FlexibleDatabase {
     pub fn new(db_path: &str) -> Result<FlexibleDatabase> {
-        let conn = Connection::open(db_path)?;
+        FlexibleDatabase::open_with(db_path, DatabaseOptions::default())
+    }
+    
+    // Open a database and apply connection settings such as journal mode
+    pub fn open_with(db_path: &str, options: DatabaseOptions) -> Result<FlexibleDatabase> {
+        FlexibleDatabase::from_connection(Connection::open(db_path)?, options)
+    }
+    
+    pub(crate) fn from_connection(conn: Connection, options: DatabaseOptions) -> Result<FlexibleDatabase> {
+        options.apply(&conn)?;
+        register_decrypt(&conn, None)?;
+        register_geo(&conn)?;
+        register_vector(&conn)?;
+        conn.set_prepared_statement_cache_capacity(DEFAULT_STATEMENT_CACHE_CAPACITY);
         Ok(FlexibleDatabase {
             conn,
             schemas: HashMap::new(),
+            telemetry: None,
+            statement_cache: StatementCache::new(DEFAULT_STATEMENT_CACHE_CAPACITY),
+            tracer: None,
+            slow_query_threshold: None,
+            #[cfg(feature = "metrics")]
+            metrics: Metrics::default(),
+            changes: None,
+            key_provider: None,
+            access_policy: None,
+            caller: CallerContext::default(),
+            audit: false,
+            conflict_strategy: ConflictStrategy::default(),
+            read_cache: None,
+            retry_policy: options.retry_policy,
+            projections: IndexMap::new(),
+            dry_run: RefCell::new(None),
+            write_behind: RefCell::new(None),
         })
     }
     
     // Define a ne schema/model type
     pub fn define_schema(&mut self, schema: Schema) -> Result<()> {
+        let schema = schema.materialize()?;
         self.schemas.insert(schema.name.clone(), schema.clone());
         
         // Create the table dynamically
-        let mut sql = format!("CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY", schema.name);
-        
-        for (field_name, field_type) in &schema.fields {
-            let sql_type = match field_type {
-                FieldType::Text => "TEXT",
-                FieldType::Integer => "INTEGER",
-                FieldType::Real => "REAL",
-                FieldType::Boolean => "INTEGER", // SQLite doesn't have boolean, using integer
-            };
-            
-            // Add NOT NULL constraint for all fields except id
-            sql.push_str(&format!(", {} {} NOT NULL", field_name, sql_type));
-        }
+        let sql = format!("CREATE TABLE IF NOT EXISTS {}", table_definition(&schema, &schema.name));
         
-        sql.push_str(")");
+        self.traced(&sql, 0, || Ok(((), self.conn.execute(&sql, [])?)))?;
         
-        self.conn.execute(&sql, [])?;
-        Ok(())
+        if !schema.fts_fields.is_empty() {
+            self.create_fts_index(&schema)?;
+        }
+        if schema.tenant_scoped {
+            self.create_tenant_index(&schema)?;
+        }
+        if schema.hierarchical {
+            self.create_parent_index(&schema)?;
+        }
+        self.create_field_indexes(&schema)?;
+        if schema.history {
+            self.create_history(&schema)?;
+        }
+        self.create_join_tables(&schema)?;
+        self.refresh_changelog(&schema)?;
+        self.refresh_change_triggers(&schema)?;
+        self.refresh_append_only_triggers(&schema)?;
+        self.record_schema(&schema)
     }
     
-    // Create a new model instance
-    pub fn create_model(&self, schema_name: &str, data: HashMap<String, Value>) -> Result<i32> {
-        let _schema = self.schemas.get(schema_name)
-            .ok_or_else(|| rusqlite::Error::ExecuteReturnedResults)?;
+    // Create a new model instance and return its id. Fields left out of
+    // `data` take their schema default; omitting a field without one is an
+    // error. Schemas with a text key take the id as the "id" entry, which
+    // may be left out when the schema generates UUID ids.
+    pub fn create_model(&self, schema_name: &str, mut data: HashMap<String, Value>) -> Result<ModelId> {
+        let schema = self.schema_or_err(schema_name)?;
+        if schema.key == PrimaryKey::Integer && self.writing_behind() {
+            return Err(KooError::InvalidData(format!(
+                "'{}' has integer ids, which creates written behind can't return",
+                schema_name
+            )));
+        }
         
         let mut fields = vec![];
         let mut placeholders = vec![];
         let mut values: Vec<Value> = vec![];
         
-        for (field_name, value) in data {
+        let text_id = match schema.key {
+            PrimaryKey::Text => match data.remove("id") {
+                Some(Value::Text(id)) => Some(id),
+                Some(_) => {
+                    return Err(KooError::InvalidData(format!("'{}' ids must be text", schema_name)));
+                }
+                None if schema.uuid_ids.is_some() => schema.uuid_ids.map(|version| version.generate()),
+                None => {
+                    return Err(KooError::MissingField {
+                        schema: schema_name.to_string(),
+                        field: "id".to_string(),
+                    });
+                }
+            },
+            _ => None,
+        };
+        if let Some(id) = &text_id {
+            fields.push("id".to_string());
+            placeholders.push("?".to_string());
+            values.push(Value::Text(id.clone()));
+        }
+        
+        for field_name in schema.fields.keys() {
+            if schema.hierarchical && field_name == PARENT_FIELD {
+                data.entry(field_name.clone()).or_insert(Value::Null);
+            }
+            if schema.is_computed(field_name) {
+                if data.contains_key(field_name) {
+                    return Err(computed_write(schema, field_name));
+                }
+                continue;
+            }
+            if !data.contains_key(field_name) {
+                let default = schema.defaults.get(field_name).ok_or_else(|| KooError::MissingField {
+                    schema: schema_name.to_string(),
+                    field: field_name.clone(),
+                })?;
+                data.insert(field_name.clone(), default.clone());
+            }
+        }
+        
+        validate(schema, &data)?;
+        
+        let key_values = match &schema.key {
+            PrimaryKey::Composite(key_fields) => key_fields.iter().map(|f| data[f].clone()).collect(),
+            _ => vec![],
+        };
+        if self.access_policy.is_some() {
+            let id = match &schema.key {
+                PrimaryKey::Integer => None,
+                PrimaryKey::Text => text_id.clone().map(ModelId::Text),
+                PrimaryKey::Composite(_) => Some(ModelId::Composite(key_values.clone())),
+            };
+            self.check_create(schema_name, id, &data)?;
+        }
+        
+        // Sorted so the same field set always produces the same cached statement
+        for (field_name, value) in sorted_fields(data) {
             // Validate that field exists in schema
-            if !self.schemas.get(schema_name).unwrap().fields.contains_key(&field_name) {
-                return Err(rusqlite::Error::ExecuteReturnedResults);
+            if !schema.fields.contains_key(&field_name) {
+                return Err(KooError::UnknownField {
+                    schema: schema_name.to_string(),
+                    field: field_name,
+                });
             }
             
+            values.push(self.seal_field(schema, &field_name, value)?);
             fields.push(field_name);
             placeholders.push("?".to_string());
-            values.push(value);
         }
         
         let sql = format!(
@@ -88,128 +1064,956 @@ impl FlexibleDatabase {
             fields.join(", "),
             placeholders.join(", ")
         );
+        if self.planned(&sql, &values)? || self.buffered(&sql, &values)? {
+            return Ok(match &schema.key {
+                PrimaryKey::Integer => ModelId::Integer(0),
+                PrimaryKey::Text => ModelId::Text(text_id.expect("text id was checked")),
+                PrimaryKey::Composite(_) => ModelId::Composite(key_values),
+            });
+        }
         
-        self.conn.execute(&sql, rusqlite::params_from_iter(values))?;
-        let id = self.conn.last_insert_rowid() as i32;
-        Ok(id)
+        self.retrying(|| {
+            self.audit_create(schema, || {
+                self.traced(&sql, values.len(), || {
+                    Ok(((), self.prepare_cached(&sql)?.execute(rusqlite::params_from_iter(&values))?))
+                })?;
+                
+                Ok(match &schema.key {
+                    PrimaryKey::Integer => ModelId::Integer(self.conn.last_insert_rowid()),
+                    PrimaryKey::Text => ModelId::Text(text_id.clone().expect("text id was checked")),
+                    PrimaryKey::Composite(_) => ModelId::Composite(key_values.clone()),
+                })
+            })
+        })
     }
     
     // Get a model by ID
-    pub fn get_model(&self, schema_name: &str, id: i32) -> Result<Option<Model>> {
-        let _schema = self.schemas.get(schema_name)
-            .ok_or_else(|| rusqlite::Error::ExecuteReturnedResults)?;
+    pub fn get_model(&self, schema_name: &str, id: impl Into<ModelId>) -> Result<Option<Model>> {
+        let schema = self.schema_or_err(schema_name)?;
+        let id = id.into();
+        let (key_sql, key_values) = key_filter(schema, &id)?;
         
-        let mut sql = format!("SELECT id");
-        for field_name in self.schemas.get(schema_name).unwrap().fields.keys() {
-            sql.push_str(&format!(", {}", field_name));
+        let sql = format!("{} WHERE {}", select_sql(schema), key_sql);
+        
+        let model = self.cached_model(schema_name, &id, || {
+            self.traced(&sql, key_values.len(), || {
+                let mut stmt = self.prepare_cached(&sql)?;
+                let mut rows = stmt.query(rusqlite::params_from_iter(&key_values))?;
+                
+                match rows.next()? {
+                    Some(row) => Ok((Some(read_model(row, schema)?), 1)),
+                    None => Ok((None, 0)),
+                }
+            })
+        })?;
+        
+        Ok(model.filter(|model| self.readable(schema_name, model)).map(|mut model| {
+            self.track_model(schema_name, &mut model);
+            model
+        }))
+    }
+    
+    // Like `get_model`, but reading only the named fields, and the key.
+    // Saves reading and decoding wide columns, like blobs, that aren't
+    // needed. With an access policy set the whole row is read, since the
+    // policy may look at any field, and then narrowed.
+    pub fn get_model_fields(&self, schema_name: &str, id: impl Into<ModelId>, field_names: &[&str]) -> Result<Option<Model>> {
+        let schema = self.schema_or_err(schema_name)?;
+        let field_names: Vec<String> = field_names.iter().map(|f| f.to_string()).collect();
+        let projected = schema.projected(&field_names)?;
+        if self.access_policy.is_some() {
+            return Ok(self.get_model(schema_name, id)?.map(|mut model| {
+                model.data.retain(|field_name, _| projected.fields.contains_key(field_name) || field_name == VERSION_COLUMN);
+                model
+            }));
         }
-        sql.push_str(&format!(" FROM {} WHERE id = ?", schema_name));
         
-        let mut stmt = self.conn.prepare(&sql)?;
-        let mut rows = stmt.query([id])?;
+        let id = id.into();
+        let (key_sql, key_values) = key_filter(&projected, &id)?;
+        let sql = format!("{} WHERE {}", select_sql(&projected), key_sql);
+        let mut models = self.cached_rows(schema_name, &sql, &key_values, || {
+            self.traced(&sql, key_values.len(), || {
+                let mut stmt = self.prepare_cached(&sql)?;
+                let mut rows = stmt.query(rusqlite::params_from_iter(&key_values))?;
+                match rows.next()? {
+                    Some(row) => Ok((vec![read_model(row, &projected)?], 1)),
+                    None => Ok((vec![], 0)),
+                }
+            })
+        })?;
+        Ok(models.pop().map(|mut model| {
+            self.track_model(schema_name, &mut model);
+            model
+        }))
+    }
+    
+    // Get several models by id with one query per chunk of ids. Models come
+    // back in the order their ids were given; ids with no model are left
+    // out.
+    pub fn get_models<I>(&self, schema_name: &str, ids: I) -> Result<Vec<Model>>
+    where
+        I: IntoIterator,
+        I::Item: Into<ModelId>,
+    {
+        let schema = self.schema_or_err(schema_name)?;
+        let ids: Vec<ModelId> = ids.into_iter().map(Into::into).collect();
+        let key_width = match &schema.key {
+            PrimaryKey::Composite(key_fields) => key_fields.len(),
+            _ => 1,
+        };
         
-        if let Some(row) = rows.next()? {
-            let mut data = HashMap::new();
-            let id: i32 = row.get(0)?;
-            
-            let mut col_index = 1; // Start from 1 because 0 is the id
-            for (field_name, field_type) in &self.schemas.get(schema_name).unwrap().fields {
-                let value = match field_type {
-                    FieldType::Text => Value::Text(row.get(col_index)?),
-                    FieldType::Integer => Value::Integer(row.get(col_index)?),
-                    FieldType::Real => Value::Real(row.get(col_index)?),
-                    FieldType::Boolean => Value::Integer(if row.get::<_, i32>(col_index)? == 0 { 0 } else { 1 }),
-                };
-                data.insert(field_name.clone(), value);
-                col_index += 1;
+        let mut found = HashMap::new();
+        for chunk in ids.chunks(MAX_BOUND_PARAMETERS / key_width) {
+            let mut params = Vec::new();
+            for id in chunk {
+                params.extend(key_filter(schema, id)?.1);
             }
             
-            Ok(Some(Model { id: Some(id), data }))
-        } else {
-            Ok(None)
+            let placeholders = vec![format!("({})", vec!["?"; key_width].join(", ")); chunk.len()];
+            let sql = match &schema.key {
+                PrimaryKey::Composite(key_fields) => format!(
+                    "{} WHERE ({}) IN (VALUES {})",
+                    select_sql(schema),
+                    key_fields.join(", "),
+                    placeholders.join(", ")
+                ),
+                _ => format!("{} WHERE id IN ({})", select_sql(schema), vec!["?"; chunk.len()].join(", ")),
+            };
+            
+            // The SQL varies with the chunk size, so it isn't worth caching
+            self.traced(&sql, params.len(), || {
+                let mut stmt = self.conn.prepare(&sql)?;
+                let mut rows = stmt.query(rusqlite::params_from_iter(&params))?;
+                let mut count = 0;
+                while let Some(row) = rows.next()? {
+                    let model = read_model(row, schema)?;
+                    let key = model.id.as_ref().expect("read models have ids").to_string();
+                    found.insert(key, model);
+                    count += 1;
+                }
+                Ok(((), count))
+            })?;
         }
+        
+        let mut models = Vec::new();
+        for id in &ids {
+            if let Some(model) = found.get(&id.to_string())
+                && self.readable(schema_name, model)
+            {
+                let mut model = model.clone();
+                self.track_model(schema_name, &mut model);
+                models.push(model);
+            }
+        }
+        Ok(models)
     }
     
     // Get all models of a type
     pub fn get_all_models(&self, schema_name: &str) -> Result<Vec<Model>> {
-        let _schema = self.schemas.get(schema_name)
-            .ok_or_else(|| rusqlite::Error::ExecuteReturnedResults)?;
-        
-        let mut sql = format!("SELECT id");
-        for field_name in self.schemas.get(schema_name).unwrap().fields.keys() {
-            sql.push_str(&format!(", {}", field_name));
-        }
-        sql.push_str(&format!(" FROM {}", schema_name));
+        let schema = self.schema_or_err(schema_name)?;
         
-        let mut stmt = self.conn.prepare(&sql)?;
-        let mut rows = stmt.query([])?;
-        
-        let mut models = Vec::new();
-        
-        while let Some(row) = rows.next()? {
-            let mut data = HashMap::new();
-            let id: i32 = row.get(0)?;
+        let sql = select_sql(schema);
+        let models = self.traced(&sql, 0, || {
+            let mut stmt = self.prepare_cached(&sql)?;
+            let mut rows = stmt.query([])?;
             
-            let mut col_index = 1; // Start from 1 because 0 is the id
-            for (field_name, field_type) in &self.schemas.get(schema_name).unwrap().fields {
-                let value = match field_type {
-                    FieldType::Text => Value::Text(row.get(col_index)?),
-                    FieldType::Integer => Value::Integer(row.get(col_index)?),
-                    FieldType::Real => Value::Real(row.get(col_index)?),
-                    FieldType::Boolean => Value::Integer(if row.get::<_, i32>(col_index)? == 0 { 0 } else { 1 }),
-                };
-                data.insert(field_name.clone(), value);
-                col_index += 1;
-            }
+            let mut models = Vec::new();
             
-            models.push(Model { id: Some(id), data });
-        }
+            while let Some(row) = rows.next()? {
+                let mut model = read_model(row, schema)?;
+                if !self.readable(schema_name, &model) {
+                    continue;
+                }
+                self.track_model(schema_name, &mut model);
+                models.push(model);
+            }
+            let count = models.len();
+            Ok((models, count))
+        })?;
         
         Ok(models)
     
     }
-    // Update a model
-    pub fn update_model(&self, schema_name: &str, id: i32, data: HashMap<String, Value>) -> Result<bool> {
-        let _schema = self.schemas.get(schema_name)
-            .ok_or_else(|| rusqlite::Error::ExecuteReturnedResults)?;
+    // Update a model. For versioned schemas `data` must hold the "version"
+    // the model was read at.
+    pub fn update_model(&self, schema_name: &str, id: impl Into<ModelId>, mut data: HashMap<String, Value>) -> Result<bool> {
+        let schema = self.schema_or_err(schema_name)?;
+        check_mutable(schema)?;
+        if schema.versioned && self.writing_behind() {
+            return Err(KooError::InvalidData(format!(
+                "'{}' is versioned, and updates written behind can't be checked for stale versions",
+                schema_name
+            )));
+        }
+        let id = id.into();
+        let (mut key_sql, mut key_values) = key_filter(schema, &id)?;
         
         let mut sets = vec![];
         let mut values: Vec<Value> = vec![];
         
-        for (field_name, value) in data {
+        let expected_version = if schema.versioned {
+            match data.remove(VERSION_COLUMN) {
+                Some(Value::Integer(version)) => Some(version),
+                Some(_) => {
+                    return Err(KooError::InvalidData(format!(
+                        "'{}.{}' must be an integer",
+                        schema_name, VERSION_COLUMN
+                    )));
+                }
+                None => {
+                    return Err(KooError::MissingField {
+                        schema: schema_name.to_string(),
+                        field: VERSION_COLUMN.to_string(),
+                    });
+                }
+            }
+        } else {
+            None
+        };
+        
+        if let Some(field_name) = data.keys().find(|f| schema.is_computed(f)) {
+            return Err(computed_write(schema, field_name));
+        }
+        validate(schema, &data)?;
+        if !self.check_change(schema_name, &id, Some(&data))? {
+            return Ok(false);
+        }
+        
+        for (field_name, value) in sorted_fields(data) {
             // Validate that field exists in schema
-            if !self.schemas.get(schema_name).unwrap().fields.contains_key(&field_name) {
-                return Err(rusqlite::Error::ExecuteReturnedResults);
+            if !schema.fields.contains_key(&field_name) {
+                return Err(KooError::UnknownField {
+                    schema: schema_name.to_string(),
+                    field: field_name,
+                });
             }
             
+            values.push(self.seal_field(schema, &field_name, value)?);
             sets.push(format!("{} = ?", field_name));
-            values.push(value);
         }
         
-        // Add the ID to the values for the WHERE clause
-        values.push(Value::Integer(id as i64));
-        
         if sets.is_empty() {
             return Ok(false);
         }
         
+        if let Some(version) = expected_version {
+            sets.push(format!("{0} = {0} + 1", VERSION_COLUMN));
+            key_sql.push_str(&format!(" AND {} = ?", VERSION_COLUMN));
+            key_values.push(Value::Integer(version));
+        }
+        
+        // Add the ID to the values for the WHERE clause
+        values.extend(key_values);
+        
         let sql = format!(
-            "UPDATE {} SET {} WHERE id = ?",
+            "UPDATE {} SET {} WHERE {}",
             schema_name,
-            sets.join(", ")
+            sets.join(", "),
+            key_sql
         );
+        if self.planned(&sql, &values)? || self.buffered(&sql, &values)? {
+            return Ok(true);
+        }
         
-        let rows_affected = self.conn.execute(&sql, rusqlite::params_from_iter(values))?;
+        let rows_affected = self.retrying(|| {
+            self.audit_change(schema, &id, AuditAction::Update, || {
+                self.traced(&sql, values.len(), || {
+                    let rows_affected = self.prepare_cached(&sql)?.execute(rusqlite::params_from_iter(&values))?;
+                    Ok((rows_affected, rows_affected))
+                })
+            })
+        })?;
+        
+        // Nothing matched: either the model is gone or its version moved on
+        if let (0, Some(expected)) = (rows_affected, expected_version) {
+            let (key_sql, key_values) = key_filter(schema, &id)?;
+            let sql = format!("SELECT EXISTS (SELECT 1 FROM {} WHERE {})", schema_name, key_sql);
+            let exists: bool = self.conn.query_row(&sql, rusqlite::params_from_iter(&key_values), |row| row.get(0))?;
+            if exists {
+                return Err(KooError::StaleVersion {
+                    schema: schema_name.to_string(),
+                    id,
+                    expected,
+                });
+            }
+        }
         Ok(rows_affected > 0)
     }
     
     // Delete a model
-    pub fn delete_model(&self, schema_name: &str, id: i32) -> Result<bool> {
-        let _schema = self.schemas.get(schema_name)
-            .ok_or_else(|| rusqlite::Error::ExecuteReturnedResults)?;
+    pub fn delete_model(&self, schema_name: &str, id: impl Into<ModelId>) -> Result<bool> {
+        let schema = self.schema_or_err(schema_name)?;
+        check_mutable(schema)?;
+        let id = id.into();
+        if !self.check_change(schema_name, &id, None)? {
+            return Ok(false);
+        }
+        let (key_sql, key_values) = key_filter(schema, &id)?;
         
-        let sql = format!("DELETE FROM {} WHERE id = ?", schema_name);
-        let rows_affected = self.conn.execute(&sql, [id])?;
+        let sql = format!("DELETE FROM {} WHERE {}", schema_name, key_sql);
+        if self.planned(&sql, &key_values)? {
+            return Ok(true);
+        }
+        let rows_affected = self.retrying(|| {
+            self.audit_change(schema, &id, AuditAction::Delete, || {
+                self.unlink_related(schema_name, &id)?;
+                self.traced(&sql, key_values.len(), || {
+                    let rows_affected = self.prepare_cached(&sql)?.execute(rusqlite::params_from_iter(&key_values))?;
+                    Ok((rows_affected, rows_affected))
+                })
+            })
+        })?;
         Ok(rows_affected > 0)
     }
-}
\ No newline at end of file
+    
+    // Count the models of a type
+    pub fn count(&self, schema_name: &str) -> Result<i64> {
+        self.schema_or_err(schema_name)?;
+        
+        let sql = format!("SELECT COUNT(*) FROM {}", schema_name);
+        self.traced(&sql, 0, || Ok((self.prepare_cached(&sql)?.query_row([], |row| row.get(0))?, 1)))
+    }
+    
+    // Approximate model count that avoids a full table scan. Uses the row
+    // count recorded by ANALYZE in sqlite_stat1 when available, otherwise
+    // MAX(rowid), which overestimates after deletes.
+    pub fn estimated_count(&self, schema_name: &str) -> Result<i64> {
+        self.schema_or_err(schema_name)?;
+        
+        let has_stats: bool = self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'sqlite_stat1')",
+            [],
+            |row| row.get(0),
+        )?;
+        if has_stats {
+            let stat: Option<String> = self.conn.query_row(
+                "SELECT stat FROM sqlite_stat1 WHERE tbl = ? LIMIT 1",
+                [schema_name],
+                |row| row.get(0),
+            ).optional()?;
+            // Every entry for a table starts with its row count
+            if let Some(rows) = stat.as_deref().and_then(|s| s.split(' ').next()).and_then(|n| n.parse().ok()) {
+                return Ok(rows);
+            }
+        }
+        
+        let sql = format!("SELECT COALESCE(MAX(rowid), 0) FROM {}", schema_name);
+        Ok(self.conn.query_row(&sql, [], |row| row.get(0))?)
+    }
+    
+    // Look up a registered schema, failing if it hasn't been defined
+    pub(crate) fn schema_or_err(&self, schema_name: &str) -> Result<&Schema> {
+        self.schemas.get(schema_name)
+            .ok_or_else(|| KooError::SchemaNotFound(schema_name.to_string()))
+    }
+    
+    // The model stored under `id`, without the access policy or telemetry
+    // that reads through the public API get
+    pub(crate) fn stored_model(&self, schema: &Schema, id: &ModelId) -> Result<Option<Model>> {
+        let (key_sql, key_values) = key_filter(schema, id)?;
+        let sql = format!("{} WHERE {}", select_sql(schema), key_sql);
+        let mut stmt = self.prepare_cached(&sql)?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&key_values))?;
+        match rows.next()? {
+            Some(row) => Ok(Some(read_model(row, schema)?)),
+            None => Ok(None),
+        }
+    }
+    
+    // Run `f` inside a transaction. On failure the transaction is rolled back
+    // and the in-memory schema registry is restored to its previous state.
+    // A savepoint is used, so this nests inside an open transaction such as
+    // the one a migration runs in.
+    pub(crate) fn in_transaction<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let schemas = self.schemas.clone();
+        let outermost = self.conn.is_autocommit();
+        // Writes buffered before the transaction aren't part of it
+        if outermost {
+            self.flush()?;
+        }
+        #[cfg(feature = "metrics")]
+        let started = std::time::Instant::now();
+        self.conn.execute_batch("SAVEPOINT koo_transaction")?;
+        
+        // A failed COMMIT (e.g. deferred constraint violations) leaves the
+        // transaction open, so it is rolled back like any other failure
+        let result = match f(self).and_then(|value| {
+            if outermost {
+                self.retrying_commit("RELEASE koo_transaction")?;
+            } else {
+                self.conn.execute_batch("RELEASE koo_transaction")?;
+            }
+            Ok(value)
+        }) {
+            Ok(value) => Ok(value),
+            Err(err) => {
+                // A full ROLLBACK when possible, so rollback hooks see it
+                if outermost && !self.conn.is_autocommit() {
+                    let _ = self.conn.execute_batch("ROLLBACK");
+                } else if !self.conn.is_autocommit() {
+                    let _ = self.conn.execute_batch("ROLLBACK TO koo_transaction; RELEASE koo_transaction");
+                }
+                self.schemas = schemas;
+                // Reads cached since may show rolled back writes
+                self.clear_read_cache();
+                Err(err)
+            }
+        };
+        #[cfg(feature = "metrics")]
+        if outermost {
+            self.metrics.record_transaction(started.elapsed(), result.is_ok());
+        }
+        result
+    }
+}
+
+// Data entries ordered by field name
+pub(crate) fn sorted_fields(data: HashMap<String, Value>) -> Vec<(String, Value)> {
+    let mut entries: Vec<(String, Value)> = data.into_iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries
+}
+
+// Error for data that sets a computed field
+pub(crate) fn computed_write(schema: &Schema, field_name: &str) -> KooError {
+    KooError::InvalidData(format!(
+        "'{}.{}' is computed, so it can't be written",
+        schema.name, field_name
+    ))
+}
+
+// Declared type of the column holding a field
+pub(crate) fn sql_type(field_type: &FieldType) -> &'static str {
+    match field_type {
+        FieldType::Text | FieldType::Enum(_) | FieldType::GeoPoint | FieldType::Polymorphic(_) => "TEXT",
+        FieldType::Integer => "INTEGER",
+        FieldType::Real => "REAL",
+        FieldType::Boolean => "INTEGER", // SQLite doesn't have boolean, using integer
+        FieldType::Reference(_) => "INTEGER",
+        FieldType::Vector(_) => "BLOB",
+    }
+}
+
+// Column DDL for a field, without the leading comma
+pub(crate) fn column_definition(field_name: &str, field_type: &FieldType, default: Option<&Value>) -> String {
+    // Add NOT NULL constraint for all fields except id
+    let mut sql = format!("{} {} NOT NULL", field_name, sql_type(field_type));
+    
+    if let Some(default) = default {
+        sql.push_str(&format!(" DEFAULT {}", sql_literal(default)));
+    }
+    if let FieldType::Reference(target) = field_type {
+        // Foreign keys can't name another database; SQLite looks the target
+        // up in the database holding the table
+        let target = target.split_once('.').map_or(target.as_str(), |(_, table)| table);
+        sql.push_str(&format!(" REFERENCES {}(id)", target));
+    }
+    sql
+}
+
+// Column DDL for a computed field. Virtual, so it takes no space and can be
+// added to an existing table.
+pub(crate) fn computed_column_definition(field_name: &str, field_type: &FieldType, expression: &str) -> String {
+    format!(
+        "{} {} GENERATED ALWAYS AS ({}) VIRTUAL",
+        field_name,
+        sql_type(field_type),
+        expression
+    )
+}
+
+// Table name and column list for a schema's CREATE TABLE statement
+pub(crate) fn table_definition(schema: &Schema, table: &str) -> String {
+    let mut columns = match &schema.key {
+        PrimaryKey::Integer => vec!["id INTEGER PRIMARY KEY".to_string()],
+        PrimaryKey::Text => vec!["id TEXT PRIMARY KEY NOT NULL".to_string()],
+        PrimaryKey::Composite(_) => vec![],
+    };
+    
+    for (field_name, field_type) in &schema.fields {
+        // A DEFAULT or CHECK would see ciphertext rather than the value
+        let encrypted = schema.encrypted_fields.contains(field_name);
+        let default = schema.defaults.get(field_name).filter(|_| !encrypted);
+        let mut column = match schema.computed_fields.get(field_name) {
+            Some(expression) => computed_column_definition(field_name, field_type, expression),
+            None => column_definition(field_name, field_type, default),
+        };
+        // Roots have no parent, so it can be NULL
+        if schema.is_nullable(field_name) || (schema.hierarchical && field_name == PARENT_FIELD) {
+            column = column.replacen(" NOT NULL", "", 1);
+        }
+        if schema.sql_checks && !encrypted {
+            for check in check_constraints(schema, field_name, field_type) {
+                column.push_str(&format!(" CHECK ({})", check));
+            }
+        }
+        columns.push(column);
+    }
+    
+    if schema.versioned {
+        columns.push(format!("{} INTEGER NOT NULL DEFAULT 1", VERSION_COLUMN));
+    }
+    
+    if let PrimaryKey::Composite(key_fields) = &schema.key {
+        columns.push(format!("PRIMARY KEY ({})", key_fields.join(", ")));
+    }
+    
+    format!("{} ({})", table, columns.join(", "))
+}
+
+// Whether a default can be stored in a field of the given type
+fn default_matches(value: &Value, field_type: &FieldType) -> bool {
+    match field_type {
+        FieldType::Text => matches!(value, Value::Text(_)),
+        FieldType::Enum(allowed) => matches!(value, Value::Text(s) if allowed.contains(s)),
+        FieldType::Integer | FieldType::Reference(_) => matches!(value, Value::Integer(_)),
+        // NaN and the infinities have no SQL literal for the DDL
+        FieldType::Real => matches!(value, Value::Real(f) if f.is_finite()) || matches!(value, Value::Integer(_)),
+        FieldType::Boolean => matches!(value, Value::Integer(0 | 1)),
+        FieldType::GeoPoint => GeoPoint::from_value(value).is_some(),
+        FieldType::Polymorphic(targets) => ModelRef::from_value(value).is_some_and(|r| targets.contains(&r.schema)),
+        FieldType::Vector(dimensions) => is_vector(value, *dimensions),
+    }
+}
+
+// Render a value as an SQL literal, for DDL where parameters aren't allowed
+pub(crate) fn sql_literal(value: &Value) -> String {
+    match value {
+        Value::Null => "NULL".to_string(),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(f) => format!("{:?}", f),
+        Value::Text(s) => format!("'{}'", s.replace('\'', "''")),
+        Value::Blob(b) => {
+            let hex: String = b.iter().map(|byte| format!("{:02x}", byte)).collect();
+            format!("X'{}'", hex)
+        }
+    }
+}
+
+// Column maintained by `Schema::with_versioning`
+pub(crate) const VERSION_COLUMN: &str = "version";
+
+// Most parameters one statement binds; SQLite builds before 3.32 refuse
+// more than 999
+pub(crate) const MAX_BOUND_PARAMETERS: usize = 999;
+
+// Column holding the SQLite rowid: the id itself for integer keys
+pub(crate) fn row_key(schema: &Schema) -> &'static str {
+    match schema.key {
+        PrimaryKey::Integer => "id",
+        _ => "rowid",
+    }
+}
+
+// Columns read by `read_model`: the rowid, the text id if there is one,
+// every schema field, then the version column of versioned schemas. They
+// are qualified by the table name, and encrypted fields are decrypted.
+pub(crate) fn select_columns(schema: &Schema) -> Vec<String> {
+    let table = &schema.name;
+    let mut columns = vec![format!("{}.{}", table, row_key(schema))];
+    if schema.key == PrimaryKey::Text {
+        columns.push(format!("{}.id", table));
+    }
+    for field_name in schema.fields.keys() {
+        if schema.encrypted_fields.contains(field_name) {
+            columns.push(format!("{}({}.{})", DECRYPT_FUNCTION, table, field_name));
+        } else {
+            columns.push(format!("{}.{}", table, field_name));
+        }
+    }
+    if schema.versioned {
+        columns.push(format!("{}.{}", table, VERSION_COLUMN));
+    }
+    columns
+}
+
+// SELECT statement listing the `select_columns` of a schema
+pub(crate) fn select_sql(schema: &Schema) -> String {
+    format!("SELECT {} FROM {}", select_columns(schema).join(", "), schema.name)
+}
+
+// WHERE condition and parameters matching one model by its key
+pub(crate) fn key_filter(schema: &Schema, id: &ModelId) -> Result<(String, Vec<Value>)> {
+    match (&schema.key, id) {
+        (PrimaryKey::Integer, ModelId::Integer(id)) => Ok(("id = ?".to_string(), vec![Value::Integer(*id)])),
+        (PrimaryKey::Text, ModelId::Text(id)) => Ok(("id = ?".to_string(), vec![Value::Text(id.clone())])),
+        (PrimaryKey::Composite(key_fields), ModelId::Composite(values)) if key_fields.len() == values.len() => {
+            let clauses: Vec<String> = key_fields.iter().map(|f| format!("{} = ?", f)).collect();
+            Ok((clauses.join(" AND "), values.clone()))
+        }
+        _ => Err(KooError::InvalidData(format!(
+            "{} is not a valid id for '{}'",
+            id, schema.name
+        ))),
+    }
+}
+
+// Build a model from a row produced by `select_sql`
+pub(crate) fn read_model(row: &Row, schema: &Schema) -> Result<Model> {
+    let mut data = HashMap::new();
+    let rowid: i64 = row.get(0)?;
+    let (mut id, first_field) = match schema.key {
+        PrimaryKey::Integer => (Some(ModelId::Integer(rowid)), 1),
+        PrimaryKey::Text => (Some(ModelId::Text(row.get(1)?)), 2),
+        PrimaryKey::Composite(_) => (None, 1),
+    };
+    
+    for (col_index, (field_name, field_type)) in (first_field..).zip(&schema.fields) {
+        // Whatever the expression gives, NULL included
+        if schema.is_computed(field_name) {
+            data.insert(field_name.clone(), row.get(col_index)?);
+            continue;
+        }
+        // Columns of adopted tables may be nullable
+        let value = match field_type {
+            FieldType::Text | FieldType::Enum(_) | FieldType::GeoPoint | FieldType::Polymorphic(_) => {
+                row.get::<_, Option<String>>(col_index)?.map_or(Value::Null, Value::Text)
+            }
+            FieldType::Integer | FieldType::Reference(_) => {
+                row.get::<_, Option<i64>>(col_index)?.map_or(Value::Null, Value::Integer)
+            }
+            FieldType::Real => row.get::<_, Option<f64>>(col_index)?.map_or(Value::Null, Value::Real),
+            FieldType::Boolean => row
+                .get::<_, Option<i32>>(col_index)?
+                .map_or(Value::Null, |b| Value::Integer(if b == 0 { 0 } else { 1 })),
+            FieldType::Vector(_) => row.get::<_, Option<Vec<u8>>>(col_index)?.map_or(Value::Null, Value::Blob),
+        };
+        data.insert(field_name.clone(), value);
+    }
+    
+    if let PrimaryKey::Composite(key_fields) = &schema.key {
+        id = Some(ModelId::Composite(key_fields.iter().map(|f| data[f].clone()).collect()));
+    }
+    if schema.versioned {
+        let version: i64 = row.get(first_field + schema.fields.len())?;
+        data.insert(VERSION_COLUMN.to_string(), Value::Integer(version));
+    }
+    Ok(Model::new(id, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::{Direction, Op, Query};
+    
+    fn numbers(count: i64) -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let fields = HashMap::from([("n".to_string(), FieldType::Integer)]);
+        db.define_schema(Schema::new("t", fields)).unwrap();
+        for n in 0..count {
+            db.create_model("t", HashMap::from([("n".to_string(), Value::Integer(n))])).unwrap();
+        }
+        db
+    }
+    
+    #[test]
+    fn estimated_counts_use_the_analyzed_row_count() {
+        let db = numbers(10);
+        db.conn.execute_batch("CREATE INDEX t_n ON t (n)").unwrap();
+        db.conn.execute_batch("DELETE FROM t WHERE n < 3").unwrap();
+        assert_eq!(db.count("t").unwrap(), 7);
+        // Without statistics the highest rowid stands in
+        assert_eq!(db.estimated_count("t").unwrap(), 10);
+    
+        db.conn.execute_batch("ANALYZE").unwrap();
+        assert_eq!(db.estimated_count("t").unwrap(), 7);
+    }
+    
+    #[test]
+    fn estimated_counts_of_empty_and_unknown_schemas() {
+        let db = numbers(0);
+        assert_eq!(db.estimated_count("t").unwrap(), 0);
+        assert!(matches!(db.estimated_count("missing"), Err(KooError::SchemaNotFound(_))));
+    }
+    
+    fn with_default(field_type: FieldType, default: impl Into<Value>) -> Schema {
+        Schema::new("t", HashMap::new()).field("x", FieldDef::new(field_type).with_default(default))
+    }
+    
+    #[test]
+    fn omitted_fields_take_their_defaults() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("t", HashMap::from([("name".to_string(), FieldType::Text)]))
+            .field("score", FieldDef::new(FieldType::Real).with_default(1.5))
+            .field("rank", FieldDef::new(FieldType::Integer).with_default(0))
+            .field("label", FieldDef::new(FieldType::Text).with_default("it's".to_string()));
+        db.define_schema(schema).unwrap();
+        
+        let id = db.create_model("t", HashMap::from([("name".to_string(), Value::Text("Ada".to_string()))])).unwrap();
+        let model = db.get_model("t", id).unwrap().unwrap();
+        assert_eq!(model.data["score"], Value::Real(1.5));
+        assert_eq!(model.data["rank"], Value::Integer(0));
+        assert_eq!(model.data["label"], Value::Text("it's".to_string()));
+        
+        // Raw inserts get them from the DDL
+        db.conn.execute_batch("INSERT INTO t (name) VALUES ('Bob')").unwrap();
+        let model = db.get_model("t", 2).unwrap().unwrap();
+        assert_eq!(model.data["score"], Value::Real(1.5));
+    }
+    
+    #[test]
+    fn fields_without_defaults_are_still_required() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(with_default(FieldType::Integer, 0).field("name", FieldDef::new(FieldType::Text))).unwrap();
+        let result = db.create_model("t", HashMap::new());
+        assert!(matches!(result, Err(KooError::MissingField { field, .. }) if field == "name"));
+    }
+    
+    #[test]
+    fn defaults_must_fit_their_field() {
+        assert!(with_default(FieldType::Integer, "zero".to_string()).materialize().is_err());
+        assert!(with_default(FieldType::Boolean, 2).materialize().is_err());
+        assert!(with_default(FieldType::Enum(vec!["a".to_string()]), "b".to_string()).materialize().is_err());
+        assert!(with_default(FieldType::Real, 1).materialize().is_ok());
+    }
+    
+    #[test]
+    fn non_finite_real_defaults_are_refused() {
+        for default in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let result = with_default(FieldType::Real, default).materialize();
+            assert!(matches!(result, Err(KooError::InvalidSchema(_))), "{}", default);
+        }
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        assert!(db.define_schema(with_default(FieldType::Real, f64::NAN)).is_err());
+    }
+    
+    fn uuid_notes(version: UuidVersion) -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("notes", HashMap::from([("body".to_string(), FieldType::Text)])).with_uuid_ids(version)).unwrap();
+        db
+    }
+    
+    fn note(body: &str) -> HashMap<String, Value> {
+        HashMap::from([("body".to_string(), Value::Text(body.to_string()))])
+    }
+    
+    #[test]
+    fn uuid_keyed_schemas_generate_their_ids() {
+        let db = uuid_notes(UuidVersion::V4);
+        let id = db.create_model("notes", note("a")).unwrap();
+        let ModelId::Text(text) = &id else { panic!("{:?} is not a text id", id) };
+        assert_eq!(uuid::Uuid::parse_str(text).unwrap().get_version_num(), 4);
+        assert_eq!(db.get_model("notes", id).unwrap().unwrap().data["body"], Value::Text("a".to_string()));
+    }
+    
+    #[test]
+    fn v7_ids_sort_by_creation() {
+        let db = uuid_notes(UuidVersion::V7);
+        let ids: Vec<ModelId> = (0..20).map(|n| db.create_model("notes", note(&n.to_string())).unwrap()).collect();
+        let mut sorted = ids.clone();
+        sorted.sort_by_key(|id| id.to_string());
+        assert_eq!(sorted, ids);
+    }
+    
+    #[test]
+    fn uuid_keyed_schemas_still_take_given_ids() {
+        let db = uuid_notes(UuidVersion::V4);
+        let mut data = note("a");
+        data.insert("id".to_string(), Value::Text("imported".to_string()));
+        assert_eq!(db.create_model("notes", data).unwrap(), ModelId::Text("imported".to_string()));
+    }
+    
+    #[test]
+    fn generated_uuid_ids_need_a_text_key() {
+        let schema = Schema::new("notes", HashMap::new()).with_uuid_ids(UuidVersion::V4).with_key(PrimaryKey::Integer);
+        assert!(matches!(schema.materialize(), Err(KooError::InvalidSchema(_))));
+    }
+    
+    fn versioned_notes() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("notes", HashMap::from([("body".to_string(), FieldType::Text)])).with_versioning()).unwrap();
+        db.create_model("notes", note("first")).unwrap();
+        db
+    }
+    
+    fn at_version(body: &str, version: i64) -> HashMap<String, Value> {
+        let mut data = note(body);
+        data.insert(VERSION_COLUMN.to_string(), Value::Integer(version));
+        data
+    }
+    
+    #[test]
+    fn versions_start_at_one_and_are_bumped_by_updates() {
+        let db = versioned_notes();
+        assert_eq!(db.get_model("notes", 1).unwrap().unwrap().data[VERSION_COLUMN], Value::Integer(1));
+        assert!(db.update_model("notes", 1, at_version("second", 1)).unwrap());
+        let model = db.get_model("notes", 1).unwrap().unwrap();
+        assert_eq!(model.data[VERSION_COLUMN], Value::Integer(2));
+        assert_eq!(model.data["body"], Value::Text("second".to_string()));
+    }
+    
+    #[test]
+    fn updates_at_an_old_version_are_stale() {
+        let db = versioned_notes();
+        db.update_model("notes", 1, at_version("second", 1)).unwrap();
+        let result = db.update_model("notes", 1, at_version("lost", 1));
+        assert!(matches!(result, Err(KooError::StaleVersion { expected: 1, .. })));
+        assert_eq!(db.get_model("notes", 1).unwrap().unwrap().data["body"], Value::Text("second".to_string()));
+    
+        // A missing model isn't stale, just not there to update
+        assert!(!db.update_model("notes", 2, at_version("none", 1)).unwrap());
+    }
+    
+    #[test]
+    fn versioned_updates_need_the_version() {
+        let db = versioned_notes();
+        let result = db.update_model("notes", 1, note("second"));
+        assert!(matches!(result, Err(KooError::MissingField { field, .. }) if field == VERSION_COLUMN));
+        let mut data = note("second");
+        data.insert(VERSION_COLUMN.to_string(), Value::Text("1".to_string()));
+        assert!(matches!(db.update_model("notes", 1, data), Err(KooError::InvalidData(_))));
+    }
+    
+    #[test]
+    fn versioned_schemas_cannot_declare_a_version_field() {
+        let schema = Schema::new("notes", HashMap::from([(VERSION_COLUMN.to_string(), FieldType::Integer)])).with_versioning();
+        assert!(matches!(schema.materialize(), Err(KooError::InvalidSchema(_))));
+    }
+    
+    #[test]
+    fn get_models_keeps_the_requested_order() {
+        let db = numbers(5);
+        let models = db.get_models("t", [4, 1, 99, 3]).unwrap();
+        let ids: Vec<ModelId> = models.into_iter().map(|model| model.id.unwrap()).collect();
+        assert_eq!(ids, [ModelId::Integer(4), ModelId::Integer(1), ModelId::Integer(3)]);
+        assert!(db.get_models("t", Vec::<i64>::new()).unwrap().is_empty());
+    }
+    
+    #[test]
+    fn get_models_splits_long_id_lists_into_chunks() {
+        let db = numbers(MAX_BOUND_PARAMETERS as i64 + 10);
+        let ids: Vec<i64> = (1..=MAX_BOUND_PARAMETERS as i64 + 10).rev().collect();
+        let models = db.get_models("t", ids.clone()).unwrap();
+        assert_eq!(models.len(), ids.len());
+        assert_eq!(models[0].id, Some(ModelId::Integer(ids[0])));
+    }
+    
+    #[test]
+    fn get_models_reads_composite_keys() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("pairs", HashMap::from([("a".to_string(), FieldType::Integer), ("b".to_string(), FieldType::Text)]))
+            .with_key(PrimaryKey::Composite(vec!["a".to_string(), "b".to_string()]));
+        db.define_schema(schema).unwrap();
+        for (a, b) in [(1, "x"), (1, "y"), (2, "x")] {
+            let data = HashMap::from([("a".to_string(), Value::Integer(a)), ("b".to_string(), Value::Text(b.to_string()))]);
+            db.create_model("pairs", data).unwrap();
+        }
+        let key = |a: i64, b: &str| ModelId::Composite(vec![Value::Integer(a), Value::Text(b.to_string())]);
+        let models = db.get_models("pairs", [key(2, "x"), key(1, "z"), key(1, "x")]).unwrap();
+        let ids: Vec<ModelId> = models.into_iter().map(|model| model.id.unwrap()).collect();
+        assert_eq!(ids, [key(2, "x"), key(1, "x")]);
+    }
+    
+    #[test]
+    fn columns_follow_the_declared_field_order() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let names = ["zeta", "alpha", "mu", "beta", "omega", "delta"];
+        let schema = names.iter().fold(Schema::new("t", []), |schema, name| schema.field(name, FieldType::Integer));
+        db.define_schema(schema).unwrap();
+        
+        let mut stmt = db.conn.prepare("SELECT name FROM pragma_table_info('t') WHERE name != 'id'").unwrap();
+        let columns: Vec<String> = stmt.query_map([], |row| row.get(0)).unwrap().collect::<rusqlite::Result<_>>().unwrap();
+        assert_eq!(columns, names);
+        let selected = select_columns(&db.schemas["t"]);
+        assert_eq!(selected[1..], names.map(|name| format!("t.{}", name)));
+        let exported: Vec<String> = crate::export::schema_to_json(&db.schemas["t"])["fields"].as_object().unwrap().keys().cloned().collect();
+        assert_eq!(exported, names);
+    }
+    
+    #[test]
+    fn get_model_fields_reads_only_the_named_fields() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("files", [("name".to_string(), FieldType::Text), ("size".to_string(), FieldType::Integer)])
+            .with_versioning();
+        db.define_schema(schema).unwrap();
+        let data = HashMap::from([("name".to_string(), Value::Text("a".to_string())), ("size".to_string(), Value::Integer(3))]);
+        let id = db.create_model("files", data).unwrap();
+    
+        let model = db.get_model_fields("files", id.clone(), &["size"]).unwrap().unwrap();
+        assert_eq!(model.id, Some(id.clone()));
+        assert_eq!(model.data, HashMap::from([("size".to_string(), Value::Integer(3)), (VERSION_COLUMN.to_string(), Value::Integer(1))]));
+        let model = db.get_model_fields("files", id.clone(), &["id"]).unwrap().unwrap();
+        assert!(!model.data.contains_key("name") && !model.data.contains_key("size"));
+    
+        assert!(db.get_model_fields("files", 9, &["size"]).unwrap().is_none());
+        assert!(matches!(db.get_model_fields("files", id, &["owner"]), Err(KooError::UnknownField { .. })));
+    }
+    
+    fn order_lines() -> Schema {
+        Schema::new("lines", [("price".to_string(), FieldType::Integer)])
+            .field("quantity", FieldDef::new(FieldType::Integer).nullable())
+            .field("total", FieldDef::new(FieldType::Integer).computed("price * quantity"))
+    }
+    
+    fn line(price: i64, quantity: Value) -> HashMap<String, Value> {
+        HashMap::from([("price".to_string(), Value::Integer(price)), ("quantity".to_string(), quantity)])
+    }
+    
+    #[test]
+    fn computed_fields_follow_the_row() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(order_lines()).unwrap();
+        let id = db.create_model("lines", line(3, Value::Integer(4))).unwrap();
+        assert_eq!(db.get_model("lines", id.clone()).unwrap().unwrap().data["total"], Value::Integer(12));
+        
+        db.update_model("lines", id.clone(), line(5, Value::Integer(4))).unwrap();
+        assert_eq!(db.get_model("lines", id.clone()).unwrap().unwrap().data["total"], Value::Integer(20));
+        // NULL where the expression is, even though the field isn't nullable
+        db.update_model("lines", id.clone(), line(5, Value::Null)).unwrap();
+        assert_eq!(db.get_model("lines", id).unwrap().unwrap().data["total"], Value::Null);
+    }
+    
+    #[test]
+    fn computed_fields_can_be_queried() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(order_lines()).unwrap();
+        for (price, quantity) in [(3, 4), (10, 1), (2, 2)] {
+            db.create_model("lines", line(price, Value::Integer(quantity))).unwrap();
+        }
+        let query = Query::new("lines").filter("total", Op::Gt, 5).order_by("total", Direction::Desc);
+        let totals: Vec<Value> = db.find(&query).unwrap().into_iter().map(|model| model.data["total"].clone()).collect();
+        assert_eq!(totals, [Value::Integer(12), Value::Integer(10)]);
+    }
+    
+    #[test]
+    fn computed_fields_cant_be_written() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(order_lines()).unwrap();
+        let mut data = line(3, Value::Integer(4));
+        data.insert("total".to_string(), Value::Integer(1));
+        assert!(matches!(db.create_model("lines", data.clone()), Err(KooError::InvalidData(_))));
+        
+        let id = db.create_model("lines", line(3, Value::Integer(4))).unwrap();
+        assert!(matches!(db.update_model("lines", id, data), Err(KooError::InvalidData(_))));
+    }
+    
+    #[test]
+    fn computed_fields_can_be_added_later() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let plain = Schema::new("lines", [("price".to_string(), FieldType::Integer)])
+            .field("quantity", FieldDef::new(FieldType::Integer).nullable());
+        db.define_schema(plain).unwrap();
+        let id = db.create_model("lines", line(3, Value::Integer(4))).unwrap();
+        
+        // Virtual, so SQLite lets it be added to a table holding rows
+        let column = computed_column_definition("total", &FieldType::Integer, "price * quantity");
+        db.execute_raw(&format!("ALTER TABLE lines ADD COLUMN {}", column), &[]).unwrap();
+        db.define_schema(order_lines()).unwrap();
+        assert_eq!(db.get_model("lines", id).unwrap().unwrap().data["total"], Value::Integer(12));
+    }
+    
+    #[test]
+    fn computed_fields_cant_take_settings_of_stored_ones() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let computed = || FieldDef::new(FieldType::Integer).computed("price * 2");
+        let base = || Schema::new("lines", [("price".to_string(), FieldType::Integer)]);
+        for schema in [
+            base().field("double", computed().with_default(0)),
+            base().field("double", computed().validate(Validator::Min(0.0))),
+            base().field("double", FieldDef::new(FieldType::Reference("lines".to_string())).computed("price")),
+            base().field("double", computed()).with_key(PrimaryKey::Composite(vec!["price".to_string(), "double".to_string()])),
+        ] {
+            assert!(matches!(db.define_schema(schema), Err(KooError::InvalidSchema(_))));
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FieldType, FlexibleDatabase, Model, Schema, read_model, row_key, select_columns};
+
+impl FlexibleDatabase {
+    // Create the `<schema>_fts` FTS5 table over the schema's fts_fields,
+    // plus triggers that keep it in step with inserts, updates and deletes
+    pub(crate) fn create_fts_index(&self, schema: &Schema) -> Result<()> {
+        for field_name in &schema.fts_fields {
+            match schema.fields.get(field_name) {
+                Some(FieldType::Text) => {}
+                Some(_) => {
+                    return Err(KooError::InvalidSchema(format!(
+                        "full-text field '{}.{}' must be Text",
+                        schema.name, field_name
+                    )));
+                }
+                None => {
+                    return Err(KooError::UnknownField {
+                        schema: schema.name.clone(),
+                        field: field_name.clone(),
+                    });
+                }
+            }
+        }
+
+        let table = &schema.name;
+        let rowid = row_key(schema);
+        let fts = format!("{}_fts", table);
+        let columns = schema.fts_fields.join(", ");
+        let new_values = prefixed(&schema.fts_fields, "new.");
+        let old_values = prefixed(&schema.fts_fields, "old.");
+
+        let exists: bool = self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE name = ?)",
+            [&fts],
+            |row| row.get(0),
+        )?;
+
+        self.conn.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS {fts} USING fts5({columns}, content='{table}', content_rowid='{rowid}');
+            CREATE TRIGGER IF NOT EXISTS {fts}_ai AFTER INSERT ON {table} BEGIN
+                INSERT INTO {fts} (rowid, {columns}) VALUES (new.{rowid}, {new_values});
+            END;
+            CREATE TRIGGER IF NOT EXISTS {fts}_ad AFTER DELETE ON {table} BEGIN
+                INSERT INTO {fts} ({fts}, rowid, {columns}) VALUES ('delete', old.{rowid}, {old_values});
+            END;
+            CREATE TRIGGER IF NOT EXISTS {fts}_au AFTER UPDATE ON {table} BEGIN
+                INSERT INTO {fts} ({fts}, rowid, {columns}) VALUES ('delete', old.{rowid}, {old_values});
+                INSERT INTO {fts} (rowid, {columns}) VALUES (new.{rowid}, {new_values});
+            END;"
+        ))?;
+
+        // Index rows that were stored before full-text search was enabled
+        if !exists {
+            self.conn
+                .execute(&format!("INSERT INTO {fts} ({fts}) VALUES ('rebuild')"), [])?;
+        }
+        Ok(())
+    }
+
+    // Remove the FTS table and triggers of a table, if it has them
+    pub(crate) fn drop_fts_index(&self, table: &str) -> Result<()> {
+        self.conn.execute_batch(&format!(
+            "DROP TRIGGER IF EXISTS {table}_fts_ai;
+            DROP TRIGGER IF EXISTS {table}_fts_ad;
+            DROP TRIGGER IF EXISTS {table}_fts_au;
+            DROP TABLE IF EXISTS {table}_fts;"
+        ))?;
+        Ok(())
+    }
+
+    // Run an FTS5 query against a schema defined `with_fts`, best matches first
+    pub fn search(&self, schema_name: &str, query: &str) -> Result<Vec<Model>> {
+        let schema = self.schema_or_err(schema_name)?;
+        if schema.fts_fields.is_empty() {
+            return Err(KooError::InvalidSchema(format!(
+                "schema '{}' has no full-text index",
+                schema_name
+            )));
+        }
+
+        let table = &schema.name;
+        let rowid = row_key(schema);
+        let columns = select_columns(schema);
+        let sql = format!(
+            "SELECT {} FROM {table} JOIN {table}_fts ON {table}_fts.rowid = {table}.{rowid} \
+             WHERE {table}_fts MATCH ? ORDER BY {table}_fts.rank",
+            columns.join(", ")
+        );
+
+        self.traced(&sql, 1, || {
+            let mut stmt = self.conn.prepare(&sql)?;
+            let mut rows = stmt.query([query])?;
+
+            let mut models = Vec::new();
+            while let Some(row) = rows.next()? {
+                let mut model = read_model(row, schema)?;
+                if !self.readable(schema_name, &model) {
+                    continue;
+                }
+                self.track_model(schema_name, &mut model);
+                models.push(model);
+            }
+            let count = models.len();
+            Ok((models, count))
+        })
+    }
+}
+
+fn prefixed(fields: &[String], prefix: &str) -> String {
+    fields
+        .iter()
+        .map(|f| format!("{}{}", prefix, f))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flexible_database::PrimaryKey;
+    use crate::model_builder::ModelBuilder;
+
+    fn articles(key: PrimaryKey) -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new(
+            "articles",
+            [
+                ("title".to_string(), FieldType::Text),
+                ("body".to_string(), FieldType::Text),
+            ],
+        )
+        .with_key(key)
+        .with_fts(&["title", "body"]);
+        db.define_schema(schema).unwrap();
+        db
+    }
+
+    fn titles(models: &[Model]) -> Vec<&str> {
+        models.iter().map(|m| m.get_str("title").unwrap()).collect()
+    }
+
+    #[test]
+    fn searches_follow_inserts_updates_and_deletes() {
+        let db = articles(PrimaryKey::Integer);
+        let rust = ModelBuilder::new().text("title", "Rust").text("body", "memory safety");
+        let id = db.create_model("articles", rust.build()).unwrap();
+        let go = ModelBuilder::new()
+            .text("title", "Go")
+            .text("body", "garbage collected");
+        db.create_model("articles", go.build()).unwrap();
+        assert_eq!(titles(&db.search("articles", "memory").unwrap()), ["Rust"]);
+
+        db.update_model("articles", &id, ModelBuilder::new().text("body", "ownership").build())
+            .unwrap();
+        assert!(db.search("articles", "memory").unwrap().is_empty());
+        assert_eq!(titles(&db.search("articles", "ownership").unwrap()), ["Rust"]);
+
+        db.delete_model("articles", &id).unwrap();
+        assert!(db.search("articles", "ownership").unwrap().is_empty());
+    }
+
+    #[test]
+    fn better_matches_come_first() {
+        let db = articles(PrimaryKey::Text);
+        for (id, title, body) in [("a", "Cooking", "soup once"), ("b", "Soup", "soup, soup and soup")] {
+            let article = ModelBuilder::new()
+                .text("id", id)
+                .text("title", title)
+                .text("body", body);
+            db.create_model("articles", article.build()).unwrap();
+        }
+        assert_eq!(titles(&db.search("articles", "soup").unwrap()), ["Soup", "Cooking"]);
+    }
+
+    #[test]
+    fn rows_stored_before_the_index_are_searchable() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("notes", [("title".to_string(), FieldType::Text)]);
+        db.define_schema(schema.clone()).unwrap();
+        db.create_model("notes", ModelBuilder::new().text("title", "early bird").build())
+            .unwrap();
+
+        db.create_fts_index(&schema.with_fts(&["title"])).unwrap();
+        let mut stmt = db
+            .conn
+            .prepare("SELECT rowid FROM notes_fts WHERE notes_fts MATCH 'bird'")
+            .unwrap();
+        assert!(stmt.exists([]).unwrap());
+    }
+
+    #[test]
+    fn only_text_fields_of_indexed_schemas_can_be_searched() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("plain", [("title".to_string(), FieldType::Text)]))
+            .unwrap();
+        assert!(matches!(db.search("plain", "x"), Err(KooError::InvalidSchema(_))));
+
+        let numbers = Schema::new("numbers", [("n".to_string(), FieldType::Integer)]).with_fts(&["n"]);
+        assert!(matches!(db.define_schema(numbers), Err(KooError::InvalidSchema(_))));
+    }
+}
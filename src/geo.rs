@@ -0,0 +1,266 @@
+use rusqlite::Connection;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::{Value, ValueRef};
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FieldType, Schema};
+
+// SQL function giving the distance in meters from a `GeoPoint` field to a
+// latitude and longitude, as the radius filters use it
+pub const DISTANCE_FUNCTION: &str = "koo_geo_distance";
+
+// Mean radius of the Earth, which the haversine formula takes as a sphere
+const EARTH_RADIUS_METERS: f64 = 6_371_008.8;
+
+// A location in degrees, as held by `FieldType::GeoPoint` fields. It is
+// stored as the JSON text "[lat,lon]", and written as a [lat, lon] array
+// in exports and by the servers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeoPoint {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl GeoPoint {
+    pub fn new(lat: f64, lon: f64) -> GeoPoint {
+        GeoPoint { lat, lon }
+    }
+
+    // The point a field value holds, if it holds one
+    pub fn from_value(value: &Value) -> Option<GeoPoint> {
+        match value {
+            Value::Text(text) => GeoPoint::from_json(&serde_json::from_str(text).ok()?),
+            _ => None,
+        }
+    }
+
+    // A [lat, lon] array within the valid ranges
+    pub(crate) fn from_json(json: &serde_json::Value) -> Option<GeoPoint> {
+        let [lat, lon] = json.as_array()?.as_slice() else {
+            return None;
+        };
+        let point = GeoPoint::new(lat.as_f64()?, lon.as_f64()?);
+        let valid = (-90.0..=90.0).contains(&point.lat) && (-180.0..=180.0).contains(&point.lon);
+        valid.then_some(point)
+    }
+
+    // Great-circle distance in meters
+    pub fn distance_to(&self, other: &GeoPoint) -> f64 {
+        let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+        let half_lat = (lat2 - lat1) / 2.0;
+        let half_lon = (other.lon - self.lon).to_radians() / 2.0;
+        let a = half_lat.sin().powi(2) + lat1.cos() * lat2.cos() * half_lon.sin().powi(2);
+        2.0 * EARTH_RADIUS_METERS * a.sqrt().min(1.0).asin()
+    }
+}
+
+impl From<GeoPoint> for Value {
+    fn from(point: GeoPoint) -> Value {
+        Value::Text(serde_json::json!([point.lat, point.lon]).to_string())
+    }
+}
+
+// Where a `GeoPoint` field must lie for `Query::within` to match
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeoArea {
+    // Between two corners. A box whose west edge is east of its east edge
+    // wraps across the antimeridian.
+    Box { south_west: GeoPoint, north_east: GeoPoint },
+    // Within `meters` of `center`
+    Radius { center: GeoPoint, meters: f64 },
+}
+
+// SQL for `field_name` lying in `area`, and its parameters
+pub(crate) fn within_sql(schema: &Schema, field_name: &str, area: &GeoArea) -> Result<(String, Vec<Value>)> {
+    match schema.fields.get(field_name) {
+        Some(FieldType::GeoPoint) => {}
+        Some(field_type) => {
+            return Err(KooError::InvalidData(format!(
+                "'{}.{}' is {}, not a GeoPoint",
+                schema.name,
+                field_name,
+                field_type.name()
+            )));
+        }
+        None => {
+            return Err(KooError::UnknownField {
+                schema: schema.name.clone(),
+                field: field_name.to_string(),
+            });
+        }
+    }
+    match area {
+        GeoArea::Box { south_west, north_east } => {
+            let lat = format!("json_extract({}, '$[0]')", field_name);
+            let lon = format!("json_extract({}, '$[1]')", field_name);
+            let joiner = if south_west.lon <= north_east.lon { "AND" } else { "OR" };
+            Ok((
+                format!("{lat} BETWEEN ? AND ? AND ({lon} >= ? {joiner} {lon} <= ?)"),
+                vec![
+                    Value::Real(south_west.lat),
+                    Value::Real(north_east.lat),
+                    Value::Real(south_west.lon),
+                    Value::Real(north_east.lon),
+                ],
+            ))
+        }
+        GeoArea::Radius { center, meters } => Ok((
+            format!("{}({}, ?, ?) <= ?", DISTANCE_FUNCTION, field_name),
+            vec![Value::Real(center.lat), Value::Real(center.lon), Value::Real(*meters)],
+        )),
+    }
+}
+
+// Define `DISTANCE_FUNCTION` on `conn`. It is NULL for values that aren't
+// points, so they never match.
+pub(crate) fn register_geo(conn: &Connection) -> Result<()> {
+    conn.create_scalar_function(
+        DISTANCE_FUNCTION,
+        3,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let ValueRef::Text(text) = ctx.get_raw(0) else {
+                return Ok(None);
+            };
+            let point = std::str::from_utf8(text)
+                .ok()
+                .and_then(|text| GeoPoint::from_json(&serde_json::from_str(text).ok()?));
+            let center = GeoPoint::new(ctx.get(1)?, ctx.get(2)?);
+            Ok(point.map(|point| point.distance_to(&center)))
+        },
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::flexible_database::{FlexibleDatabase, Model};
+    use crate::query::Query;
+
+    const LONDON: GeoPoint = GeoPoint {
+        lat: 51.5074,
+        lon: -0.1278,
+    };
+    const PARIS: GeoPoint = GeoPoint {
+        lat: 48.8566,
+        lon: 2.3522,
+    };
+
+    fn places(points: &[(&str, GeoPoint)]) -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new(
+            "places",
+            [
+                ("name".to_string(), FieldType::Text),
+                ("location".to_string(), FieldType::GeoPoint),
+            ],
+        );
+        db.define_schema(schema).unwrap();
+        for (name, point) in points {
+            let data = HashMap::from([
+                ("name".to_string(), Value::Text(name.to_string())),
+                ("location".to_string(), Value::from(*point)),
+            ]);
+            db.create_model("places", data).unwrap();
+        }
+        db
+    }
+
+    fn names(models: &[Model]) -> Vec<String> {
+        let mut names: Vec<String> = models
+            .iter()
+            .map(|model| match &model.data["name"] {
+                Value::Text(name) => name.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn distances_follow_the_great_circle() {
+        let meters = LONDON.distance_to(&PARIS);
+        assert!((meters - 343_500.0).abs() < 1_000.0, "{}", meters);
+        assert_eq!(PARIS.distance_to(&PARIS), 0.0);
+        let antipode = GeoPoint::new(0.0, 0.0).distance_to(&GeoPoint::new(0.0, 180.0));
+        assert!((antipode - std::f64::consts::PI * EARTH_RADIUS_METERS).abs() < 1.0);
+    }
+
+    #[test]
+    fn points_are_stored_as_json_arrays() {
+        assert_eq!(
+            Value::from(GeoPoint::new(1.5, -2.0)),
+            Value::Text("[1.5,-2.0]".to_string())
+        );
+        assert_eq!(GeoPoint::from_value(&Value::from(PARIS)), Some(PARIS));
+        for text in ["[91, 0]", "[0, 181]", "[1]", "[1, 2, 3]", "{\"lat\": 1}", "not json"] {
+            assert_eq!(GeoPoint::from_value(&Value::Text(text.to_string())), None, "{}", text);
+        }
+        assert_eq!(GeoPoint::from_value(&Value::Real(1.0)), None);
+    }
+
+    #[test]
+    fn radius_filters_match_nearby_points() {
+        let db = places(&[
+            ("london", LONDON),
+            ("paris", PARIS),
+            ("greenwich", GeoPoint::new(51.4769, 0.0)),
+        ]);
+        db.execute_raw("INSERT INTO places (name, location) VALUES ('broken', 'nowhere')", &[])
+            .unwrap();
+        let near = |meters: f64| {
+            let query = Query::new("places").within("location", GeoArea::Radius { center: LONDON, meters });
+            names(&db.find(&query).unwrap())
+        };
+        assert_eq!(near(100.0), ["london"]);
+        assert_eq!(near(20_000.0), ["greenwich", "london"]);
+        assert_eq!(near(400_000.0), ["greenwich", "london", "paris"]);
+    }
+
+    #[test]
+    fn box_filters_can_wrap_the_antimeridian() {
+        let db = places(&[
+            ("fiji", GeoPoint::new(-17.7, 178.0)),
+            ("samoa", GeoPoint::new(-13.8, -172.1)),
+            ("sydney", GeoPoint::new(-33.9, 151.2)),
+        ]);
+        let inside = |south_west: GeoPoint, north_east: GeoPoint| {
+            let query = Query::new("places").within("location", GeoArea::Box { south_west, north_east });
+            names(&db.find(&query).unwrap())
+        };
+        assert_eq!(
+            inside(GeoPoint::new(-40.0, 150.0), GeoPoint::new(-10.0, 179.0)),
+            ["fiji", "sydney"]
+        );
+        assert_eq!(
+            inside(GeoPoint::new(-20.0, 170.0), GeoPoint::new(-10.0, -170.0)),
+            ["fiji", "samoa"]
+        );
+        assert!(inside(GeoPoint::new(0.0, 170.0), GeoPoint::new(10.0, -170.0)).is_empty());
+    }
+
+    #[test]
+    fn only_valid_points_in_point_fields() {
+        let db = places(&[]);
+        let data = HashMap::from([
+            ("name".to_string(), Value::Text("atlantis".to_string())),
+            ("location".to_string(), Value::Text("[100, 0]".to_string())),
+        ]);
+        assert!(matches!(
+            db.create_model("places", data),
+            Err(KooError::Validation { .. })
+        ));
+
+        let area = GeoArea::Radius {
+            center: PARIS,
+            meters: 1.0,
+        };
+        let find = |field: &str| db.find(&Query::new("places").within(field, area));
+        assert!(matches!(find("name"), Err(KooError::InvalidData(_))));
+        assert!(matches!(find("where"), Err(KooError::UnknownField { .. })));
+    }
+}
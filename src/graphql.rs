@@ -0,0 +1,591 @@
+use async_graphql::dynamic::{
+    Enum, Field, FieldFuture, FieldValue, InputObject, InputValue, Object, ResolverContext, Schema as GraphQLSchema,
+    TypeRef,
+};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FieldType, FlexibleDatabase, Model, ModelId, PrimaryKey, Schema, VERSION_COLUMN};
+use crate::query::{Direction, Op, Query};
+use crate::wire::{data_from_json, json_to_value, model_to_json};
+
+// Ways a filter can compare a field, with the GraphQL name of each
+const FILTER_OPS: &[(&str, Op)] = &[
+    ("eq", Op::Eq),
+    ("ne", Op::Ne),
+    ("lt", Op::Lt),
+    ("lte", Op::Le),
+    ("gt", Op::Gt),
+    ("gte", Op::Ge),
+];
+
+// A model as handed between resolvers: its export row layout
+type Row = serde_json::Map<String, serde_json::Value>;
+
+// GraphQL schema for every schema defined on `db`, behind the `graphql`
+// feature. For a schema `users` it has:
+//
+//   users(where: users_filter, order: [users_order!], limit: Int, offset: Int): [users!]!
+//   users_by_id(id: Int!): users
+//   create_users(data: users_input!): users!
+//   update_users(id: Int!, data: users_input!): users
+//   delete_users(id: Int!): Boolean!
+//
+// Filters combine per-field comparisons, e.g. `where: {age: {gte: 30}}`,
+// and orders name one field per entry, e.g. `order: [{age: DESC}]`.
+// Reference fields resolve to the referenced model, and every schema
+// pointed at gains `<schema>_by_<field>` listing the models that refer to
+// it. Composite keys take one argument per key field in place of `id`.
+//
+// The GraphQL schema is a snapshot: rebuild it after defining or altering
+// schemas. Resolvers run on the caller's task while holding the lock.
+pub fn graphql_schema(db: Arc<Mutex<FlexibleDatabase>>) -> Result<GraphQLSchema> {
+    let schemas: Vec<Schema> = lock(&db).schemas().into_iter().cloned().collect();
+    let defined: HashSet<&str> = schemas.iter().map(|schema| schema.name.as_str()).collect();
+
+    let mut query = Object::new("Query");
+    let mut mutation = Object::new("Mutation");
+    let mut types = Vec::new();
+    let mut inputs = Vec::new();
+
+    for schema in &schemas {
+        let name = graphql_name(&schema.name);
+
+        let mut object = Object::new(&name);
+        let mut filter = InputObject::new(format!("{}_filter", name));
+        let mut order = InputObject::new(format!("{}_order", name));
+        let mut input = InputObject::new(format!("{}_input", name));
+
+        if let Some(id_type) = id_type(schema) {
+            object = object.field(row_field("id", "id", TypeRef::named(id_type)));
+            filter = filter.field(InputValue::new("id", TypeRef::named(format!("{}_filter", id_type))));
+            order = order.field(InputValue::new("id", TypeRef::named("Direction")));
+            if schema.key == PrimaryKey::Text {
+                input = input.field(InputValue::new("id", TypeRef::named(id_type)));
+            }
+        }
+
+        for (field_name, field_type) in &schema.fields {
+            let graphql_field = graphql_name(field_name);
+            // Points and vectors are lists, which can't be compared or sorted
+            if matches!(field_type, FieldType::GeoPoint | FieldType::Vector(_)) {
+                let point = TypeRef::named_nn_list(TypeRef::FLOAT);
+                object = object.field(row_field(&graphql_field, field_name, point.clone()));
+                if !schema.is_computed(field_name) {
+                    input = input.field(InputValue::new(&graphql_field, point));
+                }
+                continue;
+            }
+            // Polymorphic references mix a schema name and an id, so they
+            // go as their JSON text, e.g. "[\"posts\",12]"
+            if let FieldType::Polymorphic(_) = field_type {
+                object = object.field(json_text_field(&graphql_field, field_name));
+                if !schema.is_computed(field_name) {
+                    input = input.field(InputValue::new(&graphql_field, TypeRef::named(TypeRef::STRING)));
+                }
+                continue;
+            }
+            let scalar = scalar_type(field_type);
+            object = match field_type {
+                FieldType::Reference(target) if defined.contains(target.as_str()) => {
+                    object.field(reference_field(&graphql_field, field_name, target))
+                }
+                _ => object.field(row_field(&graphql_field, field_name, TypeRef::named(scalar))),
+            };
+            filter = filter.field(InputValue::new(
+                &graphql_field,
+                TypeRef::named(format!("{}_filter", scalar)),
+            ));
+            order = order.field(InputValue::new(&graphql_field, TypeRef::named("Direction")));
+            if !schema.is_computed(field_name) {
+                input = input.field(InputValue::new(&graphql_field, TypeRef::named(scalar)));
+            }
+        }
+
+        if schema.versioned {
+            object = object.field(row_field(VERSION_COLUMN, VERSION_COLUMN, TypeRef::named(TypeRef::INT)));
+            input = input.field(InputValue::new(VERSION_COLUMN, TypeRef::named(TypeRef::INT)));
+        }
+
+        // Models of other schemas that refer to this one
+        if schema.key == PrimaryKey::Integer {
+            for referrer in &schemas {
+                for (field_name, field_type) in &referrer.fields {
+                    if *field_type == FieldType::Reference(schema.name.clone()) {
+                        let field = format!("{}_by_{}", graphql_name(&referrer.name), graphql_name(field_name));
+                        object = object.field(referrers_field(&field, referrer, field_name));
+                    }
+                }
+            }
+        }
+
+        query = query.field(list_field(schema, &name)).field(by_id_field(schema, &name));
+        mutation = mutation
+            .field(create_field(schema, &name))
+            .field(update_field(schema, &name))
+            .field(delete_field(schema, &name));
+
+        types.push(object);
+        inputs.extend([filter, order, input]);
+    }
+
+    let mut builder = GraphQLSchema::build("Query", Some("Mutation"), None)
+        .register(query)
+        .register(mutation)
+        .register(Enum::new("Direction").item("ASC").item("DESC"));
+    for scalar in [TypeRef::STRING, TypeRef::INT, TypeRef::FLOAT, TypeRef::BOOLEAN] {
+        let mut filter = InputObject::new(format!("{}_filter", scalar));
+        for (op, _) in FILTER_OPS {
+            filter = filter.field(InputValue::new(*op, TypeRef::named(scalar)));
+        }
+        builder = builder.register(filter);
+    }
+    for object in types {
+        builder = builder.register(object);
+    }
+    for input in inputs {
+        builder = builder.register(input);
+    }
+
+    builder
+        .data(db)
+        .finish()
+        .map_err(|err| KooError::InvalidSchema(format!("can't build GraphQL schema: {}", err)))
+}
+
+// GraphQL names allow letters, digits and underscores, not starting with a digit
+fn graphql_name(name: &str) -> String {
+    let mut graphql: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !graphql.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        graphql.insert(0, '_');
+    }
+    graphql
+}
+
+fn scalar_type(field_type: &FieldType) -> &'static str {
+    match field_type {
+        FieldType::Text | FieldType::Enum(_) | FieldType::Polymorphic(_) => TypeRef::STRING,
+        FieldType::Integer | FieldType::Reference(_) => TypeRef::INT,
+        FieldType::Real | FieldType::GeoPoint | FieldType::Vector(_) => TypeRef::FLOAT,
+        FieldType::Boolean => TypeRef::BOOLEAN,
+    }
+}
+
+// Type of the `id` column, if the schema has one
+fn id_type(schema: &Schema) -> Option<&'static str> {
+    match schema.key {
+        PrimaryKey::Integer => Some(TypeRef::INT),
+        PrimaryKey::Text => Some(TypeRef::STRING),
+        PrimaryKey::Composite(_) => None,
+    }
+}
+
+fn lock(db: &Mutex<FlexibleDatabase>) -> MutexGuard<'_, FlexibleDatabase> {
+    db.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn database<'a>(ctx: &ResolverContext<'a>) -> async_graphql::Result<MutexGuard<'a, FlexibleDatabase>> {
+    Ok(lock(ctx.data::<Arc<Mutex<FlexibleDatabase>>>()?))
+}
+
+fn row_list(rows: Vec<Row>) -> FieldValue<'static> {
+    FieldValue::list(rows.into_iter().map(FieldValue::owned_any))
+}
+
+// A column of the parent row, passed through as is
+fn row_field(graphql_field: &str, column: &str, ty: TypeRef) -> Field {
+    let column = column.to_string();
+    Field::new(graphql_field, ty, move |ctx| {
+        let column = column.clone();
+        FieldFuture::new(async move {
+            let row = ctx.parent_value.try_downcast_ref::<Row>()?;
+            match row.get(&column) {
+                None | Some(serde_json::Value::Null) => Ok(None),
+                Some(json) => Ok(Some(FieldValue::value(async_graphql::Value::from_json(json.clone())?))),
+            }
+        })
+    })
+}
+
+// A column written as the text of its JSON value
+fn json_text_field(graphql_field: &str, column: &str) -> Field {
+    let column = column.to_string();
+    Field::new(graphql_field, TypeRef::named(TypeRef::STRING), move |ctx| {
+        let column = column.clone();
+        FieldFuture::new(async move {
+            let row = ctx.parent_value.try_downcast_ref::<Row>()?;
+            match row.get(&column) {
+                None | Some(serde_json::Value::Null) => Ok(None),
+                Some(json) => Ok(Some(FieldValue::value(json.to_string()))),
+            }
+        })
+    })
+}
+
+// A reference column, resolved to the model it points at
+fn reference_field(graphql_field: &str, column: &str, target: &str) -> Field {
+    let column = column.to_string();
+    let target = target.to_string();
+    Field::new(graphql_field, TypeRef::named(graphql_name(&target)), move |ctx| {
+        let column = column.clone();
+        let target = target.clone();
+        FieldFuture::new(async move {
+            let row = ctx.parent_value.try_downcast_ref::<Row>()?;
+            let Some(id) = row.get(&column).and_then(|id| id.as_i64()) else {
+                return Ok(None);
+            };
+            let db = database(&ctx)?;
+            let schema = db.schema_or_err(&target)?;
+            Ok(fetch_row(&db, schema, ModelId::Integer(id))?.map(FieldValue::owned_any))
+        })
+    })
+}
+
+// The models of `referrer` whose `column` holds the parent's id
+fn referrers_field(graphql_field: &str, referrer: &Schema, column: &str) -> Field {
+    let referrer = referrer.name.clone();
+    let column = column.to_string();
+    Field::new(
+        graphql_field,
+        TypeRef::named_nn_list_nn(graphql_name(&referrer)),
+        move |ctx| {
+            let referrer = referrer.clone();
+            let column = column.clone();
+            FieldFuture::new(async move {
+                let row = ctx.parent_value.try_downcast_ref::<Row>()?;
+                let id = row.get("id").and_then(|id| id.as_i64()).unwrap_or_default();
+                let db = database(&ctx)?;
+                let schema = db.schema_or_err(&referrer)?;
+                let models = db.find(&Query::new(&referrer).filter(&column, Op::Eq, id))?;
+                Ok(Some(row_list(
+                    models.iter().map(|model| to_row(schema, model)).collect(),
+                )))
+            })
+        },
+    )
+}
+
+fn list_field(schema: &Schema, name: &str) -> Field {
+    let schema_name = schema.name.clone();
+    Field::new(name, TypeRef::named_nn_list_nn(name), move |ctx| {
+        let schema_name = schema_name.clone();
+        FieldFuture::new(async move {
+            let db = database(&ctx)?;
+            let schema = db.schema_or_err(&schema_name)?;
+            let query = query_from_args(schema, &ctx)?;
+            let models = db.find(&query)?;
+            Ok(Some(row_list(
+                models.iter().map(|model| to_row(schema, model)).collect(),
+            )))
+        })
+    })
+    .argument(InputValue::new("where", TypeRef::named(format!("{}_filter", name))))
+    .argument(InputValue::new(
+        "order",
+        TypeRef::named_nn_list(format!("{}_order", name)),
+    ))
+    .argument(InputValue::new("limit", TypeRef::named(TypeRef::INT)))
+    .argument(InputValue::new("offset", TypeRef::named(TypeRef::INT)))
+}
+
+fn by_id_field(schema: &Schema, name: &str) -> Field {
+    let schema_name = schema.name.clone();
+    let field = Field::new(format!("{}_by_id", name), TypeRef::named(name), move |ctx| {
+        let schema_name = schema_name.clone();
+        FieldFuture::new(async move {
+            let db = database(&ctx)?;
+            let schema = db.schema_or_err(&schema_name)?;
+            let id = id_from_args(schema, &ctx)?;
+            Ok(fetch_row(&db, schema, id)?.map(FieldValue::owned_any))
+        })
+    });
+    with_id_arguments(field, schema)
+}
+
+fn create_field(schema: &Schema, name: &str) -> Field {
+    let schema_name = schema.name.clone();
+    Field::new(format!("create_{}", name), TypeRef::named_nn(name), move |ctx| {
+        let schema_name = schema_name.clone();
+        FieldFuture::new(async move {
+            let db = database(&ctx)?;
+            let schema = db.schema_or_err(&schema_name)?;
+            let data = data_from_json(schema, &input_json(schema, &ctx)?)?;
+            let id = db.create_model(&schema_name, data)?;
+            Ok(fetch_row(&db, schema, id)?.map(FieldValue::owned_any))
+        })
+    })
+    .argument(InputValue::new("data", TypeRef::named_nn(format!("{}_input", name))))
+}
+
+fn update_field(schema: &Schema, name: &str) -> Field {
+    let schema_name = schema.name.clone();
+    let field = Field::new(format!("update_{}", name), TypeRef::named(name), move |ctx| {
+        let schema_name = schema_name.clone();
+        FieldFuture::new(async move {
+            let db = database(&ctx)?;
+            let schema = db.schema_or_err(&schema_name)?;
+            let id = id_from_args(schema, &ctx)?;
+            let data = data_from_json(schema, &input_json(schema, &ctx)?)?;
+            if !db.update_model(&schema_name, id.clone(), data)? {
+                return Ok(None);
+            }
+            Ok(fetch_row(&db, schema, id)?.map(FieldValue::owned_any))
+        })
+    })
+    .argument(InputValue::new("data", TypeRef::named_nn(format!("{}_input", name))));
+    with_id_arguments(field, schema)
+}
+
+fn delete_field(schema: &Schema, name: &str) -> Field {
+    let schema_name = schema.name.clone();
+    let field = Field::new(
+        format!("delete_{}", name),
+        TypeRef::named_nn(TypeRef::BOOLEAN),
+        move |ctx| {
+            let schema_name = schema_name.clone();
+            FieldFuture::new(async move {
+                let db = database(&ctx)?;
+                let id = id_from_args(db.schema_or_err(&schema_name)?, &ctx)?;
+                let deleted = db.delete_model(&schema_name, id)?;
+                Ok(Some(FieldValue::value(deleted)))
+            })
+        },
+    );
+    with_id_arguments(field, schema)
+}
+
+// `id`, or one argument per key field for composite keys
+fn with_id_arguments(field: Field, schema: &Schema) -> Field {
+    match (&schema.key, id_type(schema)) {
+        (PrimaryKey::Composite(key_fields), _) => key_fields.iter().fold(field, |field, key_field| {
+            let scalar = scalar_type(&schema.fields[key_field]);
+            field.argument(InputValue::new(graphql_name(key_field), TypeRef::named_nn(scalar)))
+        }),
+        (_, Some(id_type)) => field.argument(InputValue::new("id", TypeRef::named_nn(id_type))),
+        (_, None) => field,
+    }
+}
+
+fn id_from_args(schema: &Schema, ctx: &ResolverContext<'_>) -> async_graphql::Result<ModelId> {
+    let id = match &schema.key {
+        PrimaryKey::Integer => ModelId::Integer(ctx.args.try_get("id")?.i64()?),
+        PrimaryKey::Text => ModelId::Text(ctx.args.try_get("id")?.string()?.to_string()),
+        PrimaryKey::Composite(key_fields) => {
+            let mut values = Vec::new();
+            for key_field in key_fields {
+                let json = ctx
+                    .args
+                    .try_get(&graphql_name(key_field))?
+                    .as_value()
+                    .clone()
+                    .into_json()?;
+                values.push(json_to_value(
+                    &json,
+                    &schema.fields[key_field],
+                    &schema.name,
+                    key_field,
+                )?);
+            }
+            ModelId::Composite(values)
+        }
+    };
+    Ok(id)
+}
+
+// The `data` argument as a JSON object keyed by field name
+fn input_json(schema: &Schema, ctx: &ResolverContext<'_>) -> async_graphql::Result<serde_json::Value> {
+    let data = ctx.args.try_get("data")?.object()?;
+    let mut object = serde_json::Map::new();
+    for column in columns(schema) {
+        if let Some(value) = data.get(&graphql_name(&column)) {
+            object.insert(column, value.as_value().clone().into_json()?);
+        }
+    }
+    Ok(object.into())
+}
+
+fn query_from_args(schema: &Schema, ctx: &ResolverContext<'_>) -> async_graphql::Result<Query> {
+    let mut query = Query::new(&schema.name);
+
+    if let Some(filter) = ctx.args.get("where") {
+        let filter = filter.object()?;
+        for column in columns(schema) {
+            let Some(comparisons) = filter.get(&graphql_name(&column)) else {
+                continue;
+            };
+            let field_type = column_type(schema, &column);
+            let comparisons = comparisons.object()?;
+            for (op_name, op) in FILTER_OPS {
+                if let Some(value) = comparisons.get(op_name) {
+                    let json = value.as_value().clone().into_json()?;
+                    let value = json_to_value(&json, &field_type, &schema.name, &column)?;
+                    query = query.filter(&column, *op, value);
+                }
+            }
+        }
+    }
+
+    if let Some(order) = ctx.args.get("order") {
+        for entry in order.list()?.iter() {
+            let entry = entry.object()?;
+            for column in columns(schema) {
+                if let Some(direction) = entry.get(&graphql_name(&column)) {
+                    let direction = match direction.enum_name()? {
+                        "DESC" => Direction::Desc,
+                        _ => Direction::Asc,
+                    };
+                    query = query.order_by(&column, direction);
+                }
+            }
+        }
+    }
+
+    if let Some(limit) = ctx.args.get("limit") {
+        query = query.limit(usize::try_from(limit.i64()?)?);
+    }
+    if let Some(offset) = ctx.args.get("offset") {
+        query = query.offset(usize::try_from(offset.i64()?)?);
+    }
+    Ok(query)
+}
+
+// The id column, if any, then the fields in declared order
+fn columns(schema: &Schema) -> Vec<String> {
+    let mut columns = Vec::new();
+    if schema.key.has_id_column() {
+        columns.push("id".to_string());
+    }
+    columns.extend(schema.fields.keys().cloned());
+    if schema.versioned {
+        columns.push(VERSION_COLUMN.to_string());
+    }
+    columns
+}
+
+fn column_type(schema: &Schema, column: &str) -> FieldType {
+    match (column, &schema.key) {
+        ("id", PrimaryKey::Text) => FieldType::Text,
+        ("id", _) => FieldType::Integer,
+        (VERSION_COLUMN, _) if !schema.fields.contains_key(column) => FieldType::Integer,
+        _ => schema.fields[column].clone(),
+    }
+}
+
+fn fetch_row(db: &FlexibleDatabase, schema: &Schema, id: ModelId) -> Result<Option<Row>> {
+    Ok(db.get_model(&schema.name, id)?.map(|model| to_row(schema, &model)))
+}
+
+fn to_row(schema: &Schema, model: &Model) -> Row {
+    match model_to_json(schema, model) {
+        serde_json::Value::Object(row) => row,
+        _ => Row::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    use crate::flexible_database::FieldDef;
+
+    fn library() -> GraphQLSchema {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("authors", [("name".to_string(), FieldType::Text)]))
+            .unwrap();
+        let books = Schema::new("books", [("title".to_string(), FieldType::Text)])
+            .field("pages", FieldDef::new(FieldType::Integer))
+            .field("author", FieldDef::new(FieldType::Reference("authors".to_string())));
+        db.define_schema(books).unwrap();
+        graphql_schema(Arc::new(Mutex::new(db))).unwrap()
+    }
+
+    // Resolvers never wait, so polling until done is enough of an executor
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut context = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        }
+    }
+
+    fn execute(schema: &GraphQLSchema, request: &str) -> serde_json::Value {
+        let response = block_on(schema.execute(request));
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        response.data.into_json().unwrap()
+    }
+
+    fn seed(schema: &GraphQLSchema) {
+        execute(schema, r#"mutation { create_authors(data: {name: "Le Guin"}) { id } }"#);
+        for (title, pages) in [("Earthsea", 183), ("Dispossessed", 387), ("Lathe", 184)] {
+            let request = format!(
+                r#"mutation {{ create_books(data: {{title: "{}", pages: {}, author: 1}}) {{ id }} }}"#,
+                title, pages
+            );
+            execute(schema, &request);
+        }
+    }
+
+    #[test]
+    fn queries_filter_sort_and_follow_references() {
+        let schema = library();
+        seed(&schema);
+        let data = execute(
+            &schema,
+            r#"{ books(where: {pages: {lt: 300}}, order: [{pages: DESC}]) { title author { name } } }"#,
+        );
+        assert_eq!(
+            data,
+            serde_json::json!({ "books": [
+                { "title": "Lathe", "author": { "name": "Le Guin" } },
+                { "title": "Earthsea", "author": { "name": "Le Guin" } },
+            ] })
+        );
+
+        let data = execute(&schema, "{ authors_by_id(id: 1) { books_by_author { title } } }");
+        assert_eq!(data["authors_by_id"]["books_by_author"].as_array().unwrap().len(), 3);
+        assert_eq!(
+            execute(&schema, "{ books_by_id(id: 9) { title } }")["books_by_id"],
+            serde_json::Value::Null
+        );
+    }
+
+    #[test]
+    fn mutations_update_and_delete() {
+        let schema = library();
+        seed(&schema);
+        let data = execute(
+            &schema,
+            r#"mutation { update_books(id: 1, data: {pages: 200}) { pages } }"#,
+        );
+        assert_eq!(data["update_books"]["pages"], 200);
+        let data = execute(&schema, "mutation { delete_books(id: 1) }");
+        assert_eq!(data["delete_books"], true);
+        let data = execute(&schema, "mutation { delete_books(id: 1) }");
+        assert_eq!(data["delete_books"], false);
+        let data = execute(&schema, "{ books(limit: 1, offset: 1) { title } }");
+        assert_eq!(data["books"][0]["title"], "Lathe");
+    }
+
+    #[test]
+    fn database_errors_become_graphql_errors() {
+        let schema = library();
+        let response = block_on(schema.execute(r#"mutation { create_books(data: {title: "Orphan"}) { id } }"#));
+        assert_eq!(response.errors.len(), 1);
+    }
+
+    #[test]
+    fn names_are_made_valid_for_graphql() {
+        assert_eq!(graphql_name("users"), "users");
+        assert_eq!(graphql_name("archive.users"), "archive_users");
+        assert_eq!(graphql_name("2024-logs"), "_2024_logs");
+    }
+}
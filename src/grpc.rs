@@ -0,0 +1,593 @@
+use rusqlite::types::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tonic::{Request, Response, Status};
+
+use crate::error::{KooError, Result};
+use crate::expiry::Expiry;
+use crate::flexible_database::{FieldType, FlexibleDatabase, Model, ModelId, PrimaryKey, Schema, UuidVersion};
+use crate::query::{Direction, Op, Query};
+use crate::validation::Validator;
+
+// Types and the client and server stubs generated from proto/koodb.proto
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("koodb.v1");
+}
+
+use proto::koo_server::{Koo, KooServer};
+
+// The Koo gRPC service over a FlexibleDatabase, behind the `grpc` feature.
+// Requests take turns on the single connection and run on tokio's blocking
+// threads. Add it to a tonic server with `into_server`, or use `serve`.
+#[derive(Clone)]
+pub struct KooService {
+    db: Arc<Mutex<FlexibleDatabase>>,
+}
+
+impl KooService {
+    pub fn new(db: FlexibleDatabase) -> KooService {
+        KooService::shared(Arc::new(Mutex::new(db)))
+    }
+
+    // Service using a connection shared with other parts of the program
+    pub fn shared(db: Arc<Mutex<FlexibleDatabase>>) -> KooService {
+        KooService { db }
+    }
+
+    pub fn into_server(self) -> KooServer<KooService> {
+        KooServer::new(self)
+    }
+
+    // Run `handler` with the database on a blocking thread. It returns None
+    // when the requested model doesn't exist.
+    async fn with_db<T, F>(&self, handler: F) -> std::result::Result<Response<T>, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut FlexibleDatabase) -> Result<Option<T>> + Send + 'static,
+    {
+        let db = self.db.clone();
+        let reply = tokio::task::spawn_blocking(move || {
+            let mut db = db.lock().unwrap_or_else(|e| e.into_inner());
+            handler(&mut db)
+        })
+        .await
+        .map_err(|err| Status::internal(err.to_string()))?;
+        match reply? {
+            Some(reply) => Ok(Response::new(reply)),
+            None => Err(Status::not_found("no such model")),
+        }
+    }
+}
+
+// Serve the Koo service on `addr` (e.g. "127.0.0.1:50051") until the process
+// stops
+pub async fn serve(db: FlexibleDatabase, addr: &str) -> Result<()> {
+    let addr = tokio::net::lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| KooError::InvalidData(format!("'{}' doesn't resolve to an address", addr)))?;
+    tonic::transport::Server::builder()
+        .add_service(KooService::new(db).into_server())
+        .serve(addr)
+        .await
+        .map_err(|err| KooError::Io(std::io::Error::other(err)))
+}
+
+#[tonic::async_trait]
+impl Koo for KooService {
+    async fn define_schema(
+        &self,
+        request: Request<proto::DefineSchemaRequest>,
+    ) -> std::result::Result<Response<proto::DefineSchemaResponse>, Status> {
+        let schema = request
+            .into_inner()
+            .schema
+            .ok_or_else(|| Status::invalid_argument("schema is required"))?;
+        let schema = schema_from_proto(schema)?;
+        self.with_db(move |db| {
+            db.define_schema(schema)?;
+            Ok(Some(proto::DefineSchemaResponse {}))
+        })
+        .await
+    }
+
+    async fn create(
+        &self,
+        request: Request<proto::CreateRequest>,
+    ) -> std::result::Result<Response<proto::CreateResponse>, Status> {
+        let request = request.into_inner();
+        self.with_db(move |db| {
+            let id = db.create_model(&request.schema, data_from_proto(request.data))?;
+            Ok(Some(proto::CreateResponse {
+                id: Some(model_id_to_proto(&id)),
+            }))
+        })
+        .await
+    }
+
+    async fn get(
+        &self,
+        request: Request<proto::GetRequest>,
+    ) -> std::result::Result<Response<proto::GetResponse>, Status> {
+        let request = request.into_inner();
+        let id = model_id_from_proto(request.id)?;
+        self.with_db(move |db| {
+            let model = db.get_model(&request.schema, id)?;
+            Ok(model.map(|model| proto::GetResponse {
+                model: Some(model_to_proto(&model)),
+            }))
+        })
+        .await
+    }
+
+    async fn query(
+        &self,
+        request: Request<proto::QueryRequest>,
+    ) -> std::result::Result<Response<proto::QueryResponse>, Status> {
+        let request = request.into_inner();
+        let mut query = Query::new(&request.schema);
+        for condition in &request.conditions {
+            let op = match condition.op() {
+                proto::Op::Eq => Op::Eq,
+                proto::Op::Ne => Op::Ne,
+                proto::Op::Lt => Op::Lt,
+                proto::Op::Le => Op::Le,
+                proto::Op::Gt => Op::Gt,
+                proto::Op::Ge => Op::Ge,
+            };
+            query = query.filter(&condition.field, op, value_from_proto(condition.value.clone()));
+        }
+        for order in &request.order {
+            let direction = if order.descending {
+                Direction::Desc
+            } else {
+                Direction::Asc
+            };
+            query = query.order_by(&order.field, direction);
+        }
+        if let Some(limit) = request.limit {
+            query = query.limit(count_from_proto(limit, "limit")?);
+        }
+        query = query.offset(count_from_proto(request.offset, "offset")?);
+
+        self.with_db(move |db| {
+            let models = db.find(&query)?;
+            Ok(Some(proto::QueryResponse {
+                models: models.iter().map(model_to_proto).collect(),
+            }))
+        })
+        .await
+    }
+
+    async fn update(
+        &self,
+        request: Request<proto::UpdateRequest>,
+    ) -> std::result::Result<Response<proto::UpdateResponse>, Status> {
+        let request = request.into_inner();
+        let id = model_id_from_proto(request.id)?;
+        self.with_db(move |db| {
+            let updated = db.update_model(&request.schema, id, data_from_proto(request.data))?;
+            Ok(updated.then_some(proto::UpdateResponse {}))
+        })
+        .await
+    }
+
+    async fn delete(
+        &self,
+        request: Request<proto::DeleteRequest>,
+    ) -> std::result::Result<Response<proto::DeleteResponse>, Status> {
+        let request = request.into_inner();
+        let id = model_id_from_proto(request.id)?;
+        self.with_db(move |db| {
+            let deleted = db.delete_model(&request.schema, id)?;
+            Ok(deleted.then_some(proto::DeleteResponse {}))
+        })
+        .await
+    }
+}
+
+impl From<KooError> for Status {
+    fn from(err: KooError) -> Status {
+        let message = err.to_string();
+        match err {
+            KooError::SchemaNotFound(_) => Status::not_found(message),
+            KooError::UnknownField { .. }
+            | KooError::MissingField { .. }
+            | KooError::Validation { .. }
+            | KooError::InvalidSchema(_)
+            | KooError::InvalidData(_) => Status::invalid_argument(message),
+            KooError::StaleVersion { .. } => Status::aborted(message),
+            KooError::AccessDenied { .. } => Status::permission_denied(message),
+            KooError::Busy { .. } => Status::unavailable(message),
+            KooError::ForeignKeyViolation { .. } => Status::failed_precondition(message),
+            KooError::Sqlite(rusqlite::Error::SqliteFailure(failure, _))
+                if failure.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                Status::failed_precondition(message)
+            }
+            _ => Status::internal(message),
+        }
+    }
+}
+
+pub fn value_to_proto(value: &Value) -> proto::Value {
+    let kind = match value {
+        Value::Null => proto::value::Kind::Null(true),
+        Value::Integer(i) => proto::value::Kind::Integer(*i),
+        Value::Real(f) => proto::value::Kind::Real(*f),
+        Value::Text(s) => proto::value::Kind::Text(s.clone()),
+        Value::Blob(b) => proto::value::Kind::Blob(b.clone()),
+    };
+    proto::Value { kind: Some(kind) }
+}
+
+// Missing values and values without a kind are NULL
+pub fn value_from_proto(value: Option<proto::Value>) -> Value {
+    match value.and_then(|value| value.kind) {
+        None | Some(proto::value::Kind::Null(_)) => Value::Null,
+        Some(proto::value::Kind::Integer(i)) => Value::Integer(i),
+        Some(proto::value::Kind::Real(f)) => Value::Real(f),
+        Some(proto::value::Kind::Text(s)) => Value::Text(s),
+        Some(proto::value::Kind::Blob(b)) => Value::Blob(b),
+    }
+}
+
+pub fn model_id_to_proto(id: &ModelId) -> proto::ModelId {
+    let kind = match id {
+        ModelId::Integer(id) => proto::model_id::Kind::Integer(*id),
+        ModelId::Text(id) => proto::model_id::Kind::Text(id.clone()),
+        ModelId::Composite(values) => proto::model_id::Kind::Composite(proto::CompositeId {
+            values: values.iter().map(value_to_proto).collect(),
+        }),
+    };
+    proto::ModelId { kind: Some(kind) }
+}
+
+pub fn model_id_from_proto(id: Option<proto::ModelId>) -> Result<ModelId> {
+    match id.and_then(|id| id.kind) {
+        None => Err(KooError::InvalidData("id is required".to_string())),
+        Some(proto::model_id::Kind::Integer(id)) => Ok(ModelId::Integer(id)),
+        Some(proto::model_id::Kind::Text(id)) => Ok(ModelId::Text(id)),
+        Some(proto::model_id::Kind::Composite(id)) => Ok(ModelId::Composite(
+            id.values
+                .into_iter()
+                .map(|value| value_from_proto(Some(value)))
+                .collect(),
+        )),
+    }
+}
+
+pub fn model_to_proto(model: &Model) -> proto::Model {
+    proto::Model {
+        id: model.id.as_ref().map(model_id_to_proto),
+        data: model
+            .data
+            .iter()
+            .map(|(name, value)| (name.clone(), value_to_proto(value)))
+            .collect(),
+    }
+}
+
+// A uint64 count, which may not fit a usize on 32-bit targets
+fn count_from_proto(count: u64, name: &str) -> Result<usize> {
+    usize::try_from(count).map_err(|_| KooError::InvalidData(format!("{} {} is too large", name, count)))
+}
+
+fn data_from_proto(data: HashMap<String, proto::Value>) -> HashMap<String, Value> {
+    data.into_iter()
+        .map(|(name, value)| (name, value_from_proto(Some(value))))
+        .collect()
+}
+
+// The proto form of a defined schema. Templates are expected to be merged
+// in already, as they are for schemas returned by `FlexibleDatabase`.
+pub fn schema_to_proto(schema: &Schema) -> proto::Schema {
+    let fields = schema
+        .fields
+        .iter()
+        .map(|(name, field_type)| {
+            let (kind, references, values) = match field_type {
+                FieldType::Text => (proto::FieldKind::Text, String::new(), Vec::new()),
+                FieldType::Integer => (proto::FieldKind::Integer, String::new(), Vec::new()),
+                FieldType::Real => (proto::FieldKind::Real, String::new(), Vec::new()),
+                FieldType::Boolean => (proto::FieldKind::Boolean, String::new(), Vec::new()),
+                FieldType::Reference(target) => (proto::FieldKind::Reference, target.clone(), Vec::new()),
+                FieldType::Polymorphic(targets) => (proto::FieldKind::Polymorphic, String::new(), targets.clone()),
+                FieldType::Enum(values) => (proto::FieldKind::Enum, String::new(), values.clone()),
+                FieldType::GeoPoint => (proto::FieldKind::GeoPoint, String::new(), Vec::new()),
+                FieldType::Vector(_) => (proto::FieldKind::Vector, String::new(), Vec::new()),
+            };
+            let validators = schema.validators.get(name).map(Vec::as_slice).unwrap_or_default();
+            proto::Field {
+                name: name.clone(),
+                kind: kind.into(),
+                references,
+                values,
+                default: schema.defaults.get(name).map(value_to_proto),
+                validators: validators.iter().map(validator_to_proto).collect(),
+                computed: schema.computed_fields.get(name).cloned().unwrap_or_default(),
+                dimensions: match field_type {
+                    FieldType::Vector(dimensions) => *dimensions as u32,
+                    _ => 0,
+                },
+            }
+        })
+        .collect();
+
+    let (key, key_fields) = match &schema.key {
+        PrimaryKey::Integer => (proto::KeyKind::Integer, Vec::new()),
+        PrimaryKey::Text => (proto::KeyKind::Text, Vec::new()),
+        PrimaryKey::Composite(key_fields) => (proto::KeyKind::Composite, key_fields.clone()),
+    };
+    let uuid_ids = match schema.uuid_ids {
+        None => proto::UuidVersion::None,
+        Some(UuidVersion::V4) => proto::UuidVersion::V4,
+        Some(UuidVersion::V7) => proto::UuidVersion::V7,
+    };
+
+    proto::Schema {
+        name: schema.name.clone(),
+        fields,
+        key: key.into(),
+        key_fields,
+        uuid_ids: uuid_ids.into(),
+        versioned: schema.versioned,
+        sql_checks: schema.sql_checks,
+        fts_fields: schema.fts_fields.clone(),
+        encrypted_fields: schema.encrypted_fields.clone(),
+        tenant_scoped: schema.tenant_scoped,
+        history: schema.history,
+        hierarchical: schema.hierarchical,
+        append_only: schema.append_only,
+        many_to_many: schema.many_to_many.clone().into_iter().collect(),
+        expiry: schema.expiry.as_ref().map(|expiry| proto::Expiry {
+            field: expiry.field.clone(),
+            flag: expiry.flag.clone().unwrap_or_default(),
+        }),
+    }
+}
+
+pub fn schema_from_proto(schema: proto::Schema) -> Result<Schema> {
+    let mut fields = Vec::new();
+    let mut defaults = HashMap::new();
+    let mut validators = HashMap::new();
+    let mut computed_fields = HashMap::new();
+    for field in schema.fields {
+        let field_type = match field.kind() {
+            proto::FieldKind::Text => FieldType::Text,
+            proto::FieldKind::Integer => FieldType::Integer,
+            proto::FieldKind::Real => FieldType::Real,
+            proto::FieldKind::Boolean => FieldType::Boolean,
+            proto::FieldKind::Reference => FieldType::Reference(field.references.clone()),
+            proto::FieldKind::Polymorphic => FieldType::Polymorphic(field.values.clone()),
+            proto::FieldKind::Enum => FieldType::Enum(field.values.clone()),
+            proto::FieldKind::GeoPoint => FieldType::GeoPoint,
+            proto::FieldKind::Vector => FieldType::Vector(field.dimensions as usize),
+            proto::FieldKind::Unspecified => {
+                return Err(KooError::InvalidSchema(format!(
+                    "field '{}.{}' has no type",
+                    schema.name, field.name
+                )));
+            }
+        };
+        if let Some(default) = field.default {
+            defaults.insert(field.name.clone(), value_from_proto(Some(default)));
+        }
+        if !field.computed.is_empty() {
+            computed_fields.insert(field.name.clone(), field.computed.clone());
+        }
+        if !field.validators.is_empty() {
+            let list = field
+                .validators
+                .into_iter()
+                .map(validator_from_proto)
+                .collect::<Result<Vec<_>>>()?;
+            validators.insert(field.name.clone(), list);
+        }
+        fields.push((field.name, field_type));
+    }
+
+    let mut defined = Schema::new(&schema.name, fields);
+    defined.key = match proto::KeyKind::try_from(schema.key).unwrap_or_default() {
+        proto::KeyKind::Integer => PrimaryKey::Integer,
+        proto::KeyKind::Text => PrimaryKey::Text,
+        proto::KeyKind::Composite => PrimaryKey::Composite(schema.key_fields.clone()),
+    };
+    defined.uuid_ids = match proto::UuidVersion::try_from(schema.uuid_ids).unwrap_or_default() {
+        proto::UuidVersion::None => None,
+        proto::UuidVersion::V4 => Some(UuidVersion::V4),
+        proto::UuidVersion::V7 => Some(UuidVersion::V7),
+    };
+    defined.versioned = schema.versioned;
+    defined.sql_checks = schema.sql_checks;
+    defined.defaults = defaults;
+    defined.computed_fields = computed_fields;
+    defined.validators = validators;
+    defined.fts_fields = schema.fts_fields;
+    defined.encrypted_fields = schema.encrypted_fields;
+    defined.tenant_scoped = schema.tenant_scoped;
+    defined.history = schema.history;
+    defined.hierarchical = schema.hierarchical;
+    defined.append_only = schema.append_only;
+    defined.expiry = schema.expiry.map(|expiry| Expiry {
+        field: expiry.field,
+        flag: Some(expiry.flag).filter(|flag| !flag.is_empty()),
+    });
+    let mut relations: Vec<(String, String)> = schema.many_to_many.into_iter().collect();
+    relations.sort();
+    defined.many_to_many = relations.into_iter().collect();
+    Ok(defined)
+}
+
+fn validator_to_proto(validator: &Validator) -> proto::Validator {
+    let kind = match validator {
+        Validator::Min(min) => proto::validator::Kind::Min(*min),
+        Validator::Max(max) => proto::validator::Kind::Max(*max),
+        Validator::MaxLength(max) => proto::validator::Kind::MaxLength(*max as u64),
+        Validator::Pattern(regex) => proto::validator::Kind::Pattern(regex.as_str().to_string()),
+    };
+    proto::Validator { kind: Some(kind) }
+}
+
+fn validator_from_proto(validator: proto::Validator) -> Result<Validator> {
+    match validator.kind {
+        Some(proto::validator::Kind::Min(min)) => Ok(Validator::Min(min)),
+        Some(proto::validator::Kind::Max(max)) => Ok(Validator::Max(max)),
+        Some(proto::validator::Kind::MaxLength(max)) => Ok(Validator::MaxLength(count_from_proto(max, "max_length")?)),
+        Some(proto::validator::Kind::Pattern(pattern)) => Validator::pattern(&pattern),
+        None => Err(KooError::InvalidSchema("validator has no kind".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_that_dont_fit_a_usize_are_invalid_arguments() {
+        assert_eq!(count_from_proto(25, "limit").unwrap(), 25);
+        match usize::try_from(u64::MAX) {
+            Ok(max) => assert_eq!(count_from_proto(u64::MAX, "offset").unwrap(), max),
+            Err(_) => {
+                let status = Status::from(count_from_proto(u64::MAX, "offset").unwrap_err());
+                assert_eq!(status.code(), tonic::Code::InvalidArgument);
+            }
+        }
+    }
+
+    fn books() -> Schema {
+        Schema::new("books", [("title".to_string(), FieldType::Text)])
+            .field(
+                "pages",
+                crate::flexible_database::FieldDef::new(FieldType::Integer)
+                    .with_default(100)
+                    .validate(Validator::Min(1.0)),
+            )
+            .field(
+                "genre",
+                FieldType::Enum(vec!["fiction".to_string(), "poetry".to_string()]),
+            )
+            .field("author", FieldType::Reference("authors".to_string()))
+            .with_versioning()
+    }
+
+    fn text(s: &str) -> proto::Value {
+        value_to_proto(&Value::Text(s.to_string()))
+    }
+
+    #[test]
+    fn schemas_round_trip_through_protobuf() {
+        let schema = books();
+        let read = schema_from_proto(schema_to_proto(&schema)).unwrap();
+        assert_eq!(read.name, schema.name);
+        assert_eq!(read.fields, schema.fields);
+        assert_eq!(read.defaults, schema.defaults);
+        assert_eq!(read.validators, schema.validators);
+        assert!(read.versioned);
+
+        let keyed = Schema::new("pairs", [("a".to_string(), FieldType::Integer)])
+            .with_key(PrimaryKey::Composite(vec!["a".to_string()]));
+        assert_eq!(schema_from_proto(schema_to_proto(&keyed)).unwrap().key, keyed.key);
+    }
+
+    #[test]
+    fn values_and_ids_round_trip_through_protobuf() {
+        for value in [
+            Value::Null,
+            Value::Integer(-4),
+            Value::Real(2.5),
+            Value::Text("é".to_string()),
+            Value::Blob(vec![0, 9]),
+        ] {
+            assert_eq!(value_from_proto(Some(value_to_proto(&value))), value);
+        }
+        assert_eq!(value_from_proto(None), Value::Null);
+
+        for id in [
+            ModelId::Integer(3),
+            ModelId::Text("a".to_string()),
+            ModelId::Composite(vec![Value::Integer(1), Value::Text("x".to_string())]),
+        ] {
+            assert_eq!(model_id_from_proto(Some(model_id_to_proto(&id))).unwrap(), id);
+        }
+        assert!(model_id_from_proto(None).is_err());
+    }
+
+    #[test]
+    fn the_service_answers_each_rpc() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let service = KooService::new(FlexibleDatabase::new(":memory:").unwrap());
+            let schema = Schema::new("notes", [("body".to_string(), FieldType::Text)]);
+            let define = proto::DefineSchemaRequest {
+                schema: Some(schema_to_proto(&schema)),
+            };
+            service.define_schema(Request::new(define)).await.unwrap();
+
+            for body in ["b", "a"] {
+                let create = proto::CreateRequest {
+                    schema: "notes".to_string(),
+                    data: HashMap::from([("body".to_string(), text(body))]),
+                };
+                service.create(Request::new(create)).await.unwrap();
+            }
+            let id = || Some(model_id_to_proto(&ModelId::Integer(1)));
+
+            let query = proto::QueryRequest {
+                schema: "notes".to_string(),
+                conditions: vec![proto::Condition {
+                    field: "body".to_string(),
+                    op: proto::Op::Ne as i32,
+                    value: Some(text("z")),
+                }],
+                order: vec![proto::Order {
+                    field: "body".to_string(),
+                    descending: false,
+                }],
+                limit: Some(1),
+                offset: 0,
+            };
+            let models = service.query(Request::new(query)).await.unwrap().into_inner().models;
+            assert_eq!(models.len(), 1);
+            assert_eq!(models[0].data["body"], text("a"));
+
+            let update = proto::UpdateRequest {
+                schema: "notes".to_string(),
+                id: id(),
+                data: HashMap::from([("body".to_string(), text("c"))]),
+            };
+            service.update(Request::new(update)).await.unwrap();
+            let get = || proto::GetRequest {
+                schema: "notes".to_string(),
+                id: id(),
+            };
+            let model = service.get(Request::new(get())).await.unwrap().into_inner().model;
+            assert_eq!(model.unwrap().data["body"], text("c"));
+
+            let delete = proto::DeleteRequest {
+                schema: "notes".to_string(),
+                id: id(),
+            };
+            service.delete(Request::new(delete)).await.unwrap();
+            let status = service.get(Request::new(get())).await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::NotFound);
+        });
+    }
+
+    #[test]
+    fn errors_map_to_status_codes() {
+        let code = |err: KooError| Status::from(err).code();
+        assert_eq!(code(KooError::SchemaNotFound("t".to_string())), tonic::Code::NotFound);
+        assert_eq!(
+            code(KooError::InvalidData("bad".to_string())),
+            tonic::Code::InvalidArgument
+        );
+        let stale = KooError::StaleVersion {
+            schema: "t".to_string(),
+            id: ModelId::Integer(1),
+            expected: 1,
+        };
+        assert_eq!(code(stale), tonic::Code::Aborted);
+    }
+}
@@ -0,0 +1,136 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::flexible_database::FlexibleDatabase;
+
+// What `health_check` found. Each check records its own failure, so one
+// that fails doesn't hide the outcome of the others.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HealthReport {
+    // Why the probe query failed, None if the connection answered it
+    pub connection_error: Option<String>,
+    // How long the probe query took to answer
+    pub probe_latency: Option<Duration>,
+    // Problems `PRAGMA quick_check` found in the file, or the error it
+    // failed with; empty when there are none
+    pub integrity_problems: Vec<String>,
+    // Free disk space left to the process where the database file lives.
+    // None for in-memory databases and on platforms that can't tell.
+    pub free_disk_bytes: Option<u64>,
+}
+
+impl HealthReport {
+    // Whether the connection answered and the file is sound. Whether the
+    // latency and free space are enough is left to the caller.
+    pub fn is_healthy(&self) -> bool {
+        self.connection_error.is_none() && self.integrity_problems.is_empty()
+    }
+}
+
+// Checks for readiness and liveness endpoints. `PRAGMA quick_check` reads
+// the whole file, so on large databases poll this sparingly.
+impl FlexibleDatabase {
+    pub fn health_check(&self) -> HealthReport {
+        let mut report = HealthReport::default();
+
+        let started = Instant::now();
+        match self.conn.query_row("SELECT 1", [], |row| row.get::<_, i64>(0)) {
+            Ok(_) => report.probe_latency = Some(started.elapsed()),
+            Err(err) => report.connection_error = Some(err.to_string()),
+        }
+
+        let quick_check = self.conn.prepare("PRAGMA quick_check").and_then(|mut stmt| {
+            stmt.query_map([], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()
+        });
+        report.integrity_problems = match quick_check {
+            Ok(messages) => messages.into_iter().filter(|message| message != "ok").collect(),
+            Err(err) => vec![err.to_string()],
+        };
+
+        report.free_disk_bytes = self
+            .conn
+            .path()
+            .filter(|path| !path.is_empty())
+            .and_then(|path| free_disk_bytes(Path::new(path)));
+        report
+    }
+}
+
+#[cfg(unix)]
+fn free_disk_bytes(path: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `stats` is only read once
+    // statvfs has filled it in
+    let stats = unsafe {
+        if libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stats.assume_init()
+    };
+    Some(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_disk_bytes(_path: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::Value;
+    use std::io::{Seek, SeekFrom, Write};
+
+    use crate::flexible_database::{FieldType, Schema};
+    use crate::temp_file::TempFile;
+
+    #[test]
+    fn sound_databases_are_healthy() {
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        let report = db.health_check();
+        assert!(report.is_healthy(), "{:?}", report);
+        assert!(report.probe_latency.is_some());
+        assert_eq!(report.free_disk_bytes, None);
+
+        let file = TempFile::new("db");
+        let db = FlexibleDatabase::new(file.path()).unwrap();
+        let report = db.health_check();
+        assert!(report.is_healthy(), "{:?}", report);
+        if cfg!(unix) {
+            assert!(report.free_disk_bytes.unwrap() > 0);
+        }
+    }
+
+    #[test]
+    fn damaged_files_report_their_problems() {
+        let file = TempFile::new("db");
+        {
+            let mut db = FlexibleDatabase::new(file.path()).unwrap();
+            db.define_schema(Schema::new("notes", [("body".to_string(), FieldType::Text)]).with_index("body"))
+                .unwrap();
+            let body = "x".repeat(500);
+            db.execute_raw(
+                "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 200)
+                 INSERT INTO notes (body) SELECT ? || i FROM n",
+                &[Value::Text(body)],
+            )
+            .unwrap();
+        }
+        // Scribble over a page past the schema
+        let mut damaged = std::fs::OpenOptions::new().write(true).open(file.path()).unwrap();
+        damaged.seek(SeekFrom::Start(4096 * 6)).unwrap();
+        damaged.write_all(&[0xA5; 4096]).unwrap();
+        drop(damaged);
+
+        let db = FlexibleDatabase::new(file.path()).unwrap();
+        let report = db.health_check();
+        assert!(report.connection_error.is_none());
+        assert!(!report.integrity_problems.is_empty());
+        assert!(!report.is_healthy());
+    }
+}
@@ -0,0 +1,441 @@
+use rusqlite::types::Value;
+
+use crate::error::{KooError, Result};
+use crate::field_encryption::DECRYPT_FUNCTION;
+use crate::flexible_database::{
+    FlexibleDatabase, Model, ModelId, PrimaryKey, Schema, VERSION_COLUMN, key_filter, read_model,
+};
+
+// Current time as history timestamps store it, with milliseconds
+const NOW: &str = "strftime('%Y-%m-%d %H:%M:%f', 'now')";
+
+// A field whose value differs between two points in time. None means the
+// model didn't exist then.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+// Schemas defined `with_history` keep every state of their models in a
+// `<schema>_history` table, filled by triggers so writes from outside
+// kooDB are kept too. Each row holds the model's columns and the UTC time
+// span, `valid_from` up to `valid_to`, during which they were current.
+// Timestamps are anything SQLite's date functions accept, such as
+// "2024-05-01 12:00:00" or "2024-05-01T12:00:00.250".
+impl FlexibleDatabase {
+    // The model as it was at `timestamp`, or None if it didn't exist then
+    pub fn get_model_as_of(&self, schema_name: &str, id: impl Into<ModelId>, timestamp: &str) -> Result<Option<Model>> {
+        let schema = self.history_schema(schema_name)?;
+        let id = id.into();
+        let timestamp = self.history_timestamp(timestamp)?;
+        let (key_sql, mut params) = key_filter(schema, &id)?;
+        params.push(Value::Text(timestamp.clone()));
+        params.push(Value::Text(timestamp));
+
+        // Laid out as `read_model` expects, with the history row's own id
+        // standing in for the rowid of models without an integer key
+        let mut selected = Vec::new();
+        if schema.key != PrimaryKey::Integer {
+            selected.push("history_id".to_string());
+        }
+        selected.extend(history_columns(schema).iter().map(|column| {
+            if schema.encrypted_fields.contains(column) {
+                format!("{}({})", DECRYPT_FUNCTION, column)
+            } else {
+                column.clone()
+            }
+        }));
+        let sql = format!(
+            "SELECT {} FROM {}_history WHERE {} AND valid_from <= ? AND (valid_to IS NULL OR valid_to > ?) \
+             ORDER BY history_id DESC LIMIT 1",
+            selected.join(", "),
+            schema.name,
+            key_sql
+        );
+
+        self.traced(&sql, params.len(), || {
+            let mut stmt = self.prepare_cached(&sql)?;
+            let mut rows = stmt.query(rusqlite::params_from_iter(&params))?;
+            match rows.next()? {
+                Some(row) => Ok((Some(read_model(row, schema)?), 1)),
+                None => Ok((None, 0)),
+            }
+        })
+        .map(|model| model.filter(|model| self.readable(schema_name, model)))
+    }
+
+    // Fields whose values differ between the model at `from` and at `to`,
+    // in field order
+    pub fn diff_model(
+        &self,
+        schema_name: &str,
+        id: impl Into<ModelId>,
+        from: &str,
+        to: &str,
+    ) -> Result<Vec<FieldChange>> {
+        let schema = self.history_schema(schema_name)?;
+        let id = id.into();
+        let before = self.get_model_as_of(schema_name, id.clone(), from)?;
+        let after = self.get_model_as_of(schema_name, id, to)?;
+
+        let mut changes = Vec::new();
+        for field_name in schema.fields.keys() {
+            let before = before
+                .as_ref()
+                .map(|m| m.data.get(field_name).cloned().unwrap_or(Value::Null));
+            let after = after
+                .as_ref()
+                .map(|m| m.data.get(field_name).cloned().unwrap_or(Value::Null));
+            if before != after {
+                changes.push(FieldChange {
+                    field: field_name.clone(),
+                    before,
+                    after,
+                });
+            }
+        }
+        Ok(changes)
+    }
+
+    // Create the history table of `schema`, or add columns it is missing,
+    // and (re)create the triggers that fill it
+    pub(crate) fn create_history(&self, schema: &Schema) -> Result<()> {
+        let table = &schema.name;
+        let history = format!("{}_history", table);
+        let columns = history_columns(schema);
+
+        let exists: bool = self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE name = ?)",
+            [&history],
+            |row| row.get(0),
+        )?;
+        if exists {
+            let mut stmt = self
+                .conn
+                .prepare(&format!("SELECT name FROM pragma_table_info('{}')", history))?;
+            let present = stmt
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            // Older states never had the column, so it stays empty for them
+            for column in columns.iter().filter(|c| !present.contains(c)) {
+                self.conn
+                    .execute(&format!("ALTER TABLE {} ADD COLUMN {}", history, column), [])?;
+            }
+        } else {
+            self.conn.execute_batch(&format!(
+                "CREATE TABLE {history} (history_id INTEGER PRIMARY KEY, {}, valid_from TEXT NOT NULL, valid_to TEXT);
+                CREATE INDEX {history}_key ON {history} ({});",
+                columns.join(", "),
+                key_columns(schema).join(", ")
+            ))?;
+        }
+
+        self.drop_history_triggers(table)?;
+        let names = columns.join(", ");
+        let new_values = prefixed(&columns, "new.");
+        let close_old = format!(
+            "UPDATE {history} SET valid_to = {NOW} WHERE {} AND valid_to IS NULL;",
+            key_columns(schema)
+                .iter()
+                .map(|c| format!("{c} = old.{c}"))
+                .collect::<Vec<_>>()
+                .join(" AND ")
+        );
+        self.conn.execute_batch(&format!(
+            "CREATE TRIGGER {table}_history_ai AFTER INSERT ON {table} BEGIN
+                INSERT INTO {history} ({names}, valid_from) VALUES ({new_values}, {NOW});
+            END;
+            CREATE TRIGGER {table}_history_au AFTER UPDATE ON {table} BEGIN
+                {close_old}
+                INSERT INTO {history} ({names}, valid_from) VALUES ({new_values}, {NOW});
+            END;
+            CREATE TRIGGER {table}_history_ad AFTER DELETE ON {table} BEGIN
+                {close_old}
+            END;"
+        ))?;
+
+        // Rows stored before history was enabled start from now
+        if !exists {
+            self.conn.execute(
+                &format!("INSERT INTO {history} ({names}, valid_from) SELECT {names}, {NOW} FROM {table}"),
+                [],
+            )?;
+        }
+        Ok(())
+    }
+
+    // Move the history recorded under `old_name` to `schema`, renamed from
+    // it, along with the index named after the table. History recorded
+    // before the schema stopped keeping it moves too.
+    pub(crate) fn rename_history(&self, old_name: &str, schema: &Schema) -> Result<()> {
+        let exists: bool = self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?)",
+            [format!("{}_history", old_name)],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Ok(());
+        }
+        let history = format!("{}_history", schema.name);
+        self.conn.execute_batch(&format!(
+            "ALTER TABLE {old_name}_history RENAME TO {history};
+            DROP INDEX IF EXISTS {old_name}_history_key;
+            CREATE INDEX {history}_key ON {history} ({});",
+            key_columns(schema).join(", ")
+        ))?;
+        Ok(())
+    }
+
+    // Remove the history triggers of a table, if it has them
+    pub(crate) fn drop_history_triggers(&self, table: &str) -> Result<()> {
+        self.conn.execute_batch(&format!(
+            "DROP TRIGGER IF EXISTS {table}_history_ai;
+            DROP TRIGGER IF EXISTS {table}_history_au;
+            DROP TRIGGER IF EXISTS {table}_history_ad;"
+        ))?;
+        Ok(())
+    }
+
+    fn history_schema(&self, schema_name: &str) -> Result<&Schema> {
+        let schema = self.schema_or_err(schema_name)?;
+        if !schema.history {
+            return Err(KooError::InvalidSchema(format!(
+                "schema '{}' doesn't keep history",
+                schema_name
+            )));
+        }
+        Ok(schema)
+    }
+
+    // `timestamp` in the stored form, so it compares as text
+    fn history_timestamp(&self, timestamp: &str) -> Result<String> {
+        let normalized: Option<String> =
+            self.conn
+                .query_row("SELECT strftime('%Y-%m-%d %H:%M:%f', ?)", [timestamp], |row| row.get(0))?;
+        normalized.ok_or_else(|| KooError::InvalidData(format!("'{}' is not a timestamp", timestamp)))
+    }
+}
+
+// Model columns copied into the history table
+fn history_columns(schema: &Schema) -> Vec<String> {
+    let mut columns = Vec::new();
+    if schema.key.has_id_column() {
+        columns.push("id".to_string());
+    }
+    columns.extend(schema.fields.keys().cloned());
+    if schema.versioned {
+        columns.push(VERSION_COLUMN.to_string());
+    }
+    columns
+}
+
+fn key_columns(schema: &Schema) -> Vec<String> {
+    match &schema.key {
+        PrimaryKey::Composite(key_fields) => key_fields.clone(),
+        _ => vec!["id".to_string()],
+    }
+}
+
+fn prefixed(columns: &[String], prefix: &str) -> String {
+    columns
+        .iter()
+        .map(|c| format!("{}{}", prefix, c))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use crate::access::{AccessPolicy, CallerContext};
+    use crate::flexible_database::FieldType;
+
+    fn prices() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(
+            Schema::new(
+                "prices",
+                [
+                    ("item".to_string(), FieldType::Text),
+                    ("cents".to_string(), FieldType::Integer),
+                ],
+            )
+            .with_history(),
+        )
+        .unwrap();
+        db
+    }
+
+    fn price(cents: i64) -> HashMap<String, Value> {
+        HashMap::from([
+            ("item".to_string(), Value::Text("tea".to_string())),
+            ("cents".to_string(), Value::Integer(cents)),
+        ])
+    }
+
+    // The current time in the stored form, after letting the clock move on
+    // so states written before and after it don't share a timestamp
+    fn now(db: &FlexibleDatabase) -> String {
+        sleep(Duration::from_millis(5));
+        let now = db
+            .conn
+            .query_row(&format!("SELECT {}", NOW), [], |row| row.get(0))
+            .unwrap();
+        sleep(Duration::from_millis(5));
+        now
+    }
+
+    fn cents_at(db: &FlexibleDatabase, id: &ModelId, timestamp: &str) -> Option<Value> {
+        let model = db.get_model_as_of("prices", id.clone(), timestamp).unwrap();
+        model.map(|model| model.data["cents"].clone())
+    }
+
+    #[test]
+    fn past_states_can_be_read_back() {
+        let db = prices();
+        let before = now(&db);
+        let id = db.create_model("prices", price(100)).unwrap();
+        let first = now(&db);
+        db.update_model("prices", id.clone(), price(120)).unwrap();
+        let second = now(&db);
+        db.delete_model("prices", id.clone()).unwrap();
+        let after = now(&db);
+
+        assert_eq!(cents_at(&db, &id, &before), None);
+        assert_eq!(cents_at(&db, &id, &first), Some(Value::Integer(100)));
+        assert_eq!(cents_at(&db, &id, &second), Some(Value::Integer(120)));
+        assert_eq!(cents_at(&db, &id, &after), None);
+
+        let model = db.get_model_as_of("prices", id.clone(), &first).unwrap().unwrap();
+        assert_eq!(model.id, Some(id));
+        assert!(!model.data.contains_key("id"));
+    }
+
+    #[test]
+    fn raw_writes_are_kept_too() {
+        let db = prices();
+        let id = db.create_model("prices", price(100)).unwrap();
+        let first = now(&db);
+        db.conn.execute("UPDATE prices SET cents = 90", []).unwrap();
+        let second = now(&db);
+
+        assert_eq!(cents_at(&db, &id, &first), Some(Value::Integer(100)));
+        assert_eq!(cents_at(&db, &id, &second), Some(Value::Integer(90)));
+    }
+
+    #[test]
+    fn diffs_list_the_changed_fields() {
+        let db = prices();
+        let before = now(&db);
+        let id = db.create_model("prices", price(100)).unwrap();
+        let first = now(&db);
+        db.update_model("prices", id.clone(), price(120)).unwrap();
+        let second = now(&db);
+
+        assert_eq!(
+            db.diff_model("prices", id.clone(), &first, &second).unwrap(),
+            [FieldChange {
+                field: "cents".to_string(),
+                before: Some(Value::Integer(100)),
+                after: Some(Value::Integer(120)),
+            }]
+        );
+        let created = db.diff_model("prices", id.clone(), &before, &first).unwrap();
+        assert_eq!(created.len(), 2);
+        assert!(created.iter().all(|change| change.before.is_none()));
+        assert!(db.diff_model("prices", id, &second, &second).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rows_stored_earlier_start_their_history_when_it_is_enabled() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new(
+            "prices",
+            [
+                ("item".to_string(), FieldType::Text),
+                ("cents".to_string(), FieldType::Integer),
+            ],
+        );
+        db.define_schema(schema.clone()).unwrap();
+        let id = db.create_model("prices", price(100)).unwrap();
+        let before = now(&db);
+        db.define_schema(schema.with_history()).unwrap();
+        let after = now(&db);
+
+        assert_eq!(cents_at(&db, &id, &before), None);
+        assert_eq!(cents_at(&db, &id, &after), Some(Value::Integer(100)));
+    }
+
+    #[test]
+    fn history_needs_a_schema_keeping_it_and_a_timestamp() {
+        let mut db = prices();
+        db.define_schema(Schema::new("plain", [("x".to_string(), FieldType::Integer)]))
+            .unwrap();
+        assert!(matches!(
+            db.get_model_as_of("plain", 1, "2024-01-01"),
+            Err(KooError::InvalidSchema(_))
+        ));
+        assert!(matches!(
+            db.get_model_as_of("prices", 1, "last tuesday"),
+            Err(KooError::InvalidData(_))
+        ));
+        assert!(
+            db.get_model_as_of("prices", 1, "2024-05-01T12:00:00.250")
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn past_states_read_back_as_get_model_reads_them() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("flags", [("enabled".to_string(), FieldType::Boolean)])
+            .with_key(PrimaryKey::Text)
+            .with_history();
+        db.define_schema(schema).unwrap();
+        let data = HashMap::from([
+            ("id".to_string(), Value::Text("dark".to_string())),
+            ("enabled".to_string(), Value::Integer(1)),
+        ]);
+        let id = db.create_model("flags", data).unwrap();
+        db.conn.execute("UPDATE flags SET enabled = 5", []).unwrap();
+        let later = now(&db);
+
+        let current = db.get_model("flags", id.clone()).unwrap().unwrap();
+        let past = db.get_model_as_of("flags", id, &later).unwrap().unwrap();
+        assert_eq!(past.data["enabled"], Value::Integer(1));
+        assert_eq!((past.id, past.data), (current.id, current.data));
+    }
+
+    #[test]
+    fn past_states_the_caller_cant_see_are_hidden() {
+        // Only the first price shows
+        struct Cheap;
+
+        impl AccessPolicy for Cheap {
+            fn can_read(&self, _schema: &str, model: &Model, _caller: &CallerContext) -> bool {
+                model.data["cents"] == Value::Integer(100)
+            }
+
+            fn can_write(&self, _schema: &str, _model: &Model, _caller: &CallerContext) -> bool {
+                true
+            }
+        }
+
+        let mut db = prices();
+        let id = db.create_model("prices", price(100)).unwrap();
+        let first = now(&db);
+        db.update_model("prices", id.clone(), price(120)).unwrap();
+        let second = now(&db);
+
+        db.set_access_policy(Cheap);
+        assert_eq!(cents_at(&db, &id, &first), Some(Value::Integer(100)));
+        assert_eq!(cents_at(&db, &id, &second), None);
+    }
+}
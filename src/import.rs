@@ -0,0 +1,671 @@
+use rusqlite::types::Value;
+use std::io::Read;
+use std::path::Path;
+
+use crate::error::{KooError, Result};
+use crate::export::csv_columns;
+use crate::expiry::Expiry;
+use crate::flexible_database::{FieldType, FlexibleDatabase, ModelId, PrimaryKey, Schema, UuidVersion, VERSION_COLUMN};
+use crate::geo::GeoPoint;
+use crate::relations::ModelRef;
+use crate::validation::{Validator, validate};
+use crate::wire::{check_version, json_to_value};
+
+// What to do when an imported row's id already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictStrategy {
+    // Keep the existing row and drop the imported one
+    Skip,
+    // Replace the existing row with the imported one
+    Overwrite,
+    // Abort the whole import
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    pub on_conflict: ConflictStrategy,
+    // Insert rows with their exported ids. When false every row of a schema
+    // with integer keys gets a fresh id, so conflicts can't happen.
+    pub preserve_ids: bool,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        ImportOptions {
+            on_conflict: ConflictStrategy::Error,
+            preserve_ids: true,
+        }
+    }
+}
+
+// Row counts produced by an import
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    pub schemas: usize,
+    pub inserted: usize,
+    pub skipped: usize,
+}
+
+impl FlexibleDatabase {
+    // Load a document produced by `export_json` (or a single schema object
+    // from `export_schema_json`). Schemas are defined as needed and all rows
+    // are inserted within one transaction, so a failed import changes nothing.
+    pub fn import_json<R: Read>(&mut self, reader: R, options: ImportOptions) -> Result<ImportReport> {
+        let document: serde_json::Value = serde_json::from_reader(reader)?;
+        // Documents from before versioning was introduced use version 1
+        check_version(document.get("version").and_then(|v| v.as_u64()).unwrap_or(1))?;
+
+        let entries = match document.get("schemas") {
+            Some(serde_json::Value::Array(entries)) => entries.clone(),
+            Some(_) => return Err(KooError::InvalidData("'schemas' must be an array".to_string())),
+            None => vec![document],
+        };
+
+        self.in_transaction(|db| {
+            // A row can come before the row it references, e.g. a child
+            // moved under a parent created after it
+            db.conn.execute_batch("PRAGMA defer_foreign_keys = ON")?;
+            let mut report = ImportReport::default();
+            for entry in &entries {
+                db.import_schema_entry(entry, &options, &mut report)?;
+            }
+            Ok(report)
+        })
+    }
+
+    // Insert the rows of a CSV file into an existing schema, converting each
+    // cell according to the field's type. With headers, columns are matched
+    // by name; without, they must follow the `export_csv` layout. An empty
+    // or missing id column assigns fresh integer ids, or generated UUIDs for
+    // schemas with them. Rows are validated as `create_model` validates
+    // them. Returns the number of rows inserted; a bad row aborts the whole
+    // import.
+    pub fn import_csv<P: AsRef<Path>>(&mut self, schema_name: &str, path: P, has_headers: bool) -> Result<usize> {
+        let schema = self.schema_or_err(schema_name)?.clone();
+        let id_type = match schema.key {
+            PrimaryKey::Text => FieldType::Text,
+            _ => FieldType::Integer,
+        };
+
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(has_headers)
+            .from_path(path)?;
+
+        let columns: Vec<String> = if has_headers {
+            let headers = reader.headers()?;
+            let mut columns = Vec::new();
+            for header in headers {
+                let header = header.trim();
+                let is_id = header == "id" && schema.key.has_id_column();
+                if !is_id && !schema.fields.contains_key(header) {
+                    return Err(KooError::UnknownField {
+                        schema: schema_name.to_string(),
+                        field: header.to_string(),
+                    });
+                }
+                columns.push(header.to_string());
+            }
+            columns
+        } else {
+            let mut columns = vec![];
+            if schema.key.has_id_column() {
+                columns.push("id".to_string());
+            }
+            columns.extend(csv_columns(&schema).into_iter().cloned());
+            columns
+        };
+
+        self.in_transaction(|db| {
+            let mut inserted = 0;
+            for (line, record) in (1..).zip(reader.records()) {
+                let record = record?;
+                if record.len() != columns.len() {
+                    return Err(KooError::InvalidData(format!(
+                        "csv record {} has {} columns, expected {}",
+                        line,
+                        record.len(),
+                        columns.len()
+                    )));
+                }
+
+                let mut names = vec![];
+                let mut values: Vec<Value> = vec![];
+                let mut checked = vec![];
+                for (column, cell) in columns.iter().zip(record.iter()) {
+                    // Exported with the rest, but SQLite fills them in
+                    if schema.is_computed(column) {
+                        continue;
+                    }
+                    if !schema.fields.contains_key(column) {
+                        if cell.trim().is_empty() {
+                            continue;
+                        }
+                        names.push(column.as_str());
+                        values.push(csv_to_value(cell, &id_type, schema_name, column, line)?);
+                    } else {
+                        names.push(column.as_str());
+                        // `export_csv` writes NULL as an empty cell
+                        let value = if cell.is_empty() && schema.is_nullable(column) {
+                            Value::Null
+                        } else {
+                            csv_to_value(cell, &schema.fields[column], schema_name, column, line)?
+                        };
+                        values.push(db.seal_field(&schema, column, value.clone())?);
+                        checked.push((column, value));
+                    }
+                }
+                validate(&schema, checked.iter().map(|(column, value)| (*column, value)))?;
+                // Generated as `create_model` generates them
+                if let Some(version) = schema.uuid_ids
+                    && !names.contains(&"id")
+                {
+                    names.push("id");
+                    values.push(Value::Text(version.generate()));
+                }
+
+                let placeholders = vec!["?"; names.len()];
+                let sql = format!(
+                    "INSERT INTO {} ({}) VALUES ({})",
+                    schema_name,
+                    names.join(", "),
+                    placeholders.join(", ")
+                );
+                let id = inserted_id(&schema, &names, &values);
+                db.audit_insert(&schema, id, || Ok(db.conn.execute(&sql, rusqlite::params_from_iter(&values))?))?;
+                inserted += 1;
+            }
+            Ok(inserted)
+        })
+    }
+
+    fn import_schema_entry(
+        &mut self,
+        entry: &serde_json::Value,
+        options: &ImportOptions,
+        report: &mut ImportReport,
+    ) -> Result<()> {
+        let schema = schema_from_json(entry)?;
+        let name = schema.name.clone();
+        let name = name.as_str();
+        self.define_schema(schema)?;
+        report.schemas += 1;
+
+        let rows = match entry.get("rows") {
+            Some(serde_json::Value::Array(rows)) => rows.as_slice(),
+            Some(_) => return Err(KooError::InvalidData(format!("'{}.rows' must be an array", name))),
+            None => &[],
+        };
+        let schema = self.schema_or_err(name)?;
+        if schema.append_only && options.on_conflict == ConflictStrategy::Overwrite {
+            return Err(KooError::InvalidSchema(format!(
+                "schema '{}' is append-only, so its rows can't be overwritten",
+                name
+            )));
+        }
+
+        let verb = match options.on_conflict {
+            ConflictStrategy::Skip => "INSERT OR IGNORE",
+            ConflictStrategy::Overwrite => "INSERT OR REPLACE",
+            ConflictStrategy::Error => "INSERT",
+        };
+
+        for row in rows {
+            let object = row
+                .as_object()
+                .ok_or_else(|| KooError::InvalidData(format!("rows of '{}' must be objects", name)))?;
+
+            let mut columns = vec![];
+            let mut values: Vec<Value> = vec![];
+            let mut checked = vec![];
+            for (field_name, json) in object {
+                if field_name == "id" {
+                    // Text ids are the only way to address their models, so
+                    // they are always kept
+                    match schema.key {
+                        PrimaryKey::Integer if options.preserve_ids => {
+                            columns.push("id".to_string());
+                            values.push(json_to_value(json, &FieldType::Integer, name, field_name)?);
+                        }
+                        PrimaryKey::Text => {
+                            columns.push("id".to_string());
+                            values.push(json_to_value(json, &FieldType::Text, name, field_name)?);
+                        }
+                        _ => {}
+                    }
+                    continue;
+                }
+                if field_name == VERSION_COLUMN && schema.versioned {
+                    columns.push(field_name.clone());
+                    values.push(json_to_value(json, &FieldType::Integer, name, field_name)?);
+                    continue;
+                }
+                let field_type = schema.fields.get(field_name).ok_or_else(|| KooError::UnknownField {
+                    schema: name.to_string(),
+                    field: field_name.clone(),
+                })?;
+                if schema.is_computed(field_name) {
+                    continue;
+                }
+                columns.push(field_name.clone());
+                let value = json_to_value(json, field_type, name, field_name)?;
+                values.push(self.seal_field(schema, field_name, value.clone())?);
+                checked.push((field_name, value));
+            }
+            // Checked as `create_model` checks them, and given an id the
+            // same way
+            validate(schema, checked.iter().map(|(field_name, value)| (*field_name, value)))?;
+            if let Some(version) = schema.uuid_ids
+                && !object.contains_key("id")
+            {
+                columns.push("id".to_string());
+                values.push(Value::Text(version.generate()));
+            }
+
+            let placeholders = vec!["?"; columns.len()];
+            let sql = format!(
+                "{} INTO {} ({}) VALUES ({})",
+                verb,
+                name,
+                columns.join(", "),
+                placeholders.join(", ")
+            );
+            let id = inserted_id(schema, &columns, &values);
+            let changed =
+                self.audit_insert(schema, id, || Ok(self.conn.execute(&sql, rusqlite::params_from_iter(&values))?))?;
+            if changed > 0 {
+                report.inserted += 1;
+            } else {
+                report.skipped += 1;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Id of a row about to be inserted, if it is given rather than numbered
+pub(crate) fn inserted_id(schema: &Schema, columns: &[impl AsRef<str>], values: &[Value]) -> Option<ModelId> {
+    let value = |name: &str| {
+        let index = columns.iter().position(|column| column.as_ref() == name)?;
+        Some(values[index].clone())
+    };
+    match &schema.key {
+        PrimaryKey::Integer => match value("id") {
+            Some(Value::Integer(id)) => Some(ModelId::Integer(id)),
+            _ => None,
+        },
+        PrimaryKey::Text => match value("id") {
+            Some(Value::Text(id)) => Some(ModelId::Text(id)),
+            _ => None,
+        },
+        PrimaryKey::Composite(key_fields) => key_fields
+            .iter()
+            .map(|field_name| value(field_name))
+            .collect::<Option<Vec<_>>>()
+            .map(ModelId::Composite),
+    }
+}
+
+// Schema described by an `export_schema_json` style object. Rows and the
+// version are ignored.
+pub(crate) fn schema_from_json(entry: &serde_json::Value) -> Result<Schema> {
+    let name = entry
+        .get("name")
+        .and_then(|name| name.as_str())
+        .ok_or_else(|| KooError::InvalidData("schema entry is missing 'name'".to_string()))?;
+
+    let mut fields = Vec::new();
+    let field_entries = entry
+        .get("fields")
+        .and_then(|fields| fields.as_object())
+        .ok_or_else(|| KooError::InvalidData(format!("schema '{}' is missing 'fields'", name)))?;
+    for (field_name, type_name) in field_entries {
+        let field_type = type_name
+            .as_str()
+            .and_then(FieldType::from_name)
+            .ok_or_else(|| {
+                KooError::InvalidData(format!("field '{}.{}' has an unknown type", name, field_name))
+            })?;
+        fields.push((field_name.clone(), field_type));
+    }
+
+    let mut schema = Schema::new(name, fields);
+    match entry.get("key") {
+        None => {}
+        Some(serde_json::Value::String(key)) if key == "Text" => schema.key = PrimaryKey::Text,
+        Some(serde_json::Value::Array(key_fields)) => {
+            let key_fields: Option<Vec<String>> =
+                key_fields.iter().map(|f| f.as_str().map(String::from)).collect();
+            let key_fields = key_fields
+                .ok_or_else(|| KooError::InvalidData(format!("key of '{}' must list field names", name)))?;
+            schema.key = PrimaryKey::Composite(key_fields);
+        }
+        Some(key) => return Err(KooError::InvalidData(format!("'{}' has an unknown key {}", name, key))),
+    }
+    schema.versioned = entry.get("versioned").and_then(|v| v.as_bool()).unwrap_or(false);
+    schema.sql_checks = entry.get("sql_checks").and_then(|v| v.as_bool()).unwrap_or(false);
+    schema.tenant_scoped = entry.get("tenant_scoped").and_then(|v| v.as_bool()).unwrap_or(false);
+    schema.history = entry.get("history").and_then(|v| v.as_bool()).unwrap_or(false);
+    schema.hierarchical = entry.get("hierarchical").and_then(|v| v.as_bool()).unwrap_or(false);
+    schema.append_only = entry.get("append_only").and_then(|v| v.as_bool()).unwrap_or(false);
+    schema.uuid_ids = match entry.get("uuid").and_then(|uuid| uuid.as_str()) {
+        None => None,
+        Some("v4") => Some(UuidVersion::V4),
+        Some("v7") => Some(UuidVersion::V7),
+        Some(other) => {
+            return Err(KooError::InvalidData(format!("'{}' has an unknown uuid version '{}'", name, other)));
+        }
+    };
+    if let Some(defaults) = entry.get("defaults").and_then(|defaults| defaults.as_object()) {
+        for (field_name, json) in defaults {
+            let field_type = schema.fields.get(field_name).ok_or_else(|| KooError::UnknownField {
+                schema: name.to_string(),
+                field: field_name.clone(),
+            })?;
+            let default = json_to_value(json, field_type, name, field_name)?;
+            schema.defaults.insert(field_name.clone(), default);
+        }
+    }
+    if let Some(computed) = entry.get("computed").and_then(|computed| computed.as_object()) {
+        for (field_name, expression) in computed {
+            let expression = expression.as_str().ok_or_else(|| {
+                KooError::InvalidData(format!("'{}.{}' must be computed from an expression", name, field_name))
+            })?;
+            schema.computed_fields.insert(field_name.clone(), expression.to_string());
+        }
+    }
+    if let Some(expiry) = entry.get("expiry") {
+        let field = expiry.get("field").and_then(|field| field.as_str()).ok_or_else(|| {
+            KooError::InvalidData(format!("expiry of '{}' must name a field", name))
+        })?;
+        schema.expiry = Some(Expiry {
+            field: field.to_string(),
+            flag: expiry.get("flag").and_then(|flag| flag.as_str()).map(String::from),
+        });
+    }
+    if let Some(validators) = entry.get("validators").and_then(|validators| validators.as_object()) {
+        for (field_name, list) in validators {
+            let list = list.as_array().map(Vec::as_slice).unwrap_or_default();
+            let list = list
+                .iter()
+                .map(|json| {
+                    Validator::from_json(json).unwrap_or_else(|| {
+                        Err(KooError::InvalidData(format!(
+                            "'{}.{}' has an unknown validator {}",
+                            name, field_name, json
+                        )))
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            schema.validators.insert(field_name.clone(), list);
+        }
+    }
+    if let Some(fts) = entry.get("fts").and_then(|fts| fts.as_array()) {
+        schema.fts_fields = fts.iter().filter_map(|f| f.as_str()).map(String::from).collect();
+    }
+    if let Some(encrypted) = entry.get("encrypted").and_then(|encrypted| encrypted.as_array()) {
+        schema.encrypted_fields = encrypted.iter().filter_map(|f| f.as_str()).map(String::from).collect();
+    }
+    if let Some(nullable) = entry.get("nullable").and_then(|nullable| nullable.as_array()) {
+        schema.nullable_fields = nullable.iter().filter_map(|f| f.as_str()).map(String::from).collect();
+    }
+    if let Some(indexes) = entry.get("indexes").and_then(|indexes| indexes.as_array()) {
+        schema.indexed_fields = indexes.iter().filter_map(|f| f.as_str()).map(String::from).collect();
+    }
+    if let Some(unique) = entry.get("unique").and_then(|unique| unique.as_array()) {
+        schema.unique_fields = unique.iter().filter_map(|f| f.as_str()).map(String::from).collect();
+    }
+    if let Some(relations) = entry.get("many_to_many").and_then(|relations| relations.as_object()) {
+        for (target, join_table) in relations {
+            let join_table = join_table.as_str().ok_or_else(|| {
+                KooError::InvalidData(format!("join table of '{}' and '{}' must be a name", name, target))
+            })?;
+            schema.many_to_many.insert(target.clone(), join_table.to_string());
+        }
+    }
+    Ok(schema)
+}
+
+// Convert a CSV cell into a value for the given field type
+fn csv_to_value(cell: &str, field_type: &FieldType, schema_name: &str, field_name: &str, line: usize) -> Result<Value> {
+    let trimmed = cell.trim();
+    let value = match field_type {
+        FieldType::Text | FieldType::Enum(_) => Some(Value::Text(cell.to_string())),
+        FieldType::GeoPoint => serde_json::from_str(trimmed)
+            .ok()
+            .and_then(|json| GeoPoint::from_json(&json))
+            .map(Value::from),
+        FieldType::Polymorphic(_) => serde_json::from_str(trimmed)
+            .ok()
+            .and_then(|json| ModelRef::from_json(&json))
+            .map(Value::from),
+        FieldType::Vector(_) => serde_json::from_str(trimmed)
+            .ok()
+            .and_then(|json| json_to_value(&json, field_type, schema_name, field_name).ok()),
+        FieldType::Integer | FieldType::Reference(_) => trimmed.parse().ok().map(Value::Integer),
+        FieldType::Real => trimmed.parse().ok().map(Value::Real),
+        FieldType::Boolean => match trimmed.to_ascii_lowercase().as_str() {
+            "true" | "1" | "yes" => Some(Value::Integer(1)),
+            "false" | "0" | "no" => Some(Value::Integer(0)),
+            _ => None,
+        },
+    };
+
+    value.ok_or_else(|| {
+        KooError::InvalidData(format!(
+            "csv record {}: '{}.{}' expects {}, got '{}'",
+            line,
+            schema_name,
+            field_name,
+            field_type.name(),
+            cell
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flexible_database::{FieldDef, ModelId};
+    use crate::model_builder::ModelBuilder;
+    use crate::temp_file::TempFile;
+
+    fn people() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("people", [("name".to_string(), FieldType::Text)])
+            .field("role", FieldType::Enum(vec!["admin".to_string(), "user".to_string()]));
+        db.define_schema(schema).unwrap();
+        db.create_model("people", ModelBuilder::new().text("name", "Ada").text("role", "admin").build()).unwrap();
+        db.create_model("people", ModelBuilder::new().text("name", "Bob").text("role", "user").build()).unwrap();
+        db
+    }
+
+    fn exported(db: &FlexibleDatabase) -> Vec<u8> {
+        let mut out = Vec::new();
+        db.export_json(&mut out).unwrap();
+        out
+    }
+
+    fn names(db: &FlexibleDatabase) -> Vec<String> {
+        let mut names: Vec<String> = db
+            .get_all_models("people")
+            .unwrap()
+            .iter()
+            .map(|model| model.get_str("name").unwrap().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    // The export of `db`'s first schema, holding `rows` instead of its own
+    fn with_rows(db: &FlexibleDatabase, rows: serde_json::Value) -> Vec<u8> {
+        let document: serde_json::Value = serde_json::from_slice(&exported(db)).unwrap();
+        let mut entry = document["schemas"][0].clone();
+        entry["rows"] = rows;
+        entry.to_string().into_bytes()
+    }
+
+    #[test]
+    fn json_exports_import_into_an_empty_database() {
+        let source = people();
+        let mut target = FlexibleDatabase::new(":memory:").unwrap();
+        let report = target.import_json(exported(&source).as_slice(), ImportOptions::default()).unwrap();
+        assert_eq!(report, ImportReport { schemas: 1, inserted: 2, skipped: 0 });
+        assert_eq!(names(&target), ["Ada", "Bob"]);
+        assert_eq!(target.get_model("people", 2).unwrap().unwrap().get_str("name").unwrap(), "Bob");
+    }
+
+    #[test]
+    fn conflicting_ids_follow_the_strategy() {
+        let source = people();
+        let mut target = people();
+        target.update_model("people", 1, ModelBuilder::new().text("name", "Ann").build()).unwrap();
+        let document = exported(&source);
+
+        let error = ImportOptions::default();
+        assert!(target.import_json(document.as_slice(), error).is_err());
+        assert_eq!(names(&target), ["Ann", "Bob"]);
+
+        let skip = ImportOptions { on_conflict: ConflictStrategy::Skip, preserve_ids: true };
+        let report = target.import_json(document.as_slice(), skip).unwrap();
+        assert_eq!((report.inserted, report.skipped), (0, 2));
+        assert_eq!(names(&target), ["Ann", "Bob"]);
+
+        let overwrite = ImportOptions { on_conflict: ConflictStrategy::Overwrite, preserve_ids: true };
+        target.import_json(document.as_slice(), overwrite).unwrap();
+        assert_eq!(names(&target), ["Ada", "Bob"]);
+    }
+
+    #[test]
+    fn fresh_ids_never_conflict() {
+        let source = people();
+        let mut target = people();
+        let options = ImportOptions { on_conflict: ConflictStrategy::Error, preserve_ids: false };
+        let report = target.import_json(exported(&source).as_slice(), options).unwrap();
+        assert_eq!(report.inserted, 2);
+        assert_eq!(target.count("people").unwrap(), 4);
+    }
+
+    #[test]
+    fn rows_are_validated_like_created_models() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let rows = serde_json::json!([{"name": "Ada", "role": "admin"}, {"name": "Zed", "role": "zzz"}]);
+        let result = db.import_json(with_rows(&people(), rows).as_slice(), ImportOptions::default());
+        assert!(matches!(result, Err(KooError::Validation { .. })), "{:?}", result);
+        // Nothing of a failed import is kept, the schema included
+        assert!(db.schema("people").is_none());
+    }
+
+    #[test]
+    fn rows_are_checked_against_field_validators() {
+        let mut source = FlexibleDatabase::new(":memory:").unwrap();
+        let points = FieldDef::new(FieldType::Integer).validate(Validator::Min(0.0));
+        source.define_schema(Schema::new("scores", []).field("points", points)).unwrap();
+        let document = with_rows(&source, serde_json::json!([{"points": 3}, {"points": -1}]));
+
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let result = db.import_json(document.as_slice(), ImportOptions::default());
+        assert!(matches!(result, Err(KooError::Validation { .. })), "{:?}", result);
+        assert!(db.schema("scores").is_none());
+    }
+
+    #[test]
+    fn csv_exports_import_into_an_empty_schema() {
+        let source = people();
+        let file = TempFile::new("csv");
+        assert_eq!(source.export_csv("people", file.path()).unwrap(), 2);
+
+        let mut target = people();
+        target.execute_raw("DELETE FROM people", &[]).unwrap();
+        assert_eq!(target.import_csv("people", file.path(), true).unwrap(), 2);
+        assert_eq!(names(&target), ["Ada", "Bob"]);
+        assert_eq!(target.get_model("people", 2).unwrap().unwrap().get_str("role").unwrap(), "user");
+    }
+
+    #[test]
+    fn empty_csv_cells_of_nullable_fields_import_as_null() {
+        let mut source = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("t", [])
+            .field("n", FieldDef::new(FieldType::Integer).nullable())
+            .field("x", FieldDef::new(FieldType::Real).nullable())
+            .field("s", FieldDef::new(FieldType::Text).nullable());
+        source.define_schema(schema.clone()).unwrap();
+        source.create_model("t", ModelBuilder::new().build()).unwrap();
+        let file = TempFile::new("csv");
+        source.export_csv("t", file.path()).unwrap();
+
+        let mut target = FlexibleDatabase::new(":memory:").unwrap();
+        target.define_schema(schema).unwrap();
+        assert_eq!(target.import_csv("t", file.path(), true).unwrap(), 1);
+        let model = target.get_model("t", 1).unwrap().unwrap();
+        for field in ["n", "x", "s"] {
+            assert_eq!(model.data.get(field), Some(&Value::Null), "{}", field);
+        }
+    }
+
+    #[test]
+    fn csv_without_headers_follows_the_export_layout() {
+        let mut db = people();
+        let file = TempFile::with_contents("csv", ",Cy,user\n,Di,admin\n");
+        assert_eq!(db.import_csv("people", file.path(), false).unwrap(), 2);
+        assert_eq!(names(&db), ["Ada", "Bob", "Cy", "Di"]);
+    }
+
+    #[test]
+    fn csv_rows_are_validated_and_a_bad_one_imports_nothing() {
+        let mut db = people();
+        let file = TempFile::with_contents("csv", "name,role\nCy,user\nZed,zzz\n");
+        let result = db.import_csv("people", file.path(), true);
+        assert!(matches!(result, Err(KooError::Validation { .. })), "{:?}", result);
+        assert_eq!(names(&db), ["Ada", "Bob"]);
+    }
+
+    #[test]
+    fn csv_cells_that_dont_parse_are_errors() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("t", [("n".to_string(), FieldType::Integer)])).unwrap();
+        let file = TempFile::with_contents("csv", "n\n1\nlots\n");
+        assert!(db.import_csv("t", file.path(), true).is_err());
+        assert_eq!(db.count("t").unwrap(), 0);
+
+        let unknown = TempFile::with_contents("csv", "m\n1\n");
+        assert!(matches!(db.import_csv("t", unknown.path(), true), Err(KooError::UnknownField { .. })));
+    }
+
+    #[test]
+    fn unknown_fields_and_versions_are_refused() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let rows = serde_json::json!([{"name": "Ada", "age": 3}]);
+        assert!(matches!(
+            db.import_json(with_rows(&people(), rows).as_slice(), ImportOptions::default()),
+            Err(KooError::UnknownField { .. })
+        ));
+        let future = serde_json::json!({"version": 99, "schemas": []}).to_string();
+        assert!(db.import_json(future.as_bytes(), ImportOptions::default()).is_err());
+    }
+
+    #[test]
+    fn rows_without_ids_get_generated_uuids() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("tags", [("label".to_string(), FieldType::Text)]).with_uuid_ids(UuidVersion::V4);
+        db.define_schema(schema).unwrap();
+        let with_ids = TempFile::with_contents("csv", "id,label\n,red\nkept,blue\n");
+        let without = TempFile::with_contents("csv", "label\ngreen\n");
+        assert_eq!(db.import_csv("tags", with_ids.path(), true).unwrap(), 2);
+        assert_eq!(db.import_csv("tags", without.path(), true).unwrap(), 1);
+
+        let mut source = FlexibleDatabase::new(":memory:").unwrap();
+        source.define_schema(db.schema("tags").unwrap().clone()).unwrap();
+        let rows = serde_json::json!([{"label": "grey"}]);
+        db.import_json(with_rows(&source, rows).as_slice(), ImportOptions::default()).unwrap();
+
+        let tags = db.get_all_models("tags").unwrap();
+        assert_eq!(tags.len(), 4);
+        assert!(db.get_model("tags", "kept").unwrap().is_some());
+        for tag in &tags {
+            let Some(ModelId::Text(id)) = &tag.id else { panic!("{:?}", tag.id) };
+            assert!(id == "kept" || uuid::Uuid::parse_str(id).is_ok(), "{}", id);
+        }
+    }
+}
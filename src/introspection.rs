@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+
+use crate::error::Result;
+use crate::flexible_database::{FlexibleDatabase, Schema};
+use crate::maintenance::DatabaseSize;
+
+// A column of a table as SQLite reports it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnInfo {
+    pub name: String,
+    // Declared type, e.g. "INTEGER"
+    pub sql_type: String,
+    pub not_null: bool,
+    // Default as written in the DDL
+    pub default: Option<String>,
+    // Position within the primary key, starting at 1; 0 when not part of it
+    pub primary_key: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexInfo {
+    pub name: String,
+    pub columns: Vec<String>,
+    pub unique: bool,
+}
+
+// What SQLite knows about the table behind a schema
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableInfo {
+    pub name: String,
+    pub columns: Vec<ColumnInfo>,
+    pub indexes: Vec<IndexInfo>,
+    pub row_count: i64,
+    // Bytes used by the table's pages, not counting its indexes. None when
+    // the SQLite build has no dbstat table.
+    pub size_bytes: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexStats {
+    pub name: String,
+    pub size_bytes: Option<i64>,
+}
+
+// Size and row count of one schema's table, from `FlexibleDatabase::stats`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaStats {
+    pub name: String,
+    pub row_count: i64,
+    // Bytes used by the table's pages, not counting its indexes
+    pub size_bytes: Option<i64>,
+    // Indexes on the table, by name, including the ones SQLite makes for
+    // text and composite keys
+    pub indexes: Vec<IndexStats>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatabaseStats {
+    // Every defined schema, by name
+    pub schemas: Vec<SchemaStats>,
+    // The main database file
+    pub size: DatabaseSize,
+}
+
+impl FlexibleDatabase {
+    // Defined schemas, ordered by name
+    pub fn schemas(&self) -> Vec<&Schema> {
+        let mut schemas: Vec<&Schema> = self.schemas.values().collect();
+        schemas.sort_by(|a, b| a.name.cmp(&b.name));
+        schemas
+    }
+
+    pub fn schema(&self, schema_name: &str) -> Option<&Schema> {
+        self.schemas.get(schema_name)
+    }
+
+    // Columns, indexes, row count and size of a schema's table, read from
+    // SQLite rather than the registry so it also shows changes made outside
+    // kooDB
+    pub fn table_info(&self, schema_name: &str) -> Result<TableInfo> {
+        self.schema_or_err(schema_name)?;
+
+        let mut stmt = self.conn.prepare("SELECT name, type, \"notnull\", dflt_value, pk FROM pragma_table_info(?)")?;
+        let columns = stmt
+            .query_map([schema_name], |row| {
+                Ok(ColumnInfo {
+                    name: row.get(0)?,
+                    sql_type: row.get(1)?,
+                    not_null: row.get(2)?,
+                    default: row.get(3)?,
+                    primary_key: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut stmt = self.conn.prepare("SELECT name, \"unique\" FROM pragma_index_list(?) ORDER BY name")?;
+        let index_list = stmt
+            .query_map([schema_name], |row| Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut indexes = Vec::new();
+        for (name, unique) in index_list {
+            let mut stmt = self.conn.prepare("SELECT name FROM pragma_index_info(?) ORDER BY seqno")?;
+            let columns = stmt
+                .query_map([&name], |row| row.get::<_, Option<String>>(0))?
+                .filter_map(|column| column.transpose())
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            indexes.push(IndexInfo { name, columns, unique });
+        }
+
+        let row_count = self.count(schema_name)?;
+
+        // dbstat is optional in SQLite builds, so a failure here just means
+        // the size is unknown
+        let size_bytes = self
+            .conn
+            .query_row("SELECT SUM(pgsize) FROM dbstat WHERE name = ?", [schema_name], |row| {
+                row.get::<_, Option<i64>>(0)
+            })
+            .ok()
+            .flatten();
+
+        Ok(TableInfo {
+            name: schema_name.to_string(),
+            columns,
+            indexes,
+            row_count,
+            size_bytes,
+        })
+    }
+
+    // Row counts and sizes of every defined schema and of the file, for
+    // dashboards and capacity planning. Rows are counted exactly, which
+    // scans each table. Sizes are None when the SQLite build has no
+    // dbstat table.
+    pub fn stats(&self) -> Result<DatabaseStats> {
+        // Bytes of every table and index, per database they live in
+        let mut sizes: HashMap<&str, Option<HashMap<String, i64>>> = HashMap::new();
+        let mut schemas = Vec::new();
+        for schema in self.schemas() {
+            let (database, table) = schema.name.split_once('.').unwrap_or(("main", schema.name.as_str()));
+            if !sizes.contains_key(database) {
+                sizes.insert(database, self.object_sizes(database));
+            }
+            let sizes = sizes[database].as_ref();
+            let size_of = |name: &str| sizes.map(|sizes| sizes.get(name).copied().unwrap_or(0));
+
+            let sql = format!(
+                "SELECT name FROM {}.sqlite_master WHERE type = 'index' AND tbl_name = ? ORDER BY name",
+                database
+            );
+            let mut stmt = self.conn.prepare(&sql)?;
+            let indexes = stmt
+                .query_map([table], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?
+                .into_iter()
+                .map(|name| IndexStats {
+                    size_bytes: size_of(&name),
+                    name,
+                })
+                .collect();
+
+            schemas.push(SchemaStats {
+                name: schema.name.clone(),
+                row_count: self.count(&schema.name)?,
+                size_bytes: size_of(table),
+                indexes,
+            });
+        }
+        Ok(DatabaseStats {
+            schemas,
+            size: self.maintenance().size()?,
+        })
+    }
+
+    // Bytes used by each table and index of `database`, or None without
+    // dbstat
+    fn object_sizes(&self, database: &str) -> Option<HashMap<String, i64>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT name, SUM(pgsize) FROM dbstat(?) GROUP BY name")
+            .ok()?;
+        stmt.query_map([database], |row| Ok((row.get(0)?, row.get(1)?)))
+            .ok()?
+            .collect::<rusqlite::Result<HashMap<String, i64>>>()
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::Value;
+
+    use crate::flexible_database::{FieldDef, FieldType};
+    use crate::temp_file::TempFile;
+
+    fn library() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let books = Schema::new("books", [("title".to_string(), FieldType::Text)])
+            .field("isbn", FieldDef::new(FieldType::Text).unique())
+            .field("pages", FieldDef::new(FieldType::Integer).nullable())
+            .with_index("title");
+        db.define_schema(books).unwrap();
+        db.define_schema(Schema::new("authors", [("name".to_string(), FieldType::Text)])).unwrap();
+        for isbn in ["1", "2"] {
+            let data = HashMap::from([
+                ("title".to_string(), Value::Text("Dune".to_string())),
+                ("isbn".to_string(), Value::Text(isbn.to_string())),
+                ("pages".to_string(), Value::Null),
+            ]);
+            db.create_model("books", data).unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn schemas_are_listed_by_name() {
+        let db = library();
+        let names: Vec<&str> = db.schemas().iter().map(|schema| schema.name.as_str()).collect();
+        assert_eq!(names, ["authors", "books"]);
+        assert!(db.schema("books").unwrap().fields.contains_key("isbn"));
+        assert!(db.schema("missing").is_none());
+    }
+
+    #[test]
+    fn table_info_shows_columns_indexes_and_rows() {
+        let db = library();
+        let info = db.table_info("books").unwrap();
+        assert_eq!(info.row_count, 2);
+
+        let id = info.columns.iter().find(|column| column.name == "id").unwrap();
+        assert_eq!((id.sql_type.as_str(), id.primary_key), ("INTEGER", 1));
+        let title = info.columns.iter().find(|column| column.name == "title").unwrap();
+        assert!(title.not_null);
+        let pages = info.columns.iter().find(|column| column.name == "pages").unwrap();
+        assert!(!pages.not_null);
+
+        let isbn = info.indexes.iter().find(|index| index.columns == ["isbn"]).unwrap();
+        assert!(isbn.unique);
+        let title = info.indexes.iter().find(|index| index.columns == ["title"]).unwrap();
+        assert!(!title.unique);
+    }
+
+    #[test]
+    fn table_info_of_unknown_schemas_is_an_error() {
+        assert!(library().table_info("missing").is_err());
+    }
+
+    #[test]
+    fn stats_cover_every_schema() {
+        let db = library();
+        let stats = db.stats().unwrap();
+        let names: Vec<&str> = stats.schemas.iter().map(|schema| schema.name.as_str()).collect();
+        assert_eq!(names, ["authors", "books"]);
+        assert_eq!(stats.schemas[0].row_count, 0);
+        let books = &stats.schemas[1];
+        assert_eq!(books.row_count, 2);
+        let indexes: Vec<&str> = books.indexes.iter().map(|index| index.name.as_str()).collect();
+        assert_eq!(indexes.len(), 2);
+        assert!(indexes.iter().any(|name| name.contains("title")));
+        if let Some(size) = books.size_bytes {
+            assert!(size > 0);
+            assert!(books.indexes.iter().all(|index| index.size_bytes.is_some()));
+        }
+        assert_eq!(stats.size, db.maintenance().size().unwrap());
+    }
+
+    #[test]
+    fn stats_count_attached_schemas_in_their_own_file() {
+        let file = TempFile::new("db");
+        let mut db = library();
+        db.attach(file.path(), "archive").unwrap();
+        db.define_schema(Schema::new("archive.books", [("title".to_string(), FieldType::Text)])).unwrap();
+        db.create_model("archive.books", HashMap::from([("title".to_string(), Value::Text("Emma".to_string()))]))
+            .unwrap();
+
+        let stats = db.stats().unwrap();
+        let archived = stats.schemas.iter().find(|schema| schema.name == "archive.books").unwrap();
+        assert_eq!(archived.row_count, 1);
+        assert!(archived.indexes.is_empty());
+        let books = stats.schemas.iter().find(|schema| schema.name == "books").unwrap();
+        assert_eq!(books.row_count, 2);
+    }
+}
@@ -0,0 +1,196 @@
+use rusqlite::OptionalExtension;
+use rusqlite::types::Value;
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::FlexibleDatabase;
+
+// Ad-hoc settings and other loose values kept next to the models, in
+// `_koo_kv`, from `FlexibleDatabase::kv`. Keys are any text; values keep
+// the SQLite type they were set with, which the typed accessors check.
+pub struct Kv<'a> {
+    db: &'a FlexibleDatabase,
+}
+
+impl FlexibleDatabase {
+    pub fn kv(&self) -> Result<Kv<'_>> {
+        self.conn
+            .execute_batch("CREATE TABLE IF NOT EXISTS _koo_kv (key TEXT PRIMARY KEY, value ANY) WITHOUT ROWID")?;
+        Ok(Kv { db: self })
+    }
+}
+
+impl Kv<'_> {
+    pub fn get(&self, key: &str) -> Result<Option<Value>> {
+        let sql = "SELECT value FROM _koo_kv WHERE key = ?";
+        self.db.traced(sql, 1, || {
+            let value: Option<Value> = self
+                .db
+                .prepare_cached(sql)?
+                .query_row([key], |row| row.get(0))
+                .optional()?;
+            let count = value.is_some() as usize;
+            Ok((value, count))
+        })
+    }
+
+    // Set `key`, replacing what it held
+    pub fn set(&self, key: &str, value: Value) -> Result<()> {
+        let sql =
+            "INSERT INTO _koo_kv (key, value) VALUES (?, ?) ON CONFLICT (key) DO UPDATE SET value = excluded.value";
+        self.db.traced(sql, 2, || {
+            let changed = self
+                .db
+                .retrying(|| Ok(self.db.prepare_cached(sql)?.execute(rusqlite::params![key, value])?))?;
+            Ok(((), changed))
+        })
+    }
+
+    // Remove `key`, returning whether it was set
+    pub fn delete(&self, key: &str) -> Result<bool> {
+        let sql = "DELETE FROM _koo_kv WHERE key = ?";
+        self.db.traced(sql, 1, || {
+            let deleted = self.db.retrying(|| Ok(self.db.prepare_cached(sql)?.execute([key])?))?;
+            Ok((deleted > 0, deleted))
+        })
+    }
+
+    // Entries whose key starts with `prefix`, by key, e.g. every key under
+    // "feature." for "feature."
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, Value)>> {
+        // Compared by bytes, as LIKE would ignore case and treat % and _
+        // in the prefix as wildcards
+        let sql = "SELECT key, value FROM _koo_kv WHERE key >= ?1 AND substr(key, 1, length(?1)) = ?1 ORDER BY key";
+        self.db.traced(sql, 1, || {
+            let entries = self
+                .db
+                .prepare_cached(sql)?
+                .query_map([prefix], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<Vec<(String, Value)>>>()?;
+            let count = entries.len();
+            Ok((entries, count))
+        })
+    }
+
+    pub fn get_string(&self, key: &str) -> Result<Option<String>> {
+        match self.get(key)? {
+            None => Ok(None),
+            Some(Value::Text(text)) => Ok(Some(text)),
+            Some(other) => Err(mistyped(key, &other, "text")),
+        }
+    }
+
+    pub fn set_string(&self, key: &str, value: &str) -> Result<()> {
+        self.set(key, Value::Text(value.to_string()))
+    }
+
+    pub fn get_i64(&self, key: &str) -> Result<Option<i64>> {
+        match self.get(key)? {
+            None => Ok(None),
+            Some(Value::Integer(i)) => Ok(Some(i)),
+            Some(other) => Err(mistyped(key, &other, "an integer")),
+        }
+    }
+
+    pub fn set_i64(&self, key: &str, value: i64) -> Result<()> {
+        self.set(key, Value::Integer(value))
+    }
+
+    // Values set with `set_json` are stored as JSON text
+    pub fn get_json(&self, key: &str) -> Result<Option<serde_json::Value>> {
+        match self.get_string(key)? {
+            None => Ok(None),
+            Some(text) => Ok(Some(serde_json::from_str(&text)?)),
+        }
+    }
+
+    pub fn set_json(&self, key: &str, value: &serde_json::Value) -> Result<()> {
+        self.set(key, Value::Text(value.to_string()))
+    }
+}
+
+fn mistyped(key: &str, value: &Value, expected: &str) -> KooError {
+    let held = match value {
+        Value::Null => "null",
+        Value::Integer(_) => "an integer",
+        Value::Real(_) => "a real",
+        Value::Text(_) => "text",
+        Value::Blob(_) => "a blob",
+    };
+    KooError::InvalidData(format!("key '{}' holds {}, not {}", key, held, expected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn values_are_set_replaced_and_deleted() {
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        let kv = db.kv().unwrap();
+        assert_eq!(kv.get("theme").unwrap(), None);
+        kv.set("theme", Value::Text("dark".to_string())).unwrap();
+        kv.set("theme", Value::Real(1.5)).unwrap();
+        kv.set("logo", Value::Blob(vec![0, 255])).unwrap();
+        assert_eq!(kv.get("theme").unwrap(), Some(Value::Real(1.5)));
+        assert_eq!(kv.get("logo").unwrap(), Some(Value::Blob(vec![0, 255])));
+
+        assert!(kv.delete("theme").unwrap());
+        assert!(!kv.delete("theme").unwrap());
+        assert_eq!(kv.get("theme").unwrap(), None);
+        // Another handle sees the same store
+        assert!(db.kv().unwrap().get("logo").unwrap().is_some());
+    }
+
+    #[test]
+    fn prefix_scans_match_literally() {
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        let kv = db.kv().unwrap();
+        for key in [
+            "feature.b",
+            "feature.a",
+            "Feature.c",
+            "featurex",
+            "feature%d",
+            "feature_e",
+            "other",
+        ] {
+            kv.set_string(key, key).unwrap();
+        }
+        let keys = |prefix: &str| -> Vec<String> {
+            kv.scan_prefix(prefix)
+                .unwrap()
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect()
+        };
+        assert_eq!(keys("feature."), ["feature.a", "feature.b"]);
+        assert_eq!(keys("feature%"), ["feature%d"]);
+        assert_eq!(keys("feature_"), ["feature_e"]);
+        assert_eq!(keys("").len(), 7);
+        assert!(keys("zzz").is_empty());
+        assert_eq!(
+            kv.scan_prefix("feature.a").unwrap()[0].1,
+            Value::Text("feature.a".to_string())
+        );
+    }
+
+    #[test]
+    fn typed_accessors_check_the_type() {
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        let kv = db.kv().unwrap();
+        kv.set_string("name", "koo").unwrap();
+        kv.set_i64("visits", -3).unwrap();
+        let settings = json!({"dark": true, "sizes": [1, 2]});
+        kv.set_json("settings", &settings).unwrap();
+
+        assert_eq!(kv.get_string("name").unwrap().as_deref(), Some("koo"));
+        assert_eq!(kv.get_i64("visits").unwrap(), Some(-3));
+        assert_eq!(kv.get_json("settings").unwrap(), Some(settings));
+        assert_eq!(kv.get_i64("missing").unwrap(), None);
+
+        assert!(matches!(kv.get_i64("name"), Err(KooError::InvalidData(_))));
+        assert!(matches!(kv.get_string("visits"), Err(KooError::InvalidData(_))));
+        assert!(kv.get_json("name").is_err());
+    }
+}
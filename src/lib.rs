@@ -1 +1,87 @@
-pub mod flexible_database;
\ No newline at end of file
+pub mod access;
+pub mod adopt;
+pub mod alter;
+pub mod append_only;
+pub mod archive;
+#[cfg(feature = "arrow")]
+pub mod arrow;
+pub mod attach;
+pub mod audit;
+pub mod backup;
+pub mod bulk;
+pub mod catalog;
+pub mod changelog;
+pub mod changes;
+pub mod coercion;
+pub mod copy;
+pub mod counters;
+pub mod diff;
+pub mod dump;
+#[cfg(feature = "sqlcipher")]
+pub mod encryption;
+pub mod error;
+pub mod events;
+pub mod expiry;
+pub mod explain;
+pub mod export;
+pub mod fake;
+pub mod field_encryption;
+pub mod fixtures;
+pub mod flexible_database;
+pub mod fts;
+pub mod geo;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod health;
+pub mod history;
+pub mod import;
+pub mod introspection;
+pub mod kv;
+pub mod maintenance;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod migrations;
+pub mod model_builder;
+pub mod options;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+pub mod pool;
+pub mod query;
+pub mod queue;
+pub mod raw;
+pub mod read_cache;
+pub mod reader_pool;
+pub mod relations;
+#[cfg(any(feature = "postgres", feature = "mysql"))]
+pub mod remote;
+pub mod retry;
+pub mod schema_builder;
+pub mod schema_change;
+pub mod schema_file;
+pub mod schema_macro;
+#[cfg(feature = "serde")]
+pub mod serialization;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod snapshot;
+pub mod statement_cache;
+pub mod stream;
+pub mod sync;
+pub mod telemetry;
+#[cfg(test)]
+mod temp_file;
+pub mod templates;
+pub mod tenancy;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod timeseries;
+pub mod tracer;
+pub mod transaction;
+pub mod tree;
+pub mod typed;
+pub mod validation;
+pub mod vector;
+pub mod wire;
+pub mod write_behind;
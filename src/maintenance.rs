@@ -0,0 +1,208 @@
+use crate::error::Result;
+use crate::flexible_database::{FlexibleDatabase, PrimaryKey};
+
+// How much of the database file is in use
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DatabaseSize {
+    pub total_bytes: i64,
+    // Bytes of pages left empty by deletes, which a vacuum gives back
+    pub free_bytes: i64,
+}
+
+// Sizes around a maintenance task
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    pub before: DatabaseSize,
+    pub after: DatabaseSize,
+}
+
+impl MaintenanceReport {
+    // Bytes the file shrank by; negative if it grew
+    pub fn reclaimed_bytes(&self) -> i64 {
+        self.before.total_bytes - self.after.total_bytes
+    }
+}
+
+// Upkeep of the main database, from `FlexibleDatabase::maintenance`. None
+// of the tasks change what the models hold; run them when the database is
+// quiet, since vacuum and integrity checks read the whole file.
+pub struct Maintenance<'a> {
+    db: &'a FlexibleDatabase,
+}
+
+impl FlexibleDatabase {
+    pub fn maintenance(&self) -> Maintenance<'_> {
+        Maintenance { db: self }
+    }
+}
+
+impl Maintenance<'_> {
+    pub fn size(&self) -> Result<DatabaseSize> {
+        let conn = &self.db.conn;
+        let page_size: i64 = conn.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+        let pages: i64 = conn.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+        let free_pages: i64 = conn.query_row("PRAGMA freelist_count", [], |row| row.get(0))?;
+        Ok(DatabaseSize {
+            total_bytes: pages * page_size,
+            free_bytes: free_pages * page_size,
+        })
+    }
+
+    // Rewrite the file without its free pages and fragmentation. Needs
+    // about as much free disk as the database takes, and fails inside a
+    // transaction.
+    pub fn vacuum(&self) -> Result<MaintenanceReport> {
+        self.measured("VACUUM", || {
+            // Tables keyed by something other than an integer id may have
+            // their rowids renumbered, which full-text indexes point at
+            for schema in self.db.schemas.values() {
+                if !schema.fts_fields.is_empty() && schema.key != PrimaryKey::Integer && !schema.name.contains('.') {
+                    let fts = format!("{}_fts", schema.name);
+                    self.db
+                        .conn
+                        .execute(&format!("INSERT INTO {fts} ({fts}) VALUES ('rebuild')"), [])?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // Gather the table statistics the query planner picks indexes by, and
+    // `estimated_count` reads
+    pub fn analyze(&self) -> Result<MaintenanceReport> {
+        self.measured("ANALYZE", || Ok(()))
+    }
+
+    // Let SQLite refresh whatever statistics it judges stale, which is
+    // cheap enough to run before closing a long-lived connection, then
+    // merge the segments of every full-text index
+    pub fn optimize(&self) -> Result<MaintenanceReport> {
+        self.measured("PRAGMA optimize", || {
+            for schema in self.db.schemas.values() {
+                if !schema.fts_fields.is_empty() {
+                    let fts = format!("{}_fts", schema.name);
+                    self.db
+                        .conn
+                        .execute(&format!("INSERT INTO {fts} ({fts}) VALUES ('optimize')"), [])?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // Problems SQLite finds in the file's structure, such as corrupt pages
+    // or indexes out of step with their tables; empty when there are none
+    pub fn integrity_check(&self) -> Result<Vec<String>> {
+        let sql = "PRAGMA integrity_check";
+        self.db.traced(sql, 0, || {
+            let mut stmt = self.db.conn.prepare(sql)?;
+            let messages = stmt
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let problems: Vec<String> = messages.into_iter().filter(|message| message != "ok").collect();
+            let count = problems.len();
+            Ok((problems, count))
+        })
+    }
+
+    // Run `sql`, then `after`, reporting the size on either side
+    fn measured(&self, sql: &str, after: impl FnOnce() -> Result<()>) -> Result<MaintenanceReport> {
+        let before = self.size()?;
+        self.db.traced(sql, 0, || Ok((self.db.conn.execute_batch(sql)?, 0)))?;
+        after()?;
+        Ok(MaintenanceReport {
+            before,
+            after: self.size()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::Value;
+    use std::collections::HashMap;
+
+    use crate::flexible_database::{FieldType, Schema};
+
+    // "notes" holding `count` models with a body of a kilobyte each
+    fn notes(count: usize) -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("notes", [("body".to_string(), FieldType::Text)]))
+            .unwrap();
+        for _ in 0..count {
+            let data = HashMap::from([("body".to_string(), Value::Text("x".repeat(1024)))]);
+            db.create_model("notes", data).unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn vacuum_gives_back_the_free_pages() {
+        let db = notes(200);
+        db.execute_raw("DELETE FROM notes WHERE id > 10", &[]).unwrap();
+        let size = db.maintenance().size().unwrap();
+        assert!(size.free_bytes > 0);
+        assert!(size.free_bytes < size.total_bytes);
+
+        let report = db.maintenance().vacuum().unwrap();
+        assert_eq!(report.before, size);
+        assert_eq!(report.after.free_bytes, 0);
+        assert!(report.reclaimed_bytes() > 0);
+        assert_eq!(db.count("notes").unwrap(), 10);
+    }
+
+    #[test]
+    fn vacuum_fails_inside_a_transaction() {
+        let db = notes(1);
+        db.execute_raw("BEGIN", &[]).unwrap();
+        assert!(db.maintenance().vacuum().is_err());
+        db.execute_raw("ROLLBACK", &[]).unwrap();
+    }
+
+    #[test]
+    fn vacuum_keeps_search_working_for_text_keys() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(
+            Schema::new("docs", [("body".to_string(), FieldType::Text)])
+                .with_key(PrimaryKey::Text)
+                .with_fts(&["body"]),
+        )
+        .unwrap();
+        for (id, body) in [("a", "red fox"), ("b", "blue whale"), ("c", "red panda")] {
+            let data = HashMap::from([
+                ("id".to_string(), Value::Text(id.to_string())),
+                ("body".to_string(), Value::Text(body.to_string())),
+            ]);
+            db.create_model("docs", data).unwrap();
+        }
+        db.delete_model("docs", "a".to_string()).unwrap();
+
+        db.maintenance().vacuum().unwrap();
+        let found = db.search("docs", "red").unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].data["body"], Value::Text("red panda".to_string()));
+        db.maintenance().optimize().unwrap();
+        assert_eq!(db.search("docs", "whale").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn analyze_gathers_statistics() {
+        let db = notes(20);
+        db.execute_raw("CREATE INDEX notes_body ON notes (body)", &[]).unwrap();
+        db.maintenance().analyze().unwrap();
+        let stats: i64 = db
+            .conn
+            .query_row("SELECT COUNT(*) FROM sqlite_stat1 WHERE tbl = 'notes'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(stats > 0);
+    }
+
+    #[test]
+    fn sound_databases_have_no_integrity_problems() {
+        let db = notes(5);
+        assert!(db.maintenance().integrity_check().unwrap().is_empty());
+    }
+}
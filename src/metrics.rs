@@ -0,0 +1,294 @@
+use std::cell::Cell;
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::flexible_database::FlexibleDatabase;
+
+// Upper bounds of the duration histogram buckets, in seconds
+const BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    // Observations per bucket, each counted only in the first that holds it
+    buckets: [Cell<u64>; BUCKETS.len()],
+    count: Cell<u64>,
+    sum: Cell<f64>,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            add(&self.buckets[bucket], 1);
+        }
+        add(&self.count, 1);
+        self.sum.set(self.sum.get() + seconds);
+    }
+
+    fn write(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.get();
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count.get());
+        let _ = writeln!(out, "{}_sum {}", name, self.sum.get());
+        let _ = writeln!(out, "{}_count {}", name, self.count.get());
+    }
+}
+
+// Counters kept for `metrics_snapshot` as statements and transactions run
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    queries: Cell<u64>,
+    failed_queries: Cell<u64>,
+    rows_read: Cell<u64>,
+    rows_written: Cell<u64>,
+    query_durations: Histogram,
+    commits: Cell<u64>,
+    rollbacks: Cell<u64>,
+    transaction_durations: Histogram,
+}
+
+impl Metrics {
+    pub(crate) fn record_query(&self, sql: &str, duration: Duration, rows: usize, failed: bool) {
+        add(&self.queries, 1);
+        if failed {
+            add(&self.failed_queries, 1);
+        }
+        // Writes report the rows they changed, reads those they returned
+        let keyword = sql.split_whitespace().next().unwrap_or_default();
+        let read = ["SELECT", "WITH", "PRAGMA", "EXPLAIN"]
+            .iter()
+            .any(|read| keyword.eq_ignore_ascii_case(read));
+        match read {
+            true => add(&self.rows_read, rows as u64),
+            false => add(&self.rows_written, rows as u64),
+        }
+        self.query_durations.observe(duration);
+    }
+
+    pub(crate) fn record_transaction(&self, duration: Duration, committed: bool) {
+        match committed {
+            true => add(&self.commits, 1),
+            false => add(&self.rollbacks, 1),
+        }
+        self.transaction_durations.observe(duration);
+    }
+}
+
+// With the `metrics` feature, every statement kooDB issues and every
+// transaction it runs is counted and timed, for services to expose to
+// Prometheus. The counters start at zero when the database is opened.
+impl FlexibleDatabase {
+    // The metrics in the Prometheus text exposition format, to serve from
+    // a /metrics endpoint
+    pub fn metrics_snapshot(&self) -> String {
+        let metrics = &self.metrics;
+        let mut out = String::new();
+        counter(
+            &mut out,
+            "koo_queries_total",
+            "Statements executed",
+            metrics.queries.get(),
+        );
+        counter(
+            &mut out,
+            "koo_query_errors_total",
+            "Statements that failed",
+            metrics.failed_queries.get(),
+        );
+        counter(
+            &mut out,
+            "koo_rows_read_total",
+            "Rows returned by reads",
+            metrics.rows_read.get(),
+        );
+        counter(
+            &mut out,
+            "koo_rows_written_total",
+            "Rows changed by writes",
+            metrics.rows_written.get(),
+        );
+        metrics
+            .query_durations
+            .write(&mut out, "koo_query_duration_seconds", "Time taken by statements");
+        counter(
+            &mut out,
+            "koo_transactions_committed_total",
+            "Transactions committed",
+            metrics.commits.get(),
+        );
+        counter(
+            &mut out,
+            "koo_transactions_rolled_back_total",
+            "Transactions rolled back",
+            metrics.rollbacks.get(),
+        );
+        metrics.transaction_durations.write(
+            &mut out,
+            "koo_transaction_duration_seconds",
+            "Time taken by transactions",
+        );
+
+        let statements = self.statement_cache_stats();
+        cache(
+            &mut out,
+            "koo_statement_cache",
+            "prepared statement cache",
+            statements.hits,
+            statements.misses,
+        );
+        if let Some(reads) = self.read_cache_stats() {
+            cache(&mut out, "koo_read_cache", "read cache", reads.hits, reads.misses);
+        }
+        out
+    }
+}
+
+fn add(cell: &Cell<u64>, n: u64) {
+    cell.set(cell.get() + n);
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// Hits and misses of a cache, and the share of lookups that hit
+fn cache(out: &mut String, name: &str, description: &str, hits: u64, misses: u64) {
+    counter(
+        out,
+        &format!("{}_hits_total", name),
+        &format!("Lookups the {} answered", description),
+        hits,
+    );
+    counter(
+        out,
+        &format!("{}_misses_total", name),
+        &format!("Lookups the {} missed", description),
+        misses,
+    );
+    let ratio = match hits + misses {
+        0 => 0.0,
+        lookups => hits as f64 / lookups as f64,
+    };
+    let name = format!("{}_hit_ratio", name);
+    header(
+        out,
+        &name,
+        &format!("Share of lookups the {} answered", description),
+        "gauge",
+    );
+    let _ = writeln!(out, "{} {}", name, ratio);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::Value;
+    use std::collections::HashMap;
+
+    use crate::error::{KooError, Result};
+    use crate::flexible_database::{FieldType, Schema};
+
+    // The value of the sample `name`, labels included
+    fn sample(snapshot: &str, name: &str) -> Option<f64> {
+        snapshot
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .map(|value| value.parse().unwrap())
+    }
+
+    fn notes() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("notes", [("n".to_string(), FieldType::Integer)]))
+            .unwrap();
+        db
+    }
+
+    fn note(n: i64) -> HashMap<String, Value> {
+        HashMap::from([("n".to_string(), Value::Integer(n))])
+    }
+
+    #[test]
+    fn histograms_count_each_bucket_and_those_below() {
+        let histogram = Histogram::default();
+        for micros in [50, 300, 300, 20_000, 10_000_000] {
+            histogram.observe(Duration::from_micros(micros));
+        }
+        let mut out = String::new();
+        histogram.write(&mut out, "t", "Test");
+        assert!(out.starts_with("# HELP t Test\n# TYPE t histogram\n"));
+        assert_eq!(sample(&out, "t_bucket{le=\"0.0001\"}"), Some(1.0));
+        assert_eq!(sample(&out, "t_bucket{le=\"0.0005\"}"), Some(3.0));
+        assert_eq!(sample(&out, "t_bucket{le=\"0.01\"}"), Some(3.0));
+        assert_eq!(sample(&out, "t_bucket{le=\"0.05\"}"), Some(4.0));
+        assert_eq!(sample(&out, "t_bucket{le=\"5\"}"), Some(4.0));
+        assert_eq!(sample(&out, "t_bucket{le=\"+Inf\"}"), Some(5.0));
+        assert_eq!(sample(&out, "t_count"), Some(5.0));
+        assert!((sample(&out, "t_sum").unwrap() - 10.02065).abs() < 1e-9);
+    }
+
+    #[test]
+    fn statements_and_rows_are_counted() {
+        let db = notes();
+        let before = sample(&db.metrics_snapshot(), "koo_queries_total").unwrap();
+        for n in 0..3 {
+            db.create_model("notes", note(n)).unwrap();
+        }
+        db.get_all_models("notes").unwrap();
+        assert!(db.query_raw("SELECT * FROM missing", &[]).is_err());
+
+        let snapshot = db.metrics_snapshot();
+        assert_eq!(sample(&snapshot, "koo_queries_total").unwrap() - before, 5.0);
+        assert_eq!(sample(&snapshot, "koo_query_errors_total"), Some(1.0));
+        assert_eq!(sample(&snapshot, "koo_rows_written_total"), Some(3.0));
+        assert_eq!(sample(&snapshot, "koo_rows_read_total"), Some(3.0));
+        assert!(snapshot.contains("# TYPE koo_query_duration_seconds histogram\n"));
+    }
+
+    #[test]
+    fn transactions_are_counted_by_outcome() {
+        let mut db = notes();
+        db.transaction(|db| {
+            db.create_model("notes", note(1))?;
+            // Only the outermost transaction counts
+            db.transaction(|db| db.create_model("notes", note(2)))
+        })
+        .unwrap();
+        let failed: Result<()> = db.transaction(|_| Err(KooError::InvalidData("no".to_string())));
+        assert!(failed.is_err());
+
+        let snapshot = db.metrics_snapshot();
+        assert_eq!(sample(&snapshot, "koo_transactions_committed_total"), Some(1.0));
+        assert_eq!(sample(&snapshot, "koo_transactions_rolled_back_total"), Some(1.0));
+        assert_eq!(sample(&snapshot, "koo_transaction_duration_seconds_count"), Some(2.0));
+    }
+
+    #[test]
+    fn cache_ratios_follow_their_lookups() {
+        let mut db = notes();
+        let snapshot = db.metrics_snapshot();
+        assert!(snapshot.contains("koo_statement_cache_hit_ratio "));
+        assert!(!snapshot.contains("koo_read_cache"));
+
+        db.enable_read_cache(10, Duration::from_secs(60));
+        let id = db.create_model("notes", note(1)).unwrap();
+        for _ in 0..4 {
+            db.get_model("notes", id.clone()).unwrap();
+        }
+        let snapshot = db.metrics_snapshot();
+        let hits = sample(&snapshot, "koo_read_cache_hits_total").unwrap();
+        let misses = sample(&snapshot, "koo_read_cache_misses_total").unwrap();
+        assert_eq!(hits + misses, 4.0);
+        assert!(hits >= 3.0);
+        assert_eq!(sample(&snapshot, "koo_read_cache_hit_ratio"), Some(hits / 4.0));
+    }
+}
@@ -0,0 +1,244 @@
+use rusqlite::OptionalExtension;
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::FlexibleDatabase;
+
+// A single versioned change to the database layout
+pub trait Migration {
+    // Unique, increasing version number for this migration
+    fn version(&self) -> i64;
+
+    // Human readable name stored alongside the version
+    fn name(&self) -> &str;
+
+    // Apply the change
+    fn up(&self, db: &mut FlexibleDatabase) -> Result<()>;
+
+    // Revert the change made by `up`
+    fn down(&self, db: &mut FlexibleDatabase) -> Result<()>;
+}
+
+// A migration that has been recorded in `_koo_migrations`
+#[derive(Debug, Clone)]
+pub struct AppliedMigration {
+    pub version: i64,
+    pub name: String,
+    pub applied_at: String,
+}
+
+impl FlexibleDatabase {
+    fn ensure_migrations_table(&self) -> Result<()> {
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS _koo_migrations (
+                version INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    // List the migrations that have been applied, oldest first
+    pub fn applied_migrations(&self) -> Result<Vec<AppliedMigration>> {
+        self.ensure_migrations_table()?;
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT version, name, applied_at FROM _koo_migrations ORDER BY version")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(AppliedMigration {
+                version: row.get(0)?,
+                name: row.get(1)?,
+                applied_at: row.get(2)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    // Highest applied migration version, if any
+    pub fn schema_version(&self) -> Result<Option<i64>> {
+        self.ensure_migrations_table()?;
+        Ok(self
+            .conn
+            .query_row("SELECT MAX(version) FROM _koo_migrations", [], |row| row.get(0))?)
+    }
+
+    // Apply every migration that hasn't been recorded yet, in version order.
+    // Returns the number of migrations applied.
+    pub fn migrate(&mut self, migrations: &[&dyn Migration]) -> Result<usize> {
+        self.ensure_migrations_table()?;
+
+        let mut pending: Vec<&dyn Migration> = Vec::new();
+        for migration in migrations {
+            let applied: Option<i64> = self
+                .conn
+                .query_row(
+                    "SELECT version FROM _koo_migrations WHERE version = ?",
+                    [migration.version()],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if applied.is_none() {
+                pending.push(*migration);
+            }
+        }
+        pending.sort_by_key(|m| m.version());
+
+        for migration in &pending {
+            self.run_migration_step(*migration, true)?;
+        }
+        Ok(pending.len())
+    }
+
+    // Revert the last `steps` applied migrations, newest first.
+    // Every reverted version must be present in `migrations`.
+    // Returns the number of migrations reverted.
+    pub fn rollback(&mut self, migrations: &[&dyn Migration], steps: usize) -> Result<usize> {
+        let applied = self.applied_migrations()?;
+
+        let mut reverted = 0;
+        for record in applied.iter().rev().take(steps) {
+            let migration = migrations
+                .iter()
+                .find(|m| m.version() == record.version)
+                .ok_or(KooError::MigrationNotFound(record.version))?;
+            self.run_migration_step(*migration, false)?;
+            reverted += 1;
+        }
+        Ok(reverted)
+    }
+
+    // Run one direction of a migration and record the outcome atomically
+    fn run_migration_step(&mut self, migration: &dyn Migration, up: bool) -> Result<()> {
+        self.in_transaction(|db| {
+            if up {
+                migration.up(db)?;
+                db.conn.execute(
+                    "INSERT INTO _koo_migrations (version, name) VALUES (?, ?)",
+                    rusqlite::params![migration.version(), migration.name()],
+                )?;
+            } else {
+                migration.down(db)?;
+                db.conn.execute(
+                    "DELETE FROM _koo_migrations WHERE version = ?",
+                    [migration.version()],
+                )?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::flexible_database::{FieldType, Schema};
+
+    // Creates table `t<version>` on the way up and drops it on the way down
+    struct CreateTable(i64);
+
+    impl Migration for CreateTable {
+        fn version(&self) -> i64 {
+            self.0
+        }
+
+        fn name(&self) -> &str {
+            "create table"
+        }
+
+        fn up(&self, db: &mut FlexibleDatabase) -> Result<()> {
+            let fields = HashMap::from([("n".to_string(), FieldType::Integer)]);
+            db.define_schema(Schema::new(&format!("t{}", self.0), fields))
+        }
+
+        fn down(&self, db: &mut FlexibleDatabase) -> Result<()> {
+            db.conn.execute(&format!("DROP TABLE t{}", self.0), [])?;
+            Ok(())
+        }
+    }
+
+    // Fails halfway through its change
+    struct Broken;
+
+    impl Migration for Broken {
+        fn version(&self) -> i64 {
+            3
+        }
+
+        fn name(&self) -> &str {
+            "broken"
+        }
+
+        fn up(&self, db: &mut FlexibleDatabase) -> Result<()> {
+            db.conn.execute("CREATE TABLE half_done (n INTEGER)", [])?;
+            Err(rusqlite::Error::InvalidQuery.into())
+        }
+
+        fn down(&self, _: &mut FlexibleDatabase) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn has_table(db: &FlexibleDatabase, name: &str) -> bool {
+        db.conn
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?)",
+                [name],
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn pending_migrations_are_applied_in_version_order() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        assert_eq!(db.schema_version().unwrap(), None);
+
+        assert_eq!(db.migrate(&[&CreateTable(2), &CreateTable(1)]).unwrap(), 2);
+        let versions: Vec<i64> = db.applied_migrations().unwrap().iter().map(|m| m.version).collect();
+        assert_eq!(versions, [1, 2]);
+        assert_eq!(db.schema_version().unwrap(), Some(2));
+        assert!(has_table(&db, "t1") && has_table(&db, "t2"));
+
+        // Already applied, so only the new one runs
+        assert_eq!(
+            db.migrate(&[&CreateTable(1), &CreateTable(2), &CreateTable(4)])
+                .unwrap(),
+            1
+        );
+        assert_eq!(db.schema_version().unwrap(), Some(4));
+    }
+
+    #[test]
+    fn rollback_reverts_the_newest_first() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let migrations: [&dyn Migration; 3] = [&CreateTable(1), &CreateTable(2), &CreateTable(4)];
+        db.migrate(&migrations).unwrap();
+
+        assert_eq!(db.rollback(&migrations, 2).unwrap(), 2);
+        assert_eq!(db.schema_version().unwrap(), Some(1));
+        assert!(has_table(&db, "t1"));
+        assert!(!has_table(&db, "t2") && !has_table(&db, "t4"));
+    }
+
+    #[test]
+    fn rolling_back_an_unknown_migration_is_an_error() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.migrate(&[&CreateTable(1), &CreateTable(2)]).unwrap();
+        let result = db.rollback(&[&CreateTable(1)], 1);
+        assert!(matches!(result, Err(KooError::MigrationNotFound(2))));
+        assert_eq!(db.schema_version().unwrap(), Some(2));
+    }
+
+    #[test]
+    fn a_failed_migration_leaves_nothing_behind() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        assert!(db.migrate(&[&CreateTable(1), &Broken, &CreateTable(4)]).is_err());
+        assert_eq!(db.schema_version().unwrap(), Some(1));
+        assert!(!has_table(&db, "half_done"));
+        assert!(!has_table(&db, "t4"));
+    }
+}
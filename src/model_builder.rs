@@ -0,0 +1,152 @@
+use rusqlite::types::Value;
+use std::collections::HashMap;
+
+use crate::flexible_database::{Model, ModelId};
+
+// Data for `create_model` and `update_model`, built up field by field:
+//
+//     let data = ModelBuilder::new().text("name", "Ada").set("age", 36).set("admin", true).build();
+//
+// A later value for the same field replaces the earlier one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelBuilder {
+    data: HashMap<String, Value>,
+}
+
+impl ModelBuilder {
+    pub fn new() -> ModelBuilder {
+        ModelBuilder::default()
+    }
+
+    // Any value rusqlite converts: integers, floats, bools, strings,
+    // blobs and options of them, with None as NULL
+    pub fn set(mut self, field_name: &str, value: impl Into<Value>) -> ModelBuilder {
+        self.data.insert(field_name.to_string(), value.into());
+        self
+    }
+
+    pub fn text(self, field_name: &str, value: impl Into<String>) -> ModelBuilder {
+        self.set(field_name, value.into())
+    }
+
+    pub fn null(self, field_name: &str) -> ModelBuilder {
+        self.set(field_name, Value::Null)
+    }
+
+    // JSON stored as text, read back with `Model::get_json`
+    pub fn json(self, field_name: &str, value: &serde_json::Value) -> ModelBuilder {
+        self.set(field_name, value.to_string())
+    }
+
+    pub fn build(self) -> HashMap<String, Value> {
+        self.data
+    }
+
+    // A model with these fields, as if read with this id
+    pub fn model(self, id: impl Into<ModelId>) -> Model {
+        Model::new(Some(id.into()), self.data)
+    }
+}
+
+impl From<ModelBuilder> for HashMap<String, Value> {
+    fn from(builder: ModelBuilder) -> Self {
+        builder.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::error::KooError;
+    use crate::flexible_database::{FieldDef, FieldType, FlexibleDatabase, Schema};
+
+    #[test]
+    fn builders_collect_the_fields() {
+        let data = ModelBuilder::new()
+            .text("name", "Ada")
+            .set("age", 36)
+            .set("admin", true)
+            .set("score", 9.5)
+            .set("nickname", None::<String>)
+            .null("email")
+            .set("age", 37)
+            .build();
+        let expected = HashMap::from([
+            ("name".to_string(), Value::Text("Ada".to_string())),
+            ("age".to_string(), Value::Integer(37)),
+            ("admin".to_string(), Value::Integer(1)),
+            ("score".to_string(), Value::Real(9.5)),
+            ("nickname".to_string(), Value::Null),
+            ("email".to_string(), Value::Null),
+        ]);
+        assert_eq!(data, expected);
+        let converted: HashMap<String, Value> = ModelBuilder::new().set("age", 1).into();
+        assert_eq!(converted["age"], Value::Integer(1));
+    }
+
+    #[test]
+    fn built_data_creates_models() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let people = Schema::new("people", [])
+            .field("name", FieldDef::new(FieldType::Text))
+            .field("admin", FieldDef::new(FieldType::Boolean));
+        db.define_schema(people).unwrap();
+        let id = db
+            .create_model(
+                "people",
+                ModelBuilder::new().text("name", "Ada").set("admin", true).build(),
+            )
+            .unwrap();
+        let ada = db.get_model("people", id).unwrap().unwrap();
+        assert_eq!(ada.get_str("name").unwrap(), "Ada");
+        assert!(ada.get_bool("admin").unwrap());
+    }
+
+    #[test]
+    fn typed_accessors_read_strictly() {
+        let model = ModelBuilder::new()
+            .text("name", "Ada")
+            .set("age", 36)
+            .set("height", 1.7)
+            .set("admin", 0)
+            .model(1);
+        assert_eq!(model.id, Some(ModelId::Integer(1)));
+        assert_eq!(model.get_str("name").unwrap(), "Ada");
+        assert_eq!(model.get_i64("age").unwrap(), 36);
+        assert_eq!(model.get_f64("age").unwrap(), 36.0);
+        assert_eq!(model.get_f64("height").unwrap(), 1.7);
+        assert!(!model.get_bool("admin").unwrap());
+
+        let err = model.get_str("age").unwrap_err();
+        assert!(matches!(
+            err,
+            KooError::TypeMismatch { ref field, expected: "text", found: "an integer" } if field == "age"
+        ));
+        assert!(matches!(model.get_i64("height"), Err(KooError::TypeMismatch { .. })));
+        assert!(matches!(model.get_bool("missing"), Err(KooError::InvalidData(_))));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_fields_read_back() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Prefs {
+            theme: String,
+            size: u32,
+        }
+
+        let model = ModelBuilder::new()
+            .json("prefs", &serde_json::json!({ "theme": "dark", "size": 12 }))
+            .model(1);
+        let prefs: Prefs = model.get_json("prefs").unwrap();
+        assert_eq!(
+            prefs,
+            Prefs {
+                theme: "dark".to_string(),
+                size: 12
+            }
+        );
+        assert!(model.get_json::<Vec<i64>>("prefs").is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use rusqlite::Connection;
+use std::time::Duration;
+
+use crate::error::Result;
+use crate::retry::RetryPolicy;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+// Connection settings applied by `FlexibleDatabase::open_with`. Anything
+// left unset keeps SQLite's default, except foreign keys, which kooDB turns
+// on so reference fields are enforced.
+#[derive(Debug, Clone)]
+pub struct DatabaseOptions {
+    pub(crate) journal_mode: Option<JournalMode>,
+    pub(crate) synchronous: Option<Synchronous>,
+    pub(crate) busy_timeout: Option<Duration>,
+    pub(crate) foreign_keys: bool,
+    pub(crate) cache_size: Option<i64>,
+    pub(crate) retry_policy: RetryPolicy,
+}
+
+impl Default for DatabaseOptions {
+    fn default() -> Self {
+        DatabaseOptions {
+            journal_mode: None,
+            synchronous: None,
+            busy_timeout: None,
+            foreign_keys: true,
+            cache_size: None,
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+}
+
+impl DatabaseOptions {
+    pub fn new() -> DatabaseOptions {
+        DatabaseOptions::default()
+    }
+
+    pub fn journal_mode(mut self, mode: JournalMode) -> DatabaseOptions {
+        self.journal_mode = Some(mode);
+        self
+    }
+
+    pub fn synchronous(mut self, level: Synchronous) -> DatabaseOptions {
+        self.synchronous = Some(level);
+        self
+    }
+
+    // How long to wait on a locked database before failing with SQLITE_BUSY
+    pub fn busy_timeout(mut self, timeout: Duration) -> DatabaseOptions {
+        self.busy_timeout = Some(timeout);
+        self
+    }
+
+    pub fn foreign_keys(mut self, enabled: bool) -> DatabaseOptions {
+        self.foreign_keys = enabled;
+        self
+    }
+
+    // Page cache size as understood by `PRAGMA cache_size`: positive values
+    // are pages, negative values are KiB
+    pub fn cache_size(mut self, size: i64) -> DatabaseOptions {
+        self.cache_size = Some(size);
+        self
+    }
+
+    // How writes are retried once the busy timeout runs out
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> DatabaseOptions {
+        self.retry_policy = policy;
+        self
+    }
+
+    pub(crate) fn apply(&self, conn: &Connection) -> Result<()> {
+        if let Some(mode) = self.journal_mode {
+            let mode = match mode {
+                JournalMode::Delete => "DELETE",
+                JournalMode::Truncate => "TRUNCATE",
+                JournalMode::Persist => "PERSIST",
+                JournalMode::Memory => "MEMORY",
+                JournalMode::Wal => "WAL",
+                JournalMode::Off => "OFF",
+            };
+            // journal_mode reports the resulting mode as a row
+            conn.query_row(&format!("PRAGMA journal_mode = {}", mode), [], |_| Ok(()))?;
+        }
+        if let Some(level) = self.synchronous {
+            let level = match level {
+                Synchronous::Off => "OFF",
+                Synchronous::Normal => "NORMAL",
+                Synchronous::Full => "FULL",
+                Synchronous::Extra => "EXTRA",
+            };
+            conn.execute_batch(&format!("PRAGMA synchronous = {}", level))?;
+        }
+        if let Some(timeout) = self.busy_timeout {
+            conn.busy_timeout(timeout)?;
+        }
+        conn.execute_batch(if self.foreign_keys {
+            "PRAGMA foreign_keys = ON"
+        } else {
+            "PRAGMA foreign_keys = OFF"
+        })?;
+        if let Some(size) = self.cache_size {
+            conn.execute_batch(&format!("PRAGMA cache_size = {}", size))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flexible_database::FlexibleDatabase;
+    use crate::temp_file::TempFile;
+
+    fn pragma<T: rusqlite::types::FromSql>(db: &FlexibleDatabase, name: &str) -> T {
+        db.conn
+            .query_row(&format!("PRAGMA {}", name), [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn options_are_applied_when_opening() {
+        let file = TempFile::new("db");
+        let options = DatabaseOptions::new()
+            .journal_mode(JournalMode::Wal)
+            .synchronous(Synchronous::Normal)
+            .busy_timeout(Duration::from_millis(1500))
+            .cache_size(-4096);
+        let db = FlexibleDatabase::open_with(file.path(), options).unwrap();
+        assert_eq!(pragma::<String>(&db, "journal_mode"), "wal");
+        assert_eq!(pragma::<i64>(&db, "synchronous"), 1);
+        assert_eq!(pragma::<i64>(&db, "busy_timeout"), 1500);
+        assert_eq!(pragma::<i64>(&db, "cache_size"), -4096);
+        assert_eq!(pragma::<i64>(&db, "foreign_keys"), 1);
+    }
+
+    #[test]
+    fn foreign_keys_can_be_turned_off() {
+        let db = FlexibleDatabase::open_with(":memory:", DatabaseOptions::new().foreign_keys(false)).unwrap();
+        assert_eq!(pragma::<i64>(&db, "foreign_keys"), 0);
+    }
+
+    #[test]
+    fn unset_options_keep_sqlite_defaults() {
+        let defaults = FlexibleDatabase::open_with(":memory:", DatabaseOptions::new()).unwrap();
+        let raw = rusqlite::Connection::open_in_memory().unwrap();
+        let raw_cache: i64 = raw.query_row("PRAGMA cache_size", [], |row| row.get(0)).unwrap();
+        assert_eq!(pragma::<i64>(&defaults, "cache_size"), raw_cache);
+        assert_eq!(pragma::<String>(&defaults, "journal_mode"), "memory");
+    }
+}
@@ -0,0 +1,324 @@
+use arrow_schema::SchemaRef;
+use parquet::arrow::ArrowWriter;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use rusqlite::types::Value;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::arrow::{BATCH_ROWS, array_value, arrow_schema, field_value, models_to_batch};
+use crate::error::{KooError, Result};
+use crate::flexible_database::{
+    FieldType, FlexibleDatabase, PrimaryKey, VERSION_COLUMN, read_model, row_key, select_sql,
+};
+use crate::import::inserted_id;
+
+// Parquet files, for handing kooDB data to analytics tools, laid out with
+// the columns and Arrow types of `arrow::arrow_schema`
+impl FlexibleDatabase {
+    // Write the rows of one schema to a Snappy-compressed Parquet file,
+    // returning how many were written. Encrypted fields are written
+    // decrypted.
+    pub fn export_parquet<P: AsRef<Path>>(&self, schema_name: &str, path: P) -> Result<usize> {
+        let schema = self.schema_or_err(schema_name)?;
+        let arrow: SchemaRef = Arc::new(arrow_schema(schema));
+        let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+        let mut writer = ArrowWriter::try_new(File::create(path)?, arrow.clone(), Some(properties))?;
+
+        let mut stmt = self
+            .conn
+            .prepare(&format!("{} ORDER BY {}", select_sql(schema), row_key(schema)))?;
+        let mut rows = stmt.query([])?;
+        let mut models = Vec::with_capacity(BATCH_ROWS);
+        let mut written = 0;
+        while let Some(row) = rows.next()? {
+            models.push(read_model(row, schema)?);
+            if models.len() == BATCH_ROWS {
+                writer.write(&models_to_batch(schema, &arrow, &models)?)?;
+                written += models.len();
+                models.clear();
+            }
+        }
+        if !models.is_empty() {
+            writer.write(&models_to_batch(schema, &arrow, &models)?)?;
+            written += models.len();
+        }
+        writer.close()?;
+        Ok(written)
+    }
+
+    // Insert the rows of a Parquet file into an existing schema, matching
+    // columns to fields by name. Any integer, float, boolean or string
+    // column fits a field stored that way, and lists of numbers fit vector
+    // fields. A missing or null id assigns a fresh integer id, or a
+    // generated UUID for schemas with them. Returns the number of rows
+    // inserted; a bad row aborts the whole import.
+    pub fn import_parquet<P: AsRef<Path>>(&self, schema_name: &str, path: P) -> Result<usize> {
+        let schema = self.schema_or_err(schema_name)?;
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?.build()?;
+
+        let id_type = match schema.key {
+            PrimaryKey::Text => FieldType::Text,
+            _ => FieldType::Integer,
+        };
+        let version_type = FieldType::Integer;
+        let tx = self.conn.unchecked_transaction()?;
+        let mut inserted = 0;
+        for batch in reader {
+            let batch = batch?;
+            // Column index and type of each column imported
+            let mut columns = Vec::new();
+            for (index, field) in batch.schema().fields().iter().enumerate() {
+                let name = field.name().as_str();
+                let field_type = match schema.fields.get(name) {
+                    Some(_) if schema.is_computed(name) => continue,
+                    Some(field_type) => field_type,
+                    None if name == "id" && schema.key.has_id_column() => &id_type,
+                    None if name == VERSION_COLUMN && schema.versioned => &version_type,
+                    None => {
+                        return Err(KooError::UnknownField {
+                            schema: schema_name.to_string(),
+                            field: name.to_string(),
+                        });
+                    }
+                };
+                columns.push((index, name.to_string(), field_type));
+            }
+
+            for row in 0..batch.num_rows() {
+                let mut names = vec![];
+                let mut values: Vec<Value> = vec![];
+                for (index, name, field_type) in &columns {
+                    let column = batch.column(*index);
+                    let value = array_value(column.as_ref(), row)
+                        .and_then(|value| field_value(value, field_type))
+                        .ok_or_else(|| {
+                            KooError::InvalidData(format!(
+                                "parquet row {}: '{}.{}' expects {}, got {}",
+                                inserted + 1,
+                                schema_name,
+                                name,
+                                field_type.name(),
+                                column.data_type()
+                            ))
+                        })?;
+                    if name == "id" && value == Value::Null {
+                        continue;
+                    }
+                    values.push(self.seal_field(schema, name, value)?);
+                    names.push(name.as_str());
+                }
+
+                if let Some(version) = schema.uuid_ids
+                    && !names.contains(&"id")
+                {
+                    names.push("id");
+                    values.push(Value::Text(version.generate()));
+                }
+
+                let placeholders = vec!["?"; names.len()];
+                let sql = format!(
+                    "INSERT INTO {} ({}) VALUES ({})",
+                    schema_name,
+                    names.join(", "),
+                    placeholders.join(", ")
+                );
+                let id = inserted_id(schema, &names, &values);
+                self.audit_insert(schema, id, || {
+                    Ok(self
+                        .prepare_cached(&sql)?
+                        .execute(rusqlite::params_from_iter(&values))?)
+                })?;
+                inserted += 1;
+            }
+        }
+        tx.commit()?;
+
+        Ok(inserted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::types::Float32Type;
+    use arrow_array::{ArrayRef, Int32Array, ListArray, RecordBatch, StringArray};
+    use arrow_schema::{Field, Schema as ArrowSchema};
+    use std::collections::HashMap;
+
+    use crate::flexible_database::{FieldDef, ModelId, Schema, UuidVersion};
+    use crate::geo::GeoPoint;
+    use crate::temp_file::TempFile;
+    use crate::vector::encode;
+
+    fn places() -> Schema {
+        let kinds = FieldType::Enum(vec!["park".to_string(), "cafe".to_string()]);
+        Schema::new("places", [])
+            .field("name", FieldDef::new(FieldType::Text))
+            .field("visits", FieldDef::new(FieldType::Integer))
+            .field("rating", FieldDef::new(FieldType::Real))
+            .field("open", FieldDef::new(FieldType::Boolean))
+            .field("kind", FieldDef::new(kinds))
+            .field("location", FieldDef::new(FieldType::GeoPoint))
+            .field("embedding", FieldDef::new(FieldType::Vector(2)))
+            .field("note", FieldDef::new(FieldType::Text).nullable())
+            .with_versioning()
+    }
+
+    fn database() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(places()).unwrap();
+        db
+    }
+
+    fn place(name: &str, note: Option<&str>) -> HashMap<String, Value> {
+        HashMap::from([
+            ("name".to_string(), Value::Text(name.to_string())),
+            ("visits".to_string(), Value::Integer(3)),
+            ("rating".to_string(), Value::Real(4.5)),
+            ("open".to_string(), Value::Integer(1)),
+            ("kind".to_string(), Value::Text("park".to_string())),
+            ("location".to_string(), GeoPoint::new(51.5, -0.1).into()),
+            ("embedding".to_string(), encode(&[0.5, -1.0])),
+            (
+                "note".to_string(),
+                note.map_or(Value::Null, |note| Value::Text(note.to_string())),
+            ),
+        ])
+    }
+
+    fn write(path: &str, columns: Vec<(&str, ArrayRef)>) {
+        let fields: Vec<Field> = columns
+            .iter()
+            .map(|(name, array)| Field::new(*name, array.data_type().clone(), true))
+            .collect();
+        let arrays = columns.into_iter().map(|(_, array)| array).collect();
+        let batch = RecordBatch::try_new(Arc::new(ArrowSchema::new(fields)), arrays).unwrap();
+        let mut writer = ArrowWriter::try_new(File::create(path).unwrap(), batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+    }
+
+    #[test]
+    fn exports_import_back_unchanged() {
+        let source = database();
+        source.create_model("places", place("common", Some("big"))).unwrap();
+        source.create_model("places", place("corner", None)).unwrap();
+        let file = TempFile::new("parquet");
+        assert_eq!(source.export_parquet("places", file.path()).unwrap(), 2);
+
+        let target = database();
+        assert_eq!(target.import_parquet("places", file.path()).unwrap(), 2);
+        for id in [1, 2] {
+            let expected = source.get_model("places", id).unwrap().unwrap();
+            let imported = target.get_model("places", id).unwrap().unwrap();
+            assert_eq!(imported.data, expected.data);
+        }
+    }
+
+    #[test]
+    fn exports_use_the_arrow_layout() {
+        let db = database();
+        db.create_model("places", place("common", None)).unwrap();
+        let file = TempFile::new("parquet");
+        db.export_parquet("places", file.path()).unwrap();
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(file.path()).unwrap()).unwrap();
+        let schema = reader.schema();
+        let expected = Arc::new(arrow_schema(&db.schemas["places"]));
+        assert_eq!(schema.fields(), expected.fields());
+    }
+
+    #[test]
+    fn other_files_fit_fields_stored_the_same_way() {
+        let db = database();
+        let file = TempFile::new("parquet");
+        let embeddings = ListArray::from_iter_primitive::<Float32Type, _, _>([Some([Some(1.0), Some(2.0)])]);
+        write(
+            file.path(),
+            vec![
+                ("name", Arc::new(StringArray::from(vec!["pier"]))),
+                ("visits", Arc::new(Int32Array::from(vec![7]))),
+                ("rating", Arc::new(Int32Array::from(vec![4]))),
+                ("open", Arc::new(Int32Array::from(vec![0]))),
+                ("kind", Arc::new(StringArray::from(vec!["cafe"]))),
+                (
+                    "location",
+                    Arc::new(StringArray::from(vec![r#"{"lat":1.0,"lon":2.0}"#])),
+                ),
+                ("embedding", Arc::new(embeddings)),
+                ("note", Arc::new(StringArray::from(vec![None::<&str>]))),
+            ],
+        );
+        assert_eq!(db.import_parquet("places", file.path()).unwrap(), 1);
+        // With no id column the rows get fresh ids
+        let imported = db.get_model("places", 1).unwrap().unwrap();
+        assert_eq!(imported.data["visits"], Value::Integer(7));
+        assert_eq!(imported.data["rating"], Value::Real(4.0));
+        assert_eq!(imported.data["embedding"], encode(&[1.0, 2.0]));
+        assert_eq!(imported.data["note"], Value::Null);
+    }
+
+    #[test]
+    fn bad_rows_abort_the_whole_import() {
+        let db = database();
+        let file = TempFile::new("parquet");
+        db.create_model("places", place("common", None)).unwrap();
+        db.export_parquet("places", file.path()).unwrap();
+        let target = database();
+        target.import_parquet("places", file.path()).unwrap();
+        // The same ids again break the key
+        assert!(target.import_parquet("places", file.path()).is_err());
+        assert_eq!(target.count("places").unwrap(), 1);
+
+        let file = TempFile::new("parquet");
+        write(
+            file.path(),
+            vec![
+                ("name", Arc::new(StringArray::from(vec!["a", "b"]))),
+                ("visits", Arc::new(StringArray::from(vec!["1", "x"]))),
+            ],
+        );
+        let err = target.import_parquet("places", file.path()).unwrap_err();
+        assert!(
+            matches!(err, KooError::InvalidData(ref message) if message.contains("'places.visits' expects")),
+            "{:?}",
+            err
+        );
+        assert_eq!(target.count("places").unwrap(), 1);
+
+        write(file.path(), vec![("colour", Arc::new(StringArray::from(vec!["red"])))]);
+        assert!(matches!(
+            target.import_parquet("places", file.path()),
+            Err(KooError::UnknownField { field, .. }) if field == "colour"
+        ));
+    }
+
+    #[test]
+    fn imported_rows_get_uuids_and_are_audited() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("tags", [("label".to_string(), FieldType::Text)]).with_uuid_ids(UuidVersion::V4);
+        db.define_schema(schema).unwrap();
+        db.enable_audit().unwrap();
+        let file = TempFile::new("parquet");
+        write(
+            file.path(),
+            vec![("label", Arc::new(StringArray::from(vec!["red", "blue"])))],
+        );
+        assert_eq!(db.import_parquet("tags", file.path()).unwrap(), 2);
+
+        for tag in db.get_all_models("tags").unwrap() {
+            let Some(ModelId::Text(id)) = tag.id else {
+                panic!("{:?}", tag.id)
+            };
+            assert!(uuid::Uuid::parse_str(&id).is_ok(), "{}", id);
+            let history = db.audit_history("tags", id).unwrap();
+            assert_eq!(history.len(), 1);
+            let Value::Text(label) = &tag.data["label"] else {
+                panic!()
+            };
+            assert_eq!(history[0].new_values.as_ref().unwrap()["label"], label.as_str());
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use rusqlite::{Connection, OpenFlags};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FlexibleDatabase, Schema};
+use crate::options::DatabaseOptions;
+
+// Opens FlexibleDatabase handles for connection pools (r2d2 and deadpool,
+// behind the features of the same name). Schemas registered on the manager
+// are shared by every handle: each one is brought up to date with the
+// registry when it is created and again on every checkout.
+//
+// Note that every handle is its own SQLite connection, so ":memory:" gives
+// each handle a separate database.
+#[derive(Debug, Clone)]
+pub struct KooManager {
+    db_path: String,
+    options: DatabaseOptions,
+    schemas: Arc<RwLock<HashMap<String, Schema>>>,
+    quick_check: bool,
+}
+
+impl KooManager {
+    pub fn new(db_path: &str) -> KooManager {
+        KooManager {
+            db_path: db_path.to_string(),
+            options: DatabaseOptions::default(),
+            schemas: Arc::new(RwLock::new(HashMap::new())),
+            quick_check: false,
+        }
+    }
+
+    // Connection settings applied to every handle
+    pub fn with_options(mut self, options: DatabaseOptions) -> KooManager {
+        self.options = options;
+        self
+    }
+
+    // Also run `PRAGMA quick_check` in health checks. This reads the whole
+    // database, so it is off by default.
+    pub fn with_quick_check(mut self, enabled: bool) -> KooManager {
+        self.quick_check = enabled;
+        self
+    }
+
+    // Add a schema to the shared registry. Handles pick it up, creating the
+    // table if needed, the next time they are checked out.
+    pub fn register_schema(&self, schema: Schema) -> Result<()> {
+        let schema = schema.materialize()?;
+        let mut schemas = self.schemas.write().unwrap_or_else(|e| e.into_inner());
+        schemas.insert(schema.name.clone(), schema);
+        Ok(())
+    }
+
+    // Snapshot of the shared registry
+    pub fn schemas(&self) -> HashMap<String, Schema> {
+        self.schemas.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn open(&self) -> Result<FlexibleDatabase> {
+        let mut db = FlexibleDatabase::open_with(&self.db_path, self.options.clone())?;
+        self.sync_schemas(&mut db)?;
+        Ok(db)
+    }
+
+    // A read-only handle on the same file, knowing the registered schemas
+    // without defining them, as it can't create tables
+    pub(crate) fn open_reader(&self) -> Result<FlexibleDatabase> {
+        let conn = Connection::open_with_flags(
+            &self.db_path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI,
+        )?;
+        let options = DatabaseOptions {
+            journal_mode: None,
+            ..self.options.clone()
+        };
+        let mut db = FlexibleDatabase::from_connection(conn, options)?;
+        self.sync_reader(&mut db);
+        Ok(db)
+    }
+
+    pub(crate) fn sync_reader(&self, db: &mut FlexibleDatabase) {
+        let schemas = self.schemas.read().unwrap_or_else(|e| e.into_inner());
+        for schema in schemas.values() {
+            if !db.schemas.contains_key(&schema.name) {
+                db.schemas.insert(schema.name.clone(), schema.clone());
+            }
+        }
+    }
+
+    pub(crate) fn options(&self) -> &DatabaseOptions {
+        &self.options
+    }
+
+    // Define any registered schema the handle doesn't know about yet
+    pub(crate) fn sync_schemas(&self, db: &mut FlexibleDatabase) -> Result<()> {
+        let missing: Vec<Schema> = {
+            let schemas = self.schemas.read().unwrap_or_else(|e| e.into_inner());
+            schemas
+                .values()
+                .filter(|schema| !db.schemas.contains_key(&schema.name))
+                .cloned()
+                .collect()
+        };
+        for schema in missing {
+            db.define_schema(schema)?;
+        }
+        Ok(())
+    }
+
+    // Verify the handle is usable and refresh its schemas
+    pub fn check(&self, db: &mut FlexibleDatabase) -> Result<()> {
+        db.conn.query_row("SELECT 1", [], |row| row.get::<_, i64>(0))?;
+
+        if self.quick_check {
+            let result: String = db.conn.query_row("PRAGMA quick_check", [], |row| row.get(0))?;
+            if result != "ok" {
+                return Err(KooError::InvalidData(format!("quick_check failed: {}", result)));
+            }
+        }
+
+        self.sync_schemas(db)
+    }
+
+    // A handle returned with a transaction still open can't be reused
+    pub fn is_broken(&self, db: &FlexibleDatabase) -> bool {
+        !db.conn.is_autocommit()
+    }
+}
+
+#[cfg(feature = "r2d2")]
+impl r2d2::ManageConnection for KooManager {
+    type Connection = FlexibleDatabase;
+    type Error = KooError;
+
+    fn connect(&self) -> Result<FlexibleDatabase> {
+        self.open()
+    }
+
+    fn is_valid(&self, db: &mut FlexibleDatabase) -> Result<()> {
+        self.check(db)
+    }
+
+    fn has_broken(&self, db: &mut FlexibleDatabase) -> bool {
+        self.is_broken(db)
+    }
+}
+
+// SQLite calls block, so these futures complete without yielding
+#[cfg(feature = "deadpool")]
+impl deadpool::managed::Manager for KooManager {
+    type Type = FlexibleDatabase;
+    type Error = KooError;
+
+    async fn create(&self) -> Result<FlexibleDatabase> {
+        self.open()
+    }
+
+    async fn recycle(
+        &self,
+        db: &mut FlexibleDatabase,
+        _metrics: &deadpool::managed::Metrics,
+    ) -> deadpool::managed::RecycleResult<KooError> {
+        if self.is_broken(db) {
+            return Err(deadpool::managed::RecycleError::message(
+                "handle was returned with an open transaction",
+            ));
+        }
+        self.check(db).map_err(deadpool::managed::RecycleError::Backend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::Value;
+
+    use crate::flexible_database::FieldType;
+    use crate::temp_file::TempFile;
+
+    fn notes() -> Schema {
+        Schema::new("notes", HashMap::from([("body".to_string(), FieldType::Text)]))
+    }
+
+    #[test]
+    fn handles_share_the_registered_schemas() {
+        let file = TempFile::new("db");
+        let manager = KooManager::new(file.path());
+        let mut first = manager.open().unwrap();
+        let second = manager.open().unwrap();
+
+        manager.register_schema(notes()).unwrap();
+        assert!(!first.schemas.contains_key("notes"));
+        manager.check(&mut first).unwrap();
+        let data = HashMap::from([("body".to_string(), Value::Text("hi".to_string()))]);
+        first.create_model("notes", data).unwrap();
+
+        // Handles opened later know the schema from the start
+        let third = manager.open().unwrap();
+        assert_eq!(third.count("notes").unwrap(), 1);
+        assert!(!second.schemas.contains_key("notes"));
+        assert!(manager.schemas().contains_key("notes"));
+    }
+
+    #[test]
+    fn invalid_schemas_are_not_registered() {
+        let manager = KooManager::new(":memory:");
+        let base = Schema::new("base", HashMap::from([("body".to_string(), FieldType::Integer)]));
+        let schema = notes().extends(&base);
+        assert!(manager.register_schema(schema).is_err());
+        assert!(manager.schemas().is_empty());
+    }
+
+    #[test]
+    fn health_checks_can_run_quick_check() {
+        let manager = KooManager::new(":memory:").with_quick_check(true);
+        let mut db = manager.open().unwrap();
+        manager.check(&mut db).unwrap();
+    }
+
+    #[test]
+    fn handles_left_in_a_transaction_are_broken() {
+        let manager = KooManager::new(":memory:");
+        let db = manager.open().unwrap();
+        assert!(!manager.is_broken(&db));
+        db.conn.execute_batch("BEGIN").unwrap();
+        assert!(manager.is_broken(&db));
+    }
+
+    #[cfg(feature = "r2d2")]
+    #[test]
+    fn r2d2_pools_hand_out_synced_handles() {
+        let file = TempFile::new("db");
+        let manager = KooManager::new(file.path());
+        manager.register_schema(notes()).unwrap();
+        let pool = r2d2::Pool::builder().max_size(2).build(manager).unwrap();
+        let data = HashMap::from([("body".to_string(), Value::Text("hi".to_string()))]);
+        pool.get().unwrap().create_model("notes", data).unwrap();
+        assert_eq!(pool.get().unwrap().count("notes").unwrap(), 1);
+    }
+}
@@ -0,0 +1,1500 @@
+use rusqlite::types::Value;
+use std::collections::HashMap;
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{
+    FlexibleDatabase, Model, ModelId, PrimaryKey, Schema, VERSION_COLUMN, read_model, select_sql,
+};
+use crate::geo::{GeoArea, within_sql};
+use crate::relations::reference_targets;
+use crate::wire::{from_hex, to_hex, value_from_tagged_json, value_to_tagged_json};
+
+// Comparison used by a filter condition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    // Text matching against a text value, taken literally even where it
+    // holds wildcards
+    StartsWith,
+    EndsWith,
+    Contains,
+    // The same ignoring case, for ASCII letters only
+    StartsWithIgnoreCase,
+    EndsWithIgnoreCase,
+    ContainsIgnoreCase,
+}
+
+impl Op {
+    pub(crate) fn sql(&self) -> &'static str {
+        match self {
+            Op::Eq => "=",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::StartsWith | Op::EndsWith | Op::Contains => "GLOB",
+            Op::StartsWithIgnoreCase | Op::EndsWithIgnoreCase | Op::ContainsIgnoreCase => "LIKE",
+        }
+    }
+
+    // For text matching, whether the pattern has a wildcard before and
+    // after the value
+    fn wildcards(&self) -> Option<(bool, bool)> {
+        match self {
+            Op::StartsWith | Op::StartsWithIgnoreCase => Some((false, true)),
+            Op::EndsWith | Op::EndsWithIgnoreCase => Some((true, false)),
+            Op::Contains | Op::ContainsIgnoreCase => Some((true, true)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Asc,
+    Desc,
+}
+
+// Value computed over each group of a grouped query. Fields that are NULL
+// are left out of everything but `Count`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Aggregate {
+    // Number of models in the group
+    Count,
+    Sum(String),
+    Avg(String),
+    Min(String),
+    Max(String),
+}
+
+impl Aggregate {
+    fn field(&self) -> Option<&str> {
+        match self {
+            Aggregate::Count => None,
+            Aggregate::Sum(field) | Aggregate::Avg(field) | Aggregate::Min(field) | Aggregate::Max(field) => {
+                Some(field)
+            }
+        }
+    }
+
+    fn sql(&self) -> String {
+        match self {
+            Aggregate::Count => "COUNT(*)".to_string(),
+            Aggregate::Sum(field) => format!("SUM({})", field),
+            Aggregate::Avg(field) => format!("AVG({})", field),
+            Aggregate::Min(field) => format!("MIN({})", field),
+            Aggregate::Max(field) => format!("MAX({})", field),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Condition {
+    pub field: String,
+    pub op: Op,
+    pub value: Value,
+}
+
+impl Condition {
+    // SQL for the condition and the value bound to its placeholder. Text
+    // matches become GLOB patterns, which are case-sensitive, or LIKE
+    // patterns, with the value's own wildcards escaped.
+    pub(crate) fn to_sql(&self) -> Result<(String, Value)> {
+        let Some((before, after)) = self.op.wildcards() else {
+            return Ok((format!("{} {} ?", self.field, self.op.sql()), self.value.clone()));
+        };
+        let Value::Text(text) = &self.value else {
+            return Err(KooError::InvalidData(format!(
+                "{:?} on '{}' needs a text value",
+                self.op, self.field
+            )));
+        };
+        let (sql, pattern, wildcard) = match self.op.sql() {
+            "GLOB" => {
+                // GLOB has no escape character, but a bracketed wildcard
+                // matches itself
+                let mut pattern = String::new();
+                for c in text.chars() {
+                    match c {
+                        '*' | '?' | '[' => pattern.extend(['[', c, ']']),
+                        _ => pattern.push(c),
+                    }
+                }
+                (format!("{} GLOB ?", self.field), pattern, "*")
+            }
+            _ => {
+                let mut pattern = String::new();
+                for c in text.chars() {
+                    if matches!(c, '\\' | '%' | '_') {
+                        pattern.push('\\');
+                    }
+                    pattern.push(c);
+                }
+                (format!("{} LIKE ? ESCAPE '\\'", self.field), pattern, "%")
+            }
+        };
+        let pattern = format!(
+            "{}{}{}",
+            if before { wildcard } else { "" },
+            pattern,
+            if after { wildcard } else { "" }
+        );
+        Ok((sql, Value::Text(pattern)))
+    }
+}
+
+// Field that must, or with `negated` mustn't, hold one of `values`
+#[derive(Debug, Clone)]
+pub(crate) struct ListCondition {
+    field: String,
+    values: Vec<Value>,
+    negated: bool,
+}
+
+impl ListCondition {
+    // Short lists get a placeholder per value. Longer ones could run past
+    // the bound parameter limit, so they are bound as one JSON array.
+    fn to_sql(&self) -> Result<(String, Vec<Value>)> {
+        let op = if self.negated { "NOT IN" } else { "IN" };
+        if self.values.len() <= MAX_LISTED_VALUES {
+            let placeholders = vec!["?"; self.values.len()].join(", ");
+            return Ok((format!("{} {} ({})", self.field, op, placeholders), self.values.clone()));
+        }
+        let mut array = Vec::new();
+        for value in &self.values {
+            array.push(match value {
+                Value::Null => serde_json::Value::Null,
+                Value::Integer(i) => (*i).into(),
+                Value::Real(f) => (*f).into(),
+                Value::Text(s) => s.clone().into(),
+                Value::Blob(_) => {
+                    return Err(KooError::InvalidData(format!(
+                        "lists of more than {} values for '{}' can't hold blobs",
+                        MAX_LISTED_VALUES, self.field
+                    )));
+                }
+            });
+        }
+        Ok((
+            format!("{} {} (SELECT value FROM json_each(?))", self.field, op),
+            vec![Value::Text(serde_json::Value::Array(array).to_string())],
+        ))
+    }
+}
+
+// Longest list `filter_in` binds a placeholder per value for
+const MAX_LISTED_VALUES: usize = 100;
+
+// Boolean combination of conditions, for what a query's plain filters,
+// which must all hold, can't express. E.g. "active or invited, and not
+// banned":
+//
+//   Filter::and(vec![
+//       Filter::or(vec![
+//           Filter::condition("status", Op::Eq, "active"),
+//           Filter::condition("status", Op::Eq, "invited"),
+//       ]),
+//       Filter::not(Filter::condition("banned", Op::Eq, 1)),
+//   ])
+#[derive(Debug, Clone)]
+pub struct Filter(FilterNode);
+
+#[derive(Debug, Clone)]
+enum FilterNode {
+    Condition(Condition),
+    List(ListCondition),
+    Within(String, GeoArea),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    pub fn condition(field_name: &str, op: Op, value: impl Into<Value>) -> Filter {
+        Filter(FilterNode::Condition(Condition {
+            field: field_name.to_string(),
+            op,
+            value: value.into(),
+        }))
+    }
+
+    pub fn is_in<V: Into<Value>>(field_name: &str, values: impl IntoIterator<Item = V>) -> Filter {
+        Filter(FilterNode::List(ListCondition {
+            field: field_name.to_string(),
+            values: values.into_iter().map(Into::into).collect(),
+            negated: false,
+        }))
+    }
+
+    pub fn not_in<V: Into<Value>>(field_name: &str, values: impl IntoIterator<Item = V>) -> Filter {
+        Filter(FilterNode::List(ListCondition {
+            field: field_name.to_string(),
+            values: values.into_iter().map(Into::into).collect(),
+            negated: true,
+        }))
+    }
+
+    // Holds when the `GeoPoint` field `field_name` lies in `area`
+    pub fn within(field_name: &str, area: GeoArea) -> Filter {
+        Filter(FilterNode::Within(field_name.to_string(), area))
+    }
+
+    // Holds when every filter does, including when there are none
+    pub fn and(filters: Vec<Filter>) -> Filter {
+        Filter(FilterNode::And(filters))
+    }
+
+    // Holds when any filter does, so never when there are none
+    pub fn or(filters: Vec<Filter>) -> Filter {
+        Filter(FilterNode::Or(filters))
+    }
+
+    // Named to read alongside `and` and `or`
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(filter: Filter) -> Filter {
+        Filter(FilterNode::Not(Box::new(filter)))
+    }
+
+    fn to_sql(&self, schema: &Schema) -> Result<(String, Vec<Value>)> {
+        let (filters, joiner, empty) = match &self.0 {
+            FilterNode::Condition(condition) => {
+                check_field(schema, &condition.field)?;
+                let (sql, param) = condition.to_sql()?;
+                return Ok((sql, vec![param]));
+            }
+            FilterNode::List(list) => {
+                check_field(schema, &list.field)?;
+                return list.to_sql();
+            }
+            FilterNode::Within(field_name, area) => return within_sql(schema, field_name, area),
+            FilterNode::Not(filter) => {
+                let (sql, params) = filter.to_sql(schema)?;
+                return Ok((format!("NOT ({})", sql), params));
+            }
+            FilterNode::And(filters) => (filters, " AND ", "1"),
+            FilterNode::Or(filters) => (filters, " OR ", "0"),
+        };
+        if filters.is_empty() {
+            return Ok((empty.to_string(), Vec::new()));
+        }
+        let mut clauses = Vec::new();
+        let mut params = Vec::new();
+        for filter in filters {
+            let (sql, filter_params) = filter.to_sql(schema)?;
+            clauses.push(format!("({})", sql));
+            params.extend(filter_params);
+        }
+        Ok((clauses.join(joiner), params))
+    }
+}
+
+// Description of a read over one schema. Conditions are combined with AND
+// and results are always ordered, falling back to the key, so paging is
+// stable.
+#[derive(Debug, Clone)]
+pub struct Query {
+    pub(crate) schema: String,
+    pub(crate) conditions: Vec<Condition>,
+    pub(crate) lists: Vec<ListCondition>,
+    pub(crate) filters: Vec<Filter>,
+    pub(crate) order: Vec<(String, Direction)>,
+    pub(crate) limit: Option<usize>,
+    pub(crate) offset: usize,
+    pub(crate) max_result_bytes: Option<usize>,
+    pub(crate) includes: Vec<String>,
+    pub(crate) group_by: Vec<String>,
+    // Named aggregates, in the order they were added
+    pub(crate) aggregates: Vec<(String, Aggregate)>,
+    // Cursor of the model to continue after
+    pub(crate) after: Option<String>,
+    pub(crate) random: bool,
+    // Fields to read, None for all of them
+    pub(crate) fields: Option<Vec<String>>,
+}
+
+// One batch of results plus the query that continues after it, if the
+// batch was cut short by `max_result_bytes`
+#[derive(Debug, Clone)]
+pub struct QueryPage {
+    pub models: Vec<Model>,
+    pub next: Option<Query>,
+    // Where the last model sorts, for `Query::after`; None for an empty page
+    pub cursor: Option<String>,
+}
+
+impl Query {
+    pub fn new(schema_name: &str) -> Query {
+        Query {
+            schema: schema_name.to_string(),
+            conditions: Vec::new(),
+            lists: Vec::new(),
+            filters: Vec::new(),
+            order: Vec::new(),
+            limit: None,
+            offset: 0,
+            max_result_bytes: None,
+            includes: Vec::new(),
+            group_by: Vec::new(),
+            aggregates: Vec::new(),
+            after: None,
+            random: false,
+            fields: None,
+        }
+    }
+
+    pub fn schema_name(&self) -> &str {
+        &self.schema
+    }
+
+    pub fn filter(mut self, field_name: &str, op: Op, value: impl Into<Value>) -> Query {
+        self.conditions.push(Condition {
+            field: field_name.to_string(),
+            op,
+            value: value.into(),
+        });
+        self
+    }
+
+    // Match models whose `field_name` is one of `values`. An empty list
+    // matches nothing.
+    pub fn filter_in<V: Into<Value>>(mut self, field_name: &str, values: impl IntoIterator<Item = V>) -> Query {
+        self.lists.push(ListCondition {
+            field: field_name.to_string(),
+            values: values.into_iter().map(Into::into).collect(),
+            negated: false,
+        });
+        self
+    }
+
+    // Match models whose `field_name` is none of `values`
+    pub fn filter_not_in<V: Into<Value>>(mut self, field_name: &str, values: impl IntoIterator<Item = V>) -> Query {
+        self.lists.push(ListCondition {
+            field: field_name.to_string(),
+            values: values.into_iter().map(Into::into).collect(),
+            negated: true,
+        });
+        self
+    }
+
+    // Match models for which `filter` holds
+    pub fn filter_by(mut self, filter: Filter) -> Query {
+        self.filters.push(filter);
+        self
+    }
+
+    // Match models whose `GeoPoint` field `field_name` lies in `area`, e.g.
+    // within 500 meters of a user:
+    //
+    //   Query::new("shops").within("location", GeoArea::Radius { center, meters: 500.0 })
+    pub fn within(self, field_name: &str, area: GeoArea) -> Query {
+        self.filter_by(Filter::within(field_name, area))
+    }
+
+    pub fn order_by(mut self, field_name: &str, direction: Direction) -> Query {
+        self.order.push((field_name.to_string(), direction));
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Query {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: usize) -> Query {
+        self.offset = offset;
+        self
+    }
+
+    // Continue after the model a page's `cursor` was taken from. Rather
+    // than skipping rows like an offset, this seeks straight to them, so
+    // later pages cost no more than the first, and models written in
+    // between don't shift the pages. The query must sort the same way as
+    // the one the cursor came from.
+    pub fn after(mut self, cursor: &str) -> Query {
+        self.after = Some(cursor.to_string());
+        self
+    }
+
+    // Shuffle the results, after any `order_by`, which still comes first.
+    // Each run gives a new order, so the results aren't cached, and they
+    // can't be paged with cursors or `max_result_bytes`.
+    pub fn random_order(mut self) -> Query {
+        self.random = true;
+        self
+    }
+
+    // Stop reading once the approximate encoded size of the results would
+    // exceed `bytes`. At least one model is always returned so the query
+    // can make progress; use `find_page` to get the continuation.
+    pub fn max_result_bytes(mut self, bytes: usize) -> Query {
+        self.max_result_bytes = Some(bytes);
+        self
+    }
+
+    // Read only these fields of each model, along with the key and any
+    // fields the query sorts by or includes. Filters can still use the
+    // others. With an access policy set whole rows are read, since the
+    // policy may look at any field, and then narrowed.
+    pub fn select(mut self, field_names: &[&str]) -> Query {
+        self.fields = Some(field_names.iter().map(|f| f.to_string()).collect());
+        self
+    }
+
+    // Also load the model the reference field `field_name` points at, into
+    // each result's `related`. The models are fetched with one more query
+    // per included field, or per schema a polymorphic one points at,
+    // rather than one per result.
+    pub fn include(mut self, field_name: &str) -> Query {
+        self.includes.push(field_name.to_string());
+        self
+    }
+
+    // Group the results by `field_name`, making this a query for
+    // `FlexibleDatabase::aggregate`. Call it again to group by more fields.
+    pub fn group_by(mut self, field_name: &str) -> Query {
+        self.group_by.push(field_name.to_string());
+        self
+    }
+
+    // Compute `aggregate` over each group, returned under `name`
+    pub fn aggregate(mut self, name: &str, aggregate: Aggregate) -> Query {
+        self.aggregates.push((name.to_string(), aggregate));
+        self
+    }
+
+    fn is_grouped(&self) -> bool {
+        !self.group_by.is_empty() || !self.aggregates.is_empty()
+    }
+
+    // The fields results keep, None for all of them
+    pub(crate) fn kept_fields(&self) -> Option<Vec<String>> {
+        let mut kept = self.fields.clone()?;
+        kept.extend(self.includes.iter().cloned());
+        kept.extend(self.order.iter().map(|(field_name, _)| field_name.clone()));
+        Some(kept)
+    }
+
+    // Full SELECT statement reading the columns of `columns`, which is
+    // `schema` or a projection of it, and its parameters
+    pub(crate) fn to_sql(&self, schema: &Schema, columns: &Schema) -> Result<(String, Vec<Value>)> {
+        if self.is_grouped() {
+            return Err(KooError::InvalidData(
+                "grouped queries return rows rather than models; run them with `aggregate`".to_string(),
+            ));
+        }
+        let mut sql = select_sql(columns);
+        let (where_sql, mut params) = self.where_sql(schema)?;
+        sql.push_str(&where_sql);
+
+        for field_name in &self.includes {
+            reference_targets(schema, field_name)?;
+        }
+
+        // Each page would be cut from a fresh shuffle
+        if self.random && self.max_result_bytes.is_some() {
+            return Err(KooError::InvalidData(
+                "randomly ordered queries can't be read in pages by size".to_string(),
+            ));
+        }
+        let columns = self.sort_columns(schema);
+        if let Some(cursor) = &self.after {
+            if self.random {
+                return Err(KooError::InvalidData(
+                    "randomly ordered queries can't continue after a cursor".to_string(),
+                ));
+            }
+            let (keyset_sql, keyset_params) = keyset_sql(&columns, cursor)?;
+            let joiner = if where_sql.is_empty() { " WHERE" } else { " AND" };
+            sql.push_str(&format!("{} ({})", joiner, keyset_sql));
+            params.extend(keyset_params);
+        }
+
+        let mut order = Vec::new();
+        for (field_name, direction) in &columns {
+            check_field(schema, field_name)?;
+            let direction = match direction {
+                Direction::Asc => "ASC",
+                Direction::Desc => "DESC",
+            };
+            order.push(format!("{} {}", field_name, direction));
+        }
+        if self.random {
+            order.insert(self.order.len(), "RANDOM()".to_string());
+        }
+        sql.push_str(&format!(" ORDER BY {}", order.join(", ")));
+
+        // SQLite needs a LIMIT before an OFFSET; -1 means no limit
+        match self.limit {
+            Some(limit) => sql.push_str(&format!(" LIMIT {}", limit)),
+            None if self.offset > 0 => sql.push_str(" LIMIT -1"),
+            None => {}
+        }
+        if self.offset > 0 {
+            sql.push_str(&format!(" OFFSET {}", self.offset));
+        }
+
+        Ok((sql, params))
+    }
+
+    // The order results come in: the query's own, then the key
+    fn sort_columns(&self, schema: &Schema) -> Vec<(String, Direction)> {
+        let mut columns = self.order.clone();
+        let key_columns = match &schema.key {
+            PrimaryKey::Composite(key_fields) => key_fields.clone(),
+            _ => vec!["id".to_string()],
+        };
+        for column in key_columns {
+            if !columns.iter().any(|(field_name, _)| *field_name == column) {
+                columns.push((column, Direction::Asc));
+            }
+        }
+        columns
+    }
+
+    // Cursor for continuing after `model`: the values it sorts by, along
+    // with the order they were sorted in, as hex-encoded JSON. None for
+    // random orders, which can't be continued.
+    fn cursor(&self, schema: &Schema, model: &Model) -> Option<String> {
+        if self.random {
+            return None;
+        }
+        let mut order = Vec::new();
+        let mut values = Vec::new();
+        for (field_name, direction) in self.sort_columns(schema) {
+            let value = match (field_name.as_str(), &model.id) {
+                ("id", Some(ModelId::Integer(id))) => Value::Integer(*id),
+                ("id", Some(ModelId::Text(id))) => Value::Text(id.clone()),
+                _ => model.data.get(&field_name).cloned().unwrap_or(Value::Null),
+            };
+            let direction = match direction {
+                Direction::Asc => "asc",
+                Direction::Desc => "desc",
+            };
+            order.push(serde_json::json!([field_name, direction]));
+            values.push(value_to_tagged_json(&value));
+        }
+        let cursor = serde_json::json!({ "order": order, "values": values });
+        Some(to_hex(cursor.to_string().as_bytes()))
+    }
+
+    // SELECT statement computing the groups, and its parameters
+    fn aggregate_sql(&self, schema: &Schema) -> Result<(String, Vec<Value>)> {
+        if !self.includes.is_empty() {
+            return Err(KooError::InvalidData(
+                "grouped queries return rows, so they can't include models".to_string(),
+            ));
+        }
+        if self.after.is_some() {
+            return Err(KooError::InvalidData(
+                "grouped queries page with offsets rather than cursors".to_string(),
+            ));
+        }
+        let mut columns = Vec::new();
+        for field_name in &self.group_by {
+            check_field(schema, field_name)?;
+            columns.push(field_name.clone());
+        }
+        for (_, aggregate) in &self.aggregates {
+            if let Some(field_name) = aggregate.field() {
+                check_field(schema, field_name)?;
+            }
+            columns.push(aggregate.sql());
+        }
+        if columns.is_empty() {
+            columns.push("COUNT(*)".to_string());
+        }
+
+        let (where_sql, params) = self.where_sql(schema)?;
+        let mut sql = format!("SELECT {} FROM {}{}", columns.join(", "), schema.name, where_sql);
+        if !self.group_by.is_empty() {
+            sql.push_str(&format!(" GROUP BY {}", self.group_by.join(", ")));
+        }
+
+        // Groups sort by a grouped field or an aggregate, by position, then
+        // by the grouped fields
+        let mut order = Vec::new();
+        for (name, direction) in &self.order {
+            let position = self
+                .group_by
+                .iter()
+                .position(|field_name| field_name == name)
+                .or_else(|| {
+                    let index = self.aggregates.iter().position(|(aggregate, _)| aggregate == name)?;
+                    Some(self.group_by.len() + index)
+                })
+                .ok_or_else(|| {
+                    KooError::InvalidData(format!(
+                        "grouped queries can only be ordered by a grouped field or aggregate, not '{}'",
+                        name
+                    ))
+                })?;
+            let direction = match direction {
+                Direction::Asc => "ASC",
+                Direction::Desc => "DESC",
+            };
+            order.push(format!("{} {}", position + 1, direction));
+        }
+        if self.random {
+            order.push("RANDOM()".to_string());
+        }
+        for position in 0..self.group_by.len() {
+            order.push(format!("{} ASC", position + 1));
+        }
+        if !order.is_empty() {
+            sql.push_str(&format!(" ORDER BY {}", order.join(", ")));
+        }
+
+        match self.limit {
+            Some(limit) => sql.push_str(&format!(" LIMIT {}", limit)),
+            None if self.offset > 0 => sql.push_str(" LIMIT -1"),
+            None => {}
+        }
+        if self.offset > 0 {
+            sql.push_str(&format!(" OFFSET {}", self.offset));
+        }
+
+        Ok((sql, params))
+    }
+
+    // WHERE clause of the conditions, with its leading space, or nothing
+    pub(crate) fn where_sql(&self, schema: &Schema) -> Result<(String, Vec<Value>)> {
+        if self.conditions.is_empty() && self.lists.is_empty() && self.filters.is_empty() {
+            return Ok((String::new(), Vec::new()));
+        }
+        let mut clauses = Vec::new();
+        let mut params = Vec::new();
+        for condition in &self.conditions {
+            check_field(schema, &condition.field)?;
+            let (clause, param) = condition.to_sql()?;
+            clauses.push(clause);
+            params.push(param);
+        }
+        for list in &self.lists {
+            check_field(schema, &list.field)?;
+            let (clause, list_params) = list.to_sql()?;
+            clauses.push(clause);
+            params.extend(list_params);
+        }
+        for filter in &self.filters {
+            let (clause, filter_params) = filter.to_sql(schema)?;
+            clauses.push(format!("({})", clause));
+            params.extend(filter_params);
+        }
+        Ok((format!(" WHERE {}", clauses.join(" AND ")), params))
+    }
+}
+
+impl FlexibleDatabase {
+    // Run a query and return the matching models
+    pub fn find(&self, query: &Query) -> Result<Vec<Model>> {
+        Ok(self.find_page(query)?.models)
+    }
+
+    // Run a query, honouring its byte budget. When the budget cuts the
+    // results short, `next` holds the query for the remaining rows.
+    pub fn find_page(&self, query: &Query) -> Result<QueryPage> {
+        let schema = self.schema_or_err(&query.schema)?;
+        let projected = match query.kept_fields() {
+            Some(kept) => Some(schema.projected(&kept)?),
+            None => None,
+        };
+        let columns = match &projected {
+            Some(projected) if self.access_policy.is_none() => projected,
+            _ => schema,
+        };
+        let (sql, params) = query.to_sql(schema, columns)?;
+
+        // Cached reads hold every row, which a byte budget is there to avoid
+        if self.read_cache.is_some() && !query.random && query.max_result_bytes.is_none() {
+            let rows = self.cached_rows(&query.schema, &sql, &params, || {
+                self.traced(&sql, params.len(), || {
+                    let mut stmt = self.conn.prepare(&sql)?;
+                    let rows = stmt
+                        .query_map(rusqlite::params_from_iter(&params), |row| Ok(read_model(row, columns)))?
+                        .collect::<rusqlite::Result<Result<Vec<_>>>>()??;
+                    let count = rows.len();
+                    Ok((rows, count))
+                })
+            })?;
+            let mut page = self.page_from(query, projected.as_ref(), rows.into_iter().map(Ok))?;
+            self.include_related(schema, &mut page.models, &query.includes)?;
+            return Ok(page);
+        }
+
+        let mut page = self.traced(&sql, params.len(), || {
+            let mut stmt = self.conn.prepare(&sql)?;
+            let mut rows = stmt.query(rusqlite::params_from_iter(&params))?;
+            let rows = std::iter::from_fn(|| match rows.next() {
+                Ok(Some(row)) => Some(read_model(row, columns)),
+                Ok(None) => None,
+                Err(err) => Some(Err(err.into())),
+            });
+            let page = self.page_from(query, projected.as_ref(), rows)?;
+            let count = page.models.len();
+            Ok((page, count))
+        })?;
+        self.include_related(schema, &mut page.models, &query.includes)?;
+        Ok(page)
+    }
+
+    // Up to `n` models picked at random. The access policy applies after
+    // the pick, so fewer may come back when it hides some.
+    pub fn sample(&self, schema_name: &str, n: usize) -> Result<Vec<Model>> {
+        self.find(&Query::new(schema_name).random_order().limit(n))
+    }
+
+    // Run a grouped query: one row per group, holding the grouped fields and
+    // the aggregates by name. Without `group_by` the aggregates are computed
+    // over every matching model, giving a single row, and with no
+    // aggregates either that row is the number of models, as "count". The
+    // limit and offset count groups. Like `count`, this reads the table
+    // directly, so the access policy doesn't apply.
+    pub fn aggregate(&self, query: &Query) -> Result<Vec<HashMap<String, Value>>> {
+        let schema = self.schema_or_err(&query.schema)?;
+        let (sql, params) = query.aggregate_sql(schema)?;
+        let mut names: Vec<&str> = query.group_by.iter().map(String::as_str).collect();
+        names.extend(query.aggregates.iter().map(|(name, _)| name.as_str()));
+        if names.is_empty() {
+            names.push("count");
+        }
+
+        self.traced(&sql, params.len(), || {
+            let mut stmt = self.prepare_cached(&sql)?;
+            let rows = stmt
+                .query_map(rusqlite::params_from_iter(&params), |row| {
+                    let mut values = HashMap::new();
+                    for (index, name) in names.iter().enumerate() {
+                        values.insert(name.to_string(), row.get::<_, Value>(index)?);
+                    }
+                    Ok(values)
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let count = rows.len();
+            Ok((rows, count))
+        })
+    }
+
+    // The different values `field_name` holds, in ascending order, among
+    // the models matching the conditions of `filter` if given. Like
+    // `aggregate`, this doesn't apply the access policy.
+    pub fn distinct_values(&self, schema_name: &str, field_name: &str, filter: Option<&Query>) -> Result<Vec<Value>> {
+        let schema = self.schema_or_err(schema_name)?;
+        check_field(schema, field_name)?;
+        let (where_sql, params) = match filter {
+            Some(query) if query.schema != schema_name => {
+                return Err(KooError::InvalidData(format!(
+                    "filter is a query on '{}', not '{}'",
+                    query.schema, schema_name
+                )));
+            }
+            Some(query) => query.where_sql(schema)?,
+            None => (String::new(), Vec::new()),
+        };
+        let sql = format!(
+            "SELECT DISTINCT {0} FROM {1}{2} ORDER BY {0}",
+            field_name, schema.name, where_sql
+        );
+
+        self.traced(&sql, params.len(), || {
+            let mut stmt = self.prepare_cached(&sql)?;
+            let values = stmt
+                .query_map(rusqlite::params_from_iter(&params), |row| row.get::<_, Value>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let count = values.len();
+            Ok((values, count))
+        })
+    }
+
+    // Page of the models among `rows` that the caller may see, narrowed to
+    // the fields of `projected` if given
+    fn page_from(
+        &self,
+        query: &Query,
+        projected: Option<&Schema>,
+        rows: impl Iterator<Item = Result<Model>>,
+    ) -> Result<QueryPage> {
+        let schema = self.schema_or_err(&query.schema)?;
+        let mut models = Vec::new();
+        let mut used_bytes = 0;
+        // Rows read so far, counting those the access policy hides
+        let mut scanned = 0;
+        for model in rows {
+            let mut model = model?;
+            if !self.readable(&query.schema, &model) {
+                scanned += 1;
+                continue;
+            }
+            if let Some(projected) = projected {
+                model
+                    .data
+                    .retain(|field_name, _| projected.fields.contains_key(field_name) || field_name == VERSION_COLUMN);
+            }
+
+            if let Some(budget) = query.max_result_bytes {
+                let size = encoded_size(&model);
+                if !models.is_empty() && used_bytes + size > budget {
+                    let mut next = query.clone();
+                    next.offset += scanned;
+                    next.limit = query.limit.map(|limit| limit - scanned);
+                    let cursor = models.last().and_then(|model| query.cursor(schema, model));
+                    return Ok(QueryPage {
+                        models,
+                        next: Some(next),
+                        cursor,
+                    });
+                }
+                used_bytes += size;
+            }
+            scanned += 1;
+
+            self.track_model(&query.schema, &mut model);
+            models.push(model);
+        }
+        let cursor = models.last().and_then(|model| query.cursor(schema, model));
+        Ok(QueryPage {
+            models,
+            next: None,
+            cursor,
+        })
+    }
+}
+
+// Condition matching the rows that sort after `cursor` in `columns`: those
+// past it in the first column, or level with it there and past it in the
+// next, and so on. NULLs sort first, as SQLite sorts them.
+fn keyset_sql(columns: &[(String, Direction)], cursor: &str) -> Result<(String, Vec<Value>)> {
+    let invalid = || KooError::InvalidData(format!("'{}' is not a cursor", cursor));
+    let json: serde_json::Value = from_hex(cursor)
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .ok_or_else(invalid)?;
+    let order: Vec<(String, Direction)> = json
+        .get("order")
+        .and_then(|order| order.as_array())
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|column| {
+            match (
+                column.get(0).and_then(|f| f.as_str()),
+                column.get(1).and_then(|d| d.as_str()),
+            ) {
+                (Some(field_name), Some("asc")) => Ok((field_name.to_string(), Direction::Asc)),
+                (Some(field_name), Some("desc")) => Ok((field_name.to_string(), Direction::Desc)),
+                _ => Err(invalid()),
+            }
+        })
+        .collect::<Result<_>>()?;
+    let values: Vec<Value> = json
+        .get("values")
+        .and_then(|values| values.as_array())
+        .ok_or_else(invalid)?
+        .iter()
+        .map(value_from_tagged_json)
+        .collect::<Result<_>>()?;
+    if order != columns || values.len() != columns.len() {
+        return Err(KooError::InvalidData(
+            "the cursor comes from a query sorted differently".to_string(),
+        ));
+    }
+
+    let mut alternatives = Vec::new();
+    let mut params = Vec::new();
+    for (index, ((field_name, direction), value)) in columns.iter().zip(&values).enumerate() {
+        let mut clauses = Vec::new();
+        for ((level_field, _), level_value) in columns[..index].iter().zip(&values) {
+            clauses.push(format!("{} IS ?", level_field));
+            params.push(level_value.clone());
+        }
+        let past = match (direction, value) {
+            (Direction::Asc, Value::Null) => format!("{} IS NOT NULL", field_name),
+            (Direction::Desc, Value::Null) => "0".to_string(),
+            (Direction::Asc, _) => format!("{} > ?", field_name),
+            (Direction::Desc, _) => format!("({0} < ? OR {0} IS NULL)", field_name),
+        };
+        if *value != Value::Null {
+            params.push(value.clone());
+        }
+        clauses.push(past);
+        alternatives.push(format!("({})", clauses.join(" AND ")));
+    }
+    Ok((alternatives.join(" OR "), params))
+}
+
+pub(crate) fn check_field(schema: &Schema, field_name: &str) -> Result<()> {
+    if schema.encrypted_fields.iter().any(|f| f == field_name) {
+        return Err(KooError::InvalidData(format!(
+            "'{}.{}' is encrypted, so it can't be filtered or sorted on",
+            schema.name, field_name
+        )));
+    }
+    if (field_name == "id" && schema.key.has_id_column()) || schema.fields.contains_key(field_name) {
+        Ok(())
+    } else {
+        Err(KooError::UnknownField {
+            schema: schema.name.clone(),
+            field: field_name.to_string(),
+        })
+    }
+}
+
+// Rough size of a model once serialized: field names plus value payloads
+fn encoded_size(model: &Model) -> usize {
+    let mut size = std::mem::size_of::<i64>();
+    for (field_name, value) in &model.data {
+        size += field_name.len();
+        size += match value {
+            Value::Null => 1,
+            Value::Integer(_) | Value::Real(_) => 8,
+            Value::Text(s) => s.len(),
+            Value::Blob(b) => b.len(),
+        };
+    }
+    size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    use crate::access::{AccessPolicy, CallerContext};
+    use crate::flexible_database::{FieldDef, FieldType, MAX_BOUND_PARAMETERS};
+
+    // "notes" holding one model per text, ranked in order
+    fn notes(texts: &[&str]) -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("notes", [])
+            .field("body", FieldDef::new(FieldType::Text))
+            .field("rank", FieldDef::new(FieldType::Integer));
+        db.define_schema(schema).unwrap();
+        for (rank, text) in texts.iter().enumerate() {
+            let data = HashMap::from([
+                ("body".to_string(), Value::Text(text.to_string())),
+                ("rank".to_string(), Value::Integer(rank as i64)),
+            ]);
+            db.create_model("notes", data).unwrap();
+        }
+        db
+    }
+
+    fn ranks(models: &[Model]) -> Vec<i64> {
+        models
+            .iter()
+            .map(|model| match model.data["rank"] {
+                Value::Integer(rank) => rank,
+                ref other => panic!("rank is {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn byte_budgets_cut_pages_short_and_continue_after_them() {
+        let body = "x".repeat(100);
+        let db = notes(&[body.as_str(); 5]);
+        let query = Query::new("notes").max_result_bytes(250);
+
+        let page = db.find_page(&query).unwrap();
+        assert_eq!(ranks(&page.models), [0, 1]);
+        let next = page.next.unwrap();
+        assert_eq!(next.offset, 2);
+
+        let page = db.find_page(&next).unwrap();
+        assert_eq!(ranks(&page.models), [2, 3]);
+        let page = db.find_page(&page.next.unwrap()).unwrap();
+        assert_eq!(ranks(&page.models), [4]);
+        assert!(page.next.is_none());
+    }
+
+    #[test]
+    fn byte_budgets_always_return_a_model() {
+        let db = notes(&["long enough to blow the budget", "and another"]);
+        let page = db.find_page(&Query::new("notes").max_result_bytes(1)).unwrap();
+        assert_eq!(ranks(&page.models), [0]);
+        let page = db.find_page(&page.next.unwrap()).unwrap();
+        assert_eq!(ranks(&page.models), [1]);
+        assert!(page.next.is_none());
+    }
+
+    #[test]
+    fn byte_budgeted_continuations_keep_what_is_left_of_the_limit() {
+        let body = "x".repeat(100);
+        let db = notes(&[body.as_str(); 5]);
+        let page = db
+            .find_page(&Query::new("notes").limit(3).max_result_bytes(250))
+            .unwrap();
+        assert_eq!(ranks(&page.models), [0, 1]);
+        let next = page.next.unwrap();
+        assert_eq!(next.limit, Some(1));
+        let page = db.find_page(&next).unwrap();
+        assert_eq!(ranks(&page.models), [2]);
+        assert!(page.next.is_none());
+    }
+
+    #[test]
+    fn queries_within_their_budget_are_not_continued() {
+        let db = notes(&["a", "b", "c"]);
+        let page = db.find_page(&Query::new("notes").max_result_bytes(10_000)).unwrap();
+        assert_eq!(ranks(&page.models), [0, 1, 2]);
+        assert!(page.next.is_none());
+        assert_eq!(db.find(&Query::new("notes").max_result_bytes(1)).unwrap().len(), 1);
+    }
+
+    // Five sales over three regions; the fourth has no amount
+    fn sales() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("sales", [])
+            .field("region", FieldDef::new(FieldType::Text))
+            .field("item", FieldDef::new(FieldType::Text))
+            .field("amount", FieldDef::new(FieldType::Integer).nullable());
+        db.define_schema(schema).unwrap();
+        let rows = [
+            ("north", "pen", Value::Integer(5)),
+            ("north", "ink", Value::Integer(7)),
+            ("south", "pen", Value::Integer(3)),
+            ("south", "pen", Value::Null),
+            ("east", "cap", Value::Integer(10)),
+        ];
+        for (region, item, amount) in rows {
+            let data = HashMap::from([
+                ("region".to_string(), Value::Text(region.to_string())),
+                ("item".to_string(), Value::Text(item.to_string())),
+                ("amount".to_string(), amount),
+            ]);
+            db.create_model("sales", data).unwrap();
+        }
+        db
+    }
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.to_string())
+    }
+
+    #[test]
+    fn groups_carry_their_aggregates() {
+        let db = sales();
+        let query = Query::new("sales")
+            .group_by("region")
+            .aggregate("sales", Aggregate::Count)
+            .aggregate("total", Aggregate::Sum("amount".to_string()))
+            .aggregate("average", Aggregate::Avg("amount".to_string()))
+            .aggregate("least", Aggregate::Min("amount".to_string()))
+            .aggregate("most", Aggregate::Max("amount".to_string()));
+        let rows = db.aggregate(&query).unwrap();
+
+        let regions: Vec<&Value> = rows.iter().map(|row| &row["region"]).collect();
+        assert_eq!(regions, [&text("east"), &text("north"), &text("south")]);
+        assert_eq!(rows[1]["sales"], Value::Integer(2));
+        assert_eq!(rows[1]["total"], Value::Integer(12));
+        assert_eq!(rows[1]["average"], Value::Real(6.0));
+        assert_eq!(
+            (&rows[1]["least"], &rows[1]["most"]),
+            (&Value::Integer(5), &Value::Integer(7))
+        );
+        // NULL amounts count as models but not as amounts
+        assert_eq!(rows[2]["sales"], Value::Integer(2));
+        assert_eq!(rows[2]["average"], Value::Real(3.0));
+    }
+
+    #[test]
+    fn groups_can_be_filtered_ordered_and_paged() {
+        let db = sales();
+        let query = Query::new("sales")
+            .filter("item", Op::Ne, text("cap"))
+            .group_by("region")
+            .group_by("item")
+            .aggregate("total", Aggregate::Sum("amount".to_string()))
+            .order_by("total", Direction::Desc)
+            .limit(2);
+        let rows = db.aggregate(&query).unwrap();
+        let groups: Vec<(&Value, &Value)> = rows.iter().map(|row| (&row["item"], &row["total"])).collect();
+        assert_eq!(
+            groups,
+            [(&text("ink"), &Value::Integer(7)), (&text("pen"), &Value::Integer(5))]
+        );
+
+        let rows = db.aggregate(&query.offset(2)).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["region"], text("south"));
+    }
+
+    #[test]
+    fn ungrouped_aggregates_give_one_row() {
+        let db = sales();
+        let rows = db
+            .aggregate(&Query::new("sales").filter("region", Op::Eq, text("north")))
+            .unwrap();
+        assert_eq!(rows, [HashMap::from([("count".to_string(), Value::Integer(2))])]);
+
+        let query = Query::new("sales").aggregate("total", Aggregate::Sum("amount".to_string()));
+        assert_eq!(db.aggregate(&query).unwrap()[0]["total"], Value::Integer(25));
+    }
+
+    #[test]
+    fn grouped_queries_are_checked() {
+        let db = sales();
+        let grouped = Query::new("sales").group_by("region");
+        assert!(matches!(db.find(&grouped), Err(KooError::InvalidData(_))));
+        assert!(matches!(
+            db.aggregate(&grouped.clone().order_by("item", Direction::Asc)),
+            Err(KooError::InvalidData(_))
+        ));
+        assert!(matches!(
+            db.aggregate(&grouped.aggregate("x", Aggregate::Max("price".to_string()))),
+            Err(KooError::UnknownField { .. })
+        ));
+    }
+
+    #[test]
+    fn distinct_values_come_sorted() {
+        let db = sales();
+        assert_eq!(
+            db.distinct_values("sales", "region", None).unwrap(),
+            [text("east"), text("north"), text("south")]
+        );
+        assert_eq!(
+            db.distinct_values("sales", "amount", None).unwrap(),
+            [
+                Value::Null,
+                Value::Integer(3),
+                Value::Integer(5),
+                Value::Integer(7),
+                Value::Integer(10)
+            ]
+        );
+
+        let pens = Query::new("sales").filter("item", Op::Eq, text("pen"));
+        assert_eq!(
+            db.distinct_values("sales", "region", Some(&pens)).unwrap(),
+            [text("north"), text("south")]
+        );
+    }
+
+    #[test]
+    fn distinct_values_are_checked() {
+        let db = sales();
+        assert!(matches!(
+            db.distinct_values("sales", "price", None),
+            Err(KooError::UnknownField { .. })
+        ));
+        let other = Query::new("notes");
+        assert!(matches!(
+            db.distinct_values("sales", "region", Some(&other)),
+            Err(KooError::InvalidData(_))
+        ));
+    }
+
+    #[test]
+    fn text_filters_match_literally() {
+        let db = notes(&["Report.txt", "report_final", "50% off", "a*b?", "draft [v2]"]);
+        let matching = |op: Op, value: &str| {
+            let query = Query::new("notes").filter("body", op, text(value));
+            ranks(&db.find(&query).unwrap())
+        };
+
+        assert_eq!(matching(Op::StartsWith, "Report"), [0]);
+        assert_eq!(matching(Op::EndsWith, ".txt"), [0]);
+        assert_eq!(matching(Op::Contains, "port"), [0, 1]);
+        // Wildcards in the value are plain characters
+        assert_eq!(matching(Op::Contains, "*"), [3]);
+        assert_eq!(matching(Op::EndsWith, "?"), [3]);
+        assert_eq!(matching(Op::Contains, "[v2]"), [4]);
+        assert!(matching(Op::StartsWith, "r?port").is_empty());
+    }
+
+    #[test]
+    fn text_filters_can_ignore_case() {
+        let db = notes(&["Report.txt", "report_final", "50% off", "REPORTS"]);
+        let matching = |op: Op, value: &str| {
+            let query = Query::new("notes").filter("body", op, text(value));
+            ranks(&db.find(&query).unwrap())
+        };
+
+        assert_eq!(matching(Op::StartsWithIgnoreCase, "report"), [0, 1, 3]);
+        assert_eq!(matching(Op::EndsWithIgnoreCase, "S"), [3]);
+        assert_eq!(matching(Op::ContainsIgnoreCase, "T_F"), [1]);
+        assert_eq!(matching(Op::ContainsIgnoreCase, "0%"), [2]);
+        assert!(matching(Op::ContainsIgnoreCase, "t%f").is_empty());
+    }
+
+    #[test]
+    fn text_filters_need_text() {
+        let db = notes(&["a"]);
+        let query = Query::new("notes").filter("rank", Op::StartsWith, 1);
+        assert!(matches!(db.find(&query), Err(KooError::InvalidData(_))));
+    }
+
+    #[test]
+    fn list_filters_match_any_or_none_of_the_values() {
+        let db = notes(&["a", "b", "c", "d"]);
+        let query = Query::new("notes").filter_in("body", [text("b"), text("d"), text("z")]);
+        assert_eq!(ranks(&db.find(&query).unwrap()), [1, 3]);
+        let query = Query::new("notes").filter_not_in("rank", [0, 3]);
+        assert_eq!(ranks(&db.find(&query).unwrap()), [1, 2]);
+
+        let none: [i64; 0] = [];
+        assert!(
+            db.find(&Query::new("notes").filter_in("rank", none))
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            db.find(&Query::new("notes").filter_not_in("rank", none)).unwrap().len(),
+            4
+        );
+    }
+
+    #[test]
+    fn long_lists_are_bound_as_one_parameter() {
+        let db = notes(&["a"; 5]);
+        let values: Vec<i64> = (1..=MAX_BOUND_PARAMETERS as i64 + 1).map(|n| n * 2).collect();
+        assert_eq!(
+            ranks(&db.find(&Query::new("notes").filter_in("rank", values.clone())).unwrap()),
+            [2, 4]
+        );
+        assert_eq!(
+            ranks(&db.find(&Query::new("notes").filter_not_in("rank", values)).unwrap()),
+            [0, 1, 3]
+        );
+
+        let blobs = vec![Value::Blob(vec![0]); MAX_LISTED_VALUES + 1];
+        let query = Query::new("notes").filter_in("body", blobs);
+        assert!(matches!(db.find(&query), Err(KooError::InvalidData(_))));
+    }
+
+    #[test]
+    fn compound_filters_nest() {
+        let db = notes(&["a", "b", "c", "d", "e"]);
+        // (rank < 2 or body = "e") and not body = "a"
+        let filter = Filter::and(vec![
+            Filter::or(vec![
+                Filter::condition("rank", Op::Lt, 2),
+                Filter::condition("body", Op::Eq, text("e")),
+            ]),
+            Filter::not(Filter::condition("body", Op::Eq, text("a"))),
+        ]);
+        assert_eq!(ranks(&db.find(&Query::new("notes").filter_by(filter)).unwrap()), [1, 4]);
+
+        // Alongside plain filters, which must hold too
+        let query = Query::new("notes")
+            .filter("rank", Op::Gt, 0)
+            .filter_by(Filter::or(vec![Filter::is_in("body", [text("a"), text("c")])]))
+            .filter_by(Filter::not(Filter::not_in("rank", [2, 3])));
+        assert_eq!(ranks(&db.find(&query).unwrap()), [2]);
+    }
+
+    #[test]
+    fn empty_groups_hold_or_fail_as_ever() {
+        let db = notes(&["a", "b"]);
+        let all = Query::new("notes").filter_by(Filter::and(vec![]));
+        assert_eq!(ranks(&db.find(&all).unwrap()), [0, 1]);
+        let none = Query::new("notes").filter_by(Filter::or(vec![]));
+        assert!(db.find(&none).unwrap().is_empty());
+    }
+
+    #[test]
+    fn compound_filters_check_every_field() {
+        let db = notes(&["a"]);
+        let filter = Filter::or(vec![
+            Filter::condition("rank", Op::Eq, 0),
+            Filter::not(Filter::condition("title", Op::Eq, text("a"))),
+        ]);
+        assert!(matches!(
+            db.find(&Query::new("notes").filter_by(filter)),
+            Err(KooError::UnknownField { .. })
+        ));
+    }
+
+    fn ids(models: &[Model]) -> Vec<i64> {
+        models
+            .iter()
+            .map(|model| model.id.as_ref().unwrap().as_i64().unwrap())
+            .collect()
+    }
+
+    // Ids of every page of `query`, each continued after the last's cursor
+    fn pages(db: &FlexibleDatabase, query: &Query) -> Vec<Vec<i64>> {
+        let mut pages = Vec::new();
+        let mut page = db.find_page(query).unwrap();
+        while !page.models.is_empty() {
+            pages.push(ids(&page.models));
+            let cursor = page.cursor.unwrap();
+            page = db.find_page(&query.clone().after(&cursor)).unwrap();
+        }
+        assert!(page.cursor.is_none());
+        pages
+    }
+
+    #[test]
+    fn cursors_walk_every_page() {
+        let db = sales();
+        let by_amount = Query::new("sales").order_by("amount", Direction::Desc).limit(2);
+        assert_eq!(pages(&db, &by_amount), [vec![5, 2], vec![1, 3], vec![4]]);
+        // NULLs sort first going up, as SQLite has them
+        let up = Query::new("sales").order_by("amount", Direction::Asc).limit(2);
+        assert_eq!(pages(&db, &up), [vec![4, 3], vec![1, 2], vec![5]]);
+        // Ties are broken by the key
+        let by_region = Query::new("sales").order_by("region", Direction::Asc).limit(2);
+        assert_eq!(pages(&db, &by_region), [vec![5, 1], vec![2, 3], vec![4]]);
+    }
+
+    #[test]
+    fn writes_between_pages_dont_shift_them() {
+        let db = sales();
+        let query = Query::new("sales").order_by("amount", Direction::Asc).limit(2);
+        let first = db.find_page(&query).unwrap();
+        assert_eq!(ids(&first.models), [4, 3]);
+
+        let data = HashMap::from([
+            ("region".to_string(), text("west")),
+            ("item".to_string(), text("pen")),
+            ("amount".to_string(), Value::Integer(1)),
+        ]);
+        db.create_model("sales", data).unwrap();
+        db.delete_model("sales", 3).unwrap();
+        let next = db.find(&query.after(&first.cursor.unwrap())).unwrap();
+        assert_eq!(ids(&next), [1, 2]);
+    }
+
+    #[test]
+    fn cursors_only_continue_queries_sorted_alike() {
+        let db = sales();
+        let cursor = db
+            .find_page(&Query::new("sales").order_by("amount", Direction::Desc).limit(1))
+            .unwrap()
+            .cursor
+            .unwrap();
+        for query in [
+            Query::new("sales").after(&cursor),
+            Query::new("sales").order_by("amount", Direction::Asc).after(&cursor),
+            Query::new("sales").after("not a cursor"),
+            Query::new("sales").random_order().after(&cursor),
+            Query::new("sales").group_by("region").after(&cursor),
+        ] {
+            let result = match query.is_grouped() {
+                true => db.aggregate(&query).map(|_| ()),
+                false => db.find(&query).map(|_| ()),
+            };
+            assert!(matches!(result, Err(KooError::InvalidData(_))));
+        }
+    }
+
+    #[test]
+    fn samples_are_distinct_models_of_the_schema() {
+        let db = notes(&["a"; 20]);
+        let mut picked = ids(&db.sample("notes", 5).unwrap());
+        assert_eq!(picked.len(), 5);
+        picked.sort_unstable();
+        picked.dedup();
+        assert_eq!(picked.len(), 5);
+        assert!(picked.iter().all(|id| (1..=20).contains(id)));
+
+        assert_eq!(db.sample("notes", 50).unwrap().len(), 20);
+        assert!(db.sample("notes", 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn random_orders_shuffle_after_the_query_order() {
+        let db = sales();
+        let query = Query::new("sales").order_by("region", Direction::Asc).random_order();
+        // Each run may differ, but the regions always come in order
+        for _ in 0..10 {
+            let models = db.find(&query).unwrap();
+            let regions: Vec<&Value> = models.iter().map(|model| &model.data["region"]).collect();
+            assert_eq!(
+                regions,
+                [
+                    &text("east"),
+                    &text("north"),
+                    &text("north"),
+                    &text("south"),
+                    &text("south")
+                ]
+            );
+        }
+
+        let orders: HashSet<Vec<i64>> = (0..50)
+            .map(|_| ids(&db.find(&Query::new("sales").random_order()).unwrap()))
+            .collect();
+        assert!(orders.len() > 1);
+    }
+
+    #[test]
+    fn random_orders_bypass_the_read_cache() {
+        let mut db = notes(&["a"; 20]);
+        db.enable_read_cache(16, Duration::from_secs(60));
+        let query = Query::new("notes").random_order().limit(3);
+        let picks: HashSet<Vec<i64>> = (0..20).map(|_| ids(&db.find(&query).unwrap())).collect();
+        assert!(picks.len() > 1);
+        assert!(db.find_page(&query).unwrap().cursor.is_none());
+    }
+
+    #[test]
+    fn random_orders_cant_be_paged_by_size() {
+        let db = notes(&["a"; 20]);
+        let query = Query::new("notes").random_order().max_result_bytes(64);
+        assert!(matches!(db.find_page(&query), Err(KooError::InvalidData(_))));
+        assert!(matches!(db.find(&query), Err(KooError::InvalidData(_))));
+    }
+
+    fn fields(model: &Model) -> Vec<&str> {
+        let mut fields: Vec<&str> = model.data.keys().map(String::as_str).collect();
+        fields.sort_unstable();
+        fields
+    }
+
+    #[test]
+    fn selections_read_only_some_fields() {
+        let db = sales();
+        let query = Query::new("sales")
+            .select(&["item"])
+            .filter("region", Op::Eq, text("north"));
+        let models = db.find(&query).unwrap();
+        assert_eq!(ids(&models), [1, 2]);
+        assert!(models.iter().all(|model| fields(model) == ["item"]));
+
+        // Fields the query sorts by come along
+        let sorted = db
+            .find(
+                &Query::new("sales")
+                    .select(&["id"])
+                    .order_by("amount", Direction::Desc)
+                    .limit(1),
+            )
+            .unwrap();
+        assert_eq!(ids(&sorted), [5]);
+        assert_eq!(fields(&sorted[0]), ["amount"]);
+
+        assert!(matches!(
+            db.find(&Query::new("sales").select(&["price"])),
+            Err(KooError::UnknownField { .. })
+        ));
+    }
+
+    #[test]
+    fn selections_read_whole_rows_for_the_access_policy() {
+        // Hides the sales without an amount, so needs that field
+        struct PricedOnly;
+
+        impl AccessPolicy for PricedOnly {
+            fn can_read(&self, _schema: &str, model: &Model, _caller: &CallerContext) -> bool {
+                model.data["amount"] != Value::Null
+            }
+
+            fn can_write(&self, _schema: &str, _model: &Model, _caller: &CallerContext) -> bool {
+                true
+            }
+        }
+
+        let mut db = sales();
+        db.set_access_policy(PricedOnly);
+        let models = db.find(&Query::new("sales").select(&["item"])).unwrap();
+        assert_eq!(ids(&models), [1, 2, 3, 5]);
+        assert!(models.iter().all(|model| fields(model) == ["item"]));
+    }
+}
@@ -0,0 +1,403 @@
+use rusqlite::types::Value;
+use rusqlite::{OptionalExtension, params_from_iter};
+use std::time::Duration;
+
+use crate::error::Result;
+use crate::flexible_database::FlexibleDatabase;
+
+const SECONDS_PER_DAY: f64 = 86400.0;
+
+// A job to add with `Queue::enqueue`. The payload is opaque to the queue;
+// JSON is a good fit for anything with structure.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    payload: String,
+    max_attempts: u32,
+    delay: Duration,
+}
+
+impl Job {
+    pub fn new(payload: impl Into<String>) -> Job {
+        Job {
+            payload: payload.into(),
+            max_attempts: 5,
+            delay: Duration::ZERO,
+        }
+    }
+
+    // Lease the job at most this many times before it is dead-lettered
+    pub fn max_attempts(mut self, max_attempts: u32) -> Job {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    // Keep the job back from workers for a while after it is enqueued
+    pub fn delay(mut self, delay: Duration) -> Job {
+        self.delay = delay;
+        self
+    }
+}
+
+// A job handed to a worker by `dequeue_with_lease`, to be passed back to
+// `complete` or `fail`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeasedJob {
+    pub id: i64,
+    pub payload: String,
+    // 1 the first time the job is leased, 2 on its first retry, and so on
+    pub attempt: u32,
+    pub max_attempts: u32,
+    worker_id: String,
+}
+
+// A job that failed every attempt it was allowed
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeadJob {
+    pub id: i64,
+    pub payload: String,
+    pub attempts: u32,
+    // What the last `fail` reported, or "lease expired" when the worker
+    // never came back
+    pub last_error: Option<String>,
+}
+
+// A named queue of jobs in `_koo_queue`, from `FlexibleDatabase::queue`.
+// Each state change is a single SQL statement, so any number of workers,
+// in this process or others sharing the file, can take jobs from the same
+// queue without a job being leased to two of them at once.
+//
+// A leased job is hidden from other workers until its lease runs out.
+// Workers finish it with `complete`, or give it back with `fail`, which
+// makes it available again after a backoff that doubles with each attempt;
+// a job whose lease runs out without either is retried the same way. Once
+// a job has used its attempts it is dead-lettered, and stays in
+// `dead_letters` until retried or purged.
+pub struct Queue<'a> {
+    db: &'a FlexibleDatabase,
+    name: String,
+    lease: Duration,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl FlexibleDatabase {
+    pub fn queue(&self, name: &str) -> Result<Queue<'_>> {
+        self.conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS _koo_queue (
+                id INTEGER PRIMARY KEY,
+                queue TEXT NOT NULL,
+                payload TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                max_attempts INTEGER NOT NULL,
+                run_at REAL NOT NULL,
+                leased_by TEXT,
+                dead INTEGER NOT NULL DEFAULT 0,
+                last_error TEXT,
+                enqueued_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE INDEX IF NOT EXISTS _koo_queue_ready ON _koo_queue (queue, dead, run_at);",
+        )?;
+        Ok(Queue {
+            db: self,
+            name: name.to_string(),
+            lease: Duration::from_secs(30),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(3600),
+        })
+    }
+}
+
+impl Queue<'_> {
+    // How long a worker has a job for before it is offered to others
+    pub fn lease(mut self, lease: Duration) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    // Wait before the first retry of a failed job, doubling for later ones
+    // up to `max`
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    // Add a job, returning its id
+    pub fn enqueue(&self, job: Job) -> Result<i64> {
+        self.write(
+            "INSERT INTO _koo_queue (queue, payload, max_attempts, run_at) VALUES (?, ?, ?, julianday('now') + ?)",
+            vec![
+                Value::Text(self.name.clone()),
+                Value::Text(job.payload),
+                Value::Integer(job.max_attempts.into()),
+                Value::Real(days(job.delay)),
+            ],
+        )?;
+        Ok(self.db.conn.last_insert_rowid())
+    }
+
+    // Lease the job that has been ready longest to `worker_id`, or None
+    // when no job is ready
+    pub fn dequeue_with_lease(&self, worker_id: &str) -> Result<Option<LeasedJob>> {
+        // Jobs whose last lease ran out are dead, not ready
+        self.write(
+            "UPDATE _koo_queue SET dead = 1, last_error = 'lease expired'
+             WHERE queue = ? AND dead = 0 AND attempts >= max_attempts AND run_at <= julianday('now')",
+            vec![Value::Text(self.name.clone())],
+        )?;
+
+        let sql = "UPDATE _koo_queue SET leased_by = ?, attempts = attempts + 1, run_at = julianday('now') + ?
+             WHERE id = (
+                SELECT id FROM _koo_queue
+                WHERE queue = ? AND dead = 0 AND attempts < max_attempts AND run_at <= julianday('now')
+                ORDER BY run_at, id LIMIT 1
+             )
+             RETURNING id, payload, attempts, max_attempts";
+        let params = [
+            Value::Text(worker_id.to_string()),
+            Value::Real(days(self.lease)),
+            Value::Text(self.name.clone()),
+        ];
+        self.db.traced(sql, params.len(), || {
+            let job = self.db.retrying(|| {
+                Ok(self
+                    .db
+                    .prepare_cached(sql)?
+                    .query_row(params_from_iter(&params), |row| {
+                        Ok(LeasedJob {
+                            id: row.get(0)?,
+                            payload: row.get(1)?,
+                            attempt: row.get(2)?,
+                            max_attempts: row.get(3)?,
+                            worker_id: worker_id.to_string(),
+                        })
+                    })
+                    .optional()?)
+            })?;
+            let count = job.is_some() as usize;
+            Ok((job, count))
+        })
+    }
+
+    // Remove a finished job. False if the lease ran out and the job went to
+    // another worker, which will run it again.
+    pub fn complete(&self, job: &LeasedJob) -> Result<bool> {
+        let deleted = self.write(
+            "DELETE FROM _koo_queue WHERE id = ? AND leased_by = ?",
+            vec![Value::Integer(job.id), Value::Text(job.worker_id.clone())],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    // Give back a job that failed, to be retried after the backoff or
+    // dead-lettered if this was its last attempt. False if the job is no
+    // longer leased to the worker.
+    pub fn fail(&self, job: &LeasedJob, error: &str) -> Result<bool> {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(job.attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        let failed = self.write(
+            "UPDATE _koo_queue
+             SET leased_by = NULL, last_error = ?, dead = attempts >= max_attempts, run_at = julianday('now') + ?
+             WHERE id = ? AND leased_by = ? AND dead = 0",
+            vec![
+                Value::Text(error.to_string()),
+                Value::Real(days(backoff)),
+                Value::Integer(job.id),
+                Value::Text(job.worker_id.clone()),
+            ],
+        )?;
+        Ok(failed > 0)
+    }
+
+    // Renew the lease of a job that is taking long, from now. False if it
+    // is no longer leased to the worker.
+    pub fn extend_lease(&self, job: &LeasedJob) -> Result<bool> {
+        let extended = self.write(
+            "UPDATE _koo_queue SET run_at = julianday('now') + ? WHERE id = ? AND leased_by = ? AND dead = 0",
+            vec![
+                Value::Real(days(self.lease)),
+                Value::Integer(job.id),
+                Value::Text(job.worker_id.clone()),
+            ],
+        )?;
+        Ok(extended > 0)
+    }
+
+    // Jobs waiting or leased, not counting dead ones
+    pub fn pending(&self) -> Result<usize> {
+        let count: i64 = self.db.conn.query_row(
+            "SELECT COUNT(*) FROM _koo_queue WHERE queue = ? AND dead = 0",
+            [&self.name],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    // Dead-lettered jobs, oldest first
+    pub fn dead_letters(&self) -> Result<Vec<DeadJob>> {
+        let mut stmt = self.db.conn.prepare(
+            "SELECT id, payload, attempts, last_error FROM _koo_queue WHERE queue = ? AND dead = 1 ORDER BY id",
+        )?;
+        let jobs = stmt
+            .query_map([&self.name], |row| {
+                Ok(DeadJob {
+                    id: row.get(0)?,
+                    payload: row.get(1)?,
+                    attempts: row.get(2)?,
+                    last_error: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(jobs)
+    }
+
+    // Make a dead job ready again, with its attempts reset. False if there
+    // is no such dead job.
+    pub fn retry_dead(&self, id: i64) -> Result<bool> {
+        let retried = self.write(
+            "UPDATE _koo_queue SET dead = 0, attempts = 0, leased_by = NULL, run_at = julianday('now')
+             WHERE id = ? AND queue = ? AND dead = 1",
+            vec![Value::Integer(id), Value::Text(self.name.clone())],
+        )?;
+        Ok(retried > 0)
+    }
+
+    // Delete every dead job, returning how many there were
+    pub fn purge_dead(&self) -> Result<usize> {
+        self.write(
+            "DELETE FROM _koo_queue WHERE queue = ? AND dead = 1",
+            vec![Value::Text(self.name.clone())],
+        )
+    }
+
+    fn write(&self, sql: &str, params: Vec<Value>) -> Result<usize> {
+        self.db.traced(sql, params.len(), || {
+            let changed = self
+                .db
+                .retrying(|| Ok(self.db.prepare_cached(sql)?.execute(params_from_iter(&params))?))?;
+            Ok((changed, changed))
+        })
+    }
+}
+
+// A duration as a fraction of a day, to add to a julianday time
+fn days(duration: Duration) -> f64 {
+    duration.as_secs_f64() / SECONDS_PER_DAY
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn payloads(jobs: &[LeasedJob]) -> Vec<&str> {
+        jobs.iter().map(|job| job.payload.as_str()).collect()
+    }
+
+    #[test]
+    fn jobs_are_leased_oldest_first_and_once() {
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        let queue = db.queue("mail").unwrap();
+        for payload in ["a", "b", "c"] {
+            queue.enqueue(Job::new(payload)).unwrap();
+        }
+        queue.enqueue(Job::new("later").delay(HOUR)).unwrap();
+        db.queue("other").unwrap().enqueue(Job::new("elsewhere")).unwrap();
+
+        let mut leased = Vec::new();
+        while let Some(job) = queue.dequeue_with_lease("worker").unwrap() {
+            leased.push(job);
+        }
+        assert_eq!(payloads(&leased), ["a", "b", "c"]);
+        assert!(leased.iter().all(|job| job.attempt == 1 && job.max_attempts == 5));
+
+        assert_eq!(queue.pending().unwrap(), 4);
+        for job in &leased {
+            assert!(queue.complete(job).unwrap());
+        }
+        assert_eq!(queue.pending().unwrap(), 1);
+        assert!(!queue.complete(&leased[0]).unwrap());
+    }
+
+    #[test]
+    fn failed_jobs_come_back_after_the_backoff() {
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        let queue = db.queue("mail").unwrap().backoff(HOUR, HOUR);
+        queue.enqueue(Job::new("a")).unwrap();
+        let job = queue.dequeue_with_lease("worker").unwrap().unwrap();
+        assert!(queue.fail(&job, "smtp down").unwrap());
+        assert!(!queue.fail(&job, "again").unwrap());
+        assert_eq!(queue.dequeue_with_lease("worker").unwrap(), None);
+
+        let queue = db.queue("mail").unwrap().backoff(Duration::ZERO, Duration::ZERO);
+        queue.enqueue(Job::new("b")).unwrap();
+        let job = queue.dequeue_with_lease("worker").unwrap().unwrap();
+        queue.fail(&job, "smtp down").unwrap();
+        let retry = queue.dequeue_with_lease("worker").unwrap().unwrap();
+        assert_eq!((retry.id, retry.attempt), (job.id, 2));
+    }
+
+    #[test]
+    fn jobs_out_of_attempts_are_dead_lettered() {
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        let queue = db.queue("mail").unwrap().backoff(Duration::ZERO, Duration::ZERO);
+        let id = queue.enqueue(Job::new("a").max_attempts(2)).unwrap();
+        for error in ["first", "second"] {
+            let job = queue.dequeue_with_lease("worker").unwrap().unwrap();
+            queue.fail(&job, error).unwrap();
+        }
+        assert_eq!(queue.dequeue_with_lease("worker").unwrap(), None);
+        assert_eq!(queue.pending().unwrap(), 0);
+        let dead = DeadJob {
+            id,
+            payload: "a".to_string(),
+            attempts: 2,
+            last_error: Some("second".to_string()),
+        };
+        assert_eq!(queue.dead_letters().unwrap(), [dead]);
+        assert!(db.queue("other").unwrap().dead_letters().unwrap().is_empty());
+
+        assert!(queue.retry_dead(id).unwrap());
+        assert!(!queue.retry_dead(id).unwrap());
+        let job = queue.dequeue_with_lease("worker").unwrap().unwrap();
+        assert_eq!(job.attempt, 1);
+        queue.fail(&job, "third").unwrap();
+        queue
+            .fail(&queue.dequeue_with_lease("worker").unwrap().unwrap(), "fourth")
+            .unwrap();
+        assert_eq!(queue.purge_dead().unwrap(), 1);
+        assert!(queue.dead_letters().unwrap().is_empty());
+    }
+
+    #[test]
+    fn expired_leases_go_to_other_workers() {
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        let queue = db.queue("mail").unwrap().lease(Duration::ZERO);
+        queue.enqueue(Job::new("a").max_attempts(2)).unwrap();
+
+        let first = queue.dequeue_with_lease("one").unwrap().unwrap();
+        let second = queue.dequeue_with_lease("two").unwrap().unwrap();
+        assert_eq!((second.id, second.attempt), (first.id, 2));
+        assert!(!queue.extend_lease(&first).unwrap());
+        assert!(!queue.complete(&first).unwrap());
+
+        // The last lease running out dead-letters the job
+        assert_eq!(queue.dequeue_with_lease("three").unwrap(), None);
+        let dead = queue.dead_letters().unwrap();
+        assert_eq!(dead[0].last_error.as_deref(), Some("lease expired"));
+    }
+
+    #[test]
+    fn extended_leases_keep_the_job() {
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        let queue = db.queue("mail").unwrap().lease(HOUR);
+        queue.enqueue(Job::new("a")).unwrap();
+        let job = queue.dequeue_with_lease("one").unwrap().unwrap();
+        assert!(queue.extend_lease(&job).unwrap());
+        assert_eq!(queue.dequeue_with_lease("two").unwrap(), None);
+        assert!(queue.complete(&job).unwrap());
+    }
+}
@@ -0,0 +1,134 @@
+use rusqlite::types::Value;
+use std::collections::HashMap;
+
+use crate::error::Result;
+use crate::flexible_database::FlexibleDatabase;
+
+impl FlexibleDatabase {
+    // Run a custom SELECT and return each row keyed by column name. Values
+    // come back as SQLite stored them, since there's no schema to say
+    // otherwise; booleans are integers. Raw statements bypass the statement
+    // cache so one-off queries don't evict the CRUD ones.
+    pub fn query_raw(&self, sql: &str, params: &[Value]) -> Result<Vec<HashMap<String, Value>>> {
+        self.traced(sql, params.len(), || {
+            let mut stmt = self.conn.prepare(sql)?;
+            let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+            let writes = !stmt.readonly();
+
+            let results = read_rows(&mut stmt, &columns, params);
+            if writes {
+                self.clear_read_cache();
+            }
+            let results = results?;
+            let count = results.len();
+            Ok((results, count))
+        })
+    }
+
+    // Run a custom statement that doesn't return rows, such as an UPDATE
+    // spanning many models, and return the number of rows changed. The
+    // schema registry isn't consulted, so DDL run this way isn't reflected
+    // in it.
+    pub fn execute_raw(&self, sql: &str, params: &[Value]) -> Result<usize> {
+        self.traced(sql, params.len(), || {
+            let mut stmt = self.conn.prepare(sql)?;
+            let changed = stmt.execute(rusqlite::params_from_iter(params));
+            // Not every write reaches the update hook, e.g. a DELETE without
+            // a WHERE clause truncates the table, so drop every cached read
+            if !stmt.readonly() {
+                self.clear_read_cache();
+            }
+            let changed = changed?;
+            Ok((changed, changed))
+        })
+    }
+}
+
+fn read_rows(
+    stmt: &mut rusqlite::Statement<'_>,
+    columns: &[String],
+    params: &[Value],
+) -> Result<Vec<HashMap<String, Value>>> {
+    let mut rows = stmt.query(rusqlite::params_from_iter(params))?;
+    let mut results = Vec::new();
+    while let Some(row) = rows.next()? {
+        let mut entry = HashMap::new();
+        for (i, column) in columns.iter().enumerate() {
+            entry.insert(column.clone(), row.get::<_, Value>(i)?);
+        }
+        results.push(entry);
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flexible_database::{FieldType, Schema};
+
+    fn scores() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new(
+            "scores",
+            HashMap::from([
+                ("name".to_string(), FieldType::Text),
+                ("points".to_string(), FieldType::Integer),
+            ]),
+        ))
+        .unwrap();
+        for (name, points) in [("ada", 3), ("bob", 5), ("cy", 8)] {
+            let sql = "INSERT INTO scores (name, points) VALUES (?, ?)";
+            db.execute_raw(sql, &[Value::Text(name.to_string()), Value::Integer(points)])
+                .unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn raw_queries_return_rows_by_column_name() {
+        let db = scores();
+        let rows = db
+            .query_raw(
+                "SELECT name, points * 2 AS doubled FROM scores WHERE points > ? ORDER BY name",
+                &[Value::Integer(4)],
+            )
+            .unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["name"], Value::Text("bob".to_string()));
+        assert_eq!(rows[0]["doubled"], Value::Integer(10));
+        assert_eq!(rows[1]["name"], Value::Text("cy".to_string()));
+    }
+
+    #[test]
+    fn raw_statements_report_the_rows_they_change() {
+        let db = scores();
+        let changed = db
+            .execute_raw(
+                "UPDATE scores SET points = points + 1 WHERE points < ?",
+                &[Value::Integer(6)],
+            )
+            .unwrap();
+        assert_eq!(changed, 2);
+        assert_eq!(db.execute_raw("DELETE FROM scores", &[]).unwrap(), 3);
+        assert_eq!(db.count("scores").unwrap(), 0);
+    }
+
+    #[test]
+    fn raw_writes_drop_cached_reads() {
+        let mut db = scores();
+        db.enable_read_cache(16, std::time::Duration::from_secs(60));
+        assert_eq!(db.get_all_models("scores").unwrap().len(), 3);
+        db.query_raw("DELETE FROM scores WHERE name = 'ada' RETURNING id", &[])
+            .unwrap();
+        assert_eq!(db.get_all_models("scores").unwrap().len(), 2);
+        db.execute_raw("DELETE FROM scores", &[]).unwrap();
+        assert!(db.get_all_models("scores").unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_sql_is_an_error() {
+        let db = scores();
+        assert!(db.query_raw("SELECT * FROM nowhere", &[]).is_err());
+        assert!(db.execute_raw("UPDATE scores SET", &[]).is_err());
+    }
+}
@@ -0,0 +1,283 @@
+use indexmap::IndexMap;
+use rusqlite::types::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::Result;
+use crate::flexible_database::{FlexibleDatabase, Model, ModelId};
+
+// Hit/miss counters for the read cache
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    // Reads currently cached, including ones a write has made stale
+    pub len: usize,
+    pub capacity: usize,
+}
+
+// Models as stored, before the access policy and telemetry are applied
+#[derive(Debug, Clone)]
+enum CachedRead {
+    Model(Option<Model>),
+    Rows(Vec<Model>),
+}
+
+#[derive(Debug)]
+struct Entry {
+    stored_at: Instant,
+    // Generation of the schema when the read was made
+    generation: u64,
+    read: CachedRead,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    // Oldest first, so the front is evicted
+    entries: IndexMap<(String, String), Entry>,
+    // Bumped on every write to a schema, making its cached reads stale
+    generations: HashMap<String, u64>,
+    hits: u64,
+    misses: u64,
+}
+
+// Shared with the connection's hooks, which report writes to it
+#[derive(Debug)]
+pub(crate) struct ReadCache {
+    max_entries: usize,
+    ttl: Duration,
+    state: Mutex<CacheState>,
+}
+
+impl ReadCache {
+    // A row of `table` in the database `db` was written
+    pub(crate) fn invalidate(&self, db: &str, table: &str) {
+        let schema_name = match db {
+            "main" => table.to_string(),
+            _ => format!("{}.{}", db, table),
+        };
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        *state.generations.entry(schema_name).or_default() += 1;
+    }
+
+    pub(crate) fn clear(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.entries.clear();
+    }
+
+    fn get(&self, key: &(String, String)) -> Option<CachedRead> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let generation = state.generations.get(&key.0).copied().unwrap_or(0);
+        let fresh = match state.entries.get(key) {
+            Some(entry) => entry.generation == generation && entry.stored_at.elapsed() < self.ttl,
+            None => false,
+        };
+        if fresh {
+            state.hits += 1;
+            return state.entries.get(key).map(|entry| entry.read.clone());
+        }
+        state.entries.shift_remove(key);
+        state.misses += 1;
+        None
+    }
+
+    fn generation(&self, schema_name: &str) -> u64 {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.generations.get(schema_name).copied().unwrap_or(0)
+    }
+
+    fn insert(&self, key: (String, String), generation: u64, read: CachedRead) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.entries.shift_remove(&key);
+        while state.entries.len() >= self.max_entries {
+            state.entries.shift_remove_index(0);
+        }
+        state.entries.insert(
+            key,
+            Entry {
+                stored_at: Instant::now(),
+                generation,
+                read,
+            },
+        );
+    }
+}
+
+// The read cache keeps the results of `get_model` and queries in memory for
+// up to `ttl`. Writes through this handle make the cached reads of the
+// schema they touch stale, as do schema changes and rolled back
+// transactions, and raw SQL that writes drops every cached read. Queries
+// in random order or with a byte budget aren't cached. Writes by other
+// connections aren't seen, so their changes show up once the TTL runs out.
+impl FlexibleDatabase {
+    pub fn enable_read_cache(&mut self, max_entries: usize, ttl: Duration) {
+        self.read_cache = Some(Arc::new(ReadCache {
+            max_entries,
+            ttl,
+            state: Mutex::new(CacheState::default()),
+        }));
+        self.install_hooks();
+    }
+
+    pub fn disable_read_cache(&mut self) {
+        self.read_cache = None;
+        self.install_hooks();
+    }
+
+    // None when the read cache isn't enabled
+    pub fn read_cache_stats(&self) -> Option<ReadCacheStats> {
+        self.read_cache.as_ref().map(|cache| {
+            let state = cache.state.lock().unwrap_or_else(|e| e.into_inner());
+            ReadCacheStats {
+                hits: state.hits,
+                misses: state.misses,
+                len: state.entries.len(),
+                capacity: cache.max_entries,
+            }
+        })
+    }
+
+    // Drop every cached read
+    pub fn clear_read_cache(&self) {
+        if let Some(cache) = &self.read_cache {
+            cache.clear();
+        }
+    }
+
+    // The stored model `id`, from the cache or else from `load`
+    pub(crate) fn cached_model(
+        &self,
+        schema_name: &str,
+        id: &ModelId,
+        load: impl FnOnce() -> Result<Option<Model>>,
+    ) -> Result<Option<Model>> {
+        let Some(cache) = &self.read_cache else {
+            return load();
+        };
+        let key = (schema_name.to_string(), format!("id {:?}", id));
+        if let Some(CachedRead::Model(model)) = cache.get(&key) {
+            return Ok(model);
+        }
+        let generation = cache.generation(schema_name);
+        let model = load()?;
+        cache.insert(key, generation, CachedRead::Model(model.clone()));
+        Ok(model)
+    }
+
+    // The rows `sql` returns, from the cache or else from `load`
+    pub(crate) fn cached_rows(
+        &self,
+        schema_name: &str,
+        sql: &str,
+        params: &[Value],
+        load: impl FnOnce() -> Result<Vec<Model>>,
+    ) -> Result<Vec<Model>> {
+        let Some(cache) = &self.read_cache else {
+            return load();
+        };
+        let key = (schema_name.to_string(), format!("{} {:?}", sql, params));
+        if let Some(CachedRead::Rows(rows)) = cache.get(&key) {
+            return Ok(rows);
+        }
+        let generation = cache.generation(schema_name);
+        let rows = load()?;
+        cache.insert(key, generation, CachedRead::Rows(rows.clone()));
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::types::Value;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use crate::flexible_database::{FieldType, FlexibleDatabase, Schema};
+    use crate::query::Query;
+
+    fn cached_db() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("t", [("n".to_string(), FieldType::Integer)])).unwrap();
+        for n in 0..3 {
+            db.create_model("t", HashMap::from([("n".to_string(), Value::Integer(n))])).unwrap();
+        }
+        db.enable_read_cache(16, Duration::from_secs(60));
+        db
+    }
+
+    #[test]
+    fn repeated_reads_are_hits() {
+        let db = cached_db();
+        db.get_model("t", 1).unwrap();
+        db.get_model("t", 1).unwrap();
+        db.find(&Query::new("t")).unwrap();
+        db.find(&Query::new("t")).unwrap();
+        let stats = db.read_cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.len), (2, 2, 2));
+    }
+
+    #[test]
+    fn writes_make_cached_reads_stale() {
+        let db = cached_db();
+        assert_eq!(db.find(&Query::new("t")).unwrap().len(), 3);
+        db.create_model("t", HashMap::from([("n".to_string(), Value::Integer(3))])).unwrap();
+        assert_eq!(db.find(&Query::new("t")).unwrap().len(), 4);
+        db.update_model("t", 1, HashMap::from([("n".to_string(), Value::Integer(10))])).unwrap();
+        assert_eq!(db.get_model("t", 1).unwrap().unwrap().get_i64("n").unwrap(), 10);
+    }
+
+    #[test]
+    fn raw_writes_that_skip_the_update_hook_drop_cached_reads() {
+        let db = cached_db();
+        assert_eq!(db.find(&Query::new("t")).unwrap().len(), 3);
+        assert!(db.get_model("t", 1).unwrap().is_some());
+        db.execute_raw("DELETE FROM t", &[]).unwrap();
+        assert!(db.find(&Query::new("t")).unwrap().is_empty());
+        assert!(db.get_model("t", 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn raw_reads_keep_cached_reads() {
+        let db = cached_db();
+        db.find(&Query::new("t")).unwrap();
+        db.query_raw("SELECT * FROM t", &[]).unwrap();
+        assert_eq!(db.read_cache_stats().unwrap().len, 1);
+    }
+
+    #[test]
+    fn queries_with_a_byte_budget_are_not_cached() {
+        let db = cached_db();
+        let query = Query::new("t").max_result_bytes(1);
+        let page = db.find_page(&query).unwrap();
+        assert_eq!(page.models.len(), 1);
+        assert!(page.next.is_some());
+        assert_eq!(db.read_cache_stats().unwrap().len, 0);
+    }
+
+    #[test]
+    fn expired_and_evicted_reads_are_misses() {
+        let mut db = cached_db();
+        db.enable_read_cache(1, Duration::ZERO);
+        db.get_model("t", 1).unwrap();
+        db.get_model("t", 1).unwrap();
+        assert_eq!(db.read_cache_stats().unwrap().hits, 0);
+
+        db.enable_read_cache(1, Duration::from_secs(60));
+        db.get_model("t", 1).unwrap();
+        db.get_model("t", 2).unwrap();
+        db.get_model("t", 1).unwrap();
+        let stats = db.read_cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.len, stats.capacity), (0, 1, 1));
+    }
+
+    #[test]
+    fn disabled_cache_has_no_stats() {
+        let mut db = cached_db();
+        db.disable_read_cache();
+        assert!(db.read_cache_stats().is_none());
+    }
+}
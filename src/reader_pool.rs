@@ -0,0 +1,248 @@
+use rusqlite::types::Value;
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FlexibleDatabase, Model, ModelId, Schema};
+use crate::options::JournalMode;
+use crate::pool::KooManager;
+use crate::query::{Query, QueryPage};
+
+// One writer and a fixed set of read-only handles on the same database
+// file in WAL mode, shared between threads. SQLite allows one writer at a
+// time, so writes queue for the writer, while reads run on the readers
+// alongside them and each other, and never wait on a write's locks.
+//
+// Query reads (`find`, `find_page`, `aggregate`, `get_model` and `count`)
+// go to a reader on their own. A reader sees what was committed when its
+// read began, so it misses a write still in progress on another thread.
+pub struct ReadWritePool {
+    manager: KooManager,
+    writer: Mutex<FlexibleDatabase>,
+    readers: Mutex<Vec<FlexibleDatabase>>,
+    returned: Condvar,
+}
+
+// A reader checked out of the pool, put back when dropped
+struct Reader<'a> {
+    pool: &'a ReadWritePool,
+    db: Option<FlexibleDatabase>,
+}
+
+impl Drop for Reader<'_> {
+    fn drop(&mut self) {
+        if let Some(db) = self.db.take() {
+            // A transaction left open would pin the reader at that point
+            if !db.conn.is_autocommit() {
+                let _ = db.conn.execute_batch("ROLLBACK");
+            }
+            self.pool.readers.lock().unwrap_or_else(|e| e.into_inner()).push(db);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+impl ReadWritePool {
+    // Open the writer, switching the database to WAL mode, and `readers`
+    // read-only handles. Schemas registered on `manager` are defined by the
+    // writer and known to the readers.
+    pub fn new(manager: KooManager, readers: usize) -> Result<ReadWritePool> {
+        if readers == 0 {
+            return Err(KooError::InvalidData(
+                "a read-write pool needs at least one reader".to_string(),
+            ));
+        }
+        let options = manager.options().clone().journal_mode(JournalMode::Wal);
+        let manager = manager.with_options(options);
+        let writer = manager.open()?;
+        // An in-memory database stays in memory mode
+        let journal_mode: String = writer.conn.query_row("PRAGMA journal_mode", [], |row| row.get(0))?;
+        if !journal_mode.eq_ignore_ascii_case("wal") {
+            return Err(KooError::InvalidData(format!(
+                "a read-write pool needs a database file in WAL mode, not {}",
+                journal_mode
+            )));
+        }
+        let readers = (0..readers)
+            .map(|_| manager.open_reader())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ReadWritePool {
+            manager,
+            writer: Mutex::new(writer),
+            readers: Mutex::new(readers),
+            returned: Condvar::new(),
+        })
+    }
+
+    pub fn manager(&self) -> &KooManager {
+        &self.manager
+    }
+
+    // Register `schema` and define it on the writer, so readers can query
+    // it from then on
+    pub fn define_schema(&self, schema: Schema) -> Result<()> {
+        self.manager.register_schema(schema)?;
+        self.write(|_| Ok(()))
+    }
+
+    // Run `f` on the writer, once any other write is done
+    pub fn write<T>(&self, f: impl FnOnce(&mut FlexibleDatabase) -> Result<T>) -> Result<T> {
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        // Left open by a write that panicked
+        if self.manager.is_broken(&writer) {
+            writer.conn.execute_batch("ROLLBACK")?;
+        }
+        self.manager.sync_schemas(&mut writer)?;
+        f(&mut writer)
+    }
+
+    // Run `f` on a reader, once one is free. Anything that writes fails.
+    pub fn read<T>(&self, f: impl FnOnce(&FlexibleDatabase) -> Result<T>) -> Result<T> {
+        let mut readers = self.readers.lock().unwrap_or_else(|e| e.into_inner());
+        let mut db = loop {
+            match readers.pop() {
+                Some(db) => break db,
+                None => readers = self.returned.wait(readers).unwrap_or_else(|e| e.into_inner()),
+            }
+        };
+        drop(readers);
+        self.manager.sync_reader(&mut db);
+        let reader = Reader {
+            pool: self,
+            db: Some(db),
+        };
+        f(reader.db.as_ref().expect("reader is held until dropped"))
+    }
+
+    pub fn find(&self, query: &Query) -> Result<Vec<Model>> {
+        self.read(|db| db.find(query))
+    }
+
+    pub fn find_page(&self, query: &Query) -> Result<QueryPage> {
+        self.read(|db| db.find_page(query))
+    }
+
+    pub fn aggregate(&self, query: &Query) -> Result<Vec<HashMap<String, Value>>> {
+        self.read(|db| db.aggregate(query))
+    }
+
+    pub fn get_model(&self, schema_name: &str, id: impl Into<ModelId>) -> Result<Option<Model>> {
+        let id = id.into();
+        self.read(|db| db.get_model(schema_name, id))
+    }
+
+    pub fn count(&self, schema_name: &str) -> Result<i64> {
+        self.read(|db| db.count(schema_name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    use crate::flexible_database::{FieldDef, FieldType};
+    use crate::query::Op;
+    use crate::temp_file::TempFile;
+
+    fn pool(file: &TempFile, readers: usize) -> ReadWritePool {
+        let pool = ReadWritePool::new(KooManager::new(file.path()), readers).unwrap();
+        pool.define_schema(Schema::new("notes", []).field("stars", FieldDef::new(FieldType::Integer)))
+            .unwrap();
+        pool
+    }
+
+    fn note(db: &FlexibleDatabase, stars: i64) -> ModelId {
+        let data = HashMap::from([("stars".to_string(), Value::Integer(stars))]);
+        db.create_model("notes", data).unwrap()
+    }
+
+    #[test]
+    fn reads_see_committed_writes() {
+        let file = TempFile::new("db");
+        let pool = pool(&file, 2);
+        let id = pool.write(|db| Ok(note(db, 3))).unwrap();
+        pool.write(|db| Ok(note(db, 5))).unwrap();
+
+        assert_eq!(pool.count("notes").unwrap(), 2);
+        let found = pool.get_model("notes", id).unwrap().unwrap();
+        assert_eq!(found.data["stars"], Value::Integer(3));
+        let query = Query::new("notes").filter("stars", Op::Gt, 4);
+        assert_eq!(pool.find(&query).unwrap().len(), 1);
+        assert_eq!(pool.find_page(&query).unwrap().models.len(), 1);
+    }
+
+    #[test]
+    fn readers_refuse_writes() {
+        let file = TempFile::new("db");
+        let pool = pool(&file, 1);
+        pool.read(|db| {
+            let data = HashMap::from([("stars".to_string(), Value::Integer(1))]);
+            assert!(db.create_model("notes", data).is_err());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pool.count("notes").unwrap(), 0);
+    }
+
+    #[test]
+    fn reads_go_on_during_a_write() {
+        let file = TempFile::new("db");
+        let pool = pool(&file, 1);
+        pool.write(|db| Ok(note(db, 1))).unwrap();
+        pool.write(|db| {
+            db.conn.execute_batch("BEGIN IMMEDIATE")?;
+            note(db, 2);
+            // Another thread reads while the write holds its lock
+            let seen = thread::scope(|scope| scope.spawn(|| pool.count("notes")).join().unwrap())?;
+            assert_eq!(seen, 1);
+            db.conn.execute_batch("COMMIT")?;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pool.count("notes").unwrap(), 2);
+    }
+
+    #[test]
+    fn threads_share_the_readers() {
+        let file = TempFile::new("db");
+        let pool = pool(&file, 2);
+        pool.write(|db| Ok(note(db, 1))).unwrap();
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| (0..10).map(|_| pool.count("notes").unwrap()).sum::<i64>()))
+                .collect();
+            for handle in handles {
+                assert_eq!(handle.join().unwrap(), 10);
+            }
+        });
+        assert_eq!(pool.readers.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn readers_come_back_without_their_transactions() {
+        let file = TempFile::new("db");
+        let pool = pool(&file, 1);
+        pool.read(|db| {
+            db.conn.execute_batch("BEGIN")?;
+            db.count("notes")
+        })
+        .unwrap();
+        pool.write(|db| Ok(note(db, 1))).unwrap();
+        // The reader isn't still pinned before the write
+        assert_eq!(pool.count("notes").unwrap(), 1);
+    }
+
+    #[test]
+    fn pools_need_readers_and_a_file() {
+        let file = TempFile::new("db");
+        assert!(matches!(
+            ReadWritePool::new(KooManager::new(file.path()), 0),
+            Err(KooError::InvalidData(_))
+        ));
+        assert!(matches!(
+            ReadWritePool::new(KooManager::new(":memory:"), 1),
+            Err(KooError::InvalidData(message)) if message.contains("WAL mode")
+        ));
+    }
+}
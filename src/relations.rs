@@ -0,0 +1,543 @@
+use rusqlite::types::Value;
+use std::collections::{HashMap, HashSet};
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FieldType, FlexibleDatabase, Model, ModelId, Schema};
+
+// A model of any schema, as held by `FieldType::Polymorphic` fields, e.g.
+// the post or photo a comment is on. It is stored as the JSON text
+// `["posts",12]`, and written as a [schema, id] array in exports and by
+// the servers. Models with composite keys can't be pointed at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelRef {
+    pub schema: String,
+    pub id: ModelId,
+}
+
+impl ModelRef {
+    pub fn new(schema: &str, id: impl Into<ModelId>) -> ModelRef {
+        ModelRef {
+            schema: schema.to_string(),
+            id: id.into(),
+        }
+    }
+
+    // The model a field value points at, if it points at one
+    pub fn from_value(value: &Value) -> Option<ModelRef> {
+        match value {
+            Value::Text(text) => ModelRef::from_json(&serde_json::from_str(text).ok()?),
+            _ => None,
+        }
+    }
+
+    // A [schema, id] array with an integer or text id
+    pub(crate) fn from_json(json: &serde_json::Value) -> Option<ModelRef> {
+        let [schema, id] = json.as_array()?.as_slice() else {
+            return None;
+        };
+        let id = match id {
+            serde_json::Value::Number(n) => ModelId::Integer(n.as_i64()?),
+            serde_json::Value::String(s) => ModelId::Text(s.clone()),
+            _ => return None,
+        };
+        Some(ModelRef::new(schema.as_str()?, id))
+    }
+}
+
+impl From<ModelRef> for Value {
+    fn from(reference: ModelRef) -> Value {
+        let id = match reference.id {
+            ModelId::Integer(id) => serde_json::Value::from(id),
+            ModelId::Text(id) => serde_json::Value::from(id),
+            ModelId::Composite(_) => serde_json::Value::Null,
+        };
+        Value::Text(serde_json::json!([reference.schema, id]).to_string())
+    }
+}
+
+// The schemas the reference field `field_name` may point at
+pub(crate) fn reference_targets<'a>(schema: &'a Schema, field_name: &str) -> Result<Vec<&'a str>> {
+    match schema.fields.get(field_name) {
+        Some(FieldType::Reference(target)) => Ok(vec![target.as_str()]),
+        Some(FieldType::Polymorphic(targets)) => Ok(targets.iter().map(String::as_str).collect()),
+        Some(_) => Err(KooError::InvalidData(format!(
+            "'{}.{}' is not a reference field",
+            schema.name, field_name
+        ))),
+        None => Err(KooError::UnknownField {
+            schema: schema.name.clone(),
+            field: field_name.to_string(),
+        }),
+    }
+}
+
+// Reference fields hold the id of a model in another schema, and
+// polymorphic ones a `ModelRef` to a model of one of several. That model
+// can be read when it is needed with `get_referenced`, or up front for
+// every result of a query with `Query::include`, which costs one query per
+// included field, and per schema for polymorphic ones, however many
+// results there are. Either way the access policy applies to the
+// referenced models too.
+impl FlexibleDatabase {
+    // The model the reference field `field_name` of `model` points at
+    pub fn get_referenced(&self, schema_name: &str, model: &Model, field_name: &str) -> Result<Option<Model>> {
+        let schema = self.schema_or_err(schema_name)?;
+        let targets = reference_targets(schema, field_name)?;
+        match (model.data.get(field_name), &schema.fields[field_name]) {
+            (Some(Value::Integer(id)), FieldType::Reference(_)) => self.get_model(targets[0], *id),
+            (Some(value), FieldType::Polymorphic(_)) => match ModelRef::from_value(value) {
+                Some(reference) if targets.contains(&reference.schema.as_str()) => {
+                    self.get_model(&reference.schema, reference.id)
+                }
+                _ => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    // Load the models that `includes` point at into `related`
+    pub(crate) fn include_related(&self, schema: &Schema, models: &mut [Model], includes: &[String]) -> Result<()> {
+        for field_name in includes {
+            let targets = reference_targets(schema, field_name)?;
+            // Each target's ids, and how the models pointing at one find it
+            let mut ids: HashMap<&str, Vec<ModelId>> = HashMap::new();
+            let mut seen = HashSet::new();
+            let mut pointers = Vec::with_capacity(models.len());
+            for model in models.iter() {
+                let pointer = match (model.data.get(field_name), &schema.fields[field_name]) {
+                    (Some(Value::Integer(id)), FieldType::Reference(_)) => Some((targets[0], ModelId::Integer(*id))),
+                    (Some(value), FieldType::Polymorphic(_)) => ModelRef::from_value(value).and_then(|reference| {
+                        let target = targets.iter().find(|target| **target == reference.schema)?;
+                        Some((*target, reference.id))
+                    }),
+                    _ => None,
+                };
+                if let Some((target, id)) = &pointer
+                    && seen.insert((*target, id.to_string()))
+                {
+                    ids.entry(target).or_default().push(id.clone());
+                }
+                pointers.push(pointer);
+            }
+
+            let mut related: HashMap<(&str, String), Model> = HashMap::new();
+            for (target, target_ids) in ids {
+                for model in self.get_models(target, target_ids)? {
+                    if let Some(id) = &model.id {
+                        related.insert((target, id.to_string()), model);
+                    }
+                }
+            }
+            for (model, pointer) in models.iter_mut().zip(pointers) {
+                if let Some((target, id)) = pointer
+                    && let Some(referenced) = related.get(&(target, id.to_string()))
+                {
+                    model.related.insert(field_name.clone(), referenced.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Many-to-many relations keep their links in a join table of
+// (source_id, target_id) pairs, source being the schema declaring the
+// relation with `relates_many`. Either side can attach, detach and read the
+// other. Deleting a model drops its links, and models the access policy
+// hides can't be linked.
+impl FlexibleDatabase {
+    // Link model `id` of `schema_name` to model `related_id` of `related`.
+    // Returns false when either model doesn't exist or they are already
+    // linked.
+    pub fn attach_related(&self, schema_name: &str, id: i64, related: &str, related_id: i64) -> Result<bool> {
+        let (join_table, near, far) = self.relation(schema_name, related)?;
+        if self.get_model(schema_name, id)?.is_none() || self.get_model(related, related_id)?.is_none() {
+            return Ok(false);
+        }
+        let sql = format!("INSERT OR IGNORE INTO {} ({}, {}) VALUES (?, ?)", join_table, near, far);
+        Ok(self.prepare_cached(&sql)?.execute([id, related_id])? > 0)
+    }
+
+    // Remove a link; returns false when there was none
+    pub fn detach_related(&self, schema_name: &str, id: i64, related: &str, related_id: i64) -> Result<bool> {
+        let (join_table, near, far) = self.relation(schema_name, related)?;
+        let sql = format!("DELETE FROM {} WHERE {} = ? AND {} = ?", join_table, near, far);
+        Ok(self.prepare_cached(&sql)?.execute([id, related_id])? > 0)
+    }
+
+    // Models of `related` linked to model `id`, in the order they were
+    // attached
+    pub fn get_related(&self, schema_name: &str, id: i64, related: &str) -> Result<Vec<Model>> {
+        let (join_table, near, far) = self.relation(schema_name, related)?;
+        let sql = format!("SELECT {} FROM {} WHERE {} = ? ORDER BY rowid", far, join_table, near);
+        let ids = self
+            .prepare_cached(&sql)?
+            .query_map([id], |row| row.get::<_, i64>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        self.get_models(related, ids)
+    }
+
+    pub(crate) fn create_join_tables(&self, schema: &Schema) -> Result<()> {
+        for (target, join_table) in &schema.many_to_many {
+            self.conn.execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS {join_table} (
+                    source_id INTEGER NOT NULL REFERENCES {}(id),
+                    target_id INTEGER NOT NULL REFERENCES {target}(id),
+                    PRIMARY KEY (source_id, target_id)
+                );
+                CREATE INDEX IF NOT EXISTS {join_table}_target ON {join_table} (target_id);",
+                schema.name
+            ))?;
+        }
+        Ok(())
+    }
+
+    // Drop the links of a model about to be deleted
+    pub(crate) fn unlink_related(&self, schema_name: &str, id: &ModelId) -> Result<()> {
+        let Some(id) = id.as_i64() else {
+            return Ok(());
+        };
+        for schema in self.schemas.values() {
+            for (target, join_table) in &schema.many_to_many {
+                let mut columns = Vec::new();
+                if schema.name == schema_name {
+                    columns.push("source_id");
+                }
+                if target == schema_name {
+                    columns.push("target_id");
+                }
+                for column in columns {
+                    let sql = format!("DELETE FROM {} WHERE {} = ?", join_table, column);
+                    self.prepare_cached(&sql)?.execute([id])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Join table linking `schema_name` to `related`, with the column holding
+    // ids of `schema_name` and the one holding ids of `related`
+    fn relation(&self, schema_name: &str, related: &str) -> Result<(String, &'static str, &'static str)> {
+        let schema = self.schema_or_err(schema_name)?;
+        if let Some(join_table) = schema.many_to_many.get(related) {
+            return Ok((join_table.clone(), "source_id", "target_id"));
+        }
+        if let Some(join_table) = self.schema_or_err(related)?.many_to_many.get(schema_name) {
+            return Ok((join_table.clone(), "target_id", "source_id"));
+        }
+        Err(KooError::InvalidSchema(format!(
+            "'{}' has no many-to-many relation with '{}'",
+            schema_name, related
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::alter::DropBehavior;
+    use crate::flexible_database::{FieldDef, PrimaryKey};
+    use crate::query::Query;
+
+    // Two authors and three books, the last without an author
+    fn library() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("authors", [("name".to_string(), FieldType::Text)]))
+            .unwrap();
+        db.define_schema(Schema::new("books", [("title".to_string(), FieldType::Text)]).field(
+            "author",
+            FieldDef::new(FieldType::Reference("authors".to_string())).nullable(),
+        ))
+        .unwrap();
+        for name in ["Ada", "Grace"] {
+            db.create_model(
+                "authors",
+                HashMap::from([("name".to_string(), Value::Text(name.to_string()))]),
+            )
+            .unwrap();
+        }
+        for (title, author) in [
+            ("Notes", Value::Integer(1)),
+            ("Cobol", Value::Integer(2)),
+            ("Anon", Value::Null),
+        ] {
+            let data = HashMap::from([
+                ("title".to_string(), Value::Text(title.to_string())),
+                ("author".to_string(), author),
+            ]);
+            db.create_model("books", data).unwrap();
+        }
+        db
+    }
+
+    fn name(model: &Model) -> &Value {
+        &model.data["name"]
+    }
+
+    #[test]
+    fn referenced_models_are_read_on_demand() {
+        let db = library();
+        let book = db.get_model("books", 2).unwrap().unwrap();
+        let author = db.get_referenced("books", &book, "author").unwrap().unwrap();
+        assert_eq!(name(&author), &Value::Text("Grace".to_string()));
+
+        let anon = db.get_model("books", 3).unwrap().unwrap();
+        assert!(db.get_referenced("books", &anon, "author").unwrap().is_none());
+    }
+
+    #[test]
+    fn included_models_come_with_each_result() {
+        let db = library();
+        let books = db.find(&Query::new("books").include("author")).unwrap();
+        assert_eq!(books.len(), 3);
+        assert_eq!(name(&books[0].related["author"]), &Value::Text("Ada".to_string()));
+        assert_eq!(name(&books[1].related["author"]), &Value::Text("Grace".to_string()));
+        assert!(books[2].related.is_empty());
+
+        // Without `include` nothing is loaded
+        assert!(db.find(&Query::new("books")).unwrap()[0].related.is_empty());
+    }
+
+    #[test]
+    fn only_reference_fields_can_be_followed() {
+        let db = library();
+        assert!(matches!(
+            db.find(&Query::new("books").include("title")),
+            Err(KooError::InvalidData(_))
+        ));
+        assert!(matches!(
+            db.find(&Query::new("books").include("editor")),
+            Err(KooError::UnknownField { .. })
+        ));
+        let book = db.get_model("books", 1).unwrap().unwrap();
+        assert!(db.get_referenced("books", &book, "title").is_err());
+    }
+
+    #[test]
+    fn includes_cost_one_query_per_field() {
+        let mut db = library();
+        for title in ["More notes", "Still more"] {
+            let data = HashMap::from([
+                ("title".to_string(), Value::Text(title.to_string())),
+                ("author".to_string(), Value::Integer(1)),
+            ]);
+            db.create_model("books", data).unwrap();
+        }
+        let statements = std::sync::Arc::new(std::sync::Mutex::new(0));
+        let counted = statements.clone();
+        db.set_tracer(move |_| *counted.lock().unwrap() += 1);
+
+        assert_eq!(db.find(&Query::new("books").include("author")).unwrap().len(), 5);
+        assert_eq!(*statements.lock().unwrap(), 2);
+    }
+
+    // Students taking courses, the relation declared by students
+    fn enrolment() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("courses", [("name".to_string(), FieldType::Text)]))
+            .unwrap();
+        db.define_schema(
+            Schema::new("students", [("name".to_string(), FieldType::Text)]).relates_many("courses", "enrolments"),
+        )
+        .unwrap();
+        for (schema, names) in [
+            ("courses", ["Maths", "Art", "Latin"]),
+            ("students", ["Ada", "Alan", "Grace"]),
+        ] {
+            for name in names {
+                db.create_model(
+                    schema,
+                    HashMap::from([("name".to_string(), Value::Text(name.to_string()))]),
+                )
+                .unwrap();
+            }
+        }
+        db
+    }
+
+    fn names(models: &[Model]) -> Vec<&Value> {
+        models.iter().map(name).collect()
+    }
+
+    #[test]
+    fn links_can_be_read_from_either_side() {
+        let db = enrolment();
+        assert!(db.attach_related("students", 1, "courses", 3).unwrap());
+        assert!(db.attach_related("students", 1, "courses", 1).unwrap());
+        assert!(db.attach_related("courses", 1, "students", 2).unwrap());
+        assert!(!db.attach_related("students", 1, "courses", 3).unwrap());
+
+        let text = |s: &str| Value::Text(s.to_string());
+        let courses = db.get_related("students", 1, "courses").unwrap();
+        assert_eq!(names(&courses), [&text("Latin"), &text("Maths")]);
+        let students = db.get_related("courses", 1, "students").unwrap();
+        assert_eq!(names(&students), [&text("Ada"), &text("Alan")]);
+        assert!(db.get_related("students", 3, "courses").unwrap().is_empty());
+
+        assert!(db.detach_related("courses", 3, "students", 1).unwrap());
+        assert!(!db.detach_related("courses", 3, "students", 1).unwrap());
+        assert_eq!(
+            names(&db.get_related("students", 1, "courses").unwrap()),
+            [&text("Maths")]
+        );
+    }
+
+    #[test]
+    fn only_existing_models_are_linked() {
+        let db = enrolment();
+        assert!(!db.attach_related("students", 1, "courses", 9).unwrap());
+        assert!(!db.attach_related("students", 9, "courses", 1).unwrap());
+        let links: i64 = db
+            .conn
+            .query_row("SELECT COUNT(*) FROM enrolments", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(links, 0);
+    }
+
+    #[test]
+    fn deleted_models_lose_their_links() {
+        let db = enrolment();
+        db.attach_related("students", 1, "courses", 1).unwrap();
+        db.attach_related("students", 2, "courses", 1).unwrap();
+        db.attach_related("students", 2, "courses", 2).unwrap();
+
+        db.delete_model("courses", 1).unwrap();
+        assert!(db.get_related("students", 1, "courses").unwrap().is_empty());
+        db.delete_model("students", 2).unwrap();
+        assert!(db.get_related("courses", 2, "students").unwrap().is_empty());
+    }
+
+    #[test]
+    fn relations_must_be_declared_between_integer_keyed_schemas() {
+        let mut db = enrolment();
+        assert!(matches!(
+            db.attach_related("courses", 1, "courses", 2),
+            Err(KooError::InvalidSchema(_))
+        ));
+        let keyed = Schema::new("rooms", [("code".to_string(), FieldType::Text)])
+            .with_key(PrimaryKey::Text)
+            .relates_many("courses", "room_courses");
+        assert!(matches!(db.define_schema(keyed), Err(KooError::InvalidSchema(_))));
+    }
+
+    // Comments on posts, keyed by integer, and photos, keyed by text
+    fn attachments() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("posts", [("title".to_string(), FieldType::Text)]))
+            .unwrap();
+        db.define_schema(Schema::new("photos", [("caption".to_string(), FieldType::Text)]).with_key(PrimaryKey::Text))
+            .unwrap();
+        let on = FieldType::Polymorphic(vec!["posts".to_string(), "photos".to_string()]);
+        db.define_schema(Schema::new("comments", [("subject".to_string(), on)]))
+            .unwrap();
+
+        db.create_model(
+            "posts",
+            HashMap::from([("title".to_string(), Value::Text("Hello".to_string()))]),
+        )
+        .unwrap();
+        db.create_model(
+            "photos",
+            HashMap::from([
+                ("id".to_string(), Value::Text("sunset".to_string())),
+                ("caption".to_string(), Value::Text("Red sky".to_string())),
+            ]),
+        )
+        .unwrap();
+        for on in [
+            ModelRef::new("posts", 1),
+            ModelRef::new("photos", "sunset"),
+            ModelRef::new("posts", 1),
+            ModelRef::new("posts", 9),
+        ] {
+            db.create_model("comments", HashMap::from([("subject".to_string(), Value::from(on))]))
+                .unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn model_refs_are_stored_as_json_arrays() {
+        assert_eq!(
+            Value::from(ModelRef::new("posts", 12)),
+            Value::Text(r#"["posts",12]"#.to_string())
+        );
+        let photo = ModelRef::new("photos", "sunset");
+        assert_eq!(ModelRef::from_value(&Value::from(photo.clone())), Some(photo));
+        for text in [r#"["posts"]"#, r#"["posts",1.5]"#, r#"[1,2]"#, "posts:12"] {
+            assert_eq!(ModelRef::from_value(&Value::Text(text.to_string())), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn polymorphic_references_resolve_to_their_schema() {
+        let db = attachments();
+        let referenced = |id: i64| {
+            let comment = db.get_model("comments", id).unwrap().unwrap();
+            db.get_referenced("comments", &comment, "subject").unwrap()
+        };
+        assert_eq!(referenced(1).unwrap().data["title"], Value::Text("Hello".to_string()));
+        assert_eq!(referenced(2).unwrap().id, Some(ModelId::Text("sunset".to_string())));
+        // Not checked to exist
+        assert!(referenced(4).is_none());
+    }
+
+    #[test]
+    fn polymorphic_includes_query_each_schema_once() {
+        let mut db = attachments();
+        let statements = std::sync::Arc::new(std::sync::Mutex::new(0));
+        let counted = statements.clone();
+        db.set_tracer(move |_| *counted.lock().unwrap() += 1);
+
+        let comments = db.find(&Query::new("comments").include("subject")).unwrap();
+        assert_eq!(*statements.lock().unwrap(), 3);
+        let related: Vec<Option<&Value>> = comments
+            .iter()
+            .map(|comment| {
+                comment
+                    .related
+                    .get("subject")
+                    .map(|model| model.data.values().next().unwrap())
+            })
+            .collect();
+        let (hello, sky) = (Value::Text("Hello".to_string()), Value::Text("Red sky".to_string()));
+        assert_eq!(related, [Some(&hello), Some(&sky), Some(&hello), None]);
+    }
+
+    #[test]
+    fn polymorphic_references_name_a_listed_schema() {
+        let db = attachments();
+        for on in [
+            Value::from(ModelRef::new("comments", 1)),
+            Value::Text("posts:1".to_string()),
+        ] {
+            let data = HashMap::from([("subject".to_string(), on)]);
+            assert!(matches!(
+                db.create_model("comments", data),
+                Err(KooError::Validation { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn renamed_schemas_are_renamed_in_polymorphic_references() {
+        let mut db = attachments();
+        db.rename_schema("posts", "articles").unwrap();
+        assert_eq!(
+            db.schemas["comments"].fields["subject"],
+            FieldType::Polymorphic(vec!["articles".to_string(), "photos".to_string()])
+        );
+        let comment = db.get_model("comments", 1).unwrap().unwrap();
+        assert_eq!(
+            ModelRef::from_value(&comment.data["subject"]),
+            Some(ModelRef::new("articles", 1))
+        );
+        assert!(db.get_referenced("comments", &comment, "subject").unwrap().is_some());
+
+        assert!(matches!(
+            db.drop_schema("photos", DropBehavior::DropTable),
+            Err(KooError::InvalidSchema(_))
+        ));
+    }
+}
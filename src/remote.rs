@@ -0,0 +1,354 @@
+use rusqlite::types::Value;
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FieldType, FlexibleDatabase, PrimaryKey, Schema};
+use crate::import::ImportReport;
+
+// A table of the server being imported from
+struct RemoteTable {
+    name: String,
+    // Name, field type and nullability, in column order
+    columns: Vec<(String, FieldType, bool)>,
+    // Primary key columns, in key order
+    key: Vec<String>,
+}
+
+// A PostgreSQL or MySQL connection tables are read from
+trait RemoteSource {
+    fn table(&mut self, name: &str) -> Result<RemoteTable>;
+
+    // Pass each row of `table` to `insert`, with a value per column
+    fn rows(&mut self, table: &RemoteTable, insert: &mut dyn FnMut(Vec<Value>) -> Result<()>) -> Result<()>;
+}
+
+// Migrating small services onto kooDB by copying their tables from the
+// PostgreSQL or MySQL server they used
+impl FlexibleDatabase {
+    // Define a schema for each of `tables`, read from the server at `url`
+    // ("postgres://..." with the `postgres` feature, "mysql://..." with
+    // the `mysql` feature), and copy its rows, all in one transaction.
+    // Integer, floating point and decimal, and boolean columns become
+    // Integer, Real and Boolean fields, and any other column a Text field
+    // holding the server's text form of its values, such as "2024-01-31"
+    // for a date. Nullable columns become nullable fields.
+    //
+    // An integer or text primary key named `id` is kept as the model id,
+    // and any other primary key becomes a composite key. Rows of tables
+    // without one get fresh integer ids.
+    pub fn import_from_url(&mut self, url: &str, tables: &[&str]) -> Result<ImportReport> {
+        let mut source = connect(url)?;
+        self.in_transaction(|db| {
+            let mut report = ImportReport::default();
+            for table_name in tables {
+                if db.schemas.contains_key(*table_name) {
+                    return Err(KooError::InvalidSchema(format!(
+                        "schema '{}' already exists",
+                        table_name
+                    )));
+                }
+                let table = source.table(table_name)?;
+                report.inserted += db.import_remote_table(source.as_mut(), &table)?;
+                report.schemas += 1;
+            }
+            Ok(report)
+        })
+    }
+
+    fn import_remote_table(&mut self, source: &mut dyn RemoteSource, table: &RemoteTable) -> Result<usize> {
+        let id = table.columns.iter().find(|(name, ..)| name == "id");
+        let key = match (table.key.as_slice(), id) {
+            ([key], Some((_, FieldType::Integer, _))) if key == "id" => PrimaryKey::Integer,
+            ([key], Some((_, FieldType::Text, _))) if key == "id" => PrimaryKey::Text,
+            (_, Some(_)) => {
+                return Err(KooError::InvalidSchema(format!(
+                    "'{}.id' can't be imported, as kooDB ids must be an integer or text primary key",
+                    table.name
+                )));
+            }
+            ([], None) => PrimaryKey::Integer,
+            (key, None) => PrimaryKey::Composite(key.to_vec()),
+        };
+
+        let mut schema = Schema::new(
+            &table.name,
+            table
+                .columns
+                .iter()
+                .filter(|(name, ..)| name != "id")
+                .map(|(name, field_type, _)| (name.clone(), field_type.clone())),
+        );
+        schema.key = key;
+        schema.nullable_fields = table
+            .columns
+            .iter()
+            .filter(|(name, _, nullable)| *nullable && name != "id" && !table.key.contains(name))
+            .map(|(name, ..)| name.clone())
+            .collect();
+        self.define_schema(schema)?;
+
+        let names: Vec<&str> = table.columns.iter().map(|(name, ..)| name.as_str()).collect();
+        let placeholders = vec!["?"; names.len()];
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            table.name,
+            names.join(", "),
+            placeholders.join(", ")
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let mut inserted = 0;
+        source.rows(table, &mut |values| {
+            let values = values
+                .into_iter()
+                .zip(&table.columns)
+                .map(|(value, (_, field_type, _))| match (value, field_type) {
+                    (Value::Integer(i), FieldType::Boolean) => Value::Integer((i != 0) as i64),
+                    (value, _) => value,
+                });
+            stmt.execute(rusqlite::params_from_iter(values))?;
+            inserted += 1;
+            Ok(())
+        })?;
+        Ok(inserted)
+    }
+}
+
+fn connect(url: &str) -> Result<Box<dyn RemoteSource>> {
+    let scheme = url.split_once("://").map_or("", |(scheme, _)| scheme);
+    match scheme {
+        #[cfg(feature = "postgres")]
+        "postgres" | "postgresql" => Ok(Box::new(postgres::Client::connect(url, postgres::NoTls)?)),
+        #[cfg(feature = "mysql")]
+        "mysql" => Ok(Box::new(mysql::Conn::new(
+            mysql::Opts::from_url(url).map_err(mysql::Error::UrlError)?,
+        )?)),
+        #[cfg(not(feature = "postgres"))]
+        "postgres" | "postgresql" => Err(KooError::InvalidData(
+            "importing from PostgreSQL needs the `postgres` feature".to_string(),
+        )),
+        #[cfg(not(feature = "mysql"))]
+        "mysql" => Err(KooError::InvalidData(
+            "importing from MySQL needs the `mysql` feature".to_string(),
+        )),
+        _ => Err(KooError::InvalidData(format!(
+            "'{}' is not a postgres:// or mysql:// URL",
+            url.split_once('@').map_or(url, |(_, host)| host)
+        ))),
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl RemoteSource for postgres::Client {
+    fn table(&mut self, name: &str) -> Result<RemoteTable> {
+        // information_schema names are domains the client can't read as
+        // text without a cast
+        let mut columns = Vec::new();
+        for row in self.query(
+            "SELECT column_name::text, data_type::text, is_nullable = 'YES'
+             FROM information_schema.columns
+             WHERE table_schema = current_schema() AND table_name = $1
+             ORDER BY ordinal_position",
+            &[&name],
+        )? {
+            let data_type: String = row.get(1);
+            let field_type = match data_type.as_str() {
+                "smallint" | "integer" | "bigint" => FieldType::Integer,
+                "real" | "double precision" | "numeric" => FieldType::Real,
+                "boolean" => FieldType::Boolean,
+                _ => FieldType::Text,
+            };
+            columns.push((row.get(0), field_type, row.get(2)));
+        }
+        if columns.is_empty() {
+            return Err(KooError::InvalidData(format!("no table '{}' to import", name)));
+        }
+        let key = self
+            .query(
+                "SELECT k.column_name::text
+                 FROM information_schema.table_constraints c
+                 JOIN information_schema.key_column_usage k
+                   ON k.constraint_name = c.constraint_name AND k.table_schema = c.table_schema
+                 WHERE c.constraint_type = 'PRIMARY KEY'
+                   AND c.table_schema = current_schema() AND c.table_name = $1
+                 ORDER BY k.ordinal_position",
+                &[&name],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        Ok(RemoteTable {
+            name: name.to_string(),
+            columns,
+            key,
+        })
+    }
+
+    fn rows(&mut self, table: &RemoteTable, insert: &mut dyn FnMut(Vec<Value>) -> Result<()>) -> Result<()> {
+        use postgres::fallible_iterator::FallibleIterator;
+
+        // Cast on the server, so each field type is read one way
+        let columns: Vec<String> = table
+            .columns
+            .iter()
+            .map(|(name, field_type, _)| {
+                let cast = match field_type {
+                    FieldType::Integer => "int8",
+                    FieldType::Real => "float8",
+                    FieldType::Boolean => "bool",
+                    _ => "text",
+                };
+                format!("\"{}\"::{}", name.replace('"', "\"\""), cast)
+            })
+            .collect();
+        let sql = format!(
+            "SELECT {} FROM \"{}\"",
+            columns.join(", "),
+            table.name.replace('"', "\"\"")
+        );
+        let mut rows = self.query_raw(&sql, std::iter::empty::<i64>())?;
+        while let Some(row) = rows.next()? {
+            let mut values = Vec::with_capacity(table.columns.len());
+            for (i, (_, field_type, _)) in table.columns.iter().enumerate() {
+                let value = match field_type {
+                    FieldType::Integer => row.try_get::<_, Option<i64>>(i)?.map(Value::Integer),
+                    FieldType::Real => row.try_get::<_, Option<f64>>(i)?.map(Value::Real),
+                    FieldType::Boolean => row.try_get::<_, Option<bool>>(i)?.map(|b| Value::Integer(b as i64)),
+                    _ => row.try_get::<_, Option<String>>(i)?.map(Value::Text),
+                };
+                values.push(value.unwrap_or(Value::Null));
+            }
+            insert(values)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl RemoteSource for mysql::Conn {
+    fn table(&mut self, name: &str) -> Result<RemoteTable> {
+        use mysql::prelude::Queryable;
+
+        let mut columns = Vec::new();
+        let rows: Vec<(String, String, String, bool)> = self.exec(
+            "SELECT CAST(column_name AS CHAR), CAST(data_type AS CHAR), CAST(column_type AS CHAR),
+                    is_nullable = 'YES'
+             FROM information_schema.columns
+             WHERE table_schema = DATABASE() AND table_name = ?
+             ORDER BY ordinal_position",
+            (name,),
+        )?;
+        for (column, data_type, column_type, nullable) in rows {
+            let field_type = match data_type.as_str() {
+                // MySQL's BOOLEAN is a TINYINT(1)
+                "tinyint" if column_type.starts_with("tinyint(1)") => FieldType::Boolean,
+                "tinyint" | "smallint" | "mediumint" | "int" | "bigint" | "year" => FieldType::Integer,
+                "float" | "double" | "decimal" => FieldType::Real,
+                _ => FieldType::Text,
+            };
+            columns.push((column, field_type, nullable));
+        }
+        if columns.is_empty() {
+            return Err(KooError::InvalidData(format!("no table '{}' to import", name)));
+        }
+        let key = self.exec(
+            "SELECT CAST(column_name AS CHAR) FROM information_schema.key_column_usage
+             WHERE constraint_name = 'PRIMARY' AND table_schema = DATABASE() AND table_name = ?
+             ORDER BY ordinal_position",
+            (name,),
+        )?;
+        Ok(RemoteTable {
+            name: name.to_string(),
+            columns,
+            key,
+        })
+    }
+
+    fn rows(&mut self, table: &RemoteTable, insert: &mut dyn FnMut(Vec<Value>) -> Result<()>) -> Result<()> {
+        use mysql::prelude::Queryable;
+
+        // Prepared, so numbers arrive as numbers rather than text. Adding a
+        // float zero turns decimals into doubles on MySQL and MariaDB alike.
+        let columns: Vec<String> = table
+            .columns
+            .iter()
+            .map(|(name, field_type, _)| {
+                let column = format!("`{}`", name.replace('`', "``"));
+                match field_type {
+                    FieldType::Integer | FieldType::Boolean => column,
+                    FieldType::Real => format!("({} + 0E0)", column),
+                    _ => format!("CAST({} AS CHAR)", column),
+                }
+            })
+            .collect();
+        let sql = format!("SELECT {} FROM `{}`", columns.join(", "), table.name.replace('`', "``"));
+        for row in self.exec_iter(sql, ())? {
+            let mut values = Vec::with_capacity(table.columns.len());
+            for (value, (column, _, _)) in row?.unwrap_raw().into_iter().zip(&table.columns) {
+                values.push(mysql_value(value, &table.name, column)?);
+            }
+            insert(values)?;
+        }
+        Ok(())
+    }
+}
+
+// A value read from MySQL, as SQLite stores it; `column` of `table` holds it
+#[cfg(feature = "mysql")]
+fn mysql_value(value: Option<mysql::Value>, table: &str, column: &str) -> Result<Value> {
+    Ok(match value {
+        None => {
+            return Err(KooError::InvalidData(format!(
+                "'{}.{}' is missing from its row",
+                table, column
+            )));
+        }
+        Some(mysql::Value::NULL) => Value::Null,
+        Some(mysql::Value::Int(i)) => Value::Integer(i),
+        Some(mysql::Value::UInt(u)) => Value::Integer(i64::try_from(u).map_err(|_| {
+            KooError::InvalidData(format!(
+                "'{}.{}' holds {}, which is too large for an Integer",
+                table, column, u
+            ))
+        })?),
+        Some(mysql::Value::Float(f)) => Value::Real(f as f64),
+        Some(mysql::Value::Double(f)) => Value::Real(f),
+        Some(mysql::Value::Bytes(bytes)) => Value::Text(
+            String::from_utf8(bytes)
+                .map_err(|_| KooError::InvalidData(format!("'{}.{}' holds text that isn't UTF-8", table, column)))?,
+        ),
+        // Dates and times are cast to text, so shouldn't arrive as such
+        Some(mysql::Value::Date(..) | mysql::Value::Time(..)) => {
+            return Err(KooError::InvalidData(format!(
+                "'{}.{}' holds a date or time rather than text",
+                table, column
+            )));
+        }
+    })
+}
+
+#[cfg(all(test, feature = "mysql"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mysql_values_convert_without_panicking() {
+        let value = |value| mysql_value(value, "t", "c");
+        assert_eq!(value(Some(mysql::Value::NULL)).unwrap(), Value::Null);
+        assert_eq!(value(Some(mysql::Value::Int(-3))).unwrap(), Value::Integer(-3));
+        assert_eq!(value(Some(mysql::Value::UInt(3))).unwrap(), Value::Integer(3));
+        assert_eq!(value(Some(mysql::Value::Double(0.5))).unwrap(), Value::Real(0.5));
+        assert_eq!(
+            value(Some(mysql::Value::Bytes(b"ok".to_vec()))).unwrap(),
+            Value::Text("ok".to_string())
+        );
+
+        for bad in [
+            None,
+            Some(mysql::Value::UInt(u64::MAX)),
+            Some(mysql::Value::Bytes(vec![0xff])),
+            Some(mysql::Value::Date(2024, 1, 2, 0, 0, 0, 0)),
+            Some(mysql::Value::Time(false, 0, 1, 0, 0, 0)),
+        ] {
+            assert!(matches!(value(bad), Err(KooError::InvalidData(_))));
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use rusqlite::ErrorCode;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::FlexibleDatabase;
+
+// How writes that find the database locked by another connection are
+// retried, on top of SQLite's busy timeout. Each wait doubles, from
+// `initial_backoff` up to `max_backoff`, and is shortened by a random part
+// of up to `jitter` of it so that writers held up together don't all come
+// back at once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub(crate) max_attempts: u32,
+    pub(crate) initial_backoff: Duration,
+    pub(crate) max_backoff: Duration,
+    pub(crate) jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(500),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Try a write up to `max_attempts` times in all
+    pub fn new(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            ..RetryPolicy::default()
+        }
+    }
+
+    // Fail with `KooError::Busy` on the first busy error
+    pub fn none() -> RetryPolicy {
+        RetryPolicy::new(1)
+    }
+
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> RetryPolicy {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    // Fraction of each wait, from 0 to 1, that is left to chance
+    pub fn jitter(mut self, fraction: f64) -> RetryPolicy {
+        self.jitter = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    // Wait before attempt `attempt` + 1, counting from 1
+    fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_backoff);
+        let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+        backoff.mul_f64(1.0 - self.jitter * random)
+    }
+}
+
+// Writes through `create_model`, `update_model` and `delete_model` are
+// retried under the retry policy when another connection holds the lock,
+// as is committing a transaction. A write made inside a transaction isn't
+// retried alone, since the locks the transaction already holds may be
+// what the other connection waits on; it fails with `KooError::Busy`
+// straight away, for the caller to retry the transaction.
+impl FlexibleDatabase {
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    // Run the write `f`, again while it fails on a busy database
+    pub(crate) fn retrying<T>(&self, f: impl FnMut() -> Result<T>) -> Result<T> {
+        let attempts = match self.conn.is_autocommit() {
+            true => self.retry_policy.max_attempts,
+            false => 1,
+        };
+        self.retry_busy(attempts, f)
+    }
+
+    // Run a COMMIT of the open transaction, which stays open when the
+    // database is busy so it can be tried again
+    pub(crate) fn retrying_commit(&self, sql: &str) -> Result<()> {
+        self.retry_busy(self.retry_policy.max_attempts, || Ok(self.conn.execute_batch(sql)?))
+    }
+
+    fn retry_busy<T>(&self, attempts: u32, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Err(err) if is_busy(&err) => {
+                    if attempt >= attempts {
+                        return Err(KooError::Busy { attempts: attempt });
+                    }
+                    thread::sleep(self.retry_policy.delay(attempt));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+fn is_busy(err: &KooError) -> bool {
+    matches!(err, KooError::Sqlite(rusqlite::Error::SqliteFailure(failure, _)) if failure.code == ErrorCode::DatabaseBusy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+    use rusqlite::types::Value;
+    use std::collections::HashMap;
+
+    use crate::flexible_database::{FieldType, Schema};
+    use crate::options::DatabaseOptions;
+    use crate::temp_file::TempFile;
+
+    // A handle that gives up on locks at once, leaving waits to `policy`
+    fn open(file: &TempFile, policy: RetryPolicy) -> FlexibleDatabase {
+        let options = DatabaseOptions::new().busy_timeout(Duration::ZERO).retry_policy(policy);
+        let mut db = FlexibleDatabase::open_with(file.path(), options).unwrap();
+        db.define_schema(Schema::new("notes", [("body".to_string(), FieldType::Text)]))
+            .unwrap();
+        db
+    }
+
+    // Another connection holding the write lock
+    fn lock(file: &TempFile) -> Connection {
+        let conn = Connection::open(file.path()).unwrap();
+        conn.execute_batch("BEGIN IMMEDIATE").unwrap();
+        conn
+    }
+
+    fn note() -> HashMap<String, Value> {
+        HashMap::from([("body".to_string(), Value::Text("a".to_string()))])
+    }
+
+    #[test]
+    fn backoffs_double_up_to_the_cap() {
+        let policy = RetryPolicy::new(10)
+            .backoff(Duration::from_millis(10), Duration::from_millis(50))
+            .jitter(0.0);
+        let delays: Vec<u128> = (1..=5).map(|attempt| policy.delay(attempt).as_millis()).collect();
+        assert_eq!(delays, [10, 20, 40, 50, 50]);
+
+        let jittered = policy.jitter(0.5);
+        for attempt in 1..=5 {
+            let delay = jittered.delay(attempt);
+            assert!(delay <= policy.delay(attempt) && delay >= policy.delay(attempt) / 2);
+        }
+    }
+
+    #[test]
+    fn policies_keep_their_settings_in_range() {
+        assert_eq!(RetryPolicy::new(0).max_attempts, 1);
+        assert_eq!(RetryPolicy::none().max_attempts, 1);
+        let policy = RetryPolicy::default()
+            .backoff(Duration::from_millis(30), Duration::from_millis(5))
+            .jitter(3.0);
+        assert_eq!(policy.max_backoff, Duration::from_millis(30));
+        assert_eq!(policy.jitter, 1.0);
+    }
+
+    #[test]
+    fn busy_writes_fail_once_the_attempts_run_out() {
+        let file = TempFile::new("db");
+        let policy = RetryPolicy::new(3).backoff(Duration::from_millis(1), Duration::from_millis(2));
+        let db = open(&file, policy);
+        let _lock = lock(&file);
+        assert!(matches!(
+            db.create_model("notes", note()),
+            Err(KooError::Busy { attempts: 3 })
+        ));
+    }
+
+    #[test]
+    fn busy_writes_succeed_once_the_lock_is_let_go() {
+        let file = TempFile::new("db");
+        let policy = RetryPolicy::new(50).backoff(Duration::from_millis(5), Duration::from_millis(20));
+        let db = open(&file, policy);
+        let conn = lock(&file);
+        let holder = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            conn.execute_batch("COMMIT").unwrap();
+        });
+        db.create_model("notes", note()).unwrap();
+        holder.join().unwrap();
+        assert_eq!(db.count("notes").unwrap(), 1);
+    }
+
+    #[test]
+    fn writes_in_a_transaction_fail_straight_away() {
+        let file = TempFile::new("db");
+        let db = open(&file, RetryPolicy::new(5));
+        let _lock = lock(&file);
+        db.execute_raw("BEGIN", &[]).unwrap();
+        assert!(matches!(
+            db.create_model("notes", note()),
+            Err(KooError::Busy { attempts: 1 })
+        ));
+        db.execute_raw("ROLLBACK", &[]).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+use crate::flexible_database::{FieldDef, FieldType, PrimaryKey, Schema};
+
+// A schema put together one field at a time, in column order:
+//
+//     Schema::builder("users")
+//         .text("name")
+//         .integer("age")
+//         .boolean("active")
+//         .nullable("bio", FieldType::Text)
+//         .index("name")
+//         .build()
+//
+// The schema is checked when it is defined, as any other is.
+#[derive(Debug, Clone)]
+pub struct SchemaBuilder {
+    schema: Schema,
+}
+
+impl Schema {
+    pub fn builder(name: &str) -> SchemaBuilder {
+        SchemaBuilder {
+            schema: Schema::new(name, []),
+        }
+    }
+}
+
+impl SchemaBuilder {
+    pub fn text(self, field_name: &str) -> SchemaBuilder {
+        self.field(field_name, FieldType::Text)
+    }
+
+    pub fn integer(self, field_name: &str) -> SchemaBuilder {
+        self.field(field_name, FieldType::Integer)
+    }
+
+    pub fn real(self, field_name: &str) -> SchemaBuilder {
+        self.field(field_name, FieldType::Real)
+    }
+
+    pub fn boolean(self, field_name: &str) -> SchemaBuilder {
+        self.field(field_name, FieldType::Boolean)
+    }
+
+    // Id of a model in `target`
+    pub fn reference(self, field_name: &str, target: &str) -> SchemaBuilder {
+        self.field(field_name, FieldType::Reference(target.to_string()))
+    }
+
+    // A field that may hold NULL, which it does when left out
+    pub fn nullable(self, field_name: &str, field_type: FieldType) -> SchemaBuilder {
+        self.field(field_name, FieldDef::new(field_type).nullable())
+    }
+
+    // Any field, with a default, validators and the rest, as `Schema::field`
+    pub fn field(mut self, field_name: &str, field: impl Into<FieldDef>) -> SchemaBuilder {
+        self.schema = self.schema.field(field_name, field);
+        self
+    }
+
+    // Index a field added before or after
+    pub fn index(mut self, field_name: &str) -> SchemaBuilder {
+        self.schema = self.schema.with_index(field_name);
+        self
+    }
+
+    // Refuse repeated values of a field added before or after
+    pub fn unique(mut self, field_name: &str) -> SchemaBuilder {
+        self.schema = self.schema.with_unique_index(field_name);
+        self
+    }
+
+    pub fn key(mut self, key: PrimaryKey) -> SchemaBuilder {
+        self.schema = self.schema.with_key(key);
+        self
+    }
+
+    pub fn build(self) -> Schema {
+        self.schema
+    }
+}
+
+impl From<SchemaBuilder> for Schema {
+    fn from(builder: SchemaBuilder) -> Self {
+        builder.schema
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::Value;
+
+    use crate::error::KooError;
+    use crate::flexible_database::FlexibleDatabase;
+    use crate::model_builder::ModelBuilder;
+    use crate::validation::Validator;
+
+    fn index_names(db: &FlexibleDatabase, table: &str) -> Vec<String> {
+        db.conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'index' AND tbl_name = ? AND sql IS NOT NULL ORDER BY name")
+            .unwrap()
+            .query_map([table], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn fields_are_added_in_column_order() {
+        let schema = Schema::builder("users")
+            .text("name")
+            .integer("age")
+            .real("height")
+            .boolean("active")
+            .reference("team", "teams")
+            .nullable("bio", FieldType::Text)
+            .field("score", FieldDef::new(FieldType::Integer).validate(Validator::Min(0.0)))
+            .build();
+        let fields: Vec<(&str, &FieldType)> = schema.fields.iter().map(|(name, t)| (name.as_str(), t)).collect();
+        assert_eq!(
+            fields,
+            vec![
+                ("name", &FieldType::Text),
+                ("age", &FieldType::Integer),
+                ("height", &FieldType::Real),
+                ("active", &FieldType::Boolean),
+                ("team", &FieldType::Reference("teams".to_string())),
+                ("bio", &FieldType::Text),
+                ("score", &FieldType::Integer),
+            ]
+        );
+        assert_eq!(schema.nullable_fields, vec!["bio".to_string()]);
+        assert_eq!(schema.validators["score"], vec![Validator::Min(0.0)]);
+        let converted: Schema = Schema::builder("users").text("name").into();
+        assert_eq!(converted.fields.len(), 1);
+    }
+
+    #[test]
+    fn nullable_fields_default_to_null() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(
+            Schema::builder("users")
+                .text("name")
+                .nullable("bio", FieldType::Text)
+                .build(),
+        )
+        .unwrap();
+        let id = db
+            .create_model("users", ModelBuilder::new().text("name", "Ada").build())
+            .unwrap();
+        let ada = db.get_model("users", id.clone()).unwrap().unwrap();
+        assert_eq!(ada.data["bio"], Value::Null);
+        // Required fields still are
+        assert!(
+            db.create_model("users", ModelBuilder::new().text("bio", "hi").build())
+                .is_err()
+        );
+        db.update_model("users", id, ModelBuilder::new().null("bio").build())
+            .unwrap();
+    }
+
+    #[test]
+    fn indexes_are_created_with_the_table() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::builder("users")
+            .index("email")
+            .text("email")
+            .text("name")
+            .unique("email")
+            .index("name")
+            .index("name")
+            .build();
+        db.define_schema(schema).unwrap();
+        assert_eq!(
+            index_names(&db, "users"),
+            vec!["users_email_idx", "users_email_key", "users_name_idx"]
+        );
+        db.create_model(
+            "users",
+            ModelBuilder::new().text("email", "a@x").text("name", "a").build(),
+        )
+        .unwrap();
+        assert!(
+            db.create_model(
+                "users",
+                ModelBuilder::new().text("email", "a@x").text("name", "b").build()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn builders_are_checked_when_defined() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let unknown = Schema::builder("users").text("name").index("email").build();
+        assert!(matches!(
+            db.define_schema(unknown),
+            Err(KooError::UnknownField { field, .. }) if field == "email"
+        ));
+
+        let keyed = Schema::builder("pairs")
+            .integer("a")
+            .nullable("b", FieldType::Integer)
+            .key(PrimaryKey::Composite(vec!["a".to_string(), "b".to_string()]))
+            .build();
+        assert!(matches!(db.define_schema(keyed), Err(KooError::InvalidSchema(_))));
+    }
+}
@@ -0,0 +1,530 @@
+use rusqlite::types::Value;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::alter::mentions;
+use crate::diff::{FieldChange, IndexChange, SchemaDiff, field_indexes, index_definitions};
+use crate::error::{KooError, Result};
+use crate::flexible_database::{
+    FieldType, FlexibleDatabase, PrimaryKey, Schema, VERSION_COLUMN, sql_literal, sql_type,
+};
+
+// One change a `MigrationPlan` makes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationStep {
+    pub description: String,
+    // Whether the step loses stored data, such as the values of a dropped
+    // field
+    pub destructive: bool,
+    // Rows whose data a destructive step loses
+    pub affected_rows: i64,
+}
+
+// The steps that bring a defined schema to a new version, from
+// `plan_schema_change`, to look over before `apply_schema_change`
+#[derive(Debug, Clone)]
+pub struct MigrationPlan {
+    pub schema: String,
+    pub steps: Vec<MigrationStep>,
+    pub diff: SchemaDiff,
+    target: Schema,
+    // The definition the plan was made from, to notice later changes
+    planned_from: String,
+}
+
+impl MigrationPlan {
+    pub fn is_destructive(&self) -> bool {
+        self.steps.iter().any(|step| step.destructive)
+    }
+
+    pub fn destructive_steps(&self) -> impl Iterator<Item = &MigrationStep> {
+        self.steps.iter().filter(|step| step.destructive)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MigrationOptions {
+    // Go ahead with destructive steps, which are refused otherwise
+    pub allow_destructive: bool,
+    // First copy the key and every column a destructive step touches into
+    // a `_koo_backup_<schema>_<unix time>` table
+    pub backup: bool,
+}
+
+// Changing a schema's definition in place, where `define_schema` leaves an
+// existing table as it is. Steps that lose data, such as dropping a field
+// or giving it a type not all its values convert to, are flagged when
+// planning and refused unless allowed.
+impl FlexibleDatabase {
+    // What it would take to replace the definition of the defined schema
+    // named like `schema` with it. Nothing is changed.
+    pub fn plan_schema_change(&self, schema: Schema) -> Result<MigrationPlan> {
+        let target = schema.materialize()?;
+        let current = self.schema_or_err(&target.name)?;
+        let table = &target.name;
+        let mut diff = current.diff(&target);
+        diff.check_additions()?;
+
+        let removed: Vec<&str> = diff
+            .fields
+            .iter()
+            .filter_map(|change| match change {
+                FieldChange::Removed { field, .. } => Some(field.as_str()),
+                _ => None,
+            })
+            .collect();
+        // `replace_schema` drops and recreates the indexes of indexed fields
+        // itself, so only the table's other indexes are carried over
+        diff.indexes.clear();
+        diff.to_indexes.clear();
+        let field_indexes = field_indexes(current);
+        for index in index_definitions(&self.conn, table)? {
+            if field_indexes.iter().any(|field_index| field_index.name == index.name) {
+                continue;
+            }
+            match removed.iter().any(|field| mentions(&index.sql, field)) {
+                true => diff.indexes.push(IndexChange::Removed(index)),
+                false => diff.to_indexes.push(index),
+            }
+        }
+
+        let rows: i64 = self
+            .conn
+            .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))?;
+        let mut steps = Vec::new();
+        let mut step = |description: String, affected_rows: Option<i64>| {
+            steps.push(MigrationStep {
+                description,
+                destructive: affected_rows.is_some(),
+                affected_rows: affected_rows.unwrap_or(0),
+            })
+        };
+        for change in &diff.fields {
+            match change {
+                FieldChange::Added { field, field_type } => {
+                    step(format!("add '{}' ({})", field, field_type.name()), None);
+                }
+                FieldChange::Removed { field, .. } if current.is_computed(field) => {
+                    step(format!("drop computed field '{}'", field), None);
+                }
+                FieldChange::Removed { field, .. } => {
+                    step(format!("drop '{}' and its values", field), Some(rows));
+                }
+                FieldChange::Retyped { field, from, to } => {
+                    let lost = self.lossy_values(current, field, from, to, rows)?;
+                    step(
+                        format!(
+                            "change '{}' from {} to {}, which {} of its values don't convert to",
+                            field,
+                            from.name(),
+                            to.name(),
+                            lost
+                        ),
+                        Some(lost),
+                    );
+                }
+            }
+        }
+        if current.key != target.key {
+            step(
+                format!("change the key of '{}', giving its models new ids", table),
+                Some(rows),
+            );
+        }
+        if current.versioned && !target.versioned {
+            step("drop the version numbers of the models".to_string(), Some(rows));
+        }
+        for change in &diff.indexes {
+            if let IndexChange::Removed(index) = change {
+                step(
+                    format!("drop index '{}', which covers a dropped field", index.name),
+                    None,
+                );
+            }
+        }
+        for field_name in current.fields.keys().filter(|field| target.fields.contains_key(*field)) {
+            match (current.is_nullable(field_name), target.is_nullable(field_name)) {
+                (false, true) => step(format!("let '{}' be NULL", field_name), None),
+                (true, false) => step(format!("require '{}', which must hold no NULLs", field_name), None),
+                _ => {}
+            }
+        }
+        for field_name in &target.indexed_fields {
+            if !current.indexed_fields.contains(field_name) {
+                step(format!("index '{}'", field_name), None);
+            }
+        }
+        for field_name in &current.indexed_fields {
+            if !target.indexed_fields.contains(field_name) && target.fields.contains_key(field_name) {
+                step(format!("drop the index on '{}'", field_name), None);
+            }
+        }
+        for field_name in &target.unique_fields {
+            if !current.unique_fields.contains(field_name) {
+                step(format!("make '{}' unique, which fails on repeated values", field_name), None);
+            }
+        }
+        for field_name in &current.unique_fields {
+            if !target.unique_fields.contains(field_name) && target.fields.contains_key(field_name) {
+                step(format!("let '{}' repeat", field_name), None);
+            }
+        }
+        if !diff.settings.is_empty() {
+            step(format!("change the settings {}", diff.settings.join(", ")), None);
+        }
+        if diff.needs_rebuild() {
+            step(format!("rebuild '{}', copying its {} rows", table, rows), None);
+        }
+
+        Ok(MigrationPlan {
+            schema: target.name.clone(),
+            steps,
+            diff,
+            planned_from: current.to_json(),
+            target,
+        })
+    }
+
+    // Carry out a plan in one transaction, returning the name of the backup
+    // table if one was made. Fails if the plan has destructive steps that
+    // `options` doesn't allow, or the schema changed since it was planned.
+    pub fn apply_schema_change(&mut self, plan: &MigrationPlan, options: MigrationOptions) -> Result<Option<String>> {
+        if plan.is_destructive() && !options.allow_destructive {
+            let steps: Vec<&str> = plan.destructive_steps().map(|step| step.description.as_str()).collect();
+            return Err(KooError::InvalidSchema(format!(
+                "changing '{}' would {}; allow destructive changes to go ahead",
+                plan.schema,
+                steps.join(" and ")
+            )));
+        }
+        let current = self.schema_or_err(&plan.schema)?;
+        if current.to_json() != plan.planned_from {
+            return Err(KooError::InvalidSchema(format!(
+                "'{}' has changed since the plan was made",
+                plan.schema
+            )));
+        }
+
+        let (from, to) = (current.clone(), &plan.target);
+        let diff = &plan.diff;
+        let rebuild = diff.needs_rebuild();
+        // Columns copied by a rebuild, converting those given a new type
+        let mut columns = Vec::new();
+        if from.key.has_id_column() && from.key == to.key {
+            columns.push(("id".to_string(), "id".to_string()));
+        }
+        for (field_name, field_type) in &from.fields {
+            let Some(new_type) = to.fields.get(field_name) else {
+                continue;
+            };
+            if from.is_computed(field_name) || to.is_computed(field_name) {
+                continue;
+            }
+            let old = match sql_type(field_type) == sql_type(new_type) {
+                true => field_name.clone(),
+                false => format!("CAST({} AS {})", field_name, sql_type(new_type)),
+            };
+            columns.push((old, field_name.clone()));
+        }
+        if from.versioned && to.versioned {
+            columns.push((VERSION_COLUMN.to_string(), VERSION_COLUMN.to_string()));
+        }
+
+        self.in_transaction(|db| {
+            let backup = match options.backup && plan.is_destructive() {
+                true => Some(db.back_up_columns(&from, to)?),
+                false => None,
+            };
+            db.replace_schema(
+                to.clone(),
+                |db| {
+                    if rebuild {
+                        return Ok(true);
+                    }
+                    for sql in diff.in_place_statements() {
+                        db.conn.execute_batch(&sql)?;
+                    }
+                    Ok(false)
+                },
+                &columns,
+            )?;
+            // A rebuilt table starts without the indexes made outside kooDB
+            if rebuild {
+                for index in &diff.to_indexes {
+                    db.conn.execute_batch(&index.sql)?;
+                }
+            }
+            Ok(backup)
+        })
+    }
+
+    // How many values of `field_name` would change if converted to `to`
+    fn lossy_values(
+        &self,
+        schema: &Schema,
+        field_name: &str,
+        from: &FieldType,
+        to: &FieldType,
+        rows: i64,
+    ) -> Result<i64> {
+        // Ciphertext doesn't convert to anything
+        if schema.encrypted_fields.iter().any(|f| f == field_name) {
+            return Ok(rows);
+        }
+        let condition = if sql_type(from) != sql_type(to) {
+            format!(
+                "CAST(CAST({f} AS {new}) AS {old}) IS NOT {f}",
+                f = field_name,
+                new = sql_type(to),
+                old = sql_type(from)
+            )
+        } else {
+            match to {
+                FieldType::Enum(allowed) => {
+                    let allowed: Vec<String> = allowed.iter().map(|v| sql_literal(&Value::Text(v.clone()))).collect();
+                    format!("{} NOT IN ({})", field_name, allowed.join(", "))
+                }
+                FieldType::Reference(target) => format!("{} NOT IN (SELECT id FROM {})", field_name, target),
+                FieldType::Boolean => format!("{} NOT IN (0, 1)", field_name),
+                _ => return Ok(0),
+            }
+        };
+        Ok(self.conn.query_row(
+            &format!("SELECT COUNT(*) FROM {} WHERE {}", schema.name, condition),
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    // Copy the key and the columns `to` drops or retypes into a new table,
+    // returning its name
+    fn back_up_columns(&self, from: &Schema, to: &Schema) -> Result<String> {
+        let mut columns: Vec<String> = match &from.key {
+            PrimaryKey::Composite(key_fields) => key_fields.clone(),
+            _ => vec!["id".to_string()],
+        };
+        for (field_name, field_type) in &from.fields {
+            let changed = to.fields.get(field_name).is_none_or(|new_type| new_type != field_type);
+            if changed && !from.is_computed(field_name) && !columns.contains(field_name) {
+                columns.push(field_name.clone());
+            }
+        }
+        if from.versioned && !to.versioned {
+            columns.push(VERSION_COLUMN.to_string());
+        }
+
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let base = format!("_koo_backup_{}_{}", from.name.replace('.', "_"), stamp);
+        let mut name = base.clone();
+        let mut n = 1;
+        while self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE name = ?)",
+            [&name],
+            |row| row.get::<_, bool>(0),
+        )? {
+            n += 1;
+            name = format!("{}_{}", base, n);
+        }
+        self.conn.execute_batch(&format!(
+            "CREATE TABLE {} AS SELECT {} FROM {}",
+            name,
+            columns.join(", "),
+            from.name
+        ))?;
+        Ok(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::flexible_database::{FieldDef, ModelId};
+
+    fn notes() -> Schema {
+        Schema::new("notes", [])
+            .field("title", FieldDef::new(FieldType::Text))
+            .field("body", FieldDef::new(FieldType::Text))
+            .field("rank", FieldDef::new(FieldType::Text))
+    }
+
+    fn database() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(notes()).unwrap();
+        for (title, rank) in [("first", "12"), ("second", "high")] {
+            let data = HashMap::from([
+                ("title".to_string(), Value::Text(title.to_string())),
+                ("body".to_string(), Value::Text(format!("{} body", title))),
+                ("rank".to_string(), Value::Text(rank.to_string())),
+            ]);
+            db.create_model("notes", data).unwrap();
+        }
+        db
+    }
+
+    fn columns(db: &FlexibleDatabase, table: &str) -> Vec<String> {
+        db.conn
+            .prepare(&format!("SELECT name FROM pragma_table_info('{}')", table))
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    fn without(field_name: &str) -> Schema {
+        let mut schema = Schema::new("notes", []);
+        for (name, field_type) in &notes().fields {
+            if name != field_name {
+                schema = schema.field(name, FieldDef::new(field_type.clone()));
+            }
+        }
+        schema
+    }
+
+    #[test]
+    fn added_fields_go_ahead_without_permission() {
+        let mut db = database();
+        let target = notes().field("pinned", FieldDef::new(FieldType::Boolean).with_default(false));
+        let plan = db.plan_schema_change(target).unwrap();
+        assert!(!plan.is_destructive());
+        assert_eq!(plan.steps[0].description, "add 'pinned' (Boolean)");
+        // Planning changes nothing
+        assert!(!columns(&db, "notes").contains(&"pinned".to_string()));
+
+        assert_eq!(
+            db.apply_schema_change(&plan, MigrationOptions::default()).unwrap(),
+            None
+        );
+        assert!(db.schemas["notes"].fields.contains_key("pinned"));
+        let note = db.get_model("notes", 1).unwrap().unwrap();
+        assert_eq!(note.data["pinned"], Value::Integer(0));
+    }
+
+    #[test]
+    fn dropped_fields_need_permission_and_can_be_backed_up() {
+        let mut db = database();
+        let plan = db.plan_schema_change(without("body")).unwrap();
+        let destructive: Vec<_> = plan.destructive_steps().collect();
+        assert_eq!(destructive.len(), 1);
+        assert_eq!(destructive[0].description, "drop 'body' and its values");
+        assert_eq!(destructive[0].affected_rows, 2);
+
+        let err = db.apply_schema_change(&plan, MigrationOptions::default()).unwrap_err();
+        assert!(
+            matches!(err, KooError::InvalidSchema(ref message) if message.contains("drop 'body'")),
+            "{:?}",
+            err
+        );
+        assert!(columns(&db, "notes").contains(&"body".to_string()));
+
+        let options = MigrationOptions {
+            allow_destructive: true,
+            backup: true,
+        };
+        let backup = db.apply_schema_change(&plan, options).unwrap().unwrap();
+        assert!(backup.starts_with("_koo_backup_notes_"), "{}", backup);
+        assert!(!columns(&db, "notes").contains(&"body".to_string()));
+        assert_eq!(columns(&db, &backup), vec!["id", "body"]);
+        let body: String = db
+            .conn
+            .query_row(&format!("SELECT body FROM {} WHERE id = 2", backup), [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(body, "second body");
+    }
+
+    #[test]
+    fn new_types_count_the_values_they_lose() {
+        let mut db = database();
+        let target = without("rank").field("rank", FieldDef::new(FieldType::Integer).nullable());
+        let plan = db.plan_schema_change(target).unwrap();
+        let step = plan.destructive_steps().next().unwrap();
+        assert_eq!(step.affected_rows, 1, "{:?}", step);
+        assert!(
+            plan.steps
+                .iter()
+                .any(|step| step.description.starts_with("rebuild 'notes'"))
+        );
+
+        let options = MigrationOptions {
+            allow_destructive: true,
+            backup: false,
+        };
+        assert_eq!(db.apply_schema_change(&plan, options).unwrap(), None);
+        let note = db.get_model("notes", 1).unwrap().unwrap();
+        assert_eq!(note.data["rank"], Value::Integer(12));
+        assert_eq!(db.count("notes").unwrap(), 2);
+    }
+
+    #[test]
+    fn narrower_enums_count_the_values_outside_them() {
+        let db = database();
+        let ranks = FieldType::Enum(vec!["low".to_string(), "high".to_string()]);
+        let target = without("rank").field("rank", FieldDef::new(ranks));
+        let plan = db.plan_schema_change(target).unwrap();
+        assert_eq!(
+            plan.destructive_steps()
+                .map(|step| step.affected_rows)
+                .collect::<Vec<_>>(),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn plans_go_stale_when_the_schema_changes() {
+        let mut db = database();
+        let plan = db.plan_schema_change(without("body")).unwrap();
+        let other = notes().field("pinned", FieldDef::new(FieldType::Boolean).with_default(false));
+        let other = db.plan_schema_change(other).unwrap();
+        db.apply_schema_change(&other, MigrationOptions::default()).unwrap();
+
+        let options = MigrationOptions {
+            allow_destructive: true,
+            backup: false,
+        };
+        assert!(matches!(
+            db.apply_schema_change(&plan, options),
+            Err(KooError::InvalidSchema(message)) if message.contains("changed since")
+        ));
+    }
+
+    #[test]
+    fn outside_indexes_are_kept_unless_they_cover_a_dropped_field() {
+        let mut db = database();
+        db.conn
+            .execute_batch("CREATE INDEX notes_by_title ON notes (title)")
+            .unwrap();
+        db.conn
+            .execute_batch("CREATE INDEX notes_by_body ON notes (body)")
+            .unwrap();
+        let target = without("body")
+            .field("title", FieldDef::new(FieldType::Text).nullable())
+            .field("rank", FieldDef::new(FieldType::Text).indexed());
+        let plan = db.plan_schema_change(target).unwrap();
+        assert!(
+            plan.steps
+                .iter()
+                .any(|step| step.description == "drop index 'notes_by_body', which covers a dropped field")
+        );
+        assert!(plan.steps.iter().any(|step| step.description == "let 'title' be NULL"));
+        assert!(plan.steps.iter().any(|step| step.description == "index 'rank'"));
+
+        let options = MigrationOptions {
+            allow_destructive: true,
+            backup: false,
+        };
+        db.apply_schema_change(&plan, options).unwrap();
+        let indexes: Vec<String> = index_definitions(&db.conn, "notes")
+            .unwrap()
+            .into_iter()
+            .map(|index| index.name)
+            .collect();
+        assert_eq!(indexes, vec!["notes_by_title", "notes_rank_idx"]);
+        assert!(db.get_model("notes", ModelId::Integer(2)).unwrap().is_some());
+    }
+}
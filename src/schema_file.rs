@@ -0,0 +1,200 @@
+use std::path::Path;
+
+use crate::error::{KooError, Result};
+use crate::export::schema_to_json;
+use crate::flexible_database::{FlexibleDatabase, Schema};
+use crate::import::schema_from_json;
+
+// Settings a schema object may have; "version" and "rows" are accepted so
+// exported schemas can be used as definitions too
+const SCHEMA_SETTINGS: &[&str] = &[
+    "version",
+    "name",
+    "fields",
+    "key",
+    "versioned",
+    "sql_checks",
+    "tenant_scoped",
+    "history",
+    "hierarchical",
+    "append_only",
+    "uuid",
+    "defaults",
+    "computed",
+    "expiry",
+    "validators",
+    "fts",
+    "encrypted",
+    "nullable",
+    "indexes",
+    "unique",
+    "many_to_many",
+    "rows",
+];
+
+impl Schema {
+    // Parse a schema written in the `export_schema_json` format, e.g.
+    // {"name": "users", "fields": {"name": "Text", "age": "Integer"}}.
+    // The schema is checked as `define_schema` would, and unknown settings
+    // are rejected so a misspelt one doesn't go unnoticed.
+    pub fn from_json(source: &str) -> Result<Schema> {
+        let entry: serde_json::Value = serde_json::from_str(source)?;
+        parse_definition(&entry)
+    }
+
+    // Inverse of `from_json`
+    pub fn to_json(&self) -> String {
+        serde_json::Value::Object(schema_to_json(self)).to_string()
+    }
+}
+
+impl FlexibleDatabase {
+    // Define every schema in a JSON file, or a YAML one (.yaml or .yml) with
+    // the "yaml" feature. The file holds one schema object, a list of them
+    // or an `export_json` document; rows in it are ignored. Errors name the
+    // file and the offending schema, and syntax errors their line. Nothing
+    // is defined unless every schema is valid. Returns the number of
+    // schemas defined.
+    pub fn define_schemas_from_file<P: AsRef<Path>>(&mut self, path: P) -> Result<usize> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)?;
+        let located = |message: String| KooError::InvalidSchema(format!("{}: {}", path.display(), message));
+
+        let document = parse_document(path, &source).map_err(located)?;
+
+        let entries = match &document {
+            serde_json::Value::Array(entries) => entries.as_slice(),
+            serde_json::Value::Object(object) => match object.get("schemas") {
+                Some(serde_json::Value::Array(entries)) => entries.as_slice(),
+                Some(_) => return Err(located("'schemas' must be a list".to_string())),
+                None => std::slice::from_ref(&document),
+            },
+            _ => return Err(located("expected a schema or a list of schemas".to_string())),
+        };
+
+        let mut schemas = Vec::new();
+        for (i, entry) in entries.iter().enumerate() {
+            let schema = parse_definition(entry).map_err(|err| {
+                let name = entry.get("name").and_then(|name| name.as_str()).unwrap_or("unnamed");
+                located(format!("schema {} ('{}'): {}", i + 1, name, message(err)))
+            })?;
+            schemas.push(schema);
+        }
+
+        let count = schemas.len();
+        self.in_transaction(|db| {
+            for schema in schemas {
+                db.define_schema(schema)?;
+            }
+            Ok(count)
+        })
+    }
+}
+
+fn parse_definition(entry: &serde_json::Value) -> Result<Schema> {
+    let object = entry
+        .as_object()
+        .ok_or_else(|| KooError::InvalidSchema("a schema must be an object".to_string()))?;
+    if let Some(setting) = object.keys().find(|key| !SCHEMA_SETTINGS.contains(&key.as_str())) {
+        return Err(KooError::InvalidSchema(format!("unknown setting '{}'", setting)));
+    }
+
+    let schema = schema_from_json(entry)?;
+    schema.materialize()?;
+    Ok(schema)
+}
+
+// Error text without the kind prefix, which the file error already has
+fn message(err: KooError) -> String {
+    match err {
+        KooError::InvalidSchema(message) | KooError::InvalidData(message) => message,
+        err => err.to_string(),
+    }
+}
+
+// The JSON or YAML in `source`, by the extension of `path`
+pub(crate) fn parse_document(path: &Path, source: &str) -> std::result::Result<serde_json::Value, String> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("yaml" | "yml") => parse_yaml(source),
+        _ => serde_json::from_str(source).map_err(|err| err.to_string()),
+    }
+}
+
+#[cfg(feature = "yaml")]
+fn parse_yaml(source: &str) -> std::result::Result<serde_json::Value, String> {
+    // serde_yaml's messages include the line and column
+    serde_yaml::from_str(source).map_err(|err| err.to_string())
+}
+
+#[cfg(not(feature = "yaml"))]
+fn parse_yaml(_source: &str) -> std::result::Result<serde_json::Value, String> {
+    Err("reading YAML needs the \"yaml\" feature".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flexible_database::FieldType;
+    use crate::temp_file::TempFile;
+
+    fn schema_error(result: Result<usize>) -> String {
+        match result {
+            Err(KooError::InvalidSchema(message)) => message,
+            other => panic!("expected a schema error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn schemas_round_trip_through_json() {
+        let schema =
+            Schema::from_json(r#"{"name": "users", "fields": {"name": "Text", "team": "Reference(teams)"}}"#).unwrap();
+        assert_eq!(schema.fields["team"], FieldType::Reference("teams".to_string()));
+        let again = Schema::from_json(&schema.to_json()).unwrap();
+        assert_eq!(again.fields, schema.fields);
+    }
+
+    #[test]
+    fn misspelt_settings_are_refused() {
+        let result = Schema::from_json(r#"{"name": "users", "fields": {}, "versoined": true}"#);
+        assert!(matches!(result, Err(KooError::InvalidSchema(message)) if message.contains("versoined")));
+    }
+
+    #[test]
+    fn files_define_every_schema_in_them() {
+        let file = TempFile::with_contents(
+            "json",
+            r#"[{"name": "teams", "fields": {"name": "Text"}},
+                {"name": "users", "fields": {"name": "Text", "team": "Reference(teams)"}}]"#,
+        );
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        assert_eq!(db.define_schemas_from_file(file.path()).unwrap(), 2);
+        assert!(db.schema("users").is_some());
+    }
+
+    #[test]
+    fn invalid_files_define_nothing_and_say_where() {
+        let file = TempFile::with_contents(
+            "json",
+            r#"{"schemas": [{"name": "teams", "fields": {"name": "Text"}},
+                {"name": "users", "fields": {"name": "Decimal"}}]}"#,
+        );
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let message = schema_error(db.define_schemas_from_file(file.path()));
+        assert!(message.contains(file.path()));
+        assert!(message.contains("schema 2 ('users')"));
+        assert!(db.schemas().is_empty());
+
+        let file = TempFile::with_contents("json", "[{\"name\": \"teams\",\n \"fields\": }]");
+        let message = schema_error(db.define_schemas_from_file(file.path()));
+        assert!(message.contains("line 2"));
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn yaml_files_are_read_too() {
+        let file = TempFile::with_contents("yaml", "name: notes\nfields:\n  body: Text\n  rank: Integer\n");
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        assert_eq!(db.define_schemas_from_file(file.path()).unwrap(), 1);
+        assert_eq!(db.schema("notes").unwrap().fields["rank"], FieldType::Integer);
+    }
+}
@@ -0,0 +1,292 @@
+// Named by the macro's expansions, so crates using it needn't depend on
+// these themselves
+#[doc(hidden)]
+pub use paste::paste;
+#[doc(hidden)]
+pub use rusqlite::types::Value;
+
+// A schema written out as a declaration, so field types and settings are
+// checked when the calling crate compiles rather than when the schema is
+// defined:
+//
+//     let users = schema! {
+//         users {
+//             name: Text,
+//             age: Integer,
+//             email: Text unique,
+//             bio: Text nullable,
+//             team: Reference(teams) indexed,
+//         }
+//     };
+//
+// Each field has a FieldType, with `Reference(schema)` naming its target,
+// `Vector(dimensions)` its size and `Enum(a, b, ...)` its values, followed
+// by any of `nullable`, `indexed` and `unique`.
+//
+// Written as a struct named after the schema, it declares that struct
+// instead, with an `id` and a field of the Rust type each schema field is
+// read as: String, i64, f64 and bool for Text, Integer, Real and Boolean,
+// the target's id for a Reference, and an Option of it when nullable.
+// Other field types can't be used in a struct.
+//
+//     schema! {
+//         #[derive(Debug, Clone, PartialEq)]
+//         pub struct User in users {
+//             name: Text,
+//             email: Text unique,
+//             bio: Text nullable,
+//         }
+//     }
+//
+// `User::schema()` gives the schema, `User::try_from(&model)` reads a model
+// of it, strictly as `Model::get_as` does, and `HashMap::from(&user)` gives
+// the data to create or update one with; the struct is a `TypedModel`.
+// Each field also gets a marker named in upper case, such as `User::EMAIL`,
+// for typed queries, and a repository type named after it, `UserRepo`:
+//
+//     let found = User::query().filter(User::EMAIL.eq("ada@example.com")).first(&db)?;
+//     UserRepo::new(&db).delete(found.unwrap().id.unwrap())?;
+#[macro_export]
+macro_rules! schema {
+    (@field_type Reference($target:ident)) => {
+        $crate::flexible_database::FieldType::Reference(stringify!($target).to_string())
+    };
+    (@field_type Vector($dimensions:literal)) => {
+        $crate::flexible_database::FieldType::Vector($dimensions)
+    };
+    (@field_type Enum($($variant:ident),* $(,)?)) => {
+        $crate::flexible_database::FieldType::Enum(vec![$(stringify!($variant).to_string()),*])
+    };
+    (@field_type $field_type:ident) => {
+        $crate::flexible_database::FieldType::$field_type
+    };
+
+    (@field_def $field:expr;) => {
+        $field
+    };
+    (@field_def $field:expr; nullable $($rest:ident)*) => {
+        $crate::schema!(@field_def $field.nullable(); $($rest)*)
+    };
+    (@field_def $field:expr; indexed $($rest:ident)*) => {
+        $crate::schema!(@field_def $field.indexed(); $($rest)*)
+    };
+    (@field_def $field:expr; unique $($rest:ident)*) => {
+        $crate::schema!(@field_def $field.unique(); $($rest)*)
+    };
+    (@field_def $field:expr; $setting:ident $($rest:ident)*) => {
+        compile_error!(concat!(
+            "unknown field setting `",
+            stringify!($setting),
+            "`, expected `nullable`, `indexed` or `unique`"
+        ))
+    };
+
+    (@rust_type $field_type:ident $(($($argument:tt)*))?; nullable $($rest:ident)*) => {
+        ::std::option::Option<$crate::schema!(@rust_type $field_type $(($($argument)*))?;)>
+    };
+    (@rust_type $field_type:ident $(($($argument:tt)*))?; $setting:ident $($rest:ident)*) => {
+        $crate::schema!(@rust_type $field_type $(($($argument)*))?; $($rest)*)
+    };
+    (@rust_type Text;) => { ::std::string::String };
+    (@rust_type Integer;) => { i64 };
+    (@rust_type Real;) => { f64 };
+    (@rust_type Boolean;) => { bool };
+    (@rust_type Reference($target:ident);) => { i64 };
+
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $struct_name:ident in $name:ident {
+            $($field:ident: $field_type:ident $(($($argument:tt)*))? $($setting:ident)*),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $struct_name {
+            pub id: ::std::option::Option<$crate::flexible_database::ModelId>,
+            $(pub $field: $crate::schema!(@rust_type $field_type $(($($argument)*))?; $($setting)*),)*
+        }
+
+        $crate::schema_macro::paste! {
+            impl $struct_name {
+                pub const SCHEMA_NAME: &'static str = stringify!($name);
+
+                $(pub const [<$field:upper>]: $crate::typed::Field<
+                    $crate::schema!(@rust_type $field_type $(($($argument)*))?; $($setting)*)
+                > = $crate::typed::Field::new(stringify!($field));)*
+
+                pub fn schema() -> $crate::flexible_database::Schema {
+                    $crate::schema!($name { $($field: $field_type $(($($argument)*))? $($setting)*),* })
+                }
+
+                pub fn query() -> $crate::typed::TypedQuery<$struct_name> {
+                    $crate::typed::TypedQuery::new()
+                }
+            }
+
+            $vis type [<$struct_name Repo>]<'db> = $crate::typed::Repository<'db, $struct_name>;
+        }
+
+        impl $crate::typed::TypedModel for $struct_name {
+            const SCHEMA_NAME: &'static str = stringify!($name);
+
+            fn schema() -> $crate::flexible_database::Schema {
+                $struct_name::schema()
+            }
+
+            fn id(&self) -> ::std::option::Option<&$crate::flexible_database::ModelId> {
+                self.id.as_ref()
+            }
+
+            fn set_id(&mut self, id: $crate::flexible_database::ModelId) {
+                self.id = Some(id);
+            }
+
+            fn from_model(model: &$crate::flexible_database::Model) -> $crate::error::Result<Self> {
+                Ok($struct_name {
+                    id: model.id.clone(),
+                    $($field: model.get_as(stringify!($field))?,)*
+                })
+            }
+
+            fn data(&self) -> ::std::collections::HashMap<::std::string::String, $crate::schema_macro::Value> {
+                let mut data = ::std::collections::HashMap::new();
+                $(data.insert(
+                    stringify!($field).to_string(),
+                    $crate::schema_macro::Value::from(self.$field.clone()),
+                );)*
+                data
+            }
+        }
+
+        impl ::std::convert::TryFrom<&$crate::flexible_database::Model> for $struct_name {
+            type Error = $crate::error::KooError;
+
+            fn try_from(model: &$crate::flexible_database::Model) -> $crate::error::Result<Self> {
+                <$struct_name as $crate::typed::TypedModel>::from_model(model)
+            }
+        }
+
+        impl ::std::convert::From<&$struct_name>
+            for ::std::collections::HashMap<::std::string::String, $crate::schema_macro::Value>
+        {
+            fn from(model: &$struct_name) -> Self {
+                $crate::typed::TypedModel::data(model)
+            }
+        }
+    };
+
+    ($name:ident { $($field:ident: $field_type:ident $(($($argument:tt)*))? $($setting:ident)*),* $(,)? }) => {{
+        let schema = $crate::flexible_database::Schema::new(stringify!($name), []);
+        $(
+            let schema = schema.field(
+                stringify!($field),
+                $crate::schema!(
+                    @field_def
+                    $crate::flexible_database::FieldDef::new($crate::schema!(@field_type $field_type $(($($argument)*))?));
+                    $($setting)*
+                ),
+            );
+        )*
+        schema
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::error::KooError;
+    use crate::flexible_database::{FieldType, FlexibleDatabase, Model, ModelId};
+
+    crate::schema! {
+        #[derive(Debug, Clone, PartialEq)]
+        pub struct Player in players {
+            name: Text unique,
+            level: Integer indexed,
+            rating: Real,
+            active: Boolean,
+            nickname: Text nullable,
+            team: Reference(teams) nullable,
+        }
+    }
+
+    #[test]
+    fn declarations_give_schemas() {
+        let schema = crate::schema! {
+            tiles {
+                label: Text indexed unique,
+                kind: Enum(floor, wall),
+                shape: Vector(3) nullable,
+                owner: Reference(players),
+            }
+        };
+        assert_eq!(schema.name, "tiles");
+        let fields: Vec<(&str, &FieldType)> = schema.fields.iter().map(|(name, t)| (name.as_str(), t)).collect();
+        assert_eq!(
+            fields,
+            vec![
+                ("label", &FieldType::Text),
+                ("kind", &FieldType::Enum(vec!["floor".to_string(), "wall".to_string()])),
+                ("shape", &FieldType::Vector(3)),
+                ("owner", &FieldType::Reference("players".to_string())),
+            ]
+        );
+        assert_eq!(schema.indexed_fields, vec!["label".to_string()]);
+        assert_eq!(schema.unique_fields, vec!["label".to_string()]);
+        assert_eq!(schema.nullable_fields, vec!["shape".to_string()]);
+    }
+
+    #[test]
+    fn structs_know_their_schema() {
+        let schema = Player::schema();
+        assert_eq!(Player::SCHEMA_NAME, "players");
+        assert_eq!(schema.name, "players");
+        assert_eq!(schema.fields.len(), 6);
+        assert_eq!(schema.unique_fields, vec!["name".to_string()]);
+        assert_eq!(schema.indexed_fields, vec!["level".to_string()]);
+        assert_eq!(schema.nullable_fields, vec!["nickname".to_string(), "team".to_string()]);
+    }
+
+    #[test]
+    fn structs_round_trip_through_models() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(crate::schema!(teams { name: Text })).unwrap();
+        db.define_schema(Player::schema()).unwrap();
+        let team = db
+            .create_model(
+                "teams",
+                HashMap::from([("name".to_string(), "reds".to_string().into())]),
+            )
+            .unwrap();
+        let ModelId::Integer(team) = team else {
+            panic!("{:?}", team)
+        };
+
+        let player = Player {
+            id: None,
+            name: "ada".to_string(),
+            level: 3,
+            rating: 4.5,
+            active: true,
+            nickname: None,
+            team: Some(team),
+        };
+        let id = db.create_model("players", HashMap::from(&player)).unwrap();
+        let model = db.get_model("players", id.clone()).unwrap().unwrap();
+        let read = Player::try_from(&model).unwrap();
+        assert_eq!(read, Player { id: Some(id), ..player });
+        assert_eq!(PlayerRepo::new(&db).count().unwrap(), 1);
+    }
+
+    #[test]
+    fn models_that_dont_fit_are_refused() {
+        let data = HashMap::from([
+            ("name".to_string(), "ada".to_string().into()),
+            ("level".to_string(), "high".to_string().into()),
+        ]);
+        let model = Model::new(Some(ModelId::Integer(1)), data);
+        assert!(matches!(
+            Player::try_from(&model),
+            Err(KooError::TypeMismatch { field, .. }) if field == "level"
+        ));
+    }
+}
@@ -0,0 +1,255 @@
+// Serde support, behind the "serde" feature. Values use their plain JSON
+// form: integers, reals, strings and null as themselves and blobs as byte
+// arrays. Booleans read back as the integers SQLite stores them as.
+
+use rusqlite::types::Value;
+use serde::de::{self, Deserializer};
+use serde::ser::{SerializeMap, SerializeSeq, Serializer};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::flexible_database::{FieldType, ModelId};
+use crate::validation::Validator;
+
+// `Value` is rusqlite's, so it goes through these wrappers
+struct ValueRef<'a>(&'a Value);
+
+impl Serialize for ValueRef<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            Value::Null => serializer.serialize_none(),
+            Value::Integer(i) => serializer.serialize_i64(*i),
+            Value::Real(f) => serializer.serialize_f64(*f),
+            Value::Text(s) => serializer.serialize_str(s),
+            Value::Blob(b) => serializer.serialize_bytes(b),
+        }
+    }
+}
+
+struct OwnedValue(Value);
+
+impl<'de> Deserialize<'de> for OwnedValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let json = serde_json::Value::deserialize(deserializer)?;
+        json_to_value(&json).map(OwnedValue).map_err(de::Error::custom)
+    }
+}
+
+fn json_to_value(json: &serde_json::Value) -> Result<Value, String> {
+    match json {
+        serde_json::Value::Null => Ok(Value::Null),
+        serde_json::Value::Bool(b) => Ok(Value::Integer(*b as i64)),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Ok(Value::Integer(i)),
+            None => n
+                .as_f64()
+                .map(Value::Real)
+                .ok_or_else(|| format!("{} is out of range", n)),
+        },
+        serde_json::Value::String(s) => Ok(Value::Text(s.clone())),
+        serde_json::Value::Array(bytes) => bytes
+            .iter()
+            .map(|b| b.as_u64().and_then(|b| u8::try_from(b).ok()))
+            .collect::<Option<Vec<u8>>>()
+            .map(Value::Blob)
+            .ok_or_else(|| "arrays must hold bytes".to_string()),
+        serde_json::Value::Object(_) => Err("objects can't be stored in a field".to_string()),
+    }
+}
+
+// `#[serde(with)]` helpers for maps of values, which are written sorted by
+// key so the output is the same on every run
+pub(crate) mod value_map {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(values: &HashMap<String, Value>, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut entries: Vec<_> = values.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut map = serializer.serialize_map(Some(entries.len()))?;
+        for (key, value) in entries {
+            map.serialize_entry(key, &ValueRef(value))?;
+        }
+        map.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<String, Value>, D::Error> {
+        let values = HashMap::<String, OwnedValue>::deserialize(deserializer)?;
+        Ok(values.into_iter().map(|(key, value)| (key, value.0)).collect())
+    }
+}
+
+pub(crate) mod validator_map {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        validators: &HashMap<String, Vec<Validator>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut entries: Vec<_> = validators.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut map = serializer.serialize_map(Some(entries.len()))?;
+        for (key, validators) in entries {
+            map.serialize_entry(key, validators)?;
+        }
+        map.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<String, Vec<Validator>>, D::Error> {
+        HashMap::deserialize(deserializer)
+    }
+}
+
+// Integer ids are numbers, text ids strings and composite ids arrays of
+// the key values
+impl Serialize for ModelId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ModelId::Integer(id) => serializer.serialize_i64(*id),
+            ModelId::Text(id) => serializer.serialize_str(id),
+            ModelId::Composite(values) => {
+                let mut seq = serializer.serialize_seq(Some(values.len()))?;
+                for value in values {
+                    seq.serialize_element(&ValueRef(value))?;
+                }
+                seq.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ModelId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match serde_json::Value::deserialize(deserializer)? {
+            serde_json::Value::Number(n) => n
+                .as_i64()
+                .map(ModelId::Integer)
+                .ok_or_else(|| de::Error::custom(format!("{} is not a valid id", n))),
+            serde_json::Value::String(id) => Ok(ModelId::Text(id)),
+            serde_json::Value::Array(values) => values
+                .iter()
+                .map(json_to_value)
+                .collect::<Result<Vec<_>, _>>()
+                .map(ModelId::Composite)
+                .map_err(de::Error::custom),
+            other => Err(de::Error::custom(format!("{} is not a valid id", other))),
+        }
+    }
+}
+
+// Field types use the names of `FieldType::name`, e.g. "Reference(users)"
+impl Serialize for FieldType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for FieldType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        FieldType::from_name(&name).ok_or_else(|| de::Error::custom(format!("unknown field type '{}'", name)))
+    }
+}
+
+// Validators use their export form, e.g. {"max_length": 10}
+impl Serialize for Validator {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_json().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Validator {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let json = serde_json::Value::deserialize(deserializer)?;
+        match Validator::from_json(&json) {
+            Some(validator) => validator.map_err(de::Error::custom),
+            None => Err(de::Error::custom(format!("unknown validator {}", json))),
+        }
+    }
+}
+
+pub(crate) fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flexible_database::{FieldDef, Model, Schema};
+
+    #[test]
+    fn models_serialize_to_plain_json() {
+        let data = HashMap::from([
+            ("name".to_string(), Value::Text("Ada".to_string())),
+            ("age".to_string(), Value::Integer(36)),
+            ("score".to_string(), Value::Real(1.5)),
+            ("photo".to_string(), Value::Blob(vec![1, 2])),
+            ("bio".to_string(), Value::Null),
+        ]);
+        let model = Model::new(Some(ModelId::Integer(7)), data);
+        let json = serde_json::to_string(&model).unwrap();
+        assert_eq!(
+            json,
+            r#"{"id":7,"data":{"age":36,"bio":null,"name":"Ada","photo":[1,2],"score":1.5}}"#
+        );
+
+        let read: Model = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.id, model.id);
+        assert_eq!(read.data, model.data);
+    }
+
+    #[test]
+    fn booleans_read_back_as_integers() {
+        let model: Model = serde_json::from_str(r#"{"id":"a","data":{"done":true}}"#).unwrap();
+        assert_eq!(model.id, Some(ModelId::Text("a".to_string())));
+        assert_eq!(model.data["done"], Value::Integer(1));
+    }
+
+    #[test]
+    fn objects_and_non_byte_arrays_are_not_values() {
+        assert!(serde_json::from_str::<Model>(r#"{"data":{"x":{"y":1}}}"#).is_err());
+        assert!(serde_json::from_str::<Model>(r#"{"data":{"x":[1,300]}}"#).is_err());
+    }
+
+    #[test]
+    fn composite_ids_are_arrays_of_key_values() {
+        let id = ModelId::Composite(vec![Value::Integer(1), Value::Text("x".to_string())]);
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, r#"[1,"x"]"#);
+        assert_eq!(serde_json::from_str::<ModelId>(&json).unwrap(), id);
+        assert!(serde_json::from_str::<ModelId>("1.5").is_err());
+    }
+
+    #[test]
+    fn schemas_round_trip_leaving_out_unset_settings() {
+        let schema = Schema::new("users", [("name".to_string(), FieldType::Text)])
+            .field(
+                "age",
+                FieldDef::new(FieldType::Integer)
+                    .with_default(18)
+                    .validate(Validator::Min(0.0)),
+            )
+            .field("team", FieldType::Reference("teams".to_string()));
+        let json = serde_json::to_value(&schema).unwrap();
+        assert_eq!(json["fields"]["team"], "Reference(teams)");
+        assert_eq!(json["validators"]["age"], serde_json::json!([{ "min": 0.0 }]));
+        assert!(json.get("versioned").is_none());
+
+        let read: Schema = serde_json::from_value(json).unwrap();
+        assert_eq!(read.fields, schema.fields);
+        assert_eq!(read.defaults, schema.defaults);
+        assert_eq!(read.validators, schema.validators);
+    }
+
+    #[test]
+    fn unknown_field_types_are_refused() {
+        assert!(serde_json::from_str::<FieldType>(r#""Decimal""#).is_err());
+        assert_eq!(
+            serde_json::from_str::<FieldType>(r#""Integer""#).unwrap(),
+            FieldType::Integer
+        );
+    }
+}
@@ -0,0 +1,409 @@
+use axum::Router;
+use axum::extract::{Path, Query as QueryParams, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use std::sync::{Arc, Mutex};
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FieldType, FlexibleDatabase, PrimaryKey, Schema};
+use crate::query::{Direction, Op, Query};
+use crate::wire::{data_from_json, model_id_from_str, model_to_json, value_from_str};
+
+// REST API over a FlexibleDatabase, behind the `server` feature. Models are
+// sent and received in the export row layout:
+//
+//   GET    /                 schema definitions
+//   GET    /{schema}         list models; see `list_models` for parameters
+//   POST   /{schema}         create a model from a JSON object
+//   GET    /{schema}/{id}    one model
+//   PUT    /{schema}/{id}    update the fields in a JSON object
+//   DELETE /{schema}/{id}    delete a model
+//
+// Composite ids are JSON arrays of the key values, e.g. /members/[1,"ann"].
+// Requests take turns on the single connection and run on tokio's blocking
+// threads, so a slow query doesn't stall the runtime.
+pub fn router(db: FlexibleDatabase) -> Router {
+    rest_routes(Arc::new(Mutex::new(db)))
+}
+
+// The REST API plus `POST /graphql`, which runs GraphQL requests against
+// `graphql_schema` on the same connection. Needs the `graphql` feature too.
+#[cfg(feature = "graphql")]
+pub fn router_with_graphql(db: FlexibleDatabase) -> Result<Router> {
+    let db = Arc::new(Mutex::new(db));
+    let schema = crate::graphql::graphql_schema(db.clone())?;
+    let graphql = Router::new()
+        .route("/graphql", axum::routing::post(execute_graphql))
+        .with_state(schema);
+    Ok(rest_routes(db).merge(graphql))
+}
+
+fn rest_routes(db: SharedDatabase) -> Router {
+    Router::new()
+        .route("/", get(list_schemas))
+        .route("/{schema}", get(list_models).post(create_model))
+        .route("/{schema}/{id}", get(get_model).put(update_model).delete(delete_model))
+        .with_state(db)
+}
+
+// Serve the API on `addr` (e.g. "127.0.0.1:3000") until the process stops
+pub async fn serve(db: FlexibleDatabase, addr: &str) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, router(db)).await?;
+    Ok(())
+}
+
+type SharedDatabase = Arc<Mutex<FlexibleDatabase>>;
+
+// A handler's outcome: the status and JSON body to send back
+type Reply = std::result::Result<(StatusCode, serde_json::Value), ApiError>;
+
+// {"error": "..."}, plus the individual problems for validation errors
+struct ApiError(StatusCode, serde_json::Value);
+
+impl<E: Into<KooError>> From<E> for ApiError {
+    fn from(err: E) -> ApiError {
+        let err = err.into();
+        let status = match &err {
+            KooError::SchemaNotFound(_) => StatusCode::NOT_FOUND,
+            KooError::AccessDenied { .. } => StatusCode::FORBIDDEN,
+            KooError::Busy { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            KooError::UnknownField { .. }
+            | KooError::MissingField { .. }
+            | KooError::Validation { .. }
+            | KooError::InvalidData(_)
+            | KooError::Json(_) => StatusCode::BAD_REQUEST,
+            KooError::StaleVersion { .. } | KooError::ForeignKeyViolation { .. } => StatusCode::CONFLICT,
+            KooError::Sqlite(rusqlite::Error::SqliteFailure(failure, _))
+                if failure.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                StatusCode::CONFLICT
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        let mut body = serde_json::json!({ "error": err.to_string() });
+        if let KooError::Validation { violations, .. } = &err {
+            body["violations"] = violations
+                .iter()
+                .map(|violation| serde_json::json!({ "field": violation.field, "message": violation.message }))
+                .collect();
+        }
+        ApiError(status, body)
+    }
+}
+
+async fn list_schemas(State(db): State<SharedDatabase>) -> Response {
+    with_db(db, |db| {
+        let schemas: Vec<serde_json::Value> = db
+            .schemas()
+            .into_iter()
+            .map(|schema| serde_json::from_str(&schema.to_json()))
+            .collect::<std::result::Result<_, _>>()?;
+        Ok((StatusCode::OK, schemas.into()))
+    })
+    .await
+}
+
+// Query parameters: `limit`, `offset`, `order=field` or `order=field:desc`
+// (may be repeated), and anything else is a filter on a field, either
+// `age=30` or `age[gte]=30` with eq, ne, lt, lte, gt or gte
+async fn list_models(
+    State(db): State<SharedDatabase>,
+    Path(schema_name): Path<String>,
+    QueryParams(params): QueryParams<Vec<(String, String)>>,
+) -> Response {
+    with_db(db, move |db| {
+        let schema = db.schema_or_err(&schema_name)?;
+        let query = query_from_params(schema, &params)?;
+        let models: Vec<serde_json::Value> = db
+            .find(&query)?
+            .iter()
+            .map(|model| model_to_json(schema, model))
+            .collect();
+        Ok((StatusCode::OK, models.into()))
+    })
+    .await
+}
+
+async fn create_model(State(db): State<SharedDatabase>, Path(schema_name): Path<String>, body: String) -> Response {
+    with_db(db, move |db| {
+        let schema = db.schema_or_err(&schema_name)?;
+        let data = data_from_json(schema, &serde_json::from_str(&body)?)?;
+        let id = db.create_model(&schema_name, data)?;
+        let model = db.get_model(&schema_name, id)?.ok_or_else(|| not_found(&schema_name))?;
+        Ok((
+            StatusCode::CREATED,
+            model_to_json(db.schema_or_err(&schema_name)?, &model),
+        ))
+    })
+    .await
+}
+
+async fn get_model(State(db): State<SharedDatabase>, Path((schema_name, id)): Path<(String, String)>) -> Response {
+    with_db(db, move |db| {
+        let schema = db.schema_or_err(&schema_name)?;
+        let id = model_id_from_str(schema, &id)?;
+        match db.get_model(&schema_name, id)? {
+            Some(model) => Ok((StatusCode::OK, model_to_json(schema, &model))),
+            None => Err(not_found(&schema_name)),
+        }
+    })
+    .await
+}
+
+async fn update_model(
+    State(db): State<SharedDatabase>,
+    Path((schema_name, id)): Path<(String, String)>,
+    body: String,
+) -> Response {
+    with_db(db, move |db| {
+        let schema = db.schema_or_err(&schema_name)?;
+        let id = model_id_from_str(schema, &id)?;
+        let data = data_from_json(schema, &serde_json::from_str(&body)?)?;
+        if !db.update_model(&schema_name, id.clone(), data)? {
+            return Err(not_found(&schema_name));
+        }
+        let model = db.get_model(&schema_name, id)?.ok_or_else(|| not_found(&schema_name))?;
+        Ok((StatusCode::OK, model_to_json(schema, &model)))
+    })
+    .await
+}
+
+async fn delete_model(State(db): State<SharedDatabase>, Path((schema_name, id)): Path<(String, String)>) -> Response {
+    with_db(db, move |db| {
+        let id = model_id_from_str(db.schema_or_err(&schema_name)?, &id)?;
+        if !db.delete_model(&schema_name, id)? {
+            return Err(not_found(&schema_name));
+        }
+        Ok((StatusCode::NO_CONTENT, serde_json::Value::Null))
+    })
+    .await
+}
+
+#[cfg(feature = "graphql")]
+async fn execute_graphql(
+    State(schema): State<async_graphql::dynamic::Schema>,
+    axum::Json(request): axum::Json<async_graphql::Request>,
+) -> axum::Json<async_graphql::Response> {
+    axum::Json(schema.execute(request).await)
+}
+
+// Run `handler` with the database on a blocking thread and turn its
+// outcome into a response
+async fn with_db<F>(db: SharedDatabase, handler: F) -> Response
+where
+    F: FnOnce(&FlexibleDatabase) -> Reply + Send + 'static,
+{
+    let reply = tokio::task::spawn_blocking(move || {
+        let db = db.lock().unwrap_or_else(|e| e.into_inner());
+        handler(&db)
+    })
+    .await;
+
+    match reply {
+        Ok(Ok((StatusCode::NO_CONTENT, _))) => StatusCode::NO_CONTENT.into_response(),
+        Ok(Ok((status, body))) => (status, axum::Json(body)).into_response(),
+        Ok(Err(ApiError(status, body))) => (status, axum::Json(body)).into_response(),
+        Err(err) => {
+            let body = serde_json::json!({ "error": err.to_string() });
+            (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(body)).into_response()
+        }
+    }
+}
+
+fn query_from_params(schema: &Schema, params: &[(String, String)]) -> Result<Query> {
+    let bad_param =
+        |key: &str, value: &str| KooError::InvalidData(format!("invalid query parameter {}={}", key, value));
+
+    let mut query = Query::new(&schema.name);
+    for (key, value) in params {
+        match key.as_str() {
+            "limit" => query = query.limit(value.parse().map_err(|_| bad_param(key, value))?),
+            "offset" => query = query.offset(value.parse().map_err(|_| bad_param(key, value))?),
+            "order" => {
+                let (field_name, direction) = match value.split_once(':') {
+                    None => (value.as_str(), Direction::Asc),
+                    Some((field_name, "asc")) => (field_name, Direction::Asc),
+                    Some((field_name, "desc")) => (field_name, Direction::Desc),
+                    Some(_) => return Err(bad_param(key, value)),
+                };
+                query = query.order_by(field_name, direction);
+            }
+            _ => {
+                let (field_name, op) = match key.split_once('[') {
+                    None => (key.as_str(), Op::Eq),
+                    Some((field_name, op)) => {
+                        let op = match op {
+                            "eq]" => Op::Eq,
+                            "ne]" => Op::Ne,
+                            "lt]" => Op::Lt,
+                            "lte]" => Op::Le,
+                            "gt]" => Op::Gt,
+                            "gte]" => Op::Ge,
+                            _ => return Err(bad_param(key, value)),
+                        };
+                        (field_name, op)
+                    }
+                };
+                let field_type = match (field_name, &schema.key) {
+                    ("id", PrimaryKey::Text) => &FieldType::Text,
+                    ("id", PrimaryKey::Integer) => &FieldType::Integer,
+                    _ => schema.fields.get(field_name).ok_or_else(|| KooError::UnknownField {
+                        schema: schema.name.clone(),
+                        field: field_name.to_string(),
+                    })?,
+                };
+                query = query.filter(
+                    field_name,
+                    op,
+                    value_from_str(value, field_type, &schema.name, field_name)?,
+                );
+            }
+        }
+    }
+    Ok(query)
+}
+
+fn not_found(schema_name: &str) -> ApiError {
+    let body = serde_json::json!({ "error": format!("no such model in '{}'", schema_name) });
+    ApiError(StatusCode::NOT_FOUND, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+
+    use crate::flexible_database::FieldDef;
+
+    fn people() -> SharedDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("people", [("name".to_string(), FieldType::Text)])
+            .field("age", FieldDef::new(FieldType::Integer))
+            .with_versioning();
+        db.define_schema(schema).unwrap();
+        Arc::new(Mutex::new(db))
+    }
+
+    // Status and JSON body of the response `handler` gives
+    fn call(handler: impl Future<Output = Response>) -> (StatusCode, serde_json::Value) {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let response = handler.await;
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let json = match body.is_empty() {
+                true => serde_json::Value::Null,
+                false => serde_json::from_slice(&body).unwrap(),
+            };
+            (status, json)
+        })
+    }
+
+    fn create(db: &SharedDatabase, body: &str) -> (StatusCode, serde_json::Value) {
+        call(create_model(
+            State(db.clone()),
+            Path("people".to_string()),
+            body.to_string(),
+        ))
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> QueryParams<Vec<(String, String)>> {
+        QueryParams(pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect())
+    }
+
+    #[test]
+    fn models_are_created_read_updated_and_deleted() {
+        let db = people();
+        let (status, created) = create(&db, r#"{"name": "Ada", "age": 36}"#);
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(created["name"], "Ada");
+        let id = created["id"].to_string();
+        let path = || Path(("people".to_string(), id.clone()));
+
+        let (status, read) = call(get_model(State(db.clone()), path()));
+        assert_eq!((status, read), (StatusCode::OK, created));
+
+        let body = r#"{"age": 37, "version": 1}"#.to_string();
+        let (status, updated) = call(update_model(State(db.clone()), path(), body.clone()));
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(updated["age"], 37);
+        assert_eq!(
+            call(update_model(State(db.clone()), path(), body)).0,
+            StatusCode::CONFLICT
+        );
+
+        assert_eq!(call(delete_model(State(db.clone()), path())).0, StatusCode::NO_CONTENT);
+        assert_eq!(call(get_model(State(db.clone()), path())).0, StatusCode::NOT_FOUND);
+        assert_eq!(call(delete_model(State(db), path())).0, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn models_are_listed_with_filters_order_and_paging() {
+        let db = people();
+        for (name, age) in [("Ada", 36), ("Bob", 20), ("Cy", 50), ("Di", 41)] {
+            create(&db, &format!(r#"{{"name": "{}", "age": {}}}"#, name, age));
+        }
+        let list = |pairs: &[(&str, &str)]| {
+            call(list_models(
+                State(db.clone()),
+                Path("people".to_string()),
+                params(pairs),
+            ))
+        };
+
+        let (status, models) = list(&[
+            ("age[gte]", "36"),
+            ("order", "age:desc"),
+            ("limit", "2"),
+            ("offset", "1"),
+        ]);
+        assert_eq!(status, StatusCode::OK);
+        let names: Vec<&str> = models
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["Di", "Ada"]);
+        assert_eq!(list(&[("name", "Bob")]).1.as_array().unwrap().len(), 1);
+
+        assert_eq!(list(&[("age[about]", "36")]).0, StatusCode::BAD_REQUEST);
+        assert_eq!(list(&[("limit", "many")]).0, StatusCode::BAD_REQUEST);
+        assert_eq!(list(&[("height", "2")]).0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn errors_map_to_statuses() {
+        let db = people();
+        let (status, body) = create(&db, r#"{"name": "Ada"}"#);
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().contains("age"));
+        assert_eq!(create(&db, "not json").0, StatusCode::BAD_REQUEST);
+
+        let missing = call(get_model(
+            State(db.clone()),
+            Path(("pets".to_string(), "1".to_string())),
+        ));
+        assert_eq!(missing.0, StatusCode::NOT_FOUND);
+
+        let (status, schemas) = call(list_schemas(State(db)));
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(schemas[0]["name"], "people");
+    }
+
+    #[test]
+    fn validation_errors_list_their_violations() {
+        let err = ApiError::from(KooError::Validation {
+            schema: "people".to_string(),
+            violations: vec![crate::validation::FieldViolation {
+                field: "age".to_string(),
+                message: "must be at least 0".to_string(),
+            }],
+        });
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        assert_eq!(err.1["violations"][0]["field"], "age");
+    }
+}
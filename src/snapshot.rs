@@ -0,0 +1,164 @@
+use rusqlite::{Connection, OpenFlags};
+use std::ops::Deref;
+
+use crate::error::{KooError, Result};
+use crate::field_encryption::register_decrypt;
+use crate::flexible_database::FlexibleDatabase;
+use crate::options::DatabaseOptions;
+
+// A read handle pinned at the moment it was taken, from
+// `FlexibleDatabase::snapshot`. It reads through its own read-only
+// connection, which holds a read transaction open, so a report run against
+// it sees one consistent state of the database while writes carry on
+// through the main handle. Anything that writes fails on it.
+//
+// The WAL can't be checkpointed past the snapshot while it is alive, so it
+// should be dropped once the reads are done.
+pub struct Snapshot {
+    db: FlexibleDatabase,
+}
+
+impl Deref for Snapshot {
+    type Target = FlexibleDatabase;
+
+    fn deref(&self) -> &FlexibleDatabase {
+        &self.db
+    }
+}
+
+impl FlexibleDatabase {
+    // Pin the database as last committed. Needs a database file in WAL
+    // mode, as in other modes a reader would block writers. Writes of a
+    // transaction still open on this handle aren't seen.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let path = match self.conn.path() {
+            Some(path) if !path.is_empty() => path,
+            _ => {
+                return Err(KooError::InvalidData(
+                    "snapshots need a database file, not an in-memory database".to_string(),
+                ));
+            }
+        };
+        let journal_mode: String = self.conn.query_row("PRAGMA journal_mode", [], |row| row.get(0))?;
+        if !journal_mode.eq_ignore_ascii_case("wal") {
+            return Err(KooError::InvalidData(format!(
+                "snapshots need the database in WAL mode, not {}; open it with JournalMode::Wal",
+                journal_mode
+            )));
+        }
+
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI,
+        )?;
+        let options = DatabaseOptions {
+            retry_policy: self.retry_policy,
+            ..DatabaseOptions::default()
+        };
+        let mut db = FlexibleDatabase::from_connection(conn, options)?;
+        register_decrypt(&db.conn, self.key_provider.clone())?;
+        db.key_provider = self.key_provider.clone();
+        db.access_policy = self.access_policy.clone();
+        db.caller = self.caller.clone();
+        // Attached databases aren't attached to the snapshot's connection
+        db.schemas = self
+            .schemas
+            .iter()
+            .filter(|(name, _)| !name.contains('.'))
+            .map(|(name, schema)| (name.clone(), schema.clone()))
+            .collect();
+
+        // A deferred transaction only takes its snapshot on the first read
+        db.conn.execute_batch("BEGIN")?;
+        db.conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| row.get::<_, i64>(0))?;
+        Ok(Snapshot { db })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::Value;
+    use std::collections::HashMap;
+
+    use crate::flexible_database::{FieldDef, FieldType, Schema};
+    use crate::options::JournalMode;
+    use crate::temp_file::TempFile;
+
+    fn accounts(path: &str) -> FlexibleDatabase {
+        let options = DatabaseOptions::new().journal_mode(JournalMode::Wal);
+        let mut db = FlexibleDatabase::open_with(path, options).unwrap();
+        db.define_schema(Schema::new("accounts", []).field("balance", FieldDef::new(FieldType::Integer)))
+            .unwrap();
+        db
+    }
+
+    fn account(db: &FlexibleDatabase, balance: i64) {
+        let data = HashMap::from([("balance".to_string(), Value::Integer(balance))]);
+        db.create_model("accounts", data).unwrap();
+    }
+
+    #[test]
+    fn snapshots_keep_seeing_the_state_they_were_taken_at() {
+        let file = TempFile::new("db");
+        let db = accounts(file.path());
+        account(&db, 10);
+
+        let snapshot = db.snapshot().unwrap();
+        account(&db, 20);
+        let data = HashMap::from([("balance".to_string(), Value::Integer(5))]);
+        db.update_model("accounts", 1, data).unwrap();
+
+        assert_eq!(snapshot.count("accounts").unwrap(), 1);
+        let pinned = snapshot.get_model("accounts", 1).unwrap().unwrap();
+        assert_eq!(pinned.data["balance"], Value::Integer(10));
+
+        let later = db.snapshot().unwrap();
+        assert_eq!(later.count("accounts").unwrap(), 2);
+        let current = later.get_model("accounts", 1).unwrap().unwrap();
+        assert_eq!(current.data["balance"], Value::Integer(5));
+    }
+
+    #[test]
+    fn snapshots_refuse_writes() {
+        let file = TempFile::new("db");
+        let db = accounts(file.path());
+        let snapshot = db.snapshot().unwrap();
+        let data = HashMap::from([("balance".to_string(), Value::Integer(1))]);
+        assert!(snapshot.create_model("accounts", data).is_err());
+        drop(snapshot);
+        assert_eq!(db.count("accounts").unwrap(), 0);
+    }
+
+    #[test]
+    fn uncommitted_writes_are_left_out() {
+        let file = TempFile::new("db");
+        let mut db = accounts(file.path());
+        account(&db, 10);
+        db.transaction(|db| {
+            account(db, 20);
+            let snapshot = db.snapshot()?;
+            assert_eq!(snapshot.count("accounts")?, 1);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(db.snapshot().unwrap().count("accounts").unwrap(), 2);
+    }
+
+    #[test]
+    fn snapshots_need_a_wal_file() {
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        assert!(matches!(
+            db.snapshot(),
+            Err(KooError::InvalidData(message)) if message.contains("in-memory")
+        ));
+
+        let file = TempFile::new("db");
+        let options = DatabaseOptions::new().journal_mode(JournalMode::Delete);
+        let db = FlexibleDatabase::open_with(file.path(), options).unwrap();
+        assert!(matches!(
+            db.snapshot(),
+            Err(KooError::InvalidData(message)) if message.contains("WAL mode, not delete")
+        ));
+    }
+}
@@ -0,0 +1,163 @@
+use rusqlite::CachedStatement;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+
+use crate::error::Result;
+use crate::flexible_database::FlexibleDatabase;
+
+pub(crate) const DEFAULT_STATEMENT_CACHE_CAPACITY: usize = 64;
+
+// Hit/miss counters for the prepared statement cache used by CRUD calls
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatementCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    // Statements currently cached
+    pub len: usize,
+    pub capacity: usize,
+}
+
+// Mirror of the connection's LRU statement cache. rusqlite doesn't report
+// hits, so the same LRU is replayed over the SQL keys to count them.
+#[derive(Debug)]
+pub(crate) struct StatementCache {
+    keys: RefCell<VecDeque<String>>,
+    capacity: Cell<usize>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+}
+
+impl StatementCache {
+    pub(crate) fn new(capacity: usize) -> StatementCache {
+        StatementCache {
+            keys: RefCell::new(VecDeque::new()),
+            capacity: Cell::new(capacity),
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+        }
+    }
+
+    fn touch(&self, sql: &str) {
+        let mut keys = self.keys.borrow_mut();
+        if let Some(pos) = keys.iter().position(|key| key == sql) {
+            let key = keys.remove(pos).expect("position is in range");
+            keys.push_front(key);
+            self.hits.set(self.hits.get() + 1);
+        } else {
+            keys.push_front(sql.to_string());
+            keys.truncate(self.capacity.get());
+            self.misses.set(self.misses.get() + 1);
+        }
+    }
+}
+
+impl FlexibleDatabase {
+    // Prepare through the connection's statement cache. CRUD statements are
+    // built deterministically from (schema, operation, field set), so the
+    // SQL text doubles as the cache key.
+    pub(crate) fn prepare_cached(&self, sql: &str) -> Result<CachedStatement<'_>> {
+        self.statement_cache.touch(sql);
+        Ok(self.conn.prepare_cached(sql)?)
+    }
+
+    pub fn statement_cache_stats(&self) -> StatementCacheStats {
+        StatementCacheStats {
+            hits: self.statement_cache.hits.get(),
+            misses: self.statement_cache.misses.get(),
+            len: self.statement_cache.keys.borrow().len(),
+            capacity: self.statement_cache.capacity.get(),
+        }
+    }
+
+    // Change how many prepared statements are kept; 0 disables caching
+    pub fn set_statement_cache_capacity(&self, capacity: usize) {
+        self.conn.set_prepared_statement_cache_capacity(capacity);
+        self.statement_cache.capacity.set(capacity);
+        self.statement_cache.keys.borrow_mut().truncate(capacity);
+    }
+
+    // Drop every cached statement, e.g. after a table is renamed or dropped
+    pub(crate) fn flush_statement_cache(&self) {
+        self.conn.flush_prepared_statement_cache();
+        self.statement_cache.keys.borrow_mut().clear();
+        // Models read before the change no longer have its layout
+        self.clear_read_cache();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::Value;
+    use std::collections::HashMap;
+
+    use crate::flexible_database::{FieldType, Schema};
+
+    fn prepare(db: &FlexibleDatabase, sql: &str) {
+        db.prepare_cached(sql).unwrap();
+    }
+
+    #[test]
+    fn repeated_reads_hit_the_cache() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("t", HashMap::from([("name".to_string(), FieldType::Text)])))
+            .unwrap();
+        let id = db
+            .create_model(
+                "t",
+                HashMap::from([("name".to_string(), Value::Text("Ada".to_string()))]),
+            )
+            .unwrap();
+        db.get_model("t", &id).unwrap();
+        let before = db.statement_cache_stats();
+        db.get_model("t", &id).unwrap();
+        db.get_model("t", &id).unwrap();
+        let after = db.statement_cache_stats();
+        assert_eq!(after.hits, before.hits + 2);
+        assert_eq!(after.misses, before.misses);
+        assert_eq!(after.capacity, DEFAULT_STATEMENT_CACHE_CAPACITY);
+    }
+
+    #[test]
+    fn the_least_recently_used_statement_is_evicted() {
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        db.set_statement_cache_capacity(2);
+        let start = db.statement_cache_stats();
+        prepare(&db, "SELECT 1");
+        prepare(&db, "SELECT 2");
+        prepare(&db, "SELECT 1");
+        prepare(&db, "SELECT 3");
+        assert_eq!(db.statement_cache_stats().len, 2);
+
+        // "SELECT 2" was pushed out, "SELECT 1" was kept by its use
+        prepare(&db, "SELECT 1");
+        prepare(&db, "SELECT 2");
+        let stats = db.statement_cache_stats();
+        assert_eq!(stats.hits - start.hits, 2);
+        assert_eq!(stats.misses - start.misses, 4);
+    }
+
+    #[test]
+    fn a_capacity_of_zero_caches_nothing() {
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        db.set_statement_cache_capacity(0);
+        let start = db.statement_cache_stats();
+        prepare(&db, "SELECT 1");
+        prepare(&db, "SELECT 1");
+        let stats = db.statement_cache_stats();
+        assert_eq!(stats.hits, start.hits);
+        assert_eq!(stats.len, 0);
+        assert_eq!(stats.capacity, 0);
+    }
+
+    #[test]
+    fn flushing_empties_the_cache() {
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        prepare(&db, "SELECT 1");
+        db.flush_statement_cache();
+        assert_eq!(db.statement_cache_stats().len, 0);
+        let misses = db.statement_cache_stats().misses;
+        prepare(&db, "SELECT 1");
+        assert_eq!(db.statement_cache_stats().misses, misses + 1);
+    }
+}
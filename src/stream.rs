@@ -0,0 +1,237 @@
+use std::collections::VecDeque;
+
+use crate::error::Result;
+use crate::flexible_database::{FlexibleDatabase, Model, read_model, row_key, select_sql};
+
+const DEFAULT_BATCH_SIZE: usize = 500;
+
+// Lazy iterator over the models of a schema in id order. Rows are read in
+// batches keyed on the last seen id, so only one batch is held in memory
+// and no statement stays open between batches.
+pub struct ModelIter<'a> {
+    db: &'a FlexibleDatabase,
+    schema_name: String,
+    batch_size: usize,
+    last_id: i64,
+    buffer: VecDeque<Model>,
+    done: bool,
+}
+
+impl ModelIter<'_> {
+    // Number of rows fetched per query
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    fn fetch_batch(&mut self) -> Result<()> {
+        let schema = self.db.schema_or_err(&self.schema_name)?;
+        let rowid = row_key(schema);
+        let sql = format!("{} WHERE {rowid} > ? ORDER BY {rowid} LIMIT ?", select_sql(schema));
+
+        let fetched = self.db.traced(&sql, 2, || {
+            let mut stmt = self.db.prepare_cached(&sql)?;
+            let mut rows = stmt.query(rusqlite::params![self.last_id, self.batch_size as i64])?;
+            let mut fetched = 0;
+            while let Some(row) = rows.next()? {
+                self.last_id = row.get(0)?;
+                fetched += 1;
+                let mut model = read_model(row, schema)?;
+                if !self.db.readable(&self.schema_name, &model) {
+                    continue;
+                }
+                self.db.track_model(&self.schema_name, &mut model);
+                self.buffer.push_back(model);
+            }
+            Ok((fetched, fetched))
+        })?;
+
+        if fetched < self.batch_size {
+            self.done = true;
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for ModelIter<'_> {
+    type Item = Result<Model>;
+
+    fn next(&mut self) -> Option<Result<Model>> {
+        // The access policy may hide a whole batch, so keep reading until
+        // a model turns up or the rows run out
+        while self.buffer.is_empty() && !self.done {
+            if let Err(err) = self.fetch_batch() {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+        self.buffer.pop_front().map(Ok)
+    }
+}
+
+impl FlexibleDatabase {
+    // Iterate over all models of a type without loading them all at once
+    pub fn iter_models(&self, schema_name: &str) -> Result<ModelIter<'_>> {
+        self.schema_or_err(schema_name)?;
+        Ok(ModelIter {
+            db: self,
+            schema_name: schema_name.to_string(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            last_id: i64::MIN,
+            buffer: VecDeque::new(),
+            done: false,
+        })
+    }
+
+    // Call `f` for each model of a type as rows are read from a single
+    // statement. Returning an error from `f` stops the scan.
+    pub fn for_each_model<F>(&self, schema_name: &str, mut f: F) -> Result<()>
+    where
+        F: FnMut(Model) -> Result<()>,
+    {
+        let schema = self.schema_or_err(schema_name)?;
+
+        let sql = format!("{} ORDER BY {}", select_sql(schema), row_key(schema));
+        self.traced(&sql, 0, || {
+            let mut stmt = self.conn.prepare(&sql)?;
+            let mut rows = stmt.query([])?;
+            let mut count = 0;
+            while let Some(row) = rows.next()? {
+                let mut model = read_model(row, schema)?;
+                if !self.readable(schema_name, &model) {
+                    continue;
+                }
+                self.track_model(schema_name, &mut model);
+                f(model)?;
+                count += 1;
+            }
+            Ok(((), count))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::types::Value;
+    use std::collections::HashMap;
+
+    use crate::access::{AccessPolicy, CallerContext};
+    use crate::flexible_database::{FieldType, FlexibleDatabase, Model, ModelId, PrimaryKey, Schema};
+
+    fn numbers(count: i64) -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("t", [("n".to_string(), FieldType::Integer)]))
+            .unwrap();
+        for n in 0..count {
+            db.create_model("t", HashMap::from([("n".to_string(), Value::Integer(n))]))
+                .unwrap();
+        }
+        db
+    }
+
+    fn n(model: &Model) -> i64 {
+        model.get_i64("n").unwrap()
+    }
+
+    struct AtLeast(i64);
+
+    impl AccessPolicy for AtLeast {
+        fn can_read(&self, _: &str, model: &Model, _: &CallerContext) -> bool {
+            n(model) >= self.0
+        }
+
+        fn can_write(&self, _: &str, _: &Model, _: &CallerContext) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn iterates_every_model_in_id_order_across_batches() {
+        let db = numbers(25);
+        let seen: Vec<i64> = db
+            .iter_models("t")
+            .unwrap()
+            .batch_size(4)
+            .map(|model| n(&model.unwrap()))
+            .collect();
+        assert_eq!(seen, (0..25).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn iterates_past_batches_the_access_policy_hides() {
+        let mut db = numbers(1000);
+        db.set_access_policy(AtLeast(600));
+        assert_eq!(db.iter_models("t").unwrap().count(), 400);
+        assert_eq!(db.iter_models("t").unwrap().batch_size(7).count(), 400);
+        assert_eq!(db.get_all_models("t").unwrap().len(), 400);
+    }
+
+    #[test]
+    fn ends_when_every_model_is_hidden() {
+        let mut db = numbers(30);
+        db.set_access_policy(AtLeast(100));
+        assert_eq!(db.iter_models("t").unwrap().batch_size(4).count(), 0);
+    }
+
+    #[test]
+    fn for_each_model_stops_at_the_first_error() {
+        let db = numbers(10);
+        let mut seen = 0;
+        let result = db.for_each_model("t", |model| {
+            seen += 1;
+            match n(&model) {
+                3 => Err(crate::error::KooError::InvalidData("stop".to_string())),
+                _ => Ok(()),
+            }
+        });
+        assert!(result.is_err());
+        assert_eq!(seen, 4);
+    }
+
+    #[test]
+    fn unknown_schemas_are_errors() {
+        let db = numbers(0);
+        assert!(db.iter_models("missing").is_err());
+    }
+
+    #[test]
+    fn iterates_text_keyed_schemas_in_insertion_order() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("t", [("n".to_string(), FieldType::Integer)]).with_key(PrimaryKey::Text);
+        db.define_schema(schema).unwrap();
+        for (n, id) in ["m", "c", "x", "a", "q"].iter().enumerate() {
+            let data = HashMap::from([
+                ("id".to_string(), Value::Text(id.to_string())),
+                ("n".to_string(), Value::Integer(n as i64)),
+            ]);
+            db.create_model("t", data).unwrap();
+        }
+        let seen: Vec<i64> = db
+            .iter_models("t")
+            .unwrap()
+            .batch_size(2)
+            .map(|model| n(&model.unwrap()))
+            .collect();
+        assert_eq!(seen, [0, 1, 2, 3, 4]);
+        let mut ids = Vec::new();
+        db.for_each_model("t", |model| {
+            ids.push(model.id.unwrap());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(ids[0], ModelId::Text("m".to_string()));
+        assert_eq!(ids.len(), 5);
+    }
+
+    #[test]
+    fn models_deleted_between_batches_are_skipped() {
+        let db = numbers(10);
+        let mut models = db.iter_models("t").unwrap().batch_size(3);
+        let first: Vec<i64> = models.by_ref().take(3).map(|model| n(&model.unwrap())).collect();
+        assert_eq!(first, [0, 1, 2]);
+        // Ids start at 1, so this is the model holding 4
+        db.delete_model("t", 5).unwrap();
+        let rest: Vec<i64> = models.map(|model| n(&model.unwrap())).collect();
+        assert_eq!(rest, [3, 5, 6, 7, 8, 9]);
+    }
+}
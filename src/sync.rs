@@ -0,0 +1,540 @@
+use rusqlite::OptionalExtension;
+use rusqlite::types::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::audit::AuditAction;
+use crate::changelog::logged_columns;
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FlexibleDatabase, ModelId, PrimaryKey, Schema, key_filter};
+
+// A model changed in both databases since they last synced. Local is the
+// database `sync_to` or `sync_from` was called on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncConflict {
+    pub schema: String,
+    pub id: ModelId,
+    // Each side's row, as in `ChangeRecord::data`; None where the model is
+    // deleted
+    pub local: Option<HashMap<String, Value>>,
+    pub remote: Option<HashMap<String, Value>>,
+    // When each side last changed the model
+    pub local_changed_at: String,
+    pub remote_changed_at: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SyncReport {
+    // Models written to the receiving database
+    pub applied: usize,
+    // Conflicts found, each already resolved by the conflict strategy
+    pub conflicts: Vec<SyncConflict>,
+}
+
+// Returns the row both sides of a conflict end up with, None to delete
+// the model
+pub type ConflictResolver = dyn Fn(&SyncConflict) -> Option<HashMap<String, Value>> + Send + Sync;
+
+// How a sync settles a conflict
+#[derive(Clone, Default)]
+pub enum ConflictStrategy {
+    // Keep the version changed last, going by the changelog timestamps
+    #[default]
+    LastWriterWins,
+    PreferLocal,
+    PreferRemote,
+    Custom(Arc<ConflictResolver>),
+}
+
+impl ConflictStrategy {
+    pub fn custom(
+        resolve: impl Fn(&SyncConflict) -> Option<HashMap<String, Value>> + Send + Sync + 'static,
+    ) -> ConflictStrategy {
+        ConflictStrategy::Custom(Arc::new(resolve))
+    }
+}
+
+impl fmt::Debug for ConflictStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictStrategy::LastWriterWins => f.write_str("LastWriterWins"),
+            ConflictStrategy::PreferLocal => f.write_str("PreferLocal"),
+            ConflictStrategy::PreferRemote => f.write_str("PreferRemote"),
+            ConflictStrategy::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+// Syncing sends one database the changes from another's changelog that it
+// hasn't applied yet, so both need `enable_changelog` and the same schemas.
+// Each database remembers how far it has got with every peer, and changes
+// never travel back to the database they came from. A model changed on
+// both sides since the last sync is a conflict, settled by the conflict
+// strategy of the database the sync was called on; the version kept
+// reaches the other side on the next sync back. Rows are copied as they
+// are, without validation, encryption or the access policy, though with
+// auditing enabled the changes applied are recorded.
+// Start a replica from an empty database rather than a copy of the file,
+// which would report the models it changed as conflicts, and give schemas
+// UUID ids so models created on both sides don't end up with the same id.
+impl FlexibleDatabase {
+    pub fn set_conflict_strategy(&mut self, strategy: ConflictStrategy) {
+        self.conflict_strategy = strategy;
+    }
+
+    // Send this database's new changes to `replica`
+    pub fn sync_to(&mut self, replica: &mut FlexibleDatabase) -> Result<SyncReport> {
+        let source = &*self;
+        replica.in_transaction(|target| target.apply_changes_from(source, &source.conflict_strategy, false))
+    }
+
+    // Apply the new changes of `primary` here
+    pub fn sync_from(&mut self, primary: &mut FlexibleDatabase) -> Result<SyncReport> {
+        let strategy = self.conflict_strategy.clone();
+        let source = &*primary;
+        self.in_transaction(|target| target.apply_changes_from(source, &strategy, true))
+    }
+
+    // `local_is_target` tells which side a conflict strategy's local is
+    fn apply_changes_from(
+        &self,
+        source: &FlexibleDatabase,
+        strategy: &ConflictStrategy,
+        local_is_target: bool,
+    ) -> Result<SyncReport> {
+        if !source.changelog_enabled()? || !self.changelog_enabled()? {
+            return Err(KooError::Sync("both databases need the changelog enabled".to_string()));
+        }
+        let source_id = source.sync_id()?;
+        let mut target_id = self.sync_id()?;
+        // A copied file comes with the id of the original
+        if target_id == source_id {
+            target_id = self.new_sync_id()?;
+        }
+        let applied_seq = self.peer_seq(&source_id)?;
+        let sent_seq = source.peer_seq(&target_id)?;
+
+        let mut changes = source.changes_since(applied_seq)?;
+        let Some(last_seq) = changes.last().map(|change| change.seq) else {
+            return Ok(SyncReport::default());
+        };
+        // Only the latest change of each model is applied
+        let mut latest = HashMap::new();
+        for change in &changes {
+            latest.insert((change.schema.clone(), change.id.to_string()), change.seq);
+        }
+        changes.retain(|change| latest[&(change.schema.clone(), change.id.to_string())] == change.seq);
+
+        // Rows may arrive before the rows they reference
+        self.conn.execute_batch("PRAGMA defer_foreign_keys = ON")?;
+        let mut report = SyncReport::default();
+        for change in changes {
+            if change.origin.as_deref() == Some(target_id.as_str()) {
+                continue;
+            }
+            let schema = self.schema_or_err(&change.schema)?;
+            let current = self.row_data(schema, &change.id)?;
+            if current == change.data {
+                continue;
+            }
+
+            let mut row = change.data.clone();
+            if let Some(changed_at) = self.changed_since(schema, &change.id, sent_seq, &source_id)? {
+                let target_side = (current.clone(), changed_at.clone());
+                let source_side = (change.data.clone(), change.changed_at.clone());
+                let (local, remote) = match local_is_target {
+                    true => (target_side, source_side),
+                    false => (source_side, target_side),
+                };
+                let conflict = SyncConflict {
+                    schema: change.schema.clone(),
+                    id: change.id.clone(),
+                    local: local.0,
+                    remote: remote.0,
+                    local_changed_at: local.1,
+                    remote_changed_at: remote.1,
+                };
+                row = match strategy {
+                    ConflictStrategy::LastWriterWins => {
+                        // Ties go the same way whichever side syncs
+                        let source_wins = (&change.changed_at, &source_id) > (&changed_at, &target_id);
+                        if source_wins {
+                            change.data.clone()
+                        } else {
+                            current.clone()
+                        }
+                    }
+                    ConflictStrategy::PreferLocal => conflict.local.clone(),
+                    ConflictStrategy::PreferRemote => conflict.remote.clone(),
+                    ConflictStrategy::Custom(resolve) => resolve(&conflict),
+                };
+                report.conflicts.push(conflict);
+                if row == current {
+                    continue;
+                }
+            }
+
+            // Models of append-only schemas can only be added
+            if schema.append_only && current.is_some() {
+                return Err(KooError::Sync(format!(
+                    "'{}' is append-only, but {} was changed or deleted",
+                    change.schema, change.id
+                )));
+            }
+            let before = self.latest_change_seq()?;
+            self.write_row(schema, &change.id, row.as_ref())?;
+            // Logged with where the change was first made, so it isn't sent
+            // back there by way of a third database either. A row merged by
+            // a custom strategy is a change of this database's own.
+            if row == change.data {
+                self.conn.execute(
+                    "UPDATE _koo_changelog SET origin = ? WHERE seq > ?",
+                    (change.origin.as_deref().unwrap_or(&source_id), before),
+                )?;
+            }
+            report.applied += 1;
+        }
+
+        self.conn.execute(
+            "INSERT INTO _koo_sync_peers (peer, applied_seq) VALUES (?, ?)
+             ON CONFLICT (peer) DO UPDATE SET applied_seq = excluded.applied_seq",
+            (&source_id, last_seq),
+        )?;
+        Ok(report)
+    }
+
+    // Id telling this database apart from its peers, created on first use
+    fn sync_id(&self) -> Result<String> {
+        self.conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS _koo_sync_identity (id TEXT NOT NULL);
+            CREATE TABLE IF NOT EXISTS _koo_sync_peers (peer TEXT PRIMARY KEY, applied_seq INTEGER NOT NULL);",
+        )?;
+        let id: Option<String> = self
+            .conn
+            .query_row("SELECT id FROM _koo_sync_identity", [], |row| row.get(0))
+            .optional()?;
+        match id {
+            Some(id) => Ok(id),
+            None => self.new_sync_id(),
+        }
+    }
+
+    fn new_sync_id(&self) -> Result<String> {
+        let id = uuid::Uuid::new_v4().to_string();
+        self.conn.execute("DELETE FROM _koo_sync_identity", [])?;
+        self.conn
+            .execute("INSERT INTO _koo_sync_identity (id) VALUES (?)", [&id])?;
+        Ok(id)
+    }
+
+    // Last change of `peer` applied here
+    fn peer_seq(&self, peer: &str) -> Result<i64> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT applied_seq FROM _koo_sync_peers WHERE peer = ?",
+                [peer],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(0))
+    }
+
+    // The stored row of `id`, keyed like a change's data
+    fn row_data(&self, schema: &Schema, id: &ModelId) -> Result<Option<HashMap<String, Value>>> {
+        let columns = logged_columns(schema);
+        let (key_sql, params) = key_filter(schema, id)?;
+        let sql = format!("SELECT {} FROM {} WHERE {}", columns.join(", "), schema.name, key_sql);
+        let mut stmt = self.conn.prepare(&sql)?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&params))?;
+        let Some(row) = rows.next()? else {
+            return Ok(None);
+        };
+        let mut data = HashMap::new();
+        for (index, column) in columns.iter().enumerate() {
+            data.insert(column.clone(), row.get::<_, Value>(index)?);
+        }
+        Ok(Some(data))
+    }
+
+    // When this database last changed `id` after its change `seq`, other
+    // than by applying changes from `peer`
+    fn changed_since(&self, schema: &Schema, id: &ModelId, seq: i64, peer: &str) -> Result<Option<String>> {
+        let (_, key_values) = key_filter(schema, id)?;
+        // Built the way the changelog triggers build it
+        let placeholders = vec!["?"; key_values.len()].join(", ");
+        let model_id = match &schema.key {
+            PrimaryKey::Composite(_) => format!("json_array({})", placeholders),
+            _ => format!("json_quote({})", placeholders),
+        };
+        let sql = format!(
+            "SELECT MAX(changed_at) FROM _koo_changelog WHERE seq > ? AND schema_name = ? AND model_id = {} \
+             AND (origin IS NULL OR origin != ?)",
+            model_id
+        );
+        let mut params = vec![Value::Integer(seq), Value::Text(schema.name.clone())];
+        params.extend(key_values);
+        params.push(Value::Text(peer.to_string()));
+        Ok(self
+            .conn
+            .query_row(&sql, rusqlite::params_from_iter(&params), |row| row.get(0))?)
+    }
+
+    // Store `data` as the row of `id`, or delete the row for None
+    fn write_row(&self, schema: &Schema, id: &ModelId, data: Option<&HashMap<String, Value>>) -> Result<()> {
+        let Some(data) = data else {
+            let (key_sql, params) = key_filter(schema, id)?;
+            self.audit_change(schema, id, AuditAction::Delete, || {
+                Ok(self.conn.execute(
+                    &format!("DELETE FROM {} WHERE {}", schema.name, key_sql),
+                    rusqlite::params_from_iter(&params),
+                )?)
+            })?;
+            return Ok(());
+        };
+
+        let columns = logged_columns(schema);
+        if let Some(column) = data.keys().find(|column| !columns.contains(column)) {
+            return Err(KooError::UnknownField {
+                schema: schema.name.clone(),
+                field: column.clone(),
+            });
+        }
+        // Computed columns are logged but come out the same on this side
+        let present: Vec<&String> = columns
+            .iter()
+            .filter(|column| data.contains_key(*column) && !schema.is_computed(column))
+            .collect();
+        let key_columns = match &schema.key {
+            PrimaryKey::Composite(key_fields) => key_fields.clone(),
+            _ => vec!["id".to_string()],
+        };
+        let updates: Vec<String> = present
+            .iter()
+            .filter(|column| !key_columns.contains(column))
+            .map(|column| format!("{0} = excluded.{0}", column))
+            .collect();
+        let on_conflict = if updates.is_empty() {
+            "DO NOTHING".to_string()
+        } else {
+            format!("DO UPDATE SET {}", updates.join(", "))
+        };
+        let names: Vec<&str> = present.iter().map(|column| column.as_str()).collect();
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT ({}) {}",
+            schema.name,
+            names.join(", "),
+            vec!["?"; present.len()].join(", "),
+            key_columns.join(", "),
+            on_conflict
+        );
+        self.audit_insert(schema, Some(id.clone()), || {
+            Ok(self.conn.execute(
+                &sql,
+                rusqlite::params_from_iter(present.iter().map(|column| &data[*column])),
+            )?)
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::flexible_database::FieldType;
+
+    fn notes() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("notes", [("body".to_string(), FieldType::Text)]))
+            .unwrap();
+        db.enable_changelog().unwrap();
+        db
+    }
+
+    fn note(body: &str) -> HashMap<String, Value> {
+        HashMap::from([("body".to_string(), Value::Text(body.to_string()))])
+    }
+
+    fn body(db: &FlexibleDatabase, id: i64) -> Option<Value> {
+        db.get_model("notes", id)
+            .unwrap()
+            .map(|model| model.data["body"].clone())
+    }
+
+    #[test]
+    fn changes_reach_the_replica_once() {
+        let (mut primary, mut replica) = (notes(), notes());
+        primary.create_model("notes", note("a")).unwrap();
+        primary.create_model("notes", note("b")).unwrap();
+        primary.update_model("notes", 1, note("a2")).unwrap();
+
+        let report = primary.sync_to(&mut replica).unwrap();
+        assert_eq!(report.applied, 2);
+        assert!(report.conflicts.is_empty());
+        assert_eq!(body(&replica, 1), Some(Value::Text("a2".to_string())));
+        assert_eq!(body(&replica, 2), Some(Value::Text("b".to_string())));
+
+        assert_eq!(primary.sync_to(&mut replica).unwrap(), SyncReport::default());
+        // What the replica applied isn't sent back
+        assert_eq!(replica.sync_to(&mut primary).unwrap().applied, 0);
+
+        primary.delete_model("notes", 2).unwrap();
+        assert_eq!(replica.sync_from(&mut primary).unwrap().applied, 1);
+        assert_eq!(body(&replica, 2), None);
+    }
+
+    #[test]
+    fn changes_travel_both_ways() {
+        let (mut primary, mut replica) = (notes(), notes());
+        primary.create_model("notes", note("a")).unwrap();
+        primary.sync_to(&mut replica).unwrap();
+
+        replica.update_model("notes", 1, note("edited offline")).unwrap();
+        let report = primary.sync_from(&mut replica).unwrap();
+        assert_eq!(report.applied, 1);
+        assert!(report.conflicts.is_empty());
+        assert_eq!(body(&primary, 1), Some(Value::Text("edited offline".to_string())));
+    }
+
+    #[test]
+    fn models_changed_on_both_sides_are_conflicts() {
+        let (mut primary, mut replica) = (notes(), notes());
+        primary.create_model("notes", note("a")).unwrap();
+        primary.sync_to(&mut replica).unwrap();
+
+        primary.update_model("notes", 1, note("primary")).unwrap();
+        replica.delete_model("notes", 1).unwrap();
+        let report = primary.sync_to(&mut replica).unwrap();
+        assert_eq!(report.conflicts.len(), 1);
+
+        let conflict = &report.conflicts[0];
+        assert_eq!(
+            (conflict.schema.as_str(), &conflict.id),
+            ("notes", &ModelId::Integer(1))
+        );
+        assert_eq!(
+            conflict.local.as_ref().unwrap()["body"],
+            Value::Text("primary".to_string())
+        );
+        assert_eq!(conflict.remote, None);
+    }
+
+    #[test]
+    fn both_sides_need_the_changelog() {
+        let mut primary = notes();
+        let mut plain = FlexibleDatabase::new(":memory:").unwrap();
+        plain
+            .define_schema(Schema::new("notes", [("body".to_string(), FieldType::Text)]))
+            .unwrap();
+        assert!(matches!(primary.sync_to(&mut plain), Err(KooError::Sync(_))));
+        assert!(matches!(plain.sync_to(&mut primary), Err(KooError::Sync(_))));
+    }
+
+    #[test]
+    fn a_failed_sync_applies_nothing() {
+        let (mut primary, mut replica) = (notes(), notes());
+        primary
+            .define_schema(Schema::new("tags", [("label".to_string(), FieldType::Text)]))
+            .unwrap();
+        primary.create_model("notes", note("a")).unwrap();
+        primary
+            .create_model(
+                "tags",
+                HashMap::from([("label".to_string(), Value::Text("x".to_string()))]),
+            )
+            .unwrap();
+
+        assert!(matches!(
+            primary.sync_to(&mut replica),
+            Err(KooError::SchemaNotFound(_))
+        ));
+        assert_eq!(body(&replica, 1), None);
+    }
+
+    // Both sides change note 1 after syncing it, the replica last
+    fn conflicting() -> (FlexibleDatabase, FlexibleDatabase) {
+        let (mut primary, mut replica) = (notes(), notes());
+        primary.create_model("notes", note("a")).unwrap();
+        primary.sync_to(&mut replica).unwrap();
+        primary.update_model("notes", 1, note("primary")).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        replica.update_model("notes", 1, note("replica")).unwrap();
+        (primary, replica)
+    }
+
+    fn settled(strategy: ConflictStrategy) -> (Option<Value>, Option<Value>) {
+        let (mut primary, mut replica) = conflicting();
+        primary.set_conflict_strategy(strategy);
+        let report = primary.sync_to(&mut replica).unwrap();
+        assert_eq!(report.conflicts.len(), 1);
+        // The version kept goes back on the next sync the other way
+        replica.sync_to(&mut primary).unwrap();
+        (body(&primary, 1), body(&replica, 1))
+    }
+
+    #[test]
+    fn the_last_writer_wins_by_default() {
+        let text = Some(Value::Text("replica".to_string()));
+        assert_eq!(settled(ConflictStrategy::default()), (text.clone(), text));
+    }
+
+    #[test]
+    fn either_side_can_be_preferred() {
+        let primary = Some(Value::Text("primary".to_string()));
+        assert_eq!(settled(ConflictStrategy::PreferLocal), (primary.clone(), primary));
+        let replica = Some(Value::Text("replica".to_string()));
+        assert_eq!(settled(ConflictStrategy::PreferRemote), (replica.clone(), replica));
+
+        // Local is the side the sync was called on, here the replica
+        let (mut primary, mut replica) = conflicting();
+        replica.set_conflict_strategy(ConflictStrategy::PreferLocal);
+        replica.sync_from(&mut primary).unwrap();
+        assert_eq!(body(&replica, 1), Some(Value::Text("replica".to_string())));
+    }
+
+    #[test]
+    fn custom_strategies_see_both_versions() {
+        let merge = ConflictStrategy::custom(|conflict| {
+            let side = |data: &Option<HashMap<String, Value>>| match &data.as_ref().unwrap()["body"] {
+                Value::Text(text) => text.clone(),
+                _ => unreachable!(),
+            };
+            let mut row = conflict.local.clone().unwrap();
+            row.insert(
+                "body".to_string(),
+                Value::Text(format!("{}+{}", side(&conflict.local), side(&conflict.remote))),
+            );
+            Some(row)
+        });
+        let merged = Some(Value::Text("primary+replica".to_string()));
+        assert_eq!(settled(merge), (merged.clone(), merged));
+
+        let (mut primary, mut replica) = conflicting();
+        primary.set_conflict_strategy(ConflictStrategy::custom(|_| None));
+        primary.sync_to(&mut replica).unwrap();
+        replica.sync_to(&mut primary).unwrap();
+        assert_eq!((body(&primary, 1), body(&replica, 1)), (None, None));
+    }
+
+    #[test]
+    fn applied_changes_are_audited() {
+        let (mut primary, mut replica) = (notes(), notes());
+        replica.enable_audit().unwrap();
+        primary.create_model("notes", note("a")).unwrap();
+        primary.sync_to(&mut replica).unwrap();
+        primary.update_model("notes", 1, note("a2")).unwrap();
+        primary.sync_to(&mut replica).unwrap();
+        primary.delete_model("notes", 1).unwrap();
+        primary.sync_to(&mut replica).unwrap();
+
+        let actions: Vec<AuditAction> = replica
+            .audit_history("notes", 1)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.action)
+            .collect();
+        assert_eq!(actions, [AuditAction::Create, AuditAction::Update, AuditAction::Delete]);
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::flexible_database::{FlexibleDatabase, Model};
+
+// Shared counters of field reads, keyed by schema then field
+#[derive(Debug, Default)]
+pub struct FieldTelemetry {
+    counts: Mutex<HashMap<String, HashMap<String, u64>>>,
+}
+
+impl FieldTelemetry {
+    pub fn record(&self, schema_name: &str, field_name: &str) {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        *counts
+            .entry(schema_name.to_string())
+            .or_default()
+            .entry(field_name.to_string())
+            .or_insert(0) += 1;
+    }
+
+    fn snapshot(&self) -> HashMap<String, HashMap<String, u64>> {
+        self.counts.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+// Handle carried by models read while telemetry is enabled
+#[derive(Clone)]
+pub(crate) struct FieldTracker {
+    schema: Arc<str>,
+    telemetry: Arc<FieldTelemetry>,
+}
+
+impl FieldTracker {
+    pub(crate) fn record(&self, field_name: &str) {
+        self.telemetry.record(&self.schema, field_name);
+    }
+}
+
+impl fmt::Debug for FieldTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FieldTracker({})", self.schema)
+    }
+}
+
+// Read counts for every field of every defined schema
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FieldAccessReport {
+    pub schemas: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+impl FieldAccessReport {
+    // Fields that were never read, as (schema, field) pairs. These are the
+    // candidates for removal migrations.
+    pub fn unused_fields(&self) -> Vec<(String, String)> {
+        let mut unused = Vec::new();
+        for (schema_name, fields) in &self.schemas {
+            for (field_name, count) in fields {
+                if *count == 0 {
+                    unused.push((schema_name.clone(), field_name.clone()));
+                }
+            }
+        }
+        unused
+    }
+}
+
+impl FlexibleDatabase {
+    // Start counting field reads made through model accessors. Counts
+    // accumulate until telemetry is disabled.
+    pub fn enable_field_telemetry(&mut self) {
+        if self.telemetry.is_none() {
+            self.telemetry = Some(Arc::new(FieldTelemetry::default()));
+        }
+    }
+
+    pub fn disable_field_telemetry(&mut self) {
+        self.telemetry = None;
+    }
+
+    // Current read counts, or None when telemetry is disabled. Fields that
+    // were never read are reported with a count of zero.
+    pub fn field_access_report(&self) -> Option<FieldAccessReport> {
+        let telemetry = self.telemetry.as_ref()?;
+        let counts = telemetry.snapshot();
+
+        let mut report = FieldAccessReport::default();
+        for (schema_name, schema) in &self.schemas {
+            let recorded = counts.get(schema_name);
+            let fields = schema
+                .fields
+                .keys()
+                .map(|field_name| {
+                    let count = recorded.and_then(|r| r.get(field_name)).copied().unwrap_or(0);
+                    (field_name.clone(), count)
+                })
+                .collect();
+            report.schemas.insert(schema_name.clone(), fields);
+        }
+        Some(report)
+    }
+
+    // Attach the telemetry handle to a model read from `schema_name`
+    pub(crate) fn track_model(&self, schema_name: &str, model: &mut Model) {
+        if let Some(telemetry) = &self.telemetry {
+            model.tracker = Some(FieldTracker {
+                schema: Arc::from(schema_name),
+                telemetry: Arc::clone(telemetry),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flexible_database::{FieldType, Schema};
+    use crate::model_builder::ModelBuilder;
+
+    fn people() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new(
+            "people",
+            [
+                ("name".to_string(), FieldType::Text),
+                ("email".to_string(), FieldType::Text),
+            ],
+        );
+        db.define_schema(schema).unwrap();
+        let person = ModelBuilder::new().text("name", "Ada").text("email", "ada@example.com");
+        db.create_model("people", person.build()).unwrap();
+        db
+    }
+
+    fn counts(db: &FlexibleDatabase) -> BTreeMap<String, u64> {
+        db.field_access_report().unwrap().schemas["people"].clone()
+    }
+
+    #[test]
+    fn reads_through_accessors_are_counted() {
+        let mut db = people();
+        assert_eq!(db.field_access_report(), None);
+        db.enable_field_telemetry();
+
+        let model = db.get_model("people", 1).unwrap().unwrap();
+        model.get("name");
+        model.get("name");
+        model.get("missing");
+        assert_eq!(counts(&db)["name"], 2);
+        assert_eq!(counts(&db)["email"], 0);
+        assert_eq!(
+            db.field_access_report().unwrap().unused_fields(),
+            [("people".to_string(), "email".to_string())]
+        );
+    }
+
+    #[test]
+    fn models_read_without_telemetry_are_not_counted() {
+        let mut db = people();
+        let untracked = db.get_model("people", 1).unwrap().unwrap();
+        db.enable_field_telemetry();
+        untracked.get("name");
+        assert_eq!(counts(&db)["name"], 0);
+
+        db.disable_field_telemetry();
+        assert_eq!(db.field_access_report(), None);
+    }
+}
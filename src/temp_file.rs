@@ -0,0 +1,36 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+// A path in the temporary directory for one test. The file and any journal
+// SQLite left next to it are removed when this is dropped, so a failing
+// assertion doesn't leave them behind.
+pub(crate) struct TempFile(String);
+
+impl TempFile {
+    // A new path ending in `.extension`; nothing is created until it's used
+    pub(crate) fn new(extension: &str) -> TempFile {
+        let id = NEXT.fetch_add(1, Ordering::Relaxed);
+        let name = format!("koo-{}-{}.{}", std::process::id(), id, extension);
+        TempFile(std::env::temp_dir().join(name).to_string_lossy().into_owned())
+    }
+
+    // A file already holding `contents`
+    pub(crate) fn with_contents(extension: &str, contents: &str) -> TempFile {
+        let file = TempFile::new(extension);
+        std::fs::write(&file.0, contents).unwrap();
+        file
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        for suffix in ["", "-journal", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", self.0, suffix));
+        }
+    }
+}
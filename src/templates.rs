@@ -0,0 +1,228 @@
+use rusqlite::types::Value;
+use std::collections::HashMap;
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FlexibleDatabase, Schema, column_definition, computed_column_definition};
+use crate::tree::PARENT_FIELD;
+
+impl FlexibleDatabase {
+    // Bring every defined schema that extends `template` up to date with
+    // its current fields. Missing columns are added with the value from
+    // `defaults` filling existing rows, computed ones excepted, so call this from a migration when
+    // a template grows. Returns the names of the schemas that changed.
+    pub fn apply_template(
+        &mut self,
+        template: &Schema,
+        defaults: &HashMap<String, Value>,
+    ) -> Result<Vec<String>> {
+        let template = template.materialize()?;
+
+        let mut dependents: Vec<String> = self
+            .schemas
+            .values()
+            .filter(|schema| schema.uses_template(&template.name))
+            .map(|schema| schema.name.clone())
+            .collect();
+        dependents.sort();
+
+        let mut changed = Vec::new();
+        for schema_name in dependents {
+            let schema = self.schema_or_err(&schema_name)?;
+            let missing: Vec<_> = template
+                .fields
+                .iter()
+                .filter(|(field_name, _)| !schema.fields.contains_key(*field_name))
+                .filter(|(field_name, _)| !(template.hierarchical && *field_name == PARENT_FIELD))
+                .collect();
+            if missing.is_empty() {
+                continue;
+            }
+
+            // Check every default up front so a schema is never half updated
+            let mut columns = Vec::new();
+            for (field_name, field_type) in &missing {
+                if let Some(expression) = template.computed_fields.get(*field_name) {
+                    columns.push(computed_column_definition(field_name, field_type, expression));
+                    continue;
+                }
+                let default = defaults
+                    .get(*field_name)
+                    .or_else(|| template.defaults.get(*field_name))
+                    .ok_or_else(|| {
+                    KooError::InvalidSchema(format!(
+                        "a default is needed to add '{}' to existing rows of '{}'",
+                        field_name, schema_name
+                    ))
+                })?;
+                let mut column = column_definition(field_name, field_type, Some(default));
+                if template.is_nullable(field_name) {
+                    column = column.replacen(" NOT NULL", "", 1);
+                }
+                columns.push(column);
+            }
+            for column in columns {
+                self.conn
+                    .execute(&format!("ALTER TABLE {} ADD COLUMN {}", schema_name, column), [])?;
+            }
+
+            let schema = self.schemas.get_mut(&schema_name).expect("schema is registered");
+            for (field_name, field_type) in missing {
+                schema.fields.insert(field_name.clone(), field_type.clone());
+                if let Some(default) = template.defaults.get(field_name) {
+                    schema.defaults.insert(field_name.clone(), default.clone());
+                }
+                if let Some(expression) = template.computed_fields.get(field_name) {
+                    schema.computed_fields.insert(field_name.clone(), expression.clone());
+                }
+                if template.encrypted_fields.contains(field_name) {
+                    schema.encrypted_fields.push(field_name.clone());
+                }
+                if template.is_nullable(field_name) {
+                    schema.nullable_fields.push(field_name.clone());
+                }
+                if template.indexed_fields.contains(field_name) {
+                    schema.indexed_fields.push(field_name.clone());
+                }
+                if template.unique_fields.contains(field_name) {
+                    schema.unique_fields.push(field_name.clone());
+                }
+            }
+            for existing in schema.templates.iter_mut() {
+                if existing.name == template.name {
+                    *existing = template.clone();
+                }
+            }
+            let schema = &self.schemas[&schema_name];
+            self.create_field_indexes(schema)?;
+            if schema.history {
+                self.create_history(schema)?;
+            }
+            self.refresh_changelog(schema)?;
+            self.record_schema(schema)?;
+            self.clear_read_cache();
+            changed.push(schema_name);
+        }
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flexible_database::FieldType;
+    use crate::model_builder::ModelBuilder;
+
+    fn timestamped() -> Schema {
+        Schema::new("timestamped", [("created_at".to_string(), FieldType::Text)])
+    }
+
+    fn posts() -> Schema {
+        Schema::new("posts", [("title".to_string(), FieldType::Text)]).extends(&timestamped())
+    }
+
+    #[test]
+    fn schemas_get_the_fields_of_their_templates() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(posts()).unwrap();
+        let post = ModelBuilder::new().text("title", "Hello").text("created_at", "2024-01-01");
+        let id = db.create_model("posts", post.build()).unwrap();
+        let model = db.get_model("posts", id).unwrap().unwrap();
+        assert_eq!(model.get_str("created_at").unwrap(), "2024-01-01");
+        assert!(posts().uses_template("timestamped"));
+        assert!(!timestamped().uses_template("posts"));
+    }
+
+    #[test]
+    fn a_field_declared_twice_must_keep_its_type() {
+        let clash = Schema::new("posts", [("created_at".to_string(), FieldType::Integer)]).extends(&timestamped());
+        assert!(matches!(clash.materialize(), Err(KooError::InvalidSchema(_))));
+
+        let same = Schema::new("posts", [("created_at".to_string(), FieldType::Text)]).extends(&timestamped());
+        assert_eq!(same.materialize().unwrap().fields.len(), 1);
+    }
+
+    #[test]
+    fn applying_a_grown_template_adds_its_fields_to_existing_rows() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(posts()).unwrap();
+        db.define_schema(Schema::new("notes", [("body".to_string(), FieldType::Text)])).unwrap();
+        let post = ModelBuilder::new().text("title", "Hello").text("created_at", "2024-01-01");
+        let id = db.create_model("posts", post.build()).unwrap();
+
+        let grown = Schema::new(
+            "timestamped",
+            [("created_at".to_string(), FieldType::Text), ("updated_at".to_string(), FieldType::Text)],
+        );
+        let defaults = HashMap::from([("updated_at".to_string(), Value::Text("never".to_string()))]);
+        assert_eq!(db.apply_template(&grown, &defaults).unwrap(), ["posts"]);
+        let model = db.get_model("posts", id).unwrap().unwrap();
+        assert_eq!(model.get_str("updated_at").unwrap(), "never");
+
+        // Nothing is missing any more
+        assert!(db.apply_template(&grown, &defaults).unwrap().is_empty());
+    }
+
+    #[test]
+    fn applying_a_template_needs_defaults_for_new_fields() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(posts()).unwrap();
+        let grown = timestamped().field("updated_at", crate::flexible_database::FieldDef::new(FieldType::Text));
+        let result = db.apply_template(&grown, &HashMap::new());
+        assert!(matches!(result, Err(KooError::InvalidSchema(_))));
+        assert!(!db.schemas["posts"].fields.contains_key("updated_at"));
+    }
+
+    #[test]
+    fn schemas_take_the_settings_of_their_templates() {
+        let base = timestamped()
+            .with_tenancy()
+            .with_versioning()
+            .with_history()
+            .with_sql_checks()
+            .with_append_only();
+        let schema = Schema::new("posts", [("title".to_string(), FieldType::Text)])
+            .extends(&base)
+            .materialize()
+            .unwrap();
+        assert!(schema.tenant_scoped && schema.versioned && schema.history);
+        assert!(schema.sql_checks && schema.append_only);
+        assert!(!posts().materialize().unwrap().versioned);
+    }
+
+    #[test]
+    fn a_schema_keeps_its_own_expiry() {
+        let base = Schema::new("expiring", [("expires_at".to_string(), FieldType::Integer)]).with_expiry("expires_at");
+        let inherited = posts().extends(&base).materialize().unwrap();
+        assert_eq!(inherited.expiry.unwrap().field, "expires_at");
+
+        let own = Schema::new("sessions", [("ends".to_string(), FieldType::Integer)])
+            .with_expiry("ends")
+            .extends(&base)
+            .materialize()
+            .unwrap();
+        assert_eq!(own.expiry.unwrap().field, "ends");
+    }
+
+    #[test]
+    fn hierarchies_from_templates_point_at_the_schema() {
+        let base = Schema::new(
+            "nodes",
+            [
+                ("name".to_string(), FieldType::Text),
+                (PARENT_FIELD.to_string(), FieldType::Reference("nodes".to_string())),
+            ],
+        )
+        .with_hierarchy();
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("folders", []).extends(&base)).unwrap();
+        let folders = &db.schemas["folders"];
+        assert!(folders.hierarchical);
+        assert_eq!(folders.fields[PARENT_FIELD], FieldType::Reference("folders".to_string()));
+
+        db.create_model("folders", ModelBuilder::new().text("name", "root").build()).unwrap();
+        let child = ModelBuilder::new().text("name", "child").set(PARENT_FIELD, 1);
+        db.create_model("folders", child.build()).unwrap();
+        assert_eq!(db.get_children("folders", 1).unwrap().len(), 1);
+        assert!(db.apply_template(&base, &HashMap::new()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,394 @@
+use rusqlite::types::Value;
+use std::collections::HashMap;
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FlexibleDatabase, Model, ModelId, PrimaryKey, Schema, key_filter};
+use crate::query::{Op, Query, QueryPage};
+
+// Field added by `Schema::with_tenancy`
+pub const TENANT_FIELD: &str = "tenant_id";
+
+// One tenant's view of the tenant-scoped schemas, from
+// `FlexibleDatabase::tenant`. Models it creates are stamped with the
+// tenant, and it only reads, updates and deletes models stamped with it.
+// Only its own methods are scoped: the `FlexibleDatabase` ones, raw SQL
+// and exports see every tenant's models.
+pub struct Tenant<'a> {
+    db: &'a FlexibleDatabase,
+    tenant_id: String,
+}
+
+impl FlexibleDatabase {
+    pub fn tenant(&self, tenant_id: &str) -> Tenant<'_> {
+        Tenant {
+            db: self,
+            tenant_id: tenant_id.to_string(),
+        }
+    }
+
+    // Index the tenant field, which every tenant read filters by
+    pub(crate) fn create_tenant_index(&self, schema: &Schema) -> Result<()> {
+        // An attached database's index is named in it, but its table isn't
+        let table = schema.name.rsplit('.').next().unwrap_or(&schema.name);
+        self.conn.execute(
+            &format!(
+                "CREATE INDEX IF NOT EXISTS {}_tenant ON {} ({})",
+                schema.name, table, TENANT_FIELD
+            ),
+            [],
+        )?;
+        Ok(())
+    }
+}
+
+impl Tenant<'_> {
+    pub fn tenant_id(&self) -> &str {
+        &self.tenant_id
+    }
+
+    // Create a model for this tenant. `data` may leave out the tenant field.
+    pub fn create_model(&self, schema_name: &str, mut data: HashMap<String, Value>) -> Result<ModelId> {
+        self.scoped_schema(schema_name)?;
+        self.check_tenant(schema_name, &data)?;
+        data.insert(TENANT_FIELD.to_string(), Value::Text(self.tenant_id.clone()));
+        self.db.create_model(schema_name, data)
+    }
+
+    pub fn get_model(&self, schema_name: &str, id: impl Into<ModelId>) -> Result<Option<Model>> {
+        let schema = self.scoped_schema(schema_name)?;
+        let id = id.into();
+        // Turns away ids of the wrong kind
+        key_filter(schema, &id)?;
+
+        let mut query = Query::new(schema_name).limit(1);
+        match (&schema.key, id) {
+            (PrimaryKey::Composite(key_fields), ModelId::Composite(values)) => {
+                for (field_name, value) in key_fields.iter().zip(values) {
+                    query = query.filter(field_name, Op::Eq, value);
+                }
+            }
+            (_, ModelId::Integer(id)) => query = query.filter("id", Op::Eq, id),
+            (_, ModelId::Text(id)) => query = query.filter("id", Op::Eq, id),
+            (_, ModelId::Composite(_)) => unreachable!("checked by key_filter"),
+        }
+        Ok(self.find(&query)?.pop())
+    }
+
+    // Several of this tenant's models by id, in the order of `ids`; other
+    // tenants' models are left out like missing ones
+    pub fn get_models<I>(&self, schema_name: &str, ids: I) -> Result<Vec<Model>>
+    where
+        I: IntoIterator,
+        I::Item: Into<ModelId>,
+    {
+        self.scoped_schema(schema_name)?;
+        let mut models = self.db.get_models(schema_name, ids)?;
+        models.retain(|model| self.stamped(model));
+        Ok(models)
+    }
+
+    pub fn get_all_models(&self, schema_name: &str) -> Result<Vec<Model>> {
+        self.find(&Query::new(schema_name))
+    }
+
+    // Run a query over this tenant's models
+    pub fn find(&self, query: &Query) -> Result<Vec<Model>> {
+        Ok(self.find_page(query)?.models)
+    }
+
+    pub fn find_page(&self, query: &Query) -> Result<QueryPage> {
+        self.scoped_schema(&query.schema)?;
+        let query = query.clone().filter(TENANT_FIELD, Op::Eq, self.tenant_id.clone());
+        self.db.find_page(&query)
+    }
+
+    // Grouped and aggregated results over this tenant's models
+    pub fn aggregate(&self, query: &Query) -> Result<Vec<HashMap<String, Value>>> {
+        self.scoped_schema(&query.schema)?;
+        let query = query.clone().filter(TENANT_FIELD, Op::Eq, self.tenant_id.clone());
+        self.db.aggregate(&query)
+    }
+
+    // Distinct values of `field_name` among this tenant's models that
+    // `filter` matches, as `FlexibleDatabase::distinct_values` gives them
+    pub fn distinct_values(&self, schema_name: &str, field_name: &str, filter: Option<&Query>) -> Result<Vec<Value>> {
+        self.scoped_schema(schema_name)?;
+        let filter = filter.cloned().unwrap_or_else(|| Query::new(schema_name)).filter(
+            TENANT_FIELD,
+            Op::Eq,
+            self.tenant_id.clone(),
+        );
+        self.db.distinct_values(schema_name, field_name, Some(&filter))
+    }
+
+    // Full-text search over this tenant's models
+    pub fn search(&self, schema_name: &str, query: &str) -> Result<Vec<Model>> {
+        self.scoped_schema(schema_name)?;
+        let mut models = self.db.search(schema_name, query)?;
+        models.retain(|model| self.stamped(model));
+        Ok(models)
+    }
+
+    pub fn count(&self, schema_name: &str) -> Result<i64> {
+        self.scoped_schema(schema_name)?;
+        let sql = format!("SELECT COUNT(*) FROM {} WHERE {} = ?", schema_name, TENANT_FIELD);
+        self.db.traced(&sql, 1, || {
+            Ok((
+                self.db
+                    .prepare_cached(&sql)?
+                    .query_row([&self.tenant_id], |row| row.get(0))?,
+                1,
+            ))
+        })
+    }
+
+    // Update one of this tenant's models; other tenants' models are left
+    // alone as if they didn't exist
+    pub fn update_model(
+        &self,
+        schema_name: &str,
+        id: impl Into<ModelId>,
+        data: HashMap<String, Value>,
+    ) -> Result<bool> {
+        let id = id.into();
+        self.check_tenant(schema_name, &data)?;
+        if !self.owns(schema_name, &id)? {
+            return Ok(false);
+        }
+        self.db.update_model(schema_name, id, data)
+    }
+
+    // Increment a number of one of this tenant's models, as
+    // `FlexibleDatabase::increment` does; other tenants' models are left
+    // alone as if they didn't exist
+    pub fn increment(
+        &self,
+        schema_name: &str,
+        id: impl Into<ModelId>,
+        field_name: &str,
+        delta: impl Into<Value>,
+    ) -> Result<Option<Value>> {
+        let id = id.into();
+        if !self.owns(schema_name, &id)? {
+            return Ok(None);
+        }
+        self.db.increment(schema_name, id, field_name, delta)
+    }
+
+    pub fn delete_model(&self, schema_name: &str, id: impl Into<ModelId>) -> Result<bool> {
+        let id = id.into();
+        if !self.owns(schema_name, &id)? {
+            return Ok(false);
+        }
+        self.db.delete_model(schema_name, id)
+    }
+
+    fn scoped_schema(&self, schema_name: &str) -> Result<&Schema> {
+        let schema = self.db.schema_or_err(schema_name)?;
+        if !schema.tenant_scoped {
+            return Err(KooError::InvalidSchema(format!(
+                "'{}' is not tenant-scoped",
+                schema_name
+            )));
+        }
+        Ok(schema)
+    }
+
+    // Models can't be moved to, or created for, another tenant
+    fn check_tenant(&self, schema_name: &str, data: &HashMap<String, Value>) -> Result<()> {
+        match data.get(TENANT_FIELD) {
+            None => Ok(()),
+            Some(Value::Text(tenant_id)) if *tenant_id == self.tenant_id => Ok(()),
+            Some(_) => Err(KooError::InvalidData(format!(
+                "'{}.{}' can only be '{}' here",
+                schema_name, TENANT_FIELD, self.tenant_id
+            ))),
+        }
+    }
+
+    // Whether a model read back belongs to this tenant
+    fn stamped(&self, model: &Model) -> bool {
+        matches!(model.data.get(TENANT_FIELD), Some(Value::Text(t)) if *t == self.tenant_id)
+    }
+
+    // Whether the model exists and belongs to this tenant
+    fn owns(&self, schema_name: &str, id: &ModelId) -> Result<bool> {
+        let schema = self.scoped_schema(schema_name)?;
+        let (key_sql, mut params) = key_filter(schema, id)?;
+        params.push(Value::Text(self.tenant_id.clone()));
+        let sql = format!(
+            "SELECT EXISTS (SELECT 1 FROM {} WHERE {} AND {} = ?)",
+            schema_name, key_sql, TENANT_FIELD
+        );
+        Ok(self
+            .db
+            .prepare_cached(&sql)?
+            .query_row(rusqlite::params_from_iter(&params), |row| row.get(0))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::flexible_database::FieldType;
+    use crate::query::Aggregate;
+
+    fn invoices() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("invoices", [("total".to_string(), FieldType::Integer)]).with_tenancy())
+            .unwrap();
+        db
+    }
+
+    fn invoice(total: i64) -> HashMap<String, Value> {
+        HashMap::from([("total".to_string(), Value::Integer(total))])
+    }
+
+    fn totals(models: &[Model]) -> Vec<Value> {
+        models.iter().map(|model| model.data["total"].clone()).collect()
+    }
+
+    #[test]
+    fn models_are_stamped_with_their_tenant() {
+        let db = invoices();
+        let id = db.tenant("acme").create_model("invoices", invoice(10)).unwrap();
+        let model = db.get_model("invoices", id).unwrap().unwrap();
+        assert_eq!(model.data[TENANT_FIELD], Value::Text("acme".to_string()));
+
+        let mut other = invoice(20);
+        other.insert(TENANT_FIELD.to_string(), Value::Text("globex".to_string()));
+        assert!(matches!(
+            db.tenant("acme").create_model("invoices", other),
+            Err(KooError::InvalidData(_))
+        ));
+    }
+
+    #[test]
+    fn tenants_only_see_their_own_models() {
+        let db = invoices();
+        let (acme, globex) = (db.tenant("acme"), db.tenant("globex"));
+        let theirs = globex.create_model("invoices", invoice(5)).unwrap();
+        acme.create_model("invoices", invoice(10)).unwrap();
+        acme.create_model("invoices", invoice(30)).unwrap();
+
+        assert_eq!(
+            totals(&acme.get_all_models("invoices").unwrap()),
+            [Value::Integer(10), Value::Integer(30)]
+        );
+        assert_eq!(acme.count("invoices").unwrap(), 2);
+        assert_eq!(db.count("invoices").unwrap(), 3);
+        assert!(acme.get_model("invoices", theirs.clone()).unwrap().is_none());
+        assert!(globex.get_model("invoices", theirs).unwrap().is_some());
+
+        let query = Query::new("invoices").filter("total", Op::Lt, 20);
+        assert_eq!(totals(&acme.find(&query).unwrap()), [Value::Integer(10)]);
+    }
+
+    #[test]
+    fn fetches_by_id_leave_out_other_tenants_models() {
+        let db = invoices();
+        let (acme, globex) = (db.tenant("acme"), db.tenant("globex"));
+        let ours = acme.create_model("invoices", invoice(10)).unwrap();
+        let theirs = globex.create_model("invoices", invoice(5)).unwrap();
+        let models = acme
+            .get_models("invoices", [theirs.clone(), ours, ModelId::Integer(9)])
+            .unwrap();
+        assert_eq!(totals(&models), [Value::Integer(10)]);
+        assert_eq!(
+            totals(&globex.get_models("invoices", [theirs]).unwrap()),
+            [Value::Integer(5)]
+        );
+    }
+
+    #[test]
+    fn aggregates_only_cover_the_tenants_models() {
+        let db = invoices();
+        db.tenant("globex").create_model("invoices", invoice(5)).unwrap();
+        let acme = db.tenant("acme");
+        acme.create_model("invoices", invoice(10)).unwrap();
+        acme.create_model("invoices", invoice(30)).unwrap();
+
+        let query = Query::new("invoices").aggregate("sum", Aggregate::Sum("total".to_string()));
+        assert_eq!(acme.aggregate(&query).unwrap()[0]["sum"], Value::Integer(40));
+        assert_eq!(db.aggregate(&query).unwrap()[0]["sum"], Value::Integer(45));
+    }
+
+    #[test]
+    fn distinct_values_only_come_from_the_tenants_models() {
+        let db = invoices();
+        db.tenant("globex").create_model("invoices", invoice(5)).unwrap();
+        let acme = db.tenant("acme");
+        for total in [10, 30, 10] {
+            acme.create_model("invoices", invoice(total)).unwrap();
+        }
+        assert_eq!(
+            acme.distinct_values("invoices", "total", None).unwrap(),
+            [Value::Integer(10), Value::Integer(30)]
+        );
+        let small = Query::new("invoices").filter("total", Op::Lt, 20);
+        assert_eq!(
+            acme.distinct_values("invoices", "total", Some(&small)).unwrap(),
+            [Value::Integer(10)]
+        );
+        assert_eq!(db.distinct_values("invoices", "total", Some(&small)).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn other_tenants_models_cant_be_changed() {
+        let db = invoices();
+        let (acme, globex) = (db.tenant("acme"), db.tenant("globex"));
+        let id = globex.create_model("invoices", invoice(5)).unwrap();
+
+        assert!(!acme.update_model("invoices", id.clone(), invoice(0)).unwrap());
+        assert!(!acme.delete_model("invoices", id.clone()).unwrap());
+        assert_eq!(totals(&globex.get_all_models("invoices").unwrap()), [Value::Integer(5)]);
+
+        let mut moved = invoice(5);
+        moved.insert(TENANT_FIELD.to_string(), Value::Text("acme".to_string()));
+        assert!(globex.update_model("invoices", id.clone(), moved).is_err());
+        assert!(globex.update_model("invoices", id.clone(), invoice(6)).unwrap());
+        assert!(globex.delete_model("invoices", id).unwrap());
+    }
+
+    #[test]
+    fn only_the_tenants_models_can_be_incremented() {
+        let db = invoices();
+        let (acme, globex) = (db.tenant("acme"), db.tenant("globex"));
+        let id = globex.create_model("invoices", invoice(5)).unwrap();
+        assert_eq!(acme.increment("invoices", id.clone(), "total", 1).unwrap(), None);
+        assert_eq!(
+            globex.increment("invoices", id.clone(), "total", 2).unwrap(),
+            Some(Value::Integer(7))
+        );
+        assert_eq!(totals(&globex.get_all_models("invoices").unwrap()), [Value::Integer(7)]);
+    }
+
+    #[test]
+    fn only_tenant_scoped_schemas_can_be_used() {
+        let mut db = invoices();
+        db.define_schema(Schema::new("rates", [("value".to_string(), FieldType::Real)]))
+            .unwrap();
+        assert!(matches!(
+            db.tenant("acme").get_all_models("rates"),
+            Err(KooError::InvalidSchema(_))
+        ));
+
+        let clash = Schema::new("bills", [(TENANT_FIELD.to_string(), FieldType::Integer)]).with_tenancy();
+        assert!(matches!(db.define_schema(clash), Err(KooError::InvalidSchema(_))));
+    }
+
+    #[test]
+    fn the_tenant_field_is_indexed() {
+        let db = invoices();
+        let indexed: bool = db
+            .conn
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = 'invoices_tenant')",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(indexed);
+    }
+}
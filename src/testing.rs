@@ -0,0 +1,285 @@
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+
+use crate::error::Result;
+use crate::flexible_database::{FieldDef, FieldType, FlexibleDatabase, Schema, read_model, row_key, select_sql};
+use crate::wire::model_to_json;
+
+// Set to accept the current contents as the new snapshots in
+// `assert_snapshot`, e.g. after an intended change
+pub const UPDATE_SNAPSHOTS_VAR: &str = "KOO_UPDATE_SNAPSHOTS";
+
+// A database for one test, deleted with its journal files when dropped.
+// Derefs to the FlexibleDatabase.
+pub struct TempDatabase {
+    // Only None while being dropped, so the connection is closed before
+    // the files are removed
+    db: Option<FlexibleDatabase>,
+    path: Option<PathBuf>,
+}
+
+impl TempDatabase {
+    pub fn in_memory() -> Result<TempDatabase> {
+        Ok(TempDatabase {
+            db: Some(FlexibleDatabase::new(":memory:")?),
+            path: None,
+        })
+    }
+
+    // A database in a new file in the temporary directory, for tests that
+    // open it again or need what only files have, such as backups or WAL
+    pub fn file() -> Result<TempDatabase> {
+        let path = std::env::temp_dir().join(format!("koo-test-{}.db", uuid::Uuid::new_v4().simple()));
+        Ok(TempDatabase {
+            db: Some(FlexibleDatabase::new(&path.to_string_lossy())?),
+            path: Some(path),
+        })
+    }
+
+    // The file of a `file` database
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+}
+
+impl Deref for TempDatabase {
+    type Target = FlexibleDatabase;
+
+    fn deref(&self) -> &FlexibleDatabase {
+        self.db.as_ref().expect("database is open")
+    }
+}
+
+impl DerefMut for TempDatabase {
+    fn deref_mut(&mut self) -> &mut FlexibleDatabase {
+        self.db.as_mut().expect("database is open")
+    }
+}
+
+impl Drop for TempDatabase {
+    fn drop(&mut self) {
+        self.db = None;
+        if let Some(path) = &self.path {
+            for suffix in ["", "-journal", "-wal", "-shm"] {
+                let mut file = path.clone().into_os_string();
+                file.push(suffix);
+                let _ = std::fs::remove_file(file);
+            }
+        }
+    }
+}
+
+// A schema named after `prefix` plus a random suffix, such as
+// "users_3f9a0c1b", so tests sharing a database don't collide:
+//
+//   let users = ThrowawaySchema::new("users").text("name").integer("age").define(&mut db)?;
+//
+// Anything else `Schema` offers can be set with `configure`.
+pub struct ThrowawaySchema {
+    schema: Schema,
+}
+
+impl ThrowawaySchema {
+    pub fn new(prefix: &str) -> ThrowawaySchema {
+        let suffix = uuid::Uuid::new_v4().simple().to_string();
+        ThrowawaySchema {
+            schema: Schema::new(&format!("{}_{}", prefix, &suffix[..8]), []),
+        }
+    }
+
+    pub fn field(mut self, field_name: &str, field: impl Into<FieldDef>) -> ThrowawaySchema {
+        self.schema = self.schema.field(field_name, field);
+        self
+    }
+
+    pub fn text(self, field_name: &str) -> ThrowawaySchema {
+        self.field(field_name, FieldType::Text)
+    }
+
+    pub fn integer(self, field_name: &str) -> ThrowawaySchema {
+        self.field(field_name, FieldType::Integer)
+    }
+
+    pub fn real(self, field_name: &str) -> ThrowawaySchema {
+        self.field(field_name, FieldType::Real)
+    }
+
+    pub fn boolean(self, field_name: &str) -> ThrowawaySchema {
+        self.field(field_name, FieldType::Boolean)
+    }
+
+    pub fn reference(self, field_name: &str, target: &str) -> ThrowawaySchema {
+        self.field(field_name, FieldType::Reference(target.to_string()))
+    }
+
+    // Apply `Schema` builders, e.g. `.configure(Schema::with_versioning)`
+    pub fn configure(mut self, f: impl FnOnce(Schema) -> Schema) -> ThrowawaySchema {
+        self.schema = f(self.schema);
+        self
+    }
+
+    // Define the schema in `db`, returning its name
+    pub fn define(self, db: &mut FlexibleDatabase) -> Result<String> {
+        let name = self.schema.name.clone();
+        db.define_schema(self.schema)?;
+        Ok(name)
+    }
+}
+
+// Every model of `schema_name` in the export row layout, in key order, as
+// a JSON array. Access policies don't apply, so the snapshot shows what is
+// stored.
+pub fn snapshot(db: &FlexibleDatabase, schema_name: &str) -> Result<serde_json::Value> {
+    let schema = db.schema_or_err(schema_name)?;
+    let mut stmt = db
+        .conn
+        .prepare(&format!("{} ORDER BY {}", select_sql(schema), row_key(schema)))?;
+    let mut rows = stmt.query([])?;
+    let mut models = Vec::new();
+    while let Some(row) = rows.next()? {
+        models.push(model_to_json(schema, &read_model(row, schema)?));
+    }
+    Ok(serde_json::Value::Array(models))
+}
+
+// Panic unless the models of `schema_name` are `expected`, as `snapshot`
+// gives them, e.g. `json!([{"id": 1, "name": "Ann"}])`
+#[track_caller]
+pub fn assert_models(db: &FlexibleDatabase, schema_name: &str, expected: serde_json::Value) {
+    let actual = snapshot(db, schema_name).unwrap_or_else(|err| panic!("can't snapshot '{}': {}", schema_name, err));
+    if actual != expected {
+        panic!(
+            "models of '{}' don't match\nexpected: {}\n  actual: {}",
+            schema_name,
+            pretty(&expected),
+            pretty(&actual)
+        );
+    }
+}
+
+// Panic unless the models of `schema_name` match the snapshot stored at
+// `path`. A missing snapshot is written rather than compared, and all are
+// rewritten when `UPDATE_SNAPSHOTS_VAR` is set, so review the file's diff
+// before committing it.
+#[track_caller]
+pub fn assert_snapshot<P: AsRef<Path>>(db: &FlexibleDatabase, schema_name: &str, path: P) {
+    let path = path.as_ref();
+    let actual = snapshot(db, schema_name).unwrap_or_else(|err| panic!("can't snapshot '{}': {}", schema_name, err));
+    let actual = format!("{}\n", pretty(&actual));
+    if !path.exists() || std::env::var_os(UPDATE_SNAPSHOTS_VAR).is_some() {
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        std::fs::write(path, &actual).unwrap_or_else(|err| panic!("can't write {}: {}", path.display(), err));
+        return;
+    }
+    let expected = std::fs::read_to_string(path).unwrap_or_else(|err| panic!("can't read {}: {}", path.display(), err));
+    if actual != expected {
+        panic!(
+            "models of '{}' don't match the snapshot {}; set {}=1 to accept them\nexpected: {}  actual: {}",
+            schema_name,
+            path.display(),
+            UPDATE_SNAPSHOTS_VAR,
+            expected,
+            actual
+        );
+    }
+}
+
+fn pretty(json: &serde_json::Value) -> String {
+    serde_json::to_string_pretty(json).unwrap_or_else(|_| json.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::Value;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::panic::{self, AssertUnwindSafe};
+
+    fn people(db: &mut FlexibleDatabase) -> String {
+        let people = ThrowawaySchema::new("people")
+            .text("name")
+            .integer("age")
+            .define(db)
+            .unwrap();
+        for (name, age) in [("Ann", 36), ("Bob", 41)] {
+            let data = HashMap::from([
+                ("name".to_string(), Value::Text(name.to_string())),
+                ("age".to_string(), Value::Integer(age)),
+            ]);
+            db.create_model(&people, data).unwrap();
+        }
+        people
+    }
+
+    #[test]
+    fn file_databases_are_deleted_when_dropped() {
+        let mut db = TempDatabase::file().unwrap();
+        let path = db.path().unwrap().to_path_buf();
+        people(&mut db);
+        assert!(path.exists());
+        drop(db);
+        assert!(!path.exists());
+        assert_eq!(TempDatabase::in_memory().unwrap().path(), None);
+    }
+
+    #[test]
+    fn throwaway_schemas_get_their_own_names() {
+        let mut db = TempDatabase::in_memory().unwrap();
+        let first = people(&mut db);
+        let second = people(&mut db);
+        assert_ne!(first, second);
+        assert!(first.starts_with("people_") && first.len() == "people_".len() + 8);
+
+        let versioned = ThrowawaySchema::new("flags")
+            .boolean("active")
+            .real("weight")
+            .reference("owner", &first)
+            .configure(Schema::with_versioning)
+            .define(&mut db)
+            .unwrap();
+        let schema = &db.schemas[&versioned];
+        assert!(schema.versioned);
+        assert_eq!(schema.fields["owner"], FieldType::Reference(first));
+    }
+
+    #[test]
+    fn models_match_their_snapshot() {
+        let mut db = TempDatabase::in_memory().unwrap();
+        let people = people(&mut db);
+        assert_models(
+            &db,
+            &people,
+            json!([{"id": 1, "name": "Ann", "age": 36}, {"id": 2, "name": "Bob", "age": 41}]),
+        );
+        assert!(snapshot(&db, "missing").is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "don't match")]
+    fn mismatched_models_panic() {
+        let mut db = TempDatabase::in_memory().unwrap();
+        let people = people(&mut db);
+        assert_models(&db, &people, json!([]));
+    }
+
+    #[test]
+    fn snapshot_files_are_written_then_compared() {
+        let mut db = TempDatabase::in_memory().unwrap();
+        let people = people(&mut db);
+        let dir = std::env::temp_dir().join(format!("koo-snapshots-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("people.json");
+
+        assert_snapshot(&db, &people, &path);
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.contains("\"Ann\"") && written.ends_with("]\n"));
+        assert_snapshot(&db, &people, &path);
+
+        db.delete_model(&people, 1).unwrap();
+        let changed = panic::catch_unwind(AssertUnwindSafe(|| assert_snapshot(&db, &people, &path)));
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(changed.is_err());
+    }
+}
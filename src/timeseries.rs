@@ -0,0 +1,327 @@
+use rusqlite::params;
+use std::ops::Range;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::Result;
+use crate::flexible_database::FlexibleDatabase;
+
+// Width of the buckets `query_bucketed` groups points into. Calendar
+// buckets start on the minute, hour, day, Monday, month or year, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BucketInterval {
+    // A fixed number of seconds, counted from the Unix epoch
+    Seconds(u32),
+    Minute,
+    Hour,
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl BucketInterval {
+    // Expression for the start of the bucket holding `ts`, as UTC text
+    fn sql(&self) -> String {
+        match self {
+            BucketInterval::Seconds(seconds) => {
+                // Rounded down to a multiple, before the epoch too
+                let seconds = (*seconds).max(1);
+                let whole = "CAST(ts AS INTEGER)";
+                format!("datetime({whole} - ({whole} % {seconds} + {seconds}) % {seconds}, 'unixepoch')")
+            }
+            BucketInterval::Minute => "strftime('%Y-%m-%d %H:%M:00', ts, 'unixepoch')".to_string(),
+            BucketInterval::Hour => "strftime('%Y-%m-%d %H:00:00', ts, 'unixepoch')".to_string(),
+            BucketInterval::Day => "strftime('%Y-%m-%d 00:00:00', ts, 'unixepoch')".to_string(),
+            BucketInterval::Week => {
+                "strftime('%Y-%m-%d 00:00:00', ts, 'unixepoch', '-6 days', 'weekday 1')".to_string()
+            }
+            BucketInterval::Month => "strftime('%Y-%m-01 00:00:00', ts, 'unixepoch')".to_string(),
+            BucketInterval::Year => "strftime('%Y-01-01 00:00:00', ts, 'unixepoch')".to_string(),
+        }
+    }
+}
+
+// Value computed over the points of each bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeAggregate {
+    Avg,
+    Sum,
+    Min,
+    Max,
+    Count,
+    // The value of the latest point
+    Last,
+}
+
+impl TimeAggregate {
+    fn sql(&self) -> &'static str {
+        match self {
+            TimeAggregate::Avg => "AVG(value)",
+            TimeAggregate::Sum => "SUM(value)",
+            TimeAggregate::Min => "MIN(value)",
+            TimeAggregate::Max => "MAX(value)",
+            TimeAggregate::Count => "COUNT(*)",
+            // A bare column next to MAX() is read from the row holding it
+            TimeAggregate::Last => "value",
+        }
+    }
+}
+
+// One bucket of `query_bucketed`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bucket {
+    // Start of the bucket, e.g. "2024-05-01 13:00:00", in UTC
+    pub start: String,
+    pub value: f64,
+    // Points that fell in the bucket
+    pub count: usize,
+}
+
+// Numeric measurements over time, such as request latencies or queue
+// depths, kept in `_koo_timeseries` apart from the models, from
+// `FlexibleDatabase::timeseries`. Points are stored as they are recorded
+// and bucketed when read, so any interval can be asked for later.
+pub struct TimeSeries<'a> {
+    db: &'a FlexibleDatabase,
+}
+
+impl FlexibleDatabase {
+    pub fn timeseries(&self) -> Result<TimeSeries<'_>> {
+        self.conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS _koo_timeseries (
+                metric TEXT NOT NULL,
+                ts REAL NOT NULL,
+                value REAL NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS _koo_timeseries_metric ON _koo_timeseries (metric, ts);",
+        )?;
+        Ok(TimeSeries { db: self })
+    }
+}
+
+impl TimeSeries<'_> {
+    pub fn record(&self, metric: &str, timestamp: SystemTime, value: f64) -> Result<()> {
+        let sql = "INSERT INTO _koo_timeseries (metric, ts, value) VALUES (?, ?, ?)";
+        self.db.traced(sql, 3, || {
+            let inserted = self.db.retrying(|| {
+                Ok(self
+                    .db
+                    .prepare_cached(sql)?
+                    .execute(params![metric, unix_seconds(timestamp), value])?)
+            })?;
+            Ok(((), inserted))
+        })
+    }
+
+    // The points of `metric` recorded in `range`, grouped into buckets of
+    // `interval` and reduced with `aggregate`, oldest first. Buckets
+    // without points are left out.
+    pub fn query_bucketed(
+        &self,
+        metric: &str,
+        range: Range<SystemTime>,
+        interval: BucketInterval,
+        aggregate: TimeAggregate,
+    ) -> Result<Vec<Bucket>> {
+        let last = match aggregate {
+            TimeAggregate::Last => ", MAX(ts)",
+            _ => "",
+        };
+        let sql = format!(
+            "SELECT {bucket} AS bucket, {value}, COUNT(*){last} FROM _koo_timeseries
+             WHERE metric = ? AND ts >= ? AND ts < ? GROUP BY bucket ORDER BY bucket",
+            bucket = interval.sql(),
+            value = aggregate.sql()
+        );
+        self.db.traced(&sql, 3, || {
+            let buckets = self
+                .db
+                .prepare_cached(&sql)?
+                .query_map(
+                    params![metric, unix_seconds(range.start), unix_seconds(range.end)],
+                    |row| {
+                        Ok(Bucket {
+                            start: row.get(0)?,
+                            value: row.get(1)?,
+                            count: row.get::<_, i64>(2)? as usize,
+                        })
+                    },
+                )?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let count = buckets.len();
+            Ok((buckets, count))
+        })
+    }
+
+    // Delete the points of `metric` recorded before `cutoff`, returning
+    // how many there were
+    pub fn prune(&self, metric: &str, cutoff: SystemTime) -> Result<usize> {
+        let sql = "DELETE FROM _koo_timeseries WHERE metric = ? AND ts < ?";
+        self.db.traced(sql, 2, || {
+            let deleted = self.db.retrying(|| {
+                Ok(self
+                    .db
+                    .prepare_cached(sql)?
+                    .execute(params![metric, unix_seconds(cutoff)])?)
+            })?;
+            Ok((deleted, deleted))
+        })
+    }
+}
+
+// Seconds since the Unix epoch, negative before it
+fn unix_seconds(time: SystemTime) -> f64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs_f64(),
+        Err(before) => -before.duration().as_secs_f64(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // 2024-05-06 12:53:20 UTC, a Monday
+    const T: i64 = 1_715_000_000;
+
+    fn at(seconds: i64) -> SystemTime {
+        match seconds {
+            0.. => UNIX_EPOCH + Duration::from_secs(seconds as u64),
+            _ => UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs()),
+        }
+    }
+
+    fn everything() -> Range<SystemTime> {
+        at(-1_000_000)..at(2 * T)
+    }
+
+    fn starts(buckets: &[Bucket]) -> Vec<&str> {
+        buckets.iter().map(|bucket| bucket.start.as_str()).collect()
+    }
+
+    fn values(buckets: &[Bucket]) -> Vec<f64> {
+        buckets.iter().map(|bucket| bucket.value).collect()
+    }
+
+    // Points in the 12:00, 13:00 and 14:00 hours, recorded out of order
+    fn latencies(db: &FlexibleDatabase) -> TimeSeries<'_> {
+        let series = db.timeseries().unwrap();
+        for (offset, value) in [(0, 10.0), (3000, 6.0), (600, 4.0), (7200, 1.0)] {
+            series.record("latency", at(T + offset), value).unwrap();
+        }
+        series.record("depth", at(T), 99.0).unwrap();
+        series
+    }
+
+    #[test]
+    fn buckets_reduce_their_points() {
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        let series = latencies(&db);
+        let hourly = |aggregate| {
+            series
+                .query_bucketed("latency", everything(), BucketInterval::Hour, aggregate)
+                .unwrap()
+        };
+
+        let averages = hourly(TimeAggregate::Avg);
+        assert_eq!(
+            starts(&averages),
+            ["2024-05-06 12:00:00", "2024-05-06 13:00:00", "2024-05-06 14:00:00"]
+        );
+        assert_eq!(values(&averages), [10.0, 5.0, 1.0]);
+        assert_eq!(averages.iter().map(|b| b.count).collect::<Vec<_>>(), [1, 2, 1]);
+        assert_eq!(values(&hourly(TimeAggregate::Sum)), [10.0, 10.0, 1.0]);
+        assert_eq!(values(&hourly(TimeAggregate::Min)), [10.0, 4.0, 1.0]);
+        assert_eq!(values(&hourly(TimeAggregate::Max)), [10.0, 6.0, 1.0]);
+        assert_eq!(values(&hourly(TimeAggregate::Count)), [1.0, 2.0, 1.0]);
+        // The latest point, not the last recorded
+        assert_eq!(values(&hourly(TimeAggregate::Last)), [10.0, 6.0, 1.0]);
+    }
+
+    #[test]
+    fn ranges_include_the_start_only() {
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        let series = latencies(&db);
+        let buckets = series
+            .query_bucketed(
+                "latency",
+                at(T + 600)..at(T + 7200),
+                BucketInterval::Day,
+                TimeAggregate::Sum,
+            )
+            .unwrap();
+        assert_eq!(starts(&buckets), ["2024-05-06 00:00:00"]);
+        assert_eq!((buckets[0].value, buckets[0].count), (10.0, 2));
+        let missing = series
+            .query_bucketed("errors", everything(), BucketInterval::Day, TimeAggregate::Sum)
+            .unwrap();
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn buckets_start_on_their_boundaries() {
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        let series = db.timeseries().unwrap();
+        // Sunday 2024-05-05 23:59, Monday 12:53, Sunday 2024-05-12 10:00,
+        // 2024-12-31 10:00 and a second before the epoch
+        for seconds in [1_714_953_540, T, 1_715_508_000, 1_735_639_200, -1] {
+            series.record("m", at(seconds), 1.0).unwrap();
+        }
+        let bucketed = |interval| {
+            series
+                .query_bucketed("m", everything(), interval, TimeAggregate::Count)
+                .unwrap()
+        };
+
+        assert_eq!(
+            starts(&bucketed(BucketInterval::Seconds(900))),
+            [
+                "1969-12-31 23:45:00",
+                "2024-05-05 23:45:00",
+                "2024-05-06 12:45:00",
+                "2024-05-12 10:00:00",
+                "2024-12-31 10:00:00"
+            ]
+        );
+        assert_eq!(
+            starts(&bucketed(BucketInterval::Minute))[2..4],
+            ["2024-05-06 12:53:00", "2024-05-12 10:00:00"]
+        );
+        let weeks = bucketed(BucketInterval::Week);
+        assert_eq!(
+            starts(&weeks),
+            [
+                "1969-12-29 00:00:00",
+                "2024-04-29 00:00:00",
+                "2024-05-06 00:00:00",
+                "2024-12-30 00:00:00"
+            ]
+        );
+        assert_eq!(values(&weeks), [1.0, 1.0, 2.0, 1.0]);
+        assert_eq!(
+            starts(&bucketed(BucketInterval::Month)),
+            ["1969-12-01 00:00:00", "2024-05-01 00:00:00", "2024-12-01 00:00:00"]
+        );
+        assert_eq!(
+            starts(&bucketed(BucketInterval::Year)),
+            ["1969-01-01 00:00:00", "2024-01-01 00:00:00"]
+        );
+    }
+
+    #[test]
+    fn pruning_drops_old_points_of_one_metric() {
+        let db = FlexibleDatabase::new(":memory:").unwrap();
+        let series = latencies(&db);
+        assert_eq!(series.prune("latency", at(T + 3000)).unwrap(), 2);
+        assert_eq!(series.prune("latency", at(T + 3000)).unwrap(), 0);
+        let left = series
+            .query_bucketed("latency", everything(), BucketInterval::Year, TimeAggregate::Count)
+            .unwrap();
+        assert_eq!(left[0].count, 2);
+        let depth = series
+            .query_bucketed("depth", everything(), BucketInterval::Year, TimeAggregate::Count)
+            .unwrap();
+        assert_eq!(depth[0].count, 1);
+    }
+}
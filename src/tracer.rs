@@ -0,0 +1,205 @@
+use std::time::{Duration, Instant};
+
+use crate::error::Result;
+use crate::flexible_database::FlexibleDatabase;
+
+// A statement run by kooDB, reported to the tracer after it finishes
+#[derive(Debug, Clone)]
+pub struct QueryEvent {
+    pub sql: String,
+    // Number of bound parameters
+    pub param_count: usize,
+    pub duration: Duration,
+    // Rows changed by a write, or rows returned by a read
+    pub rows: usize,
+    // Set when the statement failed
+    pub error: Option<String>,
+    // Whether it took longer than the slow query threshold
+    pub slow: bool,
+    // SQLite's EXPLAIN QUERY PLAN for slow statements, as in `SqlPlan`
+    pub query_plan: Option<Vec<String>>,
+}
+
+pub(crate) type Tracer = Box<dyn Fn(QueryEvent) + Send>;
+
+impl FlexibleDatabase {
+    // Receive an event for every statement issued by the CRUD, query,
+    // search and iteration APIs. With the `tracing` feature, events are also
+    // emitted as debug-level `tracing` events under the `koo_db::sql` target.
+    pub fn set_tracer(&mut self, tracer: impl Fn(QueryEvent) + Send + 'static) {
+        self.tracer = Some(Box::new(tracer));
+    }
+
+    pub fn clear_tracer(&mut self) {
+        self.tracer = None;
+    }
+
+    // Flag statements that take longer than `threshold` as slow: their
+    // events carry `slow` and the query plan, which shows the table scans
+    // an index would avoid. With the `tracing` feature they are also
+    // emitted as warn-level events under the `koo_db::slow_query` target.
+    pub fn set_slow_query_threshold(&mut self, threshold: Duration) {
+        self.slow_query_threshold = Some(threshold);
+    }
+
+    pub fn clear_slow_query_threshold(&mut self) {
+        self.slow_query_threshold = None;
+    }
+
+    // Time `f`, which runs `sql` and returns its result plus a row count,
+    // and report the outcome
+    pub(crate) fn traced<T>(&self, sql: &str, param_count: usize, f: impl FnOnce() -> Result<(T, usize)>) -> Result<T> {
+        if self.tracer.is_none() && !cfg!(feature = "tracing") && !cfg!(feature = "metrics") {
+            return f().map(|(value, _)| value);
+        }
+
+        let started = Instant::now();
+        let result = f();
+        let duration = started.elapsed();
+        let (rows, error) = match &result {
+            Ok((_, rows)) => (*rows, None),
+            Err(err) => (0, Some(err.to_string())),
+        };
+        #[cfg(feature = "metrics")]
+        self.metrics.record_query(sql, duration, rows, error.is_some());
+        if self.tracer.is_none() && !cfg!(feature = "tracing") {
+            return result.map(|(value, _)| value);
+        }
+        let slow = self.slow_query_threshold.is_some_and(|threshold| duration > threshold);
+        // Statements SQLite can't explain, such as DDL, go without a plan
+        let query_plan = match slow {
+            true => self.query_plan(sql, None).ok(),
+            false => None,
+        };
+        self.emit(QueryEvent {
+            sql: sql.to_string(),
+            param_count,
+            duration,
+            rows,
+            error,
+            slow,
+            query_plan,
+        });
+        result.map(|(value, _)| value)
+    }
+
+    fn emit(&self, event: QueryEvent) {
+        #[cfg(feature = "tracing")]
+        if event.slow {
+            tracing::warn!(
+                target: "koo_db::slow_query",
+                sql = %event.sql,
+                params = event.param_count,
+                duration_us = event.duration.as_micros() as u64,
+                rows = event.rows,
+                query_plan = event.query_plan.as_ref().map(|plan| plan.join("; ")),
+            );
+        }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            target: "koo_db::sql",
+            sql = %event.sql,
+            params = event.param_count,
+            duration_us = event.duration.as_micros() as u64,
+            rows = event.rows,
+            error = event.error.as_deref(),
+        );
+
+        if let Some(tracer) = &self.tracer {
+            tracer(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::Value;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use crate::flexible_database::{FieldType, Schema};
+    use crate::query::{Op, Query};
+
+    // Database with a "t" schema whose events land in the returned list
+    fn traced_db() -> (FlexibleDatabase, Arc<Mutex<Vec<QueryEvent>>>) {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("t", [("n".to_string(), FieldType::Integer)]))
+            .unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        db.set_tracer(move |event| sink.lock().unwrap().push(event));
+        (db, events)
+    }
+
+    fn create(db: &FlexibleDatabase, n: i64) {
+        db.create_model("t", HashMap::from([("n".to_string(), Value::Integer(n))]))
+            .unwrap();
+    }
+
+    #[test]
+    fn statements_are_reported_with_their_parameters_and_rows() {
+        let (db, events) = traced_db();
+        create(&db, 1);
+        create(&db, 2);
+        db.get_all_models("t").unwrap();
+
+        let events = events.lock().unwrap();
+        let insert = events.iter().find(|event| event.sql.starts_with("INSERT")).unwrap();
+        assert_eq!(insert.param_count, 1);
+        assert_eq!(insert.rows, 1);
+        let read = events.last().unwrap();
+        assert!(read.sql.starts_with("SELECT"));
+        assert_eq!(read.rows, 2);
+        assert!(events.iter().all(|event| event.error.is_none() && !event.slow));
+    }
+
+    #[test]
+    fn failed_statements_carry_their_error() {
+        let (db, events) = traced_db();
+        assert!(db.query_raw("SELECT * FROM missing", &[]).is_err());
+        let events = events.lock().unwrap();
+        assert!(events.last().unwrap().error.as_ref().unwrap().contains("missing"));
+    }
+
+    #[test]
+    fn cleared_tracers_hear_nothing_more() {
+        let (mut db, events) = traced_db();
+        create(&db, 1);
+        let seen = events.lock().unwrap().len();
+        db.clear_tracer();
+        create(&db, 2);
+        assert_eq!(events.lock().unwrap().len(), seen);
+    }
+
+    #[test]
+    fn slow_statements_carry_their_query_plan() {
+        let (mut db, events) = traced_db();
+        db.set_slow_query_threshold(Duration::ZERO);
+        db.get_all_models("t").unwrap();
+        let event = events.lock().unwrap().last().unwrap().clone();
+        assert!(event.slow);
+        assert!(event.query_plan.unwrap().iter().any(|step| step.contains("t")));
+
+        db.clear_slow_query_threshold();
+        db.get_all_models("t").unwrap();
+        assert!(!events.lock().unwrap().last().unwrap().slow);
+    }
+
+    #[test]
+    fn only_statements_over_the_threshold_are_slow() {
+        let (mut db, events) = traced_db();
+        db.set_slow_query_threshold(Duration::from_secs(3600));
+        create(&db, 1);
+        db.get_all_models("t").unwrap();
+        let fast = |event: &QueryEvent| !event.slow && event.query_plan.is_none();
+        assert!(events.lock().unwrap().iter().all(fast));
+
+        // Placeholders are planned as NULL
+        db.set_slow_query_threshold(Duration::ZERO);
+        let query = Query::new("t").filter("n", Op::Eq, 1);
+        db.find(&query).unwrap();
+        let plan = events.lock().unwrap().last().unwrap().query_plan.clone().unwrap();
+        assert!(plan[0].starts_with("SCAN t"), "{:?}", plan);
+    }
+}
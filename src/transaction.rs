@@ -0,0 +1,122 @@
+use crate::error::{KooError, Result};
+use crate::flexible_database::FlexibleDatabase;
+
+#[derive(Debug, Clone, Default)]
+pub struct TransactionOptions {
+    // Check foreign keys when the transaction commits instead of after each
+    // statement, so rows with forward or circular references can be
+    // inserted in any order
+    pub defer_foreign_keys: bool,
+}
+
+impl FlexibleDatabase {
+    // Run `f` in a transaction that commits if it returns Ok and rolls back
+    // otherwise
+    pub fn transaction<T>(&mut self, f: impl FnOnce(&mut FlexibleDatabase) -> Result<T>) -> Result<T> {
+        self.transaction_with(TransactionOptions::default(), f)
+    }
+
+    pub fn transaction_with<T>(
+        &mut self,
+        options: TransactionOptions,
+        f: impl FnOnce(&mut FlexibleDatabase) -> Result<T>,
+    ) -> Result<T> {
+        self.in_transaction(|db| {
+            if options.defer_foreign_keys {
+                // SQLite resets this pragma when the transaction ends
+                db.conn.execute_batch("PRAGMA defer_foreign_keys = ON")?;
+            }
+
+            let value = f(db)?;
+
+            if options.defer_foreign_keys {
+                db.check_foreign_keys()?;
+            }
+            Ok(value)
+        })
+    }
+
+    // Report the first row whose reference points at a missing model
+    fn check_foreign_keys(&self) -> Result<()> {
+        let mut stmt = self.conn.prepare("PRAGMA foreign_key_check")?;
+        let mut rows = stmt.query([])?;
+        if let Some(row) = rows.next()? {
+            return Err(KooError::ForeignKeyViolation {
+                schema: row.get(0)?,
+                id: row.get(1)?,
+                references: row.get(2)?,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flexible_database::{FieldType, Schema};
+    use crate::model_builder::ModelBuilder;
+
+    // Employees reporting to a manager, who is an employee too
+    fn staff() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new(
+            "staff",
+            [
+                ("name".to_string(), FieldType::Text),
+                ("manager".to_string(), FieldType::Reference("staff".to_string())),
+            ],
+        );
+        db.define_schema(schema).unwrap();
+        db
+    }
+
+    // Ids are handed out in order, so the first is 1
+    fn employee(name: &str, manager: i64) -> std::collections::HashMap<String, rusqlite::types::Value> {
+        ModelBuilder::new().text("name", name).set("manager", manager).build()
+    }
+
+    #[test]
+    fn transactions_commit_on_ok_and_roll_back_on_err() {
+        let mut db = staff();
+        db.transaction(|db| db.create_model("staff", employee("Ada", 1)))
+            .unwrap();
+        let result: Result<()> = db.transaction(|db| {
+            db.create_model("staff", employee("Bob", 1))?;
+            Err(KooError::InvalidData("abandoned".to_string()))
+        });
+        assert!(result.is_err());
+        assert_eq!(db.count("staff").unwrap(), 1);
+    }
+
+    #[test]
+    fn forward_references_need_deferred_checks() {
+        let mut db = staff();
+        let eager = db.transaction(|db| db.create_model("staff", employee("Ada", 2)));
+        assert!(eager.is_err());
+
+        let options = TransactionOptions {
+            defer_foreign_keys: true,
+        };
+        db.transaction_with(options, |db| {
+            db.create_model("staff", employee("Ada", 2))?;
+            db.create_model("staff", employee("Bob", 1))
+        })
+        .unwrap();
+        assert_eq!(db.count("staff").unwrap(), 2);
+    }
+
+    #[test]
+    fn dangling_references_fail_the_deferred_commit() {
+        let mut db = staff();
+        let options = TransactionOptions {
+            defer_foreign_keys: true,
+        };
+        let result = db.transaction_with(options, |db| db.create_model("staff", employee("Ada", 9)));
+        assert!(matches!(
+            result,
+            Err(KooError::ForeignKeyViolation { schema, id: 1, references }) if schema == "staff" && references == "staff"
+        ));
+        assert_eq!(db.count("staff").unwrap(), 0);
+    }
+}
@@ -0,0 +1,260 @@
+use rusqlite::types::Value;
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FlexibleDatabase, Model, Schema};
+
+// Field added by `Schema::with_hierarchy`
+pub const PARENT_FIELD: &str = "parent_id";
+
+// A model below the root of `get_subtree`, with its distance from the root
+#[derive(Debug, Clone)]
+pub struct TreeNode {
+    pub model: Model,
+    // 1 for children of the root, 2 for their children, and so on
+    pub depth: usize,
+}
+
+// Hierarchical schemas keep trees, such as categories, threaded comments or
+// org charts, by storing each model's parent in `parent_id`; models without
+// one are roots. The helpers walk the tree in SQL with recursive queries,
+// then read the models they find, so the access policy applies to each of
+// them. A cycle, which only writes from outside kooDB can make, stops the
+// walk where it would repeat a model.
+impl FlexibleDatabase {
+    // Models without a parent, by id
+    pub fn get_roots(&self, schema_name: &str) -> Result<Vec<Model>> {
+        let schema = self.hierarchical_schema(schema_name)?;
+        let sql = format!("SELECT id FROM {} WHERE {} IS NULL ORDER BY id", schema.name, PARENT_FIELD);
+        let ids = self.tree_ids(&sql, &[])?;
+        self.get_models(schema_name, ids.into_iter().map(|(id, _)| id))
+    }
+
+    // Models whose parent is `id`, by id
+    pub fn get_children(&self, schema_name: &str, id: i64) -> Result<Vec<Model>> {
+        let schema = self.hierarchical_schema(schema_name)?;
+        let sql = format!("SELECT id FROM {} WHERE {} = ? ORDER BY id", schema.name, PARENT_FIELD);
+        let ids = self.tree_ids(&sql, &[Value::Integer(id)])?;
+        self.get_models(schema_name, ids.into_iter().map(|(id, _)| id))
+    }
+
+    // The parent of `id`, its parent, and so on up to the root
+    pub fn get_ancestors(&self, schema_name: &str, id: i64) -> Result<Vec<Model>> {
+        let schema = self.hierarchical_schema(schema_name)?;
+        let sql = format!(
+            "WITH RECURSIVE up (id, parent, depth, path) AS (
+                SELECT id, {parent}, 0, '/' || id || '/' FROM {table} WHERE id = ?
+                UNION ALL
+                SELECT t.id, t.{parent}, up.depth + 1, up.path || t.id || '/'
+                    FROM {table} t JOIN up ON t.id = up.parent
+                    WHERE instr(up.path, '/' || t.id || '/') = 0
+            )
+            SELECT id, depth FROM up WHERE depth > 0 ORDER BY depth",
+            table = schema.name,
+            parent = PARENT_FIELD
+        );
+        let ids = self.tree_ids(&sql, &[Value::Integer(id)])?;
+        self.get_models(schema_name, ids.into_iter().map(|(id, _)| id))
+    }
+
+    // Everything below `id`, depth first with siblings by id, going at most
+    // `depth` levels down; None for the whole subtree
+    pub fn get_subtree(&self, schema_name: &str, id: i64, depth: Option<usize>) -> Result<Vec<TreeNode>> {
+        let schema = self.hierarchical_schema(schema_name)?;
+        // Ids padded to one width, so the paths sort depth first
+        let sql = format!(
+            "WITH RECURSIVE down (id, depth, path) AS (
+                SELECT id, 0, printf('%020d/', id) FROM {table} WHERE id = ?
+                UNION ALL
+                SELECT t.id, down.depth + 1, down.path || printf('%020d/', t.id)
+                    FROM {table} t JOIN down ON t.{parent} = down.id
+                    WHERE (?2 IS NULL OR down.depth < ?2) AND instr(down.path, printf('%020d/', t.id)) = 0
+            )
+            SELECT id, depth FROM down WHERE depth > 0 ORDER BY path",
+            table = schema.name,
+            parent = PARENT_FIELD
+        );
+        let max_depth = match depth {
+            Some(depth) => Value::Integer(depth as i64),
+            None => Value::Null,
+        };
+        let nodes = self.tree_ids(&sql, &[Value::Integer(id), max_depth])?;
+
+        let mut models = self
+            .get_models(schema_name, nodes.iter().map(|(id, _)| *id))?
+            .into_iter()
+            .peekable();
+        let mut subtree = Vec::new();
+        for (id, depth) in nodes {
+            // Models the access policy hides are missing
+            if models.peek().and_then(|model| model.id.as_ref()?.as_i64()) == Some(id)
+                && let Some(model) = models.next()
+            {
+                subtree.push(TreeNode {
+                    model,
+                    depth: depth as usize,
+                });
+            }
+        }
+        Ok(subtree)
+    }
+
+    // Index the parent field, which every walk down the tree looks up
+    pub(crate) fn create_parent_index(&self, schema: &Schema) -> Result<()> {
+        // An attached database's index is named in it, but its table isn't
+        let table = schema.name.rsplit('.').next().unwrap_or(&schema.name);
+        self.conn.execute(
+            &format!(
+                "CREATE INDEX IF NOT EXISTS {}_parent ON {} ({})",
+                schema.name, table, PARENT_FIELD
+            ),
+            [],
+        )?;
+        Ok(())
+    }
+
+    fn hierarchical_schema(&self, schema_name: &str) -> Result<&Schema> {
+        let schema = self.schema_or_err(schema_name)?;
+        if !schema.hierarchical {
+            return Err(KooError::InvalidSchema(format!(
+                "schema '{}' isn't hierarchical",
+                schema_name
+            )));
+        }
+        Ok(schema)
+    }
+
+    // (id, depth) rows of a tree query; depth is 0 where it has none
+    fn tree_ids(&self, sql: &str, params: &[Value]) -> Result<Vec<(i64, i64)>> {
+        self.traced(sql, params.len(), || {
+            let mut stmt = self.prepare_cached(sql)?;
+            let columns = stmt.column_count();
+            let ids = stmt
+                .query_map(rusqlite::params_from_iter(params), |row| {
+                    let depth = if columns > 1 { row.get(1)? } else { 0 };
+                    Ok((row.get(0)?, depth))
+                })?
+                .collect::<rusqlite::Result<Vec<(i64, i64)>>>()?;
+            let count = ids.len();
+            Ok((ids, count))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::flexible_database::{FieldType, PrimaryKey};
+
+    // Home (1) holds Garden (2) and Kitchen (3), Garden holds Tools (4), and
+    // Office (5) is a second root
+    fn categories() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("categories", [("name".to_string(), FieldType::Text)]).with_hierarchy())
+            .unwrap();
+        let entries = [
+            ("Home", Value::Null),
+            ("Garden", Value::Integer(1)),
+            ("Kitchen", Value::Integer(1)),
+            ("Tools", Value::Integer(2)),
+            ("Office", Value::Null),
+        ];
+        for (name, parent) in entries {
+            let data = HashMap::from([
+                ("name".to_string(), Value::Text(name.to_string())),
+                (PARENT_FIELD.to_string(), parent),
+            ]);
+            db.create_model("categories", data).unwrap();
+        }
+        db
+    }
+
+    fn names(models: &[Model]) -> Vec<String> {
+        models
+            .iter()
+            .map(|model| match &model.data["name"] {
+                Value::Text(name) => name.clone(),
+                other => panic!("unexpected name {:?}", other),
+            })
+            .collect()
+    }
+
+    fn subtree(db: &FlexibleDatabase, id: i64, depth: Option<usize>) -> Vec<(String, usize)> {
+        let nodes = db.get_subtree("categories", id, depth).unwrap();
+        let models: Vec<Model> = nodes.iter().map(|node| node.model.clone()).collect();
+        names(&models)
+            .into_iter()
+            .zip(nodes.iter().map(|node| node.depth))
+            .collect()
+    }
+
+    #[test]
+    fn roots_children_and_ancestors() {
+        let db = categories();
+        assert_eq!(names(&db.get_roots("categories").unwrap()), ["Home", "Office"]);
+        assert_eq!(names(&db.get_children("categories", 1).unwrap()), ["Garden", "Kitchen"]);
+        assert!(db.get_children("categories", 4).unwrap().is_empty());
+        assert_eq!(names(&db.get_ancestors("categories", 4).unwrap()), ["Garden", "Home"]);
+        assert!(db.get_ancestors("categories", 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn subtrees_go_depth_first() {
+        let db = categories();
+        let whole = [
+            ("Garden".to_string(), 1),
+            ("Tools".to_string(), 2),
+            ("Kitchen".to_string(), 1),
+        ];
+        assert_eq!(subtree(&db, 1, None), whole);
+        assert_eq!(subtree(&db, 1, Some(1)), [whole[0].clone(), whole[2].clone()]);
+        assert!(subtree(&db, 5, None).is_empty());
+    }
+
+    #[test]
+    fn cycles_stop_the_walk() {
+        let db = categories();
+        // Only raw SQL can make a cycle, as Home's parent must exist
+        db.conn
+            .execute("UPDATE categories SET parent_id = 4 WHERE id = 1", [])
+            .unwrap();
+
+        assert_eq!(names(&db.get_ancestors("categories", 4).unwrap()), ["Garden", "Home"]);
+        let names: Vec<String> = subtree(&db, 1, None).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["Garden", "Tools", "Kitchen"]);
+    }
+
+    #[test]
+    fn parents_are_references_to_the_schema() {
+        let db = categories();
+        let missing = HashMap::from([
+            ("name".to_string(), Value::Text("Lost".to_string())),
+            (PARENT_FIELD.to_string(), Value::Integer(99)),
+        ]);
+        assert!(db.create_model("categories", missing).is_err());
+
+        let indexed: bool = db
+            .conn
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = 'categories_parent')",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(indexed);
+    }
+
+    #[test]
+    fn only_hierarchical_schemas_have_trees() {
+        let mut db = categories();
+        db.define_schema(Schema::new("tags", [("name".to_string(), FieldType::Text)]))
+            .unwrap();
+        assert!(matches!(db.get_roots("tags"), Err(KooError::InvalidSchema(_))));
+
+        let clash = Schema::new("teams", [(PARENT_FIELD.to_string(), FieldType::Integer)]).with_hierarchy();
+        assert!(matches!(db.define_schema(clash), Err(KooError::InvalidSchema(_))));
+        let text_keyed = Schema::new("nodes", []).with_key(PrimaryKey::Text).with_hierarchy();
+        assert!(matches!(db.define_schema(text_keyed), Err(KooError::InvalidSchema(_))));
+    }
+}
@@ -0,0 +1,457 @@
+use rusqlite::types::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FlexibleDatabase, Model, ModelId, Schema};
+use crate::query::{Direction, Filter, Op, Query};
+
+// A struct standing for the models of one schema, as `schema!` declares
+pub trait TypedModel: Sized {
+    const SCHEMA_NAME: &'static str;
+
+    fn schema() -> Schema;
+
+    // None until the model is saved
+    fn id(&self) -> Option<&ModelId>;
+
+    fn set_id(&mut self, id: ModelId);
+
+    // Read a model of the schema, failing as `Model::get_as` does
+    fn from_model(model: &Model) -> Result<Self>;
+
+    // The data to create or update the model with, without its id
+    fn data(&self) -> HashMap<String, Value>;
+}
+
+// A field of a typed struct holding values of type `T`, such as the
+// `User::AGE` that `schema!` declares for a `User`'s `age`. Filters built
+// from one name a field that exists and compare it with a value of its type:
+//
+//     User::query().filter(User::AGE.gt(18)).filter(User::NAME.starts_with("A"))
+pub struct Field<T> {
+    name: &'static str,
+    value_type: PhantomData<fn() -> T>,
+}
+
+impl<T> Field<T> {
+    pub const fn new(name: &'static str) -> Field<T> {
+        Field {
+            name,
+            value_type: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl<T: Into<Value>> Field<T> {
+    pub fn eq(&self, value: impl Into<T>) -> Filter {
+        self.compare(Op::Eq, value)
+    }
+
+    pub fn ne(&self, value: impl Into<T>) -> Filter {
+        self.compare(Op::Ne, value)
+    }
+
+    pub fn lt(&self, value: impl Into<T>) -> Filter {
+        self.compare(Op::Lt, value)
+    }
+
+    pub fn le(&self, value: impl Into<T>) -> Filter {
+        self.compare(Op::Le, value)
+    }
+
+    pub fn gt(&self, value: impl Into<T>) -> Filter {
+        self.compare(Op::Gt, value)
+    }
+
+    pub fn ge(&self, value: impl Into<T>) -> Filter {
+        self.compare(Op::Ge, value)
+    }
+
+    pub fn is_in(&self, values: impl IntoIterator<Item = T>) -> Filter {
+        Filter::is_in(self.name, values)
+    }
+
+    pub fn not_in(&self, values: impl IntoIterator<Item = T>) -> Filter {
+        Filter::not_in(self.name, values)
+    }
+
+    fn compare(&self, op: Op, value: impl Into<T>) -> Filter {
+        Filter::condition(self.name, op, value.into())
+    }
+}
+
+// Text matching, taking the text literally even where it holds wildcards
+impl Field<String> {
+    pub fn starts_with(&self, text: &str) -> Filter {
+        Filter::condition(self.name, Op::StartsWith, text.to_string())
+    }
+
+    pub fn ends_with(&self, text: &str) -> Filter {
+        Filter::condition(self.name, Op::EndsWith, text.to_string())
+    }
+
+    pub fn contains(&self, text: &str) -> Filter {
+        Filter::condition(self.name, Op::Contains, text.to_string())
+    }
+
+    pub fn contains_ignore_case(&self, text: &str) -> Filter {
+        Filter::condition(self.name, Op::ContainsIgnoreCase, text.to_string())
+    }
+}
+
+// Not derived, which would need `T` to be Clone and Debug too
+impl<T> Clone for Field<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Field<T> {}
+
+impl<T> fmt::Debug for Field<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Field").field(&self.name).finish()
+    }
+}
+
+// A query over the models of a typed struct, read back as that struct
+pub struct TypedQuery<M> {
+    query: Query,
+    model: PhantomData<fn() -> M>,
+}
+
+impl<M: TypedModel> TypedQuery<M> {
+    pub fn new() -> TypedQuery<M> {
+        TypedQuery {
+            query: Query::new(M::SCHEMA_NAME),
+            model: PhantomData,
+        }
+    }
+
+    // Match models for which `filter` holds, along with the earlier filters
+    pub fn filter(mut self, filter: Filter) -> TypedQuery<M> {
+        self.query = self.query.filter_by(filter);
+        self
+    }
+
+    pub fn order_by<T>(mut self, field: Field<T>, direction: Direction) -> TypedQuery<M> {
+        self.query = self.query.order_by(field.name(), direction);
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> TypedQuery<M> {
+        self.query = self.query.limit(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: usize) -> TypedQuery<M> {
+        self.query = self.query.offset(offset);
+        self
+    }
+
+    // The untyped query, for what only it offers
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
+
+    pub fn find(&self, db: &FlexibleDatabase) -> Result<Vec<M>> {
+        db.find(&self.query)?.iter().map(M::from_model).collect()
+    }
+
+    pub fn first(&self, db: &FlexibleDatabase) -> Result<Option<M>> {
+        let query = self.query.clone().limit(1);
+        db.find(&query)?.first().map(M::from_model).transpose()
+    }
+}
+
+impl<M: TypedModel> Default for TypedQuery<M> {
+    fn default() -> Self {
+        TypedQuery::new()
+    }
+}
+
+impl<M> Clone for TypedQuery<M> {
+    fn clone(&self) -> Self {
+        TypedQuery {
+            query: self.query.clone(),
+            model: PhantomData,
+        }
+    }
+}
+
+impl<M> fmt::Debug for TypedQuery<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("TypedQuery").field(&self.query).finish()
+    }
+}
+
+impl<M> From<TypedQuery<M>> for Query {
+    fn from(typed: TypedQuery<M>) -> Self {
+        typed.query
+    }
+}
+
+// Reads and writes of one typed struct's models, such as the `UserRepo`
+// that `schema!` declares for a `User`:
+//
+//     let users = UserRepo::new(&db);
+//     let mut user = User { id: None, name: "Ada".to_string(), age: 36 };
+//     users.save(&mut user)?;
+//     let found = users.find(user.id.clone().unwrap())?;
+pub struct Repository<'db, M> {
+    db: &'db FlexibleDatabase,
+    model: PhantomData<fn() -> M>,
+}
+
+impl<'db, M: TypedModel> Repository<'db, M> {
+    pub fn new(db: &'db FlexibleDatabase) -> Repository<'db, M> {
+        Repository { db, model: PhantomData }
+    }
+
+    pub fn find(&self, id: impl Into<ModelId>) -> Result<Option<M>> {
+        self.db.get_model(M::SCHEMA_NAME, id)?.as_ref().map(M::from_model).transpose()
+    }
+
+    // Create the model if it has no id yet, filling its id in, and update
+    // it otherwise. A model given a text id of its own is created if there
+    // is none with that id.
+    pub fn save(&self, model: &mut M) -> Result<ModelId> {
+        let Some(id) = model.id().cloned() else {
+            let id = self.db.create_model(M::SCHEMA_NAME, model.data())?;
+            model.set_id(id.clone());
+            return Ok(id);
+        };
+        if self.db.update_model(M::SCHEMA_NAME, &id, model.data())? {
+            return Ok(id);
+        }
+        match &id {
+            ModelId::Text(text) => {
+                let mut data = model.data();
+                data.insert("id".to_string(), Value::Text(text.clone()));
+                self.db.create_model(M::SCHEMA_NAME, data)
+            }
+            _ => Err(KooError::InvalidData(format!(
+                "no '{}' model {} to update",
+                M::SCHEMA_NAME,
+                id
+            ))),
+        }
+    }
+
+    // Whether there was such a model to delete
+    pub fn delete(&self, id: impl Into<ModelId>) -> Result<bool> {
+        self.db.delete_model(M::SCHEMA_NAME, id)
+    }
+
+    // Every model, in key order
+    pub fn list(&self) -> Result<Vec<M>> {
+        self.query().find(self.db)
+    }
+
+    pub fn count(&self) -> Result<i64> {
+        self.db.count(M::SCHEMA_NAME)
+    }
+
+    // Models matching `filter`, in key order
+    pub fn filter(&self, filter: Filter) -> Result<Vec<M>> {
+        self.query().filter(filter).find(self.db)
+    }
+
+    pub fn query(&self) -> TypedQuery<M> {
+        TypedQuery::new()
+    }
+}
+
+impl<M> Clone for Repository<'_, M> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<M> Copy for Repository<'_, M> {}
+
+impl<M: TypedModel> fmt::Debug for Repository<'_, M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Repository").field(&M::SCHEMA_NAME).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::flexible_database::PrimaryKey;
+
+    crate::schema! {
+        #[derive(Debug, Clone, PartialEq)]
+        struct Book in books {
+            title: Text,
+            pages: Integer indexed,
+            price: Real nullable,
+        }
+    }
+
+    fn library() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Book::schema()).unwrap();
+        for (title, pages, price) in [
+            ("Dune", 412, Some(9.5)),
+            ("Emma", 474, None),
+            ("Ulysses", 730, Some(15.0)),
+            ("100% Fun", 96, Some(5.0)),
+        ] {
+            let book = Book {
+                id: None,
+                title: title.to_string(),
+                pages,
+                price,
+            };
+            db.create_model("books", book.data()).unwrap();
+        }
+        db
+    }
+
+    fn titles(db: &FlexibleDatabase, query: TypedQuery<Book>) -> Vec<String> {
+        query.find(db).unwrap().into_iter().map(|book| book.title).collect()
+    }
+
+    #[test]
+    fn markers_name_their_fields() {
+        assert_eq!(Book::SCHEMA_NAME, "books");
+        assert_eq!(Book::TITLE.name(), "title");
+        assert_eq!(Book::PRICE.name(), "price");
+        assert_eq!(format!("{:?}", Book::PAGES), "Field(\"pages\")");
+    }
+
+    #[test]
+    fn filters_compare_fields_with_values_of_their_type() {
+        let db = library();
+        assert_eq!(titles(&db, Book::query().filter(Book::TITLE.eq("Emma"))), ["Emma"]);
+        assert_eq!(
+            titles(&db, Book::query().filter(Book::PAGES.gt(450))),
+            ["Emma", "Ulysses"]
+        );
+        assert_eq!(
+            titles(&db, Book::query().filter(Book::PAGES.le(412))),
+            ["Dune", "100% Fun"]
+        );
+        let between = Book::query().filter(Book::PAGES.ge(412)).filter(Book::PAGES.lt(730));
+        assert_eq!(titles(&db, between), ["Dune", "Emma"]);
+        assert_eq!(
+            titles(&db, Book::query().filter(Book::PRICE.eq(Some(15.0)))),
+            ["Ulysses"]
+        );
+        assert_eq!(titles(&db, Book::query().filter(Book::TITLE.ne("Dune"))).len(), 3);
+        let listed = Book::query().filter(Book::TITLE.is_in(["Dune".to_string(), "Emma".to_string()]));
+        assert_eq!(titles(&db, listed), ["Dune", "Emma"]);
+        assert_eq!(
+            titles(&db, Book::query().filter(Book::PAGES.not_in([412, 474, 730]))),
+            ["100% Fun"]
+        );
+    }
+
+    #[test]
+    fn text_matches_are_literal() {
+        let db = library();
+        assert_eq!(
+            titles(&db, Book::query().filter(Book::TITLE.starts_with("Ul"))),
+            ["Ulysses"]
+        );
+        assert_eq!(titles(&db, Book::query().filter(Book::TITLE.ends_with("ma"))), ["Emma"]);
+        assert_eq!(
+            titles(&db, Book::query().filter(Book::TITLE.contains("%"))),
+            ["100% Fun"]
+        );
+        let ignoring_case = Book::query().filter(Book::TITLE.contains_ignore_case("DUN"));
+        assert_eq!(titles(&db, ignoring_case), ["Dune"]);
+    }
+
+    #[test]
+    fn queries_sort_page_and_read_back_structs() {
+        let db = library();
+        let by_pages = Book::query().order_by(Book::PAGES, Direction::Desc);
+        assert_eq!(titles(&db, by_pages.clone().offset(1).limit(2)), ["Emma", "Dune"]);
+
+        let first = by_pages.first(&db).unwrap().unwrap();
+        assert_eq!(first.title, "Ulysses");
+        assert_eq!(first.price, Some(15.0));
+        assert!(first.id.is_some());
+        assert_eq!(Book::query().filter(Book::PAGES.gt(1000)).first(&db).unwrap(), None);
+
+        let query: Query = by_pages.clone().into();
+        assert_eq!(query.schema, "books");
+        assert_eq!(db.find(by_pages.query()).unwrap().len(), 4);
+        // Repositories start queries of their own
+        let cheap = BookRepo::new(&db).query().filter(Book::PRICE.lt(Some(9.0)));
+        assert_eq!(titles(&db, cheap), ["100% Fun"]);
+    }
+
+    fn unsaved(title: &str, pages: i64) -> Book {
+        Book {
+            id: None,
+            title: title.to_string(),
+            pages,
+            price: None,
+        }
+    }
+
+    #[test]
+    fn saving_creates_then_updates() {
+        let db = library();
+        let books = BookRepo::new(&db);
+        let mut book = unsaved("Persuasion", 249);
+        let id = books.save(&mut book).unwrap();
+        assert_eq!(book.id, Some(id.clone()));
+        assert_eq!(books.count().unwrap(), 5);
+
+        book.price = Some(7.25);
+        assert_eq!(books.save(&mut book).unwrap(), id);
+        assert_eq!(books.count().unwrap(), 5);
+        assert_eq!(books.find(id.clone()).unwrap(), Some(book));
+        assert_eq!(books.find(99).unwrap(), None);
+
+        let mut missing = unsaved("Lost", 1);
+        missing.id = Some(ModelId::Integer(99));
+        assert!(matches!(books.save(&mut missing), Err(KooError::InvalidData(_))));
+    }
+
+    #[test]
+    fn models_with_their_own_text_ids_are_created() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Book::schema().with_key(PrimaryKey::Text)).unwrap();
+        let books = BookRepo::new(&db);
+        let mut book = unsaved("Middlemarch", 880);
+        book.id = Some(ModelId::Text("isbn-1".to_string()));
+        assert_eq!(books.save(&mut book).unwrap(), ModelId::Text("isbn-1".to_string()));
+        book.pages = 900;
+        books.save(&mut book).unwrap();
+        assert_eq!(books.count().unwrap(), 1);
+        assert_eq!(books.find("isbn-1").unwrap().unwrap().pages, 900);
+    }
+
+    #[test]
+    fn repositories_list_filter_and_delete() {
+        let db = library();
+        let books = BookRepo::new(&db);
+        let listed: Vec<String> = books.list().unwrap().into_iter().map(|book| book.title).collect();
+        assert_eq!(listed, ["Dune", "Emma", "Ulysses", "100% Fun"]);
+        let long: Vec<String> = books
+            .filter(Book::PAGES.gt(450))
+            .unwrap()
+            .into_iter()
+            .map(|book| book.title)
+            .collect();
+        assert_eq!(long, ["Emma", "Ulysses"]);
+
+        assert!(books.delete(2).unwrap());
+        assert!(!books.delete(2).unwrap());
+        assert_eq!(books.count().unwrap(), 3);
+        assert_eq!(format!("{:?}", books), "Repository(\"books\")");
+    }
+}
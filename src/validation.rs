@@ -0,0 +1,383 @@
+use regex::Regex;
+use rusqlite::types::Value;
+use std::fmt;
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FieldType, Schema, sql_literal};
+use crate::geo::GeoPoint;
+use crate::relations::ModelRef;
+use crate::vector::is_vector;
+
+// Declarative constraint on the values of a field
+#[derive(Debug, Clone)]
+pub enum Validator {
+    // Smallest allowed number, inclusive
+    Min(f64),
+    // Largest allowed number, inclusive
+    Max(f64),
+    // Longest allowed text, in characters
+    MaxLength(usize),
+    // Text must match the regular expression somewhere; anchor it with ^
+    // and $ to match the whole value. Not expressible as an SQL CHECK.
+    Pattern(Regex),
+}
+
+impl Validator {
+    // `Pattern` from a regular expression source
+    pub fn pattern(source: &str) -> Result<Validator> {
+        Regex::new(source)
+            .map(Validator::Pattern)
+            .map_err(|err| KooError::InvalidSchema(format!("invalid pattern '{}': {}", source, err)))
+    }
+
+    // Whether the validator makes sense for fields of this type
+    pub fn applies_to(&self, field_type: &FieldType) -> bool {
+        match self {
+            Validator::Min(_) | Validator::Max(_) => matches!(field_type, FieldType::Integer | FieldType::Real),
+            Validator::MaxLength(_) | Validator::Pattern(_) => matches!(field_type, FieldType::Text),
+        }
+    }
+
+    // Problem with `value`, if there is one
+    fn check(&self, value: &Value) -> Option<String> {
+        let number = match value {
+            Value::Integer(i) => Some(*i as f64),
+            Value::Real(f) => Some(*f),
+            _ => None,
+        };
+        let text = match value {
+            Value::Text(s) => Some(s.as_str()),
+            _ => None,
+        };
+
+        match self {
+            Validator::Min(min) => number.filter(|n| n < min).map(|_| format!("must be at least {}", min)),
+            Validator::Max(max) => number.filter(|n| n > max).map(|_| format!("must be at most {}", max)),
+            Validator::MaxLength(max) => text
+                .filter(|s| s.chars().count() > *max)
+                .map(|_| format!("must be at most {} characters", max)),
+            Validator::Pattern(regex) => text
+                .filter(|s| !regex.is_match(s))
+                .map(|_| format!("must match {}", regex.as_str())),
+        }
+    }
+
+    // JSON form used by exports, e.g. {"max_length": 10}
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        match self {
+            Validator::Min(min) => serde_json::json!({ "min": min }),
+            Validator::Max(max) => serde_json::json!({ "max": max }),
+            Validator::MaxLength(max) => serde_json::json!({ "max_length": max }),
+            Validator::Pattern(regex) => serde_json::json!({ "pattern": regex.as_str() }),
+        }
+    }
+
+    pub(crate) fn from_json(json: &serde_json::Value) -> Option<Result<Validator>> {
+        let (kind, arg) = json.as_object().filter(|o| o.len() == 1)?.iter().next()?;
+        let validator = match kind.as_str() {
+            "min" => Validator::Min(arg.as_f64()?),
+            "max" => Validator::Max(arg.as_f64()?),
+            "max_length" => Validator::MaxLength(arg.as_u64()? as usize),
+            "pattern" => return Some(Validator::pattern(arg.as_str()?)),
+            _ => return None,
+        };
+        Some(Ok(validator))
+    }
+
+    // SQL version of the check, when SQLite can express it
+    pub(crate) fn sql(&self, field_name: &str) -> Option<String> {
+        match self {
+            Validator::Min(min) => Some(format!("{} >= {:?}", field_name, min)),
+            Validator::Max(max) => Some(format!("{} <= {:?}", field_name, max)),
+            Validator::MaxLength(max) => Some(format!("length({}) <= {}", field_name, max)),
+            Validator::Pattern(_) => None,
+        }
+    }
+}
+
+impl PartialEq for Validator {
+    fn eq(&self, other: &Validator) -> bool {
+        match (self, other) {
+            (Validator::Min(a), Validator::Min(b)) | (Validator::Max(a), Validator::Max(b)) => a == b,
+            (Validator::MaxLength(a), Validator::MaxLength(b)) => a == b,
+            (Validator::Pattern(a), Validator::Pattern(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Validator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Validator::Min(min) => write!(f, "min {}", min),
+            Validator::Max(max) => write!(f, "max {}", max),
+            Validator::MaxLength(max) => write!(f, "max length {}", max),
+            Validator::Pattern(regex) => write!(f, "pattern {}", regex.as_str()),
+        }
+    }
+}
+
+// Why one field of a model was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldViolation {
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for FieldViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+// Check the given field values against the schema's constraints, which
+// happens before every insert and update. All violations are reported
+// together rather than stopping at the first.
+pub(crate) fn validate<'a>(schema: &Schema, values: impl IntoIterator<Item = (&'a String, &'a Value)>) -> Result<()> {
+    let mut violations = Vec::new();
+    for (field_name, value) in values {
+        let Some(field_type) = schema.fields.get(field_name) else {
+            continue;
+        };
+        if *value == Value::Null && schema.is_nullable(field_name) {
+            continue;
+        }
+        if let FieldType::Enum(allowed) = field_type {
+            let valid = matches!(value, Value::Text(s) if allowed.contains(s));
+            if !valid {
+                violations.push(FieldViolation {
+                    field: field_name.clone(),
+                    message: format!("must be one of {}", allowed.join(", ")),
+                });
+            }
+        }
+        if *field_type == FieldType::GeoPoint && GeoPoint::from_value(value).is_none() {
+            violations.push(FieldViolation {
+                field: field_name.clone(),
+                message: "must be a [latitude, longitude] point".to_string(),
+            });
+        }
+        if let FieldType::Polymorphic(targets) = field_type
+            && !ModelRef::from_value(value).is_some_and(|reference| targets.contains(&reference.schema))
+        {
+            violations.push(FieldViolation {
+                field: field_name.clone(),
+                message: format!("must be a [schema, id] reference to one of {}", targets.join(", ")),
+            });
+        }
+        if let FieldType::Vector(dimensions) = field_type
+            && !is_vector(value, *dimensions)
+        {
+            violations.push(FieldViolation {
+                field: field_name.clone(),
+                message: format!("must be a vector of {} numbers", dimensions),
+            });
+        }
+        for validator in schema.validators.get(field_name).into_iter().flatten() {
+            if let Some(message) = validator.check(value) {
+                violations.push(FieldViolation {
+                    field: field_name.clone(),
+                    message,
+                });
+            }
+        }
+    }
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        violations.sort_by(|a, b| a.field.cmp(&b.field));
+        Err(KooError::Validation {
+            schema: schema.name.clone(),
+            violations,
+        })
+    }
+}
+
+// SQL CHECK expressions enforcing the constraints `validate` applies to a
+// field, for schemas defined `with_sql_checks`
+pub(crate) fn check_constraints(schema: &Schema, field_name: &str, field_type: &FieldType) -> Vec<String> {
+    let mut checks = Vec::new();
+    if let FieldType::Enum(allowed) = field_type {
+        let allowed: Vec<String> = allowed.iter().map(|s| sql_literal(&Value::Text(s.clone()))).collect();
+        checks.push(format!("{} IN ({})", field_name, allowed.join(", ")));
+    }
+    for validator in schema.validators.get(field_name).into_iter().flatten() {
+        checks.extend(validator.sql(field_name));
+    }
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::flexible_database::{FieldDef, FlexibleDatabase};
+
+    fn tickets(sql_checks: bool) -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let status = FieldType::Enum(vec!["open".to_string(), "closed".to_string()]);
+        let mut schema = Schema::new("tickets", []).field("status", FieldDef::new(status)).field(
+            "label",
+            FieldDef::new(FieldType::Enum(vec!["bug".to_string()])).nullable(),
+        );
+        if sql_checks {
+            schema = schema.with_sql_checks();
+        }
+        db.define_schema(schema).unwrap();
+        db
+    }
+
+    fn ticket(status: &str) -> HashMap<String, Value> {
+        HashMap::from([
+            ("status".to_string(), Value::Text(status.to_string())),
+            ("label".to_string(), Value::Null),
+        ])
+    }
+
+    fn violated_fields(result: Result<impl fmt::Debug>) -> Vec<String> {
+        match result {
+            Err(KooError::Validation { violations, .. }) => violations.into_iter().map(|v| v.field).collect(),
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn enum_fields_take_only_their_values() {
+        let db = tickets(false);
+        let id = db.create_model("tickets", ticket("open")).unwrap();
+        assert_eq!(violated_fields(db.create_model("tickets", ticket("lost"))), ["status"]);
+        assert_eq!(
+            violated_fields(db.update_model("tickets", id, ticket("Open"))),
+            ["status"]
+        );
+
+        // Nullable enums take NULL, others don't
+        let mut data = ticket("closed");
+        data.insert("label".to_string(), Value::Text("feature".to_string()));
+        assert_eq!(violated_fields(db.create_model("tickets", data)), ["label"]);
+        let data = HashMap::from([("status".to_string(), Value::Null), ("label".to_string(), Value::Null)]);
+        assert_eq!(violated_fields(db.create_model("tickets", data)), ["status"]);
+    }
+
+    #[test]
+    fn sql_checks_hold_raw_writes_to_enum_values() {
+        let db = tickets(true);
+        assert!(
+            db.execute_raw("INSERT INTO tickets (status) VALUES ('lost')", &[])
+                .is_err()
+        );
+        db.execute_raw("INSERT INTO tickets (status) VALUES ('open')", &[])
+            .unwrap();
+
+        // Without them only kooDB's own writes are checked
+        let db = tickets(false);
+        db.execute_raw("INSERT INTO tickets (status) VALUES ('lost')", &[])
+            .unwrap();
+    }
+
+    #[test]
+    fn enum_types_round_trip_through_their_names() {
+        let status = FieldType::Enum(vec!["open".to_string(), "closed".to_string()]);
+        assert_eq!(status.name(), "Enum(open|closed)");
+        assert_eq!(FieldType::from_name(&status.name()), Some(status));
+    }
+
+    fn products(sql_checks: bool) -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let mut schema = Schema::new("products", [])
+            .field(
+                "sku",
+                FieldDef::new(FieldType::Text)
+                    .validate(Validator::MaxLength(6))
+                    .validate(Validator::pattern("^[A-Z]+-[0-9]+$").unwrap()),
+            )
+            .field(
+                "price",
+                FieldDef::new(FieldType::Real)
+                    .validate(Validator::Min(0.0))
+                    .validate(Validator::Max(100.0)),
+            );
+        if sql_checks {
+            schema = schema.with_sql_checks();
+        }
+        db.define_schema(schema).unwrap();
+        db
+    }
+
+    fn product(sku: &str, price: f64) -> HashMap<String, Value> {
+        HashMap::from([
+            ("sku".to_string(), Value::Text(sku.to_string())),
+            ("price".to_string(), Value::Real(price)),
+        ])
+    }
+
+    #[test]
+    fn validators_report_every_violation_per_field() {
+        let db = products(false);
+        db.create_model("products", product("AB-1", 100.0)).unwrap();
+        assert_eq!(
+            violated_fields(db.create_model("products", product("AB-1", -0.5))),
+            ["price"]
+        );
+        assert_eq!(
+            violated_fields(db.create_model("products", product("ab-1", 5.0))),
+            ["sku"]
+        );
+
+        let Err(KooError::Validation { violations, .. }) = db.create_model("products", product("abcdefg", 101.0))
+        else {
+            panic!("expected a validation error");
+        };
+        let messages: Vec<String> = violations.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            messages,
+            [
+                "price: must be at most 100",
+                "sku: must be at most 6 characters",
+                "sku: must match ^[A-Z]+-[0-9]+$",
+            ]
+        );
+    }
+
+    #[test]
+    fn updates_are_validated_too() {
+        let db = products(false);
+        let id = db.create_model("products", product("AB-1", 5.0)).unwrap();
+        let data = HashMap::from([("price".to_string(), Value::Integer(-1))]);
+        assert_eq!(violated_fields(db.update_model("products", id, data)), ["price"]);
+    }
+
+    #[test]
+    fn sql_checks_hold_raw_writes_to_validators_sqlite_can_express() {
+        let db = products(true);
+        let insert = "INSERT INTO products (sku, price) VALUES (?, ?)";
+        let row = |sku: &str, price: f64| [Value::Text(sku.to_string()), Value::Real(price)];
+        assert!(db.execute_raw(insert, &row("AB-1", 500.0)).is_err());
+        assert!(db.execute_raw(insert, &row("AB-1234", 5.0)).is_err());
+        // Patterns are only checked by kooDB
+        db.execute_raw(insert, &row("ab", 5.0)).unwrap();
+    }
+
+    #[test]
+    fn validators_must_suit_their_field() {
+        let schema =
+            Schema::new("products", []).field("sku", FieldDef::new(FieldType::Text).validate(Validator::Min(1.0)));
+        assert!(matches!(schema.materialize(), Err(KooError::InvalidSchema(_))));
+        assert!(matches!(Validator::pattern("("), Err(KooError::InvalidSchema(_))));
+    }
+
+    #[test]
+    fn validators_round_trip_through_json() {
+        for validator in [
+            Validator::Min(-1.5),
+            Validator::Max(3.0),
+            Validator::MaxLength(10),
+            Validator::pattern("^a").unwrap(),
+        ] {
+            assert_eq!(Validator::from_json(&validator.to_json()).unwrap().unwrap(), validator);
+        }
+        assert!(Validator::from_json(&serde_json::json!({ "min": 1, "max": 2 })).is_none());
+    }
+}
@@ -0,0 +1,324 @@
+use rusqlite::Connection;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::{Value, ValueRef};
+
+use crate::error::{KooError, Result};
+use crate::field_encryption::DECRYPT_FUNCTION;
+use crate::flexible_database::{FieldType, FlexibleDatabase, Model, read_model, select_columns};
+
+// SQL function giving the distance between two vector blobs under a
+// metric, NULL where they differ in length or aren't vectors
+pub const VECTOR_DISTANCE_FUNCTION: &str = "koo_vector_distance";
+
+// How `nearest` scores models against the query vector; lower is closer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VectorMetric {
+    // 1 minus the cosine similarity, from 0 for the same direction to 2
+    // for opposite ones. What most embedding models are compared by.
+    #[default]
+    Cosine,
+    // Euclidean distance
+    L2,
+}
+
+impl VectorMetric {
+    fn code(&self) -> i64 {
+        match self {
+            VectorMetric::Cosine => 0,
+            VectorMetric::L2 => 1,
+        }
+    }
+}
+
+// A model found by `nearest`, with its distance from the query vector
+#[derive(Debug, Clone)]
+pub struct Neighbor {
+    pub model: Model,
+    pub distance: f64,
+}
+
+// The value of a `FieldType::Vector` field holding `vector`: a blob of
+// little-endian f32s
+pub fn encode(vector: &[f32]) -> Value {
+    Value::Blob(vector.iter().flat_map(|x| x.to_le_bytes()).collect())
+}
+
+// Inverse of `encode`
+pub fn decode(value: &Value) -> Option<Vec<f32>> {
+    match value {
+        Value::Blob(bytes) => decode_bytes(bytes),
+        _ => None,
+    }
+}
+
+fn decode_bytes(bytes: &[u8]) -> Option<Vec<f32>> {
+    if !bytes.len().is_multiple_of(4) {
+        return None;
+    }
+    Some(
+        bytes
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().expect("four bytes")))
+            .collect(),
+    )
+}
+
+// Whether `value` holds a vector of `dimensions` numbers
+pub(crate) fn is_vector(value: &Value, dimensions: usize) -> bool {
+    matches!(value, Value::Blob(bytes) if bytes.len() == dimensions * 4)
+}
+
+fn distance(a: &[f32], b: &[f32], metric: VectorMetric) -> Option<f64> {
+    if a.len() != b.len() {
+        return None;
+    }
+    let pairs = a.iter().zip(b).map(|(x, y)| (*x as f64, *y as f64));
+    match metric {
+        VectorMetric::Cosine => {
+            let (mut dot, mut norm_a, mut norm_b) = (0.0, 0.0, 0.0);
+            for (x, y) in pairs {
+                dot += x * y;
+                norm_a += x * x;
+                norm_b += y * y;
+            }
+            let norms = (norm_a * norm_b).sqrt();
+            // A zero vector has no direction to compare
+            (norms > 0.0).then(|| 1.0 - dot / norms)
+        }
+        VectorMetric::L2 => Some(pairs.map(|(x, y)| (x - y) * (x - y)).sum::<f64>().sqrt()),
+    }
+}
+
+// Define `VECTOR_DISTANCE_FUNCTION` on `conn`
+pub(crate) fn register_vector(conn: &Connection) -> Result<()> {
+    conn.create_scalar_function(
+        VECTOR_DISTANCE_FUNCTION,
+        3,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let (ValueRef::Blob(a), ValueRef::Blob(b)) = (ctx.get_raw(0), ctx.get_raw(1)) else {
+                return Ok(None);
+            };
+            let metric = match ctx.get::<i64>(2)? {
+                0 => VectorMetric::Cosine,
+                _ => VectorMetric::L2,
+            };
+            Ok(decode_bytes(a)
+                .zip(decode_bytes(b))
+                .and_then(|(a, b)| distance(&a, &b, metric)))
+        },
+    )?;
+    Ok(())
+}
+
+// Similarity search over `Vector` fields, such as embeddings of documents
+// for retrieval. Every model is scored, which keeps up with tables of tens
+// of thousands of models; the access policy applies to those returned.
+impl FlexibleDatabase {
+    // The `k` models whose `field_name` is closest to `query` by cosine
+    // distance, closest first
+    pub fn nearest(&self, schema_name: &str, field_name: &str, query: &[f32], k: usize) -> Result<Vec<Neighbor>> {
+        self.nearest_with(schema_name, field_name, query, k, VectorMetric::Cosine)
+    }
+
+    pub fn nearest_with(
+        &self,
+        schema_name: &str,
+        field_name: &str,
+        query: &[f32],
+        k: usize,
+        metric: VectorMetric,
+    ) -> Result<Vec<Neighbor>> {
+        let schema = self.schema_or_err(schema_name)?;
+        match schema.fields.get(field_name) {
+            Some(FieldType::Vector(dimensions)) if *dimensions == query.len() => {}
+            Some(FieldType::Vector(dimensions)) => {
+                return Err(KooError::InvalidData(format!(
+                    "'{}.{}' holds vectors of {} numbers, not {}",
+                    schema_name,
+                    field_name,
+                    dimensions,
+                    query.len()
+                )));
+            }
+            Some(field_type) => {
+                return Err(KooError::InvalidData(format!(
+                    "'{}.{}' is {}, not a vector",
+                    schema_name,
+                    field_name,
+                    field_type.name()
+                )));
+            }
+            None => {
+                return Err(KooError::UnknownField {
+                    schema: schema_name.to_string(),
+                    field: field_name.to_string(),
+                });
+            }
+        }
+
+        let columns = select_columns(schema);
+        let vector = match schema.encrypted_fields.iter().any(|f| f == field_name) {
+            true => format!("{}({})", DECRYPT_FUNCTION, field_name),
+            false => field_name.to_string(),
+        };
+        let sql = format!(
+            "SELECT {}, {}({}, ?1, ?2) AS distance FROM {} WHERE distance IS NOT NULL ORDER BY distance",
+            columns.join(", "),
+            VECTOR_DISTANCE_FUNCTION,
+            vector,
+            schema.name
+        );
+        let distance_column = columns.len();
+        self.traced(&sql, 2, || {
+            let mut stmt = self.conn.prepare(&sql)?;
+            let mut rows = stmt.query(rusqlite::params![encode(query), metric.code()])?;
+            // Not limited in SQL, since the access policy may hide some
+            let mut neighbors = Vec::new();
+            while neighbors.len() < k
+                && let Some(row) = rows.next()?
+            {
+                let mut model = read_model(row, schema)?;
+                if !self.readable(schema_name, &model) {
+                    continue;
+                }
+                self.track_model(schema_name, &mut model);
+                neighbors.push(Neighbor {
+                    model,
+                    distance: row.get(distance_column)?,
+                });
+            }
+            let count = neighbors.len();
+            Ok((neighbors, count))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::access::{AccessPolicy, CallerContext};
+    use crate::flexible_database::{FieldDef, Schema};
+
+    // Documents with two-dimensional embeddings
+    fn documents(embeddings: &[(&str, Value)]) -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("docs", [])
+            .field("title", FieldDef::new(FieldType::Text))
+            .field("embedding", FieldDef::new(FieldType::Vector(2)).nullable());
+        db.define_schema(schema).unwrap();
+        for (title, embedding) in embeddings {
+            let data = HashMap::from([
+                ("title".to_string(), Value::Text(title.to_string())),
+                ("embedding".to_string(), embedding.clone()),
+            ]);
+            db.create_model("docs", data).unwrap();
+        }
+        db
+    }
+
+    fn titles(neighbors: &[Neighbor]) -> Vec<&Value> {
+        neighbors.iter().map(|n| &n.model.data["title"]).collect()
+    }
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.to_string())
+    }
+
+    #[test]
+    fn vectors_round_trip_through_blobs() {
+        let vector = [1.5, -0.25, f32::MAX];
+        assert_eq!(decode(&encode(&vector)).unwrap(), vector);
+        assert_eq!(encode(&[1.0]), Value::Blob(1.0f32.to_le_bytes().to_vec()));
+        assert_eq!(decode(&Value::Blob(vec![0; 5])), None);
+        assert_eq!(decode(&Value::Text("[1, 2]".to_string())), None);
+        assert!(is_vector(&encode(&vector), 3) && !is_vector(&encode(&vector), 2));
+    }
+
+    #[test]
+    fn distances_follow_the_metric() {
+        let cosine = |a: &[f32], b: &[f32]| distance(a, b, VectorMetric::Cosine).unwrap();
+        assert!(cosine(&[1.0, 0.0], &[3.0, 0.0]).abs() < 1e-9);
+        assert!((cosine(&[1.0, 0.0], &[0.0, 2.0]) - 1.0).abs() < 1e-9);
+        assert!((cosine(&[1.0, 0.0], &[-1.0, 0.0]) - 2.0).abs() < 1e-9);
+        assert_eq!(distance(&[0.0, 0.0], &[1.0, 0.0], VectorMetric::Cosine), None);
+        assert_eq!(distance(&[0.0, 0.0], &[3.0, 4.0], VectorMetric::L2), Some(5.0));
+        assert_eq!(distance(&[1.0], &[1.0, 2.0], VectorMetric::L2), None);
+    }
+
+    #[test]
+    fn nearest_models_come_closest_first() {
+        let db = documents(&[
+            ("east", encode(&[1.0, 0.0])),
+            ("north", encode(&[0.0, 1.0])),
+            ("far east", encode(&[10.0, 0.5])),
+            ("west", encode(&[-1.0, 0.0])),
+            ("none", Value::Null),
+            ("zero", encode(&[0.0, 0.0])),
+        ]);
+
+        let cosine = db.nearest("docs", "embedding", &[1.0, 0.0], 3).unwrap();
+        assert_eq!(titles(&cosine), [&text("east"), &text("far east"), &text("north")]);
+        assert!(cosine[0].distance.abs() < 1e-9);
+
+        let l2 = db
+            .nearest_with("docs", "embedding", &[1.0, 0.0], 10, VectorMetric::L2)
+            .unwrap();
+        let expected = ["east", "zero", "north", "west", "far east"].map(text);
+        assert_eq!(titles(&l2), expected.iter().collect::<Vec<_>>());
+        assert_eq!(l2[3].distance, 2.0);
+        assert!(db.nearest("docs", "embedding", &[1.0, 0.0], 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn hidden_models_are_skipped_not_counted() {
+        struct NotEast;
+
+        impl AccessPolicy for NotEast {
+            fn can_read(&self, _schema: &str, model: &Model, _caller: &CallerContext) -> bool {
+                model.data["title"] != Value::Text("east".to_string())
+            }
+
+            fn can_write(&self, _schema: &str, _model: &Model, _caller: &CallerContext) -> bool {
+                true
+            }
+        }
+
+        let mut db = documents(&[
+            ("east", encode(&[1.0, 0.0])),
+            ("north", encode(&[0.0, 1.0])),
+            ("west", encode(&[-1.0, 0.0])),
+        ]);
+        db.set_access_policy(NotEast);
+        let neighbors = db.nearest("docs", "embedding", &[1.0, 0.0], 2).unwrap();
+        assert_eq!(titles(&neighbors), [&text("north"), &text("west")]);
+    }
+
+    #[test]
+    fn queries_must_fit_the_field() {
+        let db = documents(&[]);
+        assert!(matches!(
+            db.nearest("docs", "embedding", &[1.0, 0.0, 0.0], 1),
+            Err(KooError::InvalidData(_))
+        ));
+        assert!(matches!(
+            db.nearest("docs", "title", &[1.0, 0.0], 1),
+            Err(KooError::InvalidData(_))
+        ));
+        assert!(matches!(
+            db.nearest("docs", "body", &[1.0, 0.0], 1),
+            Err(KooError::UnknownField { .. })
+        ));
+
+        let data = HashMap::from([
+            ("title".to_string(), text("long")),
+            ("embedding".to_string(), encode(&[1.0, 2.0, 3.0])),
+        ]);
+        assert!(matches!(
+            db.create_model("docs", data),
+            Err(KooError::Validation { .. })
+        ));
+    }
+}
@@ -0,0 +1,565 @@
+use rusqlite::types::Value;
+use std::collections::HashMap;
+
+use crate::error::{KooError, Result};
+use crate::flexible_database::{FieldType, Model, ModelId, PrimaryKey, Schema, VERSION_COLUMN};
+use crate::geo::GeoPoint;
+use crate::relations::ModelRef;
+use crate::vector;
+
+// Version stamped on every encoded artifact: exports, encoded models and
+// anything else that leaves the process. Decoders accept this version and
+// every earlier one; bump it whenever the representation changes.
+pub const WIRE_VERSION: u32 = 1;
+
+// Encoding of a single model that must stay readable across kooDB versions
+pub trait ModelCodec {
+    fn encode(&self, model: &Model) -> Result<Vec<u8>>;
+    fn decode(&self, bytes: &[u8]) -> Result<Model>;
+}
+
+// Self-describing JSON: {"version": 1, "id": 7, "data": {"name": {"type": "text", "value": "Ann"}}}
+// Text ids are strings and composite ids are arrays of tagged values.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TaggedJson;
+
+// Compact binary form: a version byte, the optional id, then each field
+// as name, type tag and payload. Integers are zigzag varints.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Binary;
+
+impl ModelCodec for TaggedJson {
+    fn encode(&self, model: &Model) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&model_to_tagged_json(model))?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Model> {
+        model_from_tagged_json(&serde_json::from_slice(bytes)?)
+    }
+}
+
+pub fn model_to_tagged_json(model: &Model) -> serde_json::Value {
+    let mut data: Vec<_> = model.data.iter().collect();
+    data.sort_by(|a, b| a.0.cmp(b.0));
+    let data: serde_json::Map<String, serde_json::Value> = data
+        .into_iter()
+        .map(|(name, value)| (name.clone(), value_to_tagged_json(value)))
+        .collect();
+    let id = match &model.id {
+        None => serde_json::Value::Null,
+        Some(ModelId::Integer(id)) => (*id).into(),
+        Some(ModelId::Text(id)) => id.clone().into(),
+        Some(ModelId::Composite(values)) => values.iter().map(value_to_tagged_json).collect(),
+    };
+    serde_json::json!({
+        "version": WIRE_VERSION,
+        "id": id,
+        "data": data,
+    })
+}
+
+pub fn model_from_tagged_json(json: &serde_json::Value) -> Result<Model> {
+    check_version(json.get("version").and_then(|v| v.as_u64()).unwrap_or(1))?;
+
+    let id = match json.get("id") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(serde_json::Value::String(id)) => Some(ModelId::Text(id.clone())),
+        Some(serde_json::Value::Array(values)) => Some(ModelId::Composite(
+            values.iter().map(value_from_tagged_json).collect::<Result<_>>()?,
+        )),
+        Some(id) => Some(ModelId::Integer(
+            id.as_i64()
+                .ok_or_else(|| KooError::InvalidData(format!("invalid model id {}", id)))?,
+        )),
+    };
+
+    let mut data = HashMap::new();
+    if let Some(fields) = json.get("data") {
+        let fields = fields
+            .as_object()
+            .ok_or_else(|| KooError::InvalidData("model 'data' must be an object".to_string()))?;
+        for (name, value) in fields {
+            data.insert(name.clone(), value_from_tagged_json(value)?);
+        }
+    }
+    Ok(Model::new(id, data))
+}
+
+pub fn value_to_tagged_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Null => serde_json::json!({ "type": "null" }),
+        Value::Integer(i) => serde_json::json!({ "type": "integer", "value": i }),
+        Value::Real(f) => serde_json::json!({ "type": "real", "value": f }),
+        Value::Text(s) => serde_json::json!({ "type": "text", "value": s }),
+        Value::Blob(b) => serde_json::json!({ "type": "blob", "value": to_hex(b) }),
+    }
+}
+
+pub fn value_from_tagged_json(json: &serde_json::Value) -> Result<Value> {
+    let invalid = || KooError::InvalidData(format!("invalid tagged value {}", json));
+    let payload = json.get("value");
+
+    let value = match json.get("type").and_then(|t| t.as_str()) {
+        Some("null") => Value::Null,
+        Some("integer") => Value::Integer(payload.and_then(|v| v.as_i64()).ok_or_else(invalid)?),
+        Some("real") => Value::Real(payload.and_then(|v| v.as_f64()).ok_or_else(invalid)?),
+        Some("text") => Value::Text(payload.and_then(|v| v.as_str()).ok_or_else(invalid)?.to_string()),
+        Some("blob") => Value::Blob(
+            payload
+                .and_then(|v| v.as_str())
+                .and_then(from_hex)
+                .ok_or_else(invalid)?,
+        ),
+        _ => return Err(invalid()),
+    };
+    Ok(value)
+}
+
+const TAG_NULL: u8 = 0;
+const TAG_INTEGER: u8 = 1;
+const TAG_REAL: u8 = 2;
+const TAG_TEXT: u8 = 3;
+const TAG_BLOB: u8 = 4;
+
+const ID_NONE: u8 = 0;
+const ID_INTEGER: u8 = 1;
+const ID_TEXT: u8 = 2;
+const ID_COMPOSITE: u8 = 3;
+
+impl ModelCodec for Binary {
+    fn encode(&self, model: &Model) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        write_varint(&mut out, WIRE_VERSION as u64);
+        match &model.id {
+            None => out.push(ID_NONE),
+            Some(ModelId::Integer(id)) => {
+                out.push(ID_INTEGER);
+                write_varint(&mut out, zigzag(*id));
+            }
+            Some(ModelId::Text(id)) => {
+                out.push(ID_TEXT);
+                write_bytes(&mut out, id.as_bytes());
+            }
+            Some(ModelId::Composite(values)) => {
+                out.push(ID_COMPOSITE);
+                write_varint(&mut out, values.len() as u64);
+                for value in values {
+                    write_value(&mut out, value);
+                }
+            }
+        }
+
+        // Sorted so equal models always encode to the same bytes
+        let mut fields: Vec<_> = model.data.iter().collect();
+        fields.sort_by(|a, b| a.0.cmp(b.0));
+
+        write_varint(&mut out, fields.len() as u64);
+        for (name, value) in fields {
+            write_bytes(&mut out, name.as_bytes());
+            write_value(&mut out, value);
+        }
+        Ok(out)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Model> {
+        let mut reader = ByteReader { bytes, pos: 0 };
+
+        let version = reader.varint()?;
+        check_version(version)?;
+
+        let id = match reader.byte()? {
+            ID_NONE => None,
+            ID_INTEGER => Some(ModelId::Integer(unzigzag(reader.varint()?))),
+            ID_TEXT => Some(ModelId::Text(reader.string()?)),
+            ID_COMPOSITE => {
+                let count = reader.varint()?;
+                let values = (0..count).map(|_| reader.value()).collect::<Result<_>>()?;
+                Some(ModelId::Composite(values))
+            }
+            tag => return Err(KooError::InvalidData(format!("unknown id tag {}", tag))),
+        };
+
+        let count = reader.varint()?;
+        let mut data = HashMap::new();
+        for _ in 0..count {
+            let name = reader.string()?;
+            let value = reader.value()?;
+            data.insert(name, value);
+        }
+        Ok(Model::new(id, data))
+    }
+}
+
+pub(crate) fn check_version(version: u64) -> Result<()> {
+    if version == 0 || version > WIRE_VERSION as u64 {
+        return Err(KooError::UnsupportedVersion(version));
+    }
+    Ok(())
+}
+
+fn zigzag(i: i64) -> u64 {
+    ((i << 1) ^ (i >> 63)) as u64
+}
+
+fn unzigzag(u: u64) -> i64 {
+    ((u >> 1) as i64) ^ -((u & 1) as i64)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(TAG_NULL),
+        Value::Integer(i) => {
+            out.push(TAG_INTEGER);
+            write_varint(out, zigzag(*i));
+        }
+        Value::Real(f) => {
+            out.push(TAG_REAL);
+            out.extend_from_slice(&f.to_le_bytes());
+        }
+        Value::Text(s) => {
+            out.push(TAG_TEXT);
+            write_bytes(out, s.as_bytes());
+        }
+        Value::Blob(b) => {
+            out.push(TAG_BLOB);
+            write_bytes(out, b);
+        }
+    }
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| KooError::InvalidData("truncated binary model".to_string()))?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(KooError::InvalidData("varint is too long".to_string()))
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        let value = match self.byte()? {
+            TAG_NULL => Value::Null,
+            TAG_INTEGER => Value::Integer(unzigzag(self.varint()?)),
+            TAG_REAL => {
+                let raw: [u8; 8] = self.take(8)?.try_into().expect("took 8 bytes");
+                Value::Real(f64::from_le_bytes(raw))
+            }
+            TAG_TEXT => Value::Text(self.string()?),
+            TAG_BLOB => {
+                let len = self.varint()? as usize;
+                Value::Blob(self.take(len)?.to_vec())
+            }
+            tag => return Err(KooError::InvalidData(format!("unknown value tag {}", tag))),
+        };
+        Ok(value)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.varint()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| KooError::InvalidData("text is not valid UTF-8".to_string()))
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Schema-typed JSON, as used in exports: a plain JSON value whose meaning
+// comes from the field type, so booleans are written as true/false
+pub fn value_to_json(value: &Value, field_type: &FieldType) -> serde_json::Value {
+    match (value, field_type) {
+        (Value::Null, _) => serde_json::Value::Null,
+        (Value::Integer(i), FieldType::Boolean) => serde_json::Value::Bool(*i != 0),
+        (Value::Text(s), FieldType::GeoPoint | FieldType::Polymorphic(_)) => {
+            serde_json::from_str(s).unwrap_or_else(|_| s.clone().into())
+        }
+        (Value::Blob(b), FieldType::Vector(_)) => match vector::decode(value) {
+            Some(numbers) => numbers.into(),
+            None => b.clone().into(),
+        },
+        (Value::Integer(i), _) => (*i).into(),
+        (Value::Real(f), _) => (*f).into(),
+        (Value::Text(s), _) => s.clone().into(),
+        (Value::Blob(b), _) => b.clone().into(),
+    }
+}
+
+// Inverse of `value_to_json`
+pub fn json_to_value(
+    json: &serde_json::Value,
+    field_type: &FieldType,
+    schema_name: &str,
+    field_name: &str,
+) -> Result<Value> {
+    let value = match (json, field_type) {
+        (serde_json::Value::Null, _) => Some(Value::Null),
+        (serde_json::Value::String(s), FieldType::Text | FieldType::Enum(_)) => Some(Value::Text(s.clone())),
+        (serde_json::Value::Number(n), FieldType::Integer | FieldType::Reference(_)) => {
+            n.as_i64().map(Value::Integer)
+        }
+        (serde_json::Value::Number(n), FieldType::Real) => n.as_f64().map(Value::Real),
+        (serde_json::Value::Bool(b), FieldType::Boolean) => Some(Value::Integer(*b as i64)),
+        (serde_json::Value::Array(_), FieldType::GeoPoint) => GeoPoint::from_json(json).map(Value::from),
+        (serde_json::Value::String(s), FieldType::GeoPoint) => serde_json::from_str(s)
+            .ok()
+            .and_then(|json| GeoPoint::from_json(&json))
+            .map(Value::from),
+        (serde_json::Value::Array(_), FieldType::Polymorphic(_)) => ModelRef::from_json(json).map(Value::from),
+        (serde_json::Value::String(s), FieldType::Polymorphic(_)) => serde_json::from_str(s)
+            .ok()
+            .and_then(|json| ModelRef::from_json(&json))
+            .map(Value::from),
+        (serde_json::Value::Array(numbers), FieldType::Vector(dimensions)) if numbers.len() == *dimensions => numbers
+            .iter()
+            .map(|n| n.as_f64().map(|n| n as f32))
+            .collect::<Option<Vec<f32>>>()
+            .map(|numbers| vector::encode(&numbers)),
+        (serde_json::Value::Number(n), FieldType::Boolean) => {
+            n.as_i64().map(|i| Value::Integer((i != 0) as i64))
+        }
+        _ => None,
+    };
+
+    value.ok_or_else(|| {
+        KooError::InvalidData(format!(
+            "'{}.{}' expects {}, got {}",
+            schema_name,
+            field_name,
+            field_type.name(),
+            json
+        ))
+    })
+}
+
+
+// A model in the export row layout: the id (unless the key is composite),
+// every field in declared order, then the version of versioned schemas
+pub fn model_to_json(schema: &Schema, model: &Model) -> serde_json::Value {
+    let mut object = serde_json::Map::new();
+    // Composite keys are already part of the fields
+    match &model.id {
+        Some(ModelId::Integer(id)) => {
+            object.insert("id".to_string(), (*id).into());
+        }
+        Some(ModelId::Text(id)) => {
+            object.insert("id".to_string(), id.clone().into());
+        }
+        _ => {}
+    }
+    for (name, field_type) in &schema.fields {
+        if let Some(value) = model.data.get(name) {
+            object.insert(name.clone(), value_to_json(value, field_type));
+        }
+    }
+    if let Some(version) = model.data.get(VERSION_COLUMN) {
+        object.insert(VERSION_COLUMN.to_string(), value_to_json(version, &FieldType::Integer));
+    }
+    object.into()
+}
+
+// Inverse of `model_to_json`, giving data ready for `create_model` or
+// `update_model`. Fields the schema doesn't declare are rejected.
+pub fn data_from_json(schema: &Schema, json: &serde_json::Value) -> Result<HashMap<String, Value>> {
+    let object = json
+        .as_object()
+        .ok_or_else(|| KooError::InvalidData("model data must be a JSON object".to_string()))?;
+
+    let mut data = HashMap::new();
+    for (field_name, json) in object {
+        let field_type = match (field_name.as_str(), &schema.key) {
+            ("id", PrimaryKey::Text) => &FieldType::Text,
+            (VERSION_COLUMN, _) if schema.versioned => &FieldType::Integer,
+            _ => schema.fields.get(field_name).ok_or_else(|| KooError::UnknownField {
+                schema: schema.name.clone(),
+                field: field_name.clone(),
+            })?,
+        };
+        data.insert(
+            field_name.clone(),
+            json_to_value(json, field_type, &schema.name, field_name)?,
+        );
+    }
+    Ok(data)
+}
+
+// An id typed by a person, as in a URL or on the command line: a number, a
+// string, or a JSON array of the key values for composite keys
+pub fn model_id_from_str(schema: &Schema, id: &str) -> Result<ModelId> {
+    let bad_id = || KooError::InvalidData(format!("'{}' is not a valid id for '{}'", id, schema.name));
+    match &schema.key {
+        PrimaryKey::Integer => id.trim().parse().map(ModelId::Integer).map_err(|_| bad_id()),
+        PrimaryKey::Text => Ok(ModelId::Text(id.to_string())),
+        PrimaryKey::Composite(key_fields) => {
+            let values: Vec<serde_json::Value> = serde_json::from_str(id).map_err(|_| bad_id())?;
+            if values.len() != key_fields.len() {
+                return Err(bad_id());
+            }
+            let values = key_fields
+                .iter()
+                .zip(&values)
+                .map(|(field_name, json)| json_to_value(json, &schema.fields[field_name], &schema.name, field_name))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(ModelId::Composite(values))
+        }
+    }
+}
+
+// A field value typed by a person: text is taken as typed and anything
+// else is read as JSON, so `30`, `2.5` and `true` work as expected
+pub fn value_from_str(text: &str, field_type: &FieldType, schema_name: &str, field_name: &str) -> Result<Value> {
+    match field_type {
+        FieldType::Text | FieldType::Enum(_) => Ok(Value::Text(text.to_string())),
+        _ => {
+            let json: serde_json::Value = serde_json::from_str(text.trim()).map_err(|_| {
+                KooError::InvalidData(format!(
+                    "'{}.{}' expects {}, got '{}'",
+                    schema_name,
+                    field_name,
+                    field_type.name(),
+                    text
+                ))
+            })?;
+            json_to_value(&json, field_type, schema_name, field_name)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(id: Option<ModelId>) -> Model {
+        let data = HashMap::from([
+            ("nothing".to_string(), Value::Null),
+            ("count".to_string(), Value::Integer(-300)),
+            ("ratio".to_string(), Value::Real(0.25)),
+            ("name".to_string(), Value::Text("Zoë".to_string())),
+            ("bytes".to_string(), Value::Blob(vec![0, 1, 254, 255])),
+        ]);
+        Model::new(id, data)
+    }
+
+    fn ids() -> Vec<Option<ModelId>> {
+        vec![
+            None,
+            Some(ModelId::Integer(i64::MIN)),
+            Some(ModelId::Text("a-key".to_string())),
+            Some(ModelId::Composite(vec![Value::Integer(7), Value::Text("x".to_string())])),
+        ]
+    }
+
+    #[test]
+    fn both_codecs_round_trip_every_value_and_id() {
+        let codecs: [&dyn ModelCodec; 2] = [&TaggedJson, &Binary];
+        for codec in codecs {
+            for id in ids() {
+                let model = sample(id);
+                let decoded = codec.decode(&codec.encode(&model).unwrap()).unwrap();
+                assert_eq!(decoded.id, model.id);
+                assert_eq!(decoded.data, model.data);
+            }
+        }
+    }
+
+    #[test]
+    fn equal_models_encode_to_equal_bytes() {
+        let first = Binary.encode(&sample(Some(ModelId::Integer(1)))).unwrap();
+        let second = Binary.encode(&sample(Some(ModelId::Integer(1)))).unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn artifacts_from_newer_versions_are_refused() {
+        let mut bytes = Binary.encode(&sample(None)).unwrap();
+        bytes[0] = WIRE_VERSION as u8 + 1;
+        assert!(matches!(Binary.decode(&bytes), Err(KooError::UnsupportedVersion(_))));
+
+        let mut json = model_to_tagged_json(&sample(None));
+        json["version"] = (WIRE_VERSION + 1).into();
+        assert!(matches!(model_from_tagged_json(&json), Err(KooError::UnsupportedVersion(_))));
+
+        // Artifacts from before versioning count as version 1
+        json.as_object_mut().unwrap().remove("version");
+        assert!(model_from_tagged_json(&json).is_ok());
+    }
+
+    #[test]
+    fn damaged_binary_models_are_refused() {
+        let bytes = Binary.encode(&sample(Some(ModelId::Text("a-key".to_string())))).unwrap();
+        for len in 0..bytes.len() {
+            assert!(Binary.decode(&bytes[..len]).is_err());
+        }
+        let mut bytes = bytes;
+        bytes[1] = 9;
+        assert!(matches!(Binary.decode(&bytes), Err(KooError::InvalidData(_))));
+    }
+
+    #[test]
+    fn mistagged_json_values_are_refused() {
+        for json in [
+            serde_json::json!({ "type": "integer", "value": "7" }),
+            serde_json::json!({ "type": "blob", "value": "abc" }),
+            serde_json::json!({ "type": "date", "value": "2024-01-01" }),
+            serde_json::json!({ "value": 7 }),
+        ] {
+            assert!(value_from_tagged_json(&json).is_err());
+        }
+    }
+
+    #[test]
+    fn hex_round_trips_and_rejects_odd_or_foreign_digits() {
+        let bytes = vec![0, 15, 16, 255];
+        assert_eq!(to_hex(&bytes), "000f10ff");
+        assert_eq!(from_hex(&to_hex(&bytes)), Some(bytes));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
@@ -0,0 +1,325 @@
+use rusqlite::types::Value;
+use std::time::{Duration, Instant};
+
+use crate::error::Result;
+use crate::flexible_database::FlexibleDatabase;
+
+// When buffered writes are flushed, besides an explicit `flush`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteBehindOptions {
+    // Flush once this many writes are buffered
+    pub max_batch: usize,
+    // Flush on the first write after the oldest buffered one has waited
+    // this long. There is no background thread, so an idle handle keeps
+    // its writes until the next write, `flush` or drop.
+    pub max_delay: Duration,
+}
+
+impl Default for WriteBehindOptions {
+    fn default() -> Self {
+        WriteBehindOptions {
+            max_batch: 500,
+            max_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+pub(crate) struct WriteBuffer {
+    options: WriteBehindOptions,
+    statements: Vec<(String, Vec<Value>)>,
+    oldest: Option<Instant>,
+}
+
+// Write-behind, for ingest-heavy work such as logging where throughput
+// matters more than each write being on disk when it returns.
+// `create_model` and `update_model` check their input as usual, then
+// buffer the statement instead of running it, and buffered statements are
+// run together in one transaction, saving a commit (and fsync) per write.
+//
+// The trade-offs: buffered writes are lost if the process dies before they
+// are flushed, and a batch that fails is rolled back as a whole, staying
+// buffered for `flush` to try again. Until flushed they aren't seen by
+// reads, and other writes such as deletes run ahead of them. Updates
+// return true. Change subscribers hear of them when the batch commits.
+//
+// Writes inside a transaction, and all writes while auditing is enabled,
+// run straight through, so they roll back with the transaction and are
+// audited as usual. A transaction flushes the buffer before it begins.
+//
+// Writes only running them can answer for are refused rather than
+// buffered: creates of integer-keyed models, whose id SQLite picks, and
+// updates of versioned ones, which may be stale.
+impl FlexibleDatabase {
+    pub fn enable_write_behind(&mut self, options: WriteBehindOptions) {
+        let mut buffer = self.write_behind.borrow_mut();
+        match buffer.as_mut() {
+            Some(buffer) => buffer.options = options,
+            None => {
+                *buffer = Some(WriteBuffer {
+                    options,
+                    statements: Vec::new(),
+                    oldest: None,
+                })
+            }
+        }
+    }
+
+    // Flush and go back to writing straight through, returning how many
+    // writes were flushed
+    pub fn disable_write_behind(&mut self) -> Result<usize> {
+        let flushed = self.flush()?;
+        *self.write_behind.borrow_mut() = None;
+        Ok(flushed)
+    }
+
+    // Whether a write made now would be buffered
+    pub(crate) fn writing_behind(&self) -> bool {
+        self.write_behind.borrow().is_some() && !self.audit && self.conn.is_autocommit()
+    }
+
+    pub fn pending_writes(&self) -> usize {
+        self.write_behind
+            .borrow()
+            .as_ref()
+            .map_or(0, |buffer| buffer.statements.len())
+    }
+
+    // Run the buffered writes in one transaction, or a savepoint inside an
+    // open one, returning how many there were. When the batch fails its
+    // writes go back in the buffer, ahead of any buffered since.
+    pub fn flush(&self) -> Result<usize> {
+        let (statements, oldest) = match self.write_behind.borrow_mut().as_mut() {
+            Some(buffer) => (std::mem::take(&mut buffer.statements), buffer.oldest.take()),
+            None => return Ok(0),
+        };
+        if statements.is_empty() {
+            return Ok(0);
+        }
+
+        let outermost = self.conn.is_autocommit();
+        #[cfg(feature = "metrics")]
+        let started = Instant::now();
+        self.retrying(|| Ok(self.conn.execute_batch("SAVEPOINT koo_write_behind")?))?;
+        let result = statements
+            .iter()
+            .try_for_each(|(sql, params)| {
+                self.retrying(|| {
+                    self.traced(sql, params.len(), || {
+                        let changed = self.prepare_cached(sql)?.execute(rusqlite::params_from_iter(params))?;
+                        Ok(((), changed))
+                    })
+                })
+            })
+            .and_then(|()| match outermost {
+                true => self.retrying_commit("RELEASE koo_write_behind"),
+                false => Ok(self.conn.execute_batch("RELEASE koo_write_behind")?),
+            });
+        if result.is_err() && !self.conn.is_autocommit() {
+            let _ = match outermost {
+                true => self.conn.execute_batch("ROLLBACK"),
+                false => self
+                    .conn
+                    .execute_batch("ROLLBACK TO koo_write_behind; RELEASE koo_write_behind"),
+            };
+        }
+        #[cfg(feature = "metrics")]
+        if outermost {
+            self.metrics.record_transaction(started.elapsed(), result.is_ok());
+        }
+        // Reads cached before the batch may be stale
+        self.clear_read_cache();
+        if let Err(err) = result {
+            if let Some(buffer) = self.write_behind.borrow_mut().as_mut() {
+                let newer = std::mem::replace(&mut buffer.statements, statements);
+                buffer.statements.extend(newer);
+                buffer.oldest = oldest;
+            }
+            return Err(err);
+        }
+        Ok(statements.len())
+    }
+
+    // Under write-behind, buffer the write `sql`, flushing if the buffer
+    // is due, and return whether it was buffered
+    pub(crate) fn buffered(&self, sql: &str, params: &[Value]) -> Result<bool> {
+        if !self.writing_behind() {
+            return Ok(false);
+        }
+        let due = match self.write_behind.borrow_mut().as_mut() {
+            Some(buffer) => {
+                buffer.statements.push((sql.to_string(), params.to_vec()));
+                let oldest = *buffer.oldest.get_or_insert_with(Instant::now);
+                buffer.statements.len() >= buffer.options.max_batch || oldest.elapsed() >= buffer.options.max_delay
+            }
+            None => return Ok(false),
+        };
+        if due {
+            self.flush()?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::KooError;
+    use crate::flexible_database::{FieldType, ModelId, Schema, UuidVersion};
+    use crate::model_builder::ModelBuilder;
+    use crate::query::Query;
+    use crate::temp_file::TempFile;
+
+    fn logs() -> FlexibleDatabase {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("logs", [("line".to_string(), FieldType::Text)]).with_uuid_ids(UuidVersion::V4);
+        db.define_schema(schema).unwrap();
+        db
+    }
+
+    fn line(text: &str) -> std::collections::HashMap<String, Value> {
+        ModelBuilder::new().text("line", text).build()
+    }
+
+    #[test]
+    fn writes_are_buffered_until_flushed() {
+        let mut db = logs();
+        db.enable_write_behind(WriteBehindOptions {
+            max_batch: 10,
+            max_delay: Duration::from_secs(60),
+        });
+        let id = db.create_model("logs", line("one")).unwrap();
+        assert!(matches!(id, ModelId::Text(_)));
+        db.create_model("logs", line("two")).unwrap();
+        assert_eq!(db.pending_writes(), 2);
+        assert_eq!(db.count("logs").unwrap(), 0);
+
+        assert_eq!(db.flush().unwrap(), 2);
+        assert_eq!(db.pending_writes(), 0);
+        assert_eq!(db.count("logs").unwrap(), 2);
+        assert_eq!(
+            db.get_model("logs", id).unwrap().unwrap().get_str("line").unwrap(),
+            "one"
+        );
+    }
+
+    #[test]
+    fn a_full_buffer_is_flushed() {
+        let mut db = logs();
+        db.enable_write_behind(WriteBehindOptions {
+            max_batch: 3,
+            max_delay: Duration::from_secs(60),
+        });
+        for n in 0..7 {
+            db.create_model("logs", line(&n.to_string())).unwrap();
+        }
+        assert_eq!(db.count("logs").unwrap(), 6);
+        assert_eq!(db.disable_write_behind().unwrap(), 1);
+        assert_eq!(db.count("logs").unwrap(), 7);
+        db.create_model("logs", line("straight through")).unwrap();
+        assert_eq!(db.pending_writes(), 0);
+    }
+
+    #[test]
+    fn buffered_writes_are_flushed_on_drop() {
+        let file = TempFile::new("db");
+        {
+            let mut db = FlexibleDatabase::new(file.path()).unwrap();
+            db.define_schema(
+                Schema::new("logs", [("line".to_string(), FieldType::Text)]).with_uuid_ids(UuidVersion::V4),
+            )
+            .unwrap();
+            db.enable_write_behind(WriteBehindOptions::default());
+            db.create_model("logs", line("kept")).unwrap();
+        }
+        let db = FlexibleDatabase::new(file.path()).unwrap();
+        let count: i64 = db
+            .conn
+            .query_row("SELECT COUNT(*) FROM logs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn creates_of_integer_keyed_models_are_refused() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        db.define_schema(Schema::new("logs", [("line".to_string(), FieldType::Text)]))
+            .unwrap();
+        db.enable_write_behind(WriteBehindOptions::default());
+        assert!(matches!(
+            db.create_model("logs", line("one")),
+            Err(KooError::InvalidData(_))
+        ));
+        assert_eq!(db.pending_writes(), 0);
+
+        db.disable_write_behind().unwrap();
+        assert_eq!(db.create_model("logs", line("one")).unwrap(), ModelId::Integer(1));
+    }
+
+    #[test]
+    fn updates_of_versioned_models_are_refused() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("logs", [("line".to_string(), FieldType::Text)])
+            .with_uuid_ids(UuidVersion::V4)
+            .with_versioning();
+        db.define_schema(schema).unwrap();
+        let id = db.create_model("logs", line("one")).unwrap();
+
+        db.enable_write_behind(WriteBehindOptions::default());
+        let mut data = line("two");
+        data.insert("version".to_string(), Value::Integer(1));
+        assert!(matches!(
+            db.update_model("logs", id, data),
+            Err(KooError::InvalidData(_))
+        ));
+        assert_eq!(db.pending_writes(), 0);
+    }
+
+    #[test]
+    fn writes_in_a_rolled_back_transaction_are_lost() {
+        let mut db = logs();
+        db.enable_write_behind(WriteBehindOptions::default());
+        db.create_model("logs", line("before")).unwrap();
+        let result: Result<()> = db.transaction(|db| {
+            assert_eq!(db.pending_writes(), 0);
+            db.create_model("logs", line("rolled back"))?;
+            assert_eq!(db.pending_writes(), 0);
+            Err(KooError::InvalidData("stop".to_string()))
+        });
+        assert!(result.is_err());
+        assert_eq!(db.flush().unwrap(), 0);
+        let lines = db.find(&Query::new("logs")).unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].get_str("line").unwrap(), "before");
+    }
+
+    #[test]
+    fn a_failed_flush_keeps_its_writes() {
+        let mut db = FlexibleDatabase::new(":memory:").unwrap();
+        let schema = Schema::new("logs", [("line".to_string(), FieldType::Text)])
+            .with_uuid_ids(UuidVersion::V4)
+            .with_unique_index("line");
+        db.define_schema(schema).unwrap();
+        let taken = db.create_model("logs", line("one")).unwrap();
+
+        db.enable_write_behind(WriteBehindOptions::default());
+        db.create_model("logs", line("one")).unwrap();
+        assert!(db.flush().is_err());
+        db.create_model("logs", line("two")).unwrap();
+        assert_eq!(db.pending_writes(), 2);
+
+        db.delete_model("logs", taken).unwrap();
+        assert_eq!(db.flush().unwrap(), 2);
+        assert_eq!(db.count("logs").unwrap(), 2);
+    }
+
+    #[test]
+    fn audited_writes_run_straight_through() {
+        let mut db = logs();
+        db.enable_audit().unwrap();
+        db.enable_write_behind(WriteBehindOptions::default());
+        let id = db.create_model("logs", line("one")).unwrap();
+        db.update_model("logs", id.clone(), line("two")).unwrap();
+        assert_eq!(db.pending_writes(), 0);
+        assert_eq!(db.audit_history("logs", id).unwrap().len(), 2);
+    }
+}